@@ -0,0 +1,192 @@
+//! Access log of the finished connections
+//!
+//! Fed by the event bus and written from its own thread, so a slow disk never
+//! holds up the streaming.
+
+use futures::executor;
+use log::{error, info};
+
+use std::fmt::Write as FmtWrite;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::events::{Event, Events, Reason};
+use crate::record::civil_from_days;
+
+/// How the lines are written
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessFormat {
+    /// Space separated: timestamp, role, address, seconds, bytes, reason
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for AccessFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(AccessFormat::Text),
+            "json" => Ok(AccessFormat::Json),
+            _ => Err(format!("Unknown access log format {}, use text or json", s)),
+        }
+    }
+}
+
+/// Where and how to log the connections
+#[derive(Clone, Debug)]
+pub struct AccessLog {
+    /// Appended to, reopened on request so it can be rotated
+    pub path: PathBuf,
+    pub format: AccessFormat,
+}
+
+/// `YYYY-MM-DDTHH:MM:SS.mmmZ`
+fn iso_time(t: SystemTime) -> String {
+    let since = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs() as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let secs = secs.rem_euclid(86400);
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year, month, day, secs / 3600, secs / 60 % 60, secs % 60, since.subsec_millis())
+}
+
+/// `s` as a JSON string, quoted and escaped
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// The line logged for a finished connection, none for the other events
+fn line(event: &Event, format: AccessFormat, at: SystemTime) -> Option<String> {
+    let (role, addr, duration, bytes, reason) = match *event {
+        Event::ProducerDisconnected { addr, duration, bytes, ref reason } => ("producer", addr, duration, bytes, reason),
+        Event::ConsumerDisconnected { addr, duration, bytes, ref reason } => ("consumer", addr, duration, bytes, reason),
+        _ => return None,
+    };
+
+    Some(match format {
+        AccessFormat::Text => {
+            format!("{} {} {} {:.3} {} {}\n", iso_time(at), role, addr, duration.as_secs_f64(), bytes, reason)
+        }
+        AccessFormat::Json => {
+            let mut out = format!("{{\"timestamp\": {:.3}, \"role\": \"{}\", \"addr\": \"{}\", \"duration_secs\": {:.3}, \"bytes\": {}, \"reason\": \"{}\"",
+                                  at.duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0),
+                                  role, addr, duration.as_secs_f64(), bytes, reason);
+            if let Reason::Error(ref e) = *reason {
+                let _ = write!(out, ", \"error\": {}", json_string(e));
+            }
+            out.push_str("}\n");
+            out
+        }
+    })
+}
+
+fn open(log: &AccessLog) -> io::Result<BufWriter<File>> {
+    let file = OpenOptions::new().create(true).append(true).open(&log.path)
+        .map_err(|e| io::Error::new(e.kind(), format!("cannot open {}: {}", log.path.display(), e)))?;
+
+    Ok(BufWriter::new(file))
+}
+
+/// Log every connection closed from now on
+///
+/// Setting the returned flag reopens the file before the next line is written.
+pub fn spawn(log: AccessLog, events: Events) -> io::Result<Arc<AtomicBool>> {
+    let mut writer = open(&log)?;
+    let reopen = Arc::new(AtomicBool::new(false));
+
+    info!("Logging the connections to {}", log.path.display());
+
+    {
+        let reopen = reopen.clone();
+
+        thread::Builder::new().name("access-log".to_owned()).spawn(move || {
+            let mut events = executor::block_on_stream(events);
+
+            while let Some(event) = events.next() {
+                if reopen.swap(false, Ordering::Relaxed) {
+                    let _ = writer.flush();
+                    match open(&log) {
+                        Ok(reopened) => writer = reopened,
+                        Err(e) => error!("Cannot reopen the access log: {}", e),
+                    }
+                }
+
+                // Write what piled up at once
+                let mut next = Some(event);
+                while let Some(event) = next {
+                    if let Some(line) = line(&event, log.format, SystemTime::now()) {
+                        if let Err(e) = writer.write_all(line.as_bytes()) {
+                            error!("Cannot write to the access log: {}", e);
+                        }
+                    }
+                    next = events.try_next();
+                }
+
+                if let Err(e) = writer.flush() {
+                    error!("Cannot write to the access log: {}", e);
+                }
+            }
+        })?;
+    }
+
+    Ok(reopen)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn at() -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(1_700_000_000_250)
+    }
+
+    #[test]
+    fn text_lines() {
+        let event = Event::ConsumerDisconnected {
+            addr: "127.0.0.1:4321".parse().unwrap(),
+            bytes: 1316,
+            duration: Duration::from_millis(1500),
+            reason: Reason::Lagging,
+        };
+
+        assert_eq!(line(&event, AccessFormat::Text, at()).unwrap(),
+                   "2023-11-14T22:13:20.250Z consumer 127.0.0.1:4321 1.500 1316 lagging\n");
+        assert_eq!(line(&Event::StreamStalled, AccessFormat::Text, at()), None);
+    }
+
+    #[test]
+    fn json_lines() {
+        let event = Event::ProducerDisconnected {
+            addr: "[::1]:4321".parse().unwrap(),
+            bytes: 188,
+            duration: Duration::from_secs(2),
+            reason: Reason::Error("broken \"pipe\"".to_owned()),
+        };
+
+        assert_eq!(line(&event, AccessFormat::Json, at()).unwrap(),
+                   "{\"timestamp\": 1700000000.250, \"role\": \"producer\", \"addr\": \"[::1]:4321\", \"duration_secs\": 2.000, \
+                    \"bytes\": 188, \"reason\": \"error\", \"error\": \"broken \\\"pipe\\\"\"}\n");
+    }
+}
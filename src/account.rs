@@ -0,0 +1,154 @@
+//! Giving up root once the low ports are bound
+
+use log::info;
+
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::ptr;
+
+/// Room for the entries of the passwd and group databases
+const ENTRY_BUFFER: usize = 16 * 1024;
+
+/// Account the restreamer switches to, by id
+#[derive(Clone, Debug, PartialEq)]
+pub struct Account {
+    pub uid: Option<libc::uid_t>,
+    pub gid: Option<libc::gid_t>,
+}
+
+impl Account {
+    /// Resolve the names or numeric ids, the group defaulting to the primary one of the user
+    pub fn lookup(user: Option<&str>, group: Option<&str>) -> io::Result<Account> {
+        let (uid, primary) = match user {
+            Some(user) => {
+                let (uid, gid) = user_ids(user)?;
+                (Some(uid), Some(gid))
+            }
+            None => (None, None),
+        };
+        let gid = match group {
+            Some(group) => Some(group_id(group)?),
+            None => primary,
+        };
+
+        Ok(Account { uid, gid })
+    }
+
+    /// Switch the whole process to the account, for good
+    ///
+    /// The supplementary groups of root are dropped along, nothing is left to
+    /// regain the privileges with.
+    pub fn switch(&self) -> io::Result<()> {
+        if let Some(gid) = self.gid {
+            unsafe {
+                if libc::getuid() == 0 && libc::setgroups(1, &gid) < 0 {
+                    return Err(failed("setgroups", gid));
+                }
+                if libc::setgid(gid) < 0 {
+                    return Err(failed("setgid", gid));
+                }
+            }
+        }
+        if let Some(uid) = self.uid {
+            if unsafe { libc::setuid(uid) } < 0 {
+                return Err(failed("setuid", uid));
+            }
+        }
+
+        let (uid, euid, gid, egid) = unsafe { (libc::getuid(), libc::geteuid(), libc::getgid(), libc::getegid()) };
+        if self.uid.is_some_and(|id| id != uid || id != euid) || self.gid.is_some_and(|id| id != gid || id != egid) {
+            return Err(io::Error::other(format!("still running as uid {}/{} gid {}/{} after switching to {}", uid, euid, gid, egid, self)));
+        }
+        if self.uid.is_some_and(|id| id != 0) && unsafe { libc::setuid(0) } == 0 {
+            return Err(io::Error::other(format!("root could be regained after switching to {}", self)));
+        }
+
+        info!("Running as uid {} gid {}", euid, egid);
+        Ok(())
+    }
+}
+
+impl fmt::Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.uid, self.gid) {
+            (Some(uid), Some(gid)) => write!(f, "uid {} gid {}", uid, gid),
+            (Some(uid), None) => write!(f, "uid {}", uid),
+            (None, Some(gid)) => write!(f, "gid {}", gid),
+            (None, None) => f.write_str("the current account"),
+        }
+    }
+}
+
+fn failed(call: &str, id: u32) -> io::Error {
+    let e = io::Error::last_os_error();
+    io::Error::new(e.kind(), format!("{}({}) failed: {}", call, id, e))
+}
+
+fn not_found(what: &str, name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no {} named {}", what, name))
+}
+
+/// The uid and primary gid of `user`, a name or a numeric uid
+fn user_ids(user: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let name = CString::new(user).map_err(|_| not_found("user", user))?;
+    let mut buf = vec![0 as libc::c_char; ENTRY_BUFFER];
+    let mut entry: libc::passwd = unsafe { mem::zeroed() };
+    let mut found = ptr::null_mut();
+
+    let res = unsafe { libc::getpwnam_r(name.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut found) };
+    if res != 0 {
+        return Err(io::Error::from_raw_os_error(res));
+    }
+    if !found.is_null() {
+        return Ok((entry.pw_uid, entry.pw_gid));
+    }
+
+    // A numeric uid without an entry keeps its own number as the group
+    match user.parse() {
+        Ok(uid) => {
+            let res = unsafe { libc::getpwuid_r(uid, &mut entry, buf.as_mut_ptr(), buf.len(), &mut found) };
+            if res == 0 && !found.is_null() {
+                Ok((entry.pw_uid, entry.pw_gid))
+            } else {
+                Ok((uid, uid))
+            }
+        }
+        Err(_) => Err(not_found("user", user)),
+    }
+}
+
+/// The gid of `group`, a name or a numeric gid
+fn group_id(group: &str) -> io::Result<libc::gid_t> {
+    let name = CString::new(group).map_err(|_| not_found("group", group))?;
+    let mut buf = vec![0 as libc::c_char; ENTRY_BUFFER];
+    let mut entry: libc::group = unsafe { mem::zeroed() };
+    let mut found = ptr::null_mut();
+
+    let res = unsafe { libc::getgrnam_r(name.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut found) };
+    if res != 0 {
+        return Err(io::Error::from_raw_os_error(res));
+    }
+    if !found.is_null() {
+        return Ok(entry.gr_gid);
+    }
+
+    group.parse().map_err(|_| not_found("group", group))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_resolved() {
+        let root = Account::lookup(Some("root"), None).unwrap();
+        assert_eq!(root, Account { uid: Some(0), gid: Some(0) });
+        assert_eq!(Account::lookup(None, Some("0")).unwrap(), Account { uid: None, gid: Some(0) });
+        assert_eq!(root.to_string(), "uid 0 gid 0");
+
+        assert_eq!(Account::lookup(Some("no-such-user-here"), None).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(Account::lookup(None, Some("no-such-group-here")).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
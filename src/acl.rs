@@ -0,0 +1,205 @@
+//! IP allow and deny lists, and the rate of the connections from each address
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Instant;
+
+/// Addresses tracked before the half seen the longest ago is forgotten
+const MAX_TRACKED: usize = 4096;
+
+/// Address block such as `10.0.0.0/8` or `2001:db8::/32`, a bare address matches itself
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+/// Treat IPv4-mapped IPv6 addresses as the IPv4 ones
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        v4 => v4,
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = parts.next().unwrap_or("").parse()
+            .map_err(|e| format!("Invalid address in {}: {}", s, e))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse().map_err(|e| format!("Invalid prefix in {}: {}", s, e))?,
+            None => max,
+        };
+
+        if prefix > max {
+            return Err(format!("Invalid prefix in {}: larger than {}", s, max));
+        }
+
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Addresses allowed to connect
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl Acl {
+    /// Denied addresses are always refused, an empty allowlist allows everybody else
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|c| c.contains(ip)) &&
+            (self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip)))
+    }
+}
+
+/// Parse a rate of connections per second such as `5` or `0.5`
+pub fn parse_accept_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        _ => Err(format!("Invalid accept rate {}, use connections per second such as 5 or 0.5", s)),
+    }
+}
+
+/// Connections accepted from each address, a second's worth at once and then
+/// `rate` per second
+#[derive(Default)]
+pub struct AcceptRate {
+    /// Connections left and when they were counted, the last time the address was seen
+    buckets: HashMap<IpAddr, (f64, Instant)>,
+}
+
+impl AcceptRate {
+    /// Whether a connection from `ip` is allowed now, taking it from its budget
+    pub fn allows(&mut self, ip: IpAddr, rate: f64, now: Instant) -> bool {
+        let burst = rate.max(1.0);
+        let refill = |(tokens, at): (f64, Instant)| (tokens + rate * now.saturating_duration_since(at).as_secs_f64()).min(burst);
+
+        if self.buckets.len() >= MAX_TRACKED {
+            let mut seen: Vec<Instant> = self.buckets.values().map(|&(_, at)| at).collect();
+            let mid = seen.len() / 2;
+            let (_, &mut oldest_kept, _) = seen.select_nth_unstable(mid);
+            self.buckets.retain(|_, &mut (_, at)| at >= oldest_kept);
+        }
+
+        let bucket = self.buckets.entry(canonical(ip)).or_insert((burst, now));
+        let tokens = refill(*bucket);
+        if tokens < 1.0 {
+            *bucket = (tokens, now);
+            return false;
+        }
+
+        *bucket = (tokens - 1.0, now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_and_match() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+
+        let net: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains(ip("2001:db8:1::1")));
+        assert!(!net.contains(ip("2001:db9::1")));
+        assert!(!net.contains(ip("10.1.2.3")));
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(ip("192.168.1.1")));
+
+        let host: Cidr = "192.168.1.1".parse().unwrap();
+        assert!(host.contains(ip("192.168.1.1")));
+        assert!(!host.contains(ip("192.168.1.2")));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn permits() {
+        let mut acl = Acl::default();
+        assert!(acl.permits(ip("1.2.3.4")));
+
+        acl.deny.push("1.2.3.0/24".parse().unwrap());
+        assert!(!acl.permits(ip("1.2.3.4")));
+        assert!(acl.permits(ip("1.2.4.4")));
+
+        acl.allow.push("1.2.0.0/16".parse().unwrap());
+        assert!(acl.permits(ip("1.2.4.4")));
+        assert!(!acl.permits(ip("1.3.0.1")));
+    }
+
+    #[test]
+    fn accept_rate() {
+        let mut rate = AcceptRate::default();
+        let start = Instant::now();
+
+        for _ in 0..5 {
+            assert!(rate.allows(ip("192.0.2.1"), 5.0, start));
+        }
+        assert!(!rate.allows(ip("192.0.2.1"), 5.0, start));
+        // Same address, mapped
+        assert!(!rate.allows(ip("::ffff:192.0.2.1"), 5.0, start));
+        assert!(rate.allows(ip("192.0.2.2"), 5.0, start));
+
+        assert!(rate.allows(ip("192.0.2.1"), 5.0, start + Duration::from_millis(200)));
+        assert!(!rate.allows(ip("192.0.2.1"), 5.0, start + Duration::from_millis(300)));
+    }
+
+    #[test]
+    fn accept_rate_forgets_the_oldest() {
+        let mut rate = AcceptRate::default();
+        let start = Instant::now();
+
+        // Never refilled at such a rate, still forgotten once the table is full
+        assert!(rate.allows(ip("192.0.2.1"), 1e-9, start));
+        assert!(!rate.allows(ip("192.0.2.1"), 1e-9, start));
+        for i in 0..MAX_TRACKED as u32 {
+            let later = start + Duration::from_millis(u64::from(i) + 1);
+            rate.allows(IpAddr::from((0x0a00_0000 + i).to_be_bytes()), 1e-9, later);
+        }
+        assert!(rate.buckets.len() <= MAX_TRACKED);
+        assert!(rate.allows(ip("192.0.2.1"), 1e-9, start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn accept_rates() {
+        assert_eq!(parse_accept_rate("0.5"), Ok(0.5));
+        for s in &["0", "-1", "NaN", "inf", "fast"] {
+            assert!(parse_accept_rate(s).is_err(), "{}", s);
+        }
+    }
+}
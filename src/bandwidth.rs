@@ -0,0 +1,210 @@
+//! Cap on the rate of all the consumers together
+//!
+//! A single budget spent chunk by chunk. Once it runs out the consumers
+//! queue up and take their turns, one chunk each, as it refills. The higher
+//! priority tiers queue up ahead of the lower ones.
+
+use tokio::time::Sleep;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Waker};
+use std::time::{Duration, Instant};
+
+/// Budget kept while the consumers have nothing to send
+const CREDIT: Duration = Duration::from_millis(100);
+
+/// Time a consumer has to take its turn before the next ones may go ahead
+const TURN: Duration = Duration::from_millis(5);
+
+struct Inner {
+    /// When the budget spent so far is refilled
+    next: Instant,
+    /// Consumers waiting for their turn, with their tier, in order
+    waiting: VecDeque<(u64, u8, Waker)>,
+    /// Bits per second expected from the admitted consumers
+    demand: u64,
+}
+
+/// Bandwidth shared by the consumers
+pub struct Bandwidth {
+    bitrate: u64,
+    ids: AtomicU64,
+    inner: Mutex<Inner>,
+}
+
+impl fmt::Debug for Bandwidth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Bandwidth({} bit/s)", self.bitrate)
+    }
+}
+
+impl Bandwidth {
+    /// Send at most `bitrate` bits per second overall
+    pub fn new(bitrate: u64) -> Self {
+        Bandwidth {
+            bitrate,
+            ids: AtomicU64::new(0),
+            inner: Mutex::new(Inner {
+                next: Instant::now(),
+                waiting: VecDeque::new(),
+                demand: 0,
+            }),
+        }
+    }
+
+    pub fn bitrate(&self) -> u64 {
+        self.bitrate
+    }
+
+    /// Bits per second expected from the admitted consumers
+    pub fn demand(&self) -> u64 {
+        self.inner.lock().unwrap().demand
+    }
+
+    /// Whether a consumer expected to take `demand` bits per second still fits
+    pub fn admits(&self, demand: u64) -> bool {
+        self.demand() + demand <= self.bitrate
+    }
+
+    /// A share for a consumer of tier `priority` expected to take `demand` bits per second
+    pub fn share(self: &Arc<Self>, demand: u64, priority: u8) -> Share {
+        self.inner.lock().unwrap().demand += demand;
+
+        Share {
+            bandwidth: self.clone(),
+            id: self.ids.fetch_add(1, Ordering::Relaxed),
+            priority,
+            demand,
+            delay: None,
+        }
+    }
+
+    fn duration(&self, bytes: usize) -> Duration {
+        Duration::from_secs_f64(bytes as f64 * 8.0 / self.bitrate as f64)
+    }
+}
+
+/// What a consumer takes from the bandwidth, given back once dropped
+pub struct Share {
+    bandwidth: Arc<Bandwidth>,
+    id: u64,
+    priority: u8,
+    demand: u64,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl Share {
+    /// Whether a chunk can be sent now, otherwise the task is woken up on its turn
+    pub fn poll_ready(&mut self, cx: &mut Context<'_>) -> bool {
+        let deadline = {
+            let mut inner = self.bandwidth.inner.lock().unwrap();
+            let position = inner.waiting.iter().position(|&(id, _, _)| id == self.id);
+
+            // Those ahead get a moment to take their turn, in case they went quiet
+            let ahead = position.unwrap_or(inner.waiting.len()) as u32;
+            let deadline = inner.next + TURN * ahead;
+            let now = Instant::now();
+
+            if now >= deadline {
+                if let Some(position) = position {
+                    inner.waiting.remove(position);
+                }
+                // Up next, now that the budget will be spent further
+                if let Some((_, _, waker)) = inner.waiting.front() {
+                    waker.wake_by_ref();
+                }
+                self.delay = None;
+                return true;
+            }
+
+            match position {
+                Some(position) => inner.waiting[position].2 = cx.waker().clone(),
+                // Behind the consumers of the same tier, ahead of the lower ones
+                None => {
+                    let behind = inner.waiting.iter().position(|&(_, priority, _)| priority < self.priority).unwrap_or(inner.waiting.len());
+                    inner.waiting.insert(behind, (self.id, self.priority, cx.waker().clone()));
+                }
+            }
+            deadline
+        };
+
+        if crate::poll_delay(&mut self.delay, deadline, cx) {
+            cx.waker().wake_by_ref();
+        }
+
+        false
+    }
+
+    /// Spend the budget on `bytes` sent
+    pub fn sent(&self, bytes: usize) {
+        let now = Instant::now();
+        let earliest = now.checked_sub(CREDIT).unwrap_or(now);
+
+        let mut inner = self.bandwidth.inner.lock().unwrap();
+        inner.next = inner.next.max(earliest) + self.bandwidth.duration(bytes);
+    }
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        let mut inner = self.bandwidth.inner.lock().unwrap();
+        inner.demand -= self.demand;
+
+        if let Some(position) = inner.waiting.iter().position(|&(id, _, _)| id == self.id) {
+            inner.waiting.remove(position);
+            if let Some((_, _, waker)) = inner.waiting.front() {
+                waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime;
+    use futures::future;
+
+    #[test]
+    fn admission() {
+        let bandwidth = Arc::new(Bandwidth::new(10_000_000));
+        let first = bandwidth.share(4_000_000, 0);
+        let _second = bandwidth.share(4_000_000, 0);
+
+        assert!(bandwidth.admits(2_000_000));
+        assert!(!bandwidth.admits(3_000_000));
+
+        drop(first);
+        assert!(bandwidth.admits(6_000_000));
+    }
+
+    #[test]
+    fn turns() {
+        let bandwidth = Arc::new(Bandwidth::new(1316 * 8 * 100));
+        let mut a = bandwidth.share(0, 0);
+        let mut b = bandwidth.share(0, 0);
+        let mut c = bandwidth.share(0, 1);
+
+        let rt = runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        rt.block_on(future::poll_fn(|cx| {
+            // Spent for the next 10ms, both wait
+            assert!(a.poll_ready(cx));
+            a.sent(1316);
+            assert!(!a.poll_ready(cx));
+            assert!(!b.poll_ready(cx));
+
+            // a waits ahead of b, b waits a turn longer, c of a higher tier goes first
+            assert!(!c.poll_ready(cx));
+            let inner = bandwidth.inner.lock().unwrap();
+            assert_eq!(inner.waiting.iter().map(|&(id, _, _)| id).collect::<Vec<_>>(), vec![c.id, a.id, b.id]);
+            std::task::Poll::Ready(())
+        }));
+
+        drop(a);
+        assert_eq!(bandwidth.inner.lock().unwrap().waiting.len(), 2);
+    }
+}
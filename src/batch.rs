@@ -0,0 +1,225 @@
+//! Chunks waiting to be written to a consumer, sent with vectored writes
+//!
+//! The chunks are kept as they come out of the queue instead of being copied
+//! into one buffer, and up to `--write-batch` bytes of them go to the socket
+//! in one `writev` on TCP and Unix sockets. With a batch of 1 every chunk is
+//! written on its own. For the other sockets, e.g. TLS, the chunks are joined
+//! up to the batch instead.
+
+use bytes::{Buf, Bytes, BytesMut};
+
+use std::collections::VecDeque;
+use std::io::IoSlice;
+
+pub struct WriteBatch {
+    chunks: VecDeque<Bytes>,
+    len: usize,
+    /// Bytes handed to a single write, the first chunk is handed whole anyway
+    max: usize,
+}
+
+impl WriteBatch {
+    pub fn new(max: usize) -> Self {
+        WriteBatch {
+            chunks: VecDeque::new(),
+            len: 0,
+            max,
+        }
+    }
+
+    pub fn set_max(&mut self, max: usize) {
+        self.max = max;
+    }
+
+    /// Bytes not written yet
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Queue a chunk, without copying it
+    pub fn push(&mut self, chunk: Bytes) {
+        // Never handed out as an empty buffer
+        if !chunk.is_empty() {
+            self.len += chunk.len();
+            self.chunks.push_back(chunk);
+        }
+    }
+
+    /// Join the first chunks into one, up to the batch, for the sockets writing
+    /// a buffer at a time
+    pub fn coalesce(&mut self) {
+        if self.chunks.len() < 2 || self.chunks[0].len() + self.chunks[1].len() > self.max {
+            return;
+        }
+
+        let mut joined = BytesMut::with_capacity(self.max.min(self.len));
+        while let Some(chunk) = self.chunks.front() {
+            if !joined.is_empty() && joined.len() + chunk.len() > self.max {
+                break;
+            }
+            joined.extend_from_slice(chunk);
+            self.chunks.pop_front();
+        }
+        self.chunks.push_front(joined.freeze());
+    }
+
+    /// Queue whatever `f` writes, e.g. the WebSocket control frames
+    pub fn put<T, F: FnOnce(&mut BytesMut) -> T>(&mut self, f: F) -> T {
+        let mut buf = BytesMut::new();
+        let res = f(&mut buf);
+        self.push(buf.freeze());
+        res
+    }
+}
+
+impl Buf for WriteBatch {
+    fn remaining(&self) -> usize {
+        self.len
+    }
+
+    fn chunk(&self) -> &[u8] {
+        self.chunks.front().map_or(&[], |chunk| &chunk[..])
+    }
+
+    fn advance(&mut self, mut cnt: usize) {
+        assert!(cnt <= self.len, "advanced past the end of the batch");
+        self.len -= cnt;
+
+        while cnt > 0 {
+            let front = self.chunks.front_mut().unwrap();
+            if cnt < front.len() {
+                front.advance(cnt);
+                return;
+            }
+            cnt -= front.len();
+            self.chunks.pop_front();
+        }
+    }
+
+    fn chunks_vectored<'a>(&'a self, dst: &mut [IoSlice<'a>]) -> usize {
+        let (mut n, mut bytes) = (0, 0);
+
+        for chunk in &self.chunks {
+            if n == dst.len() || (n > 0 && bytes + chunk.len() > self.max) {
+                break;
+            }
+            dst[n] = IoSlice::new(chunk);
+            n += 1;
+            bytes += chunk.len();
+        }
+
+        n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use tokio::io::AsyncWrite;
+    use tokio_util::io::poll_write_buf;
+
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Takes up to `limit` bytes a call, the way the TCP sockets do, counting the calls
+    struct Writev {
+        written: Vec<u8>,
+        limit: usize,
+        calls: usize,
+    }
+
+    impl AsyncWrite for Writev {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+        }
+
+        fn poll_write_vectored(mut self: Pin<&mut Self>, _: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<io::Result<usize>> {
+            self.calls += 1;
+            let mut written = 0;
+            for buf in bufs {
+                let take = buf.len().min(self.limit - written);
+                self.written.extend_from_slice(&buf[..take]);
+                written += take;
+            }
+            Poll::Ready(Ok(written))
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            true
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn flush(max: usize, limit: usize) -> (Writev, Vec<u8>) {
+        let mut batch = WriteBatch::new(max);
+        let mut data = Vec::new();
+        for n in 0..100u8 {
+            let chunk = vec![n; 1316];
+            data.extend_from_slice(&chunk);
+            batch.push(Bytes::from(chunk));
+        }
+        batch.push(Bytes::new());
+
+        let mut socket = Writev { written: Vec::new(), limit, calls: 0 };
+        let mut cx = Context::from_waker(noop_waker_ref());
+        while !batch.is_empty() {
+            let _ = poll_write_buf(Pin::new(&mut socket), &mut cx, &mut batch);
+        }
+        (socket, data)
+    }
+
+    #[test]
+    fn batched() {
+        // 49 chunks of 1316 bytes a call
+        let (socket, data) = flush(64 * 1024, usize::MAX);
+        assert_eq!(socket.calls, 3);
+        assert!(socket.written == data);
+
+        let (socket, data) = flush(1, usize::MAX);
+        assert_eq!(socket.calls, 100);
+        assert!(socket.written == data);
+    }
+
+    #[test]
+    fn partial_writes() {
+        let (socket, data) = flush(64 * 1024, 5000);
+        assert_eq!(socket.calls, data.len().div_ceil(5000));
+        assert!(socket.written == data);
+
+        let mut batch = WriteBatch::new(1);
+        batch.push(Bytes::from_static(b"abc"));
+        batch.put(|buf| buf.extend_from_slice(b"def"));
+        batch.advance(4);
+        assert_eq!((batch.len(), batch.chunk()), (2, &b"ef"[..]));
+    }
+
+    #[test]
+    fn coalesced() {
+        let mut batch = WriteBatch::new(10);
+        for chunk in &[&b"abcd"[..], b"efgh", b"ijkl", b"mnopqrstuvwxyz"] {
+            batch.push(Bytes::from_static(chunk));
+        }
+
+        batch.coalesce();
+        assert_eq!(batch.chunk(), b"abcdefgh");
+        batch.advance(8);
+        batch.coalesce();
+        assert_eq!(batch.chunk(), b"ijkl");
+        batch.advance(4);
+        batch.coalesce();
+        assert_eq!((batch.chunk(), batch.len()), (&b"mnopqrstuvwxyz"[..], 14));
+    }
+}
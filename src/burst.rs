@@ -0,0 +1,179 @@
+//! Late-join burst buffer, replaying the recent past to the new consumers
+
+use bytes::Bytes;
+
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Upper bound for the buffer when it is sized by duration
+const MAX_DURATION_BYTES: usize = 64 * 1024 * 1024;
+
+/// How much of the stream to keep
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Burst {
+    Bytes(usize),
+    Duration(Duration),
+}
+
+impl FromStr for Burst {
+    type Err = String;
+
+    /// Parse sizes such as `4M`, `512K`, `1316` or durations such as `2s`, `500ms`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Invalid burst {}, use e.g. 4M or 2s", s);
+        let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+        let (num, unit) = s.split_at(split);
+        let num: f64 = num.parse().map_err(|_| err())?;
+
+        let bytes = |mult: f64| Ok(Burst::Bytes((num * mult) as usize));
+        let secs = |secs: f64| Duration::try_from_secs_f64(secs).map(Burst::Duration).map_err(|_| err());
+
+        match unit {
+            "" | "B" => bytes(1.0),
+            "K" | "k" => bytes(1024.0),
+            "M" | "m" => bytes(1024.0 * 1024.0),
+            "G" | "g" => bytes(1024.0 * 1024.0 * 1024.0),
+            "s" => secs(num),
+            "ms" => secs(num / 1000.0),
+            _ => Err(err()),
+        }
+    }
+}
+
+/// Ring of the most recent packets
+pub struct BurstBuffer {
+    limit: Burst,
+    packets: VecDeque<(Instant, Bytes)>,
+    bytes: usize,
+}
+
+impl BurstBuffer {
+    pub fn new(limit: Burst) -> Self {
+        BurstBuffer {
+            limit,
+            packets: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    fn max_bytes(&self) -> usize {
+        match self.limit {
+            Burst::Bytes(max) => max,
+            Burst::Duration(_) => MAX_DURATION_BYTES,
+        }
+    }
+
+    fn pop(&mut self) {
+        if let Some((_, old)) = self.packets.pop_front() {
+            self.bytes -= old.len();
+        }
+    }
+
+    /// Remember a packet, evicting the old ones past the limit
+    pub fn push(&mut self, packet: &Bytes) {
+        let max = self.max_bytes();
+
+        if packet.len() > max {
+            self.clear();
+            return;
+        }
+
+        while self.bytes + packet.len() > max {
+            self.pop();
+        }
+
+        let now = Instant::now();
+        if let Burst::Duration(window) = self.limit {
+            while self.packets.front().is_some_and(|&(t, _)| now.duration_since(t) > window) {
+                self.pop();
+            }
+        }
+
+        self.bytes += packet.len();
+        self.packets.push_back((now, packet.clone()));
+    }
+
+    /// The packets kept, oldest first
+    pub fn packets(&self) -> impl DoubleEndedIterator<Item = &Bytes> {
+        self.packets.iter().map(|(_, packet)| packet)
+    }
+
+    /// The most recent `count` packets, oldest first
+    pub fn recent(&self, count: usize) -> impl Iterator<Item = &Bytes> {
+        let skip = self.packets.len().saturating_sub(count);
+        self.packets.iter().skip(skip).map(|(_, packet)| packet)
+    }
+
+    /// The most recent `count` packets within `limit`, oldest first
+    pub fn recent_within(&self, count: usize, limit: Burst) -> impl Iterator<Item = &Bytes> {
+        let now = Instant::now();
+        let mut bytes = 0;
+        let within = self.packets.iter().rev().take(count).take_while(|&&(t, ref packet)| {
+            bytes += packet.len();
+            match limit {
+                Burst::Bytes(max) => bytes <= max,
+                Burst::Duration(window) => now.duration_since(t) <= window,
+            }
+        }).count();
+
+        self.packets.iter().skip(self.packets.len() - within).map(|(_, packet)| packet)
+    }
+
+    /// The packets received at most `window` ago, oldest first, along with how
+    /// long ago the oldest packet kept was received
+    pub fn since(&self, window: Duration) -> (Vec<Bytes>, Duration) {
+        let now = Instant::now();
+        let within = self.packets.iter().rev().take_while(|&&(t, _)| now.duration_since(t) <= window).count();
+        let packets = self.packets.iter().skip(self.packets.len() - within).map(|(_, packet)| packet.clone()).collect();
+        let kept = self.packets.front().map_or(Duration::ZERO, |&(t, _)| now.duration_since(t));
+        (packets, kept)
+    }
+
+    pub fn clear(&mut self) {
+        self.packets.clear();
+        self.bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!("4M".parse(), Ok(Burst::Bytes(4 * 1024 * 1024)));
+        assert_eq!("1316".parse(), Ok(Burst::Bytes(1316)));
+        assert_eq!("2s".parse(), Ok(Burst::Duration(Duration::from_secs(2))));
+        assert_eq!("500ms".parse(), Ok(Burst::Duration(Duration::from_millis(500))));
+        assert!("2h".parse::<Burst>().is_err());
+        assert!("M".parse::<Burst>().is_err());
+        assert!("99999999999999999999s".parse::<Burst>().is_err());
+    }
+
+    #[test]
+    fn bytes_cap() {
+        let mut burst = BurstBuffer::new(Burst::Bytes(1000));
+        let packet = Bytes::from(vec![0x47; 188]);
+
+        for _ in 0..100 {
+            burst.push(&packet);
+            assert!(burst.bytes <= 1000);
+        }
+
+        assert_eq!(burst.recent(usize::MAX).count(), 5);
+        assert_eq!(burst.recent(2).count(), 2);
+        assert_eq!(burst.recent_within(usize::MAX, Burst::Bytes(400)).count(), 2);
+        assert_eq!(burst.recent_within(1, Burst::Bytes(400)).count(), 1);
+        assert_eq!(burst.recent_within(usize::MAX, Burst::Bytes(0)).count(), 0);
+        assert_eq!(burst.recent_within(usize::MAX, Burst::Duration(Duration::from_secs(1))).count(), 5);
+
+        let (packets, kept) = burst.since(Duration::from_secs(1));
+        assert_eq!(packets.len(), 5);
+        assert!(kept < Duration::from_secs(1));
+
+        burst.clear();
+        assert_eq!(burst.recent(usize::MAX).count(), 0);
+        assert!(burst.since(Duration::from_secs(1)).0.is_empty());
+    }
+}
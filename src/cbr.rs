@@ -0,0 +1,205 @@
+//! Constant bitrate outputs, with `--cbr` or `?cbr=BITRATE` on a target
+//!
+//! The null packets of the stream are dropped and the rest sent in chunks of
+//! a few packets on a timer, padded with null packets to the rate asked for.
+//! The PCRs are left as they are, so the stream had better fit: when it peaks
+//! above the rate for more than a second its backlog is sent as fast as it
+//! comes, with a warning, rather than falling further behind.
+
+use tokio::time::Sleep;
+use bytes::{Bytes, BytesMut};
+use log::{info, warn};
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::options::parse_bitrate;
+use crate::stats::CbrStats;
+use crate::ts;
+
+const NULL_PID: u16 = 0x1fff;
+/// Bytes per chunk sent to the TCP consumers, as many as in an UDP datagram
+pub const CHUNK_SIZE: usize = 7 * ts::PACKET_SIZE;
+/// Later than this, the timer starts over rather than catching up
+const MAX_LATE: Duration = Duration::from_millis(100);
+/// Backlog past which the stream is sent as it comes
+const MAX_BACKLOG: Duration = Duration::from_secs(1);
+
+/// Take the `cbr=BITRATE` parameter out of the query of `url`
+pub fn split_cbr(url: &str) -> Result<(String, Option<u64>), String> {
+    let (target, query) = match url.find('?') {
+        Some(i) => (&url[..i], &url[i + 1..]),
+        None => return Ok((url.to_owned(), None)),
+    };
+
+    let mut cbr = None;
+    let mut rest = Vec::new();
+    for param in query.split('&') {
+        match param.strip_prefix("cbr=") {
+            Some(bitrate) => cbr = Some(parse_bitrate(bitrate).map_err(|e| format!("{} in {}", e, url))?),
+            None => rest.push(param),
+        }
+    }
+
+    if rest.is_empty() {
+        Ok((target.to_owned(), cbr))
+    } else {
+        Ok((format!("{}?{}", target, rest.join("&")), cbr))
+    }
+}
+
+/// A null packet, its continuity counter left at 0 as it means nothing
+fn null_packet() -> [u8; ts::PACKET_SIZE] {
+    let mut pkt = [0xff; ts::PACKET_SIZE];
+    pkt[..4].copy_from_slice(&[ts::SYNC_BYTE, (NULL_PID >> 8) as u8, NULL_PID as u8, 0x10]);
+    pkt
+}
+
+/// Shapes the stream of an output to a constant bitrate
+pub struct Cbr {
+    /// Whole packets
+    chunk_size: usize,
+    /// Time a chunk takes at the rate
+    interval: Duration,
+    /// When the next chunk is due
+    next: Instant,
+    delay: Option<Pin<Box<Sleep>>>,
+    /// Packets waiting for their turn, the null ones aside
+    backlog: BytesMut,
+    max_backlog: usize,
+    /// Sending the backlog as it comes
+    over: bool,
+    /// Named in the logs
+    output: String,
+    stats: Arc<CbrStats>,
+}
+
+impl Cbr {
+    /// Send `chunk_size` bytes at a time to `output`
+    pub fn new(output: String, stats: Arc<CbrStats>, chunk_size: usize) -> Self {
+        let bytes_per_sec = stats.target_bps as f64 / 8.0;
+        Cbr {
+            chunk_size,
+            interval: Duration::from_secs_f64(chunk_size as f64 / bytes_per_sec),
+            next: Instant::now(),
+            delay: None,
+            backlog: BytesMut::new(),
+            max_backlog: (bytes_per_sec * MAX_BACKLOG.as_secs_f64()) as usize,
+            over: false,
+            output,
+            stats,
+        }
+    }
+
+    /// Queue the packets of `chunk`, dropping the null ones
+    pub fn push(&mut self, chunk: &[u8]) {
+        if !ts::is_aligned(chunk) {
+            self.backlog.extend_from_slice(chunk);
+        } else {
+            for pkt in chunk.chunks(ts::PACKET_SIZE) {
+                if ts::pid(pkt) == NULL_PID {
+                    self.stats.nulls_dropped.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    self.backlog.extend_from_slice(pkt);
+                }
+            }
+        }
+        self.stats.backlog.store(self.backlog.len(), Ordering::Relaxed);
+    }
+
+    /// Bytes waiting for their turn
+    pub fn pending(&self) -> usize {
+        self.backlog.len()
+    }
+
+    /// The next chunk if it is due, otherwise woken up once it is
+    pub fn poll_due(&mut self, cx: &mut Context<'_>) -> Poll<Bytes> {
+        let now = Instant::now();
+        if now > self.next + MAX_LATE {
+            self.next = now;
+        }
+
+        let over = self.backlog.len() > self.max_backlog;
+        if over != self.over {
+            if over {
+                warn!("The stream to {} peaks above its CBR of {} bps, {} bytes behind, sending them as they come",
+                      self.output, self.stats.target_bps, self.backlog.len());
+                self.stats.overruns.fetch_add(1, Ordering::Relaxed);
+            } else {
+                info!("The stream to {} is back within its CBR", self.output);
+            }
+            self.over = over;
+        }
+
+        if !over {
+            if self.next > now && !crate::poll_delay(&mut self.delay, self.next, cx) {
+                return Poll::Pending;
+            }
+            self.next += self.interval;
+        }
+
+        Poll::Ready(self.chunk())
+    }
+
+    /// A chunk of the backlog, padded with null packets
+    fn chunk(&mut self) -> Bytes {
+        let len = self.backlog.len().min(self.chunk_size);
+        let mut chunk = self.backlog.split_to(len);
+        let nulls = (self.chunk_size - len) / ts::PACKET_SIZE;
+        for _ in 0..nulls {
+            chunk.extend_from_slice(&null_packet());
+        }
+
+        self.stats.nulls_inserted.fetch_add(nulls as u64, Ordering::Relaxed);
+        self.stats.backlog.store(self.backlog.len(), Ordering::Relaxed);
+        chunk.freeze()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use crate::ts::tests::packet;
+
+    #[test]
+    fn urls() {
+        assert_eq!(split_cbr("udp://239.0.0.1:5000?cbr=8M"), Ok(("udp://239.0.0.1:5000".to_owned(), Some(8_000_000))));
+        assert_eq!(split_cbr("tcp://relay:9000?local=10.0.0.1&cbr=1500k&device=eth1"),
+                   Ok(("tcp://relay:9000?local=10.0.0.1&device=eth1".to_owned(), Some(1_500_000))));
+        assert_eq!(split_cbr("239.0.0.1:5000"), Ok(("239.0.0.1:5000".to_owned(), None)));
+        assert!(split_cbr("239.0.0.1:5000?cbr=fast").is_err());
+    }
+
+    #[test]
+    fn padded_and_paced() {
+        let stats = Arc::new(CbrStats::new(8_000_000));
+        let mut cbr = Cbr::new("239.0.0.1:5000".to_owned(), stats.clone(), CHUNK_SIZE);
+
+        let chunk: Vec<u8> = [packet(0x100, 0x10), packet(NULL_PID, 0x10), packet(0x101, 0x10)].concat();
+        cbr.push(&chunk);
+        assert_eq!(cbr.pending(), 2 * ts::PACKET_SIZE);
+
+        // Due right away
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let out = match cbr.poll_due(&mut cx) {
+            Poll::Ready(out) => out,
+            Poll::Pending => panic!("not due"),
+        };
+        assert_eq!(out.len(), CHUNK_SIZE);
+        let pids: Vec<_> = out.chunks(ts::PACKET_SIZE).map(ts::pid).collect();
+        assert_eq!(pids, vec![0x100, 0x101, NULL_PID, NULL_PID, NULL_PID, NULL_PID, NULL_PID]);
+        assert!((cbr.interval.as_nanos() as i64 - 1_316_000).abs() < 10);
+        assert_eq!((stats.nulls_dropped.load(Ordering::Relaxed), stats.nulls_inserted.load(Ordering::Relaxed)), (1, 5));
+
+        // Over a second behind, sent as it comes
+        cbr.next = Instant::now() + Duration::from_millis(50);
+        cbr.push(&packet(0x100, 0x10).repeat(6000));
+        assert!(cbr.pending() > cbr.max_backlog);
+        assert!(cbr.poll_due(&mut cx).is_ready());
+        assert_eq!(stats.overruns.load(Ordering::Relaxed), 1);
+    }
+}
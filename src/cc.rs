@@ -0,0 +1,240 @@
+//! Continuity counter monitoring
+//!
+//! Every packet broadcast is checked against the previous one of its PID, so
+//! the losses upstream can be told apart from the ones inside the restreamer.
+
+use log::warn;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::ts;
+
+/// How often the errors are logged
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Stuffing, its continuity counter is undefined
+const NULL_PID: u16 = 0x1fff;
+
+/// Counters for a single PID
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PidCounters {
+    pub packets: u64,
+    /// Packets whose continuity counter skipped ahead or went back
+    pub discontinuities: u64,
+    /// Packets sent twice in a row
+    pub duplicates: u64,
+    /// Packets flagged by the transport_error_indicator
+    pub transport_errors: u64,
+}
+
+impl PidCounters {
+    fn errors(&self) -> u64 {
+        self.discontinuities + self.duplicates + self.transport_errors
+    }
+
+    fn since(&self, old: &PidCounters) -> PidCounters {
+        PidCounters {
+            packets: self.packets - old.packets,
+            discontinuities: self.discontinuities - old.discontinuities,
+            duplicates: self.duplicates - old.duplicates,
+            transport_errors: self.transport_errors - old.transport_errors,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Pid {
+    /// Continuity counter of the last packet with a payload
+    last_cc: Option<u8>,
+    counters: PidCounters,
+    /// Counters at the last report
+    reported: PidCounters,
+}
+
+impl Pid {
+    fn check(&mut self, pkt: &[u8]) {
+        self.counters.packets += 1;
+
+        if pkt[1] & 0x80 != 0 {
+            // Nothing in the header can be trusted
+            self.counters.transport_errors += 1;
+            return;
+        }
+
+        let adaptation = pkt[3] & 0x20 != 0;
+        let payload = pkt[3] & 0x10 != 0;
+        let cc = pkt[3] & 0x0f;
+
+        // The counter only moves along with the payload
+        if !payload {
+            return;
+        }
+
+        let signalled = adaptation && pkt[4] > 0 && pkt[5] & 0x80 != 0;
+
+        match self.last_cc {
+            _ if signalled => (),
+            Some(last) if last == cc => self.counters.duplicates += 1,
+            Some(last) if (last + 1) & 0x0f != cc => self.counters.discontinuities += 1,
+            _ => (),
+        }
+
+        self.last_cc = Some(cc);
+    }
+}
+
+#[derive(Default)]
+pub struct Continuity {
+    pids: Mutex<HashMap<u16, Pid>>,
+}
+
+impl Continuity {
+    /// Check the packets of an aligned chunk, the others are left alone
+    pub fn inspect(&self, chunk: &[u8]) {
+        if !ts::is_aligned(chunk) {
+            return;
+        }
+
+        let mut pids = self.pids.lock().unwrap();
+
+        for pkt in chunk.chunks(ts::PACKET_SIZE) {
+            let pid = ts::pid(pkt);
+            if pid != NULL_PID {
+                pids.entry(pid).or_default().check(pkt);
+            }
+        }
+    }
+
+    /// A new stream starts, its counters are unrelated to the previous one
+    pub fn restart(&self) {
+        for pid in self.pids.lock().unwrap().values_mut() {
+            pid.last_cc = None;
+        }
+    }
+
+    /// Counters of every PID seen, sorted by PID
+    pub fn counters(&self) -> Vec<(u16, PidCounters)> {
+        let mut counters: Vec<_> = self.pids.lock().unwrap().iter()
+            .map(|(&pid, state)| (pid, state.counters))
+            .collect();
+        counters.sort_by_key(|&(pid, _)| pid);
+        counters
+    }
+
+    /// Counters of the PIDs with errors since the previous call
+    fn take_report(&self) -> Vec<(u16, PidCounters)> {
+        let mut report: Vec<_> = self.pids.lock().unwrap().iter_mut()
+            .filter_map(|(&pid, state)| {
+                let delta = state.counters.since(&state.reported);
+                state.reported = state.counters;
+                if delta.errors() > 0 {
+                    Some((pid, delta))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        report.sort_by_key(|&(pid, _)| pid);
+        report
+    }
+}
+
+/// Log the PIDs with errors every `REPORT_INTERVAL`
+pub async fn report(continuity: Arc<Continuity>) {
+    let mut ticks = crate::interval(REPORT_INTERVAL);
+    loop {
+        ticks.tick().await;
+        for (pid, c) in continuity.take_report() {
+            warn!("PID {:#06x}: {} discontinuities, {} duplicates, {} transport errors in {} packets over {:?}",
+                      pid, c.discontinuities, c.duplicates, c.transport_errors, c.packets, REPORT_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(pid: u16, cc: u8, flags: u8) -> Vec<u8> {
+        let mut pkt = ts::tests::packet(pid, flags | cc);
+        pkt[4..6].copy_from_slice(&[1, 0]);
+        pkt
+    }
+
+    fn payload(pid: u16, cc: u8) -> Vec<u8> {
+        packet(pid, cc, 0x10)
+    }
+
+    fn counters(continuity: &Continuity, pid: u16) -> PidCounters {
+        continuity.counters().into_iter().find(|&(p, _)| p == pid).unwrap().1
+    }
+
+    #[test]
+    fn counts_the_errors() {
+        let continuity = Continuity::default();
+        let mut chunk = Vec::new();
+
+        for cc in &[14, 15, 0, 0, 2, 3] {
+            chunk.extend(payload(0x100, *cc));
+        }
+        let mut corrupt = payload(0x100, 9);
+        corrupt[1] |= 0x80;
+        chunk.extend(corrupt);
+        // The counter wraps around
+        chunk.extend(payload(0x200, 15));
+        chunk.extend(payload(0x200, 0));
+
+        continuity.inspect(&chunk);
+
+        assert_eq!(counters(&continuity, 0x100),
+                   PidCounters { packets: 7, discontinuities: 1, duplicates: 1, transport_errors: 1 });
+        assert_eq!(counters(&continuity, 0x200), PidCounters { packets: 2, ..Default::default() });
+    }
+
+    #[test]
+    fn adaptation_only_is_not_an_error() {
+        let continuity = Continuity::default();
+        let mut chunk = payload(0x100, 3);
+        // Same counter, no payload
+        chunk.extend(packet(0x100, 3, 0x20));
+        chunk.extend(packet(0x100, 7, 0x20));
+        chunk.extend(payload(0x100, 4));
+
+        continuity.inspect(&chunk);
+
+        assert_eq!(counters(&continuity, 0x100).errors(), 0);
+    }
+
+    #[test]
+    fn signalled_discontinuity_and_restart() {
+        let continuity = Continuity::default();
+        let mut signalled = packet(0x100, 9, 0x30);
+        signalled[5] = 0x80;
+
+        continuity.inspect(&payload(0x100, 1));
+        continuity.inspect(&signalled);
+        continuity.restart();
+        continuity.inspect(&payload(0x100, 5));
+        // Neither null packets nor unaligned chunks are checked
+        continuity.inspect(&payload(NULL_PID, 0));
+        continuity.inspect(&payload(0x300, 0)[..100]);
+
+        assert_eq!(continuity.counters().len(), 1);
+        assert_eq!(counters(&continuity, 0x100).errors(), 0);
+    }
+
+    #[test]
+    fn reports_the_new_errors() {
+        let continuity = Continuity::default();
+
+        continuity.inspect(&[payload(0x100, 0), payload(0x100, 5)].concat());
+        let report = continuity.take_report();
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].1.discontinuities, 1);
+
+        continuity.inspect(&payload(0x100, 6));
+        assert!(continuity.take_report().is_empty());
+    }
+}
@@ -0,0 +1,306 @@
+//! AES-128 encryption of the stream, with `--encrypt-key` and `--decrypt-key`
+//!
+//! The payload of every packet is encrypted in CBC mode from the same IV, its
+//! last partial block with residual block termination as in SCTE 52: XORed
+//! with the encryption of the last whole block, or of the IV if there is none.
+//! The packets keep their size, so the chunks keep their boundaries. Their
+//! header and adaptation field stay in the clear, the scrambling control bits
+//! telling the encrypted payloads apart, and so do the tables and the null
+//! packets, for the receivers to find the programs.
+//!
+//! The consumers get the stream encrypted, the producers of a restreamer given
+//! the same key and IV get it decrypted: two of them form an encrypted tunnel.
+//! The chunks out of sync go as they are.
+
+use openssl::symm::{Cipher, Crypter, Mode};
+use bytes::{Bytes, BytesMut};
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::psi;
+use crate::stats::Stats;
+use crate::ts;
+
+const BLOCK: usize = 16;
+/// Scrambling control bits of a payload encrypted, with the even key
+const EVEN_KEY: u8 = 0x80;
+const SCRAMBLING: u8 = 0xc0;
+/// The PAT and the other tables of fixed PIDs
+const LAST_TABLE_PID: u16 = 0x1f;
+const NULL_PID: u16 = 0x1fff;
+
+/// 32 hexadecimal digits, the error never tells what was given
+fn parse_block(s: &str, what: &str) -> Result<[u8; BLOCK], String> {
+    if s.len() != 2 * BLOCK || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("Invalid {}, expected 32 hexadecimal digits", what));
+    }
+
+    let mut block = [0; BLOCK];
+    for (i, b) in block.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+    }
+    Ok(block)
+}
+
+/// AES-128 key, never shown
+#[derive(Clone, Copy, PartialEq)]
+pub struct Key([u8; BLOCK]);
+
+impl FromStr for Key {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_block(s, "AES-128 key").map(Key)
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+/// IV every packet starts from, all zeros unless given
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Iv([u8; BLOCK]);
+
+impl FromStr for Iv {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_block(s, "AES-128 IV").map(Iv)
+    }
+}
+
+/// What both ends of a tunnel share
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aes {
+    key: Key,
+    iv: Iv,
+}
+
+impl Aes {
+    pub fn new(key: Key, iv: Option<Iv>) -> Self {
+        Aes { key, iv: iv.unwrap_or_default() }
+    }
+
+    /// Encrypt the chunks of a consumer, leaving the tables listed in `stats` in the clear
+    pub fn encryptor(&self, stats: Arc<Stats>) -> Encryptor {
+        Encryptor {
+            ecb: Ecb::new(&self.key, Mode::Encrypt),
+            iv: self.iv.0,
+            stats,
+            generation: None,
+            pmt_pids: Vec::new(),
+        }
+    }
+
+    /// Decrypt the chunks of a producer
+    pub fn decryptor(&self) -> Decryptor {
+        Decryptor {
+            encrypt: Ecb::new(&self.key, Mode::Encrypt),
+            decrypt: Ecb::new(&self.key, Mode::Decrypt),
+            iv: self.iv.0,
+        }
+    }
+}
+
+/// A block at a time, the chaining is done here
+struct Ecb(Crypter);
+
+impl Ecb {
+    fn new(key: &Key, mode: Mode) -> Self {
+        let mut crypter = Crypter::new(Cipher::aes_128_ecb(), mode, &key.0, None).expect("AES-128 is available");
+        crypter.pad(false);
+        Ecb(crypter)
+    }
+
+    fn block(&mut self, block: &mut [u8; BLOCK]) {
+        let mut out = [0; 2 * BLOCK];
+        let n = self.0.update(block, &mut out).expect("whole blocks");
+        debug_assert_eq!(n, BLOCK);
+        block.copy_from_slice(&out[..BLOCK]);
+    }
+}
+
+fn xor(dst: &mut [u8], src: &[u8]) {
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d ^= s;
+    }
+}
+
+/// Residual block termination, the same both ways
+fn terminate(ecb: &mut Ecb, mut last: [u8; BLOCK], residual: &mut [u8]) {
+    if !residual.is_empty() {
+        ecb.block(&mut last);
+        xor(residual, &last);
+    }
+}
+
+fn encrypt(ecb: &mut Ecb, iv: &[u8; BLOCK], payload: &mut [u8]) {
+    let mut chain = *iv;
+    let mut blocks = payload.chunks_exact_mut(BLOCK);
+    for block in &mut blocks {
+        xor(&mut chain, block);
+        ecb.block(&mut chain);
+        block.copy_from_slice(&chain);
+    }
+    terminate(ecb, chain, blocks.into_remainder());
+}
+
+fn decrypt(encrypt: &mut Ecb, decrypt: &mut Ecb, iv: &[u8; BLOCK], payload: &mut [u8]) {
+    let mut chain = *iv;
+    let mut blocks = payload.chunks_exact_mut(BLOCK);
+    for block in &mut blocks {
+        let mut plain = [0; BLOCK];
+        plain.copy_from_slice(block);
+        decrypt.block(&mut plain);
+        xor(&mut plain, &chain);
+        chain.copy_from_slice(block);
+        block.copy_from_slice(&plain);
+    }
+    terminate(encrypt, chain, blocks.into_remainder());
+}
+
+/// Where the payload of `pkt` starts, if it has one
+fn payload_start(pkt: &[u8]) -> Option<usize> {
+    psi::payload(pkt).filter(|payload| !payload.is_empty()).map(|payload| pkt.len() - payload.len())
+}
+
+pub struct Encryptor {
+    ecb: Ecb,
+    iv: [u8; BLOCK],
+    stats: Arc<Stats>,
+    /// Tables the PMT PIDs were taken from
+    generation: Option<u64>,
+    pmt_pids: Vec<u16>,
+}
+
+impl Encryptor {
+    fn update(&mut self) {
+        let generation = self.stats.programs_generation();
+        if self.generation != Some(generation) {
+            self.pmt_pids = self.stats.pmt_pids();
+            self.generation = Some(generation);
+        }
+    }
+
+    /// Not a table, nor stuffing, nor encrypted already
+    fn encrypts(&self, pkt: &[u8]) -> bool {
+        let pid = ts::pid(pkt);
+        pkt[3] & SCRAMBLING == 0 && pid > LAST_TABLE_PID && pid != NULL_PID && !self.pmt_pids.contains(&pid)
+    }
+
+    /// `chunk` with the payloads of its packets encrypted
+    pub fn apply(&mut self, chunk: &Bytes) -> Bytes {
+        if !ts::is_aligned(chunk) {
+            return chunk.clone();
+        }
+        self.update();
+
+        let mut out = BytesMut::from(&chunk[..]);
+        for pkt in out.chunks_mut(ts::PACKET_SIZE) {
+            if !self.encrypts(pkt) {
+                continue;
+            }
+            if let Some(start) = payload_start(pkt) {
+                pkt[3] |= EVEN_KEY;
+                encrypt(&mut self.ecb, &self.iv, &mut pkt[start..]);
+            }
+        }
+        out.freeze()
+    }
+}
+
+pub struct Decryptor {
+    encrypt: Ecb,
+    decrypt: Ecb,
+    iv: [u8; BLOCK],
+}
+
+impl Decryptor {
+    /// Decrypt the payloads of the packets of `chunk` marked as encrypted
+    pub fn apply(&mut self, chunk: &mut BytesMut) {
+        if !ts::is_aligned(chunk) {
+            return;
+        }
+
+        for pkt in chunk.chunks_mut(ts::PACKET_SIZE) {
+            if pkt[3] & SCRAMBLING != EVEN_KEY {
+                continue;
+            }
+            if let Some(start) = payload_start(pkt) {
+                pkt[3] &= !SCRAMBLING;
+                decrypt(&mut self.encrypt, &mut self.decrypt, &self.iv, &mut pkt[start..]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(pid: u16, adaptation: Option<u8>) -> Vec<u8> {
+        let mut pkt = vec![ts::SYNC_BYTE, (pid >> 8) as u8, pid as u8, 0x10];
+        if let Some(len) = adaptation {
+            pkt[3] = 0x30;
+            pkt.push(len);
+            let end = pkt.len() + usize::from(len);
+            pkt.resize(end, 0xff);
+        }
+        pkt.extend((0..).map(|i: u32| i as u8).take(ts::PACKET_SIZE - pkt.len()));
+        pkt
+    }
+
+    #[test]
+    fn round_trip() {
+        let aes = Aes::new("000102030405060708090a0b0c0d0e0f".parse().unwrap(), Some("f0e0d0c0b0a090807060504030201000".parse().unwrap()));
+        let mut encryptor = aes.encryptor(Arc::new(Stats::default()));
+        let mut decryptor = aes.decryptor();
+
+        // Payloads of 184 bytes, a few blocks, less than a block, none
+        let packets = [packet(0x100, None), packet(0x100, Some(100)), packet(0x100, Some(175)), packet(0x100, Some(183)),
+                       packet(0, None), packet(NULL_PID, None)];
+        let chunk: Vec<u8> = packets.concat();
+
+        let encrypted = encryptor.apply(&Bytes::from(chunk.clone()));
+        assert_eq!(encrypted.len(), chunk.len());
+        let pkts: Vec<_> = encrypted.chunks(ts::PACKET_SIZE).collect();
+        for (pkt, orig) in pkts.iter().zip(&packets).take(3) {
+            assert_eq!(pkt[3] & SCRAMBLING, EVEN_KEY);
+            let start = payload_start(orig).unwrap();
+            assert_eq!((&pkt[..3], &pkt[4..start]), (&orig[..3], &orig[4..start]));
+            assert_ne!(pkt[start..], orig[start..]);
+        }
+        // Plain CBC up to the residual
+        let mut cbc = Crypter::new(Cipher::aes_128_cbc(), Mode::Encrypt, &aes.key.0, Some(&aes.iv.0)).unwrap();
+        cbc.pad(false);
+        let mut out = vec![0; 12 * BLOCK];
+        let n = cbc.update(&packets[0][4..180], &mut out).unwrap();
+        assert_eq!(pkts[0][4..180], out[..n]);
+        // Nothing to encrypt, and the PAT and the null packets stay in the clear
+        assert_eq!(pkts[3][..], packets[3][..]);
+        assert_eq!(pkts[4][..], packets[4][..]);
+        assert_eq!(pkts[5][..], packets[5][..]);
+
+        let mut decrypted = BytesMut::from(&encrypted[..]);
+        decryptor.apply(&mut decrypted);
+        assert_eq!(decrypted[..], chunk[..]);
+
+        // Out of sync, left alone
+        let line = Bytes::from_static(b"RESUMED 0\n");
+        assert_eq!(encryptor.apply(&line), line);
+    }
+
+    #[test]
+    fn keys() {
+        let key: Key = "000102030405060708090A0B0C0D0E0F".parse().unwrap();
+        assert_eq!(format!("{:?}", key), "Key(..)");
+        assert!("0001".parse::<Key>().is_err());
+        assert!("+00102030405060708090a0b0c0d0e0f".parse::<Key>().is_err());
+        assert!("g00102030405060708090a0b0c0d0e0f".parse::<Iv>().is_err());
+    }
+}
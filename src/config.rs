@@ -0,0 +1,764 @@
+//! TOML configuration file
+//!
+//! Every key is optional, the command line flags given explicitly win over the file.
+
+use serde::de::{self, Deserialize, Deserializer};
+use serde_derive::Deserialize;
+use structopt::clap::ArgMatches;
+
+use std::fmt::Display;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::Config;
+use crate::logging::LogFormat;
+use restream::{parse_bitrate, parse_dscp, parse_mode, parse_packet_size, parse_pid, parse_size, secs_duration, AccessFormat, Burst, Cidr, Fsync, Input, Iv, Key, Output, Overflow, Priority, Remap, UdpTarget};
+
+/// Deserialize a string with `FromStr`
+fn parsed<'de, D, T>(d: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Option::<String>::deserialize(d)?
+        .map(|s| s.parse().map_err(de::Error::custom))
+        .transpose()
+}
+
+/// Deserialize a list of strings with `FromStr`
+fn parsed_list<'de, D, T>(d: D) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Option::<Vec<String>>::deserialize(d)?
+        .map(|v| v.iter().map(|s| s.parse().map_err(de::Error::custom)).collect())
+        .transpose()
+}
+
+/// Deserialize a size such as `512M`
+fn size<'de, D>(d: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(d)?
+        .map(|s| parse_size(&s).map_err(de::Error::custom))
+        .transpose()
+}
+
+/// Deserialize seconds such as `1.5`, refusing the negative ones and those
+/// longer than a year
+fn secs<'de, D>(d: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<f64>::deserialize(d)?
+        .map(|secs| secs_duration(secs).ok_or_else(|| de::Error::custom(format!("Invalid duration {}, use seconds such as 1.5, up to a year", secs))))
+        .transpose()
+}
+
+/// Deserialize a rate of connections per second, finite and above zero
+fn accept_rate<'de, D>(d: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<f64>::deserialize(d)?
+        .map(|rate| match rate {
+            rate if rate.is_finite() && rate > 0.0 => Ok(rate),
+            _ => Err(de::Error::custom(format!("Invalid accept rate {}, use connections per second such as 5 or 0.5", rate))),
+        })
+        .transpose()
+}
+
+/// Deserialize a bitrate such as `2M`
+fn bitrate<'de, D>(d: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(d)?
+        .map(|s| parse_bitrate(&s).map_err(de::Error::custom))
+        .transpose()
+}
+
+/// Deserialize an octal mode such as `660`
+fn mode<'de, D>(d: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(d)?
+        .map(|s| parse_mode(&s).map_err(de::Error::custom))
+        .transpose()
+}
+
+/// Deserialize a DSCP such as `EF` or `46`
+fn dscp<'de, D>(d: D) -> Result<Option<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(d)?
+        .map(|s| parse_dscp(&s).map_err(de::Error::custom))
+        .transpose()
+}
+
+/// Deserialize a packet size, checking it is a known one
+fn packet_size<'de, D>(d: D) -> Result<Option<usize>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<u64>::deserialize(d)?
+        .map(|size| parse_packet_size(&size.to_string()).map_err(de::Error::custom))
+        .transpose()
+}
+
+/// Deserialize a list of PIDs, checking their range
+fn pids<'de, D>(d: D) -> Result<Option<Vec<u16>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<Vec<u64>>::deserialize(d)?
+        .map(|v| v.iter().map(|pid| parse_pid(&pid.to_string()).map_err(de::Error::custom)).collect())
+        .transpose()
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct File {
+    port: Option<u16>,
+    channels: Option<u16>,
+    input_host: Option<String>,
+    output_host: Option<String>,
+    buffer: Option<usize>,
+    align: Option<bool>,
+    stream_keys: Option<bool>,
+    nodelay: Option<bool>,
+    #[serde(deserialize_with = "secs")]
+    tcp_keepalive: Option<Duration>,
+    #[serde(deserialize_with = "dscp")]
+    dscp: Option<u8>,
+    proxy_protocol: Option<bool>,
+    #[serde(deserialize_with = "accept_rate")]
+    accept_rate: Option<f64>,
+    backlog: Option<u32>,
+    #[serde(deserialize_with = "secs")]
+    bind_retry: Option<Duration>,
+    reuseport: Option<usize>,
+    #[serde(deserialize_with = "parsed")]
+    local_addr: Option<IpAddr>,
+    bind_device: Option<String>,
+    user: Option<String>,
+    group: Option<String>,
+    #[serde(deserialize_with = "secs")]
+    shutdown_timeout: Option<Duration>,
+    #[serde(deserialize_with = "mode")]
+    socket_mode: Option<u32>,
+    #[serde(deserialize_with = "parsed")]
+    log_format: Option<LogFormat>,
+
+    input: InputSection,
+    producer: ProducerSection,
+    consumers: ConsumersSection,
+    udp_out: UdpOutSection,
+    filter: FilterSection,
+    push: PushSection,
+    srt: SrtSection,
+    play: PlaySection,
+    generate: GenerateSection,
+    delay: DelaySection,
+    record: RecordSection,
+    sink: SinkSection,
+    hls: HlsSection,
+    monitoring: MonitoringSection,
+    webhook: WebhookSection,
+    access_log: AccessLogSection,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct InputSection {
+    #[serde(deserialize_with = "parsed")]
+    url: Option<Input>,
+    udp: Option<bool>,
+    stdin: Option<bool>,
+    exit_on_stdin_eof: Option<bool>,
+    #[serde(deserialize_with = "secs")]
+    udp_timeout: Option<Duration>,
+    rtp: Option<bool>,
+    iface: Option<String>,
+    #[serde(deserialize_with = "parsed")]
+    backup: Option<Input>,
+    merge_window: Option<usize>,
+    #[serde(deserialize_with = "secs")]
+    failover_timeout: Option<Duration>,
+    #[serde(deserialize_with = "secs")]
+    failback_delay: Option<Duration>,
+    pull: Option<String>,
+    #[serde(deserialize_with = "secs")]
+    pull_timeout: Option<Duration>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct ProducerSection {
+    takeover: Option<bool>,
+    token: Option<String>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    tls_ca: Option<PathBuf>,
+    #[serde(deserialize_with = "parsed")]
+    decrypt_key: Option<Key>,
+    #[serde(deserialize_with = "parsed")]
+    decrypt_iv: Option<Iv>,
+    #[serde(deserialize_with = "secs")]
+    stall_timeout: Option<Duration>,
+    disconnect_consumers_on_stall: Option<bool>,
+    validate: Option<bool>,
+    sync_loss_budget: Option<usize>,
+    #[serde(deserialize_with = "packet_size")]
+    packet_size: Option<usize>,
+    #[serde(deserialize_with = "size")]
+    max_read_buffer: Option<u64>,
+    read_pool: Option<usize>,
+    #[serde(deserialize_with = "parsed_list")]
+    allow: Option<Vec<Cidr>>,
+    #[serde(deserialize_with = "parsed_list")]
+    deny: Option<Vec<Cidr>>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct ConsumersSection {
+    #[serde(deserialize_with = "parsed")]
+    url: Option<Output>,
+    http: Option<bool>,
+    ws_port: Option<u16>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    #[serde(deserialize_with = "parsed")]
+    encrypt_key: Option<Key>,
+    #[serde(deserialize_with = "parsed")]
+    encrypt_iv: Option<Iv>,
+    wait_for_producer: Option<bool>,
+    max: Option<usize>,
+    queue: Option<usize>,
+    #[serde(deserialize_with = "parsed")]
+    overflow_policy: Option<Overflow>,
+    #[serde(deserialize_with = "size")]
+    write_batch: Option<u64>,
+    max_lag_bytes: Option<usize>,
+    #[serde(deserialize_with = "secs")]
+    max_lag_secs: Option<Duration>,
+    #[serde(deserialize_with = "secs")]
+    idle_timeout: Option<Duration>,
+    #[serde(deserialize_with = "secs")]
+    keepalive_nulls: Option<Duration>,
+    options: Option<bool>,
+    relay_resume: Option<bool>,
+    #[serde(deserialize_with = "secs")]
+    reconnect_grace: Option<Duration>,
+    #[serde(deserialize_with = "bitrate")]
+    rate_limit: Option<u64>,
+    #[serde(deserialize_with = "bitrate")]
+    total_rate_limit: Option<u64>,
+    clean_start: Option<bool>,
+    #[serde(deserialize_with = "secs")]
+    clean_start_timeout: Option<Duration>,
+    #[serde(deserialize_with = "parsed")]
+    burst: Option<Burst>,
+    #[serde(deserialize_with = "secs")]
+    dvr_window: Option<Duration>,
+    #[serde(deserialize_with = "size")]
+    dvr_max_bytes: Option<u64>,
+    #[serde(deserialize_with = "parsed_list")]
+    allow: Option<Vec<Cidr>>,
+    #[serde(deserialize_with = "parsed_list")]
+    deny: Option<Vec<Cidr>>,
+    #[serde(deserialize_with = "parsed_list")]
+    priority: Option<Vec<Priority>>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct UdpOutSection {
+    #[serde(deserialize_with = "parsed_list")]
+    targets: Option<Vec<UdpTarget>>,
+    packets: Option<usize>,
+    ttl: Option<u32>,
+    rtp: Option<bool>,
+    rtp_ssrc: Option<u32>,
+    rtp_pt: Option<u8>,
+    pace_pcr: Option<bool>,
+    #[serde(deserialize_with = "secs")]
+    pace_depth: Option<Duration>,
+    #[serde(deserialize_with = "bitrate")]
+    cbr: Option<u64>,
+    #[serde(deserialize_with = "dscp")]
+    dscp: Option<u8>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct FilterSection {
+    #[serde(deserialize_with = "pids")]
+    drop_pid: Option<Vec<u16>>,
+    #[serde(deserialize_with = "pids")]
+    keep_pid: Option<Vec<u16>>,
+    program: Option<u16>,
+    #[serde(deserialize_with = "parsed_list")]
+    remap_pid: Option<Vec<Remap>>,
+    strip_nulls: Option<bool>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct PushSection {
+    targets: Option<Vec<String>>,
+    #[serde(deserialize_with = "secs")]
+    retry_min: Option<Duration>,
+    #[serde(deserialize_with = "secs")]
+    retry_max: Option<Duration>,
+    rist_buffer: Option<u64>,
+    #[serde(deserialize_with = "dscp")]
+    dscp: Option<u8>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct SrtSection {
+    latency: Option<u64>,
+    passphrase: Option<String>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct PlaySection {
+    file: Option<PathBuf>,
+    bitrate: Option<u64>,
+    slate: Option<bool>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct GenerateSection {
+    #[serde(deserialize_with = "bitrate")]
+    bitrate: Option<u64>,
+    only: Option<bool>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct DelaySection {
+    #[serde(deserialize_with = "secs")]
+    secs: Option<Duration>,
+    #[serde(deserialize_with = "size")]
+    memory: Option<u64>,
+    pad_during_prime: Option<bool>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct RecordSection {
+    dir: Option<PathBuf>,
+    #[serde(deserialize_with = "size")]
+    max_size: Option<u64>,
+    #[serde(deserialize_with = "secs")]
+    duration: Option<Duration>,
+    #[serde(deserialize_with = "parsed")]
+    fsync: Option<Fsync>,
+    splice: Option<bool>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct HlsSection {
+    enabled: Option<bool>,
+    dir: Option<PathBuf>,
+    #[serde(deserialize_with = "secs")]
+    segment_duration: Option<Duration>,
+    window: Option<usize>,
+    port: Option<u16>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct SinkSection {
+    stdout: Option<bool>,
+    file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct MonitoringSection {
+    metrics_port: Option<u16>,
+    status_port: Option<u16>,
+    #[serde(deserialize_with = "secs")]
+    health_max_idle: Option<Duration>,
+    control_socket: Option<PathBuf>,
+    #[serde(deserialize_with = "secs")]
+    pat_interval: Option<Duration>,
+    #[serde(deserialize_with = "secs")]
+    pmt_interval: Option<Duration>,
+    #[serde(deserialize_with = "secs")]
+    pid_timeout: Option<Duration>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct WebhookSection {
+    url: Option<String>,
+    consumer_thresholds: Option<Vec<usize>>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct AccessLogSection {
+    file: Option<PathBuf>,
+    #[serde(deserialize_with = "parsed")]
+    format: Option<AccessFormat>,
+}
+
+impl FromStr for File {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s).map_err(|e| e.to_string())
+    }
+}
+
+/// Read and parse the configuration file
+pub fn load(path: &Path) -> Result<File, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+
+    content.parse()
+        .map_err(|e| format!("Invalid configuration {}: {}", path.display(), e))
+}
+
+/// Set the `Config` fields from the file, unless given on the command line
+macro_rules! merge {
+    ($cfg:ident, $matches:ident, { $($field:ident: $value:expr,)* }) => {
+        $(
+            // The arguments are named after the fields, in kebab-case
+            if $matches.occurrences_of(stringify!($field).replace('_', "-")) == 0 {
+                if let Some(value) = $value {
+                    $cfg.$field = value;
+                }
+            }
+        )*
+    }
+}
+
+impl File {
+    pub fn merge(self, cfg: &mut Config, matches: &ArgMatches) {
+        let File { input, producer, consumers, udp_out, filter, push, srt, play, generate, delay, record, sink, hls, monitoring, webhook, access_log, .. } = self;
+
+        merge!(cfg, matches, {
+            port: self.port,
+            channels: self.channels,
+            input_host: self.input_host,
+            output_host: self.output_host,
+            buffer: self.buffer,
+            no_align: self.align.map(|align| !align),
+            stream_keys: self.stream_keys,
+            no_nodelay: self.nodelay.map(|nodelay| !nodelay),
+            tcp_keepalive: self.tcp_keepalive.map(Some),
+            dscp: self.dscp.map(Some),
+            proxy_protocol: self.proxy_protocol,
+            accept_rate: self.accept_rate.map(Some),
+            backlog: self.backlog,
+            bind_retry: self.bind_retry.map(Some),
+            reuseport: self.reuseport,
+            local_addr: self.local_addr.map(Some),
+            bind_device: self.bind_device.map(Some),
+            user: self.user.map(Some),
+            group: self.group.map(Some),
+            shutdown_timeout: self.shutdown_timeout,
+            socket_mode: self.socket_mode.map(Some),
+            log_format: self.log_format,
+
+            input: input.url.map(Some),
+            udp_input: input.udp,
+            stdin: input.stdin,
+            exit_on_stdin_eof: input.exit_on_stdin_eof,
+            udp_timeout: input.udp_timeout,
+            rtp_in: input.rtp,
+            input_iface: input.iface.map(Some),
+            input_backup: input.backup.map(Some),
+            merge_window: input.merge_window,
+            failover_timeout: input.failover_timeout.map(Some),
+            failback_delay: input.failback_delay,
+            pull: input.pull.map(Some),
+            pull_timeout: input.pull_timeout.map(Some),
+
+            producer_takeover: producer.takeover,
+            producer_token: producer.token.map(Some),
+            producer_tls_cert: producer.tls_cert.map(Some),
+            producer_tls_key: producer.tls_key.map(Some),
+            producer_tls_ca: producer.tls_ca.map(Some),
+            decrypt_key: producer.decrypt_key.map(Some),
+            decrypt_iv: producer.decrypt_iv.map(Some),
+            producer_stall_timeout: producer.stall_timeout.map(Some),
+            disconnect_consumers_on_stall: producer.disconnect_consumers_on_stall,
+            validate_input: producer.validate,
+            sync_loss_budget: producer.sync_loss_budget,
+            packet_size: producer.packet_size.map(Some),
+            max_read_buffer: producer.max_read_buffer,
+            read_pool: producer.read_pool,
+            allow_producer: producer.allow,
+            deny_producer: producer.deny,
+
+            output: consumers.url.map(Some),
+            http_out: consumers.http,
+            ws_port: consumers.ws_port.map(Some),
+            tls_cert: consumers.tls_cert.map(Some),
+            tls_key: consumers.tls_key.map(Some),
+            encrypt_key: consumers.encrypt_key.map(Some),
+            encrypt_iv: consumers.encrypt_iv.map(Some),
+            wait_for_producer: consumers.wait_for_producer,
+            max_consumers: consumers.max.map(Some),
+            consumer_queue: consumers.queue,
+            overflow_policy: consumers.overflow_policy,
+            write_batch: consumers.write_batch,
+            max_lag_bytes: consumers.max_lag_bytes.map(Some),
+            max_lag_secs: consumers.max_lag_secs.map(Some),
+            consumer_idle_timeout: consumers.idle_timeout.map(Some),
+            keepalive_nulls: consumers.keepalive_nulls.map(Some),
+            consumer_options: consumers.options,
+            relay_resume: consumers.relay_resume,
+            reconnect_grace: consumers.reconnect_grace.map(Some),
+            consumer_rate_limit: consumers.rate_limit.map(Some),
+            total_rate_limit: consumers.total_rate_limit.map(Some),
+            clean_start: consumers.clean_start,
+            clean_start_timeout: consumers.clean_start_timeout,
+            burst: consumers.burst.map(Some),
+            dvr_window: consumers.dvr_window.map(Some),
+            dvr_max_bytes: consumers.dvr_max_bytes,
+            allow_consumer: consumers.allow,
+            deny_consumer: consumers.deny,
+            consumer_priority: consumers.priority,
+
+            udp_out: udp_out.targets,
+            udp_packets: udp_out.packets,
+            ttl: udp_out.ttl.map(Some),
+            rtp_out: udp_out.rtp,
+            rtp_ssrc: udp_out.rtp_ssrc.map(Some),
+            rtp_pt: udp_out.rtp_pt.map(Some),
+            pace_pcr: udp_out.pace_pcr,
+            pace_depth: udp_out.pace_depth,
+            cbr: udp_out.cbr.map(Some),
+            udp_out_dscp: udp_out.dscp.map(Some),
+
+            drop_pid: filter.drop_pid,
+            keep_pid: filter.keep_pid,
+            program: filter.program.map(Some),
+            remap_pid: filter.remap_pid,
+            strip_nulls: filter.strip_nulls,
+
+            push: push.targets,
+            push_retry_min: push.retry_min,
+            push_retry_max: push.retry_max,
+            rist_buffer: push.rist_buffer,
+            push_dscp: push.dscp.map(Some),
+
+            srt_latency: srt.latency.map(Some),
+            srt_passphrase: srt.passphrase.map(Some),
+
+            play: play.file.map(Some),
+            play_bitrate: play.bitrate.map(Some),
+            slate: play.slate,
+
+            generate: generate.bitrate.map(Some),
+            generate_only: generate.only,
+
+            delay: delay.secs.map(Some),
+            delay_memory: delay.memory,
+            pad_during_prime: delay.pad_during_prime,
+
+            record: record.dir.map(Some),
+            record_max_size: record.max_size.map(Some),
+            record_duration: record.duration.map(Some),
+            record_fsync: record.fsync,
+            record_splice: record.splice,
+
+            stdout: sink.stdout,
+            sink: sink.file.map(Some),
+
+            hls: hls.enabled,
+            hls_dir: hls.dir.map(Some),
+            hls_segment_duration: hls.segment_duration,
+            hls_window: hls.window,
+            hls_port: hls.port.map(Some),
+
+            metrics_port: monitoring.metrics_port.map(Some),
+            status_port: monitoring.status_port.map(Some),
+            health_max_idle: monitoring.health_max_idle,
+            pat_interval: monitoring.pat_interval,
+            pmt_interval: monitoring.pmt_interval,
+            pid_timeout: monitoring.pid_timeout,
+            control_socket: monitoring.control_socket.map(Some),
+
+            webhook: webhook.url.map(Some),
+            webhook_threshold: webhook.consumer_thresholds,
+
+            access_log: access_log.file.map(Some),
+            access_log_format: access_log.format,
+        });
+    }
+}
+
+/// List the `Config` fields that differ
+macro_rules! changed {
+    ($old:ident, $new:ident, [ $($field:ident),* ]) => {
+        {
+            let mut keys = Vec::new();
+            $(
+                if format!("{:?}", $old.$field) != format!("{:?}", $new.$field) {
+                    keys.push(stringify!($field));
+                }
+            )*
+            keys
+        }
+    }
+}
+
+/// Settings that are applied only on startup, e.g. the ones binding sockets
+pub fn restart_required(old: &Config, new: &Config) -> Vec<&'static str> {
+    changed!(old, new, [
+        port, channels, input_host, output_host, buffer, no_align, stream_keys, backlog, bind_retry, reuseport, local_addr, bind_device, user, group, shutdown_timeout, socket_mode, log_format,
+        input, udp_input, stdin, exit_on_stdin_eof, udp_timeout, rtp_in, input_iface, input_backup, merge_window, failover_timeout, failback_delay, pull, pull_timeout,
+        producer_takeover, producer_token, producer_tls_cert, producer_tls_key, producer_tls_ca, producer_stall_timeout, disconnect_consumers_on_stall, keepalive_nulls,
+        output, http_out, ws_port, tls_cert, tls_key, wait_for_producer, burst, dvr_window, dvr_max_bytes, total_rate_limit,
+        udp_out, udp_packets, ttl, rtp_out, rtp_ssrc, rtp_pt, pace_pcr, pace_depth, cbr, udp_out_dscp,
+        drop_pid, keep_pid, program, remap_pid, strip_nulls,
+        push, push_retry_min, push_retry_max, rist_buffer, push_dscp, srt_latency, srt_passphrase,
+        play, play_bitrate, slate, generate, generate_only, delay, delay_memory, pad_during_prime,
+        record, record_max_size, record_duration, record_fsync, record_splice,
+        stdout, sink,
+        hls, hls_dir, hls_segment_duration, hls_window, hls_port,
+        metrics_port, status_port, health_max_idle, control_socket,
+        webhook, webhook_threshold,
+        access_log, access_log_format
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    fn config(args: &[&str], file: &str) -> Config {
+        let matches = Config::clap().get_matches_from(args);
+        let mut cfg = Config::from_clap(&matches);
+        file.parse::<File>().unwrap().merge(&mut cfg, &matches);
+        cfg
+    }
+
+    #[test]
+    fn example() {
+        let cfg = config(&["restream"], include_str!("../tests/restream.toml"));
+
+        assert_eq!(cfg.port, 12345);
+        assert_eq!(cfg.output_host, "0.0.0.0");
+        assert_eq!(cfg.socket_mode, Some(0o660));
+        assert!(cfg.proxy_protocol);
+        assert_eq!(cfg.accept_rate, Some(5.0));
+        assert_eq!((cfg.backlog, cfg.bind_retry, cfg.reuseport), (4096, Some(Duration::from_secs(10)), 4));
+        assert_eq!((cfg.dscp, cfg.udp_out_dscp, cfg.push_dscp), (Some(34), Some(46), None));
+        assert_eq!((cfg.user.as_deref(), cfg.group), (Some("restream"), None));
+        assert_eq!((cfg.local_addr, cfg.bind_device.as_deref()), (Some("192.0.2.10".parse().unwrap()), Some("eth1")));
+        assert!(!cfg.udp_input);
+        assert_eq!((cfg.input_backup.is_none(), cfg.merge_window), (true, 32));
+        assert_eq!((cfg.failover_timeout, cfg.failback_delay), (None, Duration::from_secs(10)));
+        assert_eq!(cfg.producer_token, Some("secret".to_owned()));
+        assert_eq!(cfg.allow_producer.len(), 1);
+        assert_eq!(cfg.consumer_priority, vec!["2=10.0.0.0/8".parse().unwrap(), "1=:8081".parse::<Priority>().unwrap()]);
+        assert_eq!(cfg.max_consumers, Some(100));
+        assert_eq!(cfg.ws_port, Some(8081));
+        assert_eq!((cfg.consumer_rate_limit, cfg.total_rate_limit), (Some(20_000_000), Some(100_000_000)));
+        assert_eq!(cfg.overflow_policy, Overflow::Disconnect);
+        assert_eq!((cfg.consumer_options, cfg.relay_resume), (false, true));
+        assert_eq!(cfg.reconnect_grace, Some(Duration::from_secs(5)));
+        assert_eq!(cfg.keepalive_nulls, Some(Duration::from_secs(2)));
+        assert_eq!(cfg.write_batch, 16 * 1024);
+        assert_eq!((cfg.clean_start, cfg.clean_start_timeout), (true, Duration::from_millis(1500)));
+        assert_eq!((cfg.dvr_window, cfg.dvr_max_bytes), (Some(Duration::from_secs(60)), 128 * 1024 * 1024));
+        assert_eq!((cfg.validate_input, cfg.sync_loss_budget), (true, 5));
+        assert_eq!(cfg.packet_size, Some(204));
+        assert_eq!(cfg.read_pool, 64);
+        assert_eq!(cfg.udp_out.len(), 2);
+        assert_eq!((cfg.udp_out[0].cbr, cfg.cbr), (Some(8_000_000), None));
+        assert!(cfg.udp_out[1].rtp);
+        assert_eq!(cfg.drop_pid, vec![0x1ff0, 0x1ff1]);
+        assert_eq!(cfg.remap_pid, vec![Remap { from: 0x1011, to: 0x100 }, Remap { from: 0x1012, to: 0x101 }]);
+        assert_eq!(cfg.push, vec!["tcp://relay.example.com:9000"]);
+        assert_eq!((cfg.srt_latency, cfg.srt_passphrase), (Some(200), None));
+        assert_eq!(cfg.rist_buffer, 1500);
+        assert_eq!(cfg.webhook_threshold, vec![1, 50, 100]);
+        assert_eq!((cfg.generate, cfg.generate_only), (None, false));
+        assert_eq!((cfg.delay, cfg.delay_memory, cfg.pad_during_prime), (Some(Duration::from_secs(7)), 128 * 1024 * 1024, true));
+        assert_eq!((cfg.record_max_size, cfg.record_splice), (Some(512 * 1024 * 1024), true));
+        assert_eq!((cfg.hls, cfg.hls_segment_duration, cfg.hls_window), (false, Duration::from_secs(4), 6));
+        assert_eq!((cfg.metrics_port, cfg.health_max_idle), (Some(9100), Duration::from_secs(10)));
+        assert_eq!((cfg.pat_interval, cfg.pmt_interval, cfg.pid_timeout), (Duration::from_millis(500), Duration::from_secs(1), Duration::from_secs(10)));
+        assert_eq!(cfg.access_log_format, AccessFormat::Json);
+    }
+
+    #[test]
+    fn defaults() {
+        let cfg = config(&["restream"], "");
+
+        assert_eq!(cfg.port, 12345);
+        assert_eq!(cfg.consumer_queue, 1024);
+        assert!(cfg.udp_out.is_empty());
+    }
+
+    #[test]
+    fn cli_wins() {
+        let cfg = config(&["restream", "-p", "4000", "--deny-consumer", "10.0.0.1/32"],
+                         "port = 5000\nbuffer = 188\n[consumers]\ndeny = [\"10.0.0.0/8\"]\n");
+
+        assert_eq!(cfg.port, 4000);
+        assert_eq!(cfg.buffer, 188);
+        assert_eq!(cfg.deny_consumer.len(), 1);
+        assert!(!cfg.deny_consumer[0].contains("10.0.0.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn restart() {
+        let old = config(&["restream"], "[consumers]\nmax = 10\n");
+        let new = config(&["restream"], "port = 4000\n[consumers]\nmax = 20\n");
+
+        assert_eq!(restart_required(&old, &new), vec!["port"]);
+    }
+
+    #[test]
+    fn errors_name_the_key() {
+        let err = "port = \"http\"".parse::<File>().unwrap_err();
+        assert!(err.contains("port"), "{}", err);
+
+        let err = "[consumers]\noverflow_policy = \"block\"".parse::<File>().unwrap_err();
+        assert!(err.contains("overflow_policy"), "{}", err);
+
+        let err = "[filter]\ndrop_pid = [8192]".parse::<File>().unwrap_err();
+        assert!(err.contains("drop_pid"), "{}", err);
+
+        let err = "[producer]\ntakover = true".parse::<File>().unwrap_err();
+        assert!(err.contains("takover"), "{}", err);
+
+        let err = "[consumers]\nmax_lag_secs = -1.0".parse::<File>().unwrap_err();
+        assert!(err.contains("max_lag_secs"), "{}", err);
+
+        let err = "[consumers]\nidle_timeout = 1e20".parse::<File>().unwrap_err();
+        assert!(err.contains("idle_timeout"), "{}", err);
+
+        let err = "[consumers]\nidle_timeout = 4e7".parse::<File>().unwrap_err();
+        assert!(err.contains("idle_timeout"), "{}", err);
+
+        let err = "accept_rate = 0.0".parse::<File>().unwrap_err();
+        assert!(err.contains("accept_rate"), "{}", err);
+    }
+}
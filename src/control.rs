@@ -0,0 +1,110 @@
+//! Local control socket
+//!
+//! One command per line:
+//! - `list` prints the producer and the consumers with their counters
+//! - `kick ADDR` disconnects a consumer
+//! - `drop-producer` disconnects the producer
+
+use tokio::net::UnixListener;
+use tokio::time;
+use tokio_util::codec::{Framed, LinesCodec};
+use futures::prelude::*;
+use log::info;
+
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use crate::net;
+use crate::Shared;
+
+fn list(state: &Arc<Mutex<Shared>>) -> String {
+    let stats = state.lock().unwrap().stats.clone();
+    let (producer, consumers) = stats.peers();
+
+    let mut out = String::new();
+    if let Some(p) = producer {
+        let _ = writeln!(out, "producer {} received={}", p.addr, p.bytes());
+    }
+    for c in consumers {
+        let _ = writeln!(out, "consumer {} sent={} queued={} dropped={}",
+                         c.addr, c.bytes(), c.queued.load(Ordering::Relaxed), c.dropped());
+    }
+    out.push_str("ok");
+
+    out
+}
+
+fn kick(state: &Arc<Mutex<Shared>>, addr: &str) -> String {
+    let addr: SocketAddr = match addr.parse() {
+        Ok(addr) => addr,
+        Err(_) => return format!("error: invalid address {}", addr),
+    };
+
+    // Dropping the Tx closes the queue, the consumer notices and finishes
+    let fanout = state.lock().unwrap().fanout.clone();
+
+    if fanout.remove(&addr) {
+        info!("Kicking {:?}", addr);
+        "ok".to_owned()
+    } else {
+        format!("error: no consumer {}", addr)
+    }
+}
+
+fn drop_producer(state: &Arc<Mutex<Shared>>) -> String {
+    match state.lock().unwrap().stop_producer() {
+        Some(addr) => {
+            info!("Dropping Producer ({:?}) on request", addr);
+            "ok".to_owned()
+        }
+        None => "error: no producer connected".to_owned(),
+    }
+}
+
+fn handle(state: &Arc<Mutex<Shared>>, line: &str) -> String {
+    let mut words = line.split_whitespace();
+
+    match (words.next(), words.next()) {
+        (Some("list"), None) => list(state),
+        (Some("kick"), Some(addr)) => kick(state, addr),
+        (Some("drop-producer"), None) => drop_producer(state),
+        (None, _) => String::new(),
+        _ => format!("error: unknown command {}", line.trim()),
+    }
+}
+
+/// Listen for commands on the unix socket at `path`, replacing a stale socket
+pub fn serve(path: &Path, state: Arc<Mutex<Shared>>) -> io::Result<impl Future<Output = ()>> {
+    if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+
+    let server = async move {
+        loop {
+            let socket = match listener.accept().await {
+                Ok((socket, _)) => socket,
+                // Out of file descriptors for a moment, not a reason to stop listening
+                Err(_) => {
+                    time::sleep(net::ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            };
+            let state = state.clone();
+            let (mut sink, stream) = Framed::new(socket, LinesCodec::new()).split();
+            let mut replies = stream.map_ok(move |line| handle(&state, &line));
+
+            tokio::spawn(async move {
+                let _ = sink.send_all(&mut replies).await;
+            });
+        }
+    };
+
+    Ok(server)
+}
@@ -0,0 +1,300 @@
+//! Fixed broadcast delay, every consumer getting the stream `--delay` seconds late
+//!
+//! The chunks go through a FIFO before the fan-out. They are kept in memory up
+//! to `--delay-memory` bytes, past that they are spilled to an unlinked
+//! temporary file until the FIFO drains. A thread of its own owns the FIFO, the
+//! producer only hands it the chunks, so neither the disk nor the waiting ever
+//! hold up the event loop.
+//!
+//! Until the first chunk comes out the consumers get nothing, or null packets
+//! with `--pad-during-prime`, rather than a shorter delay. The FIFO is emptied
+//! when the producer disconnects, and primed again for the next one.
+
+use bytes::Bytes;
+use log::{error, info};
+
+use std::collections::VecDeque;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::process;
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::fanout::Fanout;
+use crate::stats::Stats;
+use crate::ts;
+
+/// Spill files created so far, naming the next one
+static SPILLS: AtomicUsize = AtomicUsize::new(0);
+
+/// How late and where the delayed stream waits
+#[derive(Clone, Debug)]
+pub struct BroadcastDelay {
+    pub delay: Duration,
+    /// Bytes kept in memory, the next ones are spilled to disk
+    pub memory: usize,
+    /// Send null packets while the delay is priming
+    pub pad: bool,
+}
+
+enum Message {
+    Chunk(Instant, Bytes),
+    Clear,
+}
+
+/// The producer end of the delay, handing the chunks to its thread
+pub struct DelayLine {
+    tx: Sender<Message>,
+}
+
+impl DelayLine {
+    /// Delay `chunk`, never blocking
+    pub fn push(&self, chunk: Bytes) {
+        let _ = self.tx.send(Message::Chunk(Instant::now(), chunk));
+    }
+
+    /// Drop the chunks delayed, the stream is over
+    pub fn clear(&self) {
+        let _ = self.tx.send(Message::Clear);
+    }
+}
+
+/// Chunks spilled to disk, in order
+struct Spill {
+    file: File,
+    read: u64,
+    write: u64,
+    /// Arrival and length of each chunk
+    chunks: VecDeque<(Instant, usize)>,
+}
+
+impl Spill {
+    fn create() -> io::Result<Self> {
+        let n = SPILLS.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("restream-delay-{}-{}", process::id(), n));
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        // Gone with the file handle, where the platform allows it
+        let _ = fs::remove_file(&path);
+        info!("Spilling the delayed stream to {}", path.display());
+
+        Ok(Spill { file, read: 0, write: 0, chunks: VecDeque::new() })
+    }
+
+    fn push(&mut self, arrived: Instant, chunk: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.write))?;
+        self.file.write_all(chunk)?;
+        self.write += chunk.len() as u64;
+        self.chunks.push_back((arrived, chunk.len()));
+        Ok(())
+    }
+
+    fn pop(&mut self) -> io::Result<Option<(Instant, Bytes)>> {
+        let (arrived, len) = match self.chunks.pop_front() {
+            Some(chunk) => chunk,
+            None => return Ok(None),
+        };
+
+        let mut chunk = vec![0; len];
+        self.file.seek(SeekFrom::Start(self.read))?;
+        self.file.read_exact(&mut chunk)?;
+        self.read += len as u64;
+
+        if self.chunks.is_empty() {
+            self.clear()?;
+        }
+        Ok(Some((arrived, Bytes::from(chunk))))
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.chunks.clear();
+        self.read = 0;
+        self.write = 0;
+        self.file.set_len(0)
+    }
+}
+
+/// The delayed chunks, the ones in memory are older than the ones spilled
+struct Fifo {
+    memory: VecDeque<(Instant, Bytes)>,
+    bytes: usize,
+    max_memory: usize,
+    spill: Option<Spill>,
+}
+
+impl Fifo {
+    fn new(max_memory: usize) -> Self {
+        Fifo { memory: VecDeque::new(), bytes: 0, max_memory, spill: None }
+    }
+
+    fn spilled(&self) -> bool {
+        self.spill.as_ref().is_some_and(|spill| !spill.chunks.is_empty())
+    }
+
+    fn push(&mut self, arrived: Instant, chunk: Bytes) -> io::Result<()> {
+        // Once spilling, until the disk drains, or the order would be lost
+        if !self.spilled() && self.bytes + chunk.len() <= self.max_memory {
+            self.bytes += chunk.len();
+            self.memory.push_back((arrived, chunk));
+            return Ok(());
+        }
+
+        if self.spill.is_none() {
+            self.spill = Some(Spill::create()?);
+        }
+        self.spill.as_mut().unwrap().push(arrived, &chunk)
+    }
+
+    /// Arrival of the oldest chunk
+    fn front(&self) -> Option<Instant> {
+        self.memory.front().map(|&(arrived, _)| arrived)
+            .or_else(|| self.spill.as_ref().and_then(|spill| spill.chunks.front().map(|&(arrived, _)| arrived)))
+    }
+
+    fn pop(&mut self) -> io::Result<Option<(Instant, Bytes)>> {
+        if let Some((arrived, chunk)) = self.memory.pop_front() {
+            self.bytes -= chunk.len();
+            return Ok(Some((arrived, chunk)));
+        }
+        match self.spill {
+            Some(ref mut spill) => spill.pop(),
+            None => Ok(None),
+        }
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.memory.clear();
+        self.bytes = 0;
+        match self.spill {
+            Some(ref mut spill) => spill.clear(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Null packets about as long as `len` bytes
+fn nulls(len: usize) -> Bytes {
+    let mut packet = [0xff; ts::PACKET_SIZE];
+    packet[..4].copy_from_slice(&[ts::SYNC_BYTE, 0x1f, 0xff, 0x10]);
+    Bytes::from(packet.repeat((len / ts::PACKET_SIZE).max(1)))
+}
+
+fn run(delay: BroadcastDelay, rx: Receiver<Message>, fanout: Weak<Fanout>, stats: Option<Arc<Stats>>) {
+    let mut fifo = Fifo::new(delay.memory);
+    let mut primed = false;
+
+    loop {
+        let now = Instant::now();
+        // Never due if the delay is too long to tell when
+        let due_at = |arrived: Instant| arrived.checked_add(delay.delay);
+        while fifo.front().and_then(due_at).is_some_and(|due| due <= now) {
+            let fanout = match fanout.upgrade() {
+                Some(fanout) => fanout,
+                None => return,
+            };
+            match fifo.pop() {
+                Ok(Some((arrived, chunk))) => {
+                    fanout.forward(&chunk);
+                    if !primed {
+                        info!("Delay primed, broadcasting {:.1}s late", now.duration_since(arrived).as_secs_f64());
+                        primed = true;
+                    }
+                    if let Some(ref stats) = stats {
+                        stats.set_delay(Some(now.duration_since(arrived)));
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => error!("Cannot read back the delayed stream: {}", e),
+            }
+        }
+
+        let message = match fifo.front().and_then(due_at) {
+            Some(due) => rx.recv_timeout(due.saturating_duration_since(now)),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match message {
+            Ok(Message::Chunk(arrived, chunk)) => {
+                if !primed && delay.pad {
+                    if let Some(fanout) = fanout.upgrade() {
+                        fanout.pad(&nulls(chunk.len()));
+                    }
+                }
+                if let Err(e) = fifo.push(arrived, chunk) {
+                    error!("Cannot spill the delayed stream, dropping a chunk: {}", e);
+                }
+            }
+            Ok(Message::Clear) => {
+                if let Err(e) = fifo.clear() {
+                    error!("Cannot empty the delay spill file: {}", e);
+                }
+                primed = false;
+                if let Some(ref stats) = stats {
+                    stats.set_delay(None);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            // The fanout is gone
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+/// Delay every chunk broadcast by `fanout`, on a thread ending along with it
+pub fn spawn(delay: BroadcastDelay, fanout: &Arc<Fanout>, stats: Option<Arc<Stats>>) -> io::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let weak = Arc::downgrade(fanout);
+
+    if let Some(ref stats) = stats {
+        stats.set_delay_target(delay.delay);
+    }
+    thread::Builder::new().name("delay".to_owned()).spawn(move || run(delay, rx, weak, stats))?;
+    fanout.set_delay(DelayLine { tx });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spilled_in_order() {
+        let now = Instant::now();
+        let mut fifo = Fifo::new(1000);
+        for n in 0..20u8 {
+            fifo.push(now, Bytes::from(vec![n; 188])).unwrap();
+        }
+        assert_eq!((fifo.memory.len(), fifo.spill.as_ref().unwrap().chunks.len()), (5, 15));
+
+        let mut popped = Vec::new();
+        for n in 20..25u8 {
+            popped.push(fifo.pop().unwrap().unwrap().1[0]);
+            // Behind the spilled ones, even with room in memory again
+            fifo.push(now, Bytes::from(vec![n; 188])).unwrap();
+        }
+        while let Some((_, chunk)) = fifo.pop().unwrap() {
+            popped.push(chunk[0]);
+        }
+        assert_eq!(popped, (0..25).collect::<Vec<u8>>());
+        assert!(!fifo.spilled());
+
+        // The disk drained, back to memory
+        fifo.push(now, Bytes::from(vec![0; 188])).unwrap();
+        assert_eq!(fifo.memory.len(), 1);
+        fifo.clear().unwrap();
+        assert!(fifo.front().is_none());
+    }
+
+    #[test]
+    fn null_padding() {
+        let pad = nulls(7 * 188);
+        assert_eq!(pad.len(), 7 * 188);
+        assert!(ts::is_aligned(&pad));
+        assert!(pad.chunks(188).all(|pkt| ts::pid(pkt) == 0x1fff));
+        assert_eq!(nulls(100).len(), 188);
+    }
+}
@@ -0,0 +1,156 @@
+//! Time-shift buffer, letting the consumers start in the past
+//!
+//! The chunks broadcast over the last `--dvr-window` are kept in memory, no
+//! more than `--dvr-max-bytes` of them. Every chunk is numbered, a consumer
+//! rewinding follows them by number from where it started, so the replay
+//! never skips nor repeats one unless they are evicted under it.
+
+use bytes::Bytes;
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+pub struct Dvr {
+    window: Duration,
+    max_bytes: usize,
+    /// Oldest first, with the time they were broadcast
+    chunks: VecDeque<(Instant, Bytes)>,
+    /// Number of the oldest chunk kept, the next one is `first + chunks.len()`
+    first: u64,
+    bytes: usize,
+}
+
+impl Dvr {
+    pub fn new(window: Duration, max_bytes: usize) -> Self {
+        Dvr {
+            window,
+            max_bytes,
+            chunks: VecDeque::new(),
+            first: 0,
+            bytes: 0,
+        }
+    }
+
+    fn pop(&mut self) {
+        if let Some((_, old)) = self.chunks.pop_front() {
+            self.bytes -= old.len();
+            self.first += 1;
+        }
+    }
+
+    /// Keep a chunk, evicting the old ones first so the bounds always hold
+    pub fn push(&mut self, chunk: &Bytes) {
+        let now = Instant::now();
+        while self.chunks.front().is_some_and(|&(t, _)| now.duration_since(t) > self.window) {
+            self.pop();
+        }
+
+        // Too large to keep, numbered all the same
+        if chunk.len() > self.max_bytes {
+            self.clear();
+            self.first += 1;
+            return;
+        }
+
+        while self.bytes + chunk.len() > self.max_bytes {
+            self.pop();
+        }
+
+        self.bytes += chunk.len();
+        self.chunks.push_back((now, chunk.clone()));
+    }
+
+    /// Number of the next chunk to be broadcast
+    pub fn end(&self) -> u64 {
+        self.first + self.chunks.len() as u64
+    }
+
+    /// Number of the oldest chunk broadcast at most `rewind` ago
+    pub fn seek(&self, rewind: Duration) -> u64 {
+        let now = Instant::now();
+        let within = self.chunks.iter().rev().take_while(|&&(t, _)| now.duration_since(t) <= rewind).count();
+        self.end() - within as u64
+    }
+
+    /// The chunk numbered `n` or, if it was evicted already, the oldest one kept
+    /// along with its number. None once there is no such chunk yet
+    pub fn get(&self, n: u64) -> Option<(u64, &Bytes)> {
+        let n = n.max(self.first);
+        self.chunks.get((n - self.first) as usize).map(|(_, chunk)| (n, chunk))
+    }
+
+    /// The chunks broadcast at most `window` ago, oldest first, along with how
+    /// long ago the oldest chunk kept was broadcast
+    pub fn since(&self, window: Duration) -> (Vec<Bytes>, Duration) {
+        let n = self.seek(window);
+        let chunks = self.chunks.iter().skip((n - self.first) as usize).map(|(_, chunk)| chunk.clone()).collect();
+        let kept = self.chunks.front().map_or(Duration::ZERO, |&(t, _)| t.elapsed());
+        (chunks, kept)
+    }
+
+    /// The chunks kept, oldest first
+    pub fn chunks(&self) -> impl DoubleEndedIterator<Item = &Bytes> {
+        self.chunks.iter().map(|(_, chunk)| chunk)
+    }
+
+    /// Forget the chunks, the numbers carry on
+    pub fn clear(&mut self) {
+        self.first = self.end();
+        self.chunks.clear();
+        self.bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn chunk(n: u8) -> Bytes {
+        Bytes::from(vec![n; 188])
+    }
+
+    #[test]
+    fn bounded_by_bytes() {
+        let mut dvr = Dvr::new(Duration::from_secs(60), 1000);
+        for n in 0..100 {
+            dvr.push(&chunk(n));
+            assert!(dvr.bytes <= 1000);
+        }
+
+        assert_eq!(dvr.end(), 100);
+        assert_eq!(dvr.seek(Duration::from_secs(60)), 95);
+        // Evicted, the oldest one kept instead
+        assert_eq!(dvr.get(10).map(|(n, c)| (n, c[0])), Some((95, 95)));
+        assert_eq!(dvr.get(99).map(|(n, c)| (n, c[0])), Some((99, 99)));
+        assert!(dvr.get(100).is_none());
+
+        // Never kept, still numbered
+        dvr.push(&Bytes::from(vec![0; 2000]));
+        assert_eq!((dvr.bytes, dvr.end()), (0, 101));
+        assert!(dvr.get(95).is_none());
+    }
+
+    #[test]
+    fn bounded_by_time() {
+        let mut dvr = Dvr::new(Duration::from_millis(100), 1 << 20);
+        dvr.push(&chunk(0));
+        dvr.push(&chunk(1));
+        thread::sleep(Duration::from_millis(60));
+        dvr.push(&chunk(2));
+
+        assert_eq!(dvr.seek(Duration::from_millis(30)), 2);
+        assert_eq!(dvr.seek(Duration::from_secs(1)), 0);
+
+        thread::sleep(Duration::from_millis(60));
+        dvr.push(&chunk(3));
+        assert_eq!(dvr.seek(Duration::from_secs(1)), 2);
+
+        let (chunks, kept) = dvr.since(Duration::from_millis(30));
+        assert_eq!((chunks.len(), chunks[0][0]), (1, 3));
+        assert!(kept >= Duration::from_millis(60));
+
+        dvr.clear();
+        assert_eq!((dvr.bytes, dvr.end(), dvr.seek(Duration::from_secs(1))), (0, 4, 4));
+    }
+}
@@ -0,0 +1,192 @@
+//! Connection lifecycle events for the embedders
+//!
+//! Every subscriber has a bounded queue of its own, its oldest events are
+//! dropped once it is full so a slow subscriber never holds up the stream.
+
+use futures::prelude::*;
+use futures::task::AtomicWaker;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::scte35::Cue;
+use crate::tr101290::Check;
+
+/// Events kept per subscriber, the oldest are dropped past that
+const QUEUE: usize = 256;
+
+/// Why a peer went away
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reason {
+    /// The peer closed the connection
+    Closed,
+    /// The producer left and what was queued got sent
+    StreamEnded,
+    /// Taken out of the fan-out, by the overflow policy, a kick or the shutdown,
+    /// or for a producer, replaced by another one
+    Removed,
+    /// Past `max_lag` or `max_lag_bytes`
+    Lagging,
+    /// Accepted nothing for `idle_timeout`, or for an UDP producer, sent nothing
+    /// for the UDP timeout
+    Idle,
+    /// The connection failed
+    Error(String),
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Reason::Closed => "closed",
+            Reason::StreamEnded => "stream_ended",
+            Reason::Removed => "removed",
+            Reason::Lagging => "lagging",
+            Reason::Idle => "idle",
+            Reason::Error(_) => "error",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    ProducerConnected { addr: SocketAddr },
+    ProducerDisconnected { addr: SocketAddr, bytes: u64, duration: Duration, reason: Reason },
+    ConsumerConnected { addr: SocketAddr },
+    ConsumerDisconnected { addr: SocketAddr, bytes: u64, duration: Duration, reason: Reason },
+    /// The producer is connected but sends nothing
+    StreamStalled,
+    /// The stalled producer sends again
+    StreamResumed,
+    /// The backup input replaced the primary one, or the primary one is back
+    InputSwitched { backup: bool },
+    /// Most of the packets come scrambled upstream for a while, or in the clear again
+    StreamScrambled { scrambled: bool },
+    /// A TR 101 290 priority 1 indicator was raised or cleared
+    Tr101290 { check: Check, active: bool },
+    /// A SCTE-35 splice_insert or time_signal went by
+    Scte35 { cue: Cue },
+}
+
+struct Queue {
+    events: Mutex<VecDeque<Event>>,
+    dropped: AtomicU64,
+    waker: AtomicWaker,
+}
+
+/// Hands every event to the current subscribers
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Mutex<Vec<Weak<Queue>>>,
+}
+
+impl EventBus {
+    pub fn subscribe(&self) -> Events {
+        let queue = Arc::new(Queue {
+            events: Mutex::new(VecDeque::new()),
+            dropped: AtomicU64::new(0),
+            waker: AtomicWaker::new(),
+        });
+        self.subscribers.lock().unwrap().push(Arc::downgrade(&queue));
+
+        Events(queue)
+    }
+
+    /// Queue `event` for every subscriber, never blocking
+    pub fn emit(&self, event: Event) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+
+        subscribers.retain(|queue| match queue.upgrade() {
+            Some(queue) => {
+                {
+                    let mut events = queue.events.lock().unwrap();
+                    if events.len() >= QUEUE {
+                        events.pop_front();
+                        queue.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                    events.push_back(event.clone());
+                }
+                queue.waker.wake();
+                true
+            }
+            // The subscriber is gone
+            None => false,
+        });
+    }
+}
+
+/// The events emitted since subscribing, the stream never ends
+pub struct Events(Arc<Queue>);
+
+impl Events {
+    /// Events dropped because they were not received in time
+    pub fn dropped(&self) -> u64 {
+        self.0.dropped.load(Ordering::Relaxed)
+    }
+
+    /// The next event if there is one already, without waiting
+    pub fn try_next(&self) -> Option<Event> {
+        self.0.events.lock().unwrap().pop_front()
+    }
+}
+
+impl Stream for Events {
+    type Item = Event;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        self.0.waker.register(cx.waker());
+
+        match self.0.events.lock().unwrap().pop_front() {
+            Some(event) => Poll::Ready(Some(event)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+
+    fn drain(events: &mut Events) -> Vec<Event> {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut out = Vec::new();
+        while let Poll::Ready(Some(event)) = events.poll_next_unpin(&mut cx) {
+            out.push(event);
+        }
+        out
+    }
+
+    #[test]
+    fn full_queue_drops_the_oldest() {
+        let bus = EventBus::default();
+        let mut events = bus.subscribe();
+
+        bus.emit(Event::StreamStalled);
+        for _ in 0..QUEUE {
+            bus.emit(Event::StreamResumed);
+        }
+
+        let received = drain(&mut events);
+        assert_eq!(received.len(), QUEUE);
+        assert!(received.iter().all(|e| *e == Event::StreamResumed));
+        assert_eq!(events.dropped(), 1);
+    }
+
+    #[test]
+    fn gone_subscribers_forgotten() {
+        let bus = EventBus::default();
+        let mut kept = bus.subscribe();
+        drop(bus.subscribe());
+
+        bus.emit(Event::StreamStalled);
+
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+        assert_eq!(drain(&mut kept), vec![Event::StreamStalled]);
+    }
+}
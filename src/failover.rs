@@ -0,0 +1,239 @@
+//! Switching to a backup input while the primary one sends nothing
+//!
+//! The backup input fans out into a state of its own, bridged to the consumers
+//! while it replaces the primary one. The first packet of every PID following a
+//! switch is flagged as a discontinuity when it has an adaptation field, and
+//! the continuity monitoring starts over.
+
+use futures::prelude::*;
+use bytes::{Bytes, BytesMut};
+use log::{info, warn};
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::{OneShotTx, Shared};
+use crate::events::Event;
+use crate::queue::{self, Overflow};
+use crate::ts;
+
+/// How often the primary input is checked
+const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long after a switch the packets are flagged
+const MARK_DURATION: Duration = Duration::from_secs(1);
+
+/// Which input is on air, updated by the producers and the switching task
+pub struct Failover {
+    /// Silence of the primary input before switching to the backup one
+    timeout: u64,
+    /// Data from the primary input before switching back to it
+    failback: u64,
+    created: Instant,
+    /// Milliseconds from `created` to the last packet of the primary input
+    last_primary: AtomicU64,
+    /// Milliseconds from `created` to the packet ending the last silence of the primary input
+    primary_since: AtomicU64,
+    on_backup: AtomicBool,
+    /// Milliseconds from `created` to the last switch
+    switched: AtomicU64,
+    marking: AtomicBool,
+    /// PIDs seen since the last switch
+    marked: Mutex<HashSet<u16>>,
+}
+
+impl Failover {
+    pub fn new(timeout: Duration, failback: Duration) -> Self {
+        Failover {
+            timeout: timeout.as_millis() as u64,
+            failback: failback.as_millis() as u64,
+            created: Instant::now(),
+            last_primary: AtomicU64::new(0),
+            primary_since: AtomicU64::new(0),
+            on_backup: AtomicBool::new(false),
+            switched: AtomicU64::new(0),
+            marking: AtomicBool::new(false),
+            marked: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.created.elapsed().as_millis() as u64
+    }
+
+    pub fn on_backup(&self) -> bool {
+        self.on_backup.load(Ordering::Relaxed)
+    }
+
+    /// Count a chunk of the primary input, returns whether it is on air
+    pub fn primary(&self) -> bool {
+        self.primary_at(self.now())
+    }
+
+    fn primary_at(&self, now: u64) -> bool {
+        let last = self.last_primary.swap(now, Ordering::Relaxed);
+        if now.saturating_sub(last) >= self.timeout {
+            self.primary_since.store(now, Ordering::Relaxed);
+        }
+        !self.on_backup()
+    }
+
+    /// The input to switch to, if it is time to: `true` for the backup one
+    fn due(&self, now: u64) -> Option<bool> {
+        let idle = now.saturating_sub(self.last_primary.load(Ordering::Relaxed));
+        let sending = now.saturating_sub(self.primary_since.load(Ordering::Relaxed));
+
+        match self.on_backup() {
+            false if idle >= self.timeout => Some(true),
+            true if idle < self.timeout && sending >= self.failback => Some(false),
+            _ => None,
+        }
+    }
+
+    fn switch(&self, now: u64, backup: bool) {
+        self.marked.lock().unwrap().clear();
+        self.switched.store(now, Ordering::Relaxed);
+        self.marking.store(true, Ordering::Relaxed);
+        self.on_backup.store(backup, Ordering::Relaxed);
+    }
+
+    /// Flag the first packet of each PID since the switch as a discontinuity
+    pub fn mark(&self, chunk: &Bytes) -> Bytes {
+        if !self.marking.load(Ordering::Relaxed) || !ts::is_aligned(chunk) {
+            return chunk.clone();
+        }
+
+        let mut marked = self.marked.lock().unwrap();
+        let mut out = BytesMut::from(&chunk[..]);
+        for pkt in out.chunks_mut(ts::PACKET_SIZE) {
+            if marked.insert(ts::pid(pkt)) {
+                ts::set_discontinuity(pkt);
+            }
+        }
+        out.freeze()
+    }
+}
+
+/// Switch between the inputs as the primary one comes and goes, the backup
+/// input fans out into `backup`
+///
+/// `done` is dropped once the returned future is, ending the consumer session.
+pub fn run(failover: Arc<Failover>, state: &Arc<Mutex<Shared>>, backup: &Arc<Mutex<Shared>>, done: OneShotTx) -> impl Future<Output = ()> {
+    let (fanout, stats) = {
+        let state = state.lock().unwrap();
+        (state.fanout.clone(), state.stats.clone())
+    };
+
+    let mut rx = {
+        let backup = backup.lock().unwrap();
+        let (tx, rx) = queue::bounded(backup.settings.consumer_queue, Overflow::Drop, Default::default());
+        backup.fanout.insert(SocketAddr::from(([0, 0, 0, 0], 0)), tx, 0);
+        rx
+    };
+
+    let bridge = {
+        let fanout = fanout.clone();
+        async move {
+            while let Some(packet) = rx.next().await {
+                fanout.broadcast_backup(&packet);
+            }
+        }
+    };
+
+    let watch = async move {
+        let mut ticks = crate::interval(CHECK_INTERVAL);
+        loop {
+            ticks.tick().await;
+            let now = failover.now();
+
+            if failover.marking.load(Ordering::Relaxed)
+                && now.saturating_sub(failover.switched.load(Ordering::Relaxed)) >= MARK_DURATION.as_millis() as u64 {
+                failover.marking.store(false, Ordering::Relaxed);
+            }
+
+            let backup = match failover.due(now) {
+                Some(backup) => backup,
+                None => continue,
+            };
+
+            if backup {
+                warn!("No data from the primary input for {}ms, switching to the backup input", failover.timeout);
+            } else {
+                info!("The primary input is back for {}ms, switching to it", failover.failback);
+            }
+
+            // Leave the packets of the other input in the pipeline behind
+            fanout.flush();
+            failover.switch(now, backup);
+            stats.restart_stream();
+            stats.events.emit(Event::InputSwitched { backup });
+        }
+    };
+
+    async move {
+        tokio::select! {
+            _ = bridge => warn!("The backup input is gone"),
+            _ = watch => (),
+        }
+        drop(done);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failover() -> Failover {
+        Failover::new(Duration::from_secs(2), Duration::from_secs(5))
+    }
+
+    #[test]
+    fn switches_with_hysteresis() {
+        let failover = failover();
+
+        // Sending since the start
+        assert!(failover.primary_at(100));
+        assert_eq!(failover.due(1000), None);
+        // Silent for 2 seconds
+        assert_eq!(failover.due(2100), Some(true));
+        failover.switch(2100, true);
+
+        // Back, its packets are held until it sent for 5 seconds
+        assert!(!failover.primary_at(3000));
+        assert!(!failover.primary_at(4500));
+        assert_eq!(failover.due(4500), None);
+        // Silent again, counting starts over
+        for &now in &[7000, 8500, 10000, 11500] {
+            assert!(!failover.primary_at(now));
+            assert_eq!(failover.due(now), None);
+        }
+        assert!(!failover.primary_at(12000));
+        assert_eq!(failover.due(12000), Some(false));
+    }
+
+    #[test]
+    fn marks_each_pid_once() {
+        let failover = failover();
+        let mut chunk = Vec::new();
+        for &(pid, adaptation) in &[(0x100u16, true), (0x100, true), (0x101, false)] {
+            let mut pkt = ts::tests::packet(pid, if adaptation { 0x30 } else { 0x10 });
+            pkt[4] = 1;
+            pkt[5] = 0;
+            chunk.extend(pkt);
+        }
+        let chunk = Bytes::from(chunk);
+
+        assert_eq!(failover.mark(&chunk), chunk);
+
+        failover.switch(0, true);
+        let marked = failover.mark(&chunk);
+        assert_eq!(marked[5], 0x80);
+        assert_eq!(marked[ts::PACKET_SIZE + 5], 0);
+        // Without an adaptation field, nothing to flag
+        assert_eq!(marked[2 * ts::PACKET_SIZE + 5], 0);
+        assert_eq!(failover.mark(&chunk), chunk);
+    }
+}
@@ -0,0 +1,571 @@
+//! Packet fan-out to the consumer queues
+//!
+//! The producer sends to a snapshot of the member list, joining and leaving
+//! publish a new snapshot, so they never hold up a broadcast for long.
+
+use bytes::Bytes;
+use log::{debug, warn};
+
+use std::net::SocketAddr;
+use std::process;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::Tx;
+use crate::burst::{Burst, BurstBuffer};
+use crate::delay::DelayLine;
+use crate::dvr::Dvr;
+use crate::failover::Failover;
+use crate::filter::{PidFilter, ProgramFilter, Repacker};
+use crate::queue::SendError;
+use crate::relay::{self, Offset};
+use crate::remap::PidRemap;
+use crate::stats::Stats;
+
+struct Member {
+    addr: SocketAddr,
+    /// Shared with the member it was renamed from, until no broadcast uses that one
+    tx: Arc<Tx>,
+    /// Counts the bytes to resume from, nothing but the stream may go to it
+    relay: bool,
+}
+
+type Members = Arc<Vec<Arc<Member>>>;
+
+/// A member replaying the time-shift buffer, it joins once it caught up
+pub struct Rewind {
+    addr: SocketAddr,
+    tx: Tx,
+    /// Number of the next chunk to replay
+    cursor: u64,
+    /// Chunks evicted before they could be replayed
+    pub skipped: u64,
+}
+
+pub struct Fanout {
+    members: RwLock<Members>,
+    /// Recent packets replayed to the new members
+    burst: Mutex<Option<BurstBuffer>>,
+    created: Instant,
+    /// Milliseconds from `created` to the last broadcast
+    last_broadcast: AtomicU64,
+    /// Inspects every packet broadcast
+    stats: Option<Arc<Stats>>,
+    /// Packets never sent to the members
+    filter: Option<PidFilter>,
+    /// The single program sent to the members
+    program: Option<Mutex<ProgramFilter>>,
+    /// PIDs sent as others, once filtered
+    remap: Option<Mutex<PidRemap>>,
+    /// Packets left by the filter, waiting for a full chunk
+    repacker: Mutex<Repacker>,
+    /// Decides which input is on air, when there is a backup one
+    failover: OnceLock<Arc<Failover>>,
+    /// Chunks the members may rewind to, locked after `burst`
+    dvr: OnceLock<Mutex<Dvr>>,
+    /// Holds the stream back before it is forwarded, with `--delay`
+    delay: OnceLock<DelayLine>,
+    /// Tells this run apart in the offsets given to the relays
+    origin: u64,
+    /// Bytes sent so far, updated along with the burst buffer
+    offset: AtomicU64,
+}
+
+impl Fanout {
+    pub fn new(burst: Option<Burst>) -> Self {
+        Fanout {
+            members: RwLock::new(Arc::new(Vec::new())),
+            burst: Mutex::new(burst.map(BurstBuffer::new)),
+            created: Instant::now(),
+            last_broadcast: AtomicU64::new(0),
+            stats: None,
+            filter: None,
+            program: None,
+            remap: None,
+            repacker: Mutex::new(Repacker::new(0)),
+            failover: OnceLock::new(),
+            dvr: OnceLock::new(),
+            delay: OnceLock::new(),
+            origin: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64) ^ u64::from(process::id()),
+            offset: AtomicU64::new(0),
+        }
+    }
+
+    /// Analyze the stream broadcast for the monitoring endpoints
+    pub fn with_stats(mut self, stats: Arc<Stats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Leave out the packets of some PIDs or of the other programs, sending the
+    /// rest in `chunk_size` chunks
+    pub fn with_filter(mut self, filter: Option<PidFilter>, program: Option<ProgramFilter>, chunk_size: usize) -> Self {
+        self.filter = filter;
+        self.program = program.map(Mutex::new);
+        self.repacker = Mutex::new(Repacker::new(chunk_size));
+        self
+    }
+
+    /// Send some PIDs as others, the chunks must be aligned
+    pub fn with_remap(mut self, remap: Option<PidRemap>) -> Self {
+        self.remap = remap.map(Mutex::new);
+        self
+    }
+
+    /// Send the packets of the backup input instead of the producer's when `failover` says so
+    pub fn set_failover(&self, failover: Arc<Failover>) {
+        let _ = self.failover.set(failover);
+    }
+
+    /// Keep the chunks sent in `dvr`, for the members to rewind
+    pub fn set_dvr(&self, dvr: Dvr) {
+        let _ = self.dvr.set(Mutex::new(dvr));
+    }
+
+    /// Forward the packets through `delay`
+    pub fn set_delay(&self, delay: DelayLine) {
+        let _ = self.delay.set(delay);
+    }
+
+    fn snapshot(&self) -> Members {
+        self.members.read().unwrap().clone()
+    }
+
+    /// Publish a new member list built from the current one
+    fn update<T, F: FnOnce(&mut Vec<Arc<Member>>) -> T>(&self, f: F) -> T {
+        let mut members = self.members.write().unwrap();
+        let mut next = members.as_ref().clone();
+        let res = f(&mut next);
+        *members = Arc::new(next);
+        res
+    }
+
+    /// Add a member, pre-filling its queue with up to `prefill` packets of the burst buffer
+    pub fn insert(&self, addr: SocketAddr, tx: Tx, prefill: usize) {
+        self.insert_within(addr, tx, prefill, None)
+    }
+
+    /// Same as `insert`, replaying no more of the burst buffer than `limit`
+    pub fn insert_within(&self, addr: SocketAddr, tx: Tx, prefill: usize, limit: Option<Burst>) {
+        // Held until the member is published, so no packet is missed or sent twice
+        let burst = self.burst.lock().unwrap();
+
+        if let Some(ref burst) = *burst {
+            let packets: Vec<_> = match limit {
+                Some(limit) => burst.recent_within(prefill, limit).collect(),
+                None => burst.recent(prefill).collect(),
+            };
+            for packet in packets {
+                let _ = tx.send(packet.clone());
+            }
+        }
+
+        let member = Arc::new(Member { addr, tx: Arc::new(tx), relay: false });
+        self.update(move |members| members.push(member));
+    }
+
+    /// Add a relay, resuming from `resume` if the chunks since are still kept
+    /// and fit in `prefill`, otherwise joining as `insert` does. Its queue starts
+    /// with the point it resumes from, which is returned
+    pub fn insert_relay(&self, addr: SocketAddr, tx: Tx, prefill: usize, resume: Option<Offset>) -> Offset {
+        // Room for the line telling where it starts
+        let prefill = prefill.saturating_sub(1);
+        // Held until the member is published, so no packet is missed or sent twice
+        let burst = self.burst.lock().unwrap();
+        let end = self.offset.load(Ordering::Relaxed);
+
+        let resumed = resume.filter(|at| at.origin == self.origin).and_then(|at| match self.dvr.get() {
+            Some(dvr) => since(dvr.lock().unwrap().chunks(), end, at.offset, prefill),
+            None => since(burst.iter().flat_map(BurstBuffer::packets), end, at.offset, prefill),
+        });
+        let chunks = match resumed {
+            Some(chunks) => chunks,
+            None => burst.iter().flat_map(|burst| burst.recent(prefill)).cloned().collect(),
+        };
+
+        let start = Offset { origin: self.origin, offset: end - chunks.iter().map(|chunk| chunk.len() as u64).sum::<u64>() };
+        let _ = tx.send(relay::resumed(start));
+        for chunk in chunks {
+            let _ = tx.send(chunk);
+        }
+
+        let member = Arc::new(Member { addr, tx: Arc::new(tx), relay: true });
+        self.update(move |members| members.push(member));
+        start
+    }
+
+    /// Start a member `by` in the past, it is sent the chunks of the time-shift
+    /// buffer by `replay` until it joins. Without a buffer the queue is given back
+    pub fn rewind(&self, addr: SocketAddr, tx: Tx, by: Duration) -> Result<Rewind, Tx> {
+        match self.dvr.get() {
+            Some(dvr) => {
+                let cursor = dvr.lock().unwrap().seek(by);
+                Ok(Rewind { addr, tx, cursor, skipped: 0 })
+            }
+            None => Err(tx),
+        }
+    }
+
+    /// The next chunk to replay to a rewinding member, none once it caught up
+    /// and joined the others, taking it out of `rewind`
+    pub fn replay(&self, rewind: &mut Option<Rewind>) -> Option<Bytes> {
+        let dvr = self.dvr.get()?.lock().unwrap();
+
+        if let Some(ref mut rw) = *rewind {
+            if let Some((n, chunk)) = dvr.get(rw.cursor) {
+                rw.skipped += n - rw.cursor;
+                rw.cursor = n + 1;
+                return Some(chunk.clone());
+            }
+        }
+
+        // Held until the member is published, the next chunk broadcast goes to its queue
+        if let Some(Rewind { addr, tx, skipped, .. }) = rewind.take() {
+            if skipped > 0 {
+                warn!("{:?} caught up with the live stream, {} chunks were evicted before it could be sent them", addr, skipped);
+            }
+            let member = Arc::new(Member { addr, tx: Arc::new(tx), relay: false });
+            self.update(move |members| members.push(member));
+        }
+        None
+    }
+
+    /// The chunks sent at most `window` ago, from the time-shift buffer or else
+    /// the burst buffer, and how far back that buffer goes. None without either
+    ///
+    /// Only the handles are copied under the lock, the broadcast carries on.
+    pub fn since(&self, window: Duration) -> Option<(Vec<Bytes>, Duration)> {
+        if let Some(dvr) = self.dvr.get() {
+            return Some(dvr.lock().unwrap().since(window));
+        }
+        self.burst.lock().unwrap().as_ref().map(|burst| burst.since(window))
+    }
+
+    /// Remove a member, its queue is closed once no broadcast uses it anymore
+    pub fn remove(&self, addr: &SocketAddr) -> bool {
+        self.update(|members| {
+            let len = members.len();
+            members.retain(|m| m.addr != *addr);
+            members.len() != len
+        })
+    }
+
+    /// Move the queue of a member over to `to`, for a consumer coming back on
+    /// another connection, nothing broadcast meanwhile is missed
+    pub fn rename(&self, from: &SocketAddr, to: SocketAddr) -> bool {
+        self.update(|members| match members.iter().position(|m| m.addr == *from) {
+            Some(i) => {
+                members[i] = Arc::new(Member { addr: to, tx: members[i].tx.clone(), relay: members[i].relay });
+                true
+            }
+            None => false,
+        })
+    }
+
+    pub fn set_capacity(&self, capacity: usize) {
+        for member in self.snapshot().iter() {
+            member.tx.set_capacity(capacity);
+        }
+    }
+
+    /// The producer is gone, forget about its stream
+    pub fn clear_burst(&self) {
+        if let Some(ref mut burst) = *self.burst.lock().unwrap() {
+            burst.clear();
+        }
+        if let Some(dvr) = self.dvr.get() {
+            dvr.lock().unwrap().clear();
+        }
+        if let Some(delay) = self.delay.get() {
+            delay.clear();
+        }
+    }
+
+    /// Time since the last packet was broadcast
+    pub fn idle(&self) -> Duration {
+        let last = Duration::from_millis(self.last_broadcast.load(Ordering::Relaxed));
+        self.created.elapsed().saturating_sub(last)
+    }
+
+    /// Whether the stream goes as is to `addr` alone, without being kept for
+    /// anyone else
+    pub fn only(&self, addr: &SocketAddr) -> bool {
+        let members = self.snapshot();
+        let as_is = self.filter.is_none() && self.program.is_none() && self.remap.is_none();
+        let kept = self.burst.lock().unwrap().is_some() || self.dvr.get().is_some() || self.delay.get().is_some();
+
+        as_is && !kept && self.failover.get().is_none() && members.len() == 1 && members[0].addr == *addr
+    }
+
+    /// Count `len` bytes handed to the only member some other way, see `only`
+    pub fn bypassed(&self, len: usize) {
+        self.last_broadcast.store(self.created.elapsed().as_millis() as u64, Ordering::Relaxed);
+        self.offset.fetch_add(len as u64, Ordering::Relaxed);
+        if let Some(ref stats) = self.stats {
+            stats.add_spliced(len);
+        }
+    }
+
+    /// Send a packet to every member, never blocking
+    pub fn broadcast(&self, packet: &Bytes) {
+        match self.failover.get() {
+            Some(failover) if failover.primary() => self.emit(&failover.mark(packet)),
+            Some(_) => (),
+            None => self.emit(packet),
+        }
+    }
+
+    /// Send a packet of the backup input, while it replaces the producer
+    pub fn broadcast_backup(&self, packet: &Bytes) {
+        if let Some(failover) = self.failover.get().filter(|failover| failover.on_backup()) {
+            self.emit(&failover.mark(packet));
+        }
+    }
+
+    fn emit(&self, packet: &Bytes) {
+        match self.delay.get() {
+            Some(delay) => delay.push(packet.clone()),
+            None => self.forward(packet),
+        }
+    }
+
+    /// Filter and send a packet on air, once it is through the delay if any
+    pub fn forward(&self, packet: &Bytes) {
+        self.last_broadcast.store(self.created.elapsed().as_millis() as u64, Ordering::Relaxed);
+
+        if let Some(ref stats) = self.stats {
+            stats.inspect(packet);
+        }
+
+        if self.filter.is_none() && self.program.is_none() && self.remap.is_none() {
+            return self.send(packet);
+        }
+
+        let mut packets = packet.clone();
+
+        if let Some(ref filter) = self.filter {
+            let (kept, filtered) = filter.apply(&packets);
+            if let Some(ref stats) = self.stats {
+                stats.add_filtered(filtered);
+            }
+            packets = kept;
+        }
+
+        // Locked until sent, so the chunks keep their order
+        let mut repacker = self.repacker.lock().unwrap();
+
+        if let Some(ref program) = self.program {
+            packets = program.lock().unwrap().apply(&packets);
+        }
+        if let Some(ref remap) = self.remap {
+            packets = remap.lock().unwrap().apply(&packets);
+        }
+
+        for chunk in repacker.push(packets) {
+            self.send(&chunk);
+        }
+    }
+
+    /// Send the packets held back by the filter, the stream is over
+    pub fn flush(&self) {
+        let mut repacker = self.repacker.lock().unwrap();
+        if let Some(chunk) = repacker.flush() {
+            self.send(&chunk);
+        }
+    }
+
+    fn send(&self, packet: &Bytes) {
+        if let Some(ref stats) = self.stats {
+            stats.add_broadcast(packet.len());
+        }
+
+        let members = {
+            let mut burst = self.burst.lock().unwrap();
+            if let Some(ref mut burst) = *burst {
+                burst.push(packet);
+            }
+            self.offset.fetch_add(packet.len() as u64, Ordering::Relaxed);
+            // The rewinding members join either before or after it is kept
+            let _dvr = self.dvr.get().map(|dvr| {
+                let mut dvr = dvr.lock().unwrap();
+                dvr.push(packet);
+                dvr
+            });
+            self.snapshot()
+        };
+
+        self.deliver(&members, packet);
+    }
+
+    /// Send filler to the members only, while the delay is priming
+    pub fn pad(&self, packet: &Bytes) {
+        self.deliver(&self.snapshot(), packet);
+    }
+
+    /// Send filler to the members in `addrs` only, the relays aside, while
+    /// the producer is silent. Returns the members it went to
+    pub fn keepalive(&self, packet: &Bytes, addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+        let members: Vec<_> = self.snapshot().iter().filter(|m| !m.relay && addrs.contains(&m.addr)).cloned().collect();
+        let sent = members.iter().map(|m| m.addr).collect();
+        self.deliver(&Arc::new(members), packet);
+        sent
+    }
+
+    fn deliver(&self, members: &Members, packet: &Bytes) {
+        let mut gone = Vec::new();
+
+        for member in members.iter() {
+            match member.tx.send(packet.clone()) {
+                Ok(()) => (),
+                Err(SendError::Full) => {
+                    warn!("Disconnecting {:?}, queue full", member.addr);
+                    gone.push(member.addr);
+                }
+                // Left on its own, it is only forgotten
+                Err(SendError::Closed) => {
+                    debug!("Removing {:?}, consumer closed", member.addr);
+                    gone.push(member.addr);
+                }
+            }
+        }
+
+        if !gone.is_empty() {
+            self.update(|members| members.retain(|m| !gone.contains(&m.addr)));
+        }
+    }
+}
+
+/// The chunks from byte `offset` on, the first one cut, given `chunks` oldest
+/// first and ending at byte `end`. None unless they are all there and no more than `max`
+fn since<'a, I: DoubleEndedIterator<Item = &'a Bytes>>(chunks: I, end: u64, offset: u64, max: usize) -> Option<Vec<Bytes>> {
+    if offset > end {
+        return None;
+    }
+
+    let mut start = end;
+    let mut from = Vec::new();
+    for chunk in chunks.rev() {
+        if start <= offset {
+            break;
+        }
+        if from.len() == max {
+            return None;
+        }
+        start -= chunk.len() as u64;
+        from.push(chunk.clone());
+    }
+    if start > offset {
+        return None;
+    }
+
+    from.reverse();
+    if let Some(first) = from.first_mut() {
+        *first = first.slice((offset - start) as usize..);
+    }
+    Some(from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::prelude::*;
+    use futures::task::noop_waker_ref;
+    use crate::queue::{self, Overflow, Receiver};
+
+    use std::task::{Context, Poll};
+    use std::thread;
+    use std::time::Instant;
+
+    fn drain(rx: &mut Receiver) -> usize {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut n = 0;
+        while let Poll::Ready(Some(_)) = rx.poll_next_unpin(&mut cx) {
+            n += 1;
+        }
+        n
+    }
+
+    #[test]
+    fn remove_closes_the_queue() {
+        let fanout = Fanout::new(None);
+        let addr = "127.0.0.1:1000".parse().unwrap();
+        let (tx, mut rx) = queue::bounded(4, Overflow::Drop, Default::default());
+
+        fanout.insert(addr, tx, 0);
+        fanout.broadcast(&Bytes::from_static(b"a"));
+        assert!(fanout.remove(&addr));
+        assert!(!fanout.remove(&addr));
+        fanout.broadcast(&Bytes::from_static(b"b"));
+
+        assert_eq!(drain(&mut rx), 1);
+        assert_eq!(rx.poll_next_unpin(&mut Context::from_waker(noop_waker_ref())), Poll::Ready(None));
+    }
+
+    #[test]
+    fn rename_keeps_the_queue() {
+        let fanout = Fanout::new(None);
+        let (old, new) = ("127.0.0.1:1000".parse().unwrap(), "127.0.0.1:1001".parse().unwrap());
+        let (tx, mut rx) = queue::bounded(4, Overflow::Drop, Default::default());
+
+        fanout.insert(old, tx, 0);
+        fanout.broadcast(&Bytes::from_static(b"a"));
+        assert!(fanout.rename(&old, new));
+        assert!(!fanout.remove(&old));
+        fanout.broadcast(&Bytes::from_static(b"b"));
+        assert_eq!(drain(&mut rx), 2);
+
+        assert!(fanout.remove(&new));
+        assert!(rx.is_closed());
+    }
+
+    /// Broadcast to 500 consumers while others keep joining and leaving, run with
+    /// `cargo test --release --lib broadcast_500_consumers -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn broadcast_500_consumers() {
+        const CONSUMERS: usize = 500;
+        const PACKETS: usize = 2000;
+
+        let fanout = Arc::new(Fanout::new(None));
+        let mut receivers = Vec::new();
+
+        for port in 0..CONSUMERS {
+            let (tx, rx) = queue::bounded(PACKETS, Overflow::Drop, Default::default());
+            fanout.insert(([127, 0, 0, 1], port as u16).into(), tx, 0);
+            receivers.push(rx);
+        }
+
+        let churn = {
+            let fanout = fanout.clone();
+            thread::spawn(move || {
+                let mut joins = 0;
+                while Arc::strong_count(&fanout) > 1 {
+                    let addr = ([127, 0, 0, 2], joins as u16).into();
+                    let (tx, _rx) = queue::bounded(16, Overflow::Drop, Default::default());
+                    fanout.insert(addr, tx, 0);
+                    fanout.remove(&addr);
+                    joins += 1;
+                }
+                joins
+            })
+        };
+
+        let packet = Bytes::from(vec![0x47; 1316]);
+        let start = Instant::now();
+        for _ in 0..PACKETS {
+            fanout.broadcast(&packet);
+        }
+        let elapsed = start.elapsed();
+        drop(fanout);
+
+        let joins = churn.join().unwrap();
+        eprintln!("{} packets to {} consumers in {:?}, {:.0} sends/s, {} joins meanwhile",
+                  PACKETS, CONSUMERS, elapsed,
+                  (PACKETS * CONSUMERS) as f64 / elapsed.as_secs_f64(), joins);
+
+        for rx in &mut receivers {
+            assert_eq!(drain(rx), PACKETS);
+        }
+    }
+}
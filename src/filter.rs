@@ -0,0 +1,333 @@
+//! PID filtering of the stream broadcast
+//!
+//! The packets left are re-packed into full chunks, so the consumers never get
+//! more, shorter writes than without filtering.
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::psi;
+use crate::stats::Stats;
+use crate::ts;
+
+const PAT_PID: u16 = 0;
+/// Highest PID, 13 bits
+const MAX_PID: u16 = 0x1fff;
+/// Stuffing packets
+const NULL_PID: u16 = 0x1fff;
+
+/// Parse a PID, in decimal or in hexadecimal with a `0x` prefix
+pub fn parse_pid(s: &str) -> Result<u16, String> {
+    let pid = if s.starts_with("0x") || s.starts_with("0X") {
+        u16::from_str_radix(&s[2..], 16)
+    } else {
+        s.parse()
+    };
+
+    match pid {
+        Ok(pid) if pid <= MAX_PID => Ok(pid),
+        _ => Err(format!("Invalid PID {}, use 0 to 8191 or 0x0 to 0x1fff", s)),
+    }
+}
+
+/// PIDs passed on to the consumers
+#[derive(Clone)]
+pub struct PidFilter {
+    /// One bit per PID, set if it passes
+    pass: [u64; 128],
+}
+
+impl PidFilter {
+    fn set(&mut self, pid: u16, pass: bool) {
+        let (word, bit) = (usize::from(pid) / 64, pid % 64);
+        if pass {
+            self.pass[word] |= 1 << bit;
+        } else {
+            self.pass[word] &= !(1 << bit);
+        }
+    }
+
+    fn passes(&self, pid: u16) -> bool {
+        self.pass[usize::from(pid) / 64] & (1 << (pid % 64)) != 0
+    }
+
+    /// Pass every PID but these
+    pub fn drop(pids: &[u16]) -> Self {
+        let mut filter = PidFilter { pass: [!0; 128] };
+        for &pid in pids {
+            filter.set(pid, false);
+        }
+        // The PAT always passes
+        filter.set(0, true);
+        filter
+    }
+
+    /// Pass only these PIDs and the PAT
+    pub fn keep(pids: &[u16]) -> Self {
+        let mut filter = PidFilter { pass: [0; 128] };
+        for &pid in pids {
+            filter.set(pid, true);
+        }
+        filter.set(0, true);
+        filter
+    }
+
+    /// Leave out the null packets as well
+    pub fn strip_nulls(mut self) -> Self {
+        self.set(NULL_PID, false);
+        self
+    }
+
+    /// The packets of `chunk` that pass and the number filtered out
+    ///
+    /// A chunk that is not made of whole packets cannot be checked, it is
+    /// dropped altogether.
+    pub fn apply(&self, chunk: &Bytes) -> (Bytes, u64) {
+        if !ts::is_aligned(chunk) {
+            let packets = chunk.len().div_ceil(ts::PACKET_SIZE) as u64;
+            return (Bytes::new(), packets);
+        }
+
+        let packets = chunk.chunks(ts::PACKET_SIZE);
+
+        // Usually nothing is filtered, the chunk is passed on as is
+        if packets.clone().all(|pkt| self.passes(ts::pid(pkt))) {
+            return (chunk.clone(), 0);
+        }
+
+        let mut out = BytesMut::with_capacity(chunk.len());
+        let mut filtered = 0;
+
+        for pkt in packets {
+            if self.passes(ts::pid(pkt)) {
+                out.put_slice(pkt);
+            } else {
+                filtered += 1;
+            }
+        }
+
+        (out.freeze(), filtered)
+    }
+}
+
+/// Gathers the filtered chunks back into full ones
+pub struct Repacker {
+    chunk_size: usize,
+    pending: BytesMut,
+}
+
+impl Repacker {
+    pub fn new(chunk_size: usize) -> Self {
+        Repacker { chunk_size, pending: BytesMut::new() }
+    }
+
+    /// The full chunks made with `packets` and what was pending
+    pub fn push(&mut self, packets: Bytes) -> Vec<Bytes> {
+        // Nothing was filtered, the chunk goes on as is
+        if self.pending.is_empty() && packets.len() == self.chunk_size {
+            return vec![packets];
+        }
+
+        self.pending.extend_from_slice(&packets);
+
+        let mut chunks = Vec::new();
+        while self.pending.len() >= self.chunk_size {
+            chunks.push(self.pending.split_to(self.chunk_size).freeze());
+        }
+        chunks
+    }
+
+    /// The packets pending, if any
+    pub fn flush(&mut self) -> Option<Bytes> {
+        if self.pending.is_empty() {
+            None
+        } else {
+            Some(self.pending.split().freeze())
+        }
+    }
+}
+
+/// The packets of a single program, following the changes of its tables
+///
+/// The PAT is rewritten to list the program alone, the packets of the other
+/// programs are left out without being counted as filtered.
+pub struct ProgramFilter {
+    number: u16,
+    stats: Arc<Stats>,
+    /// Tables the filter was built from
+    generation: Option<u64>,
+    filter: PidFilter,
+    /// PAT packet sent instead of the original one, once the program is listed
+    pat: Option<Vec<u8>>,
+    /// Continuity counter of the PAT sent
+    pat_cc: u8,
+}
+
+impl ProgramFilter {
+    pub fn new(number: u16, stats: Arc<Stats>) -> Self {
+        ProgramFilter {
+            number,
+            stats,
+            generation: None,
+            filter: PidFilter::keep(&[]),
+            pat: None,
+            pat_cc: 0,
+        }
+    }
+
+    fn update(&mut self) {
+        let generation = self.stats.programs_generation();
+        if self.generation == Some(generation) {
+            return;
+        }
+
+        let pids = self.stats.program(self.number).map(|p| p.pids()).unwrap_or_default();
+        self.filter = PidFilter::keep(&pids);
+        self.pat = self.stats.pat_for(self.number).map(|section| psi::packetize(PAT_PID, &section));
+        self.generation = Some(generation);
+    }
+
+    /// The packets of `chunk` belonging to the program, under its own PAT
+    ///
+    /// Nothing passes until the program is listed in the PAT.
+    pub fn apply(&mut self, chunk: &Bytes) -> Bytes {
+        self.update();
+
+        let (packets, _) = self.filter.apply(chunk);
+        if !packets.chunks(ts::PACKET_SIZE).any(|pkt| ts::pid(pkt) == PAT_PID) {
+            return packets;
+        }
+
+        let mut out = BytesMut::with_capacity(packets.len());
+
+        for pkt in packets.chunks(ts::PACKET_SIZE) {
+            if ts::pid(pkt) != PAT_PID {
+                out.put_slice(pkt);
+                continue;
+            }
+
+            // One PAT sent for every one starting, whatever its length
+            if let Some(ref mut pat) = self.pat {
+                if pkt[1] & 0x40 != 0 {
+                    pat[3] = 0x10 | self.pat_cc;
+                    self.pat_cc = (self.pat_cc + 1) & 0x0f;
+                    out.put_slice(pat);
+                }
+            }
+        }
+
+        out.freeze()
+    }
+}
+
+impl fmt::Debug for PidFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let passing = (0..=MAX_PID).filter(|&pid| self.passes(pid)).count();
+        write!(f, "PidFilter({} PIDs passing)", passing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(pids: &[u16]) -> Bytes {
+        let mut out = BytesMut::new();
+        for &pid in pids {
+            let mut pkt = [0xff; ts::PACKET_SIZE];
+            pkt[..4].copy_from_slice(&[ts::SYNC_BYTE, (pid >> 8) as u8, pid as u8, 0x10]);
+            out.extend_from_slice(&pkt);
+        }
+        out.freeze()
+    }
+
+    fn pids(chunk: &Bytes) -> Vec<u16> {
+        chunk.chunks(ts::PACKET_SIZE).map(ts::pid).collect()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_pid("256"), Ok(256));
+        assert_eq!(parse_pid("0x1FFF"), Ok(0x1fff));
+        assert!(parse_pid("0x2000").is_err());
+        assert!(parse_pid("video").is_err());
+    }
+
+    #[test]
+    fn drop_pids() {
+        let filter = PidFilter::drop(&[0, 0x100]);
+        let (out, filtered) = filter.apply(&chunk(&[0, 0x100, 0x101, 0x100]));
+
+        assert_eq!(pids(&out), vec![0, 0x101]);
+        assert_eq!(filtered, 2);
+    }
+
+    #[test]
+    fn keep_pids() {
+        let filter = PidFilter::keep(&[0x100, 0x1000]);
+        let (out, filtered) = filter.apply(&chunk(&[0, 0x10, 0x1000, 0x100, 0x1fff]));
+
+        assert_eq!(pids(&out), vec![0, 0x1000, 0x100]);
+        assert_eq!(filtered, 2);
+    }
+
+    #[test]
+    fn strip_nulls() {
+        let filter = PidFilter::drop(&[]).strip_nulls();
+        let (out, filtered) = filter.apply(&chunk(&[0x1fff, 0x100, 0x1fff]));
+
+        assert_eq!(pids(&out), vec![0x100]);
+        assert_eq!(filtered, 2);
+    }
+
+    #[test]
+    fn repack() {
+        let mut repacker = Repacker::new(3 * ts::PACKET_SIZE);
+        let full = chunk(&[1, 2, 3]);
+
+        assert_eq!(repacker.push(full.clone()), vec![full.clone()]);
+        assert!(repacker.push(chunk(&[1, 2])).is_empty());
+        assert_eq!(repacker.push(chunk(&[3, 4])), vec![chunk(&[1, 2, 3])]);
+        assert_eq!(repacker.push(full.clone()).iter().map(pids).collect::<Vec<_>>(), vec![vec![4, 1, 2]]);
+        assert_eq!(repacker.flush(), Some(chunk(&[3])));
+        assert_eq!(repacker.flush(), None);
+    }
+
+    #[test]
+    fn untouched_and_unaligned_chunks() {
+        let filter = PidFilter::drop(&[0x200]);
+        let input = chunk(&[0, 0x100]);
+        let (out, filtered) = filter.apply(&input);
+
+        assert_eq!((out.as_ptr(), filtered), (input.as_ptr(), 0));
+        assert_eq!(filter.apply(&input.slice(1..300)), (Bytes::new(), 2));
+    }
+
+    #[test]
+    fn single_program() {
+        use crate::psi::tests::{packets, pat, pmt};
+
+        let stats = Arc::new(Stats::default());
+        let mut filter = ProgramFilter::new(2, stats.clone());
+
+        let tables = Bytes::from([
+            packets(0, 0, &pat(&[(1, 0x1000), (2, 0x1100)])),
+            packets(0x1100, 0, &pmt(2, 0x200, &[(0x1b, 0x200), (0x0f, 0x201)])),
+        ].concat());
+        let media = chunk(&[0x100, 0x200, 0x101, 0x201, 0x1000, 0x1fff]);
+
+        // Nothing passes until the tables are known
+        assert!(filter.apply(&tables).is_empty());
+        stats.inspect(&tables);
+
+        let out = filter.apply(&tables);
+        assert_eq!(pids(&out), vec![0, 0x1100]);
+        assert_eq!(&out[..ts::PACKET_SIZE], &psi::packetize(0, &stats.pat_for(2).unwrap())[..]);
+        assert_eq!(pids(&filter.apply(&media)), vec![0x200, 0x201]);
+
+        // The PAT sent keeps its own continuity
+        assert_eq!(filter.apply(&tables)[3], 0x11);
+    }
+}
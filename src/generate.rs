@@ -0,0 +1,287 @@
+//! Test signal produced internally
+//!
+//! A single program: the PAT and PMT every 100ms, and a private data stream
+//! filling the bitrate, carrying the PCR every 20ms. Every packet is a whole
+//! PES packet of `0xff` bytes, so the analyzers find nothing to complain about.
+
+use tokio::sync::oneshot;
+use tokio::time::{self, Sleep};
+use futures::prelude::*;
+use bytes::Bytes;
+use log::info;
+
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::{OneShotSharedRx, OneShotTx, Shared};
+use crate::fanout::Fanout;
+use crate::psi;
+use crate::ts;
+
+/// Lowest bitrate leaving room for the data stream next to the tables
+pub const MIN_BITRATE: u64 = 100_000;
+
+const PROGRAM: u16 = 1;
+const PMT_PID: u16 = 0x1000;
+const DATA_PID: u16 = 0x100;
+/// Private data, in PES packets of `private_stream_2`
+const DATA_TYPE: u8 = 0x06;
+const DATA_STREAM_ID: u8 = 0xbf;
+
+/// Between two PAT and PMT, in `PCR_HZ` units
+const TABLE_INTERVAL: u64 = ts::PCR_HZ / 10;
+/// Between two PCR, in `PCR_HZ` units
+const PCR_INTERVAL: u64 = ts::PCR_HZ / 50;
+
+/// How often a paused generator checks whether the producer is gone
+const PAUSE_CHECK: Duration = Duration::from_millis(100);
+
+/// The packets of the test signal, in order
+struct Signal {
+    bitrate: u64,
+    /// Packets made so far
+    packets: u64,
+    /// Continuity counters of the PAT, PMT and data PIDs
+    cc: [u8; 3],
+    pat: Vec<u8>,
+    pmt: Vec<u8>,
+    /// Stream time of the last tables and the last PCR
+    last_tables: Option<u64>,
+    last_pcr: Option<u64>,
+    /// The PMT follows the PAT just made
+    pmt_next: bool,
+    /// Flag the next PCR as a discontinuity
+    discontinuity: bool,
+}
+
+impl Signal {
+    fn new(bitrate: u64) -> Self {
+        let pat = psi::section(psi::PAT_TABLE_ID, 1, 0, &[(PROGRAM >> 8) as u8, PROGRAM as u8, 0xe0 | (PMT_PID >> 8) as u8, PMT_PID as u8]);
+        let pmt = psi::section(psi::PMT_TABLE_ID, PROGRAM, 0, &[
+            0xe0 | (DATA_PID >> 8) as u8, DATA_PID as u8, 0xf0, 0,
+            DATA_TYPE, 0xe0 | (DATA_PID >> 8) as u8, DATA_PID as u8, 0xf0, 0,
+        ]);
+
+        Signal {
+            bitrate,
+            packets: 0,
+            cc: [0; 3],
+            pat: psi::packetize(0, &pat),
+            pmt: psi::packetize(PMT_PID, &pmt),
+            last_tables: None,
+            last_pcr: None,
+            pmt_next: false,
+            discontinuity: false,
+        }
+    }
+
+    /// Stream time of packet `n`, in `PCR_HZ` units
+    fn time(&self, n: u64) -> u64 {
+        (u128::from(n) * (ts::PACKET_SIZE as u128 * 8) * u128::from(ts::PCR_HZ) / u128::from(self.bitrate)) as u64
+    }
+
+    fn next_cc(&mut self, i: usize) -> u8 {
+        let cc = self.cc[i];
+        self.cc[i] = (cc + 1) & 0x0f;
+        cc
+    }
+
+    /// Append the next packet to `out`
+    fn push(&mut self, out: &mut Vec<u8>) {
+        let now = self.time(self.packets);
+        self.packets += 1;
+
+        if self.pmt_next {
+            self.pmt_next = false;
+            let cc = self.next_cc(1);
+            out.extend_from_slice(&self.pmt);
+            let len = out.len();
+            out[len - ts::PACKET_SIZE + 3] = 0x10 | cc;
+            return;
+        }
+        if self.last_tables.is_none_or(|last| now - last >= TABLE_INTERVAL) {
+            self.last_tables = Some(now);
+            self.pmt_next = true;
+            let cc = self.next_cc(0);
+            out.extend_from_slice(&self.pat);
+            let len = out.len();
+            out[len - ts::PACKET_SIZE + 3] = 0x10 | cc;
+            return;
+        }
+
+        let cc = self.next_cc(2);
+        let start = out.len();
+        out.extend_from_slice(&[ts::SYNC_BYTE, 0x40 | (DATA_PID >> 8) as u8, DATA_PID as u8, 0x10 | cc]);
+
+        if self.last_pcr.is_none_or(|last| now - last >= PCR_INTERVAL) {
+            self.last_pcr = Some(now);
+            let pcr = now % ts::PCR_WRAP;
+            let (base, ext) = (pcr / 300, pcr % 300);
+            let flags = if self.discontinuity { 0x90 } else { 0x10 };
+            self.discontinuity = false;
+
+            out[start + 3] |= 0x20;
+            out.extend_from_slice(&[
+                7, flags,
+                (base >> 25) as u8, (base >> 17) as u8, (base >> 9) as u8, (base >> 1) as u8,
+                ((base & 1) << 7) as u8 | 0x7e | (ext >> 8) as u8, ext as u8,
+            ]);
+        }
+
+        // A whole PES packet in what is left
+        let len = ts::PACKET_SIZE - (out.len() - start) - 6;
+        out.extend_from_slice(&[0, 0, 1, DATA_STREAM_ID, (len >> 8) as u8, len as u8]);
+        out.resize(start + ts::PACKET_SIZE, 0xff);
+    }
+}
+
+/// Generate the signal at its bitrate, pausing while a producer is connected
+struct Generator {
+    signal: Signal,
+    state: Arc<Mutex<Shared>>,
+    fanout: Arc<Fanout>,
+    chunk_packets: u64,
+    /// Packet due at `since`
+    first: u64,
+    since: Instant,
+    delay: Pin<Box<Sleep>>,
+    paused: bool,
+    /// Dropping it wakes up the consumers
+    _done: OneShotTx,
+}
+
+impl Generator {
+    /// Time packet `n` is due
+    fn due(&self, n: u64) -> Instant {
+        let time = self.signal.time(n) - self.signal.time(self.first);
+        self.since + Duration::from_nanos(time * 1000 / 27)
+    }
+}
+
+impl Future for Generator {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            let producer = self.state.lock().unwrap().session.as_ref().map(|s| s.addr);
+
+            if let Some(addr) = producer {
+                if !self.paused {
+                    info!("Pausing the test signal, {:?} is streaming", addr);
+                    self.paused = true;
+                }
+                self.delay.as_mut().reset(time::Instant::now() + PAUSE_CHECK);
+                ready!(self.delay.as_mut().poll(cx));
+                continue;
+            }
+
+            if self.paused {
+                info!("Resuming the test signal");
+                self.paused = false;
+                self.signal.discontinuity = true;
+                self.first = self.signal.packets;
+                self.since = Instant::now();
+            }
+
+            let now = Instant::now();
+            while self.due(self.signal.packets + self.chunk_packets - 1) <= now {
+                let mut chunk = Vec::with_capacity(self.chunk_packets as usize * ts::PACKET_SIZE);
+                for _ in 0..self.chunk_packets {
+                    self.signal.push(&mut chunk);
+                }
+                self.fanout.broadcast(&Bytes::from(chunk));
+            }
+
+            let next = self.due(self.signal.packets + self.chunk_packets - 1);
+            self.delay.as_mut().reset(time::Instant::from_std(next));
+            ready!(self.delay.as_mut().poll(cx));
+        }
+    }
+}
+
+/// Generate the test signal at `bitrate` for as long as the restreamer runs
+///
+/// `on_start` is called once with the consumer session, it ends when the
+/// returned future is dropped.
+pub async fn generate<F>(bitrate: u64, state: Arc<Mutex<Shared>>, buffer_size: usize, on_start: F)
+where
+    F: FnOnce(OneShotSharedRx),
+{
+    let (done, rx) = oneshot::channel::<()>();
+    let fanout = state.lock().unwrap().fanout.clone();
+    let generator = Generator {
+        signal: Signal::new(bitrate),
+        state,
+        fanout,
+        chunk_packets: (ts::chunk_size(buffer_size) / ts::PACKET_SIZE) as u64,
+        first: 0,
+        since: Instant::now(),
+        delay: Box::pin(time::sleep_until(time::Instant::now())),
+        paused: false,
+        _done: done,
+    };
+
+    info!("Generating a test signal at {} bit/s", bitrate);
+    on_start(rx.shared());
+
+    generator.await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cc::Continuity;
+    use crate::psi::Programs;
+
+    fn signal(bitrate: u64, packets: usize) -> Vec<u8> {
+        let mut signal = Signal::new(bitrate);
+        let mut out = Vec::new();
+        for _ in 0..packets {
+            signal.push(&mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn valid_packets() {
+        let out = signal(4_000_000, 10_000);
+        assert!(ts::is_aligned(&out));
+
+        let programs = Programs::default();
+        programs.inspect(&out);
+        let found = programs.programs();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].pcr_pid, Some(DATA_PID));
+        assert_eq!(found[0].streams[0].stream_type, DATA_TYPE);
+
+        let continuity = Continuity::default();
+        continuity.inspect(&out);
+        let counters = continuity.counters();
+        assert_eq!(counters.iter().map(|&(pid, _)| pid).collect::<Vec<_>>(), vec![0, DATA_PID, PMT_PID]);
+        assert!(counters.iter().all(|&(_, c)| c.discontinuities == 0 && c.duplicates == 0));
+
+        // Whole PES packets
+        for pkt in out.chunks(ts::PACKET_SIZE).filter(|pkt| ts::pid(pkt) == DATA_PID) {
+            let payload = psi::payload(pkt).unwrap();
+            assert_eq!(&payload[..4], &[0, 0, 1, DATA_STREAM_ID]);
+            assert_eq!((usize::from(payload[4]) << 8 | usize::from(payload[5])) + 6, payload.len());
+        }
+    }
+
+    #[test]
+    fn intervals() {
+        // 2660 packets a second
+        let out = signal(4_000_000, 2660);
+        let pcrs: Vec<_> = out.chunks(ts::PACKET_SIZE).filter_map(ts::pcr).map(|(_, pcr)| pcr).collect();
+        let pats = out.chunks(ts::PACKET_SIZE).filter(|pkt| ts::pid(pkt) == 0).count();
+
+        assert_eq!(pats, 10);
+        assert_eq!(pcrs.len(), 50);
+        assert!(pcrs.windows(2).all(|w| w[1] - w[0] >= PCR_INTERVAL && w[1] - w[0] < PCR_INTERVAL + ts::PCR_HZ / 1000));
+        // The PCR follows the bitrate
+        let last = out.chunks(ts::PACKET_SIZE).rposition(|pkt| ts::pcr(pkt).is_some()).unwrap() as u64;
+        assert_eq!(pcrs[pcrs.len() - 1], last * 188 * 8 * ts::PCR_HZ / 4_000_000);
+    }
+}
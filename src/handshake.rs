@@ -0,0 +1,39 @@
+//! Line based handshakes preceding the stream
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use bytes::BytesMut;
+
+use std::io;
+
+/// Read a line terminated by `\n`, at most `max` bytes long, yielding the
+/// socket back along with the line and the bytes following it
+pub async fn read_line<S: AsyncRead + Unpin>(socket: S, max: usize) -> io::Result<(S, String, BytesMut)> {
+    read_line_after(socket, BytesMut::with_capacity(max), max).await
+}
+
+/// Same as `read_line`, starting with bytes already read from the socket
+pub async fn read_line_after<S: AsyncRead + Unpin>(mut socket: S, mut buf: BytesMut, max: usize) -> io::Result<(S, String, BytesMut)> {
+    loop {
+        if let Some(end) = buf.iter().position(|&b| b == b'\n') {
+            let line = buf.split_to(end + 1);
+            let line = String::from_utf8(line[..end].to_vec())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid handshake"))?;
+
+            return Ok((socket, line.trim_end_matches('\r').to_owned(), buf));
+        }
+
+        if buf.len() >= max {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Handshake line too long"));
+        }
+
+        buf.reserve(max - buf.len());
+        if socket.read_buf(&mut buf).await? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Incomplete handshake"));
+        }
+    }
+}
+
+/// Compare secrets without leaking how much of them matched
+pub fn secret_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,365 @@
+//! HLS output, segmenting the stream for the web clients
+//!
+//! The segmenter is fed like a consumer, from its own thread. Segments start on
+//! a keyframe with the last PAT and PMT, once they last long enough, and the
+//! playlist keeps a window of the latest ones, in memory and optionally on disk.
+
+use futures::executor;
+use bytes::Bytes;
+use log::{error, info, warn};
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::Shared;
+use crate::http;
+use crate::queue::{self, Overflow};
+use crate::stats::Stats;
+use crate::ts;
+
+/// Key of the segmenter queue in the fanout, no peer can have it
+pub const SEGMENTER_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 1));
+
+/// Served at `/hls/PLAYLIST`, the segments at `/hls/SEQUENCE.ts`
+const PLAYLIST: &str = "index.m3u8";
+
+const PAT_PID: u16 = 0;
+
+/// How the stream is segmented
+#[derive(Clone, Debug)]
+pub struct Hls {
+    /// Also write the segments and the playlist there
+    pub dir: Option<PathBuf>,
+    /// Shortest segment, they are cut on the next keyframe
+    pub segment_duration: Duration,
+    /// Segments listed in the playlist
+    pub window: usize,
+}
+
+struct Segment {
+    sequence: u64,
+    duration: Duration,
+    data: Bytes,
+}
+
+/// The latest segments
+pub struct Playlist {
+    window: usize,
+    target: Duration,
+    segments: VecDeque<Segment>,
+}
+
+impl Playlist {
+    fn new(hls: &Hls) -> Self {
+        Playlist {
+            window: hls.window.max(1),
+            target: hls.segment_duration,
+            segments: VecDeque::new(),
+        }
+    }
+
+    /// Add a segment, returning the ones out of the window
+    fn push(&mut self, segment: Segment) -> Vec<u64> {
+        self.segments.push_back(segment);
+
+        let mut evicted = Vec::new();
+        while self.segments.len() > self.window {
+            evicted.extend(self.segments.pop_front().map(|s| s.sequence));
+        }
+        evicted
+    }
+
+    /// The playlist, without segments until the first one is complete
+    pub fn m3u8(&self) -> String {
+        let longest = self.segments.iter().map(|s| s.duration).max().unwrap_or_default().max(self.target);
+
+        let mut out = String::from("#EXTM3U\n#EXT-X-VERSION:3\n");
+        let _ = writeln!(out, "#EXT-X-TARGETDURATION:{}", longest.as_secs_f64().ceil() as u64);
+        let _ = writeln!(out, "#EXT-X-MEDIA-SEQUENCE:{}", self.segments.front().map_or(0, |s| s.sequence));
+        for segment in &self.segments {
+            let _ = writeln!(out, "#EXTINF:{:.3},\n{}.ts", segment.duration.as_secs_f64(), segment.sequence);
+        }
+        out
+    }
+
+    fn segment(&self, sequence: u64) -> Option<Bytes> {
+        self.segments.iter().find(|s| s.sequence == sequence).map(|s| s.data.clone())
+    }
+}
+
+/// Answer the requests for the playlist and the segments, `None` for the other paths
+pub fn respond(playlist: &Mutex<Playlist>, path: &str) -> Option<Vec<u8>> {
+    // Players may add a query string to bust the caches
+    let path = path.split('?').next().unwrap_or(path);
+    let name = path.strip_prefix("/hls/")?;
+
+    if name == PLAYLIST {
+        let m3u8 = playlist.lock().unwrap().m3u8();
+        return Some(http::response("200 OK", "application/vnd.apple.mpegurl", "Cache-Control: no-cache\r\n", m3u8.as_bytes()));
+    }
+
+    let segment = name.strip_suffix(".ts")
+        .and_then(|sequence| sequence.parse().ok())
+        .and_then(|sequence| playlist.lock().unwrap().segment(sequence));
+
+    Some(match segment {
+        Some(data) => http::response("200 OK", "video/mp2t", "", &data),
+        None => http::response("404 Not Found", "text/plain", "", b"Not found\n"),
+    })
+}
+
+/// Time covered by the packets of the current segment
+struct Clock {
+    /// PID followed and its last PCR
+    pcr: Option<(u16, u64)>,
+    /// PCR ticks since the segment started
+    ticks: u64,
+    /// For the streams without PCR
+    started: Instant,
+}
+
+impl Clock {
+    fn advance(&mut self, pkt: &[u8]) {
+        let (pid, pcr) = match ts::pcr(pkt) {
+            Some(pcr) => pcr,
+            None => return,
+        };
+
+        match self.pcr {
+            Some((followed, last)) if followed == pid => {
+                let step = ts::pcr_step(last, pcr);
+                // Larger steps are discontinuities
+                if step <= ts::PCR_HZ {
+                    self.ticks += step;
+                }
+                self.pcr = Some((pid, pcr));
+            }
+            Some(_) => (),
+            None => self.pcr = Some((pid, pcr)),
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        match self.pcr {
+            Some(_) => Duration::from_nanos(self.ticks * 1000 / 27),
+            None => self.started.elapsed(),
+        }
+    }
+
+    fn restart(&mut self) {
+        self.ticks = 0;
+        self.started = Instant::now();
+    }
+}
+
+/// Cuts the stream into segments
+struct Segmenter {
+    hls: Hls,
+    stats: Arc<Stats>,
+    program: Option<u16>,
+    playlist: Arc<Mutex<Playlist>>,
+    /// The segment being filled, none until the first start point
+    current: Option<Vec<u8>>,
+    clock: Clock,
+    sequence: u64,
+}
+
+impl Segmenter {
+    fn push(&mut self, chunk: &[u8]) {
+        if !ts::is_aligned(chunk) {
+            if let Some(ref mut current) = self.current {
+                current.extend_from_slice(chunk);
+            }
+            return;
+        }
+
+        let video = self.stats.video_pid(self.program);
+
+        for pkt in chunk.chunks(ts::PACKET_SIZE) {
+            self.clock.advance(pkt);
+
+            let pid = ts::pid(pkt);
+            // Without video, at every PAT
+            let start = match video {
+                Some(video) => pid == video && ts::random_access(pkt),
+                None => pid == PAT_PID && pkt[1] & 0x40 != 0,
+            };
+
+            if start && (self.current.is_none() || self.clock.elapsed() >= self.hls.segment_duration) {
+                self.cut();
+                let mut segment = if video.is_some() { self.stats.tables() } else { Vec::new() };
+                segment.extend_from_slice(pkt);
+                self.current = Some(segment);
+                self.clock.restart();
+            } else if let Some(ref mut current) = self.current {
+                current.extend_from_slice(pkt);
+            }
+        }
+    }
+
+    /// Publish the current segment, if any
+    fn cut(&mut self) {
+        let data = match self.current.take() {
+            Some(data) => Bytes::from(data),
+            None => return,
+        };
+
+        let sequence = self.sequence;
+        self.sequence += 1;
+
+        if let Some(ref dir) = self.hls.dir {
+            if let Err(e) = fs::write(dir.join(format!("{}.ts", sequence)), &data) {
+                error!("Cannot write the HLS segment {}: {}", sequence, e);
+            }
+        }
+
+        let segment = Segment { sequence, duration: self.clock.elapsed(), data };
+        let (evicted, m3u8) = {
+            let mut playlist = self.playlist.lock().unwrap();
+            (playlist.push(segment), playlist.m3u8())
+        };
+
+        if let Some(ref dir) = self.hls.dir {
+            for sequence in evicted {
+                if let Err(e) = fs::remove_file(dir.join(format!("{}.ts", sequence))) {
+                    warn!("Cannot delete the HLS segment {}: {}", sequence, e);
+                }
+            }
+            if let Err(e) = write_playlist(dir, &m3u8) {
+                error!("Cannot write the HLS playlist: {}", e);
+            }
+        }
+    }
+}
+
+/// Replace the playlist at once, the clients never read half of it
+fn write_playlist(dir: &Path, m3u8: &str) -> io::Result<()> {
+    let tmp = dir.join(format!(".{}", PLAYLIST));
+    fs::write(&tmp, m3u8)?;
+    fs::rename(tmp, dir.join(PLAYLIST))
+}
+
+/// Start segmenting every packet broadcast, following the video of `program`
+/// or of the first program with video
+pub fn spawn(hls: Hls, state: &Arc<Mutex<Shared>>, program: Option<u16>) -> io::Result<Arc<Mutex<Playlist>>> {
+    let playlist = Arc::new(Mutex::new(Playlist::new(&hls)));
+
+    if let Some(ref dir) = hls.dir {
+        fs::create_dir_all(dir)?;
+        write_playlist(dir, &playlist.lock().unwrap().m3u8())?;
+    }
+
+    let (rx, stats) = {
+        let state = state.lock().unwrap();
+        // Losing packets is better than stalling the producer
+        let (tx, rx) = queue::bounded(state.settings.consumer_queue, Overflow::Drop, Default::default());
+        state.fanout.insert(SEGMENTER_ADDR, tx, 0);
+        (rx, state.stats.clone())
+    };
+
+    let mut segmenter = Segmenter {
+        hls,
+        stats,
+        program,
+        playlist: playlist.clone(),
+        current: None,
+        clock: Clock { pcr: None, ticks: 0, started: Instant::now() },
+        sequence: 0,
+    };
+
+    thread::Builder::new().name("hls".to_owned()).spawn(move || {
+        for packet in executor::block_on_stream(rx) {
+            segmenter.push(&packet);
+        }
+        info!("HLS segmenting stopped");
+    })?;
+
+    Ok(playlist)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psi::tests::{packets, pat, pmt};
+    use crate::ts::tests::pcr_packet;
+
+    fn media(pid: u16, keyframe: bool, pcr: u64) -> Vec<u8> {
+        let mut pkt = pcr_packet(pid, pcr / 300 * 300);
+        if keyframe {
+            pkt[5] |= 0x40;
+        }
+        pkt
+    }
+
+    fn segmenter(window: usize) -> (Segmenter, Vec<u8>) {
+        let stats = Arc::new(Stats::default());
+        let tables = [
+            packets(0, 0, &pat(&[(1, 0x1000)])),
+            packets(0x1000, 0, &pmt(1, 0x100, &[(0x1b, 0x100)])),
+        ].concat();
+        stats.inspect(&tables);
+
+        let hls = Hls { dir: None, segment_duration: Duration::from_secs(2), window };
+        let segmenter = Segmenter {
+            playlist: Arc::new(Mutex::new(Playlist::new(&hls))),
+            hls,
+            stats,
+            program: None,
+            current: None,
+            clock: Clock { pcr: None, ticks: 0, started: Instant::now() },
+            sequence: 0,
+        };
+        (segmenter, tables)
+    }
+
+    #[test]
+    fn empty_playlist() {
+        let (segmenter, _) = segmenter(3);
+        let m3u8 = segmenter.playlist.lock().unwrap().m3u8();
+
+        assert_eq!(m3u8, "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:0\n");
+    }
+
+    #[test]
+    fn cut_on_keyframes() {
+        let (mut segmenter, tables) = segmenter(2);
+
+        // Before the first keyframe, left out
+        segmenter.push(&media(0x100, false, 0));
+        // A keyframe every second, a segment every 2
+        for second in 0..7 {
+            for frame in 0..25u64 {
+                segmenter.push(&media(0x100, frame == 0, (second * 25 + frame) * ts::PCR_HZ / 25));
+            }
+        }
+
+        let playlist = segmenter.playlist.lock().unwrap();
+        assert_eq!(playlist.m3u8(), "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:1\n\
+                                     #EXTINF:2.000,\n1.ts\n#EXTINF:2.000,\n2.ts\n");
+
+        let segment = playlist.segment(2).unwrap();
+        assert_eq!(&segment[..tables.len()], &tables[..]);
+        assert_eq!(segment.len(), tables.len() + 50 * ts::PACKET_SIZE);
+        assert!(ts::random_access(&segment[tables.len()..]));
+        assert!(playlist.segment(0).is_none());
+    }
+
+    #[test]
+    fn requests() {
+        let (segmenter, _) = segmenter(2);
+        let playlist = &segmenter.playlist;
+
+        let res = String::from_utf8(respond(playlist, "/hls/index.m3u8?t=1").unwrap()).unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(res.ends_with("#EXT-X-MEDIA-SEQUENCE:0\n"));
+        assert!(respond(playlist, "/hls/3.ts").unwrap().starts_with(b"HTTP/1.1 404"));
+        assert!(respond(playlist, "/status").is_none());
+    }
+}
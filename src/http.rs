@@ -0,0 +1,157 @@
+//! Minimal HTTP/1.x support for the consumer side
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::time;
+use futures::prelude::*;
+use futures::stream;
+use bytes::BytesMut;
+use log::debug;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::net;
+
+/// Largest request head accepted
+const MAX_HEAD: usize = 8192;
+/// Time a client of `serve` has to send its request and read the response,
+/// so that the idle ones don't hold the concurrency limit
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Request line and headers of an incoming request
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Names lowercased, values trimmed
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn parse(head: &[u8]) -> Option<Request> {
+        let head = ::std::str::from_utf8(head).ok()?;
+        let mut lines = head.split("\r\n");
+
+        let mut request_line = lines.next()?.split(' ');
+        let method = request_line.next()?;
+        let path = request_line.next()?;
+        let version = request_line.next()?;
+
+        if method.is_empty() || !path.starts_with('/') || !version.starts_with("HTTP/1.") || request_line.next().is_some() {
+            return None;
+        }
+
+        let mut headers = Vec::new();
+        for line in lines {
+            let (name, value) = line.split_once(':')?;
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_owned()));
+        }
+
+        Some(Request {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            headers,
+        })
+    }
+
+    /// Value of the header `name`, given lowercase
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str())
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Read the request head, yielding the socket back once it is complete
+pub async fn read_request<S: AsyncRead + Unpin>(mut socket: S) -> io::Result<(S, Request)> {
+    let mut buf = BytesMut::with_capacity(1024);
+
+    loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let req = Request::parse(&buf[..end]).ok_or_else(|| invalid("Malformed request"))?;
+
+            return Ok((socket, req));
+        }
+
+        if buf.len() >= MAX_HEAD {
+            return Err(invalid("Request too large"));
+        }
+
+        buf.reserve(1024);
+        if socket.read_buf(&mut buf).await? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Incomplete request"));
+        }
+    }
+}
+
+/// Response head for a successful stream request
+pub const STREAM_OK: &[u8] = b"HTTP/1.1 200 OK\r\n\
+Content-Type: video/mp2t\r\n\
+Cache-Control: no-cache\r\n\
+Connection: close\r\n\r\n";
+
+/// Build a complete response
+pub fn response(status: &str, content_type: &str, extra_headers: &str, body: &[u8]) -> Vec<u8> {
+    let head = format!("HTTP/1.1 {}\r\n{}Content-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                       status, extra_headers, content_type, body.len());
+    let mut res = head.into_bytes();
+    res.extend_from_slice(body);
+    res
+}
+
+/// Answer every request on `addr` with the response built by `handler`
+pub fn serve<F>(addr: &SocketAddr, handler: F) -> io::Result<impl Future<Output = ()>>
+where
+    F: Fn(&Request) -> Vec<u8> + Send + Sync + 'static,
+{
+    let listener = net::listen(addr)?;
+    let handler = Arc::new(handler);
+
+    let connections = stream::unfold(listener, |listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((socket, _)) => return Some((socket, listener)),
+                Err(e) => {
+                    debug!("Cannot accept a connection: {}", e);
+                    time::sleep(net::ACCEPT_ERROR_DELAY).await;
+                }
+            }
+        }
+    });
+
+    let server = connections.for_each_concurrent(100, move |socket| {
+        let handler = handler.clone();
+
+        let exchange = async move {
+            if let Ok((mut socket, req)) = read_request(socket).await {
+                let _ = socket.write_all(&handler(&req)).await;
+            }
+        };
+        time::timeout(EXCHANGE_TIMEOUT, exchange).map(|_| ())
+    });
+
+    Ok(server)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request() {
+        let req = Request::parse(b"GET /live HTTP/1.1\r\nHost: example\r\nUser-Agent: VLC").unwrap();
+        assert_eq!(req.method, "GET");
+        assert_eq!(req.path, "/live");
+        assert_eq!(req.header("user-agent"), Some("VLC"));
+        assert_eq!(req.header("accept"), None);
+
+        assert!(Request::parse(b"GET /live").is_none());
+        assert!(Request::parse(b"GET live HTTP/1.1").is_none());
+        assert!(Request::parse(b"GET / HTTP/1.1\r\nnot a header").is_none());
+        assert!(Request::parse(b"\xff\xfe").is_none());
+    }
+}
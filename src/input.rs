@@ -0,0 +1,107 @@
+//! Producer input and consumer output description
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::srt::SrtUrl;
+
+/// Where the producer stream comes from
+#[derive(Clone, Debug)]
+pub enum Input {
+    /// Listen for a producer connection
+    Tcp(SocketAddr),
+    /// Receive datagrams, joining the group if the address is multicast
+    Udp(SocketAddr),
+    /// Connect to the producer, reconnecting when it goes away
+    Pull(SocketAddr),
+    /// Play a local file in a loop
+    File(PathBuf),
+    /// Listen for a producer on a Unix socket
+    Unix(PathBuf),
+    /// Read the standard input, e.g. piped from ffmpeg
+    Stdin,
+    /// Receive the producer over SRT
+    Srt(SrtUrl),
+    /// Generate a test signal at this many bits per second
+    Generate(u64),
+}
+
+impl FromStr for Input {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Input::Unix(path.into()));
+        }
+        if s == "-" {
+            return Ok(Input::Stdin);
+        }
+
+        let mut parts = s.splitn(2, "://");
+        let (scheme, rest) = match (parts.next(), parts.next()) {
+            (Some(scheme), Some(rest)) => (scheme, rest),
+            _ => return Err(format!("Missing scheme in {}", s)),
+        };
+
+        let addr = || {
+            rest.parse::<SocketAddr>()
+                .map_err(|e| format!("Invalid address {}: {}", rest, e))
+        };
+
+        match scheme {
+            "tcp" => addr().map(Input::Tcp),
+            "udp" => addr().map(Input::Udp),
+            "file" => Ok(Input::File(rest.into())),
+            "srt" => rest.parse().map(Input::Srt),
+            _ => Err(format!("Unsupported scheme {}", scheme)),
+        }
+    }
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Input::Tcp(ref addr) => write!(f, "tcp://{}", addr),
+            Input::Udp(ref addr) => write!(f, "udp://{}", addr),
+            Input::Pull(ref addr) => write!(f, "pull from tcp://{}", addr),
+            Input::File(ref path) => write!(f, "file://{}", path.display()),
+            Input::Unix(ref path) => write!(f, "unix:{}", path.display()),
+            Input::Stdin => f.write_str("stdin"),
+            Input::Srt(ref url) => url.fmt(f),
+            Input::Generate(bitrate) => write!(f, "test signal at {} bit/s", bitrate),
+        }
+    }
+}
+
+/// Where the consumers connect
+#[derive(Clone, Debug)]
+pub enum Output {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Output::Unix(path.into()));
+        }
+
+        match s.strip_prefix("tcp://") {
+            Some(rest) => rest.parse().map(Output::Tcp).map_err(|e| format!("Invalid address {}: {}", rest, e)),
+            None => Err(format!("Unsupported output {}, expected tcp://ADDR:PORT or unix:PATH", s)),
+        }
+    }
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Output::Tcp(ref addr) => write!(f, "tcp://{}", addr),
+            Output::Unix(ref path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Null packets keeping the consumers connected while the producer is silent
+//!
+//! Some receivers hang up after a few seconds without data and miss the start
+//! of the stream when it comes back. With `--keepalive-nulls` they are sent a
+//! burst of null packets every interval, for as long as no producer is
+//! connected or the one connected sends nothing. The bursts go straight to the
+//! consumer queues: not to the relays, the outputs nor the recording, and not
+//! kept in the burst buffer nor counted as broadcast.
+
+use bytes::Bytes;
+use log::info;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::Shared;
+use crate::ts;
+
+/// How often the producer is checked
+const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Packets in a burst, a chunk worth
+const BURST_PACKETS: usize = 7;
+
+const NULL_PID: u16 = 0x1fff;
+
+/// A burst of null packets, their continuity counters following on from `cc`
+fn burst(cc: &mut u8) -> Bytes {
+    let mut packets = Vec::with_capacity(BURST_PACKETS * ts::PACKET_SIZE);
+    for _ in 0..BURST_PACKETS {
+        let mut pkt = [0xff; ts::PACKET_SIZE];
+        pkt[..4].copy_from_slice(&[ts::SYNC_BYTE, (NULL_PID >> 8) as u8, NULL_PID as u8, 0x10 | *cc]);
+        packets.extend_from_slice(&pkt);
+        *cc = (*cc + 1) & 0x0f;
+    }
+    Bytes::from(packets)
+}
+
+/// Send a burst of null packets to the consumers every `interval` the
+/// stream has been idle for
+pub async fn send(state: Arc<Mutex<Shared>>, interval: Duration) {
+    let (stats, fanout) = {
+        let state = state.lock().unwrap();
+        (state.stats.clone(), state.fanout.clone())
+    };
+    let mut cc = 0;
+    let mut last: Option<Instant> = None;
+
+    let mut ticks = crate::interval(CHECK_INTERVAL.min(interval));
+    loop {
+        ticks.tick().await;
+        // The stream is back, the next silence waits for the whole interval again
+        if fanout.idle() < interval {
+            if last.take().is_some() {
+                info!("Stream resumed, no more keepalive null packets");
+            }
+            continue;
+        }
+        if last.is_some_and(|last| last.elapsed() < interval) {
+            continue;
+        }
+
+        let consumers: Vec<SocketAddr> = state.lock().unwrap().consumers.keys().cloned().collect();
+        let burst = burst(&mut cc);
+        let sent = fanout.keepalive(&burst, &consumers);
+        if sent.is_empty() {
+            continue;
+        }
+        if last.is_none() {
+            info!("No data for {:?}, sending keepalive null packets to {} consumers", interval, sent.len());
+        }
+        stats.add_keepalive(&sent, burst.len());
+        last = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_carry_on() {
+        let mut cc = 12;
+        let first = burst(&mut cc);
+        let second = burst(&mut cc);
+
+        assert!(ts::is_aligned(&first));
+        let counters: Vec<_> = first.chunks(ts::PACKET_SIZE).chain(second.chunks(ts::PACKET_SIZE))
+            .inspect(|pkt| assert_eq!(ts::pid(pkt), NULL_PID))
+            .map(|pkt| pkt[3] & 0x0f)
+            .collect();
+        assert_eq!(counters, vec![12, 13, 14, 15, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    }
+}
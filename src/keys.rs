@@ -0,0 +1,291 @@
+//! Routing on stream keys
+//!
+//! The producers send `PUBLISH <key>` and the consumers `PLAY <key>` as their
+//! first line, every key gets a stream of its own, created on the first
+//! publish and torn down once its producer leaves.
+
+use tokio::net::TcpStream;
+use tokio::time;
+use bytes::BytesMut;
+use log::{info, warn};
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::{accept_tcp, setup_consumer, setup_producer, OneShotSharedRx, Settings, Shared, TSPacket, AUTH_TIMEOUT};
+use crate::tls::Tls;
+use crate::burst::Burst;
+use crate::filter::PidFilter;
+use crate::remap::PidRemap;
+use crate::handshake;
+use crate::net::Socket;
+use crate::stats::Stats;
+use crate::tr101290;
+
+/// Longest key accepted
+const MAX_KEY: usize = 64;
+
+/// Whether `key` is 1 to 64 letters, digits, `_`, `-` or `.`, not starting with a `.`
+pub fn valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY && !key.starts_with('.') &&
+        key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-' || b == b'.')
+}
+
+/// The key of a `<command> <key>` line
+fn parse_line<'a>(line: &'a str, command: &str) -> Result<&'a str, String> {
+    let mut words = line.splitn(2, ' ');
+
+    match (words.next(), words.next()) {
+        (Some(c), Some(key)) if c == command && valid_key(key) => Ok(key),
+        (Some(c), Some(key)) if c == command => Err(format!("invalid key {:?}", key)),
+        _ => Err(format!("expected {} <key>", command)),
+    }
+}
+
+/// Starts streaming to a consumer once its stream is live
+type Waiting = Box<dyn FnOnce(Arc<Mutex<Shared>>, OneShotSharedRx) + Send>;
+
+struct Stream {
+    state: Arc<Mutex<Shared>>,
+    /// Resolves once the producer is gone
+    session: Option<OneShotSharedRx>,
+    /// Consumers waiting for the producer
+    waiting: Vec<Waiting>,
+}
+
+/// The streams by key, along with what is needed to create them
+pub struct Router {
+    /// Settings, access lists and shutdown of all the streams
+    template: Arc<Mutex<Shared>>,
+    streams: Mutex<HashMap<String, Stream>>,
+
+    burst: Option<Burst>,
+    filter: Option<PidFilter>,
+    program: Option<u16>,
+    remap: Option<PidRemap>,
+    chunk_size: usize,
+    buffer_size: usize,
+    align: bool,
+    takeover: bool,
+    /// Park the consumers of an unknown key instead of rejecting them
+    wait: bool,
+}
+
+impl Router {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(template: Arc<Mutex<Shared>>, burst: Option<Burst>, filter: Option<PidFilter>, program: Option<u16>,
+               remap: Option<PidRemap>, chunk_size: usize, buffer_size: usize, align: bool, takeover: bool, wait: bool) -> Self {
+        Router {
+            template,
+            streams: Mutex::new(HashMap::new()),
+            burst,
+            filter,
+            program,
+            remap,
+            chunk_size,
+            buffer_size,
+            align,
+            takeover,
+            wait,
+        }
+    }
+
+    fn stream<'a>(&self, streams: &'a mut HashMap<String, Stream>, key: &str) -> &'a mut Stream {
+        streams.entry(key.to_owned()).or_insert_with(|| {
+            let (settings, events) = {
+                let template = self.template.lock().unwrap();
+                (template.settings.clone(), template.stats.events.clone())
+            };
+            let shared = Shared::new(settings, self.burst, self.filter.clone(), self.program, self.remap.clone(),
+                                     self.chunk_size, Stats::for_key(key, events));
+            let state = Arc::new(Mutex::new(shared));
+            tokio::spawn(tr101290::watch(Arc::downgrade(&state)));
+
+            Stream { state, session: None, waiting: Vec::new() }
+        })
+    }
+
+    /// Start streaming `key` from the producer, `rd` being what followed its handshake
+    fn publish<S: Socket>(router: &Arc<Router>, key: &str, socket: S, rd: BytesMut) {
+        let addr = match socket.peer_addr() {
+            Ok(addr) => addr,
+            Err(_) => return,
+        };
+
+        if router.template.lock().unwrap().shutting_down {
+            warn!("Refusing Producer ({:?}) for {}, shutting down", addr, key);
+            return;
+        }
+
+        let mut streams = router.streams.lock().unwrap();
+        let stream = router.stream(&mut streams, key);
+
+        let packets = TSPacket::with_read_buf(socket, router.buffer_size, router.align, rd);
+        let rx = match setup_producer(packets, stream.state.clone(), router.takeover) {
+            Some(rx) => rx,
+            // Rejected, or taking over a session still going on
+            None => return,
+        };
+
+        stream.session = Some(rx.clone());
+        for start in stream.waiting.drain(..) {
+            start(stream.state.clone(), rx.clone());
+        }
+
+        let router = router.clone();
+        let key = key.to_owned();
+        let state = stream.state.clone();
+        tokio::spawn(async move {
+            let _ = rx.await;
+            router.teardown(&key, &state);
+        });
+    }
+
+    /// Forget the stream of `key` once its producer left, unless a new one published already
+    fn teardown(&self, key: &str, state: &Arc<Mutex<Shared>>) {
+        let mut streams = self.streams.lock().unwrap();
+
+        // Consumers that started waiting meanwhile keep it for the next producer
+        let gone = streams.get(key).is_some_and(|stream| {
+            Arc::ptr_eq(&stream.state, state) && stream.waiting.is_empty() && state.lock().unwrap().session.is_none()
+        });
+
+        if gone {
+            info!("Closing stream {}", key);
+            streams.remove(key);
+        }
+    }
+
+    /// Serve `key` to the consumer, or park it until a producer publishes
+    fn play<S: Socket>(&self, key: &str, socket: S, addr: SocketAddr) {
+        let mut streams = self.streams.lock().unwrap();
+
+        let live = streams.get(key).and_then(|stream| {
+            // The session ended, the teardown is on its way
+            stream.state.lock().unwrap().session.as_ref()?;
+            Some((stream.state.clone(), stream.session.clone()?))
+        });
+
+        match live {
+            Some((state, rx)) => {
+                let slot = {
+                    let state = state.lock().unwrap();
+                    if let Some(reason) = state.refusal() {
+                        warn!("Refusing Consumer ({:?}) for {}, {}", addr, key, reason);
+                        return;
+                    }
+                    state.reserve()
+                };
+                setup_consumer(socket, state, rx, self.buffer_size, slot);
+            }
+            None if self.wait => {
+                info!("Consumer ({:?}) waiting for {}", addr, key);
+                let stream = self.stream(&mut streams, key);
+                stream.session = None;
+                let buffer_size = self.buffer_size;
+                stream.waiting.push(Box::new(move |state, rx| {
+                    let slot = state.lock().unwrap().reserve();
+                    setup_consumer(socket, state, rx, buffer_size, slot)
+                }));
+            }
+            None => warn!("Rejecting Consumer ({:?}), nothing published on {}", addr, key),
+        }
+    }
+
+    /// Every stream currently known
+    pub fn states(&self) -> Vec<Arc<Mutex<Shared>>> {
+        self.streams.lock().unwrap().values().map(|stream| stream.state.clone()).collect()
+    }
+
+    /// Counters of the stream of `key`, if it is known
+    pub fn stats(&self, key: &str) -> Option<Arc<Stats>> {
+        self.streams.lock().unwrap().get(key).map(|stream| stream.state.lock().unwrap().stats.clone())
+    }
+
+    /// Apply new settings to the streams
+    pub fn update_settings(&self, settings: &Settings) {
+        for state in self.states() {
+            state.lock().unwrap().update_settings(settings.clone());
+        }
+    }
+
+    /// Stop every producer, no new one is accepted past that
+    pub fn shutdown(&self) {
+        for state in self.states() {
+            state.lock().unwrap().shutdown();
+        }
+
+        // Nothing will be published for them anymore
+        for stream in self.streams.lock().unwrap().values_mut() {
+            stream.waiting.clear();
+        }
+    }
+}
+
+/// Read the `PUBLISH <key>` line of a producer, `rd` being what it sent already
+pub fn accept_producer<S: Socket>(router: Arc<Router>, socket: S, rd: BytesMut) {
+    let addr = match socket.peer_addr() {
+        Ok(addr) => addr,
+        Err(_) => return,
+    };
+
+    tokio::spawn(async move {
+        let handshake = handshake::read_line_after(socket, rd, MAX_KEY + "PUBLISH \r\n".len());
+        match time::timeout(AUTH_TIMEOUT, handshake).await {
+            Ok(Ok((socket, line, rest))) => match parse_line(&line, "PUBLISH") {
+                Ok(key) => Router::publish(&router, key, socket, rest),
+                Err(e) => warn!("Rejecting Producer ({:?}), {}", addr, e),
+            },
+            Ok(Err(e)) => warn!("Rejecting Producer ({:?}), {}", addr, e),
+            Err(_) => warn!("Rejecting Producer ({:?}), no key within {:?}", addr, AUTH_TIMEOUT),
+        }
+    });
+}
+
+/// Read the `PLAY <key>` line of a consumer, after the TLS handshake if any
+pub fn accept_consumer(router: Arc<Router>, socket: TcpStream, tls: Option<Tls>) {
+    let template = router.template.clone();
+    accept_tcp(socket, &template, false, move |socket, addr| {
+        match tls {
+            Some(ref tls) => tls.accept(socket, addr, move |socket| read_play(router, socket, addr)),
+            None => read_play(router, socket, addr),
+        }
+    });
+}
+
+fn read_play<S: Socket>(router: Arc<Router>, socket: S, addr: SocketAddr) {
+    tokio::spawn(async move {
+        match time::timeout(AUTH_TIMEOUT, handshake::read_line(socket, MAX_KEY + "PLAY \r\n".len())).await {
+            Ok(Ok((socket, line, _))) => match parse_line(&line, "PLAY") {
+                Ok(key) => router.play(key, socket, addr),
+                Err(e) => warn!("Rejecting Consumer ({:?}), {}", addr, e),
+            },
+            Ok(Err(e)) => warn!("Rejecting Consumer ({:?}), {}", addr, e),
+            Err(_) => warn!("Rejecting Consumer ({:?}), no key within {:?}", addr, AUTH_TIMEOUT),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys() {
+        assert!(valid_key("live_1.main-hd"));
+        assert!(!valid_key(""));
+        assert!(!valid_key(".hidden"));
+        assert!(!valid_key("../etc"));
+        assert!(!valid_key("with space"));
+        assert!(!valid_key(&"k".repeat(MAX_KEY + 1)));
+    }
+
+    #[test]
+    fn lines() {
+        assert_eq!(parse_line("PUBLISH cam1", "PUBLISH"), Ok("cam1"));
+        assert!(parse_line("PLAY cam1", "PUBLISH").is_err());
+        assert!(parse_line("PLAY cam/1", "PLAY").is_err());
+        assert!(parse_line("PLAY", "PLAY").is_err());
+    }
+}
@@ -0,0 +1,2029 @@
+//! Fan out a single MPEG-TS producer to any number of consumers
+//!
+//! ```no_run
+//! use restream::Restreamer;
+//! use tokio::runtime::Runtime;
+//!
+//! let rt = Runtime::new().unwrap();
+//! let restreamer = Restreamer::builder()
+//!     .producer_listener("127.0.0.1:12345".parse().unwrap())
+//!     .consumer_listener("127.0.0.1:12346".parse().unwrap())
+//!     .spawn(&rt)
+//!     .unwrap();
+//! ```
+
+mod handshake;
+mod hls;
+mod http;
+mod access;
+#[cfg(unix)]
+mod account;
+mod acl;
+mod bandwidth;
+mod batch;
+mod burst;
+mod cbr;
+mod cc;
+mod cipher;
+#[cfg(unix)]
+mod control;
+mod delay;
+mod dvr;
+mod events;
+mod failover;
+mod fanout;
+mod filter;
+mod generate;
+mod input;
+mod keepalive;
+mod keys;
+#[cfg(feature = "srt")]
+mod libsrt;
+mod merge;
+mod net;
+mod normalize;
+mod options;
+mod pace;
+mod pcr;
+mod play;
+mod pool;
+mod priority;
+mod probe;
+mod pull;
+mod proxy;
+mod psi;
+mod push;
+mod queue;
+mod record;
+mod relay;
+mod remap;
+mod restreamer;
+#[cfg(feature = "rist")]
+mod rist;
+mod rtp;
+mod simulate;
+mod snapshot;
+mod scrambled;
+mod scte35;
+mod sink;
+mod splice;
+mod srt;
+mod stall;
+mod start;
+mod stats;
+mod stdin;
+#[cfg(unix)]
+mod systemd;
+mod tls;
+mod tr101290;
+mod transfer;
+mod ts;
+mod udp;
+mod unix;
+mod validate;
+mod webhook;
+mod ws;
+
+pub use crate::access::{AccessFormat, AccessLog};
+#[cfg(unix)]
+pub use crate::account::Account;
+pub use crate::acl::{parse_accept_rate, Acl, Cidr};
+pub use crate::bandwidth::Bandwidth;
+pub use crate::burst::Burst;
+pub use crate::cbr::split_cbr;
+pub use crate::cipher::{Aes, Iv, Key};
+pub use crate::delay::BroadcastDelay;
+pub use crate::events::{Event, Events, Reason};
+pub use crate::filter::{parse_pid, PidFilter};
+pub use crate::hls::Hls;
+pub use crate::input::{Input, Output};
+pub use crate::net::{parse_dscp, Backoff, LocalBind};
+pub use crate::normalize::parse_packet_size;
+pub use crate::options::{parse_bitrate, parse_secs, secs_duration};
+pub use crate::priority::Priority;
+pub use crate::probe::{probe, Report};
+pub use crate::queue::Overflow;
+pub use crate::record::{parse_size, Fsync, Record};
+pub use crate::remap::{PidRemap, Remap};
+pub use crate::srt::{SrtMode, SrtOptions, SrtUrl};
+pub use crate::restreamer::{Builder, Restreamer};
+pub use crate::scte35::{Cue, SpliceCommand};
+pub use crate::simulate::{Simulate, Simulator};
+pub use crate::sink::Sink;
+pub use crate::stats::Stats;
+#[cfg(unix)]
+pub use crate::systemd::{sd_notify, Activation};
+pub use crate::tls::{ProducerTls, Tls};
+pub use crate::transfer::{play, record, Copied, Ending};
+pub use crate::tr101290::{Check, Thresholds};
+pub use crate::udp::UdpTarget;
+pub use crate::unix::parse_mode;
+pub use crate::webhook::Webhook;
+
+use tokio::io::{AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::time::{self, Sleep};
+use tokio_util::io::{poll_read_buf, poll_write_buf};
+use futures::prelude::*;
+use bytes::{Buf, Bytes, BytesMut};
+use log::{debug, error, info, warn};
+
+use std::time::{Duration, Instant};
+
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Mutex, Arc, Weak};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::task::{ready, Context, Poll};
+
+use crate::fanout::{Fanout, Rewind};
+use crate::cipher::{Decryptor, Encryptor};
+use crate::filter::ProgramFilter;
+use crate::acl::AcceptRate;
+use crate::bandwidth::Share;
+use crate::batch::WriteBatch;
+use crate::options::{ConsumerOptions, Pace};
+use crate::start::CleanStart;
+use crate::validate::Validation;
+use crate::normalize::Normalizer;
+use crate::pool::BufferPool;
+use crate::record::RECORDER_ADDR;
+use crate::splice::Splice;
+use crate::stats::{CbrStats, PeerStats};
+use crate::net::{PeerName, Socket};
+use crate::proxy::Proxied;
+use crate::simulate::Impairment;
+use crate::cbr::Cbr;
+use crate::ws::WebSocket;
+
+/// Time given to the producer to send its token
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time given to the load balancer to send the PROXY header
+const PROXY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Chunks a pooled read buffer holds
+const POOLED_CHUNKS: usize = 16;
+
+type Tx = queue::Sender;
+type Rx = queue::Receiver;
+
+type OneShotTx = oneshot::Sender<()>;
+type OneShotRx = oneshot::Receiver<()>;
+type OneShotSharedRx = futures::future::Shared<OneShotRx>;
+
+enum Kind {
+    /// Resolves once the producer is gone, polled until it does only
+    Consumer(OneShotSharedRx),
+    /// Resolves once another producer takes over, the flag is cleared right away
+    Producer(OneShotRx, Arc<AtomicBool>),
+}
+
+impl Kind {
+    fn is_producer(&self) -> bool {
+        matches!(self, Kind::Producer(..))
+    }
+    fn is_consumer(&self) -> bool {
+        !self.is_producer()
+    }
+}
+
+/// Runtime tunable settings
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// Packets queued per consumer
+    pub consumer_queue: usize,
+    pub overflow: Overflow,
+    /// Disconnect the consumers falling behind by more than this many bytes
+    pub max_lag_bytes: Option<usize>,
+    /// Disconnect the consumers not accepting data for this long
+    pub max_lag: Option<Duration>,
+    /// Disconnect the consumers whose socket accepts nothing for this long, even if no new data arrives
+    pub idle_timeout: Option<Duration>,
+    pub producer_acl: Acl,
+    pub consumer_acl: Acl,
+    pub max_consumers: Option<usize>,
+    /// Disable Nagle on the accepted sockets
+    pub nodelay: bool,
+    /// Probe the idle connections to detect the dead peers
+    pub keepalive: Option<Duration>,
+    /// Stop reading from the producer while this many bytes wait to be fanned out
+    pub max_read_buffer: usize,
+    /// Bytes of the queued chunks written to a consumer at once, 1 for a chunk at a time
+    pub write_batch: usize,
+    /// Read buffers of the producers kept for reuse, 0 to allocate every one
+    pub read_pool: usize,
+    /// Give the new consumers a moment to send their options
+    pub consumer_options: bool,
+    /// Resume the relays pulling from here where they left off, and resume
+    /// the stream pulled from an upstream restreamer likewise
+    pub relay_resume: bool,
+    /// Keep the queue of a consumer with a token that lost its connection for
+    /// this long, for it to come back to
+    pub reconnect_grace: Option<Duration>,
+    /// Send each consumer at most this many bits per second, unless it asks for its own pace
+    pub rate_limit: Option<u64>,
+    /// Shared by the consumers of every channel given the same one
+    pub bandwidth: Option<Arc<Bandwidth>>,
+    /// Tiers of the consumers, the highest of the rules matching wins
+    pub priorities: Vec<Priority>,
+    /// Read a PROXY header on the TCP connections, tracking the peers by the address it carries
+    pub proxy_protocol: bool,
+    /// Accept at most this many TCP connections per second from each address, closing the others
+    pub accept_rate: Option<f64>,
+    /// Start the new consumers on the tables and a keyframe, waiting for them
+    /// no longer than this
+    pub clean_start: Option<Duration>,
+    /// Fan out nothing of the producers until their input shows TS packets,
+    /// dropping them past this many sync losses a minute
+    pub validate_input: Option<usize>,
+    /// Size of the producer packets, detected from the sync bytes when unset
+    pub packet_size: Option<usize>,
+    /// DSCP marking the packets sent on the TCP connections of the consumers and producers
+    pub dscp: Option<u8>,
+    /// How long the tables and PIDs may be missing before the TR 101 290 indicators are raised
+    pub thresholds: Thresholds,
+    /// Encrypt the stream sent to the TCP consumers
+    pub encrypt: Option<Aes>,
+    /// Decrypt the stream of the producers, as encrypted by a restreamer upstream
+    pub decrypt: Option<Aes>,
+    /// Drop and delay the chunks sent to the TCP consumers, for testing only
+    pub simulate: Option<Arc<Simulator>>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            consumer_queue: 1024,
+            overflow: Overflow::Drop,
+            max_lag_bytes: None,
+            max_lag: None,
+            idle_timeout: None,
+            producer_acl: Acl::default(),
+            consumer_acl: Acl::default(),
+            max_consumers: None,
+            nodelay: true,
+            keepalive: None,
+            max_read_buffer: 4 * 1024 * 1024,
+            write_batch: 64 * 1024,
+            read_pool: 256,
+            consumer_options: false,
+            relay_resume: false,
+            reconnect_grace: None,
+            rate_limit: None,
+            bandwidth: None,
+            priorities: Vec::new(),
+            proxy_protocol: false,
+            accept_rate: None,
+            clean_start: None,
+            validate_input: None,
+            packet_size: None,
+            dscp: None,
+            thresholds: Thresholds::default(),
+            encrypt: None,
+            decrypt: None,
+            simulate: None,
+        }
+    }
+}
+
+/// The producer currently fanning out
+struct Session {
+    addr: SocketAddr,
+    /// Dropping it stops the producer
+    stop: OneShotTx,
+    /// Cleared as soon as the producer must stop fanning out
+    active: Arc<AtomicBool>,
+    /// Dropping it wakes up the consumers
+    _done: OneShotTx,
+}
+
+/// Queue of a consumer that lost its connection, still a member of the fan-out
+struct Parked {
+    /// Its address in the fan-out, that of the connection lost
+    addr: SocketAddr,
+    rx: Rx,
+    expires: Instant,
+}
+
+struct Shared {
+    fanout: Arc<Fanout>,
+    /// Connected consumers with their tier, the UDP outputs are not counted
+    consumers: HashMap<SocketAddr, u8>,
+    /// Consumers let in but still in their handshake, not in `consumers` yet
+    handshakes: Arc<AtomicUsize>,
+    /// Queues waiting for their consumer to reconnect, by token
+    parked: HashMap<String, Parked>,
+    session: Option<Session>,
+    settings: Settings,
+    /// Read buffers of the producers
+    pool: Arc<BufferPool>,
+    /// The pipe to the recorder, with `--record-splice`
+    splice: Option<Arc<Splice>>,
+    /// Bumped on every reload, the consumers only lock to pick up the settings then
+    reloads: Arc<AtomicUsize>,
+    stats: Arc<Stats>,
+    /// Connections recently accepted from each address
+    accepts: AcceptRate,
+    /// No new producer is accepted once set
+    shutting_down: bool,
+}
+
+/// The place of a consumer in its handshake, counted against `max_consumers`
+/// until dropped once the consumer is registered or gone
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct Peer<S: Socket = TcpStream> {
+    packets: TSPacket<S>,
+    state: Arc<Mutex<Shared>>,
+    fanout: Arc<Fanout>,
+
+    rx: Rx,
+    reloads: Arc<AtomicUsize>,
+    /// Reloads the settings below were picked up from
+    reloaded: usize,
+    max_lag_bytes: Option<usize>,
+    max_lag: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    /// Fires when the socket went idle for too long
+    idle: Option<Pin<Box<Sleep>>>,
+
+    addr: SocketAddr,
+    name: PeerName,
+    kind: Kind,
+    channel: Option<usize>,
+    key: Option<String>,
+    /// Bytes written when the producer went away, the rest is being flushed
+    closing: Option<u64>,
+    /// The write buffer went past its high-water mark, the queue is left
+    /// alone until it drains
+    draining: bool,
+    /// Why the peer is going away, for the events
+    reason: Reason,
+    /// Asked for by the consumer
+    program: Option<ProgramFilter>,
+    pace: Option<Pace>,
+    /// Taken from the total bandwidth
+    share: Option<Share>,
+    /// Waiting for a clean start point, the data is held back meanwhile
+    start: Option<CleanStart>,
+    /// Drops and delays the chunks, when simulating a poor link
+    impairment: Option<Impairment>,
+    /// Pads the stream to a constant bitrate, for a push target
+    cbr: Option<Cbr>,
+    /// Encrypts the chunks of a consumer
+    encryptor: Option<Encryptor>,
+    /// Decrypts the chunks of a producer
+    decryptor: Option<Decryptor>,
+    /// Replaying the time-shift buffer, not a member of the fan-out yet
+    rewind: Option<Rewind>,
+    /// The pipe to the recorder, for a producer that can be spliced into it
+    splice: Option<Arc<Splice>>,
+    /// Spliced into the recorder pipe rather than read
+    splicing: bool,
+    /// Its queue is parked under it when the connection is lost, with `--reconnect-grace`
+    token: Option<String>,
+}
+
+/// TS Packet chunker
+struct TSPacket<S: Socket = TcpStream> {
+    buffer_size: usize,
+    align: bool,
+    socket: S,
+
+    rd: BytesMut,
+    /// Past it the producer is left in the socket, TCP slows it down
+    max_read_buffer: usize,
+    /// Where the read buffers come from, the producers only
+    pool: Option<Arc<BufferPool>>,
+    wr: WriteBatch,
+    /// Last time the socket accepted some data
+    last_write: Instant,
+    stats: Arc<PeerStats>,
+    /// Checks that a producer sends TS packets
+    validation: Option<Validation>,
+    /// Strips the producer packets down to 188 bytes
+    normalizer: Option<Normalizer>,
+    /// Frames the chunks for the WebSocket consumers
+    ws: Option<WebSocket>,
+}
+
+impl Shared {
+    fn new(settings: Settings, burst: Option<Burst>, filter: Option<PidFilter>, program: Option<u16>, remap: Option<PidRemap>,
+           chunk_size: usize, stats: Stats) -> Self {
+        let stats = Arc::new(stats);
+        let pool = Arc::new(BufferPool::new(settings.read_pool));
+        stats.set_pool(pool.clone());
+        let program = program.map(|number| ProgramFilter::new(number, stats.clone()));
+        let fanout = Fanout::new(burst).with_stats(stats.clone()).with_filter(filter, program, chunk_size).with_remap(remap);
+
+        Shared {
+            fanout: Arc::new(fanout),
+            consumers: HashMap::new(),
+            handshakes: Arc::new(AtomicUsize::new(0)),
+            parked: HashMap::new(),
+            session: None,
+            pool,
+            splice: None,
+            settings,
+            reloads: Arc::new(AtomicUsize::new(0)),
+            stats,
+            accepts: AcceptRate::default(),
+            shutting_down: false,
+        }
+    }
+
+    /// Register a consumer, pre-filling its queue with the burst buffer, up to
+    /// the one it asked for, starting it in the past if it asked to rewind, or
+    /// where it left off if it is a relay resuming. No `tx` for a consumer given
+    /// its parked queue back, a member already
+    fn add_consumer(&mut self, addr: SocketAddr, tx: Option<Tx>, stats: Arc<PeerStats>, options: &ConsumerOptions) -> Option<Rewind> {
+        self.consumers.insert(addr, stats.priority());
+        self.stats.add_consumer(stats);
+
+        let tx = tx?;
+
+        if options.relay {
+            let start = self.fanout.insert_relay(addr, tx, self.settings.consumer_queue, options.resume);
+            match options.resume {
+                Some(resume) if resume == start => info!("Relay ({:?}) resumed at {}", addr, start),
+                Some(resume) => warn!("Relay ({:?}) cannot resume at {}, no longer buffered, joining at {}", addr, resume, start),
+                None => info!("Relay ({:?}) joined at {}", addr, start),
+            }
+            return None;
+        }
+
+        let tx = match options.rewind {
+            Some(by) => match self.fanout.rewind(addr, tx, by) {
+                Ok(rewind) => return Some(rewind),
+                Err(tx) => {
+                    warn!("Consumer ({:?}) asked to rewind, there is no time-shift buffer", addr);
+                    tx
+                }
+            },
+            None => tx,
+        };
+        // Never overflow the queue right away
+        self.fanout.insert_within(addr, tx, self.settings.consumer_queue, options.burst);
+        None
+    }
+
+    /// Keep the queue of a consumer that lost its connection until `expires`,
+    /// replacing the one parked under the same token
+    fn park(&mut self, token: String, addr: SocketAddr, rx: Rx, expires: Instant) {
+        if let Some(old) = self.parked.insert(token, Parked { addr, rx, expires }) {
+            self.fanout.remove(&old.addr);
+        }
+    }
+
+    /// The queue parked under `token`, now going to `addr`, unless it expired or
+    /// got closed meanwhile
+    fn unpark(&mut self, token: &str, addr: SocketAddr) -> Option<Rx> {
+        let parked = self.parked.remove(token)?;
+        if parked.expires <= Instant::now() || parked.rx.is_closed() {
+            self.fanout.remove(&parked.addr);
+            return None;
+        }
+
+        self.fanout.rename(&parked.addr, addr);
+        info!("Consumer ({:?}) reconnected as {:?}, {} bytes queued meanwhile", parked.addr, addr, parked.rx.pending_bytes());
+        Some(parked.rx)
+    }
+
+    /// Forget the parked queues expired by now
+    fn reap(&mut self) {
+        let now = Instant::now();
+        let fanout = &self.fanout;
+        self.parked.retain(|token, parked| {
+            if parked.expires > now {
+                return true;
+            }
+            info!("Consumer ({:?}) did not reconnect in time, its session {} is gone", parked.addr, token);
+            fanout.remove(&parked.addr);
+            false
+        });
+    }
+
+    fn is_full(&self) -> bool {
+        self.settings.max_consumers.is_some_and(|max| self.consumers.len() + self.handshakes.load(Ordering::Relaxed) >= max)
+    }
+
+    /// Hold a place for a consumer let in, until it is registered or gone
+    fn reserve(&self) -> Slot {
+        self.handshakes.fetch_add(1, Ordering::Relaxed);
+        Slot(self.handshakes.clone())
+    }
+
+    /// Bits per second a new consumer is expected to take, the stream bitrate unless it is capped
+    fn consumer_demand(&self, pace: Option<u64>) -> u64 {
+        let bitrate = self.stats.input_bitrate();
+        pace.or(self.settings.rate_limit).map_or(bitrate, |pace| pace.min(bitrate))
+    }
+
+    /// Why a new consumer cannot be served, if it cannot
+    fn refusal(&self) -> Option<String> {
+        if self.is_full() {
+            return Some(format!("{} consumers connected, {} connecting", self.consumers.len(), self.handshakes.load(Ordering::Relaxed)));
+        }
+
+        let bandwidth = self.settings.bandwidth.as_ref()?;
+        let demand = self.consumer_demand(None);
+        if bandwidth.admits(demand) {
+            None
+        } else {
+            Some(format!("{} bit/s more would go past the total rate limit of {} bit/s, {} bit/s taken",
+                         demand, bandwidth.bitrate(), bandwidth.demand()))
+        }
+    }
+
+    /// Make room for a consumer of tier `priority`, disconnecting one of the
+    /// lowest tier below it, returned along with its tier. None if all are as high
+    fn displace(&mut self, priority: u8) -> Option<(SocketAddr, u8)> {
+        let mut lower: Vec<_> = self.consumers.iter().filter(|&(_, &tier)| tier < priority).map(|(&addr, &tier)| (addr, tier)).collect();
+        lower.sort_by_key(|&(_, tier)| tier);
+
+        // Those displaced already are still connected, for a moment
+        let (addr, tier) = lower.into_iter().find(|&(addr, _)| self.fanout.remove(&addr))?;
+        self.stats.add_displaced(tier);
+        Some((addr, tier))
+    }
+
+    /// Whether `addr` is the producer allowed to fan out
+    fn is_active(&self, addr: &SocketAddr) -> bool {
+        self.session.as_ref().is_some_and(|s| s.addr == *addr)
+    }
+
+    /// The producer is gone, forget about its stream
+    fn end_session(&mut self) {
+        self.fanout.flush();
+        self.fanout.clear_burst();
+        self.stats.restart_stream();
+    }
+
+    /// Swap in the reloaded settings, the existing queues are resized right away
+    fn update_settings(&mut self, settings: Settings) {
+        self.fanout.set_capacity(settings.consumer_queue);
+        self.pool.set_max(settings.read_pool);
+        self.settings = settings;
+        self.reloads.fetch_add(1, Ordering::Release);
+    }
+
+    /// Make the producer finish, returns its address if one is connected
+    fn stop_producer(&mut self) -> Option<SocketAddr> {
+        self.session.as_mut().map(|session| {
+            // Same as a takeover, the producer resolves once its stop is gone
+            session.active.store(false, Ordering::Release);
+            session.stop = oneshot::channel().0;
+            session.addr
+        })
+    }
+
+    /// Refuse new producers and stop the current one, the consumers follow
+    /// once they sent what is left in their queue
+    fn shutdown(&mut self) {
+        self.shutting_down = true;
+        self.stop_producer();
+    }
+
+    /// Create a queue sized according to the current settings
+    fn queue(&self, stats: &PeerStats) -> (Tx, Rx) {
+        queue::bounded(self.settings.consumer_queue, self.settings.overflow, stats.dropped.clone())
+    }
+}
+
+impl<S: Socket> Peer<S> {
+    fn new(state: Arc<Mutex<Shared>>, packets: TSPacket<S>, kind: Kind) -> Self {
+        Peer::with_options(state, packets, kind, ConsumerOptions::default())
+    }
+
+    fn with_options(state: Arc<Mutex<Shared>>, mut packets: TSPacket<S>, kind: Kind, options: ConsumerOptions) -> Self {
+        let addr = packets.stats.addr;
+        let name = packets.socket.peer_name().unwrap_or(PeerName::Inet(addr));
+
+        let (rx, fanout, reloads, max_lag_bytes, max_lag, idle_timeout, channel, key, stats, clean_start, rate_limit, share, impairment, encryptor, decryptor,
+             rewind, splice, reattached) = {
+            let mut state = state.lock().unwrap();
+            // Only ever lowered by the consumer itself
+            if kind.is_consumer() {
+                let priority = consumer_tier(&packets.socket, addr, &state.settings).min(options.priority.unwrap_or(u8::MAX));
+                packets.stats.priority.store(priority, Ordering::Relaxed);
+            }
+            // Back within the grace, its queue carries on from where it was left
+            let parked = match options.token {
+                Some(ref token) if kind.is_consumer() => state.unpark(token, addr),
+                _ => None,
+            };
+            let reattached = parked.is_some();
+            let (tx, rx) = match parked {
+                Some(rx) => (None, rx),
+                None => {
+                    let (tx, rx) = state.queue(&packets.stats);
+                    (Some(tx), rx)
+                }
+            };
+
+            let rewind = if kind.is_consumer() {
+                state.add_consumer(addr, tx, packets.stats.clone(), &options)
+            } else {
+                None
+            };
+            let share = match state.settings.bandwidth {
+                Some(ref bandwidth) if kind.is_consumer() => Some(bandwidth.share(state.consumer_demand(options.pace), packets.stats.priority())),
+                _ => None,
+            };
+            // Room for a few chunks at least, so one can always be split
+            packets.max_read_buffer = state.settings.max_read_buffer.max(packets.buffer_size * 4);
+            packets.wr.set_max(state.settings.write_batch);
+            if kind.is_producer() {
+                packets.pool = Some(state.pool.clone());
+            }
+            let impairment = match state.settings.simulate {
+                Some(ref simulator) if kind.is_consumer() => Some(simulator.impair(packets.stats.clone())),
+                _ => None,
+            };
+            let splice = match state.splice {
+                Some(ref splice) if kind.is_producer() && packets.socket.plain_tcp().is_some() => Some(splice.clone()),
+                _ => None,
+            };
+            let encryptor = match state.settings.encrypt {
+                Some(ref aes) if kind.is_consumer() => Some(aes.encryptor(state.stats.clone())),
+                _ => None,
+            };
+            let decryptor = match state.settings.decrypt {
+                Some(ref aes) if kind.is_producer() => Some(aes.decryptor()),
+                _ => None,
+            };
+
+            (rx, state.fanout.clone(), state.reloads.clone(), state.settings.max_lag_bytes, state.settings.max_lag, state.settings.idle_timeout,
+             state.stats.channel, state.stats.key.clone(), state.stats.clone(), state.settings.clean_start,
+             state.settings.rate_limit, share, impairment, encryptor, decryptor, rewind, splice, reattached)
+        };
+        // The paced consumers fall behind on a faster stream, until the overflow policy or the lag limits kick in
+        let pace = if kind.is_consumer() { options.pace.or(rate_limit) } else { None };
+        let start = match clean_start {
+            // A relay gets the stream as is, from the point it was told, and a
+            // consumer back within the grace from where it was
+            Some(_) if options.relay || reattached => None,
+            Some(timeout) if kind.is_consumer() => Some(CleanStart::new(addr, stats.clone(), options.program, timeout)),
+            _ => None,
+        };
+
+        Peer {
+            packets,
+            state,
+            fanout,
+            rx,
+            reloaded: reloads.load(Ordering::Acquire),
+            reloads,
+            max_lag_bytes,
+            max_lag,
+            idle_timeout,
+            idle: None,
+            addr,
+            name,
+            kind,
+            channel,
+            key,
+            closing: None,
+            draining: false,
+            reason: Reason::Closed,
+            program: options.program.map(|number| ProgramFilter::new(number, stats)),
+            pace: pace.map(Pace::new),
+            share,
+            start,
+            impairment,
+            cbr: None,
+            encryptor,
+            decryptor,
+            rewind,
+            splice,
+            splicing: false,
+            token: options.token,
+        }
+    }
+
+    /// Pad the stream of a consumer to `bitrate`, sending a chunk at a time on a timer
+    fn cbr(mut self, bitrate: Option<u64>) -> Self {
+        if let Some(bitrate) = bitrate {
+            let stats = Arc::new(CbrStats::new(bitrate));
+            let _ = self.packets.stats.cbr.set(stats.clone());
+            self.cbr = Some(Cbr::new(self.to_string(), stats, cbr::CHUNK_SIZE));
+        }
+        self
+    }
+
+    /// Bytes queued, held back or buffered but not yet written
+    fn pending(&self) -> usize {
+        self.packets.wr.len() + self.rx.pending_bytes() + self.impairment.as_ref().map_or(0, Impairment::pending)
+            + self.cbr.as_ref().map_or(0, Cbr::pending)
+    }
+
+    /// Describe how far behind the consumer is, if it is past the thresholds
+    fn lagging(&self, pending: usize) -> Option<String> {
+        let stalled = self.packets.last_write.elapsed();
+
+        let too_many_bytes = self.max_lag_bytes.is_some_and(|max| pending > max);
+        let too_long = pending > 0 && self.max_lag.is_some_and(|max| stalled > max);
+
+        if too_many_bytes || too_long {
+            Some(format!("{} bytes behind, last write {:.1}s ago", pending, stalled.as_secs_f64()))
+        } else {
+            None
+        }
+    }
+
+    /// Whether the socket accepted nothing for too long while data is pending,
+    /// otherwise arm the timer to check again
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> bool {
+        // Nor any deadline for a timeout too long to tell when it is past
+        let deadline = match self.idle_timeout.and_then(|timeout| self.packets.last_write.checked_add(timeout)) {
+            Some(deadline) if self.pending() > 0 => deadline,
+            _ => {
+                self.idle = None;
+                return false;
+            }
+        };
+
+        if Instant::now() >= deadline {
+            return true;
+        }
+
+        poll_delay(&mut self.idle, deadline, cx)
+    }
+}
+
+impl<S: Socket> Peer<S> {
+    fn poll_peer(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.kind.is_consumer() {
+            // Pick up the reloaded settings, the lock is shared with every peer
+            let reloads = self.reloads.load(Ordering::Acquire);
+            if reloads != self.reloaded {
+                self.reloaded = reloads;
+                let state = self.state.lock().unwrap();
+                self.max_lag_bytes = state.settings.max_lag_bytes;
+                self.max_lag = state.settings.max_lag;
+                self.idle_timeout = state.settings.idle_timeout;
+                self.packets.wr.set_max(state.settings.write_batch);
+            }
+
+            if let Some(lag) = self.lagging(self.pending()) {
+                warn!("Disconnecting {}, {}", self, lag);
+                self.reason = Reason::Lagging;
+                return Poll::Ready(Ok(()));
+            }
+        }
+
+        if let Kind::Consumer(ref mut rx) = self.kind {
+            // Disconnected by the overflow policy
+            if self.rx.poll_closed(cx).is_ready() {
+                self.reason = Reason::Removed;
+                return Poll::Ready(Ok(()));
+            }
+
+            // Gone, no need to wait for a write to fail
+            if self.packets.poll_hangup(cx)?.is_ready() {
+                return Poll::Ready(Ok(()));
+            }
+
+            // The producer is gone, send what is left before closing
+            if self.closing.is_none() && rx.poll_unpin(cx).is_ready() {
+                self.closing = Some(self.packets.stats.bytes());
+            }
+
+            loop {
+                if self.packets.is_drained() {
+                    self.draining = false;
+                }
+
+                // Left alone while draining, the socket wakes us up meanwhile
+                if !self.draining {
+                    while !self.packets.is_full() {
+                        // Left in the queue until the pace allows more
+                        if let Some(ref mut pace) = self.pace {
+                            if !pace.poll_ready(cx) {
+                                break;
+                            }
+                        }
+                        // Waiting for its turn otherwise
+                        if let Some(ref mut share) = self.share {
+                            if !share.poll_ready(cx) {
+                                break;
+                            }
+                        }
+                        if self.impairment.as_ref().is_some_and(Impairment::is_full) {
+                            break;
+                        }
+
+                        let next = if self.rewind.is_some() {
+                            match self.fanout.replay(&mut self.rewind) {
+                                Some(v) => Poll::Ready(Some(v)),
+                                // Joined the live stream, its queue takes over
+                                None => continue,
+                            }
+                        } else {
+                            self.rx.poll_next_unpin(cx)
+                        };
+
+                        match next {
+                            Poll::Ready(Some(v)) => {
+                                let v = match self.start.as_mut().map(|start| start.apply(&v)) {
+                                    // Held back until the start point
+                                    Some(None) => continue,
+                                    Some(Some(v)) => {
+                                        self.start = None;
+                                        v
+                                    }
+                                    None => v,
+                                };
+                                let v = match self.program {
+                                    Some(ref mut program) => program.apply(&v),
+                                    None => v,
+                                };
+                                let v = match self.encryptor {
+                                    Some(ref mut encryptor) => encryptor.apply(&v),
+                                    None => v,
+                                };
+                                if let Some(ref mut pace) = self.pace {
+                                    pace.sent(v.len());
+                                }
+                                if let Some(ref share) = self.share {
+                                    share.sent(v.len());
+                                }
+                                match (self.cbr.as_mut(), self.impairment.as_mut()) {
+                                    (Some(cbr), _) => cbr.push(&v),
+                                    (None, Some(impairment)) => impairment.push(v),
+                                    (None, None) => self.packets.buffer(v),
+                                }
+                            },
+                            Poll::Ready(None) => {
+                                self.reason = Reason::Removed;
+                                return Poll::Ready(Ok(()));
+                            }
+                            Poll::Pending => break,
+                        }
+                    }
+                    // The padded chunks go out on a timer of their own
+                    if let Some(ref mut cbr) = self.cbr {
+                        while !self.packets.is_full() && !self.impairment.as_ref().is_some_and(Impairment::is_full) {
+                            match cbr.poll_due(cx) {
+                                Poll::Ready(v) => match self.impairment {
+                                    Some(ref mut impairment) => impairment.push(v),
+                                    None => self.packets.buffer(v),
+                                },
+                                Poll::Pending => break,
+                            }
+                        }
+                    }
+                    // What is due of the chunks held back, the timer wakes us up for the next one
+                    if let Some(ref mut impairment) = self.impairment {
+                        while !self.packets.is_full() {
+                            match impairment.poll_due(cx) {
+                                Poll::Ready(Some(v)) => self.packets.buffer(v),
+                                _ => break,
+                            }
+                        }
+                    }
+                    self.draining = self.packets.is_full();
+                }
+
+                if let Poll::Ready(false) = self.packets.poll_flush(cx)? {
+                    return Poll::Ready(Ok(()));
+                }
+
+                // The WebSocket consumers are told before the socket is shut down
+                if self.closing.is_some() && self.pending() == 0 && self.packets.close_websocket() {
+                    if let Poll::Ready(false) = self.packets.poll_flush(cx)? {
+                        return Poll::Ready(Ok(()));
+                    }
+                }
+
+                if self.closing.is_some() && self.pending() == 0 {
+                    let _ = self.packets.socket.shutdown_write();
+                    self.reason = Reason::StreamEnded;
+                    return Poll::Ready(Ok(()));
+                }
+
+                // Drained without blocking, go back to the queue
+                if !(self.draining && self.packets.is_drained()) {
+                    break;
+                }
+            }
+
+            if self.poll_idle(cx) {
+                warn!("Disconnecting {}, idle for {:?} with {} bytes pending",
+                          self, self.idle_timeout.unwrap_or_default(), self.pending());
+                self.reason = Reason::Idle;
+                return Poll::Ready(Ok(()));
+            }
+
+            self.packets.stats.set_queued(self.pending());
+        } else {
+            let active = match self.kind {
+                Kind::Producer(ref mut stop, ref active) => {
+                    // Another producer took over
+                    if stop.poll_unpin(cx).is_ready() {
+                        self.reason = Reason::Removed;
+                        return Poll::Ready(Ok(()));
+                    }
+                    active.clone()
+                }
+                Kind::Consumer(_) => unreachable!(),
+            };
+
+            // Straight to the recording while it is all the stream goes to
+            match self.poll_splice(cx, &active)? {
+                Poll::Ready(false) => (),
+                Poll::Ready(true) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+
+            while let Poll::Ready(pkt) = self.packets.poll_packet(cx)? {
+                if let Some(mut packet) = pkt {
+                    // Never interleave with the new producer
+                    if !active.load(Ordering::Acquire) {
+                        self.reason = Reason::Removed;
+                        return Poll::Ready(Ok(()));
+                    }
+
+                    if let Some(ref mut decryptor) = self.decryptor {
+                        decryptor.apply(&mut packet);
+                    }
+                    self.packets.stats.forwarded.fetch_add(packet.len() as u64, Ordering::Relaxed);
+                    self.fanout.broadcast(&packet.freeze());
+                } else {
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+impl<S: Socket> Peer<S> {
+    /// Splice the producer into the recorder pipe while the recording is all
+    /// the stream goes to, ready with false once it goes the usual way and
+    /// with true once the producer is done
+    fn poll_splice(&mut self, cx: &mut Context<'_>, active: &AtomicBool) -> Poll<io::Result<bool>> {
+        let splice = match self.splice {
+            Some(ref splice) => splice.clone(),
+            None => return Poll::Ready(Ok(false)),
+        };
+
+        // Nothing to check, to strip down nor to decrypt, nobody else to send to
+        let can_splice = self.packets.validation.is_none() && self.decryptor.is_none()
+            && self.packets.normalizer.as_ref().is_none_or(|normalizer| normalizer.size() == Some(ts::PACKET_SIZE))
+            && self.fanout.only(&RECORDER_ADDR);
+
+        if !can_splice {
+            if self.splicing {
+                self.splicing = false;
+                let n = self.packets.unsplice(&splice)?;
+                info!("{} no longer spliced into the recording, {} bytes taken back", self, n);
+            }
+            return Poll::Ready(Ok(false));
+        }
+
+        if !self.splicing {
+            match self.packets.splice_read(&splice)? {
+                Some(n) => self.fanout.bypassed(n),
+                None => return Poll::Ready(Ok(false)),
+            }
+            self.splicing = true;
+            info!("{} spliced into the recording", self);
+        }
+
+        loop {
+            // Never interleave with the new producer
+            if !active.load(Ordering::Acquire) {
+                self.reason = Reason::Removed;
+                splice.end();
+                return Poll::Ready(Ok(true));
+            }
+
+            let socket = self.packets.socket.plain_tcp().unwrap();
+            match ready!(splice.poll_splice(cx, socket))? {
+                0 => {
+                    splice.end();
+                    return Poll::Ready(Ok(true));
+                }
+                n => {
+                    let stats = &self.packets.stats;
+                    stats.add_bytes(n as u64);
+                    stats.forwarded.fetch_add(n as u64, Ordering::Relaxed);
+                    self.fanout.bypassed(n);
+                }
+            }
+        }
+    }
+}
+
+impl<S: Socket> Future for Peer<S> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.packets.stats.polls.fetch_add(1, Ordering::Relaxed);
+
+        // Resolve rather than fail, the peer is cleaned up and logged once dropped
+        match self.poll_peer(cx) {
+            Poll::Ready(Err(e)) => {
+                error!("{} failed: {}", self, e);
+                self.reason = Reason::Error(e.to_string());
+                Poll::Ready(())
+            }
+            poll => poll.map(|_| ()),
+        }
+    }
+}
+
+impl<S: Socket> Drop for Peer<S> {
+    fn drop(&mut self) {
+        let dropped = self.rx.dropped();
+        {
+            let mut state = self.state.lock().unwrap();
+
+            if self.kind.is_consumer() {
+                // Lost rather than let go, and still following the live stream
+                let lost = matches!(self.reason, Reason::Closed | Reason::Error(_));
+                // Not parked for a grace too long to tell when it expires
+                let expires = state.settings.reconnect_grace.and_then(|grace| Instant::now().checked_add(grace));
+                let parked = match (self.token.take(), expires) {
+                    (Some(token), Some(expires)) if lost && self.rewind.is_none() && self.closing.is_none() => {
+                        let (_, rx) = state.queue(&self.packets.stats);
+                        state.park(token, self.addr, mem::replace(&mut self.rx, rx), expires);
+                        reap_at(Arc::downgrade(&self.state), expires);
+                        true
+                    }
+                    _ => false,
+                };
+                if !parked {
+                    self.fanout.remove(&self.addr);
+                }
+                state.consumers.remove(&self.addr);
+                state.stats.remove_consumer(&self.addr, self.reason.clone());
+            } else if state.is_active(&self.addr) {
+                state.session = None;
+                state.end_session();
+                state.stats.remove_producer(&self.addr, self.reason.clone());
+            }
+        }
+
+        // The numbers of the status, as they stand at the end
+        let stats = &self.packets.stats;
+        let reason = match self.reason {
+            Reason::Error(ref e) => format!("error ({})", e),
+            ref reason => reason.to_string(),
+        };
+        let ended = format!("{} after {:.1}s", reason, stats.duration().as_secs_f64());
+
+        if self.kind.is_producer() {
+            info!("Dropping {}, {}: {} bytes received, {} bit/s on average",
+                      self, ended, stats.bytes(), stats.average_bitrate());
+            return;
+        }
+
+        let flushed = match self.closing {
+            Some(start) => format!(", {} bytes flushed, {} bytes abandoned", stats.bytes() - start, self.pending()),
+            None => String::new(),
+        };
+        info!("Dropping {}, {}: {} bytes sent, {} packets dropped, {} bytes queued at most{}",
+                  self, ended, stats.bytes(), dropped, stats.peak_queued.load(Ordering::Relaxed), flushed);
+    }
+}
+
+/// Reap the parked queues once `at` is past, unless the state is gone by then
+fn reap_at(state: Weak<Mutex<Shared>>, at: Instant) {
+    // None once the runtime is gone, the queues go along with it
+    let runtime = match tokio::runtime::Handle::try_current() {
+        Ok(runtime) => runtime,
+        Err(_) => return,
+    };
+    runtime.spawn(async move {
+        time::sleep_until(time::Instant::from_std(at)).await;
+        if let Some(state) = state.upgrade() {
+            state.lock().unwrap().reap();
+        }
+    });
+}
+
+/// Whether `deadline` is past, otherwise `delay` wakes the task up then
+fn poll_delay(delay: &mut Option<Pin<Box<Sleep>>>, deadline: Instant, cx: &mut Context<'_>) -> bool {
+    let deadline = time::Instant::from_std(deadline);
+    let delay = delay.get_or_insert_with(|| Box::pin(time::sleep_until(deadline)));
+    if delay.deadline() != deadline {
+        delay.as_mut().reset(deadline);
+    }
+    delay.as_mut().poll(cx).is_ready()
+}
+
+/// Ticks every `period`, starting a period from now
+fn interval(period: Duration) -> time::Interval {
+    time::interval_at(time::Instant::now() + period, period)
+}
+
+use std::fmt;
+
+impl<S: Socket> fmt::Display for Peer<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = if self.kind.is_producer() {
+            "Producer"
+        } else {
+            "Consumer"
+        };
+        write!(f, "{} ({})", name, self.name)?;
+        if let Some(channel) = self.channel {
+            write!(f, " on channel {}", channel)?;
+        }
+        if let Some(ref key) = self.key {
+            write!(f, " for {}", key)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Socket> TSPacket<S> {
+    fn new(socket: S, buffer_size: usize, align: bool) -> Self {
+        TSPacket::with_read_buf(socket, buffer_size, align, BytesMut::new())
+    }
+
+    /// Start from data already read from the socket
+    fn with_read_buf(socket: S, buffer_size: usize, align: bool, rd: BytesMut) -> Self {
+        // Gone already, it is dropped on the first read or write
+        let mut stats = PeerStats::new(socket.peer_addr().unwrap_or_else(|_| net::placeholder_addr()));
+        stats.identity = socket.identity().map(str::to_owned);
+        stats.link = socket.link();
+        let stats = Arc::new(stats);
+
+        TSPacket {
+            buffer_size,
+            align,
+            socket,
+            rd,
+            max_read_buffer: Settings::default().max_read_buffer,
+            pool: None,
+            wr: WriteBatch::new(Settings::default().write_batch),
+            last_write: Instant::now(),
+            stats,
+            validation: None,
+            normalizer: None,
+            ws: None,
+        }
+    }
+
+    /// Send the chunks as WebSocket frames
+    fn websocket(mut self) -> Self {
+        self.stats.websocket.store(true, Ordering::Relaxed);
+        self.ws = Some(WebSocket::new());
+        self
+    }
+
+    /// Buffer a packet.
+    fn buffer(&mut self, line: Bytes) {
+        if self.ws.is_some() {
+            self.wr.push(Bytes::from(ws::header(ws::OP_BINARY, line.len())));
+        }
+        self.wr.push(line);
+    }
+
+    /// High-water mark, leave the packets in the queue until the socket accepts more data
+    fn is_full(&self) -> bool {
+        self.wr.len() >= self.buffer_size * 4
+    }
+
+    /// Low-water mark, down to a single chunk
+    fn is_drained(&self) -> bool {
+        self.wr.len() <= self.buffer_size
+    }
+
+    /// Flush the write buffer to the socket
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        while !self.wr.is_empty() {
+            if !self.socket.vectored() {
+                self.wr.coalesce();
+            }
+            let n = match ready!(poll_write_buf(Pin::new(&mut self.socket), cx, &mut self.wr)) {
+                Ok(n) => n,
+                Err(ref e) if net::is_disconnect(e) => return Poll::Ready(Ok(false)),
+                Err(e) => return Poll::Ready(Err(e)),
+            };
+
+            // Nothing more will ever be written
+            if n == 0 {
+                return Poll::Ready(Ok(false));
+            }
+
+            self.last_write = Instant::now();
+            self.stats.add_bytes(n as u64);
+        }
+
+        Poll::Ready(Ok(true))
+    }
+
+    /// Append the WebSocket close frame, false if there is none to send
+    fn close_websocket(&mut self) -> bool {
+        match self.ws {
+            Some(ref mut ws) => self.wr.put(|out| ws.close(out)),
+            None => false,
+        }
+    }
+
+    /// Resolve once the consumer closed its side, what it sends is discarded
+    /// unless it is a WebSocket control frame
+    fn poll_hangup(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut scratch = [0; 512];
+
+        if let Some(ref mut ws) = self.ws {
+            self.wr.put(|out| ws.poll_ping(cx, out));
+        }
+
+        loop {
+            let mut buf = ReadBuf::new(&mut scratch);
+            let n = match ready!(Pin::new(&mut self.socket).poll_read(cx, &mut buf)) {
+                Ok(()) => buf.filled().len(),
+                Err(ref e) if net::is_disconnect(e) => return Poll::Ready(Ok(())),
+                Err(e) => return Poll::Ready(Err(e)),
+            };
+            if n == 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            let closing = match self.ws {
+                Some(ref mut ws) => self.wr.put(|out| ws.receive(&scratch[..n], out))?,
+                None => false,
+            };
+            // Answered with a close frame, as far as the socket takes it
+            if closing {
+                let _ = self.poll_flush(cx);
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+
+    /// Make room for a read, moving to a buffer of the pool once the current one is used up
+    fn reserve_read(&mut self) {
+        let room = self.buffer_size * 4;
+        let pool = match self.pool {
+            Some(ref pool) if self.rd.capacity() - self.rd.len() < room => pool,
+            _ => return self.rd.reserve(room),
+        };
+
+        let mut buf = pool.get(self.buffer_size * POOLED_CHUNKS);
+        // What is left is less than a chunk, usually
+        buf.extend_from_slice(&self.rd);
+        pool.put(mem::replace(&mut self.rd, buf));
+    }
+
+    /// Hand what was read already to the recorder pipe, None if it does not fit
+    fn splice_read(&mut self, splice: &Splice) -> io::Result<Option<usize>> {
+        let mut read = self.rd.clone();
+        if let Some(ref mut normalizer) = self.normalizer {
+            read.extend_from_slice(normalizer.raw());
+        }
+        if !splice.put(&read)? {
+            return Ok(None);
+        }
+
+        self.rd.clear();
+        if let Some(ref mut normalizer) = self.normalizer {
+            normalizer.raw().clear();
+        }
+        self.stats.buffered.store(0, Ordering::Relaxed);
+        self.stats.add_bytes(read.len() as u64);
+        self.stats.forwarded.fetch_add(read.len() as u64, Ordering::Relaxed);
+        Ok(Some(read.len()))
+    }
+
+    /// Take back what the recorder did not write from its pipe, to be read as usual
+    fn unsplice(&mut self, splice: &Splice) -> io::Result<usize> {
+        match self.normalizer {
+            Some(ref mut normalizer) => {
+                let n = splice.take_back(normalizer.raw())?;
+                normalizer.normalize(&mut self.rd);
+                Ok(n)
+            }
+            None => splice.take_back(&mut self.rd),
+        }
+    }
+
+    /// Read until the socket is drained or the buffer is full, the latter with
+    /// no wakeup registered: the caller splits the buffer and reads again
+    fn fill_read_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.rd.len() < self.max_read_buffer {
+            self.reserve_read();
+            let n = match self.normalizer {
+                Some(ref mut normalizer) => {
+                    normalizer.raw().reserve(self.buffer_size * 4);
+                    let n = ready!(poll_read_buf(Pin::new(&mut self.socket), cx, normalizer.raw()))?;
+                    if n == 0 {
+                        normalizer.flush(&mut self.rd);
+                    } else {
+                        normalizer.normalize(&mut self.rd);
+                    }
+                    self.stats.packet_size.store(normalizer.size().unwrap_or(0), Ordering::Relaxed);
+                    n
+                }
+                None => ready!(poll_read_buf(Pin::new(&mut self.socket), cx, &mut self.rd))?,
+            };
+            if n == 0 {
+                return Poll::Ready(Ok(()));
+            }
+        }
+
+        Poll::Pending
+    }
+
+    /// Detect the size of the packets read from now on, or force it
+    fn normalize(&mut self, forced: Option<usize>) {
+        let raw = self.rd.split_off(0);
+        self.normalizer = Some(Normalizer::new(forced, raw));
+    }
+
+    /// Split a raw chunk, regardless of its content
+    fn split_raw(&mut self) -> Option<BytesMut> {
+        if self.rd.len() >= self.buffer_size {
+            Some(self.rd.split_to(self.buffer_size))
+        } else {
+            None
+        }
+    }
+
+    /// Split a chunk made of whole TS packets, resynchronizing if needed
+    fn split_aligned(&mut self) -> io::Result<Option<BytesMut>> {
+        if let Some(ref mut validation) = self.validation {
+            // Held back until the packets are found
+            loop {
+                match validation.check(&self.rd)? {
+                    None => return Ok(None),
+                    Some(0) => break,
+                    Some(off) => {
+                        let off = off.min(self.rd.len());
+                        if validation.started() {
+                            warn!("Skipping {} bytes to resync", off);
+                            self.stats.lost_sync(off);
+                        }
+                        self.rd.advance(off);
+                    }
+                }
+            }
+        }
+
+        match ts::sync_offset(&self.rd) {
+            Some(0) => (),
+            Some(off) => {
+                warn!("Skipping {} bytes to resync", off);
+                self.stats.lost_sync(off);
+                self.rd.advance(off);
+            }
+            None => {
+                if !self.rd.is_empty() {
+                    warn!("Skipping {} bytes, no sync byte found", self.rd.len());
+                    self.stats.lost_sync(self.rd.len());
+                }
+                self.rd.clear();
+                return Ok(None);
+            }
+        }
+
+        let chunk = ts::chunk_size(self.buffer_size);
+        let n = ts::aligned_len(&self.rd, chunk);
+
+        // Either a full chunk or the packets preceding a sync loss
+        let lost_sync = self.rd.get(n).is_some_and(|&b| b != ts::SYNC_BYTE);
+
+        if n == chunk || (n > 0 && lost_sync) {
+            Ok(Some(self.rd.split_to(n)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Split what is left once the socket is closed, the whole packets only if aligned
+    fn split_last(&mut self) -> Option<BytesMut> {
+        if self.validation.as_ref().is_some_and(|v| !v.started()) {
+            if !self.rd.is_empty() {
+                debug!("Skipping {} trailing bytes, too few to tell an MPEG-TS stream", self.rd.len());
+                self.rd.clear();
+            }
+            return None;
+        }
+
+        let n = if self.align {
+            ts::aligned_len(&self.rd, self.rd.len())
+        } else {
+            self.rd.len()
+        };
+
+        if n > 0 {
+            Some(self.rd.split_to(n))
+        } else {
+            if !self.rd.is_empty() {
+                debug!("Skipping {} trailing bytes, not a whole packet", self.rd.len());
+                self.rd.clear();
+            }
+            None
+        }
+    }
+}
+
+impl<S: Socket> TSPacket<S> {
+    /// The next chunk, None once the socket is closed and all of it was split
+    fn poll_packet(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Option<BytesMut>>> {
+        loop {
+            let sock_closed = self.fill_read_buf(cx)?.is_ready();
+            let full = self.rd.len() >= self.max_read_buffer;
+
+            let pkt = if self.align {
+                self.split_aligned()?
+            } else {
+                self.split_raw()
+            };
+            // The end of the stream, what is left goes out as a last chunk
+            let pkt = if pkt.is_none() && sock_closed {
+                self.split_last()
+            } else {
+                pkt
+            };
+            let pending = self.normalizer.as_ref().map_or(0, Normalizer::pending);
+            self.stats.buffered.store(self.rd.len() + pending, Ordering::Relaxed);
+
+            if let Some(pkt) = pkt {
+                self.stats.add_bytes(pkt.len() as u64);
+                return Poll::Ready(Ok(Some(pkt)));
+            }
+
+            if sock_closed {
+                return Poll::Ready(Ok(None));
+            }
+            // A full buffer skipped while resyncing, nothing would wake us up to read on
+            if !full || self.rd.len() >= self.max_read_buffer {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+impl<S: Socket> Stream for TSPacket<S> {
+    type Item = io::Result<BytesMut>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_packet(cx).map(Result::transpose)
+    }
+}
+
+/// Apply the socket options, a failure is not worth dropping the peer
+fn set_options(socket: &TcpStream, settings: &Settings) {
+    if let Err(e) = socket.set_nodelay(settings.nodelay) {
+        warn!("Cannot set TCP_NODELAY on {:?}: {}", socket.peer_addr(), e);
+    }
+
+    if let Err(e) = net::set_keepalive(socket, settings.keepalive) {
+        warn!("Cannot set SO_KEEPALIVE on {:?}: {}", socket.peer_addr(), e);
+    }
+}
+
+/// Mark the packets sent to the peer with `dscp`, recording what the kernel kept
+///
+/// Some values need CAP_NET_ADMIN, the peer is served unmarked without it.
+fn mark(socket: &TcpStream, dscp: Option<u8>, stats: &PeerStats) {
+    let dscp = match dscp {
+        Some(dscp) => dscp,
+        None => return,
+    };
+    let v6 = socket.local_addr().map(|addr| addr.is_ipv6()).unwrap_or(false);
+
+    match net::set_dscp(socket, v6, dscp) {
+        Ok(dscp) => stats.dscp.store(dscp, Ordering::Relaxed),
+        Err(e) => warn!("Cannot set DSCP {} on {:?}: {}", dscp, socket.peer_addr(), e),
+    }
+}
+
+fn setup<S: Socket>(packets: TSPacket<S>, state: Arc<Mutex<Shared>>, kind: Kind, options: ConsumerOptions) {
+    if let Some(socket) = packets.socket.tcp() {
+        let state = state.lock().unwrap();
+        set_options(socket, &state.settings);
+        mark(socket, state.settings.dscp, &packets.stats);
+    }
+
+    let cons = Peer::with_options(state, packets, kind, options);
+
+    info!("Adding {}", cons);
+
+    tokio::spawn(cons);
+}
+
+/// Start a producer, unless one is active and cannot be taken over
+///
+/// Returns the consumer session if a new one started.
+fn setup_producer<S: Socket>(mut packets: TSPacket<S>, state: Arc<Mutex<Shared>>, takeover: bool) -> Option<OneShotSharedRx> {
+    let addr = packets.stats.addr;
+    let (stop, stop_rx) = oneshot::channel::<()>();
+    let active = Arc::new(AtomicBool::new(true));
+
+    let rx = {
+        let mut state = state.lock().unwrap();
+
+        if state.shutting_down {
+            warn!("Refusing Producer ({:?}), shutting down", addr);
+            return None;
+        }
+
+        let rx = match state.session {
+            Some(ref mut session) if takeover => {
+                info!("Producer ({:?}) taking over from {:?}", addr, session.addr);
+                // Dropping the old stop makes the old producer resolve
+                session.active.store(false, Ordering::Release);
+                session.addr = addr;
+                session.stop = stop;
+                session.active = active.clone();
+                None
+            }
+            Some(ref session) => {
+                warn!("Rejecting Producer ({:?}), {:?} is already streaming", addr, session.addr);
+                return None;
+            }
+            None => {
+                let (done, rx) = oneshot::channel::<()>();
+                state.session = Some(Session { addr, stop, active: active.clone(), _done: done });
+                Some(rx.shared())
+            }
+        };
+
+        state.stats.set_producer(packets.stats.clone());
+        if packets.align {
+            packets.validation = state.settings.validate_input.map(Validation::new);
+            packets.normalize(state.settings.packet_size);
+        }
+
+        rx
+    };
+
+    setup(packets, state, Kind::Producer(stop_rx, active), ConsumerOptions::default());
+
+    rx
+}
+
+fn start_consumer<S: Socket>(socket: S, state: Arc<Mutex<Shared>>, rx: OneShotSharedRx, buffer_size: usize, options: ConsumerOptions, slot: Slot) {
+    setup(TSPacket::new(socket, buffer_size, false), state, Kind::Consumer(rx), options);
+    // Registered by now
+    drop(slot);
+}
+
+/// Start streaming, once the consumer sent its options if they are accepted
+fn setup_consumer<S: Socket>(socket: S, state: Arc<Mutex<Shared>>, rx: OneShotSharedRx, buffer_size: usize, slot: Slot) {
+    let (consumer_options, relay_resume, tokens) = {
+        let settings = &state.lock().unwrap().settings;
+        (settings.consumer_options, settings.relay_resume, settings.reconnect_grace.is_some())
+    };
+    if !consumer_options && !relay_resume && !tokens {
+        start_consumer(socket, state, rx, buffer_size, ConsumerOptions::default(), slot);
+        return;
+    }
+
+    let addr = match socket.peer_addr() {
+        Ok(addr) => addr,
+        // Disconnected already
+        Err(_) => return,
+    };
+
+    tokio::spawn(async move {
+        let (socket, options) = match options::read_options(socket).await {
+            Ok(read) => read,
+            Err(e) => {
+                warn!("Rejecting Consumer ({:?}), {}", addr, e);
+                return;
+            }
+        };
+        // Only what is enabled, the consumers are served as usual otherwise
+        let options = options.and_then(|options| match options {
+            ConsumerOptions { relay: true, .. } if !relay_resume => None,
+            // The token alone then
+            ConsumerOptions { relay: false, token, .. } if !consumer_options => {
+                token.map(|token| ConsumerOptions { token: Some(token), ..ConsumerOptions::default() })
+            }
+            options => Some(options),
+        });
+        if let Some(ref options) = options {
+            info!("Consumer ({:?}) asked for {:?}", addr, options);
+        }
+        start_consumer(socket, state, rx, buffer_size, options.unwrap_or_default(), slot);
+    });
+}
+
+/// Check the peer address against the access lists, returning it if allowed
+fn check_acl<S: Socket>(socket: &S, state: &Arc<Mutex<Shared>>, producer: bool) -> Option<SocketAddr> {
+    let addr = socket.peer_addr().ok()?;
+    if !check_addr(addr, state, producer) {
+        return None;
+    }
+
+    // Closed without a word, a scanner would flood the logs
+    let mut state = state.lock().unwrap();
+    let state = &mut *state;
+    match state.settings.accept_rate {
+        Some(rate) if !state.accepts.allows(addr.ip(), rate, Instant::now()) => None,
+        _ => Some(addr),
+    }
+}
+
+/// Read the PROXY header if required, then `start` with the connection if its address is allowed
+fn accept_tcp<F>(socket: TcpStream, state: &Arc<Mutex<Shared>>, producer: bool, start: F)
+where
+    F: FnOnce(Proxied, SocketAddr) + Send + 'static,
+{
+    let check = {
+        let state = state.clone();
+        move |socket: Proxied| {
+            if let Some(addr) = check_acl(&socket, &state, producer) {
+                start(socket, addr);
+            }
+        }
+    };
+
+    if !state.lock().unwrap().settings.proxy_protocol {
+        check(Proxied::direct(socket));
+        return;
+    }
+
+    let name = if producer { "Producer" } else { "Consumer" };
+    let balancer = net::peer_addr(&socket).ok();
+    tokio::spawn(async move {
+        match time::timeout(PROXY_TIMEOUT, proxy::read_header(socket)).await {
+            Ok(Ok(socket)) => check(socket),
+            Ok(Err(e)) => warn!("Rejecting {} (via {:?}), {}", name, balancer, e),
+            Err(_) => warn!("Rejecting {} (via {:?}), no PROXY header within {:?}", name, balancer, PROXY_TIMEOUT),
+        }
+    });
+}
+
+/// Whether a peer connecting from `addr` is allowed
+fn check_addr(addr: SocketAddr, state: &Arc<Mutex<Shared>>, producer: bool) -> bool {
+    let state = state.lock().unwrap();
+    let (acl, name) = if producer {
+        (&state.settings.producer_acl, "Producer")
+    } else {
+        (&state.settings.consumer_acl, "Consumer")
+    };
+
+    if acl.permits(addr.ip()) {
+        true
+    } else {
+        warn!("Refusing {} ({:?}), address not allowed", name, addr);
+        false
+    }
+}
+
+/// Check the token sent by the producer before streaming
+fn authenticate_producer<S, F>(socket: S, token: &str, start: F)
+where
+    S: Socket,
+    F: FnOnce(S, BytesMut) + Send + 'static,
+{
+    let addr = match socket.peer_addr() {
+        Ok(addr) => addr,
+        // Disconnected already
+        Err(_) => return,
+    };
+    let token = token.to_owned();
+
+    tokio::spawn(async move {
+        match time::timeout(AUTH_TIMEOUT, handshake::read_line(socket, token.len() + 2)).await {
+            Ok(Ok((socket, line, rest))) => {
+                if handshake::secret_eq(line.as_bytes(), token.as_bytes()) {
+                    start(socket, rest);
+                } else {
+                    warn!("Rejecting Producer ({:?}), invalid token", addr);
+                }
+            }
+            Ok(Err(e)) => warn!("Rejecting Producer ({:?}), {}", addr, e),
+            Err(_) => warn!("Rejecting Producer ({:?}), no token within {:?}", addr, AUTH_TIMEOUT),
+        }
+    });
+}
+
+/// Answer the HTTP request before streaming
+fn setup_http_consumer<S: Socket>(socket: S, state: Arc<Mutex<Shared>>, rx: OneShotSharedRx, buffer_size: usize, slot: Slot) {
+    let addr = match socket.peer_addr() {
+        Ok(addr) => addr,
+        // Disconnected already
+        Err(_) => return,
+    };
+
+    let fanout = state.lock().unwrap().fanout.clone();
+    let handshake = async move {
+        let (mut socket, req) = http::read_request(socket).await?;
+        // The same options as the OPTS line, e.g. /?rewind=30
+        let (path, query) = req.path.split_once('?').unwrap_or((&req.path, ""));
+        // Streamed with the options, or else answered with a response of its own
+        let stream = if req.method != "GET" {
+            warn!("Rejecting {} {} from {:?}", req.method, req.path, addr);
+            Err(http::response("405 Method Not Allowed", "text/plain", "Allow: GET\r\n", b""))
+        } else if path == snapshot::PATH {
+            Err(snapshot::response(&fanout, query))
+        } else {
+            ConsumerOptions::from_query(query).map_err(|e| {
+                warn!("Rejecting {} {} from {:?}, {}", req.method, req.path, addr, e);
+                http::response("400 Bad Request", "text/plain", "", format!("{}\n", e).as_bytes())
+            })
+        };
+
+        match stream {
+            Ok(options) => {
+                socket.write_all(http::STREAM_OK).await?;
+                Ok(Some((socket, options)))
+            }
+            Err(res) => {
+                socket.write_all(&res).await?;
+                Ok::<_, io::Error>(None)
+            }
+        }
+    };
+
+    // The slot is freed along with the task, whether it times out or fails
+    tokio::spawn(async move {
+        match time::timeout(AUTH_TIMEOUT, handshake).await {
+            Ok(Ok(Some((socket, options)))) => start_consumer(socket, state, rx, buffer_size, options, slot),
+            Ok(Ok(None)) => (),
+            Ok(Err(e)) => error!("HTTP request from {:?} failed: {}", addr, e),
+            Err(_) => warn!("Rejecting Consumer ({:?}), no HTTP request within {:?}", addr, AUTH_TIMEOUT),
+        }
+    });
+}
+
+/// Upgrade to WebSocket before streaming
+fn setup_ws_consumer<S: Socket>(socket: S, state: Arc<Mutex<Shared>>, rx: OneShotSharedRx, buffer_size: usize, slot: Slot) {
+    let addr = match socket.peer_addr() {
+        Ok(addr) => addr,
+        // Disconnected already
+        Err(_) => return,
+    };
+
+    let handshake = async move {
+        let (mut socket, req) = http::read_request(socket).await?;
+        match ws::upgrade(&req) {
+            Ok(res) => {
+                socket.write_all(&res).await?;
+                Ok(Some(socket))
+            }
+            Err(res) => {
+                warn!("Rejecting {} {} from {:?}, not a WebSocket upgrade", req.method, req.path, addr);
+                socket.write_all(&res).await?;
+                Ok::<_, io::Error>(None)
+            }
+        }
+    };
+
+    tokio::spawn(async move {
+        match time::timeout(AUTH_TIMEOUT, handshake).await {
+            Ok(Ok(Some(socket))) => {
+                let packets = TSPacket::new(socket, buffer_size, false).websocket();
+                setup(packets, state, Kind::Consumer(rx), ConsumerOptions::default());
+            }
+            Ok(Ok(None)) => (),
+            Ok(Err(e)) => error!("WebSocket upgrade from {:?} failed: {}", addr, e),
+            Err(_) => warn!("Rejecting Consumer ({:?}), no WebSocket upgrade within {:?}", addr, AUTH_TIMEOUT),
+        }
+        drop(slot);
+    });
+}
+
+/// How the consumers are streamed to
+#[derive(Clone, Copy, PartialEq)]
+enum Protocol {
+    Raw,
+    Http,
+    WebSocket,
+}
+
+/// Tier of the consumer from `addr` on `socket`, by the rules matching its address
+/// or the local port it connected to
+fn consumer_tier<S: Socket>(socket: &S, addr: SocketAddr, settings: &Settings) -> u8 {
+    let port = socket.tcp().and_then(|tcp| tcp.local_addr().ok()).map(|local| local.port());
+    priority::tier(&settings.priorities, addr.ip(), port)
+}
+
+/// Stream to the consumer, unless the server is full for its tier
+fn serve_consumer<S: Socket>(mut socket: S, state: Arc<Mutex<Shared>>, rx: OneShotSharedRx, buffer_size: usize, protocol: Protocol) {
+    // Taken under the same lock as the check, the handshakes in flight count as connected
+    let slot = {
+        let mut state = state.lock().unwrap();
+        if let Some(reason) = state.refusal() {
+            let priority = socket.peer_addr().map_or(0, |addr| consumer_tier(&socket, addr, &state.settings));
+            match state.displace(priority) {
+                Some((addr, tier)) => info!("Disconnecting Consumer ({:?}) of priority {} for one of priority {}, {}", addr, tier, priority, reason),
+                None => {
+                    if let Ok(name) = socket.peer_name() {
+                        warn!("Refusing Consumer ({}), {}", name, reason);
+                    }
+                    if protocol != Protocol::Raw {
+                        let res = http::response("503 Service Unavailable", "text/plain", "", b"Server full\n");
+                        tokio::spawn(async move {
+                            let _ = socket.write_all(&res).await;
+                        });
+                    }
+                    return;
+                }
+            }
+        }
+        state.reserve()
+    };
+
+    match protocol {
+        Protocol::Raw => setup_consumer(socket, state, rx, buffer_size, slot),
+        Protocol::Http => setup_http_consumer(socket, state, rx, buffer_size, slot),
+        Protocol::WebSocket => setup_ws_consumer(socket, state, rx, buffer_size, slot),
+    }
+}
+
+/// Accept WebSocket consumers for as long as the producer is alive
+fn serve_ws_consumers(addr: &SocketAddr, state: Arc<Mutex<Shared>>, rx: OneShotSharedRx, buffer_size: usize) {
+    let listener = match net::listen(addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Cannot serve the WebSocket consumers, {}", e);
+            return;
+        }
+    };
+
+    let done = rx.clone();
+    let srv_cons = net::accept_loop(listener, move |socket| {
+        let (state, rx) = (state.clone(), rx.clone());
+        accept_tcp(socket, &state.clone(), false, move |socket, _| {
+            serve_consumer(socket, state, rx, buffer_size, Protocol::WebSocket);
+        });
+    });
+
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = srv_cons => (),
+            _ = done => (),
+        }
+    });
+}
+
+/// Accept consumers for as long as the producer is alive, on `loops` listeners
+/// sharing the TCP port or on the ones `bound` already
+#[allow(clippy::too_many_arguments)]
+fn serve_consumers(output: &Output, state: Arc<Mutex<Shared>>, rx: OneShotSharedRx, buffer_size: usize, http: bool,
+                   tls: Option<Tls>, socket_mode: Option<u32>, loops: usize, bound: Option<&[std::net::TcpListener]>) {
+    let protocol = if http { Protocol::Http } else { Protocol::Raw };
+
+    match *output {
+        Output::Tcp(addr) => {
+            // Bound for the whole lifetime when the privileges to bind again are gone
+            let listeners = match bound {
+                Some(bound) => bound.iter().map(net::adopt).collect(),
+                None => net::listen_many(&addr, loops),
+            };
+            let listeners = match listeners {
+                Ok(listeners) => listeners,
+                Err(e) => {
+                    error!("Cannot serve the consumers, {}", e);
+                    return;
+                }
+            };
+            let counters = state.lock().unwrap().stats.accept_counters(listeners.len());
+
+            for (listener, accepts) in listeners.into_iter().zip(counters) {
+                let (state, cons_rx, tls) = (state.clone(), rx.clone(), tls.clone());
+                let done = rx.clone();
+                let srv_cons = net::accept_loop(listener, move |socket| {
+                    accepts.fetch_add(1, Ordering::Relaxed);
+                    let (state, rx, tls) = (state.clone(), cons_rx.clone(), tls.clone());
+                    accept_tcp(socket, &state.clone(), false, move |socket, addr| {
+                        match tls {
+                            Some(ref tls) => tls.accept(socket, addr, move |socket| serve_consumer(socket, state, rx, buffer_size, protocol)),
+                            None => serve_consumer(socket, state, rx, buffer_size, protocol),
+                        }
+                    });
+                });
+
+                tokio::spawn(async move {
+                    tokio::select! {
+                        _ = srv_cons => (),
+                        _ = done => (),
+                    }
+                });
+            }
+        }
+        // Local consumers, the access lists do not apply
+        #[cfg(unix)]
+        Output::Unix(ref path) => {
+            let done = rx.clone();
+            let listener = match unix::listen(path, socket_mode) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!("Cannot serve the consumers, {}", e);
+                    return;
+                }
+            };
+
+            let srv_cons = unix::accept_loop(listener, move |socket| {
+                serve_consumer(socket, state.clone(), rx.clone(), buffer_size, protocol);
+            });
+
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = srv_cons => (),
+                    _ = done => (),
+                }
+            });
+        }
+        #[cfg(not(unix))]
+        Output::Unix(_) => unreachable!("refused on spawn"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncRead, AsyncWrite};
+
+    use std::io::Cursor;
+
+    /// Producer sending `data` then closing
+    struct Feed(Cursor<Vec<u8>>);
+
+    impl AsyncRead for Feed {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Feed {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, _: &[u8]) -> Poll<io::Result<usize>> {
+            Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl Socket for Feed {
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            Ok(net::placeholder_addr())
+        }
+
+        fn shutdown_write(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn chunks(data: Vec<u8>, align: bool) -> Vec<usize> {
+        futures::executor::block_on_stream(TSPacket::new(Feed(Cursor::new(data)), 1316, align))
+            .map(|chunk| chunk.unwrap().len())
+            .collect()
+    }
+
+    fn packets(n: usize) -> Vec<u8> {
+        (0..n).flat_map(|_| {
+            let mut pkt = vec![0; 188];
+            pkt[0] = ts::SYNC_BYTE;
+            pkt
+        }).collect()
+    }
+
+    #[test]
+    fn exact_multiples() {
+        assert_eq!(chunks(packets(7), false), vec![1316]);
+        assert_eq!(chunks(packets(14), false), vec![1316, 1316]);
+        assert_eq!(chunks(packets(7), true), vec![1316]);
+        assert_eq!(chunks(packets(14), true), vec![1316, 1316]);
+    }
+
+    #[test]
+    fn remainders_flushed_on_close() {
+        let mut data = packets(9);
+        data.extend_from_slice(&[0; 50]);
+
+        assert_eq!(chunks(data.clone(), false), vec![1316, 426]);
+        // The partial packet is left out
+        assert_eq!(chunks(data, true), vec![1316, 376]);
+    }
+
+    #[test]
+    fn full_buffer_without_sync_skipped() {
+        // Filling the read buffer several times over before the packets
+        let mut data = vec![0; 1316 * 16];
+        data.extend(packets(7));
+
+        let mut stream = TSPacket::new(Feed(Cursor::new(data)), 1316, true);
+        stream.max_read_buffer = 1316 * 4;
+        let chunks: Vec<usize> = futures::executor::block_on_stream(stream).map(|chunk| chunk.unwrap().len()).collect();
+        assert_eq!(chunks, vec![1316]);
+    }
+}
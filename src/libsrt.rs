@@ -0,0 +1,595 @@
+//! SRT connections over libsrt
+//!
+//! libsrt blocks, so every connection is served by a thread of its own
+//! exchanging the payloads with the event loop over channels, the way the
+//! standard input is read.
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::PollSender;
+use futures::prelude::*;
+use futures::{future, stream};
+use bytes::Bytes;
+use log::{error, info};
+
+use std::cmp;
+use std::ffi::CStr;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::raw::{c_char, c_int, c_void};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Once};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{ready, Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{check_addr, OneShotSharedRx, Shared};
+use crate::net::{self, Backoff, Socket};
+use crate::push;
+use crate::srt::{SrtMode, SrtOptions, SrtUrl};
+use crate::stats::LinkStats;
+
+/// Payload of an SRT message in live mode, 7 packets
+const PAYLOAD: usize = 1316;
+/// Largest message received
+const MAX_MESSAGE: usize = 1500;
+/// Messages read ahead of the producer or queued for the consumer
+const QUEUE: usize = 64;
+/// How often a blocked thread checks whether the event loop still wants it, in ms
+const POLL_TIMEOUT: c_int = 500;
+/// How often the link counters are refreshed
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+/// Round-trip times reported above this, in ms, are taken as this
+const MAX_RTT_MS: f64 = 60_000.0;
+
+#[allow(non_snake_case)]
+mod sys {
+    use std::os::raw::{c_char, c_int, c_void};
+
+    pub type SrtSocket = c_int;
+
+    pub const SRT_ERROR: c_int = -1;
+    pub const SRT_INVALID_SOCK: SrtSocket = -1;
+    /// Nothing was received or sent before `SRTO_RCVTIMEO` or `SRTO_SNDTIMEO`
+    pub const SRT_ETIMEOUT: c_int = 6003;
+
+    pub const SRTO_SNDTIMEO: c_int = 13;
+    pub const SRTO_RCVTIMEO: c_int = 14;
+    pub const SRTO_LATENCY: c_int = 23;
+    pub const SRTO_PASSPHRASE: c_int = 26;
+
+    /// The leading part of `SRT_TRACEBSTATS`, up to the round-trip time, as
+    /// `struct CBytePerfMon` is laid out in the srt.h of libsrt 1.4 and 1.5
+    #[repr(C)]
+    pub struct TraceBStats {
+        // Totals
+        pub msTimeStamp: i64,
+        pub pktSentTotal: i64,
+        pub pktRecvTotal: i64,
+        pub pktSndLossTotal: c_int,
+        pub pktRcvLossTotal: c_int,
+        pub pktRetransTotal: c_int,
+        pub pktSentACKTotal: c_int,
+        pub pktRecvACKTotal: c_int,
+        pub pktSentNAKTotal: c_int,
+        pub pktRecvNAKTotal: c_int,
+        pub usSndDurationTotal: i64,
+        pub pktSndDropTotal: c_int,
+        pub pktRcvDropTotal: c_int,
+        pub pktRcvUndecryptTotal: c_int,
+        pub byteSentTotal: u64,
+        pub byteRecvTotal: u64,
+        pub byteRcvLossTotal: u64,
+        pub byteRetransTotal: u64,
+        pub byteSndDropTotal: u64,
+        pub byteRcvDropTotal: u64,
+        pub byteRcvUndecryptTotal: u64,
+        // Since the last reset
+        pub pktSent: i64,
+        pub pktRecv: i64,
+        pub pktSndLoss: c_int,
+        pub pktRcvLoss: c_int,
+        pub pktRetrans: c_int,
+        pub pktRcvRetrans: c_int,
+        pub pktSentACK: c_int,
+        pub pktRecvACK: c_int,
+        pub pktSentNAK: c_int,
+        pub pktRecvNAK: c_int,
+        pub mbpsSendRate: f64,
+        pub mbpsRecvRate: f64,
+        pub usSndDuration: i64,
+        pub pktReorderDistance: c_int,
+        pub pktRcvAvgBelatedTime: f64,
+        pub pktRcvBelated: i64,
+        pub pktSndDrop: c_int,
+        pub pktRcvDrop: c_int,
+        pub pktRcvUndecrypt: c_int,
+        pub byteSent: u64,
+        pub byteRecv: u64,
+        pub byteRcvLoss: u64,
+        pub byteRetrans: u64,
+        pub byteSndDrop: u64,
+        pub byteRcvDrop: u64,
+        pub byteRcvUndecrypt: u64,
+        // Instant
+        pub usPktSndPeriod: f64,
+        pub pktFlowWindow: c_int,
+        pub pktCongestionWindow: c_int,
+        pub pktFlightSize: c_int,
+        pub msRTT: f64,
+        /// What follows, left alone
+        pub rest: [u64; 64],
+    }
+
+    // The offsets of the fields read in `CBytePerfMon`, checked at build time
+    const _: () = {
+        use std::mem::offset_of;
+        assert!(offset_of!(TraceBStats, pktSndLossTotal) == 24);
+        assert!(offset_of!(TraceBStats, pktRetransTotal) == 32);
+        assert!(offset_of!(TraceBStats, pktSent) == 136);
+        assert!(offset_of!(TraceBStats, mbpsSendRate) == 184);
+        assert!(offset_of!(TraceBStats, msRTT) == 328);
+    };
+
+    #[link(name = "srt")]
+    extern "C" {
+        pub fn srt_startup() -> c_int;
+        pub fn srt_create_socket() -> SrtSocket;
+        pub fn srt_bind(u: SrtSocket, name: *const c_void, namelen: c_int) -> c_int;
+        pub fn srt_listen(u: SrtSocket, backlog: c_int) -> c_int;
+        pub fn srt_accept(u: SrtSocket, addr: *mut c_void, addrlen: *mut c_int) -> SrtSocket;
+        pub fn srt_connect(u: SrtSocket, name: *const c_void, namelen: c_int) -> c_int;
+        pub fn srt_close(u: SrtSocket) -> c_int;
+        pub fn srt_setsockflag(u: SrtSocket, opt: c_int, optval: *const c_void, optlen: c_int) -> c_int;
+        pub fn srt_recvmsg(u: SrtSocket, buf: *mut c_char, len: c_int) -> c_int;
+        pub fn srt_sendmsg2(u: SrtSocket, buf: *const c_char, len: c_int, mctrl: *mut c_void) -> c_int;
+        pub fn srt_getlasterror(errno_loc: *mut c_int) -> c_int;
+        pub fn srt_getlasterror_str() -> *const c_char;
+        pub fn srt_bstats(u: SrtSocket, perf: *mut TraceBStats, clear: c_int) -> c_int;
+    }
+}
+
+const AF_INET: u16 = 2;
+const AF_INET6: u16 = 10;
+
+/// A socket address as libsrt takes it, in the Linux `sockaddr` layout
+#[repr(C, align(8))]
+struct RawAddr {
+    bytes: [u8; 128],
+    len: c_int,
+}
+
+impl RawAddr {
+    fn empty() -> Self {
+        RawAddr { bytes: [0; 128], len: 128 }
+    }
+
+    fn new(addr: &SocketAddr) -> Self {
+        let mut raw = RawAddr::empty();
+        let b = &mut raw.bytes;
+        b[2..4].copy_from_slice(&addr.port().to_be_bytes());
+
+        match *addr {
+            SocketAddr::V4(ref addr) => {
+                b[..2].copy_from_slice(&AF_INET.to_ne_bytes());
+                b[4..8].copy_from_slice(&addr.ip().octets());
+                raw.len = 16;
+            }
+            SocketAddr::V6(ref addr) => {
+                b[..2].copy_from_slice(&AF_INET6.to_ne_bytes());
+                b[4..8].copy_from_slice(&addr.flowinfo().to_be_bytes());
+                b[8..24].copy_from_slice(&addr.ip().octets());
+                b[24..28].copy_from_slice(&addr.scope_id().to_ne_bytes());
+                raw.len = 28;
+            }
+        }
+        raw
+    }
+
+    fn addr(&self) -> Option<SocketAddr> {
+        let b = &self.bytes;
+        let port = u16::from_be_bytes([b[2], b[3]]);
+
+        match u16::from_ne_bytes([b[0], b[1]]) {
+            AF_INET => Some(SocketAddrV4::new(Ipv4Addr::new(b[4], b[5], b[6], b[7]), port).into()),
+            AF_INET6 => {
+                let mut ip = [0; 16];
+                ip.copy_from_slice(&b[8..24]);
+                let flowinfo = u32::from_be_bytes([b[4], b[5], b[6], b[7]]);
+                let scope_id = u32::from_ne_bytes([b[24], b[25], b[26], b[27]]);
+                Some(SocketAddrV6::new(Ipv6Addr::from(ip), port, flowinfo, scope_id).into())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The last libsrt error, prefixed by what failed
+fn last_error(what: &str) -> io::Error {
+    let msg = unsafe { CStr::from_ptr(sys::srt_getlasterror_str()) };
+    io::Error::other(format!("{}: {}", what, msg.to_string_lossy()))
+}
+
+fn timed_out() -> bool {
+    unsafe { sys::srt_getlasterror(std::ptr::null_mut()) == sys::SRT_ETIMEOUT }
+}
+
+/// An SRT socket, closed once dropped
+struct Srt(sys::SrtSocket);
+
+impl Srt {
+    fn new(options: &SrtOptions) -> io::Result<Self> {
+        static STARTUP: Once = Once::new();
+        STARTUP.call_once(|| unsafe {
+            sys::srt_startup();
+        });
+
+        let socket = unsafe { sys::srt_create_socket() };
+        if socket == sys::SRT_INVALID_SOCK {
+            return Err(last_error("cannot create the SRT socket"));
+        }
+        let srt = Srt(socket);
+
+        if let Some(latency) = options.latency {
+            srt.set_int(sys::SRTO_LATENCY, latency.as_millis() as c_int)?;
+        }
+        if let Some(ref passphrase) = options.passphrase {
+            srt.set(sys::SRTO_PASSPHRASE, passphrase.as_bytes())?;
+        }
+        srt.timeouts()?;
+
+        Ok(srt)
+    }
+
+    fn set(&self, opt: c_int, value: &[u8]) -> io::Result<()> {
+        let res = unsafe { sys::srt_setsockflag(self.0, opt, value.as_ptr() as *const c_void, value.len() as c_int) };
+        if res == sys::SRT_ERROR {
+            return Err(last_error("cannot set the SRT options"));
+        }
+        Ok(())
+    }
+
+    fn set_int(&self, opt: c_int, value: c_int) -> io::Result<()> {
+        self.set(opt, &value.to_ne_bytes())
+    }
+
+    /// Block no longer than `POLL_TIMEOUT` on reading and writing
+    fn timeouts(&self) -> io::Result<()> {
+        self.set_int(sys::SRTO_RCVTIMEO, POLL_TIMEOUT)?;
+        self.set_int(sys::SRTO_SNDTIMEO, POLL_TIMEOUT)
+    }
+
+    fn listen(&self, addr: &SocketAddr) -> io::Result<()> {
+        let raw = RawAddr::new(addr);
+        if unsafe { sys::srt_bind(self.0, raw.bytes.as_ptr() as *const c_void, raw.len) } == sys::SRT_ERROR {
+            return Err(last_error(&format!("cannot bind SRT port {}", addr.port())));
+        }
+        if unsafe { sys::srt_listen(self.0, 1) } == sys::SRT_ERROR {
+            return Err(last_error("cannot listen"));
+        }
+        Ok(())
+    }
+
+    fn accept(&self) -> io::Result<(Srt, SocketAddr)> {
+        let mut raw = RawAddr::empty();
+        let socket = unsafe { sys::srt_accept(self.0, raw.bytes.as_mut_ptr() as *mut c_void, &mut raw.len) };
+        if socket == sys::SRT_INVALID_SOCK {
+            return Err(last_error("cannot accept"));
+        }
+
+        let srt = Srt(socket);
+        srt.timeouts()?;
+        Ok((srt, raw.addr().unwrap_or_else(net::placeholder_addr)))
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<()> {
+        let raw = RawAddr::new(addr);
+        if unsafe { sys::srt_connect(self.0, raw.bytes.as_ptr() as *const c_void, raw.len) } == sys::SRT_ERROR {
+            return Err(last_error("cannot connect"));
+        }
+        Ok(())
+    }
+
+    /// Receive a message, none if nothing came before the timeout
+    fn recv(&self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        match unsafe { sys::srt_recvmsg(self.0, buf.as_mut_ptr() as *mut c_char, buf.len() as c_int) } {
+            sys::SRT_ERROR if timed_out() => Ok(None),
+            sys::SRT_ERROR => Err(last_error("cannot receive")),
+            n => Ok(Some(n as usize)),
+        }
+    }
+
+    /// Send a message, false if it could not be queued before the timeout
+    fn send(&self, msg: &[u8]) -> io::Result<bool> {
+        match unsafe { sys::srt_sendmsg2(self.0, msg.as_ptr() as *const c_char, msg.len() as c_int, std::ptr::null_mut()) } {
+            sys::SRT_ERROR if timed_out() => Ok(false),
+            sys::SRT_ERROR => Err(last_error("cannot send")),
+            _ => Ok(true),
+        }
+    }
+
+    /// Copy the connection counters to `link`
+    fn refresh(&self, link: &LinkStats) {
+        let mut perf: sys::TraceBStats = unsafe { mem::zeroed() };
+        if unsafe { sys::srt_bstats(self.0, &mut perf, 0) } == sys::SRT_ERROR {
+            return;
+        }
+
+        let ms = if perf.msRTT.is_nan() { 0.0 } else { perf.msRTT.clamp(0.0, MAX_RTT_MS) };
+        let rtt = Duration::from_secs_f64(ms / 1000.0);
+        let lost = i64::from(perf.pktSndLossTotal) + i64::from(perf.pktRcvLossTotal);
+        link.update(rtt, perf.pktRetransTotal.max(0) as u64, lost.max(0) as u64);
+    }
+}
+
+impl Drop for Srt {
+    fn drop(&mut self) {
+        unsafe {
+            sys::srt_close(self.0);
+        }
+    }
+}
+
+/// An SRT connection, as a socket of the event loop
+pub struct SrtPeer {
+    addr: SocketAddr,
+    /// Messages received from a producer, a consumer only gets the end of the connection
+    rx: mpsc::Receiver<Bytes>,
+    /// What is left of the last message
+    pending: Bytes,
+    /// Messages to send to a consumer
+    tx: Option<PollSender<Bytes>>,
+    /// Tells the thread the connection is not wanted anymore
+    closed: Arc<AtomicBool>,
+    link: Arc<LinkStats>,
+}
+
+impl SrtPeer {
+    /// Receive from a producer, returning the thread serving it
+    fn producer(srt: Srt, addr: SocketAddr) -> (Self, thread::JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(QUEUE);
+        let closed = Arc::new(AtomicBool::new(false));
+        let link = Arc::new(LinkStats::default());
+
+        let thread = {
+            let closed = closed.clone();
+            let link = link.clone();
+            thread::spawn(move || receive(srt, addr, tx, &closed, &link))
+        };
+
+        (SrtPeer { addr, rx, pending: Bytes::new(), tx: None, closed, link }, thread)
+    }
+
+    /// Send to a consumer
+    fn consumer(srt: Srt, addr: SocketAddr) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE);
+        let (hangup, hangup_rx) = mpsc::channel(1);
+        let closed = Arc::new(AtomicBool::new(false));
+        let link = Arc::new(LinkStats::default());
+
+        {
+            let link = link.clone();
+            thread::spawn(move || send(srt, addr, rx, hangup, &link));
+        }
+
+        SrtPeer { addr, rx: hangup_rx, pending: Bytes::new(), tx: Some(PollSender::new(tx)), closed, link }
+    }
+}
+
+impl Drop for SrtPeer {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Release);
+    }
+}
+
+/// Forward the messages of a producer until either side is gone
+fn receive(srt: Srt, addr: SocketAddr, tx: mpsc::Sender<Bytes>, closed: &AtomicBool, link: &LinkStats) {
+    let mut buf = vec![0; MAX_MESSAGE];
+    let mut refreshed = Instant::now();
+
+    while !closed.load(Ordering::Acquire) {
+        if refreshed.elapsed() >= STATS_INTERVAL {
+            srt.refresh(link);
+            refreshed = Instant::now();
+        }
+
+        match srt.recv(&mut buf) {
+            Ok(Some(n)) => {
+                if tx.blocking_send(Bytes::copy_from_slice(&buf[..n])).is_err() {
+                    return;
+                }
+            }
+            Ok(None) => (),
+            Err(e) => {
+                info!("SRT producer {} gone: {}", addr, e);
+                return;
+            }
+        }
+    }
+}
+
+/// Send the messages to a consumer until either side is gone, `_hangup`
+/// tells the event loop once done
+fn send(srt: Srt, addr: SocketAddr, mut rx: mpsc::Receiver<Bytes>, _hangup: mpsc::Sender<Bytes>, link: &LinkStats) {
+    let mut refreshed = Instant::now();
+
+    while let Some(msg) = rx.blocking_recv() {
+        if refreshed.elapsed() >= STATS_INTERVAL {
+            srt.refresh(link);
+            refreshed = Instant::now();
+        }
+
+        loop {
+            match srt.send(&msg) {
+                Ok(true) => break,
+                Ok(false) => (),
+                Err(e) => {
+                    info!("SRT consumer {} gone: {}", addr, e);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl AsyncRead for SrtPeer {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if self.pending.is_empty() {
+            match self.rx.poll_recv(cx) {
+                Poll::Ready(Some(msg)) => self.pending = msg,
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let n = cmp::min(buf.remaining(), self.pending.len());
+        buf.put_slice(&self.pending.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Every write is a message, of 7 packets at most
+impl AsyncWrite for SrtPeer {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let tx = match self.tx {
+            Some(ref mut tx) => tx,
+            None => return Poll::Ready(Err(io::Error::other("cannot write to an SRT producer"))),
+        };
+
+        if ready!(tx.poll_reserve(cx)).is_err() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        let n = cmp::min(buf.len(), PAYLOAD);
+        match tx.send_item(Bytes::copy_from_slice(&buf[..n])) {
+            Ok(()) => Poll::Ready(Ok(n)),
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Socket for SrtPeer {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn link(&self) -> Option<Arc<LinkStats>> {
+        Some(self.link.clone())
+    }
+}
+
+/// The producers connecting to `url`, or the one it calls, reconnecting
+/// according to `backoff` once gone
+pub fn producers(url: SrtUrl, options: SrtOptions, state: Arc<Mutex<Shared>>, backoff: Backoff) -> io::Result<impl Stream<Item = SrtPeer>> {
+    let (tx, mut rx) = mpsc::channel(1);
+
+    match url.mode {
+        SrtMode::Listener => {
+            let listener = Srt::new(&options)?;
+            listener.listen(&url.addr)?;
+            info!("Listening for SRT producers on {}", url.addr);
+            thread::spawn(move || accept(listener, tx));
+        }
+        SrtMode::Caller => {
+            thread::spawn(move || call(url.addr, &options, backoff, tx));
+        }
+    }
+
+    // Dropping a refused producer closes its connection
+    Ok(stream::poll_fn(move |cx| rx.poll_recv(cx))
+        .filter(move |peer: &SrtPeer| future::ready(check_addr(peer.addr, &state, true))))
+}
+
+fn accept(listener: Srt, tx: mpsc::Sender<SrtPeer>) {
+    loop {
+        let (srt, addr) = match listener.accept() {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("Cannot accept the SRT producers: {}", e);
+                return;
+            }
+        };
+
+        info!("SRT producer {} connected", addr);
+        let (peer, _) = SrtPeer::producer(srt, addr);
+        if tx.blocking_send(peer).is_err() {
+            return;
+        }
+    }
+}
+
+fn call(addr: SocketAddr, options: &SrtOptions, backoff: Backoff, tx: mpsc::Sender<SrtPeer>) {
+    let mut delay = backoff.initial;
+
+    loop {
+        match Srt::new(options).and_then(|srt| srt.connect(&addr).map(|_| srt)) {
+            Ok(srt) => {
+                info!("Connected to the SRT producer {}", addr);
+                let since = Instant::now();
+                let (peer, thread) = SrtPeer::producer(srt, addr);
+                if tx.blocking_send(peer).is_err() {
+                    return;
+                }
+                let _ = thread.join();
+
+                // Start over unless the producer keeps dropping the connection
+                if since.elapsed() >= backoff.max {
+                    delay = backoff.initial;
+                }
+            }
+            Err(e) => error!("Cannot connect to srt://{}: {}", addr, e),
+        }
+
+        info!("Reconnecting to srt://{} in {:?}", addr, delay);
+        thread::sleep(delay);
+        delay = cmp::min(delay * 2, backoff.max);
+    }
+}
+
+/// Connect to a consumer, without blocking the event loop
+async fn connect(addr: SocketAddr, options: SrtOptions) -> io::Result<SrtPeer> {
+    let (tx, rx) = oneshot::channel();
+    thread::spawn(move || {
+        let _ = tx.send(Srt::new(&options).and_then(|srt| srt.connect(&addr).map(|_| srt)));
+    });
+
+    match rx.await {
+        Ok(res) => res.map(|srt| SrtPeer::consumer(srt, addr)),
+        Err(_) => Err(io::Error::other("the SRT connection thread is gone")),
+    }
+}
+
+/// Keep an SRT consumer connection to `addr` open for as long as the restreamer runs
+pub fn push(addr: SocketAddr, options: SrtOptions, cbr: Option<u64>, state: Arc<Mutex<Shared>>, shutdown: OneShotSharedRx, buffer_size: usize,
+            backoff: Backoff) -> impl Future<Output = ()> {
+    net::retry(format!("srt://{}", addr), backoff, move || connect(addr, options.clone()),
+               move |peer| push::serve(peer, &state, &shutdown, buffer_size, None, cbr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_addr() {
+        for addr in &["10.0.0.1:9000", "[2001:db8::1]:9000"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            assert_eq!(RawAddr::new(&addr).addr(), Some(addr));
+        }
+        assert_eq!(RawAddr::new(&"10.0.0.1:9000".parse().unwrap()).bytes[2..8], [0x23, 0x28, 10, 0, 0, 1]);
+    }
+}
@@ -0,0 +1,94 @@
+//! Set up the logger, filtered through `RUST_LOG` as usual
+
+use env_logger::Builder;
+
+use std::env;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Level logged when `RUST_LOG` is not set
+const DEFAULT_FILTER: &str = "info";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// `LEVEL target: message`
+    Text,
+    /// One JSON object per record
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format {}, use text or json", s)),
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_record(at: SystemTime, level: &str, target: &str, message: &str) -> String {
+    format!("{{\"timestamp\": {:.3}, \"level\": \"{}\", \"target\": {}, \"message\": {}}}",
+            at.duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0),
+            level.to_lowercase(), json_string(target), json_string(message))
+}
+
+/// Install the logger, once
+///
+/// A malformed `RUST_LOG` is reported and ignored, a logger already set is kept.
+pub fn init(format: LogFormat) {
+    let filter = env::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_FILTER.to_owned());
+    let mut builder = Builder::new();
+
+    match format {
+        LogFormat::Text => builder.format(|buf, record| writeln!(buf, "{:<5} {}: {}", record.level(), record.target(), record.args())),
+        LogFormat::Json => builder.format(|buf, record| {
+            writeln!(buf, "{}", json_record(SystemTime::now(), &record.level().to_string(), record.target(), &record.args().to_string()))
+        }),
+    };
+
+    if let Err(e) = builder.parse_filters(&filter).try_init() {
+        eprintln!("Cannot set up the logger: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn json_records() {
+        let at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_250);
+
+        assert_eq!(json_record(at, "WARN", "restream", "Disconnecting Consumer (\"a\"), lagging"),
+                   "{\"timestamp\": 1700000000.250, \"level\": \"warn\", \"target\": \"restream\", \
+                    \"message\": \"Disconnecting Consumer (\\\"a\\\"), lagging\"}");
+    }
+
+    #[test]
+    fn second_init_ignored() {
+        init(LogFormat::Json);
+        init(LogFormat::Text);
+    }
+}
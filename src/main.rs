@@ -1,331 +1,1226 @@
-extern crate bytes;
-#[macro_use]
-extern crate futures;
-extern crate pretty_env_logger;
-extern crate tokio;
-#[macro_use]
-extern crate tokio_io;
-
-#[macro_use]
-extern crate structopt;
-
-extern crate mio;
-extern crate tk_listen;
+mod config;
+mod logging;
 
 use structopt::StructOpt;
+use structopt::clap::ArgMatches;
+use log::{error, info, warn};
 
 use tokio::runtime::Runtime;
-use tokio::net::{TcpListener, TcpStream};
-use tokio_io::AsyncRead;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time;
 use futures::prelude::*;
-use futures::task;
-use futures::sync::mpsc;
-use futures::sync::oneshot;
-use futures::future::IntoStream;
-use bytes::{BufMut, Bytes, BytesMut};
-
-use mio::unix::UnixReady;
-use tk_listen::ListenExt;
+use futures::future::{self, Either};
+use futures::stream;
+
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process;
+use std::sync::Arc;
 use std::time::Duration;
 
-use std::io::{self, Write};
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::{Mutex, Arc};
+use crate::logging::LogFormat;
+#[cfg(unix)]
+use restream::{sd_notify, Account, Activation};
+use restream::{parse_accept_rate, parse_bitrate, parse_dscp, parse_mode, parse_packet_size, parse_pid, parse_secs, parse_size, split_cbr, AccessFormat, AccessLog, Acl, Aes, Backoff, Bandwidth, BroadcastDelay, Burst, Cidr, Copied, Ending, Fsync, Hls, Input, Iv, Key, LocalBind, Output, Overflow, PidFilter, PidRemap, Priority, ProducerTls, Record, Remap, Restreamer, Settings, Simulate, Simulator, Sink, SrtOptions, Thresholds, Tls, UdpTarget, Webhook};
 
-type Tx = mpsc::UnboundedSender<Bytes>;
-type Rx = mpsc::UnboundedReceiver<Bytes>;
+#[derive(StructOpt, Clone, Debug)]
+#[structopt()]
+struct Config {
+    #[structopt(long = "config", parse(from_os_str), help = "Load the settings from this TOML file")]
+    /// The flags given on the command line override the file
+    config: Option<PathBuf>,
 
-type OneShotTx = oneshot::Sender<()>;
-type OneShotRx = oneshot::Receiver<()>;
-type OneShotSharedRx = futures::future::Shared<OneShotRx>;
-type OneShotStreamRx = IntoStream<futures::future::Shared<OneShotRx>>;
+    #[structopt(long = "log-format", help = "Format of the log records: text or json", default_value = "text")]
+    /// json writes one object per record, with its timestamp, level, target and message. RUST_LOG filters them, info by default
+    log_format: LogFormat,
 
-enum Kind {
-    Consumer(OneShotStreamRx),
-    Producer(OneShotTx),
-}
+    #[structopt(short = "p", long = "port", help = "Set listening ports", default_value = "12345")]
+    /// Set the listening ports, consumer ports is ${producer port +1}
+    port: u16,
+    #[structopt(long = "channels", help = "Serve this many independent channels on consecutive port pairs", default_value = "1")]
+    /// Channel N listens on ${port + 2N} and ${port + 2N + 1}, the monitoring ports are shifted the same way
+    channels: u16,
 
-impl Kind {
-    fn is_producer(&self) -> bool {
-        match self {
-            Kind::Producer(_) => true,
-            _ => false
-        }
-    }
-    fn is_consumer(&self) -> bool {
-        !self.is_producer()
-    }
-}
+    #[structopt(short = "I", help = "Set the input host", default_value = "127.0.0.1")]
+    /// Address or hostname, resolved on startup. :: accepts both IPv6 and IPv4 producers
+    input_host: String,
 
-struct Shared {
-    peers: HashMap<SocketAddr, Tx>,
-}
+    #[structopt(short = "O", help = "Set the output host", default_value = "127.0.0.1")]
+    /// Address or hostname, resolved on startup. :: accepts both IPv6 and IPv4 consumers
+    output_host: String,
 
-struct Peer {
-    packets: TSPacket,
-    state: Arc<Mutex<Shared>>,
+    #[structopt(short = "b", help = "Set the packet buffer size", default_value = "1316")]
+    buffer: usize,
 
-    rx: Rx,
+    #[structopt(long = "no-align", help = "Do not align the chunks to the MPEG-TS packets")]
+    /// Forward the producer data in raw buffer-sized chunks
+    no_align: bool,
 
-    addr: SocketAddr,
-    kind: Kind,
-}
+    #[structopt(long = "no-nodelay", help = "Let Nagle batch the writes to the TCP peers")]
+    /// TCP_NODELAY is set on the producer and consumer sockets by default
+    no_nodelay: bool,
 
-/// TS Packet chunker
-struct TSPacket {
-    buffer_size: usize,
-    socket: TcpStream,
+    #[structopt(long = "tcp-keepalive", parse(try_from_str = parse_secs), help = "Send TCP keepalive probes after this many idle seconds")]
+    /// Detects the half-dead peers instead of queueing for them forever
+    tcp_keepalive: Option<Duration>,
 
-    rd: BytesMut,
-    wr: BytesMut,
-}
+    #[structopt(long = "dscp", parse(try_from_str = parse_dscp), help = "Mark the packets sent to the peers with this DSCP, 0 to 63 or a name such as EF or AF41")]
+    /// Applies to the producer and consumer connections and to the outputs without a DSCP of their own;
+    /// a value the kernel refuses is logged and the packets go unmarked
+    dscp: Option<u8>,
 
-impl Shared {
-    fn new() -> Self {
-        Shared {
-            peers: HashMap::new(),
-        }
-    }
-}
+    #[structopt(long = "proxy-protocol", help = "Expect a PROXY protocol header on the producer and consumer connections")]
+    /// Sent by a load balancer, v1 or v2; the connections without one within 3 seconds are closed
+    proxy_protocol: bool,
 
-impl Peer {
-    fn new(state: Arc<Mutex<Shared>>, packets: TSPacket, kind: Kind) -> Peer {
-        let addr = packets.socket.peer_addr().unwrap();
+    #[structopt(long = "accept-rate", parse(try_from_str = parse_accept_rate), help = "Accept at most this many connections per second from each address")]
+    /// The others are closed right away, without a log line
+    accept_rate: Option<f64>,
 
-        let (tx, rx) = mpsc::unbounded();
+    #[structopt(long = "backlog", help = "Connections queued by the kernel on each TCP listener until accepted", default_value = "1024")]
+    /// Raise along with net.core.somaxconn, for the reconnect storms
+    backlog: u32,
 
-        if kind.is_consumer() {
-            state.lock().unwrap().peers.insert(addr, tx);
-        }
+    #[structopt(long = "bind-retry", parse(try_from_str = parse_secs), help = "Retry binding the TCP ports in use for this many seconds")]
+    /// While the instance being replaced lets go of them
+    bind_retry: Option<Duration>,
 
-        Peer {
-            packets,
-            state,
-            rx,
-            addr,
-            kind,
-        }
-    }
-}
+    #[structopt(long = "reuseport", help = "Accept the consumers with this many listeners sharing the port", default_value = "1")]
+    /// SO_REUSEPORT lets the kernel spread the connections, a single listener where it is not available
+    reuseport: usize,
 
-impl Future for Peer {
-    type Item = ();
-    type Error = io::Error;
-
-    fn poll(&mut self) -> Poll<(), io::Error> {
-        if let Kind::Consumer(ref mut rx) = self.kind {
-            while self.packets.wr.remaining_mut() > 0 {
-                let rx = rx.by_ref().map(|_| Bytes::new()).map_err(|_| ());
-                match self.rx.by_ref()
-                    .select(rx)
-                    .poll().unwrap_or(Async::Ready(None)) {
-                    Async::Ready(Some(v)) => {
-                        self.packets.buffer(&v);
-                    },
-                    Async::Ready(None) => return Ok(Async::Ready(())),
-                    _ => break,
-                }
-            }
+    #[structopt(long = "local-addr", help = "Send the UDP, RIST and push outputs from this local address")]
+    /// Per target, append ?local=IP to the --udp-out or tcp:// --push url instead
+    local_addr: Option<IpAddr>,
 
-            if self.packets.wr.remaining_mut() <= 0 {
-                task::current().notify();
-            }
+    #[structopt(long = "bind-device", help = "Send the UDP, RIST and push outputs through this network interface, Linux only")]
+    /// SO_BINDTODEVICE, needs CAP_NET_RAW on older kernels; per target, append ?device=NAME to the url instead
+    bind_device: Option<String>,
 
-            match self.packets.poll_flush()? {
-                Async::Ready(false) => return Ok(Async::Ready(())),
-                _ => (),
-            }
-        } else {
-            while let Async::Ready(pkt) = self.packets.poll()? {
-                if let Some(packet) = pkt {
-                    let packet = packet.freeze();
-
-                    for (_addr, tx) in &self.state.lock().unwrap().peers {
-                        tx.unbounded_send(packet.clone()).unwrap();
-                    }
-                } else {
-                    return Ok(Async::Ready(()));
-                }
-            }
-        }
+    #[structopt(long = "user", help = "Switch to this user once the producer and consumer ports are bound, Unix only")]
+    /// For the ports below 1024, the stream is never handled as root; the group defaults to the primary one of the user
+    user: Option<String>,
 
-        Ok(Async::NotReady)
-    }
-}
+    #[structopt(long = "group", help = "Switch to this group once the producer and consumer ports are bound, Unix only")]
+    group: Option<String>,
 
-impl Drop for Peer {
-    fn drop(&mut self) {
-        self.state.lock().unwrap().peers.remove(&self.addr);
+    #[structopt(short = "u", long = "udp-input", help = "Receive the producer stream over UDP")]
+    /// Bind an UDP socket on the producer port instead of listening for a TCP connection
+    udp_input: bool,
 
-        eprintln!("Dropping {}", self);
-    }
+    #[structopt(long = "stdin", help = "Read the producer stream from the standard input")]
+    /// e.g. ffmpeg ... -f mpegts - | restream --stdin, the same as --input -
+    stdin: bool,
+
+    #[structopt(long = "exit-on-stdin-eof", help = "Exit once the standard input ends")]
+    /// Otherwise the end of the standard input is a producer disconnect, the monitoring keeps running
+    exit_on_stdin_eof: bool,
+
+    #[structopt(long = "udp-timeout", parse(try_from_str = parse_secs), help = "Seconds without datagrams before the UDP producer is considered gone", default_value = "5")]
+    udp_timeout: Duration,
+
+    #[structopt(long = "rtp-in", help = "Strip the RTP header from the UDP input datagrams")]
+    /// Datagrams that do not look like RTP are passed through
+    rtp_in: bool,
+
+    #[structopt(long = "input-backup", help = "Switch to this input when the producer fails, e.g. file:///srv/slate.ts, tcp://HOST:PORT or udp://ADDR:PORT")]
+    /// Without --failover-timeout, an udp:// backup of an UDP input receives the same RTP stream, both paths are merged
+    input_backup: Option<Input>,
+
+    #[structopt(long = "failover-timeout", parse(try_from_str = parse_secs), help = "Seconds without data from the producer before switching to the backup input")]
+    failover_timeout: Option<Duration>,
+
+    #[structopt(long = "failback-delay", parse(try_from_str = parse_secs), help = "Seconds the producer must send again for before switching back to it", default_value = "5")]
+    failback_delay: Duration,
+
+    #[structopt(long = "merge-window", help = "Packets a packet missing on one input path waits for the other one", default_value = "64")]
+    merge_window: usize,
+
+    #[structopt(long = "input", help = "Set the producer input, e.g. tcp://127.0.0.1:12345, udp://239.1.2.3:5000, srt://:9000 or unix:/run/restreamer/in.sock")]
+    /// Overrides the input host and port
+    input: Option<Input>,
+
+    #[structopt(long = "output", help = "Set the consumer listener, e.g. tcp://127.0.0.1:12346 or unix:/run/restreamer/out.sock")]
+    /// Overrides the output host and port
+    output: Option<Output>,
+
+    #[structopt(long = "socket-mode", parse(try_from_str = parse_mode), help = "Set the permissions of the unix sockets, e.g. 660")]
+    socket_mode: Option<u32>,
+
+    #[structopt(long = "pull", help = "Connect to the producer at tcp://HOST:PORT instead of waiting for it")]
+    /// Overrides the other inputs, the connection is retried whenever it drops
+    pull: Option<String>,
+
+    #[structopt(long = "pull-timeout", parse(try_from_str = parse_secs), help = "Reconnect to the pulled producer after this many seconds without data")]
+    pull_timeout: Option<Duration>,
+
+    #[structopt(long = "play", parse(from_os_str), help = "Play this MPEG-TS file in a loop as the producer")]
+    /// Overrides the other inputs, unless --slate is given
+    play: Option<PathBuf>,
+
+    #[structopt(long = "play-bitrate", help = "Play the file at this many bits per second instead of following its PCR")]
+    play_bitrate: Option<u64>,
+
+    #[structopt(long = "slate", help = "Play the file only while no producer is streaming")]
+    /// The consumers stay connected when the producer comes and goes
+    slate: bool,
+
+    #[structopt(long = "generate", parse(try_from_str = parse_bitrate), help = "Generate a test signal at this bitrate, e.g. 4M, while no producer is streaming")]
+    /// A single program with its PAT, PMT and PCR, and private data filling the bitrate
+    generate: Option<u64>,
+
+    #[structopt(long = "generate-only", help = "Generate the test signal as the only producer")]
+    /// Overrides the other inputs, for load tests
+    generate_only: bool,
+
+    #[structopt(long = "input-iface", help = "Set the interface used to join the input multicast group")]
+    /// IPv4 address of the interface for IPv4 groups, interface index for IPv6 ones
+    input_iface: Option<String>,
+
+    #[structopt(long = "http-out", help = "Serve the consumers over HTTP")]
+    /// Consumers must send a GET request before receiving the stream
+    http_out: bool,
+
+    #[structopt(long = "ws-port", help = "Also serve the consumers over WebSocket on this port")]
+    /// Bound on the output host, each chunk goes out as a binary frame, e.g. for mpegts.js
+    ws_port: Option<u16>,
+
+    #[structopt(long = "tls-cert", parse(from_os_str), help = "Serve the consumers over TLS with this PEM certificate chain")]
+    /// Requires --tls-key
+    tls_cert: Option<PathBuf>,
+
+    #[structopt(long = "tls-key", parse(from_os_str), help = "PEM (PKCS#8) private key of the TLS certificate")]
+    tls_key: Option<PathBuf>,
+
+    #[structopt(long = "encrypt-key", help = "Encrypt the packets sent to the TCP consumers with this AES-128 key, 32 hexadecimal digits")]
+    /// CBC with residual block termination, the tables and the headers stay in the clear
+    encrypt_key: Option<Key>,
+
+    #[structopt(long = "encrypt-iv", help = "IV of the encrypted packets, 32 hexadecimal digits, all zeros by default")]
+    encrypt_iv: Option<Iv>,
+
+    #[structopt(long = "producer-takeover", help = "Let a new producer replace the active one")]
+    /// By default a second producer is rejected while one is streaming
+    producer_takeover: bool,
+
+    #[structopt(long = "producer-token", help = "Require the producer to send this token, followed by a newline, first")]
+    producer_token: Option<String>,
+
+    #[structopt(long = "producer-tls-cert", parse(from_os_str), help = "Require the producers to use TLS, with this PEM certificate chain")]
+    /// Requires --producer-tls-key and --producer-tls-ca
+    producer_tls_cert: Option<PathBuf>,
+
+    #[structopt(long = "producer-tls-key", parse(from_os_str), help = "PEM private key of the producer TLS certificate")]
+    producer_tls_key: Option<PathBuf>,
+
+    #[structopt(long = "producer-tls-ca", parse(from_os_str), help = "Only accept producers with a client certificate signed by this PEM CA")]
+    producer_tls_ca: Option<PathBuf>,
+
+    #[structopt(long = "decrypt-key", help = "Decrypt the packets of the producers, as encrypted with --encrypt-key upstream")]
+    /// E.g. pulling from a restreamer given the same key, for an encrypted tunnel over plain TCP
+    decrypt_key: Option<Key>,
+
+    #[structopt(long = "decrypt-iv", help = "IV of the packets decrypted, as given with --encrypt-iv upstream")]
+    decrypt_iv: Option<Iv>,
+
+    #[structopt(long = "allow-producer", help = "Only accept producers from this address block", number_of_values = 1)]
+    /// Can be repeated, e.g. 10.0.0.0/8 or 2001:db8::/32
+    allow_producer: Vec<Cidr>,
+
+    #[structopt(long = "deny-producer", help = "Refuse producers from this address block", number_of_values = 1)]
+    deny_producer: Vec<Cidr>,
+
+    #[structopt(long = "allow-consumer", help = "Only accept consumers from this address block", number_of_values = 1)]
+    allow_consumer: Vec<Cidr>,
+
+    #[structopt(long = "deny-consumer", help = "Refuse consumers from this address block", number_of_values = 1)]
+    deny_consumer: Vec<Cidr>,
+
+    #[structopt(long = "consumer-priority", help = "Put the consumers from an address block, or connecting to a local port, in a higher tier, e.g. 2=10.0.0.0/8 or 1=:8081", number_of_values = 1)]
+    /// Can be repeated, the highest tier matching wins; the higher tiers go first under the total rate limit and take the place of the lower ones when full
+    consumer_priority: Vec<Priority>,
+
+    #[structopt(long = "max-consumers", help = "Set the maximum number of consumers connected at the same time")]
+    max_consumers: Option<usize>,
+
+    #[structopt(long = "consumer-queue", help = "Set the number of packets queued per consumer", default_value = "1024")]
+    consumer_queue: usize,
+
+    #[structopt(long = "overflow-policy", help = "What to do when a consumer queue is full: drop or disconnect", default_value = "drop")]
+    /// drop discards the oldest queued packet, disconnect closes the consumer connection
+    overflow_policy: Overflow,
+
+    #[structopt(long = "write-batch", parse(try_from_str = parse_size), help = "Write up to this much of the chunks queued for a consumer at once, 1 for a chunk at a time", default_value = "64K")]
+    /// The chunks go out in one vectored write, fewer system calls for a bit more latency
+    write_batch: u64,
+
+    #[structopt(long = "max-lag-bytes", help = "Disconnect the consumers falling behind by more than this many bytes")]
+    max_lag_bytes: Option<usize>,
+
+    #[structopt(long = "max-lag-secs", parse(try_from_str = parse_secs), help = "Disconnect the consumers not accepting data for this many seconds")]
+    max_lag_secs: Option<Duration>,
+
+    #[structopt(long = "consumer-idle-timeout", parse(try_from_str = parse_secs), help = "Disconnect the consumers whose socket accepts no data for this many seconds")]
+    /// Only while data is waiting for them, checked even if the producer sends nothing new
+    consumer_idle_timeout: Option<Duration>,
+
+    #[structopt(long = "consumer-options", help = "Let the consumers send OPTS burst=2s program=3 pace=2M rewind=30 within 500ms of connecting")]
+    /// The consumers sending nothing or anything else get the stream as usual, after the 500ms
+    consumer_options: bool,
+
+    #[structopt(long = "relay-resume", help = "Resume the relays pulling from here where they left off, and the stream pulled with --pull likewise")]
+    /// Both ends of a chain need it, the relays then send RESUME on connecting
+    relay_resume: bool,
+
+    #[structopt(long = "reconnect-grace", parse(try_from_str = parse_secs), help = "Keep the queue of a consumer sending OPTS token=... for this many seconds after it lost its connection")]
+    /// Reconnecting with the same token within the grace period picks the stream up where it was left
+    reconnect_grace: Option<Duration>,
+
+    #[structopt(long = "consumer-rate-limit", parse(try_from_str = parse_bitrate), help = "Send each consumer at most this many bits per second, e.g. 2M")]
+    /// A consumer sending OPTS pace=... gets its own pace instead
+    consumer_rate_limit: Option<u64>,
+
+    #[structopt(long = "total-rate-limit", parse(try_from_str = parse_bitrate), help = "Send at most this many bits per second to all the consumers together, e.g. 100M")]
+    /// Across the channels; the consumers that would push the expected total over it are refused
+    total_rate_limit: Option<u64>,
+
+    #[structopt(long = "clean-start", help = "Start the new consumers on the PAT and PMT followed by a keyframe")]
+    /// The live data is held back until then, at most --clean-start-timeout
+    clean_start: bool,
+
+    #[structopt(long = "clean-start-timeout", parse(try_from_str = parse_secs), help = "Seconds to wait for a keyframe before starting the consumers anyway", default_value = "2")]
+    clean_start_timeout: Duration,
+
+    #[structopt(long = "stream-keys", help = "Route on the PUBLISH <key> and PLAY <key> lines sent first by the producers and consumers")]
+    /// Every key is a stream of its own, on the same producer and consumer ports
+    stream_keys: bool,
+
+    #[structopt(long = "wait-for-producer", help = "Keep the consumers of a stream key until it is published, instead of rejecting them")]
+    wait_for_producer: bool,
+
+    #[structopt(long = "producer-stall-timeout", parse(try_from_str = parse_secs), help = "Flag the producer as stalled after this many seconds without data")]
+    /// Logged and reported in the metrics and the status
+    producer_stall_timeout: Option<Duration>,
+
+    #[structopt(long = "disconnect-consumers-on-stall", help = "Disconnect the consumers when the producer stalls")]
+    /// So their players can fail over to another source
+    disconnect_consumers_on_stall: bool,
+
+    #[structopt(long = "keepalive-nulls", parse(try_from_str = parse_secs), help = "Send the consumers null packets every this many seconds while no producer sends anything")]
+    /// For the receivers hanging up on silence, stops as soon as the stream is back
+    keepalive_nulls: Option<Duration>,
+
+    #[structopt(long = "validate-input", help = "Fan out nothing of a producer until its input shows MPEG-TS packets")]
+    /// The producers sending anything else are disconnected
+    validate_input: bool,
+
+    #[structopt(long = "sync-loss-budget", help = "Disconnect a validated producer losing sync more often than this a minute", default_value = "10")]
+    sync_loss_budget: usize,
+
+    #[structopt(long = "packet-size", parse(try_from_str = parse_packet_size), help = "Size of the producer packets, 188, 192 or 204, detected from the sync bytes by default")]
+    /// For the streams too ambiguous to tell, the packets are stripped down to 188 bytes either way
+    packet_size: Option<usize>,
+
+    #[structopt(long = "max-read-buffer", parse(try_from_str = parse_size), help = "Stop reading from the producer while this much data waits to be fanned out, e.g. 4M", default_value = "4M")]
+    /// The producer is then slowed down by TCP, a new value applies to the next producer
+    max_read_buffer: u64,
+
+    #[structopt(long = "read-pool", help = "Read buffers of the producers kept for reuse, 0 to allocate every one", default_value = "256")]
+    /// A buffer is read into again once the chunks fanned out of it are gone
+    read_pool: usize,
+
+    #[structopt(long = "burst", help = "Replay the last part of the stream to new consumers, e.g. 4M or 2s")]
+    /// Size of the late-join buffer, in bytes (K, M, G suffixes) or duration (s, ms suffixes)
+    burst: Option<Burst>,
+
+    #[structopt(long = "dvr-window", parse(try_from_str = parse_secs), help = "Keep the last this many seconds for the consumers to rewind, with OPTS rewind=30 or ?rewind=30")]
+    /// Replayed from the past at their own pace, the consumers then follow the live stream
+    dvr_window: Option<Duration>,
+
+    #[structopt(long = "dvr-max-bytes", parse(try_from_str = parse_size), help = "Keep no more than this for rewinding, e.g. 512M", default_value = "256M")]
+    dvr_max_bytes: u64,
+
+    #[structopt(long = "drop-pid", parse(try_from_str = parse_pid), help = "Never send the packets of this PID to the consumers", number_of_values = 1)]
+    /// Can be repeated, in decimal or hexadecimal, e.g. 0x100
+    drop_pid: Vec<u16>,
+
+    #[structopt(long = "keep-pid", parse(try_from_str = parse_pid), help = "Only send the packets of this PID to the consumers", number_of_values = 1, conflicts_with = "drop-pid")]
+    /// Can be repeated, the PAT (PID 0) is always sent
+    keep_pid: Vec<u16>,
+
+    #[structopt(long = "program", help = "Only send this program to the consumers, under a PAT listing it alone")]
+    /// Its PMT, PCR and elementary streams, as listed by the stream tables
+    program: Option<u16>,
+
+    #[structopt(long = "remap-pid", help = "Send the packets of PID OLD as PID NEW, given as OLD:NEW", number_of_values = 1)]
+    /// Can be repeated, the PAT and PMT are patched to match
+    remap_pid: Vec<Remap>,
+
+    #[structopt(long = "strip-nulls", help = "Never send the null packets (PID 0x1fff) to the consumers")]
+    /// The packets left are sent in full chunks
+    strip_nulls: bool,
+
+    #[structopt(long = "delay", parse(try_from_str = parse_secs), help = "Fan out the stream this many seconds after it is received, e.g. 7")]
+    /// The consumers connecting meanwhile wait for the delay to fill rather than get a shorter one
+    delay: Option<Duration>,
+
+    #[structopt(long = "delay-memory", parse(try_from_str = parse_size), help = "Spill the delayed stream to disk past this size, e.g. 64M", default_value = "64M")]
+    delay_memory: u64,
+
+    #[structopt(long = "pad-during-prime", help = "Send null packets to the consumers until the delay is filled")]
+    pad_during_prime: bool,
+
+    #[structopt(long = "shutdown-timeout", parse(try_from_str = parse_secs), help = "Seconds to wait for the consumers to flush on shutdown", default_value = "5")]
+    /// After that the remaining consumers are closed right away
+    shutdown_timeout: Duration,
+
+    #[structopt(long = "record", parse(from_os_str), help = "Record the stream to files in this directory")]
+    /// The files are named after their UTC start time, e.g. 20240101-1200.ts
+    record: Option<PathBuf>,
+
+    #[structopt(long = "record-max-size", parse(try_from_str = parse_size), help = "Start a new recording file past this size, e.g. 512M")]
+    record_max_size: Option<u64>,
+
+    #[structopt(long = "record-duration", parse(try_from_str = parse_secs), help = "Start a new recording file every this many seconds")]
+    record_duration: Option<Duration>,
+
+    #[structopt(long = "record-fsync", help = "When to sync the recording to the disk: never, rotate or always", default_value = "rotate")]
+    /// rotate syncs every file once it is closed, always after every chunk
+    record_fsync: Fsync,
+
+    #[structopt(long = "record-splice", help = "Splice the producer straight into the recording while nothing else takes the stream, Linux only")]
+    /// Keeps the stream out of userspace while the recorder is its only taker, a TCP producer going as is
+    record_splice: bool,
+
+    #[structopt(long = "hls", help = "Segment the stream for HLS clients, served on /hls/index.m3u8")]
+    /// On the status, metrics and --hls-port ports, the segments are kept in memory
+    hls: bool,
+
+    #[structopt(long = "hls-dir", parse(from_os_str), help = "Also write the HLS segments and playlist to this directory")]
+    /// Implies --hls, the segments out of the playlist are deleted
+    hls_dir: Option<PathBuf>,
+
+    #[structopt(long = "hls-segment-duration", parse(try_from_str = parse_secs), help = "Shortest HLS segment, in seconds", default_value = "6")]
+    /// Segments are cut on the next keyframe, or on the next PAT without video
+    hls_segment_duration: Duration,
+
+    #[structopt(long = "hls-window", help = "Number of segments in the HLS playlist", default_value = "5")]
+    hls_window: usize,
+
+    #[structopt(long = "hls-port", help = "Serve the HLS playlist and segments on this port")]
+    /// Bound on the output host
+    hls_port: Option<u16>,
+
+    #[structopt(long = "stdout", help = "Copy the stream to the standard output")]
+    /// e.g. restream --stdout | ffprobe -, the logs only go to the standard error
+    stdout: bool,
+
+    #[structopt(long = "sink", parse(from_os_str), help = "Copy the stream to this file", conflicts_with = "stdout")]
+    /// Appended to, without rotation. A sink falling behind follows --overflow-policy like a consumer
+    sink: Option<PathBuf>,
+
+    #[structopt(long = "metrics-port", help = "Serve Prometheus metrics on /metrics on this port")]
+    /// Bound on the output host
+    metrics_port: Option<u16>,
+
+    #[structopt(long = "status-port", help = "Serve a JSON status on /status on this port")]
+    /// Bound on the output host, /healthz and /livez are served there too
+    status_port: Option<u16>,
+
+    #[structopt(long = "health-max-idle", parse(try_from_str = parse_secs), help = "Seconds the producer may send nothing before /healthz fails", default_value = "5")]
+    health_max_idle: Duration,
+
+    #[structopt(long = "pat-interval", parse(try_from_str = parse_secs), help = "Seconds without a PAT before the TR 101 290 PAT error is raised", default_value = "0.5")]
+    pat_interval: Duration,
+
+    #[structopt(long = "pmt-interval", parse(try_from_str = parse_secs), help = "Seconds without a PMT before the TR 101 290 PMT error is raised", default_value = "0.5")]
+    pmt_interval: Duration,
+
+    #[structopt(long = "pid-timeout", parse(try_from_str = parse_secs), help = "Seconds without a PID listed in a PMT before the TR 101 290 PID error is raised", default_value = "5")]
+    pid_timeout: Duration,
+
+    #[structopt(long = "control-socket", parse(from_os_str), help = "Accept control commands on this unix socket")]
+    /// list, kick ADDR and drop-producer, one per line
+    control_socket: Option<PathBuf>,
+
+    #[structopt(long = "webhook", help = "POST the producer and consumer events as JSON to this http://HOST[:PORT]/PATH")]
+    /// Producer connected, disconnected, stalled and the consumer thresholds crossed
+    webhook: Option<String>,
+
+    #[structopt(long = "webhook-threshold", help = "Notify the webhook when the number of consumers reaches or falls below this", number_of_values = 1)]
+    /// Can be repeated
+    webhook_threshold: Vec<usize>,
+
+    #[structopt(long = "access-log", parse(from_os_str), help = "Log every finished connection to this file")]
+    /// Role, address, duration, bytes and reason of the disconnection, reopened on SIGUSR1.
+    /// Channel N > 0 logs to FILE.N
+    access_log: Option<PathBuf>,
+
+    #[structopt(long = "access-log-format", help = "Format of the access log lines: text or json", default_value = "text")]
+    access_log_format: AccessFormat,
+
+    #[structopt(long = "push", help = "Connect to a consumer at tcp://HOST:PORT or srt://HOST:PORT, or send to a RIST receiver at rist://HOST:PORT", number_of_values = 1)]
+    /// Can be repeated, the connection is retried for the whole process lifetime
+    push: Vec<String>,
+
+    #[structopt(long = "push-retry-min", parse(try_from_str = parse_secs), help = "Seconds before the first reconnection to a push consumer", default_value = "1")]
+    push_retry_min: Duration,
+
+    #[structopt(long = "push-retry-max", parse(try_from_str = parse_secs), help = "Maximum seconds between the reconnections to a push consumer", default_value = "30")]
+    /// The delay doubles after every failed attempt up to this value
+    push_retry_max: Duration,
+
+    #[structopt(long = "rist-buffer", help = "Milliseconds of stream the RIST outputs keep to retransmit the lost packets", default_value = "1000")]
+    /// The receiver asks for them with NACKs over RTCP
+    rist_buffer: u64,
+
+    #[structopt(long = "push-dscp", parse(try_from_str = parse_dscp), help = "Mark the packets sent to the TCP and RIST push targets with this DSCP")]
+    push_dscp: Option<u8>,
+
+    #[structopt(long = "srt-latency", help = "Milliseconds given to the SRT connections to recover the lost packets")]
+    /// Applies to the SRT input and push targets, the libsrt default is 120
+    srt_latency: Option<u64>,
+
+    #[structopt(long = "srt-passphrase", help = "Encrypt the SRT connections with this passphrase, 10 to 79 characters")]
+    srt_passphrase: Option<String>,
+
+    #[structopt(long = "udp-out", help = "Push the stream to an UDP destination, use rtp://ADDR:PORT for RTP", number_of_values = 1)]
+    /// Can be repeated, the destinations are served for the whole process lifetime
+    udp_out: Vec<UdpTarget>,
+
+    #[structopt(long = "udp-packets", help = "Set the number of TS packets per UDP datagram", default_value = "7")]
+    udp_packets: usize,
+
+    #[structopt(long = "ttl", help = "Set the ttl of the multicast UDP outputs")]
+    ttl: Option<u32>,
+
+    #[structopt(long = "rtp-out", help = "Wrap all the UDP outputs in RTP")]
+    rtp_out: bool,
+
+    #[structopt(long = "rtp-ssrc", help = "Set the RTP SSRC, random by default")]
+    rtp_ssrc: Option<u32>,
+
+    #[structopt(long = "rtp-pt", help = "Set the RTP payload type, 33 (MP2T) by default")]
+    rtp_pt: Option<u8>,
+
+    #[structopt(long = "pace-pcr", help = "Pace the UDP outputs on the PCR of the stream")]
+    /// Smooths out the input bursts, sent unpaced if the stream carries no PCR
+    pace_pcr: bool,
+
+    #[structopt(long = "pace-depth", parse(try_from_str = parse_secs), help = "Seconds the paced UDP datagrams can be held back", default_value = "0.1")]
+    /// Absorbs the input jitter, adding as much latency
+    pace_depth: Duration,
+
+    #[structopt(long = "cbr", parse(try_from_str = parse_bitrate), help = "Pad the UDP outputs and push targets to this constant bitrate with null packets, e.g. 8M")]
+    /// Per target, append ?cbr=BITRATE to the --udp-out or tcp:// --push url instead. The PCRs are kept as they are
+    cbr: Option<u64>,
+
+    #[structopt(long = "udp-out-dscp", parse(try_from_str = parse_dscp), help = "Mark the UDP output datagrams with this DSCP")]
+    udp_out_dscp: Option<u8>,
+
+    #[structopt(long = "simulate", help = "Testing only: impair the TCP consumer links, e.g. loss=1%,delay=50ms,jitter=20ms")]
+    /// Chunks are dropped at random and the others held back, in order
+    simulate: Option<Simulate>,
+
+    #[structopt(long = "simulate-seed", help = "Seed the random draws of --simulate, to replay a run")]
+    simulate_seed: Option<u64>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
-use std::fmt;
+#[derive(StructOpt, Clone, Debug)]
+enum Command {
+    #[structopt(name = "probe", about = "Read a stream for a few seconds, print what it carries and exit")]
+    /// Exits with 2 if the stream has problems, 1 if it cannot be read at all
+    Probe {
+        #[structopt(help = "The stream to read: tcp://HOST:PORT, udp://ADDR:PORT, file://PATH or unix:PATH")]
+        /// TCP and Unix streams are connected to as a consumer, UDP ones received, joining the group if multicast
+        source: Input,
+
+        #[structopt(long = "duration", parse(try_from_str = parse_secs), help = "Seconds to read the stream for", default_value = "5")]
+        /// Of stream time for a file, as told by its PCR
+        duration: Duration,
+    },
+
+    #[structopt(name = "record", about = "Record a stream to a file and exit")]
+    /// Exits with 2 if the stream ends before the duration, 1 if it cannot be reached
+    Record {
+        #[structopt(help = "The stream to record: tcp://HOST:PORT")]
+        /// Connected to once, e.g. the consumer port of a restreamer
+        source: String,
+
+        #[structopt(parse(from_os_str), help = "The file to write, truncated if it exists")]
+        output: PathBuf,
+
+        #[structopt(long = "duration", parse(try_from_str = parse_secs), help = "Seconds to record for, until the stream ends if unset")]
+        duration: Option<Duration>,
+    },
+
+    #[structopt(name = "play", about = "Send a file to a peer once and exit")]
+    /// Exits with 2 if the peer closes the connection before the end of the file, 1 if it cannot be reached
+    Play {
+        #[structopt(parse(from_os_str), help = "The file to send")]
+        input: PathBuf,
+
+        #[structopt(help = "Where to send it: tcp://HOST:PORT")]
+        /// E.g. the producer port of a restreamer
+        target: String,
+
+        #[structopt(long = "realtime", help = "Send the file at the pace of its PCR rather than as fast as possible")]
+        realtime: bool,
+    },
+}
 
-impl fmt::Display for Peer {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let name = if self.kind.is_producer() {
-            "Producer"
-        } else {
-            "Consumer"
-        };
-        write!(f, "{} ({:?})", name, self.addr)
+/// Runtime tunable settings, sharing `bandwidth` and `simulator` across the reloads
+fn settings(cfg: &Config, bandwidth: &Option<Arc<Bandwidth>>, simulator: &Option<Arc<Simulator>>) -> Settings {
+    Settings {
+        consumer_queue: cfg.consumer_queue,
+        overflow: cfg.overflow_policy,
+        max_lag_bytes: cfg.max_lag_bytes,
+        max_lag: cfg.max_lag_secs,
+        idle_timeout: cfg.consumer_idle_timeout,
+        producer_acl: Acl {
+            allow: cfg.allow_producer.clone(),
+            deny: cfg.deny_producer.clone(),
+        },
+        consumer_acl: Acl {
+            allow: cfg.allow_consumer.clone(),
+            deny: cfg.deny_consumer.clone(),
+        },
+        priorities: cfg.consumer_priority.clone(),
+        max_consumers: cfg.max_consumers,
+        nodelay: !cfg.no_nodelay,
+        keepalive: cfg.tcp_keepalive,
+        max_read_buffer: cfg.max_read_buffer as usize,
+        read_pool: cfg.read_pool,
+        write_batch: cfg.write_batch as usize,
+        consumer_options: cfg.consumer_options,
+        relay_resume: cfg.relay_resume,
+        reconnect_grace: cfg.reconnect_grace,
+        rate_limit: cfg.consumer_rate_limit,
+        bandwidth: bandwidth.clone(),
+        proxy_protocol: cfg.proxy_protocol,
+        accept_rate: cfg.accept_rate,
+        validate_input: if cfg.validate_input { Some(cfg.sync_loss_budget) } else { None },
+        packet_size: cfg.packet_size,
+        clean_start: if cfg.clean_start { Some(cfg.clean_start_timeout) } else { None },
+        dscp: cfg.dscp,
+        thresholds: Thresholds {
+            pat_interval: cfg.pat_interval,
+            pmt_interval: cfg.pmt_interval,
+            pid_timeout: cfg.pid_timeout,
+        },
+        encrypt: cfg.encrypt_key.map(|key| Aes::new(key, cfg.encrypt_iv)),
+        decrypt: cfg.decrypt_key.map(|key| Aes::new(key, cfg.decrypt_iv)),
+        simulate: simulator.clone(),
     }
 }
 
-impl TSPacket {
-    fn new(socket: TcpStream, buffer_size: usize) -> Self {
-        TSPacket {
-            buffer_size,
-            socket,
-            rd: BytesMut::new(),
-            wr: BytesMut::new(),
+/// Re-read the configuration file, applying what can change without a restart
+fn reload(matches: &ArgMatches, running: &Config, bandwidth: &Option<Arc<Bandwidth>>, simulator: &Option<Arc<Simulator>>,
+          restreamers: &[Restreamer]) {
+    let path = match running.config {
+        Some(ref path) => path,
+        None => {
+            warn!("Nothing to reload, no configuration file given");
+            return;
+        }
+    };
+
+    let mut cfg = Config::from_clap(matches);
+    match config::load(path) {
+        Ok(file) => file.merge(&mut cfg, matches),
+        Err(e) => {
+            error!("{}, keeping the current settings", e);
+            return;
         }
     }
 
-    /// Buffer a packet.
-    fn buffer(&mut self, line: &[u8]) {
-        self.wr.reserve(self.buffer_size * 4);
-        self.wr.put(line);
+    for key in config::restart_required(running, &cfg) {
+        warn!("Ignoring the new {}, requires restart", key);
     }
 
-    /// Flush the write buffer to the socket
-    fn poll_flush(&mut self) -> Poll<bool, io::Error> {
-        if let Async::Ready(val) = self.socket.poll_write_ready()? {
-            if UnixReady::from(val).is_hup() {
-                return Ok(Async::Ready(false));
-            }
-        }
-        while !self.wr.is_empty() {
-            let n = try_nb!(self.socket.write(&self.wr));
+    for restreamer in restreamers {
+        restreamer.update_settings(settings(&cfg, bandwidth, simulator));
+    }
 
-            assert!(n > 0);
+    info!("Reloaded {}", path.display());
+}
 
-            let _ = self.wr.split_to(n);
+/// Print what `source` carries and exit, with a status telling whether it is healthy
+fn probe(source: &Input, duration: Duration) -> ! {
+    match restream::probe(source, duration) {
+        Ok(report) => {
+            println!("{}", report);
+            process::exit(if report.healthy() { 0 } else { 2 });
+        }
+        Err(e) => {
+            error!("Cannot probe {}: {}", source, e);
+            process::exit(1);
         }
-
-        Ok(Async::Ready(true))
     }
+}
 
-    fn fill_read_buf(&mut self) -> Poll<(), io::Error> {
-        loop {
-            self.rd.reserve(self.buffer_size * 4);
-            let n = try_ready!(self.socket.read_buf(&mut self.rd));
-            if n == 0 {
-                return Ok(Async::Ready(()));
+/// Exit with a status telling how the copy ended
+fn copied(what: &str, res: io::Result<Copied>) -> ! {
+    match res {
+        Ok(copied) => {
+            match copied.ending {
+                Ending::Complete => info!("{} done, {} bytes", what, copied.bytes),
+                Ending::Stopped => info!("{} stopped, {} bytes", what, copied.bytes),
+                Ending::Early => warn!("{} ended early, the peer closed the connection after {} bytes", what, copied.bytes),
             }
+            process::exit(if copied.ending == Ending::Early { 2 } else { 0 });
+        }
+        Err(e) => {
+            error!("{} failed: {}", what, e);
+            process::exit(1);
         }
     }
 }
 
-impl Stream for TSPacket {
-    type Item = BytesMut;
-    type Error = io::Error;
-
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let sock_closed = self.fill_read_buf()?.is_ready();
+/// Run a one-shot copy, stopped by the first Ctrl-C, SIGINT or SIGTERM
+fn copy<F, C>(what: &str, start: F) -> !
+where
+    F: FnOnce(Pin<Box<dyn Future<Output = ()> + Send>>) -> io::Result<C>,
+    C: Future<Output = io::Result<Copied>>,
+{
+    let rt = Runtime::new().unwrap();
+    // Resolving only once a signal is received
+    let stop = async {
+        if let Err(e) = shutdown_signal().await {
+            error!("Cannot wait for signals: {}", e);
+            future::pending::<()>().await;
+        }
+    };
 
-        if self.rd.len() > self.buffer_size {
-            let pkt = self.rd.split_to(self.buffer_size);
+    let res = start(Box::pin(stop)).and_then(|copy| rt.block_on(copy));
+    copied(what, res)
+}
 
-            return Ok(Async::Ready(Some(pkt)));
+/// Resolve to the first address, exiting if there is none
+fn resolve<A: ToSocketAddrs>(addr: A, name: &str) -> SocketAddr {
+    match addr.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => addr,
+        Ok(None) => {
+            error!("Cannot resolve {}: no address found", name);
+            process::exit(1);
         }
-
-        if sock_closed {
-            Ok(Async::Ready(None))
-        } else {
-            Ok(Async::NotReady)
+        Err(e) => {
+            error!("Cannot resolve {}: {}", name, e);
+            process::exit(1);
         }
     }
 }
 
-fn setup(socket: TcpStream, state: Arc<Mutex<Shared>>, kind: Kind, buffer_size: usize) {
-    let packets = TSPacket::new(socket, buffer_size);
-
-    let cons = Peer::new(state, packets, kind);
+/// Resolve a tcp://HOST:PORT url
+fn tcp_addr(url: &str, what: &str) -> SocketAddr {
+    if !url.starts_with("tcp://") {
+        error!("Invalid {} {}, expected tcp://HOST:PORT", what, url);
+        process::exit(1);
+    }
 
-    eprintln!("Adding {}", cons);
+    resolve(&url["tcp://".len()..], url)
+}
 
-    tokio::spawn(cons.map_err(|e| println!("FAIL {:?}", e)));
+/// Take the constant bitrate and the local end out of a --push url
+fn split_push(url: &str) -> Result<(String, LocalBind, Option<u64>), String> {
+    let (url, cbr) = split_cbr(url)?;
+    let (target, bind) = LocalBind::split(&url)?;
+    Ok((target.to_owned(), bind, cbr))
 }
 
-fn setup_producer(socket: TcpStream, state: Arc<Mutex<Shared>>, buffer_size: usize) -> OneShotSharedRx {
-    let (tx, rx) = oneshot::channel::<()>();
+/// Whether any output is padded to a constant bitrate
+fn cbr_wanted(cfg: &Config) -> bool {
+    cfg.cbr.is_some() || cfg.udp_out.iter().any(|target| target.cbr.is_some()) || cfg.push.iter().any(|url| split_cbr(url).is_ok_and(|(_, cbr)| cbr.is_some()))
+}
 
-    setup(socket, state, Kind::Producer(tx), buffer_size);
+/// The same address, `by` ports further
+fn shift(addr: SocketAddr, by: u16) -> SocketAddr {
+    SocketAddr::new(addr.ip(), addr.port() + by)
+}
 
-    rx.shared()
+/// Resolve on the first SIGINT or SIGTERM
+#[cfg(unix)]
+async fn shutdown_signal() -> io::Result<()> {
+    let mut int = signal(SignalKind::interrupt())?;
+    let mut term = signal(SignalKind::terminate())?;
+
+    let sig = tokio::select! {
+        _ = int.recv() => "SIGINT",
+        _ = term.recv() => "SIGTERM",
+    };
+    info!("Received signal {}, shutting down", sig);
+    Ok(())
 }
 
-fn setup_consumer(socket: TcpStream, state: Arc<Mutex<Shared>>, rx: OneShotSharedRx, buffer_size: usize) {
-    let rx = rx.into_stream();
-    setup(socket, state, Kind::Consumer(rx), buffer_size);
+/// Resolve on the first Ctrl-C
+#[cfg(not(unix))]
+async fn shutdown_signal() -> io::Result<()> {
+    tokio::signal::ctrl_c().await?;
+    info!("Received Ctrl-C, shutting down");
+    Ok(())
 }
 
-use std::net::IpAddr;
+/// Every SIGHUP
+#[cfg(unix)]
+fn reload_signals() -> io::Result<impl Stream<Item = ()> + Unpin> {
+    let mut hup = signal(SignalKind::hangup())?;
+    Ok(stream::poll_fn(move |cx| hup.poll_recv(cx)))
+}
 
-#[derive(StructOpt, Debug)]
-#[structopt()]
-struct Config {
-    #[structopt(short = "p", long = "port", help = "Set listening ports", default_value = "12345")]
-    /// Set the listening ports, consumer ports is ${producer port +1}
-    port: u16,
-    #[structopt(short = "I", help = "Set the input host", default_value = "127.0.0.1")]
-    /// Set the producer host
-    input_host: IpAddr,
+/// Nothing to wait for, there is no SIGHUP
+#[cfg(not(unix))]
+fn reload_signals() -> io::Result<impl Stream<Item = ()> + Unpin> {
+    Ok(stream::empty())
+}
 
-    #[structopt(short = "O", help = "Set the output host", default_value = "127.0.0.1")]
-    /// Set the producer host
-    output_host: IpAddr,
+/// Every SIGUSR1
+#[cfg(unix)]
+fn reopen_signals() -> io::Result<impl Stream<Item = ()> + Unpin> {
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    Ok(stream::poll_fn(move |cx| usr1.poll_recv(cx)))
+}
 
-    #[structopt(short = "b", help = "Set the packet buffer size", default_value = "1316")]
-    buffer: usize,
+/// Nothing to wait for, there is no SIGUSR1
+#[cfg(not(unix))]
+fn reopen_signals() -> io::Result<impl Stream<Item = ()> + Unpin> {
+    Ok(stream::empty())
 }
 
 pub fn main() {
-    pretty_env_logger::init().unwrap();
+    let matches = Config::clap().get_matches();
+    let mut cfg = Config::from_clap(&matches);
 
-    let state = Arc::new(Mutex::new(Shared::new()));
-    let mut rt = Runtime::new().unwrap();
+    if let Some(Command::Probe { ref source, duration }) = cfg.command {
+        logging::init(cfg.log_format);
+        probe(source, duration);
+    }
+    if let Some(Command::Record { ref source, ref output, duration }) = cfg.command {
+        logging::init(cfg.log_format);
+        let addr = tcp_addr(source, "source");
+        copy("Recording", |stop| restream::record(addr, output, duration, stop));
+    }
+    if let Some(Command::Play { ref input, ref target, realtime }) = cfg.command {
+        logging::init(cfg.log_format);
+        let addr = tcp_addr(target, "target");
+        copy("Playback", |stop| restream::play(input, addr, realtime, stop));
+    }
 
-    let prod_state = state.clone();
+    if let Some(path) = cfg.config.clone() {
+        match config::load(&path) {
+            Ok(file) => file.merge(&mut cfg, &matches),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
 
-    let cfg = Config::from_args();
+    logging::init(cfg.log_format);
 
-    let l_prod = TcpListener::bind(&(cfg.input_host, cfg.port).into()).unwrap();
+    let input_addr = resolve((cfg.input_host.as_str(), cfg.port), &cfg.input_host);
+    let input = match (cfg.play.clone(), cfg.pull.as_ref(), cfg.input.clone()) {
+        _ if cfg.generate_only => match cfg.generate {
+            Some(bitrate) => Input::Generate(bitrate),
+            None => {
+                error!("--generate-only requires --generate");
+                process::exit(1);
+            }
+        },
+        (Some(path), _, _) if !cfg.slate => Input::File(path),
+        (_, Some(url), _) => Input::Pull(tcp_addr(url, "pull source")),
+        (_, None, Some(input)) => input,
+        (_, None, None) if cfg.stdin => Input::Stdin,
+        (_, None, None) if cfg.udp_input => Input::Udp(input_addr),
+        (_, None, None) => Input::Tcp(input_addr),
+    };
+    let slate = if cfg.slate { cfg.play.clone() } else { None };
+    // A backup is never waited for, a TCP one is pulled
+    let input_backup = match cfg.input_backup.clone() {
+        Some(Input::Tcp(addr)) => Some(Input::Pull(addr)),
+        backup => backup,
+    };
+
+    let pid_filter = match (cfg.keep_pid.is_empty(), cfg.drop_pid.is_empty()) {
+        (true, true) => None,
+        (true, false) => Some(PidFilter::drop(&cfg.drop_pid)),
+        (false, true) => Some(PidFilter::keep(&cfg.keep_pid)),
+        (false, false) => {
+            error!("Cannot both keep and drop PIDs");
+            process::exit(1);
+        }
+    };
+
+    let remap = match PidRemap::new(&cfg.remap_pid) {
+        Ok(_) if cfg.remap_pid.is_empty() => None,
+        Ok(remap) => Some(remap),
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
 
-    let buffer_size = cfg.buffer;
+    let webhook = cfg.webhook.as_ref().map(|url| match Webhook::new(url) {
+        Ok(webhook) => webhook,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    });
+
+    let tls = match (cfg.tls_cert.as_ref(), cfg.tls_key.as_ref()) {
+        (Some(cert), Some(key)) => match Tls::from_pem(cert, key) {
+            Ok(tls) => Some(tls),
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        },
+        (None, None) => None,
+        _ => {
+            error!("--tls-cert and --tls-key go together");
+            process::exit(1);
+        }
+    };
+
+    let producer_tls = match (cfg.producer_tls_cert.as_ref(), cfg.producer_tls_key.as_ref(), cfg.producer_tls_ca.as_ref()) {
+        (Some(cert), Some(key), Some(ca)) => match ProducerTls::from_pem(cert, key, ca) {
+            Ok(tls) => Some(tls),
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        },
+        (None, None, None) => None,
+        _ => {
+            error!("--producer-tls-cert, --producer-tls-key and --producer-tls-ca go together");
+            process::exit(1);
+        }
+    };
 
-    let srv_prod = l_prod
-        .incoming()
-        .sleep_on_error(Duration::from_millis(100))
-        .map(move |socket| {
-            let rx = setup_producer(socket, prod_state.clone(), buffer_size.clone());
+    // Only whole packets can be padded
+    if cbr_wanted(&cfg) && cfg.no_align {
+        error!("--cbr requires the chunks aligned, without --no-align");
+        process::exit(1);
+    }
 
-            let l_cons = TcpListener::bind(&(cfg.output_host, cfg.port + 1).into()).unwrap();
-            let cons_state = state.clone();
-            let cons_rx = rx.clone();
+    if cfg.keepalive_nulls == Some(Duration::ZERO) {
+        error!("--keepalive-nulls must be a positive number of seconds");
+        process::exit(1);
+    }
 
-            let srv_cons = l_cons
-                .incoming()
-                .sleep_on_error(Duration::from_millis(100))
-                .map(move |socket| {
-                    setup_consumer(socket, cons_state.clone(), cons_rx.clone(), buffer_size.clone());
+    // Only the chunks in sync are encrypted
+    if (cfg.encrypt_key.is_some() || cfg.decrypt_key.is_some()) && cfg.no_align {
+        error!("--encrypt-key and --decrypt-key require the chunks aligned, without --no-align");
+        process::exit(1);
+    }
 
-                    Ok(())
-                })
-                .listen(1000)
-                .select(rx.clone().into_future().map(|_| ()).map_err(|_| ()));
+    if cfg.channels == 0 {
+        error!("At least one channel is needed");
+        process::exit(1);
+    }
 
-            tokio::spawn(srv_cons.map(|_| ()).map_err(|_| ()));
+    if cfg.channels > 1 {
+        let listening = matches!(input, Input::Tcp(_) | Input::Udp(_)) && !matches!(cfg.output, Some(Output::Unix(_)));
+        if !listening || !cfg.udp_out.is_empty() || !cfg.push.is_empty() || cfg.record.is_some() || cfg.stdout || cfg.sink.is_some() || cfg.hls_dir.is_some() {
+            error!("Multiple channels only listen on ports, without --pull, --play, unix sockets, --push, --udp-out, --record, --stdout, --sink or --hls-dir");
+            process::exit(1);
+        }
+    }
+
+    #[cfg(unix)]
+    let activation = match Activation::from_env() {
+        Ok(Some(_)) if cfg.channels > 1 => {
+            error!("Socket activation requires a single channel");
+            process::exit(1);
+        }
+        Ok(activation) => {
+            if let Some(ref activation) = activation {
+                let addrs: Vec<_> = activation.producer.iter().chain(&activation.consumers).filter_map(|l| l.local_addr().ok()).collect();
+                info!("Listening on the sockets passed by systemd {:?}", addrs);
+            }
+            activation
+        }
+        Err(e) => {
+            error!("Cannot take the sockets passed by systemd: {}", e);
+            process::exit(1);
+        }
+    };
 
-            Ok(())
+    if (cfg.user.is_some() || cfg.group.is_some()) && cfg.channels > 1 {
+        error!("--user and --group require a single channel");
+        process::exit(1);
+    }
+    #[cfg(not(unix))]
+    if cfg.user.is_some() || cfg.group.is_some() {
+        error!("--user and --group require Unix");
+        process::exit(1);
+    }
+
+    // Every channel takes the next pair of ports
+    let last = 2 * u32::from(cfg.channels - 1) + 1;
+    let highest = [Some(cfg.port), cfg.ws_port, cfg.metrics_port, cfg.status_port, cfg.hls_port].iter().filter_map(|&port| port).max().unwrap_or(0);
+    if u32::from(highest) + last > u32::from(u16::MAX) {
+        error!("Not enough ports above {} for {} channels", highest, cfg.channels);
+        process::exit(1);
+    }
+
+    let output = match cfg.output {
+        Some(ref output) => output.clone(),
+        None => Output::Tcp(resolve((cfg.output_host.as_str(), cfg.port + 1), &cfg.output_host)),
+    };
+    let metrics_addr = cfg.metrics_port.map(|port| resolve((cfg.output_host.as_str(), port), &cfg.output_host));
+    let status_addr = cfg.status_port.map(|port| resolve((cfg.output_host.as_str(), port), &cfg.output_host));
+    let ws_addr = cfg.ws_port.map(|port| resolve((cfg.output_host.as_str(), port), &cfg.output_host));
+    let hls_addr = cfg.hls_port.map(|port| resolve((cfg.output_host.as_str(), port), &cfg.output_host));
+
+    // A single one for all the channels
+    let bandwidth = cfg.total_rate_limit.map(|bitrate| Arc::new(Bandwidth::new(bitrate)));
+    let simulator = cfg.simulate.clone().map(|simulate| Arc::new(Simulator::new(simulate, cfg.simulate_seed)));
+
+    let builder = cfg.udp_out.iter().cloned().fold(Restreamer::builder(), |b, target| b.udp_output(target));
+    let builder = cfg.webhook_threshold.iter().fold(builder, |b, &count| b.webhook_threshold(count));
+    let builder = cfg.push.iter().fold(builder, |b, url| {
+            let (target, bind, cbr) = split_push(url).unwrap_or_else(|e| {
+                error!("{}", e);
+                process::exit(1);
+            });
+            if bind.is_set() && !target.starts_with("tcp://") {
+                error!("Invalid push target {}, only the tcp:// ones take a local address or device", url);
+                process::exit(1);
+            }
+            if cbr.is_some() && !target.starts_with("tcp://") {
+                error!("Invalid push target {}, only the tcp:// ones take a bitrate, use --cbr for the others", url);
+                process::exit(1);
+            }
+
+            if let Some(addr) = url.strip_prefix("srt://") {
+                b.push_srt(resolve(addr, url))
+            } else if let Some(addr) = url.strip_prefix("rist://") {
+                b.push_rist(resolve(addr, url))
+            } else {
+                b.push_target(tcp_addr(&target, "push target"), bind, cbr)
+            }
         })
-        .listen(1);
+        .buffer_size(cfg.buffer)
+        .align(!cfg.no_align)
+        .http(cfg.http_out)
+        .socket_mode(cfg.socket_mode)
+        .backlog(cfg.backlog)
+        .bind_retry(cfg.bind_retry)
+        .reuseport(cfg.reuseport)
+        .local_bind(LocalBind {
+            addr: cfg.local_addr,
+            device: cfg.bind_device.clone(),
+        })
+        .tls(tls)
+        .producer_takeover(cfg.producer_takeover)
+        .producer_token(cfg.producer_token.clone())
+        .producer_tls(producer_tls)
+        .stream_keys(cfg.stream_keys)
+        .wait_for_producer(cfg.wait_for_producer)
+        .settings(settings(&cfg, &bandwidth, &simulator))
+        .burst(cfg.burst)
+        .delay(cfg.delay.map(|delay| BroadcastDelay {
+            delay,
+            memory: cfg.delay_memory as usize,
+            pad: cfg.pad_during_prime,
+        }))
+        .dvr(cfg.dvr_window)
+        .dvr_max_bytes(cfg.dvr_max_bytes as usize)
+        .pid_filter(pid_filter)
+        .program(cfg.program)
+        .remap_pids(remap)
+        .strip_nulls(cfg.strip_nulls)
+        .udp_timeout(cfg.udp_timeout)
+        .rtp_input(cfg.rtp_in)
+        .input_iface(cfg.input_iface.clone())
+        .merge_window(cfg.merge_window)
+        .failover_timeout(cfg.failover_timeout)
+        .failback_delay(cfg.failback_delay)
+        .pull_timeout(cfg.pull_timeout)
+        .play_bitrate(cfg.play_bitrate)
+        .slate(slate)
+        .generate(if cfg.generate_only { None } else { cfg.generate })
+        .udp_packets(cfg.udp_packets)
+        .multicast_ttl(cfg.ttl)
+        .rtp_output(cfg.rtp_out)
+        .rtp_ssrc(cfg.rtp_ssrc)
+        .pace_pcr(if cfg.pace_pcr { Some(cfg.pace_depth) } else { None })
+        .udp_output_dscp(cfg.udp_out_dscp)
+        .cbr(cfg.cbr)
+        .rist_buffer(Duration::from_millis(cfg.rist_buffer))
+        .push_dscp(cfg.push_dscp)
+        .srt(SrtOptions {
+            latency: cfg.srt_latency.map(Duration::from_millis),
+            passphrase: cfg.srt_passphrase.clone(),
+        })
+        .push_backoff(Backoff {
+            initial: cfg.push_retry_min,
+            max: cfg.push_retry_max,
+        })
+        .producer_stall_timeout(cfg.producer_stall_timeout)
+        .disconnect_on_stall(cfg.disconnect_consumers_on_stall)
+        .keepalive_nulls(cfg.keepalive_nulls)
+        .record(cfg.record.clone().map(|dir| Record {
+            dir,
+            max_size: cfg.record_max_size,
+            duration: cfg.record_duration,
+            fsync: cfg.record_fsync,
+            splice: cfg.record_splice,
+        }))
+        .sink(if cfg.stdout { Some(Sink::Stdout) } else { cfg.sink.clone().map(Sink::File) })
+        .hls(if cfg.hls || cfg.hls_dir.is_some() {
+            Some(Hls {
+                dir: cfg.hls_dir.clone(),
+                segment_duration: cfg.hls_segment_duration,
+                window: cfg.hls_window,
+            })
+        } else {
+            None
+        })
+        .webhook(webhook);
+
+    let builder = match cfg.rtp_pt {
+        Some(pt) => builder.rtp_payload_type(pt),
+        None => builder,
+    };
+
+    #[cfg(unix)]
+    let builder = match activation {
+        Some(activation) => builder.inherited(activation.producer, activation.consumers),
+        None => builder,
+    };
+    #[cfg(unix)]
+    let builder = match (cfg.user.as_deref(), cfg.group.as_deref()) {
+        (None, None) => builder,
+        (user, group) => match Account::lookup(user, group) {
+            Ok(account) => builder.account(Some(account)),
+            Err(e) => {
+                error!("Cannot switch the account: {}", e);
+                process::exit(1);
+            }
+        },
+    };
+
+    let rt = Runtime::new().unwrap();
+
+    let restreamers: Vec<_> = (0..cfg.channels).map(|channel| {
+        let by = 2 * channel;
+        let input = match input {
+            Input::Tcp(addr) => Input::Tcp(shift(addr, by)),
+            Input::Udp(addr) => Input::Udp(shift(addr, by)),
+            ref input => input.clone(),
+        };
+        let output = match output {
+            Output::Tcp(addr) => Output::Tcp(shift(addr, by)),
+            ref output => output.clone(),
+        };
+        let control_socket = match cfg.control_socket {
+            Some(ref path) if channel > 0 => Some(PathBuf::from(format!("{}.{}", path.display(), channel))),
+            ref path => path.clone(),
+        };
+        let access_log = match cfg.access_log {
+            Some(ref path) if channel > 0 => Some(PathBuf::from(format!("{}.{}", path.display(), channel))),
+            ref path => path.clone(),
+        };
+
+        let builder = builder.clone()
+            .input(input)
+            .input_backup(match input_backup {
+                Some(Input::Udp(addr)) => Some(Input::Udp(shift(addr, by))),
+                ref backup => backup.clone(),
+            })
+            .output(output)
+            .ws_listener(ws_addr.map(|addr| shift(addr, by)))
+            .metrics(metrics_addr.map(|addr| shift(addr, by)))
+            .status(status_addr.map(|addr| shift(addr, by)))
+            .health_max_idle(cfg.health_max_idle)
+            .hls_port(hls_addr.map(|addr| shift(addr, by)))
+            .control_socket(control_socket)
+            .access_log(access_log.map(|path| AccessLog { path, format: cfg.access_log_format }))
+            .channel(if cfg.channels > 1 { Some(channel.into()) } else { None });
+
+        match builder.spawn(&rt) {
+            Ok(restreamer) => restreamer,
+            Err(e) if cfg.channels > 1 => {
+                error!("Cannot start channel {}: {}", channel, e);
+                process::exit(1);
+            }
+            Err(e) => {
+                error!("Cannot start: {}", e);
+                process::exit(1);
+            }
+        }
+    }).collect();
 
-    rt.spawn(srv_prod);
+    #[cfg(unix)]
+    if let Err(e) = sd_notify("READY=1") {
+        warn!("Cannot notify systemd: {}", e);
+    }
+
+    {
+        let restreamers = restreamers.clone();
+        let running = cfg.clone();
+
+        rt.spawn(async move {
+            let mut reloads = match reload_signals() {
+                Ok(reloads) => reloads,
+                Err(e) => return error!("Cannot wait for SIGHUP: {}", e),
+            };
+            while reloads.next().await.is_some() {
+                reload(&matches, &running, &bandwidth, &simulator, &restreamers);
+            }
+        });
+    }
+
+    if cfg.access_log.is_some() {
+        let restreamers = restreamers.clone();
+
+        rt.spawn(async move {
+            let mut reopens = match reopen_signals() {
+                Ok(reopens) => reopens,
+                Err(e) => return error!("Cannot wait for SIGUSR1: {}", e),
+            };
+            while reopens.next().await.is_some() {
+                info!("Reopening the access log");
+                for restreamer in &restreamers {
+                    restreamer.reopen_access_log();
+                }
+            }
+        });
+    }
+
+    let stdin_closed = if cfg.exit_on_stdin_eof {
+        Either::Left(restreamers[0].stdin_closed().map(|()| info!("End of the standard input, exiting")))
+    } else {
+        Either::Right(future::pending())
+    };
+    let signal = async {
+        if let Err(e) = shutdown_signal().await {
+            error!("Cannot wait for signals: {}", e);
+        }
+    };
+
+    rt.block_on(async {
+        tokio::select! {
+            _ = signal => (),
+            _ = stdin_closed => (),
+        }
+    });
+
+    let stopped = future::join_all(restreamers.iter().map(|r| r.stop()).collect::<Vec<_>>());
+    // The timer needs the runtime, it is created once polled
+    let stopped = async { time::timeout(cfg.shutdown_timeout, stopped).await };
+
+    if rt.block_on(stopped).is_err() {
+        warn!("Consumers still flushing after {:.1}s, closing them", cfg.shutdown_timeout.as_secs_f64());
+    }
 
-    rt.shutdown_on_idle().wait().unwrap();
+    rt.shutdown_background();
 }
@@ -0,0 +1,186 @@
+//! Seamless merge of a producer received over two paths (SMPTE 2022-7)
+//!
+//! Both paths carry the same RTP packets, with the same sequence numbers. The
+//! first copy of each packet goes out, in order, the other is dropped. A packet
+//! missing on one path waits for the other one, for as long as `window`
+//! packets follow it.
+
+use bytes::Bytes;
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use crate::rtp::RtpReceiver;
+use crate::stats::LegStats;
+
+/// Reorder the packets of the legs by sequence number
+pub struct Merger {
+    window: usize,
+    legs: Vec<Arc<LegStats>>,
+    /// Loss accounting of each leg
+    receivers: Vec<RtpReceiver>,
+    /// Sequence number of the first slot
+    next: Option<u16>,
+    /// The packets following the last one sent, with the leg they came by
+    pending: VecDeque<Option<(usize, Bytes)>>,
+    /// Packets missing on every leg
+    pub lost: u64,
+}
+
+impl Merger {
+    pub fn new(window: usize, legs: Vec<Arc<LegStats>>) -> Self {
+        Merger {
+            window: window.max(1),
+            receivers: legs.iter().map(|_| RtpReceiver::default()).collect(),
+            legs,
+            next: None,
+            pending: VecDeque::new(),
+            lost: 0,
+        }
+    }
+
+    /// Take the datagram received on `leg`, dropping it unless it is RTP
+    pub fn receive(&mut self, leg: usize, datagram: &[u8], out: &mut Vec<Bytes>) {
+        let receiver = &mut self.receivers[leg];
+        let (seq, payload) = match receiver.receive(datagram) {
+            Some(packet) => packet,
+            None => {
+                receiver.raw += 1;
+                return;
+            }
+        };
+        self.legs[leg].lost.store(receiver.lost, Ordering::Relaxed);
+
+        self.push(leg, seq, payload, out);
+    }
+
+    /// Take the packet `seq` received on `leg`, appending to `out` the packets now in order
+    pub fn push(&mut self, leg: usize, seq: u16, payload: &[u8], out: &mut Vec<Bytes>) {
+        self.legs[leg].packets.fetch_add(1, Ordering::Relaxed);
+
+        let next = *self.next.get_or_insert(seq);
+        let mut offset = seq.wrapping_sub(next);
+
+        // Sent already, or given up on
+        if offset >= 0x8000 {
+            return;
+        }
+
+        // Too far ahead, the packets missing a window before it are not coming anymore
+        while usize::from(offset) >= self.window && !self.pending.is_empty() {
+            self.release(out);
+            offset -= 1;
+        }
+        if usize::from(offset) >= self.window {
+            self.lost += u64::from(offset);
+            self.next = Some(seq);
+            offset = 0;
+        }
+
+        let offset = usize::from(offset);
+        if self.pending.len() <= offset {
+            self.pending.resize(offset + 1, None);
+        }
+        if self.pending[offset].is_none() {
+            self.pending[offset] = Some((leg, Bytes::copy_from_slice(payload)));
+        }
+
+        while let Some(&Some(_)) = self.pending.front() {
+            self.release(out);
+        }
+    }
+
+    /// Send the first slot, or count it lost
+    fn release(&mut self, out: &mut Vec<Bytes>) {
+        match self.pending.pop_front() {
+            Some(Some((leg, payload))) => {
+                self.legs[leg].used.fetch_add(1, Ordering::Relaxed);
+                out.push(payload);
+            }
+            Some(None) => self.lost += 1,
+            None => return,
+        }
+        self.next = self.next.map(|next| next.wrapping_add(1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merger(window: usize) -> (Merger, Vec<Arc<LegStats>>) {
+        let legs = vec![
+            Arc::new(LegStats::new("127.0.0.1:5000".parse().unwrap())),
+            Arc::new(LegStats::new("127.0.0.1:5002".parse().unwrap())),
+        ];
+        (Merger::new(window, legs.clone()), legs)
+    }
+
+    fn used(leg: &LegStats) -> u64 {
+        leg.used.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn each_packet_once() {
+        let (mut merger, legs) = merger(8);
+        let mut out = Vec::new();
+
+        // The second leg is 3 packets late and loses the 5th, the first loses the 16th
+        for i in 0..20u16 {
+            if i != 16 {
+                let seq = i.wrapping_add(65530);
+                merger.push(0, seq, &[seq as u8], &mut out);
+            }
+            if i >= 3 && i - 3 != 5 {
+                let seq = (i - 3).wrapping_add(65530);
+                merger.push(1, seq, &[seq as u8], &mut out);
+            }
+        }
+
+        let seqs: Vec<u8> = out.iter().map(|p| p[0]).collect();
+        let expected: Vec<u8> = (0..20u16).map(|seq| seq.wrapping_add(65530) as u8).collect();
+        assert_eq!(seqs, expected);
+        assert_eq!(merger.lost, 0);
+        assert_eq!((used(&legs[0]), used(&legs[1])), (19, 1));
+    }
+
+    #[test]
+    fn leg_dies() {
+        let (mut merger, legs) = merger(8);
+        let mut out = Vec::new();
+
+        for seq in 0..10 {
+            merger.push(0, seq, &[seq as u8], &mut out);
+            merger.push(1, seq, &[seq as u8], &mut out);
+        }
+        for seq in 10..20 {
+            merger.push(1, seq, &[seq as u8], &mut out);
+        }
+
+        assert_eq!(out.len(), 20);
+        assert_eq!((used(&legs[0]), used(&legs[1])), (10, 10));
+    }
+
+    #[test]
+    fn lost_on_both() {
+        let (mut merger, _) = merger(4);
+        let mut out = Vec::new();
+
+        for seq in (0..3).chain(5..8) {
+            merger.push(0, seq, &[seq as u8], &mut out);
+        }
+        // Held back until the window is over
+        assert_eq!(out.len(), 3);
+
+        for seq in 8..13 {
+            merger.push(0, seq, &[seq as u8], &mut out);
+        }
+        assert_eq!(out.iter().map(|p| p[0]).collect::<Vec<_>>(), vec![0, 1, 2, 5, 6, 7, 8, 9, 10, 11, 12]);
+        assert_eq!(merger.lost, 2);
+
+        // Too late now
+        merger.push(1, 3, &[3], &mut out);
+        assert_eq!(out.len(), 11);
+    }
+}
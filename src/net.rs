@@ -0,0 +1,603 @@
+//! TCP listeners, outgoing connections and peer addresses
+
+use socket2::{Domain, Protocol, SockRef, TcpKeepalive, Type};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+#[cfg(unix)]
+use tokio::net::unix::UCred;
+use tokio::time;
+use futures::prelude::*;
+use log::{debug, error, info, warn};
+
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::stats::LinkStats;
+
+/// How a peer is shown in the logs
+#[derive(Clone, Debug)]
+pub enum PeerName {
+    Inet(SocketAddr),
+    /// Connected to the Unix socket at `path`
+    #[cfg(unix)]
+    Unix { path: PathBuf, cred: Option<UCred> },
+    Stdin,
+}
+
+impl fmt::Display for PeerName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PeerName::Inet(ref addr) => write!(f, "{:?}", addr),
+            #[cfg(unix)]
+            PeerName::Unix { ref path, cred: Some(ref cred) } => write!(f, "{}, uid {}, gid {}", path.display(), cred.uid(), cred.gid()),
+            #[cfg(unix)]
+            PeerName::Unix { ref path, cred: None } => write!(f, "{}", path.display()),
+            PeerName::Stdin => f.write_str("stdin"),
+        }
+    }
+}
+
+/// Source of the placeholder addresses, 0.0.0.0:0 is the recorder
+static NEXT_PLACEHOLDER: AtomicU32 = AtomicU32::new(1);
+
+/// Connections the kernel queues on each listener until they are accepted
+static BACKLOG: AtomicU32 = AtomicU32::new(1024);
+
+/// Use `backlog` for the listeners bound from now on
+pub fn set_backlog(backlog: u32) {
+    BACKLOG.store(backlog, Ordering::Relaxed);
+}
+
+/// Pause of the accept loops after an error
+pub const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Time between the attempts to bind a busy port
+const BIND_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A unique address for the peers without one, e.g. 0.0.0.1:0
+pub fn placeholder_addr() -> SocketAddr {
+    SocketAddrV4::new(Ipv4Addr::from(NEXT_PLACEHOLDER.fetch_add(1, Ordering::Relaxed)), 0).into()
+}
+
+/// A connection to a peer, over TCP, TLS, a Unix socket or the standard input
+pub trait Socket: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    /// Address the peer is tracked by
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    fn peer_name(&self) -> io::Result<PeerName> {
+        self.peer_addr().map(PeerName::Inet)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()>;
+
+    /// Whether `poll_write_buf` sends several buffers in one write
+    fn vectored(&self) -> bool {
+        false
+    }
+
+    /// The TCP connection underneath, for the socket options
+    fn tcp(&self) -> Option<&TcpStream> {
+        None
+    }
+
+    /// The TCP connection the stream is read from as is, to splice it
+    fn plain_tcp(&mut self) -> Option<&mut TcpStream> {
+        None
+    }
+
+    /// Who the peer proved to be, if it did
+    fn identity(&self) -> Option<&str> {
+        None
+    }
+
+    /// Counters of the SRT connection underneath
+    fn link(&self) -> Option<Arc<LinkStats>> {
+        None
+    }
+}
+
+impl Socket for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        peer_addr(self)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        SockRef::from(self).shutdown(Shutdown::Write)
+    }
+
+    fn vectored(&self) -> bool {
+        true
+    }
+
+    fn tcp(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+
+    fn plain_tcp(&mut self) -> Option<&mut TcpStream> {
+        Some(self)
+    }
+}
+
+/// Enable SO_KEEPALIVE, probing the connection once it is idle for `idle`, or disable it
+pub fn set_keepalive(socket: &TcpStream, idle: Option<Duration>) -> io::Result<()> {
+    let socket = SockRef::from(socket);
+    match idle {
+        Some(idle) => socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle)),
+        None => socket.set_keepalive(false),
+    }
+}
+
+/// Whether the error means the peer went away, rather than a failure worth reporting
+pub fn is_disconnect(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted)
+}
+
+/// Delay between the connection attempts, doubled after every failure
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Local end of the outgoing sockets, for the hosts on several networks
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LocalBind {
+    /// Source address, picked by the routing table when unset
+    pub addr: Option<IpAddr>,
+    /// Interface the socket is tied to with SO_BINDTODEVICE, Linux only
+    pub device: Option<String>,
+}
+
+impl LocalBind {
+    /// Split the `?local=IP&device=NAME` query off a target url
+    pub fn split(url: &str) -> Result<(&str, LocalBind), String> {
+        let mut parts = url.splitn(2, '?');
+        let target = parts.next().unwrap_or("");
+        let mut bind = LocalBind::default();
+
+        for param in parts.next().into_iter().flat_map(|q| q.split('&')) {
+            if let Some(addr) = param.strip_prefix("local=") {
+                bind.addr = Some(addr.parse().map_err(|e| format!("Invalid local address {} in {}: {}", addr, url, e))?);
+            } else if let Some(device) = param.strip_prefix("device=").filter(|device| !device.is_empty()) {
+                bind.device = Some(device.to_owned());
+            } else {
+                return Err(format!("Unsupported parameter {} in {}, expected local=IP or device=NAME", param, url));
+            }
+        }
+
+        Ok((target, bind))
+    }
+
+    /// The options of a target, falling back on the `global` ones
+    pub fn or(&self, global: &LocalBind) -> LocalBind {
+        LocalBind {
+            addr: self.addr.or(global.addr),
+            device: self.device.clone().or_else(|| global.device.clone()),
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.addr.is_some() || self.device.is_some()
+    }
+
+    /// Where to bind a socket reaching `target`
+    fn local_addr(&self, target: &SocketAddr) -> SocketAddr {
+        match (self.addr, target) {
+            (Some(ip), _) => SocketAddr::new(ip, 0),
+            (None, SocketAddr::V4(_)) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            (None, SocketAddr::V6(_)) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        }
+    }
+
+    /// Name the local end and the `target` in a binding failure
+    pub fn error(&self, e: io::Error, target: &str) -> io::Error {
+        io::Error::new(e.kind(), format!("cannot bind {} to {}: {}", target, self, e))
+    }
+}
+
+impl fmt::Display for LocalBind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.addr, self.device.as_ref()) {
+            (Some(addr), Some(device)) => write!(f, "{} on device {}", addr, device),
+            (Some(addr), None) => write!(f, "{}", addr),
+            (None, Some(device)) => write!(f, "device {}", device),
+            (None, None) => f.write_str("any address"),
+        }
+    }
+}
+
+/// Parse a DSCP given as a number up to 63 or by name, such as `EF`, `AF41` or `CS5`
+pub fn parse_dscp(s: &str) -> Result<u8, String> {
+    let err = || format!("Invalid DSCP {}, use 0 to 63, EF, AFxy or CSx", s);
+    let name = s.to_ascii_uppercase();
+    let digit = |i: usize| name[i..].parse::<u8>().map_err(|_| err());
+
+    let dscp = match name.as_str() {
+        "EF" => 46,
+        "BE" | "DF" => 0,
+        "VA" => 44,
+        _ if name.starts_with("CS") => match digit(2)? {
+            class @ 0..=7 => class << 3,
+            _ => return Err(err()),
+        },
+        _ if name.starts_with("AF") && name.len() == 4 => match (digit(2)? / 10, digit(2)? % 10) {
+            (class @ 1..=4, drop @ 1..=3) => class << 3 | drop << 1,
+            _ => return Err(err()),
+        },
+        _ => s.parse().map_err(|_| err())?,
+    };
+
+    if dscp > 63 {
+        return Err(err());
+    }
+    Ok(dscp)
+}
+
+/// Mark the packets sent on the socket with `dscp`, returning the one the kernel kept
+#[cfg(unix)]
+pub fn set_dscp<S: AsRawFd>(socket: &S, v6: bool, dscp: u8) -> io::Result<u8> {
+    let (level, name) = if v6 {
+        (libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_TOS)
+    };
+    // The low bits are ECN, left to the kernel
+    let mut tos = libc::c_int::from(dscp) << 2;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    unsafe {
+        if libc::setsockopt(socket.as_raw_fd(), level, name, &tos as *const _ as *const libc::c_void, len) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::getsockopt(socket.as_raw_fd(), level, name, &mut tos as *mut _ as *mut libc::c_void, &mut len) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok((tos >> 2) as u8 & 0x3f)
+}
+
+#[cfg(not(unix))]
+pub fn set_dscp<S>(_: &S, _: bool, _: u8) -> io::Result<u8> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "DSCP marking requires Unix"))
+}
+
+/// Tie the socket to the interface `device`, whatever the routing table says
+#[cfg(target_os = "linux")]
+fn bind_device<S: AsRawFd>(socket: &S, device: &str) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_BINDTODEVICE,
+                         device.as_ptr() as *const libc::c_void, device.len() as libc::socklen_t)
+    };
+
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device<S>(_: &S, _: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "binding to a device requires Linux"))
+}
+
+/// A TCP socket bound according to `bind`, to be connected to `target`
+pub fn tcp_socket(target: &SocketAddr, bind: &LocalBind) -> io::Result<net::TcpStream> {
+    let socket = socket2::Socket::new(Domain::for_address(*target), Type::STREAM, Some(Protocol::TCP))?;
+
+    if let Some(ref device) = bind.device {
+        bind_device(&socket, device)?;
+    }
+    socket.bind(&bind.local_addr(target).into())?;
+    Ok(socket.into())
+}
+
+/// An UDP socket bound according to `bind`, to send to `target`
+pub fn udp_socket(target: &SocketAddr, bind: &LocalBind) -> io::Result<net::UdpSocket> {
+    let socket = socket2::Socket::new(Domain::for_address(*target), Type::DGRAM, Some(Protocol::UDP))?;
+
+    if let Some(ref device) = bind.device {
+        bind_device(&socket, device)?;
+    }
+    socket.bind(&bind.local_addr(target).into())?;
+    Ok(socket.into())
+}
+
+/// Bind a listener, the IPv6 unspecified address accepts the IPv4 clients as well
+fn bind(addr: &SocketAddr) -> io::Result<net::TcpListener> {
+    bind_with(addr, false)
+}
+
+/// Bind a listener, sharing the port with the others bound with `reuse_port`
+fn bind_with(addr: &SocketAddr, reuse_port: bool) -> io::Result<net::TcpListener> {
+    let socket = socket2::Socket::new(Domain::for_address(*addr), Type::STREAM, Some(Protocol::TCP))?;
+    if let SocketAddr::V6(ref v6) = *addr {
+        socket.set_only_v6(!v6.ip().is_unspecified())?;
+    }
+
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(reuse_port)?;
+    #[cfg(not(unix))]
+    let _ = reuse_port;
+    socket.bind(&(*addr).into())?;
+    socket.listen(BACKLOG.load(Ordering::Relaxed) as i32)?;
+    Ok(socket.into())
+}
+
+/// Bind a listener, naming the port if it fails
+pub fn bind_port(addr: &SocketAddr) -> io::Result<net::TcpListener> {
+    bind_port_with(addr, false)
+}
+
+fn bind_port_with(addr: &SocketAddr, reuse_port: bool) -> io::Result<net::TcpListener> {
+    bind_with(addr, reuse_port).map_err(|e| io::Error::new(e.kind(), format!("cannot listen on port {} ({}): {}", addr.port(), addr, e)))
+}
+
+/// Serve a bound listener on the runtime
+fn register(listener: net::TcpListener) -> io::Result<TcpListener> {
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+pub fn listen(addr: &SocketAddr) -> io::Result<TcpListener> {
+    register(bind_port(addr)?)
+}
+
+/// Bind `count` listeners on `addr` with SO_REUSEPORT, the kernel spreads
+/// the new connections across them
+pub fn listen_many(addr: &SocketAddr, count: usize) -> io::Result<Vec<TcpListener>> {
+    bind_many(addr, count)?.into_iter().map(register).collect()
+}
+
+/// Bind the listeners of `listen_many`, to be served later on
+#[cfg(unix)]
+pub fn bind_many(addr: &SocketAddr, count: usize) -> io::Result<Vec<net::TcpListener>> {
+    if count <= 1 {
+        return Ok(vec![bind_port(addr)?]);
+    }
+
+    // The others share the port the first one got, when asked for any
+    let first = bind_port_with(addr, true)?;
+    let mut addr = *addr;
+    addr.set_port(first.local_addr()?.port());
+
+    let mut listeners = vec![first];
+    for _ in 1..count {
+        listeners.push(bind_port_with(&addr, true)?);
+    }
+    Ok(listeners)
+}
+
+/// A single listener, there is no SO_REUSEPORT to share the port
+#[cfg(not(unix))]
+pub fn bind_many(addr: &SocketAddr, count: usize) -> io::Result<Vec<net::TcpListener>> {
+    if count > 1 {
+        warn!("SO_REUSEPORT is not available, accepting on {} with a single loop", addr);
+    }
+
+    Ok(vec![bind_port(addr)?])
+}
+
+/// Serve a listener bound earlier, keeping it bound once the copy returned is dropped
+pub fn adopt(listener: &net::TcpListener) -> io::Result<TcpListener> {
+    register(listener.try_clone()?)
+}
+
+/// Accept the connections of `listener` for as long as the future is polled,
+/// sleeping a moment on the errors, e.g. out of file descriptors
+pub async fn accept_loop<F: FnMut(TcpStream)>(listener: TcpListener, mut accept: F) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, _)) => accept(socket),
+            Err(e) => {
+                debug!("Cannot accept a connection: {}", e);
+                time::sleep(ACCEPT_ERROR_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Fail early if `addr` cannot be listened on, for the listeners bound later on
+pub fn check(addr: &SocketAddr) -> io::Result<()> {
+    bind_port(addr).map(|_| ())
+}
+
+/// Wait up to `timeout` for the ports of `addrs` in use to be released, e.g.
+/// by the instance being replaced, blocking the thread meanwhile
+pub fn wait_for_ports(addrs: &[SocketAddr], timeout: Duration) -> io::Result<()> {
+    let deadline = Instant::now() + timeout;
+
+    for addr in addrs {
+        let mut warned = false;
+        loop {
+            match bind(addr) {
+                Ok(_) => break,
+                Err(ref e) if e.kind() == io::ErrorKind::AddrInUse && Instant::now() < deadline => {
+                    if !warned {
+                        warn!("Port {} ({}) in use, retrying for up to {:?}", addr.port(), addr, deadline - Instant::now());
+                        warned = true;
+                    }
+                    thread::sleep(BIND_RETRY_INTERVAL);
+                }
+                Err(_) => return check(addr),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Show the IPv4-mapped addresses in their natural form
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+pub fn peer_addr(socket: &TcpStream) -> io::Result<SocketAddr> {
+    socket.peer_addr().map(canonical)
+}
+
+/// Connect from the local end `bind`, giving up after `timeout` if set
+pub async fn connect(addr: &SocketAddr, timeout: Option<Duration>, bind: &LocalBind) -> io::Result<TcpStream> {
+    let connect = async {
+        if !bind.is_set() {
+            return TcpStream::connect(addr).await;
+        }
+        let socket = tcp_socket(addr, bind).map_err(|e| bind.error(e, "the connection"))?;
+        socket.set_nonblocking(true)?;
+        TcpSocket::from_std_stream(socket).connect(*addr).await
+    };
+
+    match timeout {
+        Some(timeout) => time::timeout(timeout, connect).await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "connection timed out"))),
+        None => connect.await,
+    }
+}
+
+/// Keep a connection to `addr` open from the local end `bind`, calling `serve` on every new one
+///
+/// `serve` resolves once the connection is gone, the next attempt waits
+/// according to `backoff`.
+pub async fn reconnect<F, S>(addr: SocketAddr, bind: LocalBind, backoff: Backoff, timeout: Option<Duration>, serve: F)
+where
+    F: FnMut(TcpStream) -> S,
+    S: Future<Output = ()>,
+{
+    retry(format!("{:?}", addr), backoff, || connect(&addr, timeout, &bind), serve).await
+}
+
+/// Keep a connection to `name` open as `reconnect` does, opening it with `connect`
+pub async fn retry<C, T, P, F, S>(name: String, backoff: Backoff, mut connect: C, mut serve: F)
+where
+    C: FnMut() -> T,
+    T: Future<Output = io::Result<P>>,
+    F: FnMut(P) -> S,
+    S: Future<Output = ()>,
+{
+    let mut delay = backoff.initial;
+
+    loop {
+        match connect().await {
+            Ok(socket) => {
+                let since = Instant::now();
+                serve(socket).await;
+                // Start over unless the peer keeps dropping the connection
+                if since.elapsed() >= backoff.max {
+                    delay = backoff.initial;
+                }
+            }
+            Err(e) => error!("Cannot connect to {}: {}", name, e),
+        }
+
+        info!("Reconnecting to {} in {:?}", name, delay);
+        time::sleep(delay).await;
+        delay = cmp::min(delay * 2, backoff.max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dual_stack() {
+        let listener = match bind(&"[::]:0".parse().unwrap()) {
+            Ok(listener) => listener,
+            // No IPv6 support here
+            Err(_) => return,
+        };
+        let port = listener.local_addr().unwrap().port();
+
+        let _client = net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let (_, addr) = listener.accept().unwrap();
+
+        assert_eq!(canonical(addr), ([127, 0, 0, 1], addr.port()).into());
+    }
+
+    #[test]
+    fn dscp_names() {
+        assert_eq!(parse_dscp("EF"), Ok(46));
+        assert_eq!(parse_dscp("af41"), Ok(34));
+        assert_eq!(parse_dscp("AF13"), Ok(14));
+        assert_eq!(parse_dscp("CS6"), Ok(48));
+        assert_eq!(parse_dscp("BE"), Ok(0));
+        assert_eq!(parse_dscp("63"), Ok(63));
+
+        for invalid in &["64", "AF51", "AF14", "CS8", "AF", "X", "-1"] {
+            assert!(parse_dscp(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dscp_kept() {
+        let socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert_eq!(set_dscp(&socket, false, 46).unwrap(), 46);
+        assert_eq!(set_dscp(&socket, false, 10).unwrap(), 10);
+    }
+
+    #[test]
+    fn local_bind_query() {
+        let (target, bind) = LocalBind::split("tcp://relay:9000?local=10.0.0.5&device=eth1").unwrap();
+        assert_eq!(target, "tcp://relay:9000");
+        assert_eq!(bind.addr, Some("10.0.0.5".parse().unwrap()));
+        assert_eq!(bind.to_string(), "10.0.0.5 on device eth1");
+
+        assert_eq!(LocalBind::split("239.0.0.1:5000").unwrap().1, LocalBind::default());
+        assert!(LocalBind::split("239.0.0.1:5000?local=eth1").is_err());
+        assert!(LocalBind::split("239.0.0.1:5000?ttl=4").is_err());
+
+        let global = LocalBind { addr: Some("10.0.0.1".parse().unwrap()), device: Some("eth0".to_owned()) };
+        let bind = LocalBind { addr: Some("10.0.0.2".parse().unwrap()), device: None }.or(&global);
+        assert_eq!(bind.to_string(), "10.0.0.2 on device eth0");
+    }
+
+    #[test]
+    fn bound_sockets() {
+        let local = LocalBind { addr: Some("127.0.0.1".parse().unwrap()), device: None };
+        let target: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        assert_eq!(udp_socket(&target, &local).unwrap().local_addr().unwrap().ip(), local.addr.unwrap());
+
+        // Not an address of this host
+        let foreign = LocalBind { addr: Some("192.0.2.1".parse().unwrap()), device: None };
+        let err = tcp_socket(&target, &foreign).map_err(|e| foreign.error(e, "push target tcp://127.0.0.1:9")).unwrap_err();
+        assert!(err.to_string().starts_with("cannot bind push target tcp://127.0.0.1:9 to 192.0.2.1: "), "{}", err);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shared_port() {
+        // The listeners are registered with the reactor
+        let rt = tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap();
+        let _guard = rt.enter();
+
+        let listeners = listen_many(&"127.0.0.1:0".parse().unwrap(), 1).unwrap();
+        assert_eq!(listeners.len(), 1);
+
+        let first = bind_with(&"127.0.0.1:0".parse().unwrap(), true).unwrap();
+        let addr = first.local_addr().unwrap();
+        assert!(bind_with(&addr, true).is_ok());
+        assert!(bind(&addr).is_err());
+    }
+}
@@ -0,0 +1,248 @@
+//! Packet size detection of the producer input
+//!
+//! DVB modulators send 204-byte packets, 188 bytes followed by Reed-Solomon
+//! parity, and M2TS sources 192-byte ones, a 4-byte timestamp followed by the
+//! 188 bytes. Either way the extra bytes are stripped before fanning out.
+
+use bytes::{Buf, BytesMut};
+use log::{debug, info, warn};
+
+use crate::ts;
+
+/// Packet sizes found in the wild, the plain one first
+pub const PACKET_SIZES: [usize; 3] = [188, 192, 204];
+/// Packets in a row telling their size
+const DETECT_PACKETS: usize = 5;
+
+pub fn parse_packet_size(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(size) if PACKET_SIZES.contains(&size) => Ok(size),
+        _ => Err(format!("Invalid packet size {}, use 188, 192 or 204", s)),
+    }
+}
+
+/// The packet sizes the sync bytes of `buf` line up with, with the offset of
+/// their first packet, none while more is needed to tell
+fn detect(buf: &[u8]) -> Option<Vec<(usize, usize)>> {
+    let largest = PACKET_SIZES[PACKET_SIZES.len() - 1];
+    let mut need_more = buf.len() < largest;
+    let mut found = Vec::new();
+
+    for &size in &PACKET_SIZES {
+        for off in 0..size.min(buf.len()) {
+            let syncs = (0..DETECT_PACKETS).map(|k| buf.get(off + k * size));
+
+            if syncs.clone().all(|b| b.is_none_or(|&b| b == ts::SYNC_BYTE)) {
+                if syncs.clone().all(|b| b.is_some()) {
+                    found.push((size, off));
+                    break;
+                }
+                need_more = true;
+            }
+        }
+    }
+
+    // A larger size may still fit
+    if need_more && buf.len() < DETECT_PACKETS * largest {
+        None
+    } else {
+        Some(found)
+    }
+}
+
+/// Turns the packets read from a producer into 188-byte ones
+pub struct Normalizer {
+    /// Given rather than detected
+    forced: Option<usize>,
+    size: Option<usize>,
+    /// Read but not normalized yet
+    raw: BytesMut,
+}
+
+impl Normalizer {
+    pub fn new(forced: Option<usize>, raw: BytesMut) -> Self {
+        Normalizer { forced, size: forced, raw }
+    }
+
+    /// Size of the packets read, once known
+    pub fn size(&self) -> Option<usize> {
+        self.size
+    }
+
+    /// Where to read to
+    pub fn raw(&mut self) -> &mut BytesMut {
+        &mut self.raw
+    }
+
+    pub fn pending(&self) -> usize {
+        self.raw.len()
+    }
+
+    fn pass(&mut self, out: &mut BytesMut) {
+        out.extend_from_slice(&self.raw);
+        self.raw.clear();
+    }
+
+    /// Move the whole packets read so far to `out`, as 188-byte packets
+    pub fn normalize(&mut self, out: &mut BytesMut) {
+        loop {
+            let size = match self.size {
+                Some(size) => size,
+                None => match detect(&self.raw) {
+                    None => return,
+                    // No packets to find, left for the resync to skip
+                    Some(ref sizes) if sizes.is_empty() => return self.pass(out),
+                    Some(found) => {
+                        let (size, off) = found[0];
+                        if found.len() > 1 {
+                            let sizes: Vec<_> = found.iter().map(|&(size, _)| size).collect();
+                            warn!("Packet sizes {:?} all fit the producer input, assuming {}, see --packet-size", sizes, size);
+                        } else if size != ts::PACKET_SIZE {
+                            info!("The producer sends {}-byte packets, stripping them to {}", size, ts::PACKET_SIZE);
+                        }
+                        if off > 0 {
+                            debug!("Skipping {} bytes to the first packet", off);
+                            self.raw.advance(off);
+                        }
+                        self.size = Some(size);
+                        size
+                    }
+                },
+            };
+
+            // Nothing to strip, the resync is left to the chunker
+            if size == ts::PACKET_SIZE {
+                return self.pass(out);
+            }
+
+            while self.raw.len() >= size {
+                if self.raw[0] == ts::SYNC_BYTE {
+                    out.extend_from_slice(&self.raw[..ts::PACKET_SIZE]);
+                    self.raw.advance(size);
+                    continue;
+                }
+
+                let next = (1..self.raw.len()).find(|&i| {
+                    self.raw[i] == ts::SYNC_BYTE && self.raw.get(i + size).is_none_or(|&b| b == ts::SYNC_BYTE)
+                });
+
+                match next {
+                    Some(off) if off < size || self.forced.is_some() => {
+                        debug!("Skipping {} bytes to resync", off);
+                        self.raw.advance(off);
+                    }
+                    None if self.forced.is_some() => {
+                        warn!("Skipping {} bytes, no sync byte found", self.raw.len());
+                        self.raw.clear();
+                    }
+                    // Not packets of that size anymore
+                    _ => {
+                        warn!("Lost the {}-byte packets, detecting their size again", size);
+                        self.size = None;
+                        break;
+                    }
+                }
+            }
+
+            if self.size.is_some() {
+                return;
+            }
+        }
+    }
+
+    /// The producer is gone, move what is left to `out`
+    pub fn flush(&mut self, out: &mut BytesMut) {
+        self.normalize(out);
+
+        match self.size {
+            Some(size) if size != ts::PACKET_SIZE => {
+                // The parity of the last packet may be missing
+                if self.raw.len() >= ts::PACKET_SIZE && self.raw[0] == ts::SYNC_BYTE {
+                    out.extend_from_slice(&self.raw[..ts::PACKET_SIZE]);
+                }
+                self.raw.clear();
+            }
+            _ => self.pass(out),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Packets of `size` bytes, the 188 of each at `at` and the extra bytes set to 0xaa
+    fn stream(size: usize, at: usize, packets: usize) -> (Vec<u8>, Vec<u8>) {
+        let (mut raw, mut plain) = (Vec::new(), Vec::new());
+        for i in 0..packets {
+            let mut pkt = vec![i as u8; ts::PACKET_SIZE];
+            pkt[0] = ts::SYNC_BYTE;
+            let mut sized = vec![0xaa; size];
+            sized[at..at + ts::PACKET_SIZE].copy_from_slice(&pkt);
+            raw.extend(sized);
+            plain.extend(pkt);
+        }
+        (raw, plain)
+    }
+
+    fn normalize(normalizer: &mut Normalizer, input: &[u8]) -> Vec<u8> {
+        let mut out = BytesMut::new();
+        for part in input.chunks(500) {
+            normalizer.raw().extend_from_slice(part);
+            normalizer.normalize(&mut out);
+        }
+        normalizer.flush(&mut out);
+        out.to_vec()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_packet_size("204"), Ok(204));
+        assert!(parse_packet_size("200").is_err());
+    }
+
+    #[test]
+    fn detected_and_stripped() {
+        for &(size, at) in &[(188, 0), (192, 4), (204, 0)] {
+            let (raw, plain) = stream(size, at, 20);
+            let mut normalizer = Normalizer::new(None, BytesMut::new());
+
+            assert_eq!(normalize(&mut normalizer, &raw), plain, "{}-byte packets", size);
+            assert_eq!(normalizer.size(), Some(size));
+        }
+    }
+
+    #[test]
+    fn nothing_before_detection() {
+        let (raw, _) = stream(204, 0, 4);
+        let mut normalizer = Normalizer::new(None, BytesMut::from(&raw[..]));
+        let mut out = BytesMut::new();
+
+        normalizer.normalize(&mut out);
+        assert!(out.is_empty());
+        assert_eq!(normalizer.size(), None);
+
+        // No size can fit, left to the validation
+        let mut normalizer = Normalizer::new(None, BytesMut::from(&[b'x'; 400][..]));
+        normalizer.normalize(&mut out);
+        assert_eq!(out.len(), 400);
+    }
+
+    #[test]
+    fn size_change_detected_again() {
+        let (first, plain_first) = stream(204, 0, 10);
+        let (second, plain_second) = stream(192, 4, 10);
+        let mut normalizer = Normalizer::new(None, BytesMut::new());
+
+        assert_eq!(normalize(&mut normalizer, &[first, second].concat()), [plain_first, plain_second].concat());
+        assert_eq!(normalizer.size(), Some(192));
+    }
+
+    #[test]
+    fn forced_size() {
+        let (raw, plain) = stream(204, 0, 3);
+        let mut normalizer = Normalizer::new(Some(204), BytesMut::new());
+
+        assert_eq!(normalize(&mut normalizer, &raw), plain);
+    }
+}
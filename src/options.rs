@@ -0,0 +1,314 @@
+//! Options a consumer may ask for on its own connection
+//!
+//! Right after connecting a consumer may send `OPTS key=value ...\n`, or a
+//! relay `RESUME ...\n`, the consumers sending anything else, or nothing in
+//! time, are served as usual.
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::{self, Sleep};
+use bytes::BytesMut;
+
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::Context;
+use std::time::{Duration, Instant};
+
+use crate::burst::Burst;
+use crate::relay::Offset;
+
+/// Time given to the consumer to send its options
+pub const OPTIONS_TIMEOUT: Duration = Duration::from_millis(500);
+const PREFIX: &[u8] = b"OPTS";
+const RESUME: &[u8] = b"RESUME";
+/// Longest options line
+const MAX_LINE: usize = 256;
+/// Rate credit a paced consumer keeps while it has nothing to send
+const PACE_CREDIT: Duration = Duration::from_millis(100);
+/// Longest duration given in seconds, far from overflowing an `Instant`
+const MAX_SECS: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConsumerOptions {
+    /// Replay at most this much of the burst buffer, `burst=0` for none
+    pub burst: Option<Burst>,
+    /// Only send the packets of this program
+    pub program: Option<u16>,
+    /// Send at most this many bits per second
+    pub pace: Option<u64>,
+    /// Start this far in the past, from the time-shift buffer
+    pub rewind: Option<Duration>,
+    /// A relay, told where its stream starts
+    pub relay: bool,
+    /// Where the previous connection of the relay left the stream
+    pub resume: Option<Offset>,
+    /// Names the session, a reconnection giving it back gets the queue left behind
+    pub token: Option<String>,
+    /// Tier asked for, never above the one the address or port of the consumer gets
+    pub priority: Option<u8>,
+}
+
+/// Parse bitrates such as `2000000`, `1500k` or `2M`
+pub fn parse_bitrate(s: &str) -> Result<u64, String> {
+    let err = || format!("Invalid bitrate {}, use e.g. 1500k or 2M", s);
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: f64 = num.parse().map_err(|_| err())?;
+
+    let mult = match unit {
+        "" => 1e0,
+        "k" | "K" => 1e3,
+        "m" | "M" => 1e6,
+        "g" | "G" => 1e9,
+        _ => return Err(err()),
+    };
+
+    match (num * mult) as u64 {
+        0 => Err(err()),
+        bitrate => Ok(bitrate),
+    }
+}
+
+/// Seconds as a `Duration`, none if negative or longer than a year
+pub fn secs_duration(secs: f64) -> Option<Duration> {
+    Duration::try_from_secs_f64(secs).ok().filter(|duration| *duration <= MAX_SECS)
+}
+
+/// Parse seconds such as `30` or `1.5`, refusing the negative ones and those
+/// longer than a year
+pub fn parse_secs(s: &str) -> Result<Duration, String> {
+    s.parse::<f64>().ok()
+        .and_then(secs_duration)
+        .ok_or_else(|| format!("Invalid duration {}, use seconds such as 1.5, up to a year", s))
+}
+
+/// Session tokens, 1 to 64 letters, digits, `_` or `-`
+fn parse_token(s: &str) -> Result<String, String> {
+    if !s.is_empty() && s.len() <= 64 && s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-') {
+        Ok(s.to_owned())
+    } else {
+        Err(format!("Invalid token {:?}, use up to 64 letters, digits, _ or -", s))
+    }
+}
+
+/// Seconds to rewind, such as `30` or `1.5`
+fn parse_rewind(s: &str) -> Result<Duration, String> {
+    parse_secs(s).map_err(|_| format!("Invalid rewind {}, use seconds such as 30", s))
+}
+
+impl ConsumerOptions {
+    /// Parse the query string of an HTTP consumer, such as `rewind=30&program=3`,
+    /// ignoring the keys not known
+    pub fn from_query(query: &str) -> Result<Self, String> {
+        ConsumerOptions::from_pairs(query.split('&').filter(|pair| !pair.is_empty()))
+    }
+
+    fn from_pairs<'a, I: Iterator<Item = &'a str>>(pairs: I) -> Result<Self, String> {
+        let mut options = ConsumerOptions::default();
+
+        for pair in pairs {
+            let mut kv = pair.splitn(2, '=');
+            let (key, value) = match (kv.next(), kv.next()) {
+                (Some(key), Some(value)) if !key.is_empty() => (key, value),
+                _ => return Err(format!("Invalid option {:?}, expected key=value", pair)),
+            };
+
+            match key {
+                "burst" => options.burst = Some(value.parse()?),
+                "program" => {
+                    options.program = Some(value.parse().map_err(|_| format!("Invalid program {}", value))?);
+                }
+                "pace" => options.pace = Some(parse_bitrate(value)?),
+                "rewind" => options.rewind = Some(parse_rewind(value)?),
+                "token" => options.token = Some(parse_token(value)?),
+                "priority" => {
+                    options.priority = Some(value.parse().map_err(|_| format!("Invalid priority {}, use 0 to 255", value))?);
+                }
+                _ => (),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+impl FromStr for ConsumerOptions {
+    type Err = String;
+
+    /// Parse `OPTS key=value ...`, ignoring the keys not known, or `RESUME [origin:offset]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        match words.next().map(str::as_bytes) {
+            Some(PREFIX) => ConsumerOptions::from_pairs(words),
+            Some(RESUME) => {
+                let resume = words.next().map(str::parse).transpose()?;
+                if words.next().is_some() {
+                    return Err(format!("Invalid resume {:?}, expected RESUME [origin:offset]", s));
+                }
+                Ok(ConsumerOptions { relay: true, resume, ..ConsumerOptions::default() })
+            }
+            _ => Err(format!("Invalid options {:?}, expected OPTS key=value ...", s)),
+        }
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Wait briefly for the options line, yielding the socket back, with no
+/// options for the consumers not sending them
+pub async fn read_options<S: AsyncRead + Unpin>(mut socket: S) -> io::Result<(S, Option<ConsumerOptions>)> {
+    let mut buf = BytesMut::with_capacity(MAX_LINE);
+    let timeout = time::sleep(OPTIONS_TIMEOUT);
+    tokio::pin!(timeout);
+
+    loop {
+        if let Some(end) = buf.iter().position(|&b| b == b'\n') {
+            let line = ::std::str::from_utf8(&buf[..end])
+                .map_err(|_| invalid("Invalid options, not UTF-8".to_owned()))?;
+            let options = line.trim_end_matches('\r').parse().map_err(invalid)?;
+
+            return Ok((socket, Some(options)));
+        }
+
+        // Whatever a consumer sends is discarded otherwise
+        let starts = |prefix: &[u8]| {
+            let n = buf.len().min(prefix.len());
+            buf[..n] == prefix[..n]
+        };
+        if !starts(PREFIX) && !starts(RESUME) {
+            return Ok((socket, None));
+        }
+
+        if buf.len() >= MAX_LINE {
+            return Err(invalid("Options line too long".to_owned()));
+        }
+
+        buf.reserve(MAX_LINE - buf.len());
+        let n = tokio::select! {
+            n = socket.read_buf(&mut buf) => n?,
+            _ = &mut timeout => {
+                if buf.is_empty() {
+                    return Ok((socket, None));
+                }
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "Incomplete options"));
+            }
+        };
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Incomplete options"));
+        }
+    }
+}
+
+/// Cap on the rate a consumer is sent at
+pub struct Pace {
+    bytes_per_sec: f64,
+    /// When the next chunk may be sent
+    next: Instant,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl Pace {
+    pub fn new(bitrate: u64) -> Self {
+        Pace {
+            bytes_per_sec: bitrate as f64 / 8.0,
+            next: Instant::now(),
+            delay: None,
+        }
+    }
+
+    /// Whether more can be sent now, otherwise the task is woken up once it can
+    pub fn poll_ready(&mut self, cx: &mut Context<'_>) -> bool {
+        if Instant::now() >= self.next {
+            self.delay = None;
+            return true;
+        }
+
+        crate::poll_delay(&mut self.delay, self.next, cx)
+    }
+
+    /// Account for `bytes` sent, the credit left from the idle periods is bounded
+    pub fn sent(&mut self, bytes: usize) {
+        let now = Instant::now();
+        let earliest = now.checked_sub(PACE_CREDIT).unwrap_or(now);
+
+        self.next = self.next.max(earliest) + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!("OPTS".parse(), Ok(ConsumerOptions::default()));
+        assert_eq!("OPTS burst=2s program=3 pace=1.5M color=blue".parse(), Ok(ConsumerOptions {
+            burst: Some(Burst::Duration(Duration::from_secs(2))),
+            program: Some(3),
+            pace: Some(1_500_000),
+            ..ConsumerOptions::default()
+        }));
+        assert_eq!("OPTS rewind=30".parse::<ConsumerOptions>().unwrap().rewind, Some(Duration::from_secs(30)));
+        assert!("OPTS rewind=-1".parse::<ConsumerOptions>().is_err());
+        assert!("OPTS rewind=1e20".parse::<ConsumerOptions>().is_err());
+        assert_eq!("OPTS burst=0".parse::<ConsumerOptions>().unwrap().burst, Some(Burst::Bytes(0)));
+        assert!("OPTS burst=99999999999999999999s".parse::<ConsumerOptions>().is_err());
+        assert_eq!("OPTS token=tv-1".parse::<ConsumerOptions>().unwrap().token.as_deref(), Some("tv-1"));
+        assert!("OPTS token=".parse::<ConsumerOptions>().is_err());
+        assert!("OPTS token=a/b".parse::<ConsumerOptions>().is_err());
+        assert_eq!("OPTS priority=0".parse::<ConsumerOptions>().unwrap().priority, Some(0));
+        assert!("OPTS priority=high".parse::<ConsumerOptions>().is_err());
+
+        let resume: ConsumerOptions = "RESUME 00000000000000ab:1316".parse().unwrap();
+        assert_eq!((resume.relay, resume.resume), (true, Some(Offset { origin: 0xab, offset: 1316 })));
+        assert_eq!("RESUME".parse::<ConsumerOptions>().map(|options| (options.relay, options.resume)), Ok((true, None)));
+        assert!("RESUME 1316".parse::<ConsumerOptions>().is_err());
+
+        assert!("GET / HTTP/1.1".parse::<ConsumerOptions>().is_err());
+        assert!("OPTS burst".parse::<ConsumerOptions>().is_err());
+        assert!("OPTS =1".parse::<ConsumerOptions>().is_err());
+        assert!("OPTS program=x".parse::<ConsumerOptions>().is_err());
+        assert!("OPTS pace=0".parse::<ConsumerOptions>().is_err());
+    }
+
+    #[test]
+    fn secs() {
+        assert_eq!(parse_secs("1.5"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_secs("0"), Ok(Duration::ZERO));
+        assert!(parse_secs("-1").is_err());
+        assert_eq!(parse_secs("31536000"), Ok(MAX_SECS));
+        assert!(parse_secs("31536000.5").is_err());
+        assert!(parse_secs("1e20").is_err());
+        assert!(parse_secs("NaN").is_err());
+        assert!(parse_secs("soon").is_err());
+    }
+
+    #[test]
+    fn query() {
+        assert_eq!(ConsumerOptions::from_query(""), Ok(ConsumerOptions::default()));
+        let options = ConsumerOptions::from_query("rewind=2.5&program=3&utm_source=x").unwrap();
+        assert_eq!((options.rewind, options.program), (Some(Duration::from_millis(2500)), Some(3)));
+        assert!(ConsumerOptions::from_query("rewind=soon").is_err());
+        assert!(ConsumerOptions::from_query("rewind").is_err());
+    }
+
+    #[test]
+    fn pace_spreads_the_chunks() {
+        // 1316 bytes every 10ms
+        let mut pace = Pace::new(1316 * 8 * 100);
+        let start = Instant::now();
+
+        for _ in 0..10 {
+            pace.sent(1316);
+        }
+        assert!(pace.next >= start + Duration::from_millis(99));
+
+        // The credit left from an idle period is bounded
+        let mut idle = Pace::new(1316 * 8 * 100);
+        idle.next = start - Duration::from_secs(10);
+        idle.sent(1316);
+        assert!(idle.next >= start - PACE_CREDIT);
+    }
+}
@@ -0,0 +1,206 @@
+//! Pacing of the UDP outputs on the PCR
+//!
+//! The datagrams carrying a PCR are sent when it is due, the ones in between
+//! at the rate measured over the previous PCR interval, so the output tracks
+//! the original mux rate instead of the bursts of the input.
+
+use log::{info, warn};
+
+use std::time::{Duration, Instant};
+
+use crate::ts;
+
+/// Give up pacing if no PCR shows up for this long
+const PCR_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Larger PCR steps, or going back, are discontinuities
+const MAX_PCR_STEP: u64 = ts::PCR_HZ;
+
+fn pcr_duration(ticks: u64) -> Duration {
+    Duration::from_nanos(ticks * 1000 / 27)
+}
+
+/// Send time of the datagrams
+pub struct PcrPacer {
+    /// How long the datagrams are held back, to absorb the input jitter
+    depth: Duration,
+    /// PID followed and its last PCR
+    pcr: Option<(u16, u64)>,
+    /// When the stream time 0 is due
+    anchor: Instant,
+    /// Stream time of the last PCR
+    elapsed: Duration,
+    /// Measured over the last PCR interval
+    bytes_per_sec: Option<f64>,
+    /// Bytes since the last PCR, counting the one carrying it
+    bytes: u64,
+    /// Since when no PCR was found
+    searching: Option<Instant>,
+    /// No PCR in the stream, sending unpaced
+    unpaced: bool,
+}
+
+impl PcrPacer {
+    pub fn new(depth: Duration) -> Self {
+        PcrPacer {
+            depth,
+            pcr: None,
+            anchor: Instant::now(),
+            elapsed: Duration::from_secs(0),
+            bytes_per_sec: None,
+            bytes: 0,
+            searching: None,
+            unpaced: false,
+        }
+    }
+
+    /// Start over from `now`, at the PCR given
+    fn restart(&mut self, now: Instant, pid: u16, pcr: u64) {
+        self.pcr = Some((pid, pcr));
+        self.anchor = now + self.depth;
+        self.elapsed = Duration::from_secs(0);
+        self.bytes_per_sec = None;
+        self.bytes = 0;
+    }
+
+    fn on_pcr(&mut self, now: Instant, pid: u16, pcr: u64) {
+        match self.pcr {
+            Some((followed, last)) if followed == pid => {
+                let step = ts::pcr_step(last, pcr);
+
+                if step == 0 || step > MAX_PCR_STEP {
+                    warn!("PCR discontinuity on PID {:#06x}, pacing from now", pid);
+                    return self.restart(now, pid, pcr);
+                }
+
+                let step = pcr_duration(step);
+                self.bytes_per_sec = Some(self.bytes as f64 / step.as_secs_f64());
+                self.elapsed += step;
+                self.bytes = 0;
+                self.pcr = Some((pid, pcr));
+            }
+            Some(_) => (),
+            None => {
+                if self.unpaced {
+                    info!("PCR found on PID {:#06x}, pacing the output", pid);
+                    self.unpaced = false;
+                }
+                self.restart(now, pid, pcr);
+            }
+        }
+    }
+
+    /// When the datagram made of `packets` is due, `None` to send it right away
+    pub fn due(&mut self, now: Instant, packets: &[u8]) -> Option<Instant> {
+        let before = self.bytes;
+        // Bytes of the datagram preceding its PCR, if it carries one
+        let mut lead = None;
+
+        for (i, pkt) in packets.chunks(ts::PACKET_SIZE).enumerate() {
+            if let Some((pid, pcr)) = ts::pcr(pkt) {
+                self.on_pcr(now, pid, pcr);
+                if self.pcr == Some((pid, pcr)) {
+                    lead = Some(i * ts::PACKET_SIZE);
+                }
+            }
+            self.bytes += pkt.len() as u64;
+        }
+
+        if self.pcr.is_none() {
+            let since = *self.searching.get_or_insert(now);
+            if !self.unpaced && now - since >= PCR_TIMEOUT {
+                warn!("No PCR within {:?}, sending unpaced", PCR_TIMEOUT);
+                self.unpaced = true;
+            }
+            return None;
+        }
+
+        // Away from the PCR the datagrams go at the rate measured
+        let at_rate = |bytes: u64| match self.bytes_per_sec {
+            Some(rate) if rate > 0.0 => Duration::from_secs_f64(bytes as f64 / rate),
+            _ => Duration::from_secs(0),
+        };
+        let pcr_time = self.anchor + self.elapsed;
+        let due = match lead {
+            Some(lead) => pcr_time - at_rate(lead as u64),
+            None => pcr_time + at_rate(before),
+        };
+
+        // Too late to catch up, the input stalled: pace from now
+        if due + self.depth < now {
+            self.anchor += now - due;
+            return Some(now);
+        }
+
+        Some(due)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ts::tests::{packet, pcr_packet};
+
+    const DEPTH: Duration = Duration::from_millis(100);
+    const TICKS_10MS: u64 = ts::PCR_HZ / 100;
+
+    #[test]
+    fn follows_the_pcr() {
+        let mut pacer = PcrPacer::new(DEPTH);
+        let now = Instant::now();
+        let start = now + DEPTH;
+
+        assert_eq!(pacer.due(now, &pcr_packet(0x100, 1000)), Some(start));
+        assert_eq!(pacer.due(now, &packet(0x100, 0x10)), Some(start));
+        assert_eq!(pacer.due(now, &pcr_packet(0x100, 1000 + TICKS_10MS)), Some(start + Duration::from_millis(10)));
+        // 2 packets per 10ms, measured over the last interval
+        let due = pacer.due(now, &packet(0x100, 0x10)).unwrap();
+        assert!(due > start + Duration::from_millis(14) && due < start + Duration::from_millis(16), "{:?}", due - start);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let mut pacer = PcrPacer::new(DEPTH);
+        let now = Instant::now();
+        let start = now + DEPTH;
+
+        pacer.due(now, &pcr_packet(0x100, ts::PCR_WRAP - TICKS_10MS));
+        assert_eq!(pacer.due(now, &pcr_packet(0x100, TICKS_10MS)), Some(start + Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn discontinuity_restarts() {
+        let mut pacer = PcrPacer::new(DEPTH);
+        let now = Instant::now();
+        let later = now + Duration::from_millis(50);
+
+        pacer.due(now, &pcr_packet(0x100, 5 * ts::PCR_HZ));
+        assert_eq!(pacer.due(later, &pcr_packet(0x100, ts::PCR_HZ)), Some(later + DEPTH));
+        // Another program does not matter
+        assert_eq!(pacer.due(later, &pcr_packet(0x200, 1000)), Some(later + DEPTH));
+    }
+
+    #[test]
+    fn catches_up_after_a_stall() {
+        let mut pacer = PcrPacer::new(DEPTH);
+        let now = Instant::now();
+        let later = now + Duration::from_secs(2);
+
+        pacer.due(now, &pcr_packet(0x100, 1000));
+        assert_eq!(pacer.due(later, &pcr_packet(0x100, 1000 + TICKS_10MS)), Some(later));
+        assert_eq!(pacer.due(later, &pcr_packet(0x100, 1000 + 2 * TICKS_10MS)), Some(later + Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn unpaced_without_pcr() {
+        let mut pacer = PcrPacer::new(DEPTH);
+        let now = Instant::now();
+
+        assert_eq!(pacer.due(now, &packet(0x100, 0x10)), None);
+        assert_eq!(pacer.due(now + PCR_TIMEOUT, &packet(0x100, 0x10)), None);
+        assert!(pacer.unpaced);
+
+        assert_eq!(pacer.due(now + PCR_TIMEOUT, &pcr_packet(0x100, 1000)), Some(now + PCR_TIMEOUT + DEPTH));
+        assert!(!pacer.unpaced);
+    }
+}
@@ -0,0 +1,287 @@
+//! PCR timing of the stream broadcast
+//!
+//! The PCRs of the PCR PID, the one of the first program listing one or else
+//! the first PID carrying them, are checked against their byte position: each
+//! step from one PCR to the next is compared with the time the bytes between
+//! them take at the mux rate of the PCRs before it. The difference is the
+//! jitter, reported with the repetition interval over the last 10 seconds of
+//! PCRs, and counted in histograms. A step back or of more than a second, or a
+//! signalled discontinuity, starts the measurement over.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::psi::Program;
+use crate::ts;
+
+/// Span of PCRs the jitter and the interval are reported over
+const WINDOW: u64 = 10 * ts::PCR_HZ;
+/// Longer steps from one PCR to the next are discontinuities
+const MAX_STEP: u64 = ts::PCR_HZ;
+
+/// Upper bounds of the buckets, in seconds
+pub const JITTER_BUCKETS: [f64; 6] = [0.000_000_5, 0.000_001, 0.000_01, 0.000_1, 0.001, 0.01];
+pub const INTERVAL_BUCKETS: [f64; 6] = [0.01, 0.02, 0.04, 0.1, 0.5, 1.0];
+
+/// Observations counted since the start, for the Prometheus histograms
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Histogram {
+    /// One per bucket, not cumulative
+    pub counts: [u64; 6],
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, bounds: &[f64; 6], value: f64) {
+        if let Some(i) = bounds.iter().position(|&bound| value <= bound) {
+            self.counts[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Smallest, largest and average over the window
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Range {
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+}
+
+impl Range {
+    fn of<I: Iterator<Item = f64>>(values: I) -> Option<Range> {
+        let (mut min, mut max, mut sum, mut n) = (f64::MAX, f64::MIN, 0.0, 0);
+        for value in values {
+            min = min.min(value);
+            max = max.max(value);
+            sum += value;
+            n += 1;
+        }
+        if n > 0 {
+            Some(Range { min, max, avg: sum / f64::from(n) })
+        } else {
+            None
+        }
+    }
+}
+
+/// The PCR timing over the window
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Summary {
+    pub pid: u16,
+    pub mux_rate_bps: u64,
+    /// Absolute jitter, in seconds, none until the mux rate is known
+    pub jitter: Option<Range>,
+    /// Repetition interval, in seconds
+    pub interval: Range,
+}
+
+struct Sample {
+    /// PCR time from the start of the measurement
+    clock: u64,
+    /// Byte position in the stream
+    position: u64,
+    interval: f64,
+    jitter: Option<f64>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Tables the PCR PIDs were taken from
+    generation: Option<u64>,
+    pcr_pids: Vec<u16>,
+    /// PID followed, found in the stream when no table lists one
+    pid: Option<u16>,
+    /// Bytes inspected
+    position: u64,
+    /// Last PCR, with the time it stands for and its byte position
+    last: Option<(u64, u64, u64)>,
+    samples: VecDeque<Sample>,
+    jitter: Histogram,
+    interval: Histogram,
+}
+
+impl State {
+    fn start_over(&mut self) {
+        self.last = None;
+        self.samples.clear();
+    }
+
+    fn pcr(&mut self, pcr: u64, discontinuity: bool) {
+        let position = self.position;
+        let step = match self.last {
+            Some((last_pcr, _, _)) if !discontinuity => ts::pcr_step(last_pcr, pcr),
+            _ => 0,
+        };
+        let (clock, last_position) = match self.last {
+            Some((_, clock, last_position)) if step > 0 && step <= MAX_STEP => (clock, last_position),
+            _ => {
+                self.samples.clear();
+                self.last = Some((pcr, 0, position));
+                return;
+            }
+        };
+
+        // The rate of the PCRs before, in bytes per tick of the clock
+        let jitter = self.samples.front()
+            .filter(|first| clock > first.clock && last_position > first.position)
+            .map(|first| {
+                let rate = (last_position - first.position) as f64 / (clock - first.clock) as f64;
+                let expected = (position - last_position) as f64 / rate;
+                (step as f64 - expected).abs() / ts::PCR_HZ as f64
+            });
+        let interval = step as f64 / ts::PCR_HZ as f64;
+        self.interval.observe(&INTERVAL_BUCKETS, interval);
+        if let Some(jitter) = jitter {
+            self.jitter.observe(&JITTER_BUCKETS, jitter);
+        }
+
+        if self.samples.is_empty() {
+            self.samples.push_back(Sample { clock, position: last_position, interval: 0.0, jitter: None });
+        }
+        let clock = clock + step;
+        self.samples.push_back(Sample { clock, position, interval, jitter });
+        while self.samples.front().is_some_and(|first| clock - first.clock > WINDOW) {
+            self.samples.pop_front();
+        }
+        self.last = Some((pcr, clock, position));
+    }
+
+    fn summary(&self) -> Option<Summary> {
+        let pid = self.pid?;
+        // The first sample only anchors the others
+        let measured = self.samples.iter().skip(1);
+        let interval = Range::of(measured.clone().map(|s| s.interval))?;
+        let (first, last) = (self.samples.front()?, self.samples.back()?);
+        let mux_rate_bps = ((last.position - first.position) as f64 * 8.0 * ts::PCR_HZ as f64 / (last.clock - first.clock) as f64).round() as u64;
+
+        Some(Summary { pid, mux_rate_bps, jitter: Range::of(measured.filter_map(|s| s.jitter)), interval })
+    }
+}
+
+/// Whether the adaptation field signals a discontinuity
+fn discontinuity(pkt: &[u8]) -> bool {
+    pkt[3] & 0x20 != 0 && pkt[4] > 0 && pkt[5] & 0x80 != 0
+}
+
+#[derive(Default)]
+pub struct Timing {
+    state: Mutex<State>,
+}
+
+impl Timing {
+    /// Measure the PCRs of an aligned chunk, the PCR PIDs being refreshed
+    /// whenever the tables change
+    pub fn inspect<F>(&self, chunk: &[u8], generation: u64, programs: F)
+        where F: FnOnce() -> Vec<Program>
+    {
+        if !ts::is_aligned(chunk) {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.generation != Some(generation) {
+            state.pcr_pids = programs().iter().filter_map(|p| p.pcr_pid).collect();
+            state.generation = Some(generation);
+            if state.pcr_pids.first().is_some_and(|&pid| state.pid != Some(pid)) {
+                state.pid = state.pcr_pids.first().cloned();
+                state.start_over();
+            }
+        }
+
+        for pkt in chunk.chunks(ts::PACKET_SIZE) {
+            if pkt[1] & 0x80 == 0 {
+                if let Some((pid, pcr)) = ts::pcr(pkt) {
+                    if state.pid.is_none() && state.pcr_pids.is_empty() {
+                        state.pid = Some(pid);
+                    }
+                    if state.pid == Some(pid) {
+                        state.pcr(pcr, discontinuity(pkt));
+                    }
+                }
+            }
+            state.position += ts::PACKET_SIZE as u64;
+        }
+    }
+
+    /// A new stream starts, its PCRs are unrelated to the previous one
+    pub fn restart(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.generation = None;
+        state.pcr_pids.clear();
+        state.pid = None;
+        state.start_over();
+    }
+
+    /// The timing over the window, none without PCRs
+    pub fn summary(&self) -> Option<Summary> {
+        self.state.lock().unwrap().summary()
+    }
+
+    /// Jitter and repetition interval histograms, since the start
+    pub fn histograms(&self) -> (Histogram, Histogram) {
+        let state = self.state.lock().unwrap();
+        (state.jitter.clone(), state.interval.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ts::tests::{packet, pcr_packet};
+
+    /// A PCR and 9 other packets every 40ms, with `error` ticks added to each PCR
+    fn stream(timing: &Timing, start: u64, count: u64, error: impl Fn(u64) -> u64) {
+        for i in 0..count {
+            let pcr = (start + i * ts::PCR_HZ / 25 + error(i)) % ts::PCR_WRAP;
+            let mut chunk = pcr_packet(0x100, pcr);
+            for _ in 0..9 {
+                chunk.extend(packet(0x101, 0x10));
+            }
+            timing.inspect(&chunk, 0, Vec::new);
+        }
+    }
+
+    #[test]
+    fn steady_across_the_wrap_around() {
+        let timing = Timing::default();
+        assert_eq!(timing.summary(), None);
+
+        // Wraps around after 5 seconds
+        stream(&timing, ts::PCR_WRAP - 5 * ts::PCR_HZ, 100, |_| 0);
+
+        let summary = timing.summary().unwrap();
+        assert_eq!(summary.pid, 0x100);
+        assert_eq!(summary.mux_rate_bps, 10 * 188 * 8 * 25);
+        let jitter = summary.jitter.unwrap();
+        assert!(jitter.max < 1e-9, "{:?}", jitter);
+        assert!((summary.interval.avg - 0.04).abs() < 1e-9);
+
+        let (jitter, interval) = timing.histograms();
+        assert_eq!((jitter.count, jitter.counts[0]), (98, 98));
+        assert_eq!((interval.count, interval.counts[2]), (99, 99));
+    }
+
+    #[test]
+    fn jitter_and_discontinuities() {
+        let timing = Timing::default();
+        // A PCR 2µs late
+        stream(&timing, 0, 50, |i| if i == 30 { 54 } else { 0 });
+
+        let jitter = timing.summary().unwrap().jitter.unwrap();
+        assert!(jitter.max > 1.9e-6 && jitter.max < 4.1e-6, "{:?}", jitter);
+        assert!(jitter.min < 0.1e-6, "{:?}", jitter);
+
+        // Back in time, measured anew
+        stream(&timing, 0, 1, |_| 0);
+        assert_eq!(timing.summary(), None);
+
+        timing.restart();
+        for _ in 0..10 {
+            timing.inspect(&packet(0x101, 0x10), 1, Vec::new);
+        }
+        assert_eq!(timing.summary(), None);
+    }
+}
@@ -0,0 +1,245 @@
+//! Producer looping over a local file
+//!
+//! The chunks are sent at the rate given or, without one, following the PCR
+//! of the first program carrying it.
+
+use tokio::sync::oneshot;
+use tokio::time::{self, Sleep};
+use futures::prelude::*;
+use bytes::Bytes;
+use log::{error, info};
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::{OneShotSharedRx, OneShotTx, Shared};
+use crate::fanout::Fanout;
+use crate::ts;
+
+/// How often a paused playback checks whether the producer is gone
+const PAUSE_CHECK: Duration = Duration::from_millis(100);
+
+/// Larger PCR steps are discontinuities, e.g. the file looping
+const MAX_PCR_STEP: u64 = ts::PCR_HZ;
+
+/// When every chunk is due
+pub struct Pacer {
+    bitrate: Option<u64>,
+    start: Instant,
+    /// Stream time sent so far
+    elapsed: Duration,
+    /// PID followed and its last PCR
+    pcr: Option<(u16, u64)>,
+}
+
+impl Pacer {
+    pub fn new(bitrate: Option<u64>) -> Self {
+        Pacer {
+            bitrate,
+            start: Instant::now(),
+            elapsed: Duration::from_secs(0),
+            pcr: None,
+        }
+    }
+
+    /// Start over from now
+    fn reset(&mut self) {
+        *self = Pacer::new(self.bitrate);
+    }
+
+    fn advance_pcr(&mut self, chunk: &[u8]) {
+        for (pid, pcr) in chunk.chunks(ts::PACKET_SIZE).filter_map(ts::pcr) {
+            match self.pcr {
+                Some((followed, last)) if followed == pid => {
+                    let step = ts::pcr_step(last, pcr);
+                    if step <= MAX_PCR_STEP {
+                        self.elapsed += Duration::from_nanos(step * 1000 / 27);
+                    }
+                    self.pcr = Some((pid, pcr));
+                }
+                Some(_) => (),
+                None => self.pcr = Some((pid, pcr)),
+            }
+        }
+    }
+
+    /// Time `chunk` is due
+    pub fn next(&mut self, chunk: &[u8]) -> Instant {
+        match self.bitrate {
+            Some(bitrate) => self.elapsed += Duration::from_secs_f64(chunk.len() as f64 * 8.0 / bitrate as f64),
+            None => self.advance_pcr(chunk),
+        }
+
+        self.start + self.elapsed
+    }
+}
+
+/// Loop over the file, pausing while a producer is connected
+struct Player {
+    file: File,
+    path: PathBuf,
+    state: Arc<Mutex<Shared>>,
+    fanout: Arc<Fanout>,
+    chunk_size: usize,
+    pacer: Pacer,
+    delay: Pin<Box<Sleep>>,
+    /// Next chunk, sent once the delay expires
+    pending: Option<Bytes>,
+    paused: bool,
+    /// Dropping it wakes up the consumers
+    _done: OneShotTx,
+}
+
+impl Player {
+    fn new(path: &Path, state: Arc<Mutex<Shared>>, buffer_size: usize, bitrate: Option<u64>, done: OneShotTx) -> io::Result<Self> {
+        let fanout = state.lock().unwrap().fanout.clone();
+
+        Ok(Player {
+            file: File::open(path)?,
+            path: path.to_owned(),
+            state,
+            fanout,
+            chunk_size: ts::chunk_size(buffer_size),
+            pacer: Pacer::new(bitrate),
+            delay: Box::pin(time::sleep_until(time::Instant::now())),
+            pending: None,
+            paused: false,
+            _done: done,
+        })
+    }
+
+    /// Read the next whole packets, going back to the start at the end of the file
+    fn read_chunk(&mut self) -> io::Result<Bytes> {
+        let mut buf = vec![0; self.chunk_size];
+        let mut len = read_packets(&mut self.file, &mut buf)?;
+
+        if len == 0 {
+            self.file.seek(SeekFrom::Start(0))?;
+            len = read_packets(&mut self.file, &mut buf)?;
+            if len == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "no whole packet in the file"));
+            }
+        }
+
+        buf.truncate(len);
+        Ok(Bytes::from(buf))
+    }
+}
+
+/// Fill `buf` from `file` up to its end, returning the length of the whole packets read
+///
+/// A trailing partial packet is left out.
+pub fn read_packets(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+
+    while len < buf.len() {
+        match file.read(&mut buf[len..])? {
+            0 => return Ok(len - len % ts::PACKET_SIZE),
+            n => len += n,
+        }
+    }
+
+    Ok(len)
+}
+
+impl Future for Player {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let producer = self.state.lock().unwrap().session.as_ref().map(|s| s.addr);
+
+            if let Some(addr) = producer {
+                if !self.paused {
+                    info!("Pausing {}, {:?} is streaming", self.path.display(), addr);
+                    self.paused = true;
+                    self.pending = None;
+                }
+                self.delay.as_mut().reset(time::Instant::now() + PAUSE_CHECK);
+                ready!(self.delay.as_mut().poll(cx));
+                continue;
+            }
+
+            if self.paused {
+                info!("Resuming {}", self.path.display());
+                self.paused = false;
+                self.pacer.reset();
+            }
+
+            if self.pending.is_none() {
+                let chunk = self.read_chunk()?;
+                let next = self.pacer.next(&chunk);
+                self.delay.as_mut().reset(time::Instant::from_std(next));
+                self.pending = Some(chunk);
+            }
+
+            ready!(self.delay.as_mut().poll(cx));
+
+            if let Some(chunk) = self.pending.take() {
+                self.fanout.broadcast(&chunk);
+            }
+        }
+    }
+}
+
+/// Play `path` in a loop for as long as the restreamer runs, failing right
+/// away if it cannot be opened
+///
+/// `on_start` is called once with the consumer session, it ends when the
+/// returned future is dropped.
+pub fn play<F>(path: PathBuf, state: Arc<Mutex<Shared>>, buffer_size: usize, bitrate: Option<u64>, on_start: F) -> io::Result<impl Future<Output = ()>>
+where
+    F: FnOnce(OneShotSharedRx),
+{
+    let (done, rx) = oneshot::channel::<()>();
+    let player = Player::new(&path, state, buffer_size, bitrate, done)?;
+
+    Ok(async move {
+        info!("Playing {}", path.display());
+        on_start(rx.shared());
+
+        if let Err(e) = player.await {
+            error!("Playback failed: {}", e);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ts::tests::pcr_packet;
+
+    #[test]
+    fn pcr() {
+        assert_eq!(ts::pcr(&pcr_packet(0x100, 123_456_789)), Some((0x100, 123_456_789)));
+        assert_eq!(ts::pcr(&[ts::SYNC_BYTE; ts::PACKET_SIZE][..4]), None);
+    }
+
+    #[test]
+    fn bitrate() {
+        let mut pacer = Pacer::new(Some(8_000));
+        let start = pacer.start;
+
+        assert_eq!(pacer.next(&[0; 500]), start + Duration::from_millis(500));
+        assert_eq!(pacer.next(&[0; 500]), start + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn follows_the_pcr() {
+        let mut pacer = Pacer::new(None);
+        let start = pacer.start;
+
+        assert_eq!(pacer.next(&pcr_packet(0x100, 1000)), start);
+        // Another program is ignored
+        assert_eq!(pacer.next(&pcr_packet(0x200, 5_000_000)), start);
+        assert_eq!(pacer.next(&pcr_packet(0x100, 1000 + ts::PCR_HZ / 10)), start + Duration::from_millis(100));
+        // Looping back to the start of the file
+        assert_eq!(pacer.next(&pcr_packet(0x100, 1000)), start + Duration::from_millis(100));
+        assert_eq!(pacer.next(&pcr_packet(0x100, 1000 + ts::PCR_HZ / 10)), start + Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,156 @@
+//! Read buffers of the producers, recycled once the chunks split off them are gone
+//!
+//! The chunks fanned out share the buffer they were read into, so a buffer can
+//! only be read into again once the consumer queues, the burst buffer and the
+//! rest let go of all its chunks. The buffers handed back wait in a FIFO: the
+//! oldest one is read into again if it is free, a new one is allocated
+//! otherwise. Up to `--read-pool` buffers are kept, the others are freed along
+//! with their last chunk, as is the oldest one when it is still in use while
+//! the pool is full. The free ones the stream no longer needs are dropped as
+//! the pool is drawn from.
+
+use bytes::{Bytes, BytesMut};
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+pub struct BufferPool {
+    /// Oldest first, free once no chunk of theirs is left
+    buffers: Mutex<VecDeque<Bytes>>,
+    max: AtomicUsize,
+    allocated: AtomicU64,
+    reused: AtomicU64,
+}
+
+impl BufferPool {
+    pub fn new(max: usize) -> Self {
+        BufferPool {
+            buffers: Mutex::new(VecDeque::new()),
+            max: AtomicUsize::new(max),
+            allocated: AtomicU64::new(0),
+            reused: AtomicU64::new(0),
+        }
+    }
+
+    /// Keep up to `max` buffers, 0 to allocate every one
+    pub fn set_max(&self, max: usize) {
+        self.max.store(max, Ordering::Relaxed);
+    }
+
+    /// An empty buffer with room for `size` bytes, the oldest one if it is free
+    pub fn get(&self, size: usize) -> BytesMut {
+        let mut buffers = self.buffers.lock().unwrap();
+        let max = self.max.load(Ordering::Relaxed);
+        while buffers.len() > max {
+            buffers.pop_front();
+        }
+
+        while let Some(oldest) = buffers.pop_front() {
+            match oldest.try_into_mut() {
+                Ok(mut buf) => {
+                    // Free as well, one less is needed
+                    if let Some(next) = buffers.pop_front() {
+                        if let Err(next) = next.try_into_mut() {
+                            buffers.push_front(next);
+                        }
+                    }
+                    // The whole buffer again, no chunk uses it
+                    buf.reserve(size);
+                    self.reused.fetch_add(1, Ordering::Relaxed);
+                    return buf;
+                }
+                // A chunk is kept for long, given up on once the pool is full
+                Err(_) if buffers.len() + 1 >= max => (),
+                Err(oldest) => {
+                    buffers.push_front(oldest);
+                    break;
+                }
+            }
+        }
+
+        self.allocated.fetch_add(1, Ordering::Relaxed);
+        BytesMut::with_capacity(size)
+    }
+
+    /// Take back a buffer read into no more, to hand it out again once its
+    /// chunks are gone
+    pub fn put(&self, mut buf: BytesMut) {
+        buf.clear();
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max.load(Ordering::Relaxed) {
+            buffers.push_back(buf.freeze());
+        }
+    }
+
+    /// Buffers kept, free or not
+    pub fn len(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+
+    /// Buffers allocated and buffers reused so far
+    pub fn counts(&self) -> (u64, u64) {
+        (self.allocated.load(Ordering::Relaxed), self.reused.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BufMut;
+
+    /// Read `n` chunks of 100 bytes into `buf`, splitting them off
+    fn read(buf: &mut BytesMut, n: usize) -> Vec<Bytes> {
+        (0..n).map(|_| {
+            buf.put(&[0x47; 100][..]);
+            buf.split_to(100).freeze()
+        }).collect()
+    }
+
+    #[test]
+    fn reused_once_free() {
+        let pool = BufferPool::new(4);
+
+        let mut buf = pool.get(1000);
+        let ptr = buf.as_ptr();
+        let chunks = read(&mut buf, 10);
+        pool.put(buf);
+
+        // Its chunks are still out there
+        let mut other = pool.get(1000);
+        assert_ne!(other.as_ptr(), ptr);
+        drop(chunks);
+        let _ = read(&mut other, 1);
+        pool.put(other);
+
+        let buf = pool.get(1000);
+        assert_eq!((buf.as_ptr(), buf.capacity(), buf.len()), (ptr, 1000, 0));
+        assert_eq!(pool.counts(), (2, 1));
+        // The other one was free too, and dropped
+        assert_eq!(pool.len(), 0);
+    }
+
+    #[test]
+    fn bounded() {
+        let pool = BufferPool::new(3);
+        let mut held = Vec::new();
+
+        // Peers coming and going, some chunks held for long
+        for n in 0..100 {
+            let mut buf = pool.get(1000);
+            let chunks = read(&mut buf, 5);
+            if n % 10 == 0 {
+                held.extend(chunks);
+            }
+            pool.put(buf);
+            assert!(pool.len() <= 3);
+        }
+        let (allocated, reused) = pool.counts();
+        assert_eq!(allocated + reused, 100);
+        assert!(reused >= 75, "{} reused", reused);
+
+        pool.set_max(0);
+        drop(pool.get(1000));
+        assert_eq!(pool.len(), 0);
+    }
+}
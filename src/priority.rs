@@ -0,0 +1,79 @@
+//! Priority tiers of the consumers, the last ones to shed load
+//!
+//! `--consumer-priority TIER=MATCH` puts the consumers connecting from an
+//! address block, or to a local port, in a tier from 1 to 255, the others are
+//! in tier 0. While the total rate limit is spent, the higher tiers take their
+//! turns first and the lower ones fall behind, their queues dropping what they
+//! cannot hold. A consumer that would be refused for a full server takes the
+//! place of one in a lower tier instead.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::acl::Cidr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Match {
+    Addr(Cidr),
+    /// Local port the consumer connected to
+    Port(u16),
+}
+
+/// Tier given to the consumers matching a rule
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Priority {
+    pub tier: u8,
+    on: Match,
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    /// `2=10.0.0.0/8` or `1=:8081`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Invalid priority {}, expected TIER=CIDR or TIER=:PORT", s);
+        let (tier, on) = s.split_once('=').ok_or_else(err)?;
+        let tier = tier.parse().map_err(|_| err())?;
+        let on = match on.strip_prefix(':') {
+            Some(port) => Match::Port(port.parse().map_err(|_| err())?),
+            None => Match::Addr(on.parse()?),
+        };
+
+        Ok(Priority { tier, on })
+    }
+}
+
+impl Priority {
+    fn matches(&self, ip: IpAddr, port: Option<u16>) -> bool {
+        match self.on {
+            Match::Addr(ref cidr) => cidr.contains(ip),
+            Match::Port(p) => port == Some(p),
+        }
+    }
+}
+
+/// The highest tier of the rules a consumer from `ip`, connected to the local
+/// `port`, matches, 0 if none
+pub fn tier(rules: &[Priority], ip: IpAddr, port: Option<u16>) -> u8 {
+    rules.iter().filter(|rule| rule.matches(ip, port)).map(|rule| rule.tier).max().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highest_match() {
+        let rules: Vec<Priority> = ["2=10.0.0.0/8", "1=:8081", "3=10.1.0.0/16"].iter().map(|s| s.parse().unwrap()).collect();
+        let ip = |s: &str| s.parse().unwrap();
+
+        assert_eq!(tier(&rules, ip("192.0.2.1"), Some(8080)), 0);
+        assert_eq!(tier(&rules, ip("192.0.2.1"), Some(8081)), 1);
+        assert_eq!(tier(&rules, ip("10.2.0.1"), Some(8081)), 2);
+        assert_eq!(tier(&rules, ip("10.1.0.1"), None), 3);
+
+        assert!("2".parse::<Priority>().is_err());
+        assert!("256=10.0.0.0/8".parse::<Priority>().is_err());
+        assert!("1=:http".parse::<Priority>().is_err());
+    }
+}
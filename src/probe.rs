@@ -0,0 +1,397 @@
+//! Inspection of a stream from the outside, for `restream probe`
+//!
+//! The stream is read the way a consumer reads it, for a few seconds, and
+//! goes through the packet size detection, program tables and continuity
+//! checks of the producer input.
+
+use bytes::{Buf, BytesMut};
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+use crate::cc::{Continuity, PidCounters};
+use crate::input::Input;
+use crate::normalize::Normalizer;
+use crate::psi::{Program, Programs};
+use crate::ts;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Read at once, the largest datagram included
+const READ_SIZE: usize = 65536;
+/// A longer step between two PCRs is a discontinuity, left out of the stream time
+const MAX_PCR_STEP: u64 = 10 * ts::PCR_HZ;
+
+/// What was found in the stream
+#[derive(Debug)]
+pub struct Report {
+    packet_size: Option<usize>,
+    programs: Vec<Program>,
+    pids: Vec<(u16, PidCounters)>,
+    bytes: u64,
+    /// Time the bytes were read over, unknown for a file without PCR
+    duration: Option<Duration>,
+    sync_losses: u64,
+}
+
+impl Report {
+    fn cc_errors(&self) -> u64 {
+        self.pids.iter().map(|&(_, c)| c.discontinuities + c.duplicates + c.transport_errors).sum()
+    }
+
+    fn bitrate(&self) -> Option<f64> {
+        let secs = self.duration?.as_secs_f64();
+        if secs > 0.0 {
+            Some(self.bytes as f64 * 8.0 / secs)
+        } else {
+            None
+        }
+    }
+
+    /// What is wrong with the stream, nothing if it is healthy
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.bytes == 0 {
+            problems.push("nothing received".to_owned());
+        } else if self.packet_size.is_none() {
+            problems.push("not an MPEG-TS stream".to_owned());
+        } else if self.programs.is_empty() {
+            problems.push("no PAT received".to_owned());
+        } else if self.programs.iter().any(|p| p.pcr_pid.is_none()) {
+            problems.push("PMT missing".to_owned());
+        }
+        if self.cc_errors() > 0 {
+            problems.push(format!("{} continuity errors", self.cc_errors()));
+        }
+        if self.sync_losses > 0 {
+            problems.push(format!("sync lost {} times", self.sync_losses));
+        }
+
+        problems
+    }
+
+    pub fn healthy(&self) -> bool {
+        self.problems().is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.packet_size {
+            Some(size) => writeln!(f, "Packet size: {} bytes", size)?,
+            None => writeln!(f, "Packet size: unknown")?,
+        }
+        match (self.bitrate(), self.duration) {
+            (Some(bitrate), Some(duration)) => writeln!(f, "Bitrate: {:.3} Mbit/s, {} bytes over {:.1}s",
+                                                        bitrate / 1e6, self.bytes, duration.as_secs_f64())?,
+            _ => writeln!(f, "Bitrate: unknown, {} bytes", self.bytes)?,
+        }
+
+        for program in &self.programs {
+            writeln!(f, "{}", program)?;
+        }
+        for &(pid, c) in &self.pids {
+            writeln!(f, "PID {:#06x}: {} packets, {} discontinuities, {} duplicates, {} transport errors",
+                     pid, c.packets, c.discontinuities, c.duplicates, c.transport_errors)?;
+        }
+        writeln!(f, "Continuity errors: {}", self.cc_errors())?;
+        writeln!(f, "Sync lost: {}", self.sync_losses)?;
+
+        let problems = self.problems();
+        if problems.is_empty() {
+            write!(f, "Healthy")
+        } else {
+            write!(f, "Unhealthy: {}", problems.join(", "))
+        }
+    }
+}
+
+/// Inspects the stream as it is read
+struct Prober {
+    normalizer: Normalizer,
+    /// Packets normalized but not inspected yet
+    packets: BytesMut,
+    programs: Programs,
+    continuity: Continuity,
+    bytes: u64,
+    /// The last packet was followed by another one
+    in_sync: bool,
+    sync_losses: u64,
+    /// PID the stream time is taken from, with its last PCR
+    pcr: Option<(u16, u64)>,
+    /// Stream time covered by the PCRs, in `PCR_HZ` units
+    pcr_span: u64,
+}
+
+impl Prober {
+    fn new() -> Self {
+        Prober {
+            normalizer: Normalizer::new(None, BytesMut::new()),
+            packets: BytesMut::new(),
+            programs: Programs::default(),
+            continuity: Continuity::default(),
+            bytes: 0,
+            in_sync: false,
+            sync_losses: 0,
+            pcr: None,
+            pcr_span: 0,
+        }
+    }
+
+    fn feed(&mut self, buf: &[u8]) {
+        self.bytes += buf.len() as u64;
+
+        let known = self.normalizer.size().is_some();
+        self.normalizer.raw().extend_from_slice(buf);
+        self.normalizer.normalize(&mut self.packets);
+        if known && self.normalizer.size().is_none() {
+            self.lost_sync();
+        }
+
+        // Not packets, as far as can be told
+        if self.normalizer.size().is_none() {
+            self.packets.clear();
+            return;
+        }
+        self.inspect();
+    }
+
+    fn lost_sync(&mut self) {
+        if self.in_sync {
+            self.sync_losses += 1;
+            self.in_sync = false;
+        }
+    }
+
+    /// Inspect the packets in sync, skipping to the next sync byte otherwise
+    fn inspect(&mut self) {
+        loop {
+            let len = ts::aligned_len(&self.packets, self.packets.len());
+            if len > 0 {
+                let chunk = self.packets.split_to(len);
+                self.programs.inspect(&chunk);
+                self.continuity.inspect(&chunk);
+                for pkt in chunk.chunks(ts::PACKET_SIZE) {
+                    if let Some((pid, pcr)) = ts::pcr(pkt) {
+                        self.on_pcr(pid, pcr);
+                    }
+                }
+                self.in_sync = true;
+            }
+
+            if self.packets.len() < ts::PACKET_SIZE {
+                return;
+            }
+
+            // Something else where the next packet should start
+            self.lost_sync();
+            match ts::sync_offset(&self.packets[1..]) {
+                Some(off) => self.packets.advance(off + 1),
+                None => self.packets.clear(),
+            }
+        }
+    }
+
+    fn on_pcr(&mut self, pid: u16, pcr: u64) {
+        match self.pcr {
+            Some((last_pid, last)) if last_pid == pid => {
+                let step = ts::pcr_step(last, pcr);
+                if step <= MAX_PCR_STEP {
+                    self.pcr_span += step;
+                }
+            }
+            Some(_) => return,
+            None => (),
+        }
+        self.pcr = Some((pid, pcr));
+    }
+
+    /// Stream time covered by the PCRs read so far
+    fn stream_time(&self) -> Duration {
+        Duration::from_nanos(self.pcr_span * 1000 / 27)
+    }
+
+    /// The report, over `duration` or else over the time covered by the PCRs
+    fn report(mut self, duration: Option<Duration>) -> Report {
+        self.normalizer.flush(&mut self.packets);
+        self.inspect();
+
+        let duration = duration.or_else(|| if self.pcr_span > 0 { Some(self.stream_time()) } else { None });
+
+        Report {
+            packet_size: self.normalizer.size(),
+            programs: self.programs.programs(),
+            pids: self.continuity.counters(),
+            bytes: self.bytes,
+            duration,
+            sync_losses: self.sync_losses,
+        }
+    }
+}
+
+/// The datagrams received, one read each
+struct Datagrams(UdpSocket);
+
+impl Read for Datagrams {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+}
+
+/// Bind to `addr`, joining its group if it is a multicast one
+fn datagrams(addr: &SocketAddr) -> io::Result<Datagrams> {
+    let socket = match addr.ip() {
+        IpAddr::V4(group) if group.is_multicast() => {
+            let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, addr.port()))?;
+            socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+            socket
+        }
+        IpAddr::V6(group) if group.is_multicast() => {
+            let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, addr.port()))?;
+            socket.join_multicast_v6(&group, 0)?;
+            socket
+        }
+        _ => UdpSocket::bind(addr)?,
+    };
+    Ok(Datagrams(socket))
+}
+
+/// Feed what `source` sends within `duration`
+fn read_for<R, T>(mut source: R, set_timeout: T, duration: Duration) -> io::Result<Report>
+    where R: Read,
+          T: Fn(&R, Option<Duration>) -> io::Result<()>
+{
+    let mut prober = Prober::new();
+    let mut buf = vec![0; READ_SIZE];
+    let start = Instant::now();
+
+    while let Some(left) = duration.checked_sub(start.elapsed()).filter(|left| *left > Duration::from_millis(0)) {
+        set_timeout(&source, Some(left))?;
+        match source.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => prober.feed(&buf[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => break,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(prober.report(Some(start.elapsed())))
+}
+
+/// Read `input` for `duration` and tell what it is made of
+///
+/// The TCP and Unix ones are connected to as a consumer, a file is read up to
+/// `duration` of stream time.
+pub fn probe(input: &Input, duration: Duration) -> io::Result<Report> {
+    match *input {
+        Input::Tcp(ref addr) => {
+            let socket = TcpStream::connect_timeout(addr, CONNECT_TIMEOUT)?;
+            read_for(socket, TcpStream::set_read_timeout, duration)
+        }
+        #[cfg(unix)]
+        Input::Unix(ref path) => {
+            let socket = UnixStream::connect(path)?;
+            read_for(socket, UnixStream::set_read_timeout, duration)
+        }
+        Input::Udp(ref addr) => read_for(datagrams(addr)?, |socket, timeout| socket.0.set_read_timeout(timeout), duration),
+        Input::File(ref path) => {
+            let mut file = File::open(path)?;
+            let mut prober = Prober::new();
+            let mut buf = vec![0; READ_SIZE];
+
+            while prober.stream_time() < duration {
+                match file.read(&mut buf)? {
+                    0 => break,
+                    n => prober.feed(&buf[..n]),
+                }
+            }
+            Ok(prober.report(None))
+        }
+        ref input => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("cannot probe {}", input))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psi::tests::{packets, pat, pmt};
+    use crate::ts::tests::pcr_packet;
+
+    /// A packet of `pid` with continuity counter `cc`, carrying `pcr` if set
+    fn packet(pid: u16, cc: u8, pcr: Option<u64>) -> Vec<u8> {
+        let mut pkt = match pcr {
+            Some(pcr) => pcr_packet(pid, pcr),
+            None => ts::tests::packet(pid, 0x10),
+        };
+        pkt[3] |= cc & 0x0f;
+        pkt
+    }
+
+    /// Tables then a second of video with a PCR every 100ms
+    fn stream() -> Vec<u8> {
+        let mut out = packets(0, 0, &pat(&[(1, 0x1000)]));
+        out.extend(packets(0x1000, 0, &pmt(1, 0x100, &[(0x1b, 0x100)])));
+        for cc in 0..=10u8 {
+            out.extend(packet(0x100, cc, Some(u64::from(cc) * ts::PCR_HZ / 10)));
+        }
+        out
+    }
+
+    #[test]
+    fn healthy_stream() {
+        let mut prober = Prober::new();
+        for chunk in stream().chunks(100) {
+            prober.feed(chunk);
+        }
+        assert_eq!(prober.stream_time(), Duration::from_secs(1));
+
+        let report = prober.report(None);
+        assert_eq!(report.packet_size, Some(188));
+        assert_eq!(report.programs.len(), 1);
+        assert_eq!(report.programs[0].pcr_pid, Some(0x100));
+        assert_eq!(report.bitrate(), Some(13.0 * 188.0 * 8.0));
+        assert!(report.healthy(), "{}", report);
+    }
+
+    #[test]
+    fn errors_found() {
+        let mut input = stream();
+        // A packet lost, then garbage between two others
+        input.drain(5 * ts::PACKET_SIZE..6 * ts::PACKET_SIZE);
+        input.splice(7 * ts::PACKET_SIZE..7 * ts::PACKET_SIZE, vec![0; 50]);
+
+        let mut prober = Prober::new();
+        prober.feed(&input);
+        let report = prober.report(Some(Duration::from_secs(2)));
+        assert_eq!(report.cc_errors(), 1);
+        assert_eq!(report.sync_losses, 1);
+        assert_eq!(report.problems(), vec!["1 continuity errors", "sync lost 1 times"]);
+
+        let mut prober = Prober::new();
+        prober.feed(b"GET / HTTP/1.1\r\n\r\n");
+        let report = prober.report(Some(Duration::from_secs(2)));
+        assert_eq!(report.problems(), vec!["not an MPEG-TS stream"]);
+        assert_eq!(Prober::new().report(None).problems(), vec!["nothing received"]);
+    }
+
+    #[test]
+    fn dvb_packets() {
+        let mut input = Vec::new();
+        for pkt in stream().chunks(ts::PACKET_SIZE) {
+            input.extend_from_slice(pkt);
+            input.extend_from_slice(&[0; 16]);
+        }
+
+        let mut prober = Prober::new();
+        prober.feed(&input);
+        let report = prober.report(None);
+        assert_eq!(report.packet_size, Some(204));
+        assert!(report.healthy(), "{}", report);
+    }
+}
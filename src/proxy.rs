@@ -0,0 +1,226 @@
+//! PROXY protocol, for the peers connecting through a load balancer
+//!
+//! The balancer sends a header with the address of the client before any
+//! data, in text (v1) or binary (v2). The header is read byte for byte, so
+//! whatever follows it, a TLS handshake or the stream, stays in the socket.
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+use std::io::{self, IoSlice};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::str;
+use std::task::{Context, Poll};
+
+use crate::net::{self, Socket};
+
+/// Start of a v2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest v1 header, CRLF included
+const V1_MAX: usize = 107;
+
+/// A TCP connection, tracked by the address of the client behind the balancer if any
+pub struct Proxied {
+    stream: TcpStream,
+    /// From the PROXY header, unless the balancer checked its own connection
+    source: Option<SocketAddr>,
+}
+
+impl Proxied {
+    /// A connection without header
+    pub fn direct(stream: TcpStream) -> Self {
+        Proxied { stream, source: None }
+    }
+}
+
+impl AsyncRead for Proxied {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+// The vectored write too, the default takes a buffer at a time
+impl AsyncWrite for Proxied {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(mut self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+impl Socket for Proxied {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self.source {
+            Some(addr) => Ok(addr),
+            None => net::peer_addr(&self.stream),
+        }
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.stream.shutdown_write()
+    }
+
+    fn vectored(&self) -> bool {
+        true
+    }
+
+    fn tcp(&self) -> Option<&TcpStream> {
+        Some(&self.stream)
+    }
+
+    fn plain_tcp(&mut self) -> Option<&mut TcpStream> {
+        Some(&mut self.stream)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid PROXY header, {}", msg))
+}
+
+/// Read the PROXY header of a connection
+pub async fn read_header(mut stream: TcpStream) -> io::Result<Proxied> {
+    let mut start = [0; 8];
+    stream.read_exact(&mut start).await?;
+
+    let source = if start[..] == V2_SIGNATURE[..8] {
+        read_v2(&mut stream).await?
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(&mut stream, start.to_vec()).await?
+    } else {
+        return Err(invalid("no signature"));
+    };
+
+    Ok(Proxied { stream, source })
+}
+
+async fn read_v1(stream: &mut TcpStream, mut line: Vec<u8>) -> io::Result<Option<SocketAddr>> {
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX {
+            return Err(invalid("no CRLF"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    parse_v1(&line)
+}
+
+/// The rest of a v2 header, past the first 8 bytes
+async fn read_v2(stream: &mut TcpStream) -> io::Result<Option<SocketAddr>> {
+    let mut head = [0; 8];
+    stream.read_exact(&mut head).await?;
+    if head[..4] != V2_SIGNATURE[8..] {
+        return Err(invalid("no signature"));
+    }
+    let len = usize::from(u16::from_be_bytes([head[6], head[7]]));
+
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body).await?;
+    parse_v2(head[4], head[5], &body)
+}
+
+/// Source address of a v1 header, `None` for UNKNOWN
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("not text"))?;
+    let fields: Vec<_> = line.split(' ').collect();
+
+    match fields.get(1) {
+        Some(&"UNKNOWN") => return Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") if fields.len() == 6 => (),
+        _ => return Err(invalid(line)),
+    }
+
+    let ip: IpAddr = fields[2].parse().map_err(|_| invalid(line))?;
+    let port: u16 = fields[4].parse().map_err(|_| invalid(line))?;
+    if ip.is_ipv4() != (fields[1] == "TCP4") {
+        return Err(invalid(line));
+    }
+
+    Ok(Some(net::canonical(SocketAddr::new(ip, port))))
+}
+
+/// Source address of a v2 header, `None` for LOCAL or an unspecified family
+fn parse_v2(version_command: u8, family: u8, body: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unknown version"));
+    }
+    match version_command & 0x0f {
+        // Health checks of the balancer itself
+        0 => return Ok(None),
+        1 => (),
+        _ => return Err(invalid("unknown command")),
+    }
+
+    let addr = match family >> 4 {
+        0 => return Ok(None),
+        1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            SocketAddr::new(ip.into(), u16::from_be_bytes([body[8], body[9]]))
+        }
+        2 if body.len() >= 36 => {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&body[..16]);
+            SocketAddr::new(Ipv6Addr::from(ip).into(), u16::from_be_bytes([body[32], body[33]]))
+        }
+        1 | 2 => return Err(invalid("addresses too short")),
+        // Unix sockets, nothing to track the client by
+        3 => return Ok(None),
+        _ => return Err(invalid("unknown address family")),
+    };
+
+    Ok(Some(net::canonical(addr)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v1() {
+        assert_eq!(parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n").unwrap(),
+                   Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").unwrap(),
+                   Some("[2001:db8::1]:56324".parse().unwrap()));
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+
+        assert!(parse_v1(b"PROXY TCP4 2001:db8::1 198.51.100.1 56324 443\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n").is_err());
+        assert!(parse_v1(b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n").is_err());
+    }
+
+    #[test]
+    fn v2() {
+        let tcp4 = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        assert_eq!(parse_v2(0x21, 0x11, &tcp4).unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        // A TLV following the addresses
+        let mut tlv = tcp4.to_vec();
+        tlv.extend_from_slice(&[0x04, 0, 1, 0]);
+        assert_eq!(parse_v2(0x21, 0x11, &tlv).unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+
+        let mut tcp6 = vec![0; 36];
+        tcp6[0] = 0x20;
+        tcp6[1] = 0x01;
+        tcp6[15] = 1;
+        tcp6[32..34].copy_from_slice(&[0xdc, 0x04]);
+        assert_eq!(parse_v2(0x21, 0x21, &tcp6).unwrap(), Some("[2001::1]:56324".parse().unwrap()));
+
+        assert_eq!(parse_v2(0x20, 0x00, &[]).unwrap(), None);
+        assert!(parse_v2(0x11, 0x11, &tcp4).is_err());
+        assert!(parse_v2(0x21, 0x11, &tcp4[..8]).is_err());
+    }
+}
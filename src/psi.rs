@@ -0,0 +1,527 @@
+//! Program tables of the stream broadcast
+//!
+//! The PAT and PMT sections are assembled from PID 0 and the PMT PIDs it
+//! lists, the programs are logged whenever they change.
+
+use log::info;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::ts;
+
+const PAT_PID: u16 = 0;
+pub const PAT_TABLE_ID: u8 = 0x00;
+pub const PMT_TABLE_ID: u8 = 0x02;
+/// Longest section, header included
+const MAX_SECTION: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stream {
+    pub pid: u16,
+    pub stream_type: u8,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+    pub number: u16,
+    pub pmt_pid: u16,
+    /// Known once the PMT is received
+    pub pcr_pid: Option<u16>,
+    pub streams: Vec<Stream>,
+}
+
+/// Whether the stream type is a video one, whose keyframes a stream can start on
+fn is_video(stream_type: u8) -> bool {
+    matches!(stream_type, 0x01 | 0x02 | 0x10 | 0x1b | 0x24)
+}
+
+/// Common names of the stream types
+fn type_name(stream_type: u8) -> &'static str {
+    match stream_type {
+        0x01 => "MPEG-1 video",
+        0x02 => "MPEG-2 video",
+        0x03 => "MPEG-1 audio",
+        0x04 => "MPEG-2 audio",
+        0x06 => "private data",
+        0x0f => "AAC",
+        0x11 => "AAC LATM",
+        0x15 => "metadata",
+        0x1b => "H.264",
+        0x24 => "HEVC",
+        0x81 => "AC-3",
+        0x86 => "SCTE-35",
+        0x87 => "E-AC-3",
+        _ => "unknown",
+    }
+}
+
+impl Program {
+    /// The PMT, PCR and elementary stream PIDs
+    pub fn pids(&self) -> Vec<u16> {
+        let mut pids = vec![self.pmt_pid];
+        pids.extend(self.pcr_pid);
+        pids.extend(self.streams.iter().map(|s| s.pid));
+        pids
+    }
+
+    /// The first video stream
+    pub fn video_pid(&self) -> Option<u16> {
+        self.streams.iter().find(|s| is_video(s.stream_type)).map(|s| s.pid)
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Program {}: PMT PID {:#06x}", self.number, self.pmt_pid)?;
+        if let Some(pcr_pid) = self.pcr_pid {
+            write!(f, ", PCR PID {:#06x}", pcr_pid)?;
+        }
+        for (i, s) in self.streams.iter().enumerate() {
+            write!(f, "{} {:#06x} {:#04x} ({})",
+                   if i == 0 { ", streams" } else { "," }, s.pid, s.stream_type, type_name(s.stream_type))?;
+        }
+        Ok(())
+    }
+}
+
+/// CRC-32/MPEG-2, zero over a whole section when it is intact
+pub fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0xffff_ffff, |mut crc, &b| {
+        crc ^= u32::from(b) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+/// Wrap a table body with its header and CRC, as the current single section
+pub fn section(table_id: u8, id: u16, version: u8, body: &[u8]) -> Vec<u8> {
+    let len = 5 + body.len() + 4;
+    let mut s = vec![table_id, 0xb0 | (len >> 8) as u8, len as u8, (id >> 8) as u8, id as u8, 0xc1 | (version & 0x1f) << 1, 0, 0];
+    s.extend_from_slice(body);
+    let crc = crc32(&s);
+    s.extend_from_slice(&crc.to_be_bytes());
+    s
+}
+
+/// The packets of `pid` carrying a whole section, their continuity counter from 0
+pub fn packetize(pid: u16, section: &[u8]) -> Vec<u8> {
+    let mut payload = vec![0];
+    payload.extend_from_slice(section);
+
+    let mut out = Vec::with_capacity(payload.len().div_ceil(ts::PACKET_SIZE - 4) * ts::PACKET_SIZE);
+    for (i, part) in payload.chunks(ts::PACKET_SIZE - 4).enumerate() {
+        let pusi = if i == 0 { 0x40 } else { 0 };
+        out.extend_from_slice(&[ts::SYNC_BYTE, pusi | (pid >> 8) as u8, pid as u8, 0x10 | (i as u8 & 0x0f)]);
+        out.extend_from_slice(part);
+        out.resize(out.len() + ts::PACKET_SIZE - 4 - part.len(), 0xff);
+    }
+    out
+}
+
+/// Length of the section at the start of `buf`, once its header is there
+pub fn section_len(buf: &[u8]) -> Option<usize> {
+    if buf.len() < 3 {
+        return None;
+    }
+    Some(3 + ((usize::from(buf[1] & 0x0f) << 8) | usize::from(buf[2])))
+}
+
+/// Table section split over the packets of a PID
+#[derive(Default)]
+pub struct Assembler {
+    buf: Vec<u8>,
+    /// A section start was seen, the following payloads continue it
+    started: bool,
+}
+
+impl Assembler {
+    /// Feed the payload of a packet, returning the sections completed
+    pub fn push(&mut self, payload: &[u8], unit_start: bool) -> Vec<Vec<u8>> {
+        let mut sections = Vec::new();
+
+        let rest = if unit_start {
+            let pointer = match payload.first() {
+                Some(&pointer) => usize::from(pointer),
+                None => return sections,
+            };
+            if payload.len() < 1 + pointer {
+                self.reset();
+                return sections;
+            }
+            // The end of the previous section comes first
+            if self.started && !self.buf.is_empty() {
+                self.buf.extend_from_slice(&payload[1..1 + pointer]);
+                self.take(&mut sections);
+            }
+            self.reset();
+            self.started = true;
+            &payload[1 + pointer..]
+        } else if self.started {
+            payload
+        } else {
+            return sections;
+        };
+
+        self.buf.extend_from_slice(rest);
+        self.take(&mut sections);
+
+        sections
+    }
+
+    /// Move the complete sections out of the buffer
+    fn take(&mut self, sections: &mut Vec<Vec<u8>>) {
+        loop {
+            // Stuffing follows the last section
+            if self.buf.first().is_some_and(|&b| b == 0xff) {
+                self.reset();
+                return;
+            }
+
+            match section_len(&self.buf) {
+                Some(len) if len > MAX_SECTION => {
+                    self.reset();
+                    return;
+                }
+                Some(len) if self.buf.len() >= len => {
+                    let rest = self.buf.split_off(len);
+                    sections.push(std::mem::replace(&mut self.buf, rest));
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.buf.clear();
+        self.started = false;
+    }
+}
+
+/// Payload of a packet, if it carries one
+pub fn payload(pkt: &[u8]) -> Option<&[u8]> {
+    let start = match pkt[3] & 0x30 {
+        0x10 => 4,
+        0x30 => 5 + usize::from(pkt[4]),
+        _ => return None,
+    };
+    pkt.get(start..)
+}
+
+#[derive(Default)]
+struct State {
+    assemblers: HashMap<u16, Assembler>,
+    /// Program number to PMT PID, from the last PAT
+    pat: BTreeMap<u16, u16>,
+    /// Transport stream id and version of the last PAT
+    pat_id: (u16, u8),
+    /// Programs described by a PMT
+    pmts: BTreeMap<u16, Program>,
+    /// Last PAT and PMT sections by PID, to start the new consumers with
+    sections: BTreeMap<u16, Vec<u8>>,
+}
+
+impl State {
+    /// Whether the programs changed
+    fn on_pat(&mut self, section: &[u8]) -> bool {
+        let mut pat = if section[6] == 0 {
+            BTreeMap::new()
+        } else {
+            self.pat.clone()
+        };
+
+        for entry in section[8..section.len() - 4].chunks(4).filter(|e| e.len() == 4) {
+            let number = (u16::from(entry[0]) << 8) | u16::from(entry[1]);
+            let pid = (u16::from(entry[2] & 0x1f) << 8) | u16::from(entry[3]);
+            // Program 0 points to the network information
+            if number != 0 {
+                pat.insert(number, pid);
+            }
+        }
+
+        if pat == self.pat {
+            return false;
+        }
+
+        self.pmts.retain(|number, program| pat.get(number) == Some(&program.pmt_pid));
+        self.sections.retain(|pid, _| *pid == PAT_PID || pat.values().any(|pmt| pmt == pid));
+        self.pat = pat;
+        self.pat_id = ((u16::from(section[3]) << 8) | u16::from(section[4]), (section[5] >> 1) & 0x1f);
+        self.assemblers.retain(|pid, _| *pid == PAT_PID);
+
+        let programs: Vec<_> = self.pat.iter().map(|(n, pid)| format!("{} (PMT PID {:#06x})", n, pid)).collect();
+        info!("PAT changed, programs {}", programs.join(", "));
+        true
+    }
+
+    /// Whether the programs changed
+    fn on_pmt(&mut self, pid: u16, section: &[u8]) -> bool {
+        let number = (u16::from(section[3]) << 8) | u16::from(section[4]);
+        if section.len() < 16 || self.pat.get(&number) != Some(&pid) {
+            return false;
+        }
+
+        let pcr_pid = (u16::from(section[8] & 0x1f) << 8) | u16::from(section[9]);
+        let info_len = (usize::from(section[10] & 0x0f) << 8) | usize::from(section[11]);
+        let end = section.len() - 4;
+        let mut streams = Vec::new();
+        let mut i = 12 + info_len;
+
+        while i + 5 <= end {
+            streams.push(Stream {
+                pid: (u16::from(section[i + 1] & 0x1f) << 8) | u16::from(section[i + 2]),
+                stream_type: section[i],
+            });
+            i += 5 + ((usize::from(section[i + 3] & 0x0f) << 8) | usize::from(section[i + 4]));
+        }
+
+        let program = Program { number, pmt_pid: pid, pcr_pid: Some(pcr_pid), streams };
+
+        if self.pmts.get(&number) == Some(&program) {
+            return false;
+        }
+
+        info!("{}", program);
+        self.pmts.insert(number, program);
+        true
+    }
+
+    /// Whether the programs changed
+    fn on_section(&mut self, pid: u16, section: &[u8]) -> bool {
+        // Only the current tables with an intact CRC are considered
+        if section.len() < 12 || section[5] & 0x01 == 0 || crc32(section) != 0 {
+            return false;
+        }
+
+        let changed = match section[0] {
+            PAT_TABLE_ID if pid == PAT_PID => self.on_pat(section),
+            PMT_TABLE_ID if pid != PAT_PID => self.on_pmt(pid, section),
+            _ => return false,
+        };
+
+        // A PMT of a program no longer listed is not kept
+        if self.is_psi(pid) {
+            self.sections.insert(pid, section.to_vec());
+        }
+        changed
+    }
+
+    fn is_psi(&self, pid: u16) -> bool {
+        pid == PAT_PID || self.pat.values().any(|&pmt| pmt == pid)
+    }
+}
+
+#[derive(Default)]
+pub struct Programs {
+    state: Mutex<State>,
+    /// Bumped whenever the programs change
+    generation: AtomicU64,
+}
+
+impl Programs {
+    /// Look for tables in the packets of an aligned chunk
+    pub fn inspect(&self, chunk: &[u8]) {
+        if !ts::is_aligned(chunk) {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        for pkt in chunk.chunks(ts::PACKET_SIZE) {
+            let pid = ts::pid(pkt);
+            // Skip the corrupt packets
+            if !state.is_psi(pid) || pkt[1] & 0x80 != 0 {
+                continue;
+            }
+
+            let payload = match payload(pkt) {
+                Some(payload) => payload,
+                None => continue,
+            };
+
+            let sections = state.assemblers.entry(pid).or_default().push(payload, pkt[1] & 0x40 != 0);
+            for section in sections {
+                if state.on_section(pid, &section) {
+                    self.generation.fetch_add(1, Ordering::Release);
+                }
+            }
+        }
+    }
+
+    /// A new stream starts, drop the sections half received
+    pub fn restart(&self) {
+        self.state.lock().unwrap().assemblers.clear();
+    }
+
+    /// The last PAT and PMT, as packets to start a stream with
+    pub fn tables(&self) -> Vec<u8> {
+        let state = self.state.lock().unwrap();
+        state.sections.iter().flat_map(|(&pid, section)| packetize(pid, section)).collect()
+    }
+
+    /// Changes whenever the programs do
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Programs listed in the PAT, with the details of their PMT if received
+    pub fn programs(&self) -> Vec<Program> {
+        let state = self.state.lock().unwrap();
+
+        state.pat.iter().map(|(&number, &pmt_pid)| {
+            state.pmts.get(&number).cloned().unwrap_or(Program { number, pmt_pid, pcr_pid: None, streams: Vec::new() })
+        }).collect()
+    }
+
+    /// A PAT listing only program `number`, if the last one listed it
+    ///
+    /// It keeps the transport stream id and the version of the PAT it is made
+    /// from, so it changes version along with it.
+    pub fn pat_for(&self, number: u16) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        let &pmt_pid = state.pat.get(&number)?;
+        let (id, version) = state.pat_id;
+        let body = [(number >> 8) as u8, number as u8, 0xe0 | (pmt_pid >> 8) as u8, pmt_pid as u8];
+
+        Some(section(PAT_TABLE_ID, id, version, &body))
+    }
+
+    /// Render the programs as a JSON array
+    pub fn json(&self) -> String {
+        let programs = self.programs();
+        let mut out = String::from("[");
+
+        for (i, p) in programs.iter().enumerate() {
+            let _ = write!(out, "{}\n    {{\"number\": {}, \"pmt_pid\": {}, \"pcr_pid\": {}, \"streams\": [",
+                           if i > 0 { "," } else { "" }, p.number, p.pmt_pid,
+                           p.pcr_pid.map_or("null".to_owned(), |pid| pid.to_string()));
+            for (j, s) in p.streams.iter().enumerate() {
+                let _ = write!(out, "{}{{\"pid\": {}, \"type\": {}}}", if j > 0 { ", " } else { "" }, s.pid, s.stream_type);
+            }
+            out.push_str("]}");
+        }
+        if !programs.is_empty() {
+            out.push_str("\n  ");
+        }
+
+        out.push(']');
+        out
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    pub fn pat(programs: &[(u16, u16)]) -> Vec<u8> {
+        let body: Vec<u8> = programs.iter()
+            .flat_map(|&(n, pid)| vec![(n >> 8) as u8, n as u8, 0xe0 | (pid >> 8) as u8, pid as u8])
+            .collect();
+        section(PAT_TABLE_ID, 1, 0, &body)
+    }
+
+    pub fn pmt(number: u16, pcr_pid: u16, streams: &[(u8, u16)]) -> Vec<u8> {
+        let mut body = vec![0xe0 | (pcr_pid >> 8) as u8, pcr_pid as u8, 0xf0, 2, 0x0a, 0];
+        for &(stream_type, pid) in streams {
+            body.extend_from_slice(&[stream_type, 0xe0 | (pid >> 8) as u8, pid as u8, 0xf0, 3, 1, 2, 3]);
+        }
+        section(PMT_TABLE_ID, number, 0, &body)
+    }
+
+    /// Split a payload into packets, the first one starting with `pointer` bytes of junk
+    pub fn packets(pid: u16, pointer: u8, data: &[u8]) -> Vec<u8> {
+        let mut payload = vec![pointer];
+        payload.extend(vec![0xaa; pointer as usize]);
+        payload.extend_from_slice(data);
+
+        let mut out = Vec::new();
+        for (i, part) in payload.chunks(ts::PACKET_SIZE - 4).enumerate() {
+            let pusi = if i == 0 { 0x40 } else { 0 };
+            out.extend_from_slice(&[ts::SYNC_BYTE, pusi | (pid >> 8) as u8, pid as u8, 0x10 | (i as u8 & 0x0f)]);
+            out.extend_from_slice(part);
+            out.resize(out.len() + ts::PACKET_SIZE - 4 - part.len(), 0xff);
+        }
+        out
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0x0376_e6e7);
+    }
+
+    #[test]
+    fn programs() {
+        let programs = Programs::default();
+        programs.inspect(&packets(0, 0, &pat(&[(0, 0x10), (1, 0x1000), (2, 0x1100)])));
+        programs.inspect(&packets(0x1000, 0, &pmt(1, 0x100, &[(0x1b, 0x100), (0x0f, 0x101)])));
+
+        assert_eq!(programs.programs(), vec![
+            Program {
+                number: 1,
+                pmt_pid: 0x1000,
+                pcr_pid: Some(0x100),
+                streams: vec![Stream { pid: 0x100, stream_type: 0x1b }, Stream { pid: 0x101, stream_type: 0x0f }],
+            },
+            Program { number: 2, pmt_pid: 0x1100, pcr_pid: None, streams: Vec::new() },
+        ]);
+        assert!(programs.json().contains("{\"number\": 1, \"pmt_pid\": 4096, \"pcr_pid\": 256, \"streams\": [{\"pid\": 256, \"type\": 27}, "));
+        assert!(programs.json().contains("\"pcr_pid\": null"));
+    }
+
+    #[test]
+    fn section_split_over_packets() {
+        let streams: Vec<_> = (0..40).map(|i| (0x1b, 0x100 + i)).collect();
+        let programs = Programs::default();
+
+        programs.inspect(&packets(0, 0, &pat(&[(1, 0x1000)])));
+        // A pointer field past the start of the section, then 2 packets
+        let data = packets(0x1000, 10, &pmt(1, 0x100, &streams));
+        assert_eq!(data.len(), 2 * ts::PACKET_SIZE);
+        programs.inspect(&data[..ts::PACKET_SIZE]);
+        assert_eq!(programs.programs()[0].pcr_pid, None);
+        programs.inspect(&data[ts::PACKET_SIZE..]);
+
+        assert_eq!(programs.programs()[0].streams.len(), 40);
+    }
+
+    #[test]
+    fn pointer_field_ends_the_previous_section() {
+        let mut assembler = Assembler::default();
+        let first = pat(&[(1, 0x1000)]);
+        let second = pat(&[(2, 0x1100)]);
+
+        assert!(assembler.push(&[&[0][..], &first[..5]].concat(), true).is_empty());
+        let mut payload = vec![first.len() as u8 - 5];
+        payload.extend_from_slice(&first[5..]);
+        payload.extend_from_slice(&second);
+        payload.extend_from_slice(&[0xff; 4]);
+
+        assert_eq!(assembler.push(&payload, true), vec![first, second]);
+    }
+
+    #[test]
+    fn corrupt_sections_ignored() {
+        let programs = Programs::default();
+        let mut section = pat(&[(1, 0x1000)]);
+        section[9] ^= 1;
+        programs.inspect(&packets(0, 0, &section));
+
+        assert!(programs.programs().is_empty());
+    }
+
+    #[test]
+    fn single_program_pat() {
+        let programs = Programs::default();
+        let body = [0, 1, 0xf0, 0x00, 0, 2, 0xf1, 0x00];
+        programs.inspect(&packets(0, 0, &section(PAT_TABLE_ID, 7, 3, &body)));
+
+        let single = programs.pat_for(2).unwrap();
+        assert_eq!(single, section(PAT_TABLE_ID, 7, 3, &[0, 2, 0xf1, 0x00]));
+        assert_eq!(crc32(&single), 0);
+        assert_eq!(programs.pat_for(3), None);
+    }
+}
@@ -0,0 +1,140 @@
+//! Producer reached by connecting to it
+//!
+//! With `--relay-resume` each connection to an upstream restreamer resumes
+//! the stream where the previous one left it, see `relay`.
+
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use futures::prelude::*;
+use futures::future::{self, Either};
+use log::{error, info, warn};
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::{mark, set_options, Kind, OneShotSharedRx, Peer, Session, Shared, TSPacket};
+use crate::net::{self, Backoff, LocalBind};
+use crate::relay::{self, Offset};
+use crate::stats::PeerStats;
+
+/// How often a stalled producer is looked for
+const STALL_CHECK: Duration = Duration::from_millis(250);
+
+/// Stop the producer once no data arrived for `timeout`, never resolving
+async fn watchdog(stats: Arc<PeerStats>, state: Arc<Mutex<Shared>>, timeout: Duration) {
+    let mut bytes = stats.bytes();
+    let mut since = Instant::now();
+
+    let mut ticks = crate::interval(STALL_CHECK);
+    loop {
+        ticks.tick().await;
+        if stats.bytes() != bytes {
+            bytes = stats.bytes();
+            since = Instant::now();
+        } else if since.elapsed() >= timeout {
+            let mut state = state.lock().unwrap();
+            if state.is_active(&stats.addr) {
+                warn!("Dropping Producer ({:?}), no data for {:?}", stats.addr, timeout);
+                state.stop_producer();
+            }
+            since = Instant::now();
+        }
+    }
+}
+
+/// Stream from the producer until it goes away, resolving once it is done
+///
+/// The stream resumed from `from` is followed in `last`, for the next connection.
+fn serve(socket: TcpStream, state: &Arc<Mutex<Shared>>, buffer_size: usize, align: bool, timeout: Option<Duration>,
+         from: Option<(Offset, Arc<Mutex<Option<Offset>>>)>) -> impl Future<Output = ()> {
+    let mut packets = TSPacket::new(socket, buffer_size, align);
+    let stats = packets.stats.clone();
+    let (stop, stop_rx) = oneshot::channel::<()>();
+    let active = Arc::new(AtomicBool::new(true));
+
+    {
+        let mut state = state.lock().unwrap();
+
+        if state.shutting_down {
+            return Either::Right(future::ready(()));
+        }
+
+        // Nobody waits on it, the consumers last as long as the pull task
+        let (done, _) = oneshot::channel::<()>();
+        state.session = Some(Session { addr: stats.addr, stop, active: active.clone(), _done: done });
+        state.stats.set_producer(stats.clone());
+
+        set_options(&packets.socket, &state.settings);
+        mark(&packets.socket, state.settings.dscp, &stats);
+        if align {
+            packets.normalize(state.settings.packet_size);
+        }
+    }
+
+    let peer = Peer::new(state.clone(), packets, Kind::Producer(stop_rx, active));
+
+    info!("Adding {}", peer);
+
+    let (done, finished) = oneshot::channel::<()>();
+    let forwarded = stats.clone();
+    tokio::spawn(async move {
+        peer.await;
+        if let Some((from, last)) = from {
+            let offset = from.offset + forwarded.forwarded.load(Ordering::Relaxed);
+            *last.lock().unwrap() = Some(Offset { offset, ..from });
+        }
+        let _ = done.send(());
+    });
+
+    let watchdog = match timeout {
+        Some(timeout) => Either::Left(watchdog(stats, state.clone(), timeout)),
+        None => Either::Right(future::pending()),
+    };
+
+    Either::Left(async move {
+        tokio::select! {
+            _ = finished => (),
+            _ = watchdog => (),
+        }
+    })
+}
+
+/// Keep pulling the stream from `addr` for as long as the restreamer runs
+///
+/// `on_start` is called once with the consumer session, it ends when the
+/// returned future is dropped.
+pub async fn pull<F>(addr: SocketAddr, state: Arc<Mutex<Shared>>, buffer_size: usize, align: bool, backoff: Backoff, timeout: Option<Duration>, on_start: F)
+where
+    F: FnOnce(OneShotSharedRx),
+{
+    // Dropped along with the returned future
+    let (_done, rx) = oneshot::channel::<()>();
+    on_start(rx.shared());
+
+    // Where the last connection left the upstream stream
+    let last = Arc::new(Mutex::new(None));
+
+    net::reconnect(addr, LocalBind::default(), backoff, timeout, move |socket| {
+        if !state.lock().unwrap().settings.relay_resume {
+            return Either::Left(serve(socket, &state, buffer_size, align, timeout, None));
+        }
+
+        let state = state.clone();
+        let last = last.clone();
+        let asked = *last.lock().unwrap();
+        Either::Right(async move {
+            let (socket, from) = match relay::resume(socket, asked).await {
+                Ok(resumed) => resumed,
+                Err(e) => return error!("Cannot resume the stream of {:?}: {}", addr, e),
+            };
+            match asked {
+                Some(asked) if asked == from => info!("Resumed the stream of {:?} at {}", addr, from),
+                Some(asked) => warn!("Resumed the stream of {:?} at {} instead of {}, past a gap", addr, from, asked),
+                None => info!("Joined the stream of {:?} at {}", addr, from),
+            }
+            serve(socket, &state, buffer_size, align, timeout, Some((from, last))).await
+        })
+    }).await
+}
@@ -0,0 +1,46 @@
+//! Consumers reached by connecting to them
+
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use futures::prelude::*;
+use log::info;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::{mark, set_options, Kind, OneShotSharedRx, Peer, Shared, TSPacket};
+use crate::net::{self, Backoff, LocalBind, Socket};
+
+/// Serve the consumer until it goes away, resolving once it is done
+///
+/// Its packets are marked with `dscp`, or the DSCP of the consumers if unset,
+/// and padded to the `cbr` bitrate if set.
+pub fn serve<S: Socket>(socket: S, state: &Arc<Mutex<Shared>>, shutdown: &OneShotSharedRx, buffer_size: usize, dscp: Option<u8>,
+                        cbr: Option<u64>) -> impl Future<Output = ()> {
+    let packets = TSPacket::new(socket, buffer_size, false);
+    if let Some(tcp) = packets.socket.tcp() {
+        let settings = &state.lock().unwrap().settings;
+        set_options(tcp, settings);
+        mark(tcp, dscp.or(settings.dscp), &packets.stats);
+    }
+
+    let peer = Peer::new(state.clone(), packets, Kind::Consumer(shutdown.clone())).cbr(cbr);
+
+    info!("Adding {}", peer);
+
+    // Spawned on its own, so it can flush on shutdown once the loop is gone
+    let (done, finished) = oneshot::channel::<()>();
+    tokio::spawn(async move {
+        peer.await;
+        let _ = done.send(());
+    });
+
+    finished.map(|_| ())
+}
+
+/// Keep a consumer connection to `addr` open from the local end `bind`, for as long as the restreamer runs
+#[allow(clippy::too_many_arguments)]
+pub fn push(addr: SocketAddr, bind: LocalBind, dscp: Option<u8>, cbr: Option<u64>, state: Arc<Mutex<Shared>>, shutdown: OneShotSharedRx,
+            buffer_size: usize, backoff: Backoff) -> impl Future<Output = ()> {
+    net::reconnect(addr, bind, backoff, None, move |socket: TcpStream| serve(socket, &state, &shutdown, buffer_size, dscp, cbr))
+}
@@ -0,0 +1,258 @@
+//! Bounded packet queue between the producer and each consumer
+
+use futures::prelude::*;
+use futures::task::AtomicWaker;
+use bytes::Bytes;
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+/// What to do when a consumer queue is full
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    /// Drop the oldest packet in the queue
+    Drop,
+    /// Disconnect the consumer
+    Disconnect,
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(Overflow::Drop),
+            "disconnect" => Ok(Overflow::Disconnect),
+            _ => Err(format!("Unknown overflow policy {}, use drop or disconnect", s)),
+        }
+    }
+}
+
+/// Why a packet was not queued
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SendError {
+    /// Disconnected by the overflow policy, the queue being full
+    Full,
+    /// The consumer is gone, or was disconnected already
+    Closed,
+}
+
+struct Inner {
+    packets: VecDeque<Bytes>,
+    closed: bool,
+}
+
+struct Queue {
+    inner: Mutex<Inner>,
+    /// Bytes in `packets`, changed under the lock and read without it
+    bytes: AtomicUsize,
+    capacity: AtomicUsize,
+    overflow: Overflow,
+    dropped: Arc<AtomicU64>,
+    waker: AtomicWaker,
+}
+
+impl Queue {
+    fn close(&self) {
+        self.inner.lock().unwrap().closed = true;
+        self.waker.wake();
+    }
+}
+
+/// Producer side, never blocks
+pub struct Sender(Arc<Queue>);
+
+/// Consumer side
+pub struct Receiver(Arc<Queue>);
+
+/// Create a queue holding up to `capacity` packets, counting the overflows in `dropped`
+pub fn bounded(capacity: usize, overflow: Overflow, dropped: Arc<AtomicU64>) -> (Sender, Receiver) {
+    let queue = Arc::new(Queue {
+        inner: Mutex::new(Inner {
+            packets: VecDeque::with_capacity(capacity.min(1024)),
+            closed: false,
+        }),
+        bytes: AtomicUsize::new(0),
+        capacity: AtomicUsize::new(capacity.max(1)),
+        overflow,
+        dropped,
+        waker: AtomicWaker::new(),
+    });
+
+    (Sender(queue.clone()), Receiver(queue))
+}
+
+impl Sender {
+    /// Queue a packet, unless the consumer is gone or gets disconnected
+    pub fn send(&self, packet: Bytes) -> Result<(), SendError> {
+        let queue = &self.0;
+        {
+            let mut inner = queue.inner.lock().unwrap();
+
+            if inner.closed {
+                return Err(SendError::Closed);
+            }
+
+            let capacity = queue.capacity.load(Ordering::Relaxed);
+
+            if inner.packets.len() >= capacity {
+                match queue.overflow {
+                    Overflow::Drop => {
+                        // More than one if the queue got shrunk
+                        while inner.packets.len() >= capacity {
+                            if let Some(old) = inner.packets.pop_front() {
+                                queue.bytes.fetch_sub(old.len(), Ordering::Relaxed);
+                                queue.dropped.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                    }
+                    Overflow::Disconnect => {
+                        queue.dropped.fetch_add(1, Ordering::Relaxed);
+                        inner.closed = true;
+                        inner.packets.clear();
+                        queue.bytes.store(0, Ordering::Relaxed);
+                        drop(inner);
+                        queue.waker.wake();
+                        return Err(SendError::Full);
+                    }
+                }
+            }
+
+            queue.bytes.fetch_add(packet.len(), Ordering::Relaxed);
+            inner.packets.push_back(packet);
+        }
+        queue.waker.wake();
+
+        Ok(())
+    }
+
+    /// Change the number of packets the queue can hold, applied on the next send
+    pub fn set_capacity(&self, capacity: usize) {
+        self.0.capacity.store(capacity.max(1), Ordering::Relaxed);
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+impl Receiver {
+    /// Packets dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.0.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Bytes waiting in the queue
+    pub fn pending_bytes(&self) -> usize {
+        self.0.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Whether the sender is gone, outside of a task
+    pub fn is_closed(&self) -> bool {
+        self.0.inner.lock().unwrap().closed
+    }
+
+    /// Check if the sender is gone, without consuming any packet
+    pub fn poll_closed(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        self.0.waker.register(cx.waker());
+
+        if self.0.inner.lock().unwrap().closed {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Stream for Receiver {
+    type Item = Bytes;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
+        self.0.waker.register(cx.waker());
+
+        let mut inner = self.0.inner.lock().unwrap();
+
+        match inner.packets.pop_front() {
+            Some(packet) => {
+                self.0.bytes.fetch_sub(packet.len(), Ordering::Relaxed);
+                Poll::Ready(Some(packet))
+            }
+            None if inner.closed => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+
+    fn poll(rx: &mut Receiver) -> Poll<Option<Bytes>> {
+        rx.poll_next_unpin(&mut Context::from_waker(noop_waker_ref()))
+    }
+
+    fn drain(rx: &mut Receiver) -> Vec<Bytes> {
+        let mut out = Vec::new();
+        while let Poll::Ready(Some(pkt)) = poll(rx) {
+            out.push(pkt);
+        }
+        out
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let (tx, mut rx) = bounded(2, Overflow::Drop, Default::default());
+
+        for i in 0..5u8 {
+            assert_eq!(tx.send(Bytes::from(vec![i])), Ok(()));
+        }
+
+        assert_eq!(rx.pending_bytes(), 2);
+        assert_eq!(drain(&mut rx), vec![Bytes::from(vec![3]), Bytes::from(vec![4])]);
+        assert_eq!(rx.pending_bytes(), 0);
+        assert_eq!(rx.dropped(), 3);
+
+        drop(tx);
+        assert_eq!(poll(&mut rx), Poll::Ready(None));
+    }
+
+    #[test]
+    fn disconnect() {
+        let (tx, mut rx) = bounded(2, Overflow::Disconnect, Default::default());
+
+        assert_eq!(tx.send(Bytes::from(vec![0])), Ok(()));
+        assert_eq!(tx.send(Bytes::from(vec![1])), Ok(()));
+        assert_eq!(tx.send(Bytes::from(vec![2])), Err(SendError::Full));
+        assert_eq!(tx.send(Bytes::from(vec![3])), Err(SendError::Closed));
+
+        assert_eq!(poll(&mut rx), Poll::Ready(None));
+        assert_eq!(rx.dropped(), 1);
+    }
+    #[test]
+    fn shrink() {
+        let (tx, mut rx) = bounded(4, Overflow::Drop, Default::default());
+
+        for i in 0..4u8 {
+            assert_eq!(tx.send(Bytes::from(vec![i])), Ok(()));
+        }
+
+        tx.set_capacity(2);
+        assert_eq!(tx.send(Bytes::from(vec![4])), Ok(()));
+
+        assert_eq!(drain(&mut rx), vec![Bytes::from(vec![3]), Bytes::from(vec![4])]);
+        assert_eq!(rx.dropped(), 3);
+    }
+}
@@ -0,0 +1,403 @@
+//! Recording of the stream to rotating files
+//!
+//! The recorder is fed like a consumer, through a queue dropping the oldest
+//! packets, and writes from its own thread so a slow disk never holds up
+//! the network path. With `--record-splice` the stream skips the queue and
+//! goes to the files through a pipe whenever it can, see `splice`.
+
+use tokio::sync::oneshot;
+use futures::executor;
+use futures::prelude::*;
+use futures::stream;
+use bytes::{Bytes, BytesMut};
+use log::{error, info, warn};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{OneShotRx, Shared};
+use crate::burst::Burst;
+use crate::queue::{self, Overflow};
+use crate::splice::{self, Splice};
+use crate::ts;
+
+/// Key of the recorder queue in the fanout, no peer can have it
+pub const RECORDER_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+/// When the recorded data is flushed to the disk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fsync {
+    /// Leave it to the kernel
+    Never,
+    /// When a file is closed
+    Rotate,
+    /// After every chunk
+    Always,
+}
+
+impl FromStr for Fsync {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Fsync::Never),
+            "rotate" => Ok(Fsync::Rotate),
+            "always" => Ok(Fsync::Always),
+            _ => Err(format!("Unknown fsync mode {}, use never, rotate or always", s)),
+        }
+    }
+}
+
+/// Parse sizes such as `512M` or `2G`
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    match s.parse() {
+        Ok(Burst::Bytes(bytes)) => Ok(bytes as u64),
+        _ => Err(format!("Invalid size {}, use e.g. 512M or 2G", s)),
+    }
+}
+
+/// Where and how to record
+#[derive(Clone, Debug)]
+pub struct Record {
+    pub dir: PathBuf,
+    /// Start a new file once this many bytes are written
+    pub max_size: Option<u64>,
+    /// Start a new file once it is this old
+    pub duration: Option<Duration>,
+    pub fsync: Fsync,
+    /// Splice the producer straight into the files while nothing else takes the stream
+    pub splice: bool,
+}
+
+/// Days since the epoch to (year, month, day), from Howard Hinnant's `civil_from_days`
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+/// `YYYYMMDD-HHMM`, in UTC
+fn file_stem(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let minutes = secs.rem_euclid(86400) / 60;
+
+    format!("{:04}{:02}{:02}-{:02}{:02}", year, month, day, minutes / 60, minutes % 60)
+}
+
+/// The file being written
+struct Output {
+    file: File,
+    path: PathBuf,
+    bytes: u64,
+    since: Instant,
+}
+
+struct Recorder {
+    record: Record,
+    output: Option<Output>,
+    /// Failures since the last successful write
+    errors: u64,
+}
+
+impl Recorder {
+    /// Create a new file, never overwriting an existing one
+    fn open(dir: &Path) -> io::Result<Output> {
+        let stem = file_stem(SystemTime::now());
+
+        for n in 0.. {
+            let path = if n == 0 {
+                dir.join(format!("{}.ts", stem))
+            } else {
+                dir.join(format!("{}-{}.ts", stem, n))
+            };
+
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    info!("Recording to {}", path.display());
+                    return Ok(Output { file, path, bytes: 0, since: Instant::now() });
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        unreachable!()
+    }
+
+    /// Whether `len` more bytes belong to a new file
+    fn must_rotate(&self, output: &Output, len: usize) -> bool {
+        let too_big = self.record.max_size.is_some_and(|max| output.bytes > 0 && output.bytes + len as u64 > max);
+        let too_old = self.record.duration.is_some_and(|max| output.since.elapsed() >= max);
+
+        too_big || too_old
+    }
+
+    fn close(&mut self) {
+        if let Some(output) = self.output.take() {
+            if self.record.fsync != Fsync::Never {
+                if let Err(e) = output.file.sync_all() {
+                    error!("Cannot sync {}: {}", output.path.display(), e);
+                }
+            }
+            info!("Closing {}, {} bytes recorded", output.path.display(), output.bytes);
+        }
+    }
+
+    /// The file being written, a new one if there is none
+    fn output(&mut self) -> io::Result<&mut Output> {
+        if self.output.is_none() {
+            self.output = Some(Recorder::open(&self.record.dir)?);
+        }
+
+        Ok(self.output.as_mut().unwrap())
+    }
+
+    fn try_write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let fsync = self.record.fsync;
+        let output = self.output()?;
+
+        output.file.write_all(chunk)?;
+        output.bytes += chunk.len() as u64;
+
+        if fsync == Fsync::Always {
+            output.file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    fn report(&mut self, res: io::Result<()>) {
+        match res {
+            Ok(()) => {
+                if self.errors > 0 {
+                    info!("Recording recovered after {} errors", self.errors);
+                    self.errors = 0;
+                }
+            }
+            Err(e) => {
+                // Start over with a new file, reporting only the first failure
+                if self.errors == 0 {
+                    error!("Recording failed: {}", e);
+                }
+                self.errors += 1;
+                self.output = None;
+            }
+        }
+    }
+
+    /// Write a chunk, the chunks are whole TS packets so rotating between them
+    /// never splits one
+    fn write(&mut self, chunk: &[u8]) {
+        let rotate = self.output.as_ref().is_some_and(|output| self.must_rotate(output, chunk.len()));
+        if rotate {
+            self.close();
+        }
+
+        let res = self.try_write(chunk);
+        self.report(res);
+    }
+
+    /// Bytes of the `pending` ones spliced into the current file, closing it
+    /// first once it is due for rotation
+    ///
+    /// The spliced data is not split in packets, the file is rotated past the
+    /// packet it ends in.
+    fn splice_len(&mut self, pending: usize) -> usize {
+        let packet = ts::PACKET_SIZE as u64;
+        let (written, too_old) = match self.output {
+            Some(ref output) => (output.bytes, self.record.duration.is_some_and(|max| output.since.elapsed() >= max)),
+            None => (0, false),
+        };
+
+        let end = match self.record.max_size {
+            _ if too_old => written,
+            Some(max) => (max / packet * packet).max(packet),
+            None => u64::MAX,
+        };
+        let end = end.max(written.div_ceil(packet) * packet);
+
+        match (end - written).min(pending as u64) as usize {
+            0 => {
+                self.close();
+                self.splice_len(pending)
+            }
+            len => len,
+        }
+    }
+
+    /// Write what the producer spliced into the pipe
+    fn splice(&mut self, splice: &Splice) {
+        let res = splice.drain(|rd, pending| {
+            let len = self.splice_len(pending);
+            let fsync = self.record.fsync;
+            let output = self.output()?;
+
+            let n = splice::to_file(rd, &output.file, len)?;
+            output.bytes += n as u64;
+
+            if fsync == Fsync::Always {
+                output.file.sync_data()?;
+            }
+            Ok(n)
+        });
+
+        // Dropped rather than left to fill the pipe up
+        if res.is_err() {
+            let _ = splice.take_back(&mut BytesMut::new());
+        }
+        self.report(res);
+    }
+}
+
+/// What the recorder thread is woken up by
+enum Wakeup {
+    Chunk(Bytes),
+    /// The producer spliced more into the pipe
+    Spliced,
+    /// Removed from the fanout
+    Closed,
+}
+
+/// Start recording every packet broadcast, until the recorder is removed from the fanout
+///
+/// The returned receiver resolves once the last file is closed.
+pub fn spawn(record: Record, state: &Arc<Mutex<Shared>>) -> io::Result<OneShotRx> {
+    fs::create_dir_all(&record.dir)?;
+
+    let splice = if record.splice {
+        match Splice::new() {
+            Ok((splice, woken)) => Some((Arc::new(splice), woken)),
+            Err(e) => {
+                warn!("Cannot splice the recording, {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let dropped = Arc::new(AtomicU64::new(0));
+    let rx = {
+        let mut state = state.lock().unwrap();
+        // Losing packets is better than stalling the producer
+        let (tx, rx) = queue::bounded(state.settings.consumer_queue, Overflow::Drop, dropped.clone());
+        state.fanout.insert(RECORDER_ADDR, tx, 0);
+        state.splice = splice.as_ref().map(|(splice, _)| splice.clone());
+        rx
+    };
+
+    let (done, finished) = oneshot::channel::<()>();
+
+    thread::Builder::new().name("recorder".to_owned()).spawn(move || {
+        let mut recorder = Recorder { record, output: None, errors: 0 };
+
+        let chunks = rx.map(Wakeup::Chunk).chain(stream::iter(Some(Wakeup::Closed)));
+        let (wakeups, splice): (Box<dyn Stream<Item = Wakeup> + Send + Unpin>, _) = match splice {
+            Some((splice, mut woken)) => {
+                let spliced = stream::poll_fn(move |cx| woken.poll_recv(cx)).map(|()| Wakeup::Spliced);
+                (Box::new(stream::select(chunks, spliced)), Some(splice))
+            }
+            None => (Box::new(chunks), None),
+        };
+
+        for wakeup in executor::block_on_stream(wakeups) {
+            match wakeup {
+                Wakeup::Chunk(chunk) => recorder.write(&chunk),
+                Wakeup::Spliced => recorder.splice(splice.as_ref().unwrap()),
+                Wakeup::Closed => break,
+            }
+        }
+        // What is left in the pipe
+        if let Some(ref splice) = splice {
+            recorder.splice(splice);
+        }
+
+        recorder.close();
+        info!("Recording stopped, {} packets dropped", dropped.load(Ordering::Relaxed));
+
+        let _ = done.send(());
+    })?;
+
+    Ok(finished)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stem() {
+        assert_eq!(file_stem(UNIX_EPOCH), "19700101-0000");
+        // 2024-02-29 13:05:59
+        assert_eq!(file_stem(UNIX_EPOCH + Duration::from_secs(1_709_211_959)), "20240229-1305");
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("2K"), Ok(2048));
+        assert_eq!(parse_size("1316"), Ok(1316));
+        assert!(parse_size("2s").is_err());
+    }
+
+    #[test]
+    fn rotate_by_size() {
+        let dir = std::env::temp_dir().join(format!("restream-record-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let record = Record { dir: dir.clone(), max_size: Some(376), duration: None, fsync: Fsync::Rotate, splice: false };
+        let mut recorder = Recorder { record, output: None, errors: 0 };
+
+        for _ in 0..3 {
+            recorder.write(&[0x47; 188]);
+            recorder.write(&[0x47; 188]);
+        }
+        recorder.close();
+
+        let mut sizes: Vec<_> = fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .collect();
+        sizes.sort();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(sizes, vec![376, 376, 376]);
+    }
+
+    #[test]
+    fn spliced_rotation() {
+        let dir = std::env::temp_dir().join(format!("restream-splice-rotate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let record = Record { dir: dir.clone(), max_size: Some(200), duration: None, fsync: Fsync::Never, splice: true };
+        let mut recorder = Recorder { record, output: None, errors: 0 };
+
+        // Whole packets up to the size
+        assert_eq!(recorder.splice_len(1000), 188);
+        // The packet cut short is finished first
+        recorder.output = Some(Recorder::open(&dir).unwrap());
+        recorder.output.as_mut().unwrap().bytes = 250;
+        assert_eq!(recorder.splice_len(1000), 126);
+        assert_eq!(recorder.splice_len(50), 50);
+        // Then the next file takes over
+        recorder.output.as_mut().unwrap().bytes = 376;
+        assert_eq!(recorder.splice_len(1000), 188);
+        assert!(recorder.output.is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,106 @@
+//! Resuming the stream of an upstream restreamer, with `--relay-resume`
+//!
+//! A relay pulling from another restreamer sends `RESUME\n` on its first
+//! connection and `RESUME <origin>:<offset>\n` on the next ones, telling where
+//! its last connection left the stream. The origin answers with
+//! `RESUMED <origin>:<offset>\n`, the point the stream it sends next starts
+//! from, then streams as to any consumer. Offsets count the bytes the origin
+//! fanned out since it started, and `<origin>` tells its runs apart.
+//!
+//! The origin resumes from its time-shift buffer, or its burst buffer, when the
+//! offset is still in there. Otherwise the relay joins live as usual and the
+//! answer shows the gap.
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use bytes::Bytes;
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+/// Longest `RESUMED` line
+const MAX_LINE: usize = 64;
+
+/// A point of the stream of an origin
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Offset {
+    /// Tells the runs of the origin apart
+    pub origin: u64,
+    /// Bytes fanned out by the origin before this point
+    pub offset: u64,
+}
+
+impl fmt::Display for Offset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}:{}", self.origin, self.offset)
+    }
+}
+
+impl FromStr for Offset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Invalid offset {:?}, expected origin:offset", s);
+        let (origin, offset) = s.split_once(':').ok_or_else(err)?;
+
+        Ok(Offset {
+            origin: u64::from_str_radix(origin, 16).map_err(|_| err())?,
+            offset: offset.parse().map_err(|_| err())?,
+        })
+    }
+}
+
+/// The answer of the origin, ahead of the stream
+pub fn resumed(from: Offset) -> Bytes {
+    Bytes::from(format!("RESUMED {}\n", from))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Ask the origin to resume from `last`, yielding the socket back along with
+/// where the stream resumes from
+pub async fn resume(mut socket: TcpStream, last: Option<Offset>) -> io::Result<(TcpStream, Offset)> {
+    let request = match last {
+        Some(last) => format!("RESUME {}\n", last),
+        None => "RESUME\n".to_owned(),
+    };
+
+    socket.write_all(request.as_bytes()).await?;
+
+    // Byte by byte, the stream follows right after the line
+    let mut line = Vec::new();
+    loop {
+        let byte = socket.read_u8().await?;
+        if byte == b'\n' {
+            break;
+        }
+        line.push(byte);
+        if line.len() >= MAX_LINE || !b"RESUMED ".starts_with(&line[..line.len().min(8)]) {
+            return Err(invalid("The upstream does not resume, is it run with --relay-resume?".to_owned()));
+        }
+    }
+
+    let line = String::from_utf8_lossy(&line).into_owned();
+    let from = line.strip_prefix("RESUMED ").unwrap_or("").trim_end_matches('\r').parse().map_err(invalid)?;
+    Ok((socket, from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets() {
+        let offset = Offset { origin: 0xabc, offset: 1316 };
+        assert_eq!(offset.to_string(), "0000000000000abc:1316");
+        assert_eq!(offset.to_string().parse(), Ok(offset));
+        assert_eq!(&resumed(offset)[..], b"RESUMED 0000000000000abc:1316\n");
+
+        assert!("abc".parse::<Offset>().is_err());
+        assert!("xyz:1".parse::<Offset>().is_err());
+        assert!("abc:-1".parse::<Offset>().is_err());
+    }
+}
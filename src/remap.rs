@@ -0,0 +1,269 @@
+//! PID remapping of the stream broadcast
+//!
+//! The packet headers are rewritten along with the PAT and PMT references to
+//! the PIDs, their CRC recomputed, so the stream stays self-consistent.
+
+use bytes::{Bytes, BytesMut};
+use log::warn;
+
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::filter::parse_pid;
+use crate::psi::{self, crc32, payload};
+use crate::ts;
+
+const PAT_PID: u16 = 0;
+const NULL_PID: u16 = 0x1fff;
+const PAT_TABLE_ID: u8 = 0x00;
+const PMT_TABLE_ID: u8 = 0x02;
+
+/// A PID sent as another one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Remap {
+    pub from: u16,
+    pub to: u16,
+}
+
+impl FromStr for Remap {
+    type Err = String;
+
+    /// Parse `OLD:NEW`, the PIDs in decimal or hexadecimal
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut pids = s.splitn(2, ':');
+        match (pids.next(), pids.next()) {
+            (Some(from), Some(to)) => Ok(Remap { from: parse_pid(from)?, to: parse_pid(to)? }),
+            _ => Err(format!("Invalid remapping {}, use OLD:NEW, e.g. 0x1011:0x100", s)),
+        }
+    }
+}
+
+/// Read the 13 bits PID at the start of `field`
+fn get_pid(field: &[u8]) -> u16 {
+    (u16::from(field[0] & 0x1f) << 8) | u16::from(field[1])
+}
+
+/// Write the 13 bits PID at the start of `field`, keeping the bits above
+fn set_pid(field: &mut [u8], pid: u16) {
+    field[0] = (field[0] & 0xe0) | (pid >> 8) as u8;
+    field[1] = pid as u8;
+}
+
+#[derive(Clone)]
+pub struct PidRemap {
+    /// The PID every PID is sent as
+    map: Vec<u16>,
+    /// PMT PIDs listed by the PAT, before remapping
+    pmt_pids: HashSet<u16>,
+    /// PIDs whose sections span several packets, left untouched
+    warned: HashSet<u16>,
+}
+
+impl PidRemap {
+    /// Check that no two PIDs end up as one
+    pub fn new(remaps: &[Remap]) -> Result<Self, String> {
+        let mut map: Vec<u16> = (0..=NULL_PID).collect();
+        let mut from = HashSet::new();
+        let mut to = HashSet::new();
+
+        for remap in remaps {
+            if [remap.from, remap.to].iter().any(|&pid| pid == PAT_PID || pid == NULL_PID) {
+                return Err(format!("Cannot remap {:#06x} to {:#06x}, the PAT and the null packets keep their PID",
+                                   remap.from, remap.to));
+            }
+            if !from.insert(remap.from) {
+                return Err(format!("PID {:#06x} is remapped twice", remap.from));
+            }
+            if !to.insert(remap.to) {
+                return Err(format!("Several PIDs are remapped to {:#06x}", remap.to));
+            }
+            map[usize::from(remap.from)] = remap.to;
+        }
+
+        Ok(PidRemap { map, pmt_pids: HashSet::new(), warned: HashSet::new() })
+    }
+
+    fn remapped(&self, pid: u16) -> u16 {
+        self.map[usize::from(pid)]
+    }
+
+    fn is_psi(&self, pid: u16) -> bool {
+        pid == PAT_PID || self.pmt_pids.contains(&pid)
+    }
+
+    /// Remap the PID references of an intact section
+    fn remap_section(&mut self, pid: u16, section: &mut [u8]) {
+        if section.len() < 12 || crc32(section) != 0 {
+            return;
+        }
+
+        let end = section.len() - 4;
+        let mut fields = Vec::new();
+
+        match section[0] {
+            PAT_TABLE_ID if pid == PAT_PID => {
+                if section[6] == 0 {
+                    self.pmt_pids.clear();
+                }
+                for i in (8..end).step_by(4).filter(|i| i + 4 <= end) {
+                    // Program 0 points to the network information
+                    if section[i] != 0 || section[i + 1] != 0 {
+                        self.pmt_pids.insert(get_pid(&section[i + 2..]));
+                    }
+                    fields.push(i + 2);
+                }
+            }
+            PMT_TABLE_ID if pid != PAT_PID => {
+                fields.push(8);
+                let mut i = 12 + ((usize::from(section[10] & 0x0f) << 8) | usize::from(section[11]));
+                while i + 5 <= end {
+                    fields.push(i + 1);
+                    i += 5 + ((usize::from(section[i + 3] & 0x0f) << 8) | usize::from(section[i + 4]));
+                }
+            }
+            _ => return,
+        }
+
+        let mut changed = false;
+        for i in fields {
+            let to = self.remapped(get_pid(&section[i..]));
+            if to != get_pid(&section[i..]) {
+                set_pid(&mut section[i..], to);
+                changed = true;
+            }
+        }
+
+        if changed {
+            let crc = crc32(&section[..end]);
+            section[end..].copy_from_slice(&crc.to_be_bytes());
+        }
+    }
+
+    /// Remap the sections starting in the payload of a PSI packet
+    fn remap_tables(&mut self, pid: u16, pkt: &mut [u8]) {
+        // Skip the corrupt packets and the ones continuing a section
+        if pkt[1] & 0x80 != 0 || pkt[1] & 0x40 == 0 {
+            return;
+        }
+
+        let start = ts::PACKET_SIZE - match payload(pkt) {
+            Some(payload) => payload.len(),
+            None => return,
+        };
+        let payload = &mut pkt[start..];
+        let mut pos = 1 + usize::from(payload[0]);
+
+        while pos + 3 <= payload.len() && payload[pos] != 0xff {
+            let len = psi::section_len(&payload[pos..]).unwrap_or(0);
+            if pos + len > payload.len() {
+                if self.warned.insert(pid) {
+                    warn!("The tables of PID {:#06x} span several packets, their PIDs are not remapped", pid);
+                }
+                return;
+            }
+
+            self.remap_section(pid, &mut payload[pos..pos + len]);
+            pos += len;
+        }
+    }
+
+    /// `chunk` with the PIDs remapped, the chunk must be aligned
+    pub fn apply(&mut self, chunk: &Bytes) -> Bytes {
+        let touched = |pkt: &[u8]| {
+            let pid = ts::pid(pkt);
+            self.is_psi(pid) || self.remapped(pid) != pid
+        };
+        if !ts::is_aligned(chunk) || !chunk.chunks(ts::PACKET_SIZE).any(touched) {
+            return chunk.clone();
+        }
+
+        let mut out = BytesMut::from(&chunk[..]);
+
+        for pkt in out.chunks_mut(ts::PACKET_SIZE) {
+            let pid = ts::pid(pkt);
+            if self.is_psi(pid) {
+                self.remap_tables(pid, pkt);
+            }
+
+            let to = self.remapped(pid);
+            if to != pid {
+                set_pid(&mut pkt[1..], to);
+            }
+        }
+
+        out.freeze()
+    }
+}
+
+impl fmt::Debug for PidRemap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let remapped = self.map.iter().enumerate().filter(|&(pid, &to)| pid != usize::from(to)).count();
+        write!(f, "PidRemap({} PIDs remapped)", remapped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psi::tests::{packets, pat, pmt};
+    use crate::psi::{Program, Programs, Stream};
+    use crate::ts::tests::packet;
+
+    fn remap(pairs: &[(u16, u16)]) -> Result<PidRemap, String> {
+        PidRemap::new(&pairs.iter().map(|&(from, to)| Remap { from, to }).collect::<Vec<_>>())
+    }
+
+    fn media(pid: u16) -> Vec<u8> {
+        let mut pkt = packet(pid, 0x10);
+        pkt[1] |= 0x40;
+        pkt
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("0x1011:256".parse(), Ok(Remap { from: 0x1011, to: 0x100 }));
+        assert!("0x1011".parse::<Remap>().is_err());
+        assert!("0x1011:0x2000".parse::<Remap>().is_err());
+    }
+
+    #[test]
+    fn collisions_rejected() {
+        assert!(remap(&[(0x200, 0x100), (0x100, 0x200)]).is_ok());
+        assert!(remap(&[(0x200, 0x100), (0x201, 0x100)]).unwrap_err().contains("Several PIDs"));
+        assert!(remap(&[(0x200, 0x100), (0x200, 0x101)]).unwrap_err().contains("twice"));
+        assert!(remap(&[(0x200, 0)]).is_err());
+        assert!(remap(&[(0x1fff, 0x100)]).is_err());
+    }
+
+    #[test]
+    fn sample_mux() {
+        let mut remap = remap(&[(0x1011, 0x100), (0x1012, 0x101), (0x1000, 0x20)]).unwrap();
+
+        let mux = Bytes::from([
+            packets(0, 0, &pat(&[(1, 0x1000)])),
+            packets(0x1000, 0, &pmt(1, 0x1011, &[(0x1b, 0x1011), (0x0f, 0x1012)])),
+            media(0x1011),
+            media(0x1012),
+            media(0x1fff),
+        ].concat());
+        let out = remap.apply(&mux);
+
+        let pids: Vec<_> = out.chunks(ts::PACKET_SIZE).map(ts::pid).collect();
+        assert_eq!(pids, vec![0, 0x20, 0x100, 0x101, 0x1fff]);
+
+        // The tables parse back, their CRC checked, with the new PIDs
+        let programs = Programs::default();
+        programs.inspect(&out);
+        assert_eq!(programs.programs(), vec![Program {
+            number: 1,
+            pmt_pid: 0x20,
+            pcr_pid: Some(0x100),
+            streams: vec![Stream { pid: 0x100, stream_type: 0x1b }, Stream { pid: 0x101, stream_type: 0x0f }],
+        }]);
+
+        // Nothing to remap, the chunk is passed on as is
+        let untouched = Bytes::from(media(0x300));
+        assert_eq!(remap.apply(&untouched).as_ptr(), untouched.as_ptr());
+    }
+}
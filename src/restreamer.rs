@@ -0,0 +1,1570 @@
+//! Builder and handle for embedding the restreamer
+
+use tokio::runtime::Runtime;
+use tokio::sync::oneshot;
+use futures::prelude::*;
+use futures::future::{self, Either};
+use bytes::BytesMut;
+use log::{error, info};
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::{accept_tcp, authenticate_producer, serve_consumers, serve_ws_consumers, setup_producer};
+use crate::{OneShotRx, OneShotSharedRx, OneShotTx, Settings, Shared, TSPacket};
+#[cfg(unix)]
+use crate::account::Account;
+use crate::access::{self, AccessLog};
+use crate::burst::Burst;
+use crate::filter::PidFilter;
+use crate::cc;
+use crate::delay::{self, BroadcastDelay};
+use crate::dvr::Dvr;
+use crate::events::Events;
+use crate::failover::{self, Failover};
+use crate::generate;
+#[cfg(unix)]
+use crate::control;
+use crate::hls::{self, Hls};
+use crate::http;
+use crate::input::{Input, Output};
+use crate::keys::{self, Router};
+use crate::net::{self, Backoff, LocalBind, Socket};
+use crate::play;
+use crate::pull;
+use crate::push;
+use crate::record::{self, Record};
+use crate::remap::PidRemap;
+use crate::sink::{self, Sink};
+#[cfg(feature = "srt")]
+use crate::libsrt;
+use crate::srt::SrtOptions;
+use crate::rtp::{self, RtpState};
+use crate::keepalive;
+use crate::stall;
+#[cfg(feature = "rist")]
+use crate::rist::Rist;
+#[cfg(feature = "rist")]
+use crate::stats::PeerStats;
+use crate::stats::{self, Stats};
+use crate::stdin::StdinPeer;
+use crate::tls::{ProducerTls, Tls};
+use crate::tr101290;
+use crate::ts;
+use crate::udp::{self, UdpTarget};
+use crate::unix;
+use crate::webhook::{self, Webhook};
+
+/// Run `f` until the shutdown starts
+fn until_shutdown<F>(f: F, shutdown: &OneShotSharedRx) -> impl Future<Output = ()>
+where
+    F: Future<Output = ()>,
+{
+    let shutdown = shutdown.clone();
+    async move {
+        tokio::select! {
+            _ = f => (),
+            _ = shutdown => (),
+        }
+    }
+}
+
+/// What a TCP producer needs once accepted
+#[derive(Clone)]
+struct ProducerSetup<F> {
+    state: Arc<Mutex<Shared>>,
+    /// Set when routing on stream keys
+    router: Option<Arc<Router>>,
+    token: Option<String>,
+    buffer_size: usize,
+    align: bool,
+    takeover: bool,
+    start_consumers: F,
+}
+
+impl<F> ProducerSetup<F>
+where
+    F: Fn(Arc<Mutex<Shared>>, OneShotSharedRx) + Clone + Send + 'static,
+{
+    /// Check the token if one is required, then start streaming
+    fn accept<S: Socket>(self, socket: S) {
+        match self.token.clone() {
+            Some(token) => authenticate_producer(socket, &token, move |socket, rd| self.start(socket, rd)),
+            None => self.start(socket, BytesMut::new()),
+        }
+    }
+
+    fn start<S: Socket>(self, socket: S, rd: BytesMut) {
+        if let Some(router) = self.router {
+            return keys::accept_producer(router, socket, rd);
+        }
+
+        let packets = TSPacket::with_read_buf(socket, self.buffer_size, self.align, rd);
+        if let Some(rx) = setup_producer(packets, self.state.clone(), self.takeover) {
+            (self.start_consumers)(self.state, rx);
+        }
+    }
+}
+
+/// Configure the restreamer before spawning it
+#[derive(Clone, Debug)]
+pub struct Builder {
+    input: Input,
+    output: Output,
+    /// Permissions of the Unix sockets
+    socket_mode: Option<u32>,
+    backlog: u32,
+    bind_retry: Option<Duration>,
+    /// Accept loops on the consumer port
+    reuseport: usize,
+    buffer_size: usize,
+    align: bool,
+    http: bool,
+    ws: Option<SocketAddr>,
+    tls: Option<Tls>,
+    takeover: bool,
+    producer_tls: Option<ProducerTls>,
+    token: Option<String>,
+    stream_keys: bool,
+    wait_for_producer: bool,
+    settings: Settings,
+    burst: Option<Burst>,
+    channel: Option<usize>,
+    pid_filter: Option<PidFilter>,
+    program: Option<u16>,
+    remap: Option<PidRemap>,
+    strip_nulls: bool,
+
+    udp_timeout: Duration,
+    rtp_in: bool,
+    input_iface: Option<String>,
+    input_backup: Option<Input>,
+    merge_window: usize,
+    failover_timeout: Option<Duration>,
+    failback_delay: Duration,
+
+    pull_backoff: Backoff,
+    pull_timeout: Option<Duration>,
+
+    play_bitrate: Option<u64>,
+    slate: Option<PathBuf>,
+    generate: Option<u64>,
+
+    udp_out: Vec<UdpTarget>,
+    udp_packets: usize,
+    ttl: Option<u32>,
+    rtp_out: bool,
+    rtp_ssrc: Option<u32>,
+    rtp_pt: u8,
+    pace: Option<Duration>,
+    udp_dscp: Option<u8>,
+
+    /// With the constant bitrate of each, if padded to one
+    push: Vec<(SocketAddr, LocalBind, Option<u64>)>,
+    push_srt: Vec<SocketAddr>,
+    push_rist: Vec<SocketAddr>,
+    rist_buffer: Duration,
+    push_backoff: Backoff,
+    local_bind: LocalBind,
+    push_dscp: Option<u8>,
+    /// Of the UDP outputs and push targets not given one of their own
+    cbr: Option<u64>,
+    #[cfg(unix)]
+    account: Option<Account>,
+    /// Bound by someone else, e.g. systemd
+    inherited_producer: Option<Arc<std::net::TcpListener>>,
+    inherited_consumers: Option<Arc<Vec<std::net::TcpListener>>>,
+    srt: SrtOptions,
+
+    record: Option<Record>,
+    sink: Option<Sink>,
+    hls: Option<Hls>,
+    hls_port: Option<SocketAddr>,
+
+    stall_timeout: Option<Duration>,
+    disconnect_on_stall: bool,
+    keepalive_nulls: Option<Duration>,
+
+    dvr: Option<Duration>,
+    dvr_max_bytes: usize,
+    delay: Option<BroadcastDelay>,
+
+    metrics: Option<SocketAddr>,
+    status: Option<SocketAddr>,
+    health_max_idle: Duration,
+    control_socket: Option<PathBuf>,
+
+    webhook: Option<Webhook>,
+    webhook_thresholds: Vec<usize>,
+
+    access_log: Option<AccessLog>,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Builder {
+            input: Input::Tcp(([127, 0, 0, 1], 12345).into()),
+            output: Output::Tcp(([127, 0, 0, 1], 12346).into()),
+            socket_mode: None,
+            backlog: 1024,
+            bind_retry: None,
+            reuseport: 1,
+            buffer_size: 1316,
+            align: true,
+            http: false,
+            ws: None,
+            tls: None,
+            takeover: false,
+            producer_tls: None,
+            token: None,
+            stream_keys: false,
+            wait_for_producer: false,
+            settings: Settings::default(),
+            burst: None,
+            channel: None,
+            pid_filter: None,
+            program: None,
+            remap: None,
+            strip_nulls: false,
+
+            udp_timeout: Duration::from_secs(5),
+            rtp_in: false,
+            input_iface: None,
+            input_backup: None,
+            merge_window: 64,
+            failover_timeout: None,
+            failback_delay: Duration::from_secs(5),
+
+            pull_backoff: Backoff::default(),
+            pull_timeout: None,
+
+            play_bitrate: None,
+            slate: None,
+            generate: None,
+
+            udp_out: Vec::new(),
+            udp_packets: 7,
+            ttl: None,
+            rtp_out: false,
+            rtp_ssrc: None,
+            rtp_pt: rtp::PAYLOAD_TYPE_MP2T,
+            pace: None,
+            udp_dscp: None,
+
+            push: Vec::new(),
+            push_srt: Vec::new(),
+            push_rist: Vec::new(),
+            rist_buffer: Duration::from_secs(1),
+            push_backoff: Backoff::default(),
+            local_bind: LocalBind::default(),
+            push_dscp: None,
+            cbr: None,
+            #[cfg(unix)]
+            account: None,
+            inherited_producer: None,
+            inherited_consumers: None,
+            srt: SrtOptions::default(),
+
+            record: None,
+            sink: None,
+            hls: None,
+            hls_port: None,
+
+            stall_timeout: None,
+            disconnect_on_stall: false,
+            keepalive_nulls: None,
+
+            dvr: None,
+            dvr_max_bytes: 256 << 20,
+            delay: None,
+
+            metrics: None,
+            status: None,
+            health_max_idle: Duration::from_secs(5),
+            control_socket: None,
+
+            webhook: None,
+            webhook_thresholds: Vec::new(),
+
+            access_log: None,
+        }
+    }
+}
+
+impl Builder {
+    /// Listen for the TCP producer on `addr`
+    pub fn producer_listener(self, addr: SocketAddr) -> Self {
+        self.input(Input::Tcp(addr))
+    }
+
+    /// Receive the producer stream as UDP datagrams on `addr`
+    pub fn udp_input(self, addr: SocketAddr) -> Self {
+        self.input(Input::Udp(addr))
+    }
+
+    /// Connect to the producer on `addr`, reconnecting whenever it goes away
+    pub fn pull(self, addr: SocketAddr) -> Self {
+        self.input(Input::Pull(addr))
+    }
+
+    /// Listen for the producer on the Unix socket at `path`
+    pub fn producer_socket(self, path: PathBuf) -> Self {
+        self.input(Input::Unix(path))
+    }
+
+    pub fn input(mut self, input: Input) -> Self {
+        self.input = input;
+        self
+    }
+
+    /// Listen for the consumers on `addr` while a producer is streaming
+    pub fn consumer_listener(self, addr: SocketAddr) -> Self {
+        self.output(Output::Tcp(addr))
+    }
+
+    /// Listen for the consumers on the Unix socket at `path` while a producer is streaming
+    pub fn consumer_socket(self, path: PathBuf) -> Self {
+        self.output(Output::Unix(path))
+    }
+
+    pub fn output(mut self, output: Output) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// Permissions of the Unix sockets, e.g. 0o660
+    pub fn socket_mode(mut self, mode: Option<u32>) -> Self {
+        self.socket_mode = mode;
+        self
+    }
+
+    /// Connections queued by the kernel on each TCP listener until accepted,
+    /// the last value spawned applies to the whole process
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Wait up to `timeout` for the TCP ports in use to be released, instead of failing right away
+    pub fn bind_retry(mut self, timeout: Option<Duration>) -> Self {
+        self.bind_retry = timeout;
+        self
+    }
+
+    /// Accept the TCP consumers with `loops` listeners sharing the port with
+    /// SO_REUSEPORT, a single one where it is not available
+    pub fn reuseport(mut self, loops: usize) -> Self {
+        self.reuseport = loops;
+        self
+    }
+
+    /// Size of the chunks forwarded to the consumers
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size;
+        self
+    }
+
+    /// Align the chunks to the MPEG-TS packets, on by default
+    pub fn align(mut self, align: bool) -> Self {
+        self.align = align;
+        self
+    }
+
+    /// Serve the consumers over HTTP
+    pub fn http(mut self, http: bool) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// Also listen for WebSocket consumers on `addr`, each chunk goes out as a binary frame
+    pub fn ws_listener(mut self, addr: Option<SocketAddr>) -> Self {
+        self.ws = addr;
+        self
+    }
+
+    /// Serve the consumers over TLS
+    pub fn tls(mut self, tls: Option<Tls>) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Let a new producer replace the active one
+    pub fn producer_takeover(mut self, takeover: bool) -> Self {
+        self.takeover = takeover;
+        self
+    }
+
+    /// Require the producers to complete a TLS handshake with a client certificate signed by the given CA
+    pub fn producer_tls(mut self, tls: Option<ProducerTls>) -> Self {
+        self.producer_tls = tls;
+        self
+    }
+
+    /// Require the producer to send this token, followed by a newline, first
+    pub fn producer_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// Route on the `PUBLISH <key>` and `PLAY <key>` lines sent first by the peers,
+    /// every key being a stream of its own
+    pub fn stream_keys(mut self, keys: bool) -> Self {
+        self.stream_keys = keys;
+        self
+    }
+
+    /// Keep the consumers of a key nothing is published on until a producer shows up,
+    /// instead of disconnecting them
+    pub fn wait_for_producer(mut self, wait: bool) -> Self {
+        self.wait_for_producer = wait;
+        self
+    }
+
+    pub fn settings(mut self, settings: Settings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Replay the last part of the stream to new consumers
+    pub fn burst(mut self, burst: Option<Burst>) -> Self {
+        self.burst = burst;
+        self
+    }
+
+    /// Index of the channel served, when running several side by side, shown in the logs and the stats
+    pub fn channel(mut self, channel: Option<usize>) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    /// Leave out the packets of some PIDs, the chunks must be aligned
+    pub fn pid_filter(mut self, filter: Option<PidFilter>) -> Self {
+        self.pid_filter = filter;
+        self
+    }
+
+    /// Send a single program out of the stream, under a PAT listing it alone,
+    /// the chunks must be aligned
+    pub fn program(mut self, program: Option<u16>) -> Self {
+        self.program = program;
+        self
+    }
+
+    /// Send some PIDs as others, patching the PAT and PMT to match, the chunks
+    /// must be aligned
+    pub fn remap_pids(mut self, remap: Option<PidRemap>) -> Self {
+        self.remap = remap;
+        self
+    }
+
+    /// Leave out the null packets, the chunks must be aligned
+    pub fn strip_nulls(mut self, strip: bool) -> Self {
+        self.strip_nulls = strip;
+        self
+    }
+
+    /// Time without datagrams before the UDP producer is considered gone
+    pub fn udp_timeout(mut self, timeout: Duration) -> Self {
+        self.udp_timeout = timeout;
+        self
+    }
+
+    /// Strip the RTP header from the UDP input datagrams
+    pub fn rtp_input(mut self, rtp: bool) -> Self {
+        self.rtp_in = rtp;
+        self
+    }
+
+    /// Interface used to join the input multicast group
+    pub fn input_iface(mut self, iface: Option<String>) -> Self {
+        self.input_iface = iface;
+        self
+    }
+
+    /// Input replacing the producer when it fails, with `failover_timeout`, or
+    /// without it a second UDP address receiving the same RTP stream, both paths
+    /// being merged
+    pub fn input_backup(mut self, input: Option<Input>) -> Self {
+        self.input_backup = input;
+        self
+    }
+
+    /// Switch to the backup input once the producer sent nothing for `timeout`
+    pub fn failover_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.failover_timeout = timeout;
+        self
+    }
+
+    /// Time the producer must send again for before switching back to it from the backup input
+    pub fn failback_delay(mut self, delay: Duration) -> Self {
+        self.failback_delay = delay;
+        self
+    }
+
+    /// Packets a packet missing on one path waits for the other one
+    pub fn merge_window(mut self, window: usize) -> Self {
+        self.merge_window = window;
+        self
+    }
+
+    /// Delays between the attempts to connect to the pulled producer
+    pub fn pull_backoff(mut self, backoff: Backoff) -> Self {
+        self.pull_backoff = backoff;
+        self
+    }
+
+    /// Reconnect to the pulled producer once no data arrived for this long
+    pub fn pull_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.pull_timeout = timeout;
+        self
+    }
+
+    /// Bits per second of the file playback, the PCR is followed if not set
+    pub fn play_bitrate(mut self, bitrate: Option<u64>) -> Self {
+        self.play_bitrate = bitrate;
+        self
+    }
+
+    /// Play this file in a loop whenever no producer is streaming
+    pub fn slate(mut self, path: Option<PathBuf>) -> Self {
+        self.slate = path;
+        self
+    }
+
+    /// Generate a test signal at this bitrate whenever no producer is streaming
+    pub fn generate(mut self, bitrate: Option<u64>) -> Self {
+        self.generate = bitrate;
+        self
+    }
+
+    /// Push the stream to an UDP destination, can be called more than once
+    pub fn udp_output(mut self, target: UdpTarget) -> Self {
+        self.udp_out.push(target);
+        self
+    }
+
+    /// Number of TS packets per UDP datagram
+    pub fn udp_packets(mut self, packets: usize) -> Self {
+        self.udp_packets = packets;
+        self
+    }
+
+    /// ttl of the multicast UDP outputs
+    pub fn multicast_ttl(mut self, ttl: Option<u32>) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Wrap all the UDP outputs in RTP
+    pub fn rtp_output(mut self, rtp: bool) -> Self {
+        self.rtp_out = rtp;
+        self
+    }
+
+    /// RTP SSRC of the outputs, random by default
+    pub fn rtp_ssrc(mut self, ssrc: Option<u32>) -> Self {
+        self.rtp_ssrc = ssrc;
+        self
+    }
+
+    /// RTP payload type of the outputs, 33 (MP2T) by default
+    pub fn rtp_payload_type(mut self, pt: u8) -> Self {
+        self.rtp_pt = pt;
+        self
+    }
+
+    /// Pace the UDP outputs on the PCR, holding the datagrams back up to `depth`
+    pub fn pace_pcr(mut self, depth: Option<Duration>) -> Self {
+        self.pace = depth;
+        self
+    }
+
+    /// DSCP of the UDP outputs, the one of the consumers when unset
+    pub fn udp_output_dscp(mut self, dscp: Option<u8>) -> Self {
+        self.udp_dscp = dscp;
+        self
+    }
+
+    /// Connect to a consumer instead of waiting for it, can be called more than once
+    pub fn push(self, addr: SocketAddr) -> Self {
+        self.push_via(addr, LocalBind::default())
+    }
+
+    /// Connect to a consumer as `push` does, from the local end `bind`
+    pub fn push_via(self, addr: SocketAddr, bind: LocalBind) -> Self {
+        self.push_target(addr, bind, None)
+    }
+
+    /// Connect to a consumer as `push_via` does, padding its stream to the `cbr` bitrate if set
+    pub fn push_target(mut self, addr: SocketAddr, bind: LocalBind, cbr: Option<u64>) -> Self {
+        self.push.push((addr, bind, cbr));
+        self
+    }
+
+    /// Connect to an SRT consumer, can be called more than once
+    pub fn push_srt(mut self, addr: SocketAddr) -> Self {
+        self.push_srt.push(addr);
+        self
+    }
+
+    /// Send the stream to a RIST receiver, can be called more than once
+    pub fn push_rist(mut self, addr: SocketAddr) -> Self {
+        self.push_rist.push(addr);
+        self
+    }
+
+    /// How long the RIST outputs keep the datagrams sent, for retransmission
+    pub fn rist_buffer(mut self, buffer: Duration) -> Self {
+        self.rist_buffer = buffer;
+        self
+    }
+
+    /// Latency and encryption of the SRT input and push targets
+    pub fn srt(mut self, options: SrtOptions) -> Self {
+        self.srt = options;
+        self
+    }
+
+    /// Delays between the attempts to connect to the push consumers
+    pub fn push_backoff(mut self, backoff: Backoff) -> Self {
+        self.push_backoff = backoff;
+        self
+    }
+
+    /// DSCP of the TCP and RIST push targets, the one of the consumers when unset
+    pub fn push_dscp(mut self, dscp: Option<u8>) -> Self {
+        self.push_dscp = dscp;
+        self
+    }
+
+    /// Pad the UDP outputs and the push targets not given a bitrate of their
+    /// own to this one, with null packets
+    pub fn cbr(mut self, bitrate: Option<u64>) -> Self {
+        self.cbr = bitrate;
+        self
+    }
+
+    /// Switch to this account once the producer and consumer listeners are bound,
+    /// before anything else is opened
+    #[cfg(unix)]
+    pub fn account(mut self, account: Option<Account>) -> Self {
+        self.account = account;
+        self
+    }
+
+    /// Accept the producers and consumers on listeners bound already, such as
+    /// the ones passed by systemd, instead of binding the ports given
+    pub fn inherited(mut self, producer: Option<std::net::TcpListener>, consumers: Vec<std::net::TcpListener>) -> Self {
+        self.inherited_producer = producer.map(Arc::new);
+        self.inherited_consumers = if consumers.is_empty() { None } else { Some(Arc::new(consumers)) };
+        self
+    }
+
+    /// Local address or interface of the UDP, RIST and push outputs without one of their own
+    pub fn local_bind(mut self, bind: LocalBind) -> Self {
+        self.local_bind = bind;
+        self
+    }
+
+    /// Record the stream to files in a directory
+    pub fn record(mut self, record: Option<Record>) -> Self {
+        self.record = record;
+        self
+    }
+
+    /// Copy the stream to the standard output or a file, across the producers
+    pub fn sink(mut self, sink: Option<Sink>) -> Self {
+        self.sink = sink;
+        self
+    }
+
+    /// Segment the stream for HLS clients, served under `/hls/` on the status and metrics ports
+    pub fn hls(mut self, hls: Option<Hls>) -> Self {
+        self.hls = hls;
+        self
+    }
+
+    /// Serve the HLS playlist and segments on a port of their own
+    pub fn hls_port(mut self, addr: Option<SocketAddr>) -> Self {
+        self.hls_port = addr;
+        self
+    }
+
+    /// Flag the producer as stalled once it sends nothing for this long
+    pub fn producer_stall_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.stall_timeout = timeout;
+        self
+    }
+
+    /// Disconnect the consumers when the producer stalls, so they can fail over
+    pub fn disconnect_on_stall(mut self, disconnect: bool) -> Self {
+        self.disconnect_on_stall = disconnect;
+        self
+    }
+
+    /// Send the consumers null packets every `interval` while no producer
+    /// sends anything, so they keep the connection open
+    pub fn keepalive_nulls(mut self, interval: Option<Duration>) -> Self {
+        self.keepalive_nulls = interval;
+        self
+    }
+
+    /// Keep the chunks broadcast over this window for the consumers to rewind,
+    /// with `OPTS rewind=SECS` or `?rewind=SECS`
+    pub fn dvr(mut self, window: Option<Duration>) -> Self {
+        self.dvr = window;
+        self
+    }
+
+    /// Most bytes kept for rewinding, the oldest chunks are evicted first
+    pub fn dvr_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.dvr_max_bytes = max_bytes;
+        self
+    }
+
+    /// Hold the whole stream back this long before fanning it out
+    pub fn delay(mut self, delay: Option<BroadcastDelay>) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Serve Prometheus metrics on `/metrics`
+    pub fn metrics(mut self, addr: Option<SocketAddr>) -> Self {
+        self.metrics = addr;
+        self
+    }
+
+    /// Serve a JSON status on `/status`, along with `/healthz` and `/livez`
+    pub fn status(mut self, addr: Option<SocketAddr>) -> Self {
+        self.status = addr;
+        self
+    }
+
+    /// Longest the producer may send nothing for `/healthz` to report healthy
+    pub fn health_max_idle(mut self, max_idle: Duration) -> Self {
+        self.health_max_idle = max_idle;
+        self
+    }
+
+    /// Accept control commands on this unix socket
+    pub fn control_socket(mut self, path: Option<PathBuf>) -> Self {
+        self.control_socket = path;
+        self
+    }
+
+    /// POST the producer and consumer events to this endpoint
+    pub fn webhook(mut self, webhook: Option<Webhook>) -> Self {
+        self.webhook = webhook;
+        self
+    }
+
+    /// Notify the webhook when the number of consumers reaches or falls below `count`,
+    /// can be called more than once
+    pub fn webhook_threshold(mut self, count: usize) -> Self {
+        self.webhook_thresholds.push(count);
+        self
+    }
+
+    /// Log every finished connection to a file
+    pub fn access_log(mut self, log: Option<AccessLog>) -> Self {
+        self.access_log = log;
+        self
+    }
+
+    /// Addresses of the TCP listeners
+    fn tcp_ports(&self) -> Vec<SocketAddr> {
+        let input = match self.input {
+            Input::Tcp(addr) if self.inherited_producer.is_none() => Some(addr),
+            _ => None,
+        };
+        let output = match self.output {
+            Output::Tcp(addr) if self.inherited_consumers.is_none() => Some(addr),
+            _ => None,
+        };
+
+        [input, output, self.ws, self.metrics, self.status, self.hls_port].iter().flatten().cloned().collect()
+    }
+
+    /// Bind the listeners and start serving on `rt`
+    pub fn spawn(self, rt: &Runtime) -> io::Result<Restreamer> {
+        let _guard = rt.enter();
+
+        let filter = match self.pid_filter.clone() {
+            Some(filter) if self.strip_nulls => Some(filter.strip_nulls()),
+            None if self.strip_nulls => Some(PidFilter::drop(&[]).strip_nulls()),
+            filter => filter,
+        };
+
+        if (filter.is_some() || self.program.is_some() || self.remap.is_some()) && !self.align {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "filtering or remapping PIDs requires aligned chunks"));
+        }
+
+        if self.settings.validate_input.is_some() && !self.align {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "validating the input requires aligned chunks"));
+        }
+
+        if let Some(ref hls) = self.hls {
+            if !self.align {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "HLS requires aligned chunks"));
+            }
+            if hls.dir.is_none() && self.hls_port.is_none() && self.status.is_none() && self.metrics.is_none() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "HLS needs a directory or a port to serve the segments on"));
+            }
+        } else if self.hls_port.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "an HLS port requires HLS"));
+        }
+
+        if self.inherited_producer.is_some() && !matches!(self.input, Input::Tcp(_)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "an inherited producer listener requires TCP producers"));
+        }
+        if self.inherited_consumers.is_some() && !matches!(self.output, Output::Tcp(_)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "inherited consumer listeners require TCP consumers"));
+        }
+
+        if self.stream_keys {
+            let tcp = matches!(self.input, Input::Tcp(_)) && matches!(self.output, Output::Tcp(_));
+            if !tcp || self.http || self.ws.is_some() || self.slate.is_some() || self.generate.is_some() || !self.udp_out.is_empty() || !self.push.is_empty() || !self.push_srt.is_empty() || !self.push_rist.is_empty() || self.record.is_some() || self.sink.is_some() || self.hls.is_some() || self.dvr.is_some() || self.delay.is_some() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "stream keys require TCP producers and consumers, without HTTP, WebSocket, slate, test signal, UDP outputs, push, recording, sink, HLS, DVR or delay"));
+            }
+        }
+
+        if self.slate.is_some() && self.generate.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "a slate and a test signal cannot both fill in for the producer"));
+        }
+        let generated = match self.input {
+            Input::Generate(bitrate) => Some(bitrate),
+            _ => self.generate,
+        };
+        if generated.is_some_and(|bitrate| bitrate < generate::MIN_BITRATE) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("the test signal needs at least {} bit/s", generate::MIN_BITRATE)));
+        }
+
+        if !self.push_rist.is_empty() && cfg!(not(feature = "rist")) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "RIST support requires building with the rist feature"));
+        }
+        if let Some(addr) = self.push_rist.iter().find(|addr| !addr.port().is_multiple_of(2)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("RIST needs an even port, {} is odd", addr)));
+        }
+
+        let srt = matches!(self.input, Input::Srt(_)) || !self.push_srt.is_empty();
+        if srt && cfg!(not(feature = "srt")) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "SRT support requires building with the srt feature"));
+        }
+        if self.srt != SrtOptions::default() && !srt {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the SRT options require an SRT input or push target"));
+        }
+        self.srt.check().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if self.local_bind.is_set() && !self.push_srt.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the SRT push targets cannot be bound to a local address or device"));
+        }
+
+        // Bound once now, so a wrong address or device fails at startup rather than on every attempt
+        for &(addr, ref bind, _) in &self.push {
+            let bind = bind.or(&self.local_bind);
+            if bind.is_set() {
+                net::tcp_socket(&addr, &bind).map_err(|e| bind.error(e, &format!("push target tcp://{}", addr)))?;
+            }
+        }
+
+        match (self.input_backup.as_ref(), self.failover_timeout) {
+            (None, None) => (),
+            (None, Some(_)) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "a failover timeout requires a backup input"));
+            }
+            (Some(&Input::Udp(_)), None) if matches!(self.input, Input::Udp(_)) => (),
+            (Some(_), None) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "without a failover timeout, the backup input is merged and both must be UDP"));
+            }
+            (Some(&Input::File(_)), Some(_)) | (Some(&Input::Pull(_)), Some(_)) | (Some(&Input::Udp(_)), Some(_)) => {
+                if self.slate.is_some() || self.generate.is_some() || self.stream_keys {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "a backup input cannot be used with a slate, a test signal or stream keys"));
+                }
+            }
+            (Some(input), Some(_)) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported backup input {}, expected a file, a pulled TCP source or UDP", input)));
+            }
+        }
+
+        if self.producer_tls.is_some() && !matches!(self.input, Input::Tcp(_)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "producer TLS requires a TCP producer"));
+        }
+
+        if self.tls.is_some() && !matches!(self.output, Output::Tcp(_)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "TLS requires TCP consumers"));
+        }
+
+        #[cfg(not(unix))]
+        {
+            if matches!(self.input, Input::Unix(_)) || matches!(self.output, Output::Unix(_)) || self.control_socket.is_some() {
+                return Err(unix::unsupported());
+            }
+        }
+
+        net::set_backlog(self.backlog);
+        if let Some(timeout) = self.bind_retry {
+            net::wait_for_ports(&self.tcp_ports(), timeout)?;
+        }
+
+        // Without the privileges to bind them again, the listeners are kept for the whole lifetime
+        #[cfg(unix)]
+        let keep_listeners = self.account.is_some();
+        #[cfg(not(unix))]
+        let keep_listeners = false;
+
+        // The consumers are served once a producer connects, a busy port must not wait for it.
+        // Port 0 is bound right away as well, to keep the one picked by the system
+        let mut consumer_listeners = match self.output {
+            Output::Tcp(_) if self.inherited_consumers.is_some() => self.inherited_consumers.clone(),
+            Output::Tcp(ref addr) if keep_listeners || addr.port() == 0 => Some(Arc::new(net::bind_many(addr, self.reuseport)?)),
+            Output::Tcp(ref addr) => {
+                net::check(addr)?;
+                None
+            }
+            Output::Unix(_) => None,
+        };
+        let mut producer_listener = match self.input {
+            Input::Tcp(_) if self.inherited_producer.is_some() => self.inherited_producer.clone(),
+            Input::Tcp(ref addr) if keep_listeners || addr.port() == 0 => Some(Arc::new(net::bind_port(addr)?)),
+            _ => None,
+        };
+        let producer_addr = match (producer_listener.as_ref(), &self.input) {
+            (Some(listener), _) => Some(listener.local_addr()?),
+            (None, &Input::Tcp(addr)) => Some(addr),
+            _ => None,
+        };
+        let consumer_addr = match (consumer_listeners.as_ref(), &self.output) {
+            (Some(listeners), _) => Some(listeners[0].local_addr()?),
+            (None, &Output::Tcp(addr)) => Some(addr),
+            _ => None,
+        };
+
+        #[cfg(unix)]
+        if let Some(ref account) = self.account {
+            account.switch().map_err(|e| io::Error::new(e.kind(), format!("cannot switch to {}: {}", account, e)))?;
+        }
+
+        // Created by the account serving it
+        #[cfg(unix)]
+        if let Output::Unix(ref path) = self.output {
+            drop(unix::listen(path, self.socket_mode)?);
+        }
+
+        if let Some(ref addr) = self.ws {
+            net::check(addr)?;
+        }
+
+        let chunk_size = ts::chunk_size(self.buffer_size);
+        let stats = Stats::for_channel(self.channel);
+        let state = Arc::new(Mutex::new(Shared::new(self.settings.clone(), self.burst, filter.clone(), self.program,
+                                                    self.remap.clone(), chunk_size, stats)));
+        if let Some(window) = self.dvr {
+            state.lock().unwrap().fanout.set_dvr(Dvr::new(window, self.dvr_max_bytes));
+        }
+        if let Some(ref delay) = self.delay {
+            let state = state.lock().unwrap();
+            delay::spawn(delay.clone(), &state.fanout, Some(state.stats.clone()))?;
+        }
+
+        let (shutdown_tx, shutdown) = oneshot::channel::<()>();
+        let shutdown = shutdown.shared();
+
+        let playlist = match self.hls {
+            Some(ref hls) => {
+                let playlist = hls::spawn(hls.clone(), &state, self.program)?;
+                let fanout = state.lock().unwrap().fanout.clone();
+
+                // Closing the queue stops the segmenter
+                rt.spawn(shutdown.clone().map(move |_| {
+                    fanout.remove(&hls::SEGMENTER_ADDR);
+                }));
+
+                Some(playlist)
+            }
+            None => None,
+        };
+
+        if let (Some(addr), Some(playlist)) = (self.hls_port, playlist.clone()) {
+            let server = http::serve(&addr, move |req| {
+                hls::respond(&playlist, &req.path)
+                    .unwrap_or_else(|| http::response("404 Not Found", "text/plain", "", b"Not found\n"))
+            })?;
+
+            rt.spawn(until_shutdown(server, &shutdown));
+        }
+
+        if let Some(addr) = self.metrics {
+            let stats = state.lock().unwrap().stats.clone();
+            let playlist = playlist.clone();
+            let metrics = http::serve(&addr, move |req| {
+                if req.path == "/metrics" {
+                    http::response("200 OK", "text/plain; version=0.0.4", "", stats.prometheus().as_bytes())
+                } else if let Some(res) = playlist.as_ref().and_then(|playlist| hls::respond(playlist, &req.path)) {
+                    res
+                } else {
+                    http::response("404 Not Found", "text/plain", "", b"Not found\n")
+                }
+            })?;
+
+            rt.spawn(until_shutdown(metrics, &shutdown));
+        }
+
+        #[cfg(unix)]
+        if let Some(ref path) = self.control_socket {
+            let control = control::serve(path, state.clone())?;
+
+            rt.spawn(until_shutdown(control, &shutdown));
+        }
+
+        if let Some(addr) = self.status {
+            let stats = state.lock().unwrap().stats.clone();
+
+            let sampler = {
+                let stats = stats.clone();
+                async move {
+                    let mut ticks = crate::interval(Duration::from_secs(1));
+                    loop {
+                        ticks.tick().await;
+                        stats.sample();
+                    }
+                }
+            };
+
+            let playlist = playlist.clone();
+            let max_idle = self.health_max_idle;
+            let status = http::serve(&addr, move |req| {
+                if req.path == "/status" {
+                    http::response("200 OK", "application/json", "", stats.json().as_bytes())
+                } else if req.path == "/healthz" {
+                    let (healthy, body) = stats.health(max_idle);
+                    let status = if healthy { "200 OK" } else { "503 Service Unavailable" };
+                    http::response(status, "application/json", "", body.as_bytes())
+                } else if req.path == "/livez" {
+                    // Answered by the runtime, that is all it tells
+                    http::response("200 OK", "application/json", "", b"{\"live\": true}\n")
+                } else if let Some(res) = playlist.as_ref().and_then(|playlist| hls::respond(playlist, &req.path)) {
+                    res
+                } else {
+                    http::response("404 Not Found", "text/plain", "", b"Not found\n")
+                }
+            })?;
+
+            rt.spawn(until_shutdown(sampler, &shutdown));
+            rt.spawn(until_shutdown(status, &shutdown));
+        }
+
+        let stats = state.lock().unwrap().stats.clone();
+        if let Some(ref hook) = self.webhook {
+            let (notifier, delivery) = webhook::spawn(hook.clone(), self.webhook_thresholds.clone(), self.channel);
+            stats.set_webhook(notifier);
+            rt.spawn(until_shutdown(delivery, &shutdown));
+        }
+        let access_log_reopen = match self.access_log {
+            Some(ref log) => Some(access::spawn(log.clone(), stats.events.subscribe())?),
+            None => None,
+        };
+        rt.spawn(until_shutdown(cc::report(stats.continuity.clone()), &shutdown));
+        rt.spawn(until_shutdown(tr101290::watch(Arc::downgrade(&state)), &shutdown));
+        rt.spawn(until_shutdown(stats::report(stats), &shutdown));
+
+        if let Some(timeout) = self.stall_timeout {
+            rt.spawn(until_shutdown(stall::watch(state.clone(), timeout, self.disconnect_on_stall), &shutdown));
+        }
+
+        if let Some(interval) = self.keepalive_nulls {
+            rt.spawn(until_shutdown(keepalive::send(state.clone(), interval), &shutdown));
+        }
+
+        for target in &self.udp_out {
+            let rtp = if target.rtp || self.rtp_out {
+                Some(RtpState::new(self.rtp_ssrc, self.rtp_pt))
+            } else {
+                None
+            };
+            let output = udp::UdpOutput::new(target.addr, &target.bind.or(&self.local_bind), state.clone(), self.udp_packets * ts::PACKET_SIZE, self.ttl, rtp, self.pace)?
+                .dscp(self.udp_dscp.or(self.settings.dscp))
+                .cbr(target.cbr.or(self.cbr));
+
+            info!("Adding UDP Output ({})", target);
+
+            rt.spawn(until_shutdown(output.unwrap_or_else(|e| error!("FAIL {:?}", e)), &shutdown));
+        }
+
+        #[cfg(feature = "rist")]
+        for addr in &self.push_rist {
+            let rist = Rist::new(*addr, self.rist_buffer)?;
+            let mut stats = PeerStats::new(*addr);
+            stats.rist = Some(rist.stats());
+
+            let rtp = RtpState::new(self.rtp_ssrc, self.rtp_pt).even_ssrc();
+            let output = udp::UdpOutput::with_stats(*addr, &self.local_bind, state.clone(), self.udp_packets * ts::PACKET_SIZE, self.ttl, Some(rtp), self.pace, stats)?
+                .dscp(self.push_dscp.or(self.settings.dscp))
+                .cbr(self.cbr)
+                .rist(rist);
+
+            info!("Adding RIST Output (rist://{})", addr);
+
+            rt.spawn(until_shutdown(output.unwrap_or_else(|e| error!("FAIL {:?}", e)), &shutdown));
+        }
+
+        for &(addr, ref bind, cbr) in &self.push {
+            let bind = bind.or(&self.local_bind);
+            if bind.is_set() {
+                info!("Pushing to {:?} from {}", addr, bind);
+            } else {
+                info!("Pushing to {:?}", addr);
+            }
+
+            let push = push::push(addr, bind, self.push_dscp, cbr.or(self.cbr), state.clone(), shutdown.clone(), self.buffer_size, self.push_backoff);
+            rt.spawn(until_shutdown(push, &shutdown));
+        }
+
+        #[cfg(feature = "srt")]
+        for addr in &self.push_srt {
+            info!("Pushing to srt://{}", addr);
+
+            let push = libsrt::push(*addr, self.srt.clone(), self.cbr, state.clone(), shutdown.clone(), self.buffer_size, self.push_backoff);
+            rt.spawn(until_shutdown(push, &shutdown));
+        }
+
+        let recording = match self.record {
+            Some(ref record) => {
+                let finished = record::spawn(record.clone(), &state)?;
+                let fanout = state.lock().unwrap().fanout.clone();
+
+                // Closing the queue lets the recorder close the last file
+                rt.spawn(shutdown.clone().map(move |_| {
+                    fanout.remove(&record::RECORDER_ADDR);
+                }));
+
+                Some(finished.shared())
+            }
+            None => None,
+        };
+
+        let sink_flushed = match self.sink {
+            Some(ref sink) => {
+                let (addr, writer) = sink::spawn(sink.clone(), &state)?;
+                let fanout = state.lock().unwrap().fanout.clone();
+                let (flushed, flushed_rx) = oneshot::channel::<()>();
+
+                rt.spawn(writer.map(move |()| {
+                    let _ = flushed.send(());
+                }));
+                // Closing the queue lets the sink write what is left
+                rt.spawn(shutdown.clone().map(move |_| {
+                    fanout.remove(&addr);
+                }));
+
+                Some(flushed_rx.shared())
+            }
+            None => None,
+        };
+
+        let router = if self.stream_keys {
+            let router = Arc::new(Router::new(state.clone(), self.burst, filter, self.program, self.remap.clone(), chunk_size,
+                                              self.buffer_size, self.align, self.takeover, self.wait_for_producer));
+            let consumers = router.clone();
+            let tls = self.tls.clone();
+
+            let addr = match self.output {
+                Output::Tcp(addr) => addr,
+                Output::Unix(_) => unreachable!("stream keys require TCP consumers"),
+            };
+
+            // The same listeners for all the streams, for the whole lifetime
+            let listeners = match consumer_listeners.take() {
+                Some(listeners) => listeners.iter().map(net::adopt).collect::<io::Result<_>>()?,
+                None => net::listen_many(&addr, self.reuseport)?,
+            };
+            let counters = state.lock().unwrap().stats.accept_counters(listeners.len());
+
+            for (listener, accepts) in listeners.into_iter().zip(counters) {
+                let (consumers, tls) = (consumers.clone(), tls.clone());
+                let srv_cons = net::accept_loop(listener, move |socket| {
+                    accepts.fetch_add(1, Ordering::Relaxed);
+                    keys::accept_consumer(consumers.clone(), socket, tls.clone());
+                });
+
+                rt.spawn(until_shutdown(srv_cons, &shutdown));
+            }
+
+            Some(router)
+        } else {
+            None
+        };
+
+        let buffer_size = self.buffer_size;
+        let align = self.align;
+        let slate = self.slate.is_some() || self.generate.is_some() || self.failover_timeout.is_some();
+
+        let serve = {
+            let output = self.output.clone();
+            let (socket_mode, http_out, ws, tls, loops) = (self.socket_mode, self.http, self.ws, self.tls.clone(), self.reuseport);
+            let bound = consumer_listeners;
+            move |state: Arc<Mutex<Shared>>, rx: OneShotSharedRx| {
+                if let Some(ref addr) = ws {
+                    serve_ws_consumers(addr, state.clone(), rx.clone(), buffer_size);
+                }
+                serve_consumers(&output, state, rx, buffer_size, http_out, tls.clone(), socket_mode, loops, bound.as_ref().map(|bound| bound.as_slice()));
+            }
+        };
+
+        // With a slate or a backup input the consumers are served for as long as it plays
+        let start_consumers = {
+            let serve = serve.clone();
+            move |state: Arc<Mutex<Shared>>, rx: OneShotSharedRx| {
+                if !slate {
+                    serve(state, rx);
+                }
+            }
+        };
+
+        if let Some(ref path) = self.slate {
+            let cons_state = state.clone();
+            let serve = serve.clone();
+
+            let player = play::play(path.clone(), state.clone(), buffer_size, self.play_bitrate, move |rx| {
+                serve(cons_state, rx);
+            })?;
+
+            rt.spawn(until_shutdown(player, &shutdown));
+        }
+
+        if let Some(bitrate) = self.generate {
+            let cons_state = state.clone();
+            let serve = serve.clone();
+
+            let generator = generate::generate(bitrate, state.clone(), buffer_size, move |rx| {
+                serve(cons_state, rx);
+            });
+
+            rt.spawn(until_shutdown(generator, &shutdown));
+        }
+
+        if let (Some(timeout), Some(ref input)) = (self.failover_timeout, self.input_backup.as_ref()) {
+            let failover = Arc::new(Failover::new(timeout, self.failback_delay));
+            state.lock().unwrap().fanout.set_failover(failover.clone());
+
+            let backup = Arc::new(Mutex::new(Shared::new(self.settings.clone(), None, None, None, None, chunk_size, Stats::default())));
+            let srv_backup = self.backup_producer(input, &backup)?;
+            rt.spawn(until_shutdown(srv_backup, &shutdown));
+            info!("Switching to {} after {:?} without data", input, timeout);
+
+            let (done, rx) = oneshot::channel::<()>();
+            rt.spawn(until_shutdown(failover::run(failover, &state, &backup, done), &shutdown));
+
+            let cons_state = state.clone();
+            let serve = serve.clone();
+            rt.spawn(async move {
+                serve(cons_state, rx.shared());
+            });
+        }
+
+        let mut stdin_closed = None;
+
+        match self.input {
+            Input::Udp(input_addr) => {
+                let (socket, group) = udp::bind(&input_addr, self.input_iface.as_deref())
+                    .map_err(|e| io::Error::new(e.kind(), format!("cannot bind UDP port {} ({}): {}", input_addr.port(), input_addr, e)))?;
+                let cons_state = state.clone();
+
+                let mut srv_prod = udp::UdpProducer::new(socket, group, state.clone(), self.udp_timeout, move |rx| {
+                    start_consumers(cons_state.clone(), rx);
+                }).rtp(self.rtp_in);
+
+                if let (Some(Input::Udp(backup_addr)), None) = (self.input_backup.clone(), self.failover_timeout) {
+                    let (socket, group) = udp::bind(&backup_addr, self.input_iface.as_deref())
+                        .map_err(|e| io::Error::new(e.kind(), format!("cannot bind UDP port {} ({}): {}", backup_addr.port(), backup_addr, e)))?;
+                    info!("Merging the RTP streams received on {} and {}", input_addr, backup_addr);
+                    srv_prod = srv_prod.backup(socket, group, [input_addr, backup_addr], self.merge_window);
+                }
+
+                rt.spawn(until_shutdown(srv_prod.unwrap_or_else(|e| error!("FAIL {:?}", e)), &shutdown));
+            }
+            Input::Pull(input_addr) => {
+                let cons_state = state.clone();
+
+                let srv_prod = pull::pull(input_addr, state.clone(), buffer_size, align, self.pull_backoff, self.pull_timeout, move |rx| {
+                    start_consumers(cons_state, rx);
+                });
+
+                rt.spawn(until_shutdown(srv_prod, &shutdown));
+            }
+            Input::File(ref path) => {
+                let cons_state = state.clone();
+
+                let srv_prod = play::play(path.clone(), state.clone(), buffer_size, self.play_bitrate, move |rx| {
+                    start_consumers(cons_state, rx);
+                })?;
+
+                rt.spawn(until_shutdown(srv_prod, &shutdown));
+            }
+            Input::Generate(bitrate) => {
+                let cons_state = state.clone();
+
+                let srv_prod = generate::generate(bitrate, state.clone(), buffer_size, move |rx| {
+                    start_consumers(cons_state, rx);
+                });
+
+                rt.spawn(until_shutdown(srv_prod, &shutdown));
+            }
+            Input::Tcp(input_addr) => {
+                let l_prod = match producer_listener.take() {
+                    Some(listener) => net::adopt(&listener)?,
+                    None => net::listen(&input_addr)?,
+                };
+                let prod_state = state.clone();
+                let producer_tls = self.producer_tls.clone();
+                let setup = self.producer_setup(&state, &router, start_consumers);
+
+                let srv_prod = net::accept_loop(l_prod, move |socket| {
+                    let (setup, producer_tls) = (setup.clone(), producer_tls.clone());
+                    accept_tcp(socket, &prod_state, true, move |socket, addr| {
+                        match producer_tls {
+                            Some(ref tls) => tls.accept(socket, addr, move |stream| setup.accept(stream)),
+                            None => setup.accept(socket),
+                        }
+                    });
+                });
+
+                rt.spawn(until_shutdown(srv_prod, &shutdown));
+            }
+            Input::Stdin => {
+                let (closed_tx, closed) = oneshot::channel::<()>();
+                stdin_closed = Some(closed.shared());
+                let state = state.clone();
+
+                rt.spawn(async move {
+                    let packets = TSPacket::new(StdinPeer::new(), buffer_size, align);
+                    if let Some(rx) = setup_producer(packets, state.clone(), false) {
+                        start_consumers(state, rx.clone());
+                        // Its end is the end of the session
+                        let _ = rx.await;
+                        let _ = closed_tx.send(());
+                    }
+                });
+            }
+            // Local producers, the access lists do not apply
+            #[cfg(unix)]
+            Input::Unix(ref path) => {
+                let setup = self.producer_setup(&state, &router, start_consumers);
+
+                let srv_prod = unix::accept_loop(unix::listen(path, self.socket_mode)?, move |socket| {
+                    setup.clone().accept(socket);
+                });
+
+                rt.spawn(until_shutdown(srv_prod, &shutdown));
+            }
+            #[cfg(not(unix))]
+            Input::Unix(_) => unreachable!(),
+            #[cfg(feature = "srt")]
+            Input::Srt(url) => {
+                let setup = self.producer_setup(&state, &router, start_consumers);
+
+                let srv_prod = libsrt::producers(url, self.srt.clone(), state.clone(), self.pull_backoff)?
+                    .for_each(move |peer| {
+                        setup.clone().accept(peer);
+                        future::ready(())
+                    });
+
+                rt.spawn(until_shutdown(srv_prod, &shutdown));
+            }
+            #[cfg(not(feature = "srt"))]
+            Input::Srt(_) => unreachable!(),
+        }
+
+        let mut sockets = Vec::new();
+        if let Input::Unix(ref path) = self.input {
+            sockets.push(path.clone());
+        }
+        if let Output::Unix(ref path) = self.output {
+            sockets.push(path.clone());
+        }
+
+        Ok(Restreamer {
+            state,
+            router,
+            shutdown: Arc::new(Mutex::new(Some(shutdown_tx))),
+            recording,
+            sink_flushed,
+            access_log_reopen,
+            sockets,
+            stdin_closed,
+            producer_addr,
+            consumer_addr,
+        })
+    }
+
+    /// The producer of the backup input, fanning out into `state`
+    fn backup_producer(&self, input: &Input, state: &Arc<Mutex<Shared>>) -> io::Result<Pin<Box<dyn Future<Output = ()> + Send>>> {
+        Ok(match *input {
+            Input::File(ref path) => {
+                Box::pin(play::play(path.clone(), state.clone(), self.buffer_size, self.play_bitrate, |_| ())?)
+            }
+            Input::Pull(addr) => {
+                Box::pin(pull::pull(addr, state.clone(), self.buffer_size, self.align, self.pull_backoff, self.pull_timeout, |_| ()))
+            }
+            Input::Udp(addr) => {
+                let (socket, group) = udp::bind(&addr, self.input_iface.as_deref())
+                    .map_err(|e| io::Error::new(e.kind(), format!("cannot bind UDP port {} ({}): {}", addr.port(), addr, e)))?;
+                Box::pin(udp::UdpProducer::new(socket, group, state.clone(), self.udp_timeout, |_| ())
+                    .rtp(self.rtp_in)
+                    .unwrap_or_else(|e| error!("Backup input failed: {:?}", e)))
+            }
+            _ => unreachable!(),
+        })
+    }
+
+    fn producer_setup<F>(&self, state: &Arc<Mutex<Shared>>, router: &Option<Arc<Router>>, start_consumers: F) -> ProducerSetup<F> {
+        ProducerSetup {
+            state: state.clone(),
+            router: router.clone(),
+            token: self.token.clone(),
+            buffer_size: self.buffer_size,
+            align: self.align,
+            takeover: self.takeover,
+            start_consumers,
+        }
+    }
+}
+
+/// A running restreamer
+#[derive(Clone)]
+pub struct Restreamer {
+    state: Arc<Mutex<Shared>>,
+    /// The streams by key, when routing on them
+    router: Option<Arc<Router>>,
+    /// Dropping it stops the listeners
+    shutdown: Arc<Mutex<Option<OneShotTx>>>,
+    /// Resolves once the recording is closed
+    recording: Option<future::Shared<OneShotRx>>,
+    /// Resolves once the sink wrote what was queued
+    sink_flushed: Option<future::Shared<OneShotRx>>,
+    /// Set to reopen the access log, e.g. once rotated
+    access_log_reopen: Option<Arc<AtomicBool>>,
+    /// Unix socket files to remove on shutdown
+    sockets: Vec<PathBuf>,
+    /// Resolves once the standard input reached its end
+    stdin_closed: Option<OneShotSharedRx>,
+    producer_addr: Option<SocketAddr>,
+    consumer_addr: Option<SocketAddr>,
+}
+
+impl Restreamer {
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    /// Counters of the producer and the consumers
+    pub fn stats(&self) -> Arc<Stats> {
+        self.state.lock().unwrap().stats.clone()
+    }
+
+    /// Counters of the stream published on `key`, when routing on the stream keys
+    pub fn stream_stats(&self, key: &str) -> Option<Arc<Stats>> {
+        self.router.as_ref().and_then(|router| router.stats(key))
+    }
+
+    /// Connections, disconnections and stalls from now on, of every stream
+    ///
+    /// The oldest events are dropped once the subscriber lags too far behind,
+    /// the stream is never held up.
+    pub fn events(&self) -> Events {
+        self.state.lock().unwrap().stats.events.subscribe()
+    }
+
+    /// Every stream served, one per key when routing on them
+    fn states(&self) -> Vec<Arc<Mutex<Shared>>> {
+        let mut states = vec![self.state.clone()];
+        if let Some(ref router) = self.router {
+            states.extend(router.states());
+        }
+        states
+    }
+
+    /// Address the TCP producers connect to, with the port picked when 0 was given
+    pub fn producer_addr(&self) -> Option<SocketAddr> {
+        self.producer_addr
+    }
+
+    /// Address the TCP consumers connect to, with the port picked when 0 was given
+    pub fn consumer_addr(&self) -> Option<SocketAddr> {
+        self.consumer_addr
+    }
+
+    /// Consumers currently connected, the UDP outputs are not counted
+    pub fn consumers(&self) -> usize {
+        self.states().iter().map(|state| state.lock().unwrap().consumers.len()).sum()
+    }
+
+    /// Swap in new settings, applied to the connected peers as well
+    pub fn update_settings(&self, settings: Settings) {
+        if let Some(ref router) = self.router {
+            router.update_settings(&settings);
+        }
+        self.state.lock().unwrap().update_settings(settings);
+    }
+
+    /// Reopen the access log before writing the next line, once it was rotated
+    pub fn reopen_access_log(&self) {
+        if let Some(ref reopen) = self.access_log_reopen {
+            reopen.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Resolves once the standard input reached its end, never with the other inputs
+    pub fn stdin_closed(&self) -> impl Future<Output = ()> + Send {
+        match self.stdin_closed {
+            Some(ref closed) => Either::Left(closed.clone().map(|_| ())),
+            None => Either::Right(future::pending()),
+        }
+    }
+
+    /// Stop accepting connections and disconnect the producer.
+    ///
+    /// The future resolves once the consumers sent what was left in their queue
+    /// and the recording and the sink are closed.
+    pub fn stop(&self) -> impl Future<Output = ()> + Send {
+        self.state.lock().unwrap().shutdown();
+        if let Some(ref router) = self.router {
+            router.shutdown();
+        }
+        self.shutdown.lock().unwrap().take();
+
+        for path in &self.sockets {
+            if let Err(e) = unix::remove(path) {
+                error!("Cannot remove {}: {}", path.display(), e);
+            }
+        }
+
+        let restreamer = self.clone();
+
+        let recording = match self.recording {
+            Some(ref finished) => Either::Left(finished.clone().map(|_| ())),
+            None => Either::Right(future::ready(())),
+        };
+        let sink_flushed = match self.sink_flushed {
+            Some(ref flushed) => Either::Left(flushed.clone().map(|_| ())),
+            None => Either::Right(future::ready(())),
+        };
+
+        let drained = async move {
+            let mut ticks = crate::interval(Duration::from_millis(100));
+            loop {
+                ticks.tick().await;
+                if restreamer.consumers() == 0 {
+                    break;
+                }
+            }
+        };
+
+        future::join3(drained, recording, sink_flushed).map(|_| ())
+    }
+}
+
@@ -0,0 +1,289 @@
+//! RIST simple profile output (VSF TR-06-1)
+//!
+//! The stream goes out as RTP, the datagrams sent are kept for a while and
+//! sent again when the receiver reports them lost over RTCP, flagged by the
+//! lowest bit of the SSRC. Sender reports keep the receiver informed of where
+//! to send its requests.
+
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+use tokio::time::{self, Interval};
+use bytes::Bytes;
+use log::debug;
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::rtp::{self, RtpState};
+use crate::stats::RistStats;
+use crate::udp;
+
+/// How often the sender reports go out
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// Seconds from 1900, the NTP epoch, to 1970
+const NTP_OFFSET: u64 = 2_208_988_800;
+const CNAME: &str = "restream";
+
+const VERSION: u8 = 2;
+const PT_SR: u8 = 200;
+const PT_SDES: u8 = 202;
+const PT_APP: u8 = 204;
+const PT_RTPFB: u8 = 205;
+/// Generic NACK, in the RTPFB format field
+const FMT_NACK: u8 = 1;
+/// Range NACK, in the subtype of the RIST APP packets
+const SUBTYPE_RANGE_NACK: u8 = 0;
+
+fn seq(datagram: &[u8]) -> u16 {
+    u16::from_be_bytes([datagram[2], datagram[3]])
+}
+
+/// The sequence numbers a compound RTCP packet reports lost, in generic or range NACKs
+pub fn parse_nacks(buf: &[u8]) -> Vec<u16> {
+    let mut lost = Vec::new();
+    let mut pos = 0;
+
+    while pos + 4 <= buf.len() {
+        let pkt = &buf[pos..];
+        if pkt[0] >> 6 != VERSION {
+            break;
+        }
+        let len = (usize::from(u16::from_be_bytes([pkt[2], pkt[3]])) + 1) * 4;
+        if len > pkt.len() {
+            break;
+        }
+        let fmt = pkt[0] & 0x1f;
+
+        match pkt[1] {
+            // Lost packet and bitmask of the 16 following
+            PT_RTPFB if fmt == FMT_NACK && len >= 12 => {
+                for fci in pkt[12..len].chunks_exact(4) {
+                    let pid = u16::from_be_bytes([fci[0], fci[1]]);
+                    let blp = u16::from_be_bytes([fci[2], fci[3]]);
+                    lost.push(pid);
+                    lost.extend((0..16).filter(|i| blp & (1 << i) != 0).map(|i| pid.wrapping_add(i + 1)));
+                }
+            }
+            // First lost packet and count of the following
+            PT_APP if fmt == SUBTYPE_RANGE_NACK && len >= 12 && &pkt[8..12] == b"RIST" => {
+                for range in pkt[12..len].chunks_exact(4) {
+                    let start = u16::from_be_bytes([range[0], range[1]]);
+                    let extra = u16::from_be_bytes([range[2], range[3]]);
+                    lost.extend((0..=extra).map(|i| start.wrapping_add(i)));
+                }
+            }
+            _ => (),
+        }
+
+        pos += len;
+    }
+
+    lost
+}
+
+/// A sender report followed by the CNAME, as a compound RTCP packet
+fn sender_report(ssrc: u32, now: SystemTime, timestamp: u32, packets: u32, octets: u32) -> Vec<u8> {
+    let since = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let ntp_secs = (since.as_secs() + NTP_OFFSET) as u32;
+    let ntp_frac = ((u64::from(since.subsec_nanos()) << 32) / 1_000_000_000) as u32;
+
+    let mut out = vec![VERSION << 6, PT_SR, 0, 6];
+    for word in &[ssrc, ntp_secs, ntp_frac, timestamp, packets, octets] {
+        out.extend_from_slice(&word.to_be_bytes());
+    }
+
+    // The items end with a null byte, padded to a whole word
+    let mut chunk = ssrc.to_be_bytes().to_vec();
+    chunk.extend_from_slice(&[1, CNAME.len() as u8]);
+    chunk.extend_from_slice(CNAME.as_bytes());
+    chunk.push(0);
+    while !chunk.len().is_multiple_of(4) {
+        chunk.push(0);
+    }
+
+    let words = (chunk.len() / 4) as u16;
+    out.extend_from_slice(&[(VERSION << 6) | 1, PT_SDES]);
+    out.extend_from_slice(&words.to_be_bytes());
+    out.extend(chunk);
+
+    out
+}
+
+/// The datagrams sent over the last `window`
+struct RetransmitBuffer {
+    window: Duration,
+    datagrams: VecDeque<(Instant, Bytes)>,
+}
+
+impl RetransmitBuffer {
+    fn push(&mut self, now: Instant, datagram: Bytes) {
+        while self.datagrams.front().is_some_and(|&(sent, _)| now.duration_since(sent) > self.window) {
+            self.datagrams.pop_front();
+        }
+        self.datagrams.push_back((now, datagram));
+    }
+
+    /// The datagram with sequence number `seq`, the sequence numbers follow each other
+    fn get(&self, seq: u16) -> Option<&Bytes> {
+        let first = seq_of(self.datagrams.front()?);
+        self.datagrams.get(usize::from(seq.wrapping_sub(first)))
+            .map(|(_, datagram)| datagram)
+            .filter(|datagram| self::seq(datagram) == seq)
+    }
+}
+
+fn seq_of(entry: &(Instant, Bytes)) -> u16 {
+    seq(&entry.1)
+}
+
+/// Retransmissions and reports of a RIST output
+pub struct Rist {
+    /// Sends the reports, the receiver answers to it
+    rtcp: UdpSocket,
+    /// The port following the RTP one
+    rtcp_target: SocketAddr,
+    buffer: RetransmitBuffer,
+    report: Interval,
+    packets: u32,
+    octets: u32,
+    stats: Arc<RistStats>,
+    buf: Vec<u8>,
+}
+
+impl Rist {
+    /// Keep the datagrams sent to `target` for `window`, `target` must be an even port
+    pub fn new(target: SocketAddr, window: Duration) -> io::Result<Self> {
+        if !target.port().is_multiple_of(2) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("RIST needs an even port, {} is odd", target.port())));
+        }
+
+        let local: SocketAddr = match target {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+
+        Ok(Rist {
+            rtcp: udp::from_std(std::net::UdpSocket::bind(local)?)?,
+            rtcp_target: SocketAddr::new(target.ip(), target.port() + 1),
+            buffer: RetransmitBuffer { window, datagrams: VecDeque::new() },
+            report: time::interval(REPORT_INTERVAL),
+            packets: 0,
+            octets: 0,
+            stats: Arc::new(RistStats::default()),
+            buf: vec![0; 1500],
+        })
+    }
+
+    pub fn stats(&self) -> Arc<RistStats> {
+        self.stats.clone()
+    }
+
+    /// Keep a datagram about to be sent
+    pub fn sent(&mut self, datagram: &[u8]) {
+        self.packets = self.packets.wrapping_add(1);
+        self.octets = self.octets.wrapping_add((datagram.len() - rtp::HEADER_SIZE) as u32);
+        self.buffer.push(Instant::now(), Bytes::copy_from_slice(datagram));
+        self.stats.buffered.store(self.buffer.datagrams.len(), Ordering::Relaxed);
+    }
+
+    /// Send the reports due, and the datagrams requested again on `socket`
+    pub fn poll(&mut self, cx: &mut Context<'_>, socket: &UdpSocket, target: &SocketAddr, rtp: &RtpState) {
+        while self.report.poll_tick(cx).is_ready() {
+            let report = sender_report(rtp.ssrc(), SystemTime::now(), rtp.timestamp(), self.packets, self.octets);
+            if let Poll::Ready(Err(e)) = self.rtcp.poll_send_to(cx, &report, self.rtcp_target) {
+                debug!("Cannot send the RIST report to {}: {}", self.rtcp_target, e);
+            }
+        }
+
+        loop {
+            let mut buf = ReadBuf::new(&mut self.buf);
+            let n = match self.rtcp.poll_recv_from(cx, &mut buf) {
+                Poll::Ready(Ok(_)) => buf.filled().len(),
+                Poll::Pending => return,
+                Poll::Ready(Err(e)) => {
+                    debug!("Cannot receive the RIST requests of {}: {}", target, e);
+                    return;
+                }
+            };
+
+            for seq in parse_nacks(&self.buf[..n]) {
+                let datagram = match self.buffer.get(seq) {
+                    Some(datagram) => datagram,
+                    None => {
+                        self.stats.unavailable.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                };
+
+                // Flagged as a retransmission
+                let mut again = datagram.to_vec();
+                again[11] |= 1;
+                match socket.poll_send_to(cx, &again, *target) {
+                    Poll::Ready(Ok(_)) => {
+                        self.stats.retransmitted.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Poll::Ready(Err(e)) => debug!("Cannot retransmit to {}: {}", target, e),
+                    Poll::Pending => (),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(seq: u16) -> Bytes {
+        let mut datagram = vec![0; rtp::HEADER_SIZE + 188];
+        datagram[2..4].copy_from_slice(&seq.to_be_bytes());
+        datagram.into()
+    }
+
+    #[test]
+    fn nacks() {
+        // Generic NACK for 10, 11 and 14, then a range NACK for 65535 to 1
+        let mut rtcp = vec![0x81, PT_RTPFB, 0, 3, 0, 0, 0, 1, 0, 0, 0, 2, 0, 10, 0b0000_0000, 0b0000_1001];
+        rtcp.extend_from_slice(&[0x80, PT_APP, 0, 3, 0, 0, 0, 1]);
+        rtcp.extend_from_slice(b"RIST");
+        rtcp.extend_from_slice(&[0xff, 0xff, 0, 2]);
+
+        assert_eq!(parse_nacks(&rtcp), vec![10, 11, 14, 65535, 0, 1]);
+        // A receiver report alone asks for nothing
+        assert!(parse_nacks(&[0x80, 201, 0, 1, 0, 0, 0, 1]).is_empty());
+    }
+
+    #[test]
+    fn report_layout() {
+        let report = sender_report(0x1234_5678, UNIX_EPOCH + Duration::from_millis(1500), 90_000, 10, 13160);
+
+        assert_eq!(&report[..4], &[0x80, PT_SR, 0, 6]);
+        assert_eq!(&report[8..12], &(NTP_OFFSET as u32 + 1).to_be_bytes());
+        assert_eq!(&report[12..16], &0x8000_0000u32.to_be_bytes());
+        // The SDES follows, a whole number of words long
+        assert_eq!(report[29], PT_SDES);
+        assert_eq!(report.len() % 4, 0);
+        assert_eq!(&report[38..38 + CNAME.len()], CNAME.as_bytes());
+    }
+
+    #[test]
+    fn retransmit_window() {
+        let start = Instant::now();
+        let mut buffer = RetransmitBuffer { window: Duration::from_millis(100), datagrams: VecDeque::new() };
+
+        for i in 0..10u16 {
+            buffer.push(start + Duration::from_millis(u64::from(i) * 20), datagram(65530u16.wrapping_add(i)));
+        }
+
+        // Past the window, the first ones are gone
+        assert!(buffer.get(65530).is_none());
+        assert_eq!(buffer.get(65535).map(|d| seq(d)), Some(65535));
+        assert_eq!(buffer.get(3).map(|d| seq(d)), Some(3));
+        assert!(buffer.get(4).is_none());
+    }
+}
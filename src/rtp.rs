@@ -0,0 +1,249 @@
+//! RTP encapsulation and de-encapsulation (RFC 3550, RFC 2250 for the MPEG-TS payload)
+
+use log::warn;
+
+use std::process;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Size of the fixed RTP header
+pub const HEADER_SIZE: usize = 12;
+/// Static payload type for MPEG-TS
+pub const PAYLOAD_TYPE_MP2T: u8 = 33;
+/// RTP clock rate for video payloads
+pub const CLOCK_RATE: u64 = 90_000;
+
+const VERSION: u8 = 2;
+
+use crate::ts;
+
+/// Per-destination RTP state, kept across packets
+pub struct RtpState {
+    ssrc: u32,
+    payload_type: u8,
+    seq: u16,
+    base: Instant,
+    ts_offset: u32,
+}
+
+/// Some entropy to pick the initial sequence number, timestamp and default SSRC
+fn seed() -> u32 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() ^ d.as_secs() as u32)
+        .unwrap_or(0);
+
+    nanos.wrapping_mul(2_654_435_761) ^ process::id()
+}
+
+impl RtpState {
+    pub fn new(ssrc: Option<u32>, payload_type: u8) -> Self {
+        let seed = seed();
+
+        RtpState {
+            ssrc: ssrc.unwrap_or_else(|| seed.rotate_left(16)),
+            payload_type: payload_type & 0x7f,
+            seq: seed as u16,
+            base: Instant::now(),
+            ts_offset: seed.wrapping_mul(31),
+        }
+    }
+
+    /// Clear the lowest bit of the SSRC, RIST flags the retransmissions with it
+    #[cfg_attr(not(feature = "rist"), allow(dead_code))]
+    pub fn even_ssrc(mut self) -> Self {
+        self.ssrc &= !1;
+        self
+    }
+
+    #[cfg_attr(not(feature = "rist"), allow(dead_code))]
+    pub fn ssrc(&self) -> u32 {
+        self.ssrc
+    }
+
+    /// Current 90kHz timestamp, derived from the wall clock
+    pub fn timestamp(&self) -> u32 {
+        let elapsed = self.base.elapsed();
+        let ticks = elapsed.as_secs() * CLOCK_RATE
+            + u64::from(elapsed.subsec_nanos()) * CLOCK_RATE / 1_000_000_000;
+
+        self.ts_offset.wrapping_add(ticks as u32)
+    }
+
+    /// Write the header for the next packet, advancing the sequence number
+    pub fn write_header(&mut self, buf: &mut [u8], timestamp: u32) {
+        buf[0] = VERSION << 6;
+        buf[1] = self.payload_type;
+        buf[2..4].copy_from_slice(&self.seq.to_be_bytes());
+        buf[4..8].copy_from_slice(&timestamp.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.ssrc.to_be_bytes());
+
+        self.seq = self.seq.wrapping_add(1);
+    }
+}
+
+/// Split a RTP packet into sequence number and payload
+///
+/// Returns `None` if `buf` does not look like RTP.
+pub fn parse(buf: &[u8]) -> Option<(u16, &[u8])> {
+    if buf.len() < HEADER_SIZE || buf[0] >> 6 != VERSION {
+        return None;
+    }
+
+    let padding = buf[0] & 0x20 != 0;
+    let extension = buf[0] & 0x10 != 0;
+    let csrc_count = (buf[0] & 0x0f) as usize;
+    let seq = u16::from_be_bytes([buf[2], buf[3]]);
+
+    let mut start = HEADER_SIZE + csrc_count * 4;
+    if extension {
+        let ext = buf.get(start + 2..start + 4)?;
+        start += 4 + u16::from_be_bytes([ext[0], ext[1]]) as usize * 4;
+    }
+
+    let mut end = buf.len();
+    if padding {
+        end = end.checked_sub(*buf.last()? as usize)?;
+    }
+
+    if start > end {
+        return None;
+    }
+
+    Some((seq, &buf[start..end]))
+}
+
+/// Strip the RTP headers from the incoming datagrams, keeping track of the losses
+#[derive(Default)]
+pub struct RtpReceiver {
+    last_seq: Option<u16>,
+    /// Packets missing according to the sequence numbers
+    pub lost: u64,
+    /// Datagrams passed through as they are
+    pub raw: u64,
+}
+
+impl RtpReceiver {
+    /// Return the payload, or the whole datagram if it is not RTP
+    pub fn strip_header<'a>(&mut self, buf: &'a [u8]) -> &'a [u8] {
+        // Raw TS, the version bits of a sync byte are 01
+        if buf.first() == Some(&ts::SYNC_BYTE) {
+            self.raw += 1;
+            return buf;
+        }
+
+        match self.receive(buf) {
+            Some((_, payload)) => payload,
+            None => {
+                self.raw += 1;
+                buf
+            }
+        }
+    }
+
+    /// Split a RTP packet as `parse` does, counting the packets missing before it
+    pub fn receive<'a>(&mut self, buf: &'a [u8]) -> Option<(u16, &'a [u8])> {
+        let (seq, payload) = parse(buf)?;
+
+        if let Some(last) = self.last_seq {
+            let gap = seq.wrapping_sub(last).wrapping_sub(1);
+            // Reordered or duplicated packets show up as huge gaps
+            if gap > 0 && gap < 0x8000 {
+                warn!("RTP sequence gap, {} packets lost", gap);
+                self.lost += u64::from(gap);
+            }
+        }
+        self.last_seq = Some(seq);
+
+        Some((seq, payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seq(buf: &[u8]) -> u16 {
+        u16::from_be_bytes([buf[2], buf[3]])
+    }
+
+    #[test]
+    fn header_layout() {
+        let mut rtp = RtpState::new(Some(0xdead_beef), PAYLOAD_TYPE_MP2T);
+        let mut buf = [0u8; HEADER_SIZE];
+
+        rtp.write_header(&mut buf, 0x0102_0304);
+
+        assert_eq!(buf[0], 0x80);
+        assert_eq!(buf[1], 33);
+        assert_eq!(&buf[4..8], &[1, 2, 3, 4]);
+        assert_eq!(&buf[8..12], &[0xde, 0xad, 0xbe, 0xef]);
+    }
+
+    #[test]
+    fn sequence_continuity() {
+        let mut rtp = RtpState::new(None, 96);
+        let mut buf = [0u8; HEADER_SIZE];
+
+        rtp.write_header(&mut buf, rtp.timestamp());
+        let mut last = seq(&buf);
+        let ssrc = buf[8..12].to_vec();
+
+        // Enough packets to wrap around the 16 bits sequence number
+        for _ in 0..70_000 {
+            let ts = rtp.timestamp();
+            rtp.write_header(&mut buf, ts);
+
+            assert_eq!(seq(&buf), last.wrapping_add(1));
+            assert_eq!(buf[1], 96);
+            assert_eq!(&buf[8..12], &ssrc[..]);
+            last = seq(&buf);
+        }
+    }
+
+    #[test]
+    fn parse_extensions() {
+        let mut pkt = vec![0x90 | 0x01, 33, 0x12, 0x34, 0, 0, 0, 0, 0, 0, 0, 0];
+        // One CSRC and an extension with one word
+        pkt.extend_from_slice(&[1, 2, 3, 4]);
+        pkt.extend_from_slice(&[0xbe, 0xde, 0, 1, 9, 9, 9, 9]);
+        pkt.extend_from_slice(&[0x47; 188]);
+
+        let (seq, payload) = parse(&pkt).unwrap();
+        assert_eq!(seq, 0x1234);
+        assert_eq!(payload, &[0x47; 188][..]);
+
+        pkt[0] |= 0x20;
+        pkt.extend_from_slice(&[0, 0, 3]);
+        assert_eq!(parse(&pkt).unwrap().1, &[0x47; 188][..]);
+    }
+
+    #[test]
+    fn receiver_counts_losses() {
+        let mut tx = RtpState::new(None, PAYLOAD_TYPE_MP2T);
+        let mut rx = RtpReceiver::default();
+        let mut pkt = vec![0u8; HEADER_SIZE + 188];
+
+        for i in 0..100 {
+            tx.write_header(&mut pkt, 0);
+            if i % 10 == 5 {
+                continue;
+            }
+            assert_eq!(rx.strip_header(&pkt).len(), 188);
+        }
+        assert_eq!(rx.lost, 10);
+
+        assert_eq!(rx.strip_header(&[0x47; 188]).len(), 188);
+        assert_eq!(rx.raw, 1);
+    }
+
+    #[test]
+    fn timestamp_advances() {
+        let rtp = RtpState::new(None, PAYLOAD_TYPE_MP2T);
+        let start = rtp.timestamp();
+
+        ::std::thread::sleep(::std::time::Duration::from_millis(20));
+
+        let ticks = rtp.timestamp().wrapping_sub(start);
+        assert!((1800..90_000).contains(&ticks), "{} ticks", ticks);
+    }
+}
@@ -0,0 +1,137 @@
+//! Detection of a stream scrambled upstream
+//!
+//! The scrambling control bits of the packets broadcast are counted, leaving
+//! out the tables, the stuffing and the packets without a payload, whose bits
+//! mean nothing. Once most of the others come scrambled for several seconds
+//! in a row the stream is flagged, until they come in the clear again.
+
+use log::{info, warn};
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::ts;
+
+/// How long the packets are counted for before the share scrambled is checked
+const WINDOW: Duration = Duration::from_secs(1);
+/// Windows in a row over the threshold before the stream is flagged
+const SUSTAINED: u32 = 5;
+/// Share of the packets scrambled in a window for it to count
+const THRESHOLD: f64 = 0.5;
+/// The PAT and the other tables of fixed PIDs
+const LAST_TABLE_PID: u16 = 0x1f;
+const NULL_PID: u16 = 0x1fff;
+
+#[derive(Default)]
+struct State {
+    /// Start of the current window
+    started: Option<Instant>,
+    packets: u64,
+    scrambled: u64,
+    /// Windows in a row over the threshold
+    over: u32,
+    /// Tables the PMT PIDs were taken from
+    generation: Option<u64>,
+    pmt_pids: Vec<u16>,
+}
+
+#[derive(Default)]
+pub struct Scrambling {
+    state: Mutex<State>,
+    flagged: AtomicBool,
+}
+
+impl Scrambling {
+    /// Count the packets of an aligned chunk, the PMT PIDs being refreshed
+    /// whenever the tables change, telling whether the flag changed
+    pub fn inspect<F>(&self, chunk: &[u8], generation: u64, pmt_pids: F) -> Option<bool>
+        where F: FnOnce() -> Vec<u16>
+    {
+        if !ts::is_aligned(chunk) {
+            return None;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.generation != Some(generation) {
+            state.pmt_pids = pmt_pids();
+            state.generation = Some(generation);
+        }
+        self.check(&mut state, chunk, Instant::now())
+    }
+
+    fn check(&self, state: &mut State, chunk: &[u8], now: Instant) -> Option<bool> {
+        for pkt in chunk.chunks(ts::PACKET_SIZE) {
+            let pid = ts::pid(pkt);
+            // Corrupt, without a payload, stuffing or a table
+            if pkt[1] & 0x80 != 0 || pkt[3] & 0x10 == 0 || pid <= LAST_TABLE_PID || pid == NULL_PID || state.pmt_pids.contains(&pid) {
+                continue;
+            }
+            state.packets += 1;
+            if pkt[3] & 0xc0 != 0 {
+                state.scrambled += 1;
+            }
+        }
+
+        let started = *state.started.get_or_insert(now);
+        if now.duration_since(started) < WINDOW {
+            return None;
+        }
+
+        // A window with nothing to tell leaves the count as it was
+        if state.packets > 0 {
+            let share = state.scrambled as f64 / state.packets as f64;
+            state.over = if share > THRESHOLD { state.over + 1 } else { 0 };
+        }
+        state.started = Some(now);
+        state.packets = 0;
+        state.scrambled = 0;
+
+        let flagged = state.over >= SUSTAINED;
+        if self.flagged.swap(flagged, Ordering::Relaxed) == flagged {
+            return None;
+        }
+        if flagged {
+            warn!("The stream is scrambled upstream, most of its packets came encrypted for {}s, the consumers cannot decode it",
+                  SUSTAINED as u64 * WINDOW.as_secs());
+        } else {
+            info!("The stream is no longer scrambled");
+        }
+        Some(flagged)
+    }
+
+    pub fn is_flagged(&self) -> bool {
+        self.flagged.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ts::tests::packet;
+
+    #[test]
+    fn flagged_once_sustained() {
+        let scrambling = Scrambling::default();
+        let mut state = State { pmt_pids: vec![0x1000], ..State::default() };
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        // Two of three scrambled, the tables and the adaptation field only ones aside
+        let chunk: Vec<u8> = [packet(0x100, 0x90), packet(0x101, 0xd0), packet(0x102, 0x10),
+                              packet(0, 0x10), packet(0x1000, 0x10), packet(0x102, 0x20), packet(NULL_PID, 0x10)].concat();
+        for secs in 0..SUSTAINED as u64 {
+            assert_eq!(scrambling.check(&mut state, &chunk, at(secs)), None);
+        }
+        assert_eq!(scrambling.check(&mut state, &chunk, at(SUSTAINED as u64)), Some(true));
+        assert!(scrambling.is_flagged());
+
+        // Nothing but tables, no change either way
+        let tables = packet(0, 0x10);
+        assert_eq!(scrambling.check(&mut state, &tables, at(7)), None);
+
+        let clear: Vec<u8> = [packet(0x100, 0x10), packet(0x101, 0x90), packet(0x102, 0x10)].concat();
+        assert_eq!(scrambling.check(&mut state, &clear, at(8)), Some(false));
+        assert!(!scrambling.is_flagged());
+    }
+}
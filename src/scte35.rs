@@ -0,0 +1,402 @@
+//! SCTE-35 cues of the stream broadcast
+//!
+//! The splice_info_sections of the PIDs the PMTs list as SCTE-35 are
+//! assembled, and their splice_insert and time_signal commands logged and
+//! handed back with their event id, the splice time and the pre-roll, the time
+//! left until the splice by the last PCR of the program. Heartbeats and the
+//! other commands are left alone, encrypted sections too. The sections that do
+//! not parse or fail their CRC are counted and skipped.
+
+use log::{debug, info};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use crate::psi::{self, Assembler, Program};
+use crate::ts;
+
+const SCTE35_STREAM_TYPE: u8 = 0x86;
+const SPLICE_INFO_TABLE_ID: u8 = 0xfc;
+const SPLICE_INSERT: u8 = 0x05;
+const TIME_SIGNAL: u8 = 0x06;
+/// Clock of the PTS and of the PCR bases
+const PTS_HZ: u64 = 90_000;
+const PTS_WRAP: u64 = 1 << 33;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpliceCommand {
+    SpliceInsert,
+    TimeSignal,
+}
+
+impl fmt::Display for SpliceCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            SpliceCommand::SpliceInsert => "splice_insert",
+            SpliceCommand::TimeSignal => "time_signal",
+        })
+    }
+}
+
+/// A splice command as it went by
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cue {
+    pub pid: u16,
+    pub command: SpliceCommand,
+    /// For splice_insert only
+    pub event_id: Option<u32>,
+    /// The splice_insert cancels the event
+    pub cancel: bool,
+    /// The splice_insert leaves the network for a break, rather than returning to it
+    pub out_of_network: bool,
+    /// Splice time, with the PTS adjustment, none for an immediate splice
+    pub pts: Option<u64>,
+    /// Time left until the splice, none until a PCR of the program is seen
+    pub pre_roll: Option<Duration>,
+    /// Break duration of the splice_insert, if given
+    pub duration: Option<Duration>,
+    /// When the section was received
+    pub arrival: SystemTime,
+}
+
+impl fmt::Display for Cue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SCTE-35 {} on PID {:#06x}", self.command, self.pid)?;
+        if let Some(event_id) = self.event_id {
+            write!(f, ", event {}", event_id)?;
+        }
+        if self.cancel {
+            f.write_str(", cancelled")?;
+        } else if self.command == SpliceCommand::SpliceInsert {
+            f.write_str(if self.out_of_network { ", out of network" } else { ", back to network" })?;
+        }
+        match self.pre_roll {
+            Some(pre_roll) => write!(f, ", pre-roll {:.3}s", pre_roll.as_secs_f64())?,
+            None if self.pts.is_none() && !self.cancel => f.write_str(", immediate")?,
+            None => (),
+        }
+        if let Some(duration) = self.duration {
+            write!(f, ", break of {:.3}s", duration.as_secs_f64())?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the fields of a command, failing past its end
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < n {
+            return Err("truncated command".to_owned());
+        }
+        let (head, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    /// 33 bits, after 7 others
+    fn time(&mut self) -> Result<u64, String> {
+        let b = self.bytes(5)?;
+        Ok((u64::from(b[0] & 1) << 32) | (u64::from(b[1]) << 24) | (u64::from(b[2]) << 16) | (u64::from(b[3]) << 8) | u64::from(b[4]))
+    }
+
+    /// splice_time(), the PTS if one is specified
+    fn splice_time(&mut self) -> Result<Option<u64>, String> {
+        if self.data.first().is_some_and(|&b| b & 0x80 != 0) {
+            self.time().map(Some)
+        } else {
+            self.bytes(1).map(|_| None)
+        }
+    }
+}
+
+fn ticks(t: u64) -> Duration {
+    Duration::from_nanos(t * 1_000_000_000 / PTS_HZ)
+}
+
+/// Parse a splice_info_section, none for the commands not reported
+fn parse(pid: u16, section: &[u8], arrival: SystemTime) -> Result<Option<Cue>, String> {
+    if section.len() < 18 {
+        return Err("short section".to_owned());
+    }
+    if section[0] != SPLICE_INFO_TABLE_ID {
+        return Err(format!("table id {:#04x}", section[0]));
+    }
+    if psi::crc32(section) != 0 {
+        return Err("CRC mismatch".to_owned());
+    }
+    if section[3] != 0 {
+        return Err(format!("protocol version {}", section[3]));
+    }
+    if section[4] & 0x80 != 0 {
+        debug!("Encrypted SCTE-35 section on PID {:#06x} skipped", pid);
+        return Ok(None);
+    }
+
+    let pts_adjustment = Reader { data: &section[4..9] }.time()?;
+    let command_len = (usize::from(section[11] & 0x0f) << 8) | usize::from(section[12]);
+    let command_type = section[13];
+    // 0xfff is a length left unspecified
+    let end = if command_len == 0xfff { section.len() - 4 } else { 14 + command_len };
+    let command = section.get(14..end).filter(|_| end <= section.len() - 4).ok_or("command past the section")?;
+    let mut reader = Reader { data: command };
+
+    let mut cue = Cue {
+        pid,
+        command: SpliceCommand::TimeSignal,
+        event_id: None,
+        cancel: false,
+        out_of_network: false,
+        pts: None,
+        pre_roll: None,
+        duration: None,
+        arrival,
+    };
+    match command_type {
+        SPLICE_INSERT => {
+            let id = reader.bytes(4)?;
+            cue.command = SpliceCommand::SpliceInsert;
+            cue.event_id = Some(u32::from_be_bytes([id[0], id[1], id[2], id[3]]));
+            cue.cancel = reader.u8()? & 0x80 != 0;
+            if !cue.cancel {
+                let flags = reader.u8()?;
+                cue.out_of_network = flags & 0x80 != 0;
+                let (program_splice, has_duration, immediate) = (flags & 0x40 != 0, flags & 0x20 != 0, flags & 0x10 != 0);
+                if program_splice && !immediate {
+                    cue.pts = reader.splice_time()?;
+                }
+                if !program_splice {
+                    // The components splice on their own, the first time stands for them
+                    for _ in 0..reader.u8()? {
+                        reader.u8()?;
+                        if !immediate {
+                            let pts = reader.splice_time()?;
+                            cue.pts = cue.pts.or(pts);
+                        }
+                    }
+                }
+                if has_duration {
+                    cue.duration = Some(ticks(reader.time()?));
+                }
+                reader.bytes(4)?;
+            }
+        }
+        TIME_SIGNAL => cue.pts = reader.splice_time()?,
+        _ => return Ok(None),
+    }
+    cue.pts = cue.pts.map(|pts| (pts + pts_adjustment) % PTS_WRAP);
+
+    Ok(Some(cue))
+}
+
+#[derive(Default)]
+struct State {
+    /// Tables the SCTE-35 PIDs were taken from
+    generation: Option<u64>,
+    /// SCTE-35 PIDs, with the PCR PID of their program
+    pids: HashMap<u16, Option<u16>>,
+    assemblers: HashMap<u16, Assembler>,
+    /// Last PCR base of the PCR PIDs
+    pcrs: HashMap<u16, u64>,
+}
+
+impl State {
+    fn follow(&mut self, programs: &[Program]) {
+        self.pids = programs.iter()
+            .flat_map(|p| p.streams.iter().filter(|s| s.stream_type == SCTE35_STREAM_TYPE).map(move |s| (s.pid, p.pcr_pid)))
+            .collect();
+        let pids = &self.pids;
+        self.assemblers.retain(|pid, _| pids.contains_key(pid));
+        self.pcrs.retain(|pid, _| pids.values().any(|&pcr_pid| pcr_pid == Some(*pid)));
+    }
+
+    /// Time from the last PCR of `pid` to the splice
+    fn pre_roll(&self, pcr_pid: Option<u16>, pts: Option<u64>) -> Option<Duration> {
+        let now = *self.pcrs.get(&pcr_pid?)?;
+        let left = match pts {
+            Some(pts) => (pts + PTS_WRAP - now) % PTS_WRAP,
+            None => 0,
+        };
+        // Already past
+        Some(if left > PTS_WRAP / 2 { Duration::from_secs(0) } else { ticks(left) })
+    }
+}
+
+#[derive(Default)]
+pub struct Cues {
+    state: Mutex<State>,
+    /// Cues reported
+    pub received: AtomicU64,
+    /// Sections skipped for not parsing
+    pub malformed: AtomicU64,
+}
+
+impl Cues {
+    /// Look for cues in the packets of an aligned chunk, the SCTE-35 PIDs being
+    /// refreshed whenever the tables change
+    pub fn inspect<F>(&self, chunk: &[u8], generation: u64, programs: F) -> Vec<Cue>
+        where F: FnOnce() -> Vec<Program>
+    {
+        let mut cues = Vec::new();
+        if !ts::is_aligned(chunk) {
+            return cues;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.generation != Some(generation) {
+            state.follow(&programs());
+            state.generation = Some(generation);
+        }
+        if state.pids.is_empty() {
+            return cues;
+        }
+
+        for pkt in chunk.chunks(ts::PACKET_SIZE) {
+            // Nothing in the header can be trusted
+            if pkt[1] & 0x80 != 0 {
+                continue;
+            }
+
+            if let Some((pid, pcr)) = ts::pcr(pkt) {
+                if state.pids.values().any(|&pcr_pid| pcr_pid == Some(pid)) {
+                    state.pcrs.insert(pid, pcr / 300);
+                }
+            }
+
+            let pid = ts::pid(pkt);
+            let pcr_pid = match state.pids.get(&pid) {
+                Some(&pcr_pid) => pcr_pid,
+                None => continue,
+            };
+            let payload = match psi::payload(pkt) {
+                Some(payload) => payload,
+                None => continue,
+            };
+
+            let sections = state.assemblers.entry(pid).or_default().push(payload, pkt[1] & 0x40 != 0);
+            for section in sections {
+                match parse(pid, &section, SystemTime::now()) {
+                    Ok(Some(mut cue)) => {
+                        cue.pre_roll = state.pre_roll(pcr_pid, cue.pts);
+                        info!("{}", cue);
+                        self.received.fetch_add(1, Ordering::Relaxed);
+                        cues.push(cue);
+                    }
+                    Ok(None) => (),
+                    Err(e) => {
+                        debug!("Malformed SCTE-35 section on PID {:#06x} skipped: {}", pid, e);
+                        self.malformed.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+        cues
+    }
+
+    /// A new stream starts, drop the sections half received and the PCRs
+    pub fn restart(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.assemblers.clear();
+        state.pcrs.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psi::Stream;
+    use crate::psi::tests::packets;
+    use crate::ts::tests::pcr_packet;
+
+    /// A splice_info_section around `command`
+    fn section(pts_adjustment: u64, command_type: u8, command: &[u8]) -> Vec<u8> {
+        let len = 11 + command.len() + 2 + 4;
+        let mut s = vec![SPLICE_INFO_TABLE_ID, 0x30 | (len >> 8) as u8, len as u8, 0,
+                         (pts_adjustment >> 32) as u8 & 1, (pts_adjustment >> 24) as u8, (pts_adjustment >> 16) as u8,
+                         (pts_adjustment >> 8) as u8, pts_adjustment as u8, 0, 0xff,
+                         0xf0 | (command.len() >> 8) as u8, command.len() as u8, command_type];
+        s.extend_from_slice(command);
+        s.extend_from_slice(&[0, 0]);
+        let crc = psi::crc32(&s);
+        s.extend_from_slice(&crc.to_be_bytes());
+        s
+    }
+
+    fn time(flag: u8, t: u64) -> Vec<u8> {
+        vec![flag | 0x7e | (t >> 32) as u8 & 1, (t >> 24) as u8, (t >> 16) as u8, (t >> 8) as u8, t as u8]
+    }
+
+    fn splice_insert(event_id: u32, pts: u64, duration: u64) -> Vec<u8> {
+        let mut command = event_id.to_be_bytes().to_vec();
+        command.extend_from_slice(&[0x7f, 0xef]);
+        command.extend(time(0x80, pts));
+        command.extend(time(0x80, duration));
+        command.extend_from_slice(&[0, 1, 0, 0]);
+        command
+    }
+
+    fn programs() -> Vec<Program> {
+        vec![Program {
+            number: 1,
+            pmt_pid: 0x1000,
+            pcr_pid: Some(0x100),
+            streams: vec![Stream { pid: 0x100, stream_type: 0x1b }, Stream { pid: 0x1f0, stream_type: SCTE35_STREAM_TYPE }],
+        }]
+    }
+
+    #[test]
+    fn splice_insert_and_time_signal() {
+        let cues = Cues::default();
+        let pcr = 10 * PTS_HZ * 300;
+
+        // Split over two packets, after the PCR of the program
+        let mut insert = section(PTS_HZ, SPLICE_INSERT, &splice_insert(42, 13 * PTS_HZ, 30 * PTS_HZ));
+        insert.extend(vec![0xff; 200]);
+        let chunk = [pcr_packet(0x100, pcr), packets(0x1f0, 0, &insert)].concat();
+        let found = cues.inspect(&chunk, 0, programs);
+        assert_eq!(found.len(), 1);
+        let cue = &found[0];
+        assert_eq!((cue.command, cue.event_id, cue.cancel, cue.out_of_network), (SpliceCommand::SpliceInsert, Some(42), false, true));
+        assert_eq!(cue.pts, Some(14 * PTS_HZ));
+        assert_eq!(cue.pre_roll, Some(Duration::from_secs(4)));
+        assert_eq!(cue.duration, Some(Duration::from_secs(30)));
+
+        // Immediate, and a splice already past
+        let signal = section(0, TIME_SIGNAL, &[0x7f]);
+        let past = section(0, TIME_SIGNAL, &time(0x80, PTS_HZ));
+        let found = cues.inspect(&[packets(0x1f0, 0, &signal), packets(0x1f0, 0, &past)].concat(), 0, programs);
+        assert_eq!(found.iter().map(|c| (c.command, c.event_id, c.pre_roll)).collect::<Vec<_>>(),
+                   vec![(SpliceCommand::TimeSignal, None, Some(Duration::from_secs(0))),
+                        (SpliceCommand::TimeSignal, None, Some(Duration::from_secs(0)))]);
+        assert_eq!(cues.received.load(Ordering::Relaxed), 3);
+
+        // Not a SCTE-35 PID
+        assert!(cues.inspect(&packets(0x1f1, 0, &signal), 0, programs).is_empty());
+    }
+
+    #[test]
+    fn malformed_skipped() {
+        let cues = Cues::default();
+
+        let mut corrupt = section(0, TIME_SIGNAL, &time(0x80, PTS_HZ));
+        corrupt[15] ^= 1;
+        // The command runs past its length
+        let truncated = section(0, SPLICE_INSERT, &[0, 0, 0, 1, 0x7f]);
+        let null = section(0, 0x00, &[]);
+        let chunk = [packets(0x1f0, 0, &corrupt), packets(0x1f0, 0, &truncated), packets(0x1f0, 0, &null)].concat();
+
+        assert!(cues.inspect(&chunk, 0, programs).is_empty());
+        assert_eq!(cues.malformed.load(Ordering::Relaxed), 2);
+        assert_eq!(cues.received.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,240 @@
+//! Impaired consumer links, a test feature to see how the players cope
+//!
+//! `--simulate loss=1%,delay=50ms,jitter=20ms` drops chunks at random on the
+//! way to each TCP consumer and holds the others back. The chunks keep their
+//! order, TCP could not deliver them otherwise, so the jitter only ever
+//! stretches the gaps between them. The draws of each consumer follow from
+//! the seed and the order it connected in, so a run can be replayed.
+
+use tokio::time::Sleep;
+use bytes::Bytes;
+use log::warn;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::stats::PeerStats;
+
+/// Chunks held back per consumer, the next ones wait in its queue
+const MAX_HELD: usize = 4096;
+
+/// Impairments of every consumer link
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Simulate {
+    /// Share of the chunks dropped, from 0 to 1
+    pub loss: f64,
+    pub delay: Duration,
+    /// Added to or taken from the delay of each chunk, at most
+    pub jitter: Duration,
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let err = || format!("Invalid duration {}, use e.g. 50ms or 0.5s", s);
+    let secs = match (s.strip_suffix("ms"), s.strip_suffix('s')) {
+        (Some(ms), _) => ms.parse::<f64>().map_err(|_| err())? / 1000.0,
+        (None, Some(secs)) => secs.parse().map_err(|_| err())?,
+        (None, None) => return Err(err()),
+    };
+    Duration::try_from_secs_f64(secs).map_err(|_| err())
+}
+
+impl FromStr for Simulate {
+    type Err = String;
+
+    /// Parse `loss=1%,delay=50ms,jitter=20ms`, the impairments left out are off
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut simulate = Simulate::default();
+
+        for item in s.split(',') {
+            let mut kv = item.splitn(2, '=');
+            let (key, value) = match (kv.next(), kv.next()) {
+                (Some(key), Some(value)) => (key.trim(), value.trim()),
+                _ => return Err(format!("Invalid impairment {:?}, use e.g. loss=1%,delay=50ms,jitter=20ms", item)),
+            };
+
+            match key {
+                "loss" => {
+                    let percent: f64 = value.trim_end_matches('%').parse()
+                        .map_err(|_| format!("Invalid loss {}, use e.g. 1%", value))?;
+                    if !(0.0..=100.0).contains(&percent) {
+                        return Err(format!("Invalid loss {}, between 0% and 100%", value));
+                    }
+                    simulate.loss = percent / 100.0;
+                }
+                "delay" => simulate.delay = parse_duration(value)?,
+                "jitter" => simulate.jitter = parse_duration(value)?,
+                _ => return Err(format!("Unknown impairment {:?}, expected loss, delay or jitter", key)),
+            }
+        }
+
+        Ok(simulate)
+    }
+}
+
+/// The impairments and the seed of the draws, shared by the consumers
+pub struct Simulator {
+    simulate: Simulate,
+    seed: u64,
+    /// Consumers impaired so far, each gets draws of its own
+    consumers: AtomicU64,
+}
+
+impl fmt::Debug for Simulator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Simulator({:?}, seed {})", self.simulate, self.seed)
+    }
+}
+
+impl Simulator {
+    /// Impair the consumers with `simulate`, seeded from the clock unless `seed` is given
+    pub fn new(simulate: Simulate, seed: Option<u64>) -> Self {
+        let seed = seed.unwrap_or_else(|| {
+            SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_nanos() as u64).unwrap_or_default()
+        });
+        warn!("Simulating impaired consumer links, {:?}, seed {}", simulate, seed);
+
+        Simulator { simulate, seed, consumers: AtomicU64::new(0) }
+    }
+
+    /// The impairment of the next consumer, counting its drops in `stats`
+    pub(crate) fn impair(&self, stats: Arc<PeerStats>) -> Impairment {
+        let n = self.consumers.fetch_add(1, Ordering::Relaxed);
+        stats.simulated.store(true, Ordering::Relaxed);
+
+        Impairment {
+            simulate: self.simulate.clone(),
+            rng: splitmix64(self.seed.wrapping_add(n)),
+            held: VecDeque::new(),
+            bytes: 0,
+            delay: None,
+            stats,
+        }
+    }
+}
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    // Never 0, xorshift would be stuck on it
+    (z ^ (z >> 31)) | 1
+}
+
+/// The link of a consumer, between its queue and its socket
+pub struct Impairment {
+    simulate: Simulate,
+    /// xorshift64* state
+    rng: u64,
+    /// Chunks held back and when they are due, in order
+    held: VecDeque<(Instant, Bytes)>,
+    bytes: usize,
+    delay: Option<Pin<Box<Sleep>>>,
+    stats: Arc<PeerStats>,
+}
+
+impl Impairment {
+    /// Uniform in [0, 1)
+    fn random(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        (self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Whether no more chunks can be held back
+    pub fn is_full(&self) -> bool {
+        self.held.len() >= MAX_HELD
+    }
+
+    /// Bytes held back
+    pub fn pending(&self) -> usize {
+        self.bytes
+    }
+
+    /// Drop `chunk`, or hold it back until it is due
+    pub fn push(&mut self, chunk: Bytes) {
+        if self.simulate.loss > 0.0 && self.random() < self.simulate.loss {
+            self.stats.simulated_drops.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let jitter = self.simulate.jitter.as_secs_f64() * (2.0 * self.random() - 1.0);
+        let delay = Duration::from_secs_f64((self.simulate.delay.as_secs_f64() + jitter).max(0.0));
+        let mut due = Instant::now() + delay;
+        // Never before the one ahead of it
+        if let Some(&(last, _)) = self.held.back() {
+            due = due.max(last);
+        }
+
+        self.bytes += chunk.len();
+        self.held.push_back((due, chunk));
+    }
+
+    /// The next chunk if it is due, otherwise woken up once it is
+    pub fn poll_due(&mut self, cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
+        let due = match self.held.front() {
+            Some(&(due, _)) => due,
+            None => return Poll::Ready(None),
+        };
+
+        if due > Instant::now() && !crate::poll_delay(&mut self.delay, due, cx) {
+            return Poll::Pending;
+        }
+
+        let (_, chunk) = self.held.pop_front().unwrap();
+        self.bytes -= chunk.len();
+        Poll::Ready(Some(chunk))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+
+    #[test]
+    fn parsed() {
+        let simulate: Simulate = "loss=1%,delay=50ms,jitter=0.02s".parse().unwrap();
+        assert_eq!(simulate, Simulate { loss: 0.01, delay: Duration::from_millis(50), jitter: Duration::from_millis(20) });
+        assert_eq!("delay=1s".parse(), Ok(Simulate { delay: Duration::from_secs(1), ..Simulate::default() }));
+
+        assert!("loss=101%".parse::<Simulate>().is_err());
+        assert!("delay=50".parse::<Simulate>().is_err());
+        assert!("delay=1e20s".parse::<Simulate>().is_err());
+        assert!("reorder=1%".parse::<Simulate>().is_err());
+        assert!("loss".parse::<Simulate>().is_err());
+    }
+
+    fn drops(seed: u64, chunks: usize) -> (u64, Vec<Bytes>) {
+        let simulator = Simulator::new("loss=10%".parse().unwrap(), Some(seed));
+        let stats = Arc::new(PeerStats::new("127.0.0.1:1234".parse().unwrap()));
+        let mut impairment = simulator.impair(stats.clone());
+
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut kept = Vec::new();
+        for i in 0..chunks {
+            impairment.push(Bytes::from(vec![i as u8; 188]));
+            while let Poll::Ready(Some(chunk)) = impairment.poll_due(&mut cx) {
+                kept.push(chunk);
+            }
+        }
+        (stats.simulated_drops.load(Ordering::Relaxed), kept)
+    }
+
+    #[test]
+    fn seeded_losses() {
+        let (dropped, kept) = drops(42, 10_000);
+        assert!(dropped > 800 && dropped < 1200, "{} dropped", dropped);
+        assert_eq!(dropped as usize + kept.len(), 10_000);
+
+        // The same seed drops the same chunks
+        assert_eq!(drops(42, 10_000).1, kept);
+        assert_ne!(drops(43, 10_000).1, kept);
+    }
+}
@@ -0,0 +1,138 @@
+//! Copy of the output to the standard output or a file
+//!
+//! The sink is fed like a consumer, through a queue applying the overflow
+//! policy, but for the whole lifetime rather than a single producer session.
+
+use futures::prelude::*;
+use futures::future::Either;
+use tokio::fs::File;
+use tokio::io::{stdout, AsyncWrite, Stdout};
+use bytes::{Buf, Bytes};
+use log::{error, info};
+
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{ready, Context, Poll};
+
+use crate::{Rx, Shared};
+use crate::net;
+use crate::queue;
+
+/// Where the copy goes
+#[derive(Clone, Debug, PartialEq)]
+pub enum Sink {
+    Stdout,
+    /// Appended to, never rotated
+    File(PathBuf),
+}
+
+impl fmt::Display for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Sink::Stdout => write!(f, "stdout"),
+            Sink::File(ref path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Write the queued chunks in order
+struct Writer<W: AsyncWrite + Unpin> {
+    sink: Sink,
+    writer: W,
+    rx: Rx,
+    /// What is left of the chunk being written
+    chunk: Option<Bytes>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl<W: AsyncWrite + Unpin> Future for Writer<W> {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        loop {
+            if let Some(ref mut chunk) = this.chunk {
+                while !chunk.is_empty() {
+                    let n = ready!(Pin::new(&mut this.writer).poll_write(cx, &chunk[..]))?;
+                    if n == 0 {
+                        return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                    }
+                    chunk.advance(n);
+                }
+            }
+            this.chunk = None;
+
+            match this.rx.poll_next_unpin(cx) {
+                Poll::Ready(Some(chunk)) => this.chunk = Some(chunk),
+                // Removed on shutdown or disconnected by the overflow policy
+                Poll::Ready(None) => {
+                    ready!(Pin::new(&mut this.writer).poll_flush(cx))?;
+                    return Poll::Ready(Ok(()));
+                }
+                Poll::Pending => {
+                    // Nothing new, do not leave a partial chunk in the buffers meanwhile
+                    if let Poll::Ready(Err(e)) = Pin::new(&mut this.writer).poll_flush(cx) {
+                        return Poll::Ready(Err(e));
+                    }
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Writer<W> {
+    fn new(sink: Sink, writer: W, state: &Arc<Mutex<Shared>>) -> (Self, SocketAddr) {
+        let addr = net::placeholder_addr();
+        let dropped = Arc::new(AtomicU64::new(0));
+        let rx = {
+            let state = state.lock().unwrap();
+            let (tx, rx) = queue::bounded(state.settings.consumer_queue, state.settings.overflow, dropped.clone());
+            state.fanout.insert(addr, tx, 0);
+            rx
+        };
+
+        (Writer { sink, writer, rx, chunk: None, dropped }, addr)
+    }
+
+    fn run(self) -> impl Future<Output = ()> + Send where W: Send + 'static {
+        let sink = self.sink.clone();
+        let dropped = self.dropped.clone();
+
+        self.map(move |res| {
+            let dropped = dropped.load(Ordering::Relaxed);
+            match res {
+                Ok(()) => info!("Dropping Sink ({}), {} packets dropped", sink, dropped),
+                Err(e) => error!("Dropping Sink ({}), {} packets dropped: {}", sink, dropped, e),
+            }
+        })
+    }
+}
+
+/// Copy every packet broadcast to `sink`, until its queue is removed from the fanout
+///
+/// Returns the queue address along with the writer, which resolves once
+/// everything queued is written.
+pub fn spawn(sink: Sink, state: &Arc<Mutex<Shared>>) -> io::Result<(SocketAddr, impl Future<Output = ()> + Send)> {
+    info!("Adding Sink ({})", sink);
+
+    match sink {
+        Sink::Stdout => {
+            let (writer, addr) = Writer::<Stdout>::new(sink, stdout(), state);
+            Ok((addr, Either::Left(writer.run())))
+        }
+        Sink::File(ref path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("cannot open {}: {}", path.display(), e)))?;
+            let (writer, addr) = Writer::new(sink.clone(), File::from_std(file), state);
+            Ok((addr, Either::Right(writer.run())))
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! `GET /snapshot?duration=30` on the HTTP consumer port, the recent stream as a file
+//!
+//! The chunks come out of the time-shift buffer if there is one, of the burst
+//! buffer otherwise, and are cut on packet boundaries. A request going back
+//! further than the buffer gets what there is, with `X-Available-Duration`.
+
+use bytes::Bytes;
+use log::info;
+
+use std::time::Duration;
+
+use crate::fanout::Fanout;
+use crate::http;
+use crate::ts;
+
+/// Path of the snapshots, the other ones are streams
+pub const PATH: &str = "/snapshot";
+
+/// Seconds asked for with `duration=`, the whole buffer if left out
+fn parse_query(query: &str) -> Result<Duration, String> {
+    let mut duration = Duration::MAX;
+
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        if let Some(value) = pair.strip_prefix("duration=") {
+            duration = value.parse::<f64>().ok()
+                .filter(|&secs| secs > 0.0)
+                .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                .ok_or_else(|| format!("Invalid duration {}, expected seconds", value))?;
+        }
+    }
+
+    Ok(duration)
+}
+
+/// The chunks joined, from the first packet start to the last whole packet
+fn cut(chunks: &[Bytes]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(chunks.iter().map(|chunk| chunk.len()).sum());
+    for chunk in chunks {
+        data.extend_from_slice(chunk);
+    }
+
+    let start = ts::sync_offset(&data).unwrap_or(data.len());
+    data.drain(..start);
+    data.truncate(data.len() / ts::PACKET_SIZE * ts::PACKET_SIZE);
+    data
+}
+
+/// The complete response to a snapshot request with `query`
+pub fn response(fanout: &Fanout, query: &str) -> Vec<u8> {
+    let duration = match parse_query(query) {
+        Ok(duration) => duration,
+        Err(e) => return http::response("400 Bad Request", "text/plain", "", format!("{}\n", e).as_bytes()),
+    };
+
+    let (chunks, kept) = match fanout.since(duration) {
+        Some(recent) => recent,
+        None => return http::response("404 Not Found", "text/plain", "", b"Snapshots need --dvr-window or --burst\n"),
+    };
+
+    let data = cut(&chunks);
+    let mut headers = String::from("Content-Disposition: attachment; filename=\"snapshot.ts\"\r\n");
+    if kept < duration {
+        headers += &format!("X-Available-Duration: {:.3}\r\n", kept.as_secs_f64());
+    }
+    info!("Snapshot of {} bytes, {:.1}s buffered", data.len(), kept.as_secs_f64());
+
+    http::response("200 OK", "video/mp2t", &headers, &data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query() {
+        assert_eq!(parse_query(""), Ok(Duration::MAX));
+        assert_eq!(parse_query("duration=2.5&format=ts"), Ok(Duration::from_millis(2500)));
+        assert!(parse_query("duration=0").is_err());
+        assert!(parse_query("duration=soon").is_err());
+        assert!(parse_query("duration=1e20").is_err());
+    }
+
+    #[test]
+    fn cut_on_packets() {
+        let packet = |n: u8| {
+            let mut pkt = vec![n; ts::PACKET_SIZE];
+            pkt[0] = ts::SYNC_BYTE;
+            pkt
+        };
+        let mut data = packet(1)[100..].to_vec();
+        data.extend(packet(2));
+        data.extend(packet(3));
+        data.extend(&packet(4)[..50]);
+
+        let chunks: Vec<_> = data.chunks(300).map(Bytes::copy_from_slice).collect();
+        let snapshot = cut(&chunks);
+        assert_eq!(snapshot.len(), 2 * ts::PACKET_SIZE);
+        assert_eq!((snapshot[1], snapshot[ts::PACKET_SIZE + 1]), (2, 3));
+
+        assert!(cut(&[]).is_empty());
+    }
+}
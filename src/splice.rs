@@ -0,0 +1,291 @@
+//! Recording without copying the stream through userspace, with `--record-splice`
+//!
+//! While the recorder is all the stream goes to, the producer socket is
+//! spliced into a pipe and the recorder thread splices the pipe into the
+//! file, the data never leaves the kernel. Once anything else takes the
+//! stream, a consumer, an output or a filter, the producer takes back what is
+//! left in the pipe and goes the usual way again. The pipe is the recorder
+//! queue meanwhile, a disk falling behind slows the producer down rather than
+//! losing packets.
+//!
+//! Linux only, elsewhere the recording always goes the usual way.
+
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::task::AtomicWaker;
+use bytes::BytesMut;
+
+use std::fs::File;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{ready, Context, Poll};
+
+use self::sys::Pipe;
+use crate::ts;
+
+/// Asked of the kernel, the pipe keeps its default size past the limit of the system
+const PIPE_SIZE: usize = 1024 * 1024;
+
+pub struct Splice {
+    pipe: Pipe,
+    /// The producer waiting for room in the pipe
+    waker: AtomicWaker,
+    /// Set until the recorder is done with the data spliced so far
+    woken: AtomicBool,
+    /// The producer is gone, its last packet is written even if cut short
+    ended: AtomicBool,
+    wakeup: UnboundedSender<()>,
+}
+
+impl Splice {
+    /// A pipe from the producer to the recorder, along with the wakeups of the latter
+    pub fn new() -> io::Result<(Splice, UnboundedReceiver<()>)> {
+        let pipe = Pipe::new(PIPE_SIZE)?;
+        let (wakeup, woken) = mpsc::unbounded_channel();
+        let splice = Splice { pipe, waker: AtomicWaker::new(), woken: AtomicBool::new(false), ended: AtomicBool::new(false), wakeup };
+
+        Ok((splice, woken))
+    }
+
+    fn wake(&self) {
+        if !self.woken.swap(true, Ordering::AcqRel) {
+            let _ = self.wakeup.send(());
+        }
+    }
+
+    /// Queue the data read already, if it fits, ahead of what is spliced next
+    pub fn put(&self, data: &[u8]) -> io::Result<bool> {
+        if self.pipe.pending()? + data.len() > self.pipe.size() {
+            return Ok(false);
+        }
+        if !data.is_empty() {
+            self.pipe.write(data)?;
+            self.wake();
+        }
+        Ok(true)
+    }
+
+    /// Splice what `socket` has into the pipe, 0 once the peer closed it
+    pub fn poll_splice(&self, cx: &mut Context<'_>, socket: &mut TcpStream) -> Poll<io::Result<usize>> {
+        let n = ready!(self.pipe.poll_splice_from(cx, socket, &self.waker))?;
+        self.wake();
+        Poll::Ready(Ok(n))
+    }
+
+    /// Let the recorder write the last packet of the producer, whole or not
+    pub fn end(&self) {
+        self.ended.store(true, Ordering::Release);
+        self.wake();
+    }
+
+    /// Hand the whole packets in the pipe to `write` until there are none left
+    /// or `write` takes nothing, the pipe stays locked meanwhile
+    ///
+    /// The rest is left for the producer to take back along with the whole
+    /// packets, so it can pick up the stream between two of them.
+    pub fn drain<F>(&self, mut write: F) -> io::Result<()>
+        where F: FnMut(&File, usize) -> io::Result<usize>
+    {
+        // Whatever is spliced from now on wakes the recorder up again
+        self.woken.store(false, Ordering::Release);
+        let ended = self.ended.swap(false, Ordering::AcqRel);
+
+        let res = {
+            let rd = self.pipe.lock();
+            loop {
+                let pending = self.pipe.pending()?;
+                let whole = if ended { pending } else { pending / ts::PACKET_SIZE * ts::PACKET_SIZE };
+                if whole == 0 || write(&rd, whole)? == 0 {
+                    break Ok(());
+                }
+            }
+        };
+        // Room again
+        self.waker.wake();
+        res
+    }
+
+    /// Take back what the recorder did not write yet, appending it to `out`
+    pub fn take_back(&self, out: &mut BytesMut) -> io::Result<usize> {
+        let rd = self.pipe.lock();
+        let start = out.len();
+        out.resize(start + self.pipe.pending()?, 0);
+        let n = sys::read(&rd, &mut out[start..])?;
+        out.truncate(start + n);
+        self.waker.wake();
+        Ok(n)
+    }
+}
+
+/// Splice up to `len` bytes from the locked pipe `rd` to the end of `file`
+pub fn to_file(rd: &File, file: &File, len: usize) -> io::Result<usize> {
+    sys::splice_to(rd, file, len)
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use tokio::io::ReadBuf;
+    use tokio::net::TcpStream;
+    use futures::task::AtomicWaker;
+
+    use std::fs::File;
+    use std::io::{self, Read, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+    use std::ptr;
+    use std::sync::{Mutex, MutexGuard};
+    use std::task::{ready, Context, Poll};
+
+    pub struct Pipe {
+        /// Locked while the recorder writes from it or the producer takes it back
+        rd: Mutex<File>,
+        wr: File,
+        size: usize,
+    }
+
+    fn check(res: libc::c_int) -> io::Result<libc::c_int> {
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(res)
+        }
+    }
+
+    /// Whether `fd` is ready for `events`, without waiting
+    fn ready(fd: RawFd, events: libc::c_short) -> io::Result<bool> {
+        let mut pollfd = libc::pollfd { fd, events, revents: 0 };
+        check(unsafe { libc::poll(&mut pollfd, 1, 0) })?;
+        Ok(pollfd.revents != 0)
+    }
+
+    /// None if it would block, on either side
+    fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<Option<usize>> {
+        let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+        let n = unsafe { libc::splice(from, ptr::null_mut(), to, ptr::null_mut(), len, flags) };
+        if n >= 0 {
+            return Ok(Some(n as usize));
+        }
+        match io::Error::last_os_error() {
+            ref e if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            e => Err(e),
+        }
+    }
+
+    impl Pipe {
+        pub fn new(size: usize) -> io::Result<Pipe> {
+            let mut fds = [0; 2];
+            check(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) })?;
+            let (rd, wr) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+            let _ = check(unsafe { libc::fcntl(wr.as_raw_fd(), libc::F_SETPIPE_SZ, size as libc::c_int) });
+            let size = check(unsafe { libc::fcntl(wr.as_raw_fd(), libc::F_GETPIPE_SZ) })? as usize;
+
+            Ok(Pipe { rd: Mutex::new(rd), wr, size })
+        }
+
+        pub fn size(&self) -> usize {
+            self.size
+        }
+
+        /// Bytes in the pipe
+        pub fn pending(&self) -> io::Result<usize> {
+            let mut n: libc::c_int = 0;
+            check(unsafe { libc::ioctl(self.wr.as_raw_fd(), libc::FIONREAD, &mut n) })?;
+            Ok(n as usize)
+        }
+
+        pub fn write(&self, data: &[u8]) -> io::Result<()> {
+            (&self.wr).write_all(data)
+        }
+
+        pub fn lock(&self) -> MutexGuard<'_, File> {
+            self.rd.lock().unwrap()
+        }
+
+        /// Splice what the socket has, `waker` is woken up once the recorder
+        /// makes room if the pipe is full
+        pub fn poll_splice_from(&self, cx: &mut Context<'_>, socket: &mut TcpStream, waker: &AtomicWaker) -> Poll<io::Result<usize>> {
+            loop {
+                if let Some(n) = splice(socket.as_raw_fd(), self.wr.as_raw_fd(), self.size)? {
+                    return Poll::Ready(Ok(n));
+                }
+
+                if !ready(self.wr.as_raw_fd(), libc::POLLOUT)? {
+                    waker.register(cx.waker());
+                    // Drained meanwhile
+                    if !ready(self.wr.as_raw_fd(), libc::POLLOUT)? {
+                        return Poll::Pending;
+                    }
+                    continue;
+                }
+
+                // The socket is drained, peeking waits for more
+                ready!(socket.poll_peek(cx, &mut ReadBuf::new(&mut [0])))?;
+            }
+        }
+    }
+
+    pub fn read(rd: &File, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read = 0;
+        while read < buf.len() {
+            match (&*rd).read(&mut buf[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(read)
+    }
+
+    pub fn splice_to(rd: &File, file: &File, len: usize) -> io::Result<usize> {
+        Ok(splice(rd.as_raw_fd(), file.as_raw_fd(), len)?.unwrap_or(0))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use tokio::net::TcpStream;
+    use futures::task::AtomicWaker;
+
+    use std::fs::File;
+    use std::io;
+    use std::sync::MutexGuard;
+    use std::task::{Context, Poll};
+
+    /// Never made, there is no splicing here
+    pub enum Pipe {}
+
+    impl Pipe {
+        pub fn new(_: usize) -> io::Result<Pipe> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "splicing is only available on Linux"))
+        }
+
+        pub fn size(&self) -> usize {
+            match *self {}
+        }
+
+        pub fn pending(&self) -> io::Result<usize> {
+            match *self {}
+        }
+
+        pub fn write(&self, _: &[u8]) -> io::Result<()> {
+            match *self {}
+        }
+
+        pub fn lock(&self) -> MutexGuard<'_, File> {
+            match *self {}
+        }
+
+        pub fn poll_splice_from(&self, _: &mut Context<'_>, _: &mut TcpStream, _: &AtomicWaker) -> Poll<io::Result<usize>> {
+            match *self {}
+        }
+    }
+
+    pub fn read(_: &File, _: &mut [u8]) -> io::Result<usize> {
+        unreachable!()
+    }
+
+    pub fn splice_to(_: &File, _: &File, _: usize) -> io::Result<usize> {
+        unreachable!()
+    }
+}
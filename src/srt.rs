@@ -0,0 +1,112 @@
+//! SRT endpoints and options
+//!
+//! The connections themselves need libsrt, linked with the `srt` feature.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Who opens the SRT connection
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SrtMode {
+    /// Wait for the peer to connect
+    Listener,
+    /// Connect to the peer
+    Caller,
+}
+
+/// An SRT endpoint, parsed from the part of `srt://[HOST]:PORT[?mode=listener|caller]`
+/// following the scheme
+///
+/// Without a mode, an endpoint without host listens and one with a host calls.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SrtUrl {
+    pub addr: SocketAddr,
+    pub mode: SrtMode,
+}
+
+impl FromStr for SrtUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '?');
+        let host = parts.next().unwrap_or("");
+        let query = parts.next();
+
+        let addr = if host.starts_with(':') {
+            format!("0.0.0.0{}", host)
+        } else {
+            host.to_owned()
+        };
+        let addr: SocketAddr = addr.parse().map_err(|e| format!("Invalid address {}: {}", host, e))?;
+
+        let mut mode = if addr.ip().is_unspecified() { SrtMode::Listener } else { SrtMode::Caller };
+        for param in query.into_iter().flat_map(|q| q.split('&')) {
+            mode = match param {
+                "mode=listener" => SrtMode::Listener,
+                "mode=caller" => SrtMode::Caller,
+                _ => return Err(format!("Unsupported SRT parameter {}, expected mode=listener or mode=caller", param)),
+            };
+        }
+
+        if mode == SrtMode::Caller && addr.ip().is_unspecified() {
+            return Err(format!("Missing host to call in {}", s));
+        }
+
+        Ok(SrtUrl { addr, mode })
+    }
+}
+
+impl fmt::Display for SrtUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = match self.mode {
+            SrtMode::Listener => "listener",
+            SrtMode::Caller => "caller",
+        };
+        write!(f, "srt://{}?mode={}", self.addr, mode)
+    }
+}
+
+/// Settings of every SRT connection
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SrtOptions {
+    /// Time given to recover the lost packets, the libsrt default when unset
+    pub latency: Option<Duration>,
+    /// Encrypt the connections with a key derived from it
+    pub passphrase: Option<String>,
+}
+
+impl SrtOptions {
+    /// Check the values libsrt would refuse
+    pub fn check(&self) -> Result<(), String> {
+        match self.passphrase {
+            Some(ref passphrase) if passphrase.len() < 10 || passphrase.len() > 79 => {
+                Err("The SRT passphrase must be 10 to 79 characters long".to_owned())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(":9000".parse(), Ok(SrtUrl { addr: ([0, 0, 0, 0], 9000).into(), mode: SrtMode::Listener }));
+        assert_eq!("10.0.0.1:9000".parse(), Ok(SrtUrl { addr: ([10, 0, 0, 1], 9000).into(), mode: SrtMode::Caller }));
+        assert_eq!("10.0.0.1:9000?mode=listener".parse::<SrtUrl>().unwrap().mode, SrtMode::Listener);
+        assert!(":9000?mode=caller".parse::<SrtUrl>().is_err());
+        assert!(":9000?latency=200".parse::<SrtUrl>().is_err());
+        assert_eq!(":9000".parse::<SrtUrl>().unwrap().to_string(), "srt://0.0.0.0:9000?mode=listener");
+    }
+
+    #[test]
+    fn passphrase_length() {
+        assert!(SrtOptions::default().check().is_ok());
+        assert!(SrtOptions { passphrase: Some("short".to_owned()), ..SrtOptions::default() }.check().is_err());
+        assert!(SrtOptions { passphrase: Some("long enough".to_owned()), ..SrtOptions::default() }.check().is_ok());
+    }
+}
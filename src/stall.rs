@@ -0,0 +1,57 @@
+//! Detection of the producers connected but sending nothing
+
+use log::{info, warn};
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::Shared;
+
+/// How often the producer is checked
+const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Flag the producer as stalled once no data arrived for `timeout`, dropping
+/// the consumers as well if `disconnect` is set
+pub async fn watch(state: Arc<Mutex<Shared>>, timeout: Duration, disconnect: bool) {
+    let (stats, fanout) = {
+        let state = state.lock().unwrap();
+        (state.stats.clone(), state.fanout.clone())
+    };
+
+    let mut ticks = crate::interval(CHECK_INTERVAL);
+    loop {
+        ticks.tick().await;
+        let producer = stats.producer();
+        let stalled = producer.as_ref().is_some_and(|p| {
+            // A producer that just connected had no chance to send yet
+            let connected = p.since.elapsed().unwrap_or_default();
+            fanout.idle().min(connected) >= timeout
+        });
+
+        if stalled == stats.is_stalled() {
+            continue;
+        }
+        stats.set_stalled(stalled);
+
+        let addr = match producer {
+            Some(ref p) => p.addr,
+            None => continue,
+        };
+
+        if !stalled {
+            info!("Producer ({:?}) recovered", addr);
+            continue;
+        }
+
+        warn!("Producer ({:?}) stalled, no data for {:?}", addr, timeout);
+
+        if disconnect {
+            let state = state.lock().unwrap();
+            warn!("Disconnecting {} consumers", state.consumers.len());
+            // Dropping the queues makes the consumers finish
+            for consumer in state.consumers.keys() {
+                fanout.remove(consumer);
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Clean start of the new consumers
+//!
+//! The live data is held back until the next PAT, then the consumer gets the
+//! last PAT and PMT followed by the stream from the next keyframe, so a set-top
+//! box joining mid-GOP has nothing broken to decode.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use log::{debug, info};
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::stats::Stats;
+use crate::ts;
+
+const PAT_PID: u16 = 0;
+
+pub struct CleanStart {
+    addr: SocketAddr,
+    stats: Arc<Stats>,
+    /// Program whose video is waited for, the first one with video otherwise
+    program: Option<u16>,
+    timeout: Duration,
+    /// None for a timeout too long to ever be past
+    deadline: Option<Instant>,
+    /// A PAT went by since joining
+    pat_seen: bool,
+}
+
+impl CleanStart {
+    pub fn new(addr: SocketAddr, stats: Arc<Stats>, program: Option<u16>, timeout: Duration) -> Self {
+        CleanStart {
+            addr,
+            stats,
+            program,
+            timeout,
+            deadline: Instant::now().checked_add(timeout),
+            pat_seen: false,
+        }
+    }
+
+    /// What to send of `chunk`, nothing while waiting for the start point
+    ///
+    /// Past the timeout the chunk is sent as is, and so are the next ones.
+    pub fn apply(&mut self, chunk: &Bytes) -> Option<Bytes> {
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) || !ts::is_aligned(chunk) {
+            info!("Consumer ({:?}) found no clean start point within {:?}, starting right away", self.addr, self.timeout);
+            return Some(chunk.clone());
+        }
+
+        let mut video_pid = None;
+
+        for (i, pkt) in chunk.chunks(ts::PACKET_SIZE).enumerate() {
+            let pid = ts::pid(pkt);
+
+            if !self.pat_seen {
+                self.pat_seen = pid == PAT_PID && pkt[1] & 0x40 != 0;
+                continue;
+            }
+
+            // The tables are known by the time the chunk is broadcast
+            if video_pid.is_none() {
+                video_pid = Some(self.stats.video_pid(self.program));
+            }
+
+            if Some(Some(pid)) == video_pid && ts::random_access(pkt) {
+                let tables = self.stats.tables();
+                let mut out = BytesMut::with_capacity(tables.len() + chunk.len() - i * ts::PACKET_SIZE);
+                out.put_slice(&tables);
+                out.put_slice(&chunk[i * ts::PACKET_SIZE..]);
+
+                debug!("Consumer ({:?}) starting on a keyframe of PID {:#06x}", self.addr, pid);
+                return Some(out.freeze());
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psi::tests::{packets, pat, pmt};
+    use crate::ts::tests::packet;
+
+    fn media(pid: u16, keyframe: bool) -> Vec<u8> {
+        let mut pkt = packet(pid, 0x30);
+        pkt[4..6].copy_from_slice(&[1, if keyframe { 0x40 } else { 0 }]);
+        pkt
+    }
+
+    #[test]
+    fn tables_then_keyframe() {
+        let stats = Arc::new(Stats::default());
+        let tables = [
+            packets(0, 0, &pat(&[(1, 0x1000)])),
+            packets(0x1000, 0, &pmt(1, 0x100, &[(0x0f, 0x101), (0x1b, 0x100)])),
+        ].concat();
+        stats.inspect(&tables);
+
+        let mut start = CleanStart::new("127.0.0.1:1000".parse().unwrap(), stats.clone(), None, Duration::from_secs(5));
+
+        // Mid-GOP, before the PAT
+        assert_eq!(start.apply(&Bytes::from(media(0x100, true))), None);
+        // A PAT, then a keyframe on the audio only
+        let chunk = [tables.clone(), media(0x101, true), media(0x100, false)].concat();
+        assert_eq!(start.apply(&Bytes::from(chunk)), None);
+
+        let chunk = [media(0x101, false), media(0x100, true), media(0x101, false)].concat();
+        let out = start.apply(&Bytes::from(chunk.clone())).unwrap();
+        assert_eq!(&out[..], &[stats.tables(), chunk[ts::PACKET_SIZE..].to_vec()].concat()[..]);
+        assert_eq!(stats.tables(), tables);
+    }
+
+    #[test]
+    fn timeout_starts_right_away() {
+        let stats = Arc::new(Stats::default());
+        let mut start = CleanStart::new("127.0.0.1:1000".parse().unwrap(), stats, None, Duration::from_millis(0));
+        let chunk = Bytes::from(media(0x100, false));
+
+        assert_eq!(start.apply(&chunk), Some(chunk));
+    }
+}
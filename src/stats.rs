@@ -0,0 +1,1499 @@
+//! Counters shared with the monitoring endpoints
+//!
+//! The streaming path only touches atomics, besides the stream analyzers
+//! locked once per chunk, the registries are locked briefly on connect,
+//! disconnect and when rendering.
+
+use log::info;
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::access::json_string;
+use crate::cc::{Continuity, PidCounters};
+use crate::events::{self, EventBus, Reason};
+use crate::pcr::{self, Histogram, Range, Timing};
+use crate::pool::BufferPool;
+use crate::psi::{Program, Programs};
+use crate::scrambled::Scrambling;
+use crate::scte35::Cues;
+use crate::tr101290::Monitor;
+use crate::ts;
+use crate::webhook::{Event, Notifier};
+
+/// How many per-second samples the bitrates are averaged over
+const RATE_SAMPLES: usize = 5;
+
+/// How often the bitrates are logged
+const REPORT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Producer sessions kept for the status, the oldest are forgotten past that
+const HISTORY: usize = 50;
+
+/// Held by `PeerStats::dscp` while the peer is not marked
+pub const NO_DSCP: u8 = u8::MAX;
+
+/// Bytes counted per second, updated by the single task serving the peer
+#[derive(Default)]
+struct Window {
+    /// Seconds from the connection to the current window
+    second: AtomicU64,
+    current: AtomicU64,
+    /// Bytes of the window before the current one
+    previous: AtomicU64,
+}
+
+/// Counters for a single connection
+pub struct PeerStats {
+    pub addr: SocketAddr,
+    /// CN of the client certificate, for the producers using mutual TLS
+    pub identity: Option<String>,
+    pub since: SystemTime,
+    started: Instant,
+    /// Bytes received from the producer or sent to the consumer
+    bytes: AtomicU64,
+    /// Milliseconds from `started` to the last bytes counted
+    last_bytes: AtomicU64,
+    window: Window,
+    /// Packets dropped because the consumer queue was full
+    pub dropped: Arc<AtomicU64>,
+    /// Bytes waiting to be sent to the consumer
+    pub queued: AtomicUsize,
+    /// Most bytes waiting to be sent to the consumer at once
+    pub peak_queued: AtomicUsize,
+    /// Bytes read from the producer, not fanned out yet
+    pub buffered: AtomicUsize,
+    /// Bytes of the producer fanned out, what is left over once it is gone is lost
+    pub forwarded: AtomicU64,
+    /// Size of the packets the producer sends, 0 until detected
+    pub packet_size: AtomicUsize,
+    /// Times the task serving the peer was polled, a busy loop shows up here
+    pub polls: AtomicU64,
+    /// Counters of the SRT connection, for the peers using one
+    pub link: Option<Arc<LinkStats>>,
+    /// Counters of the retransmissions, for the RIST outputs
+    pub rist: Option<Arc<RistStats>>,
+    /// Counters of the padding, for the outputs at a constant bitrate
+    pub cbr: OnceLock<Arc<CbrStats>>,
+    /// Counters of each path, for a producer received twice
+    pub legs: Vec<Arc<LegStats>>,
+    /// Counters of the RTP headers stripped, for a producer received over RTP
+    pub rtp: Option<Arc<RtpStats>>,
+    /// Served over a WebSocket
+    pub websocket: AtomicBool,
+    /// DSCP the kernel marks the packets sent to the peer with, `NO_DSCP` if none was set
+    pub dscp: AtomicU8,
+    /// Sent through a simulated link, see `--simulate`
+    pub simulated: AtomicBool,
+    /// Chunks the simulated link dropped
+    pub simulated_drops: AtomicU64,
+    /// Bytes of null packets queued while the producer was silent, see `--keepalive-nulls`
+    pub keepalive: AtomicU64,
+    /// Tier of the consumer, see `--consumer-priority`
+    pub priority: AtomicU8,
+    /// Resyncs of the producer, and those that lost more than a packet
+    sync_byte_errors: AtomicU64,
+    sync_losses: AtomicU64,
+}
+
+impl PeerStats {
+    pub fn new(addr: SocketAddr) -> Self {
+        PeerStats {
+            addr,
+            identity: None,
+            since: SystemTime::now(),
+            started: Instant::now(),
+            bytes: AtomicU64::new(0),
+            last_bytes: AtomicU64::new(0),
+            window: Window::default(),
+            dropped: Arc::new(AtomicU64::new(0)),
+            queued: AtomicUsize::new(0),
+            forwarded: AtomicU64::new(0),
+            peak_queued: AtomicUsize::new(0),
+            buffered: AtomicUsize::new(0),
+            packet_size: AtomicUsize::new(0),
+            polls: AtomicU64::new(0),
+            link: None,
+            rist: None,
+            cbr: OnceLock::new(),
+            legs: Vec::new(),
+            rtp: None,
+            websocket: AtomicBool::new(false),
+            dscp: AtomicU8::new(NO_DSCP),
+            simulated: AtomicBool::new(false),
+            simulated_drops: AtomicU64::new(0),
+            keepalive: AtomicU64::new(0),
+            priority: AtomicU8::new(0),
+            sync_byte_errors: AtomicU64::new(0),
+            sync_losses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// The producer lost sync, `skipped` bytes were left out to find it again
+    pub fn lost_sync(&self, skipped: usize) {
+        self.sync_byte_errors.fetch_add(1, Ordering::Relaxed);
+        if skipped > ts::PACKET_SIZE {
+            self.sync_losses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Sync byte errors and sync losses so far
+    pub fn sync_errors(&self) -> (u64, u64) {
+        (self.sync_byte_errors.load(Ordering::Relaxed), self.sync_losses.load(Ordering::Relaxed))
+    }
+
+    /// Count the bytes read from the producer or written to the consumer
+    pub fn add_bytes(&self, bytes: u64) {
+        self.add_bytes_at(bytes, Instant::now());
+    }
+
+    fn add_bytes_at(&self, bytes: u64, now: Instant) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.last_bytes.store(now.saturating_duration_since(self.started).as_millis() as u64, Ordering::Relaxed);
+
+        let second = now.saturating_duration_since(self.started).as_secs();
+        let current = self.window.second.load(Ordering::Relaxed);
+
+        if second != current {
+            // Nothing was counted for a whole second if it is not the next window
+            let previous = if second == current + 1 { self.window.current.load(Ordering::Relaxed) } else { 0 };
+            self.window.previous.store(previous, Ordering::Relaxed);
+            self.window.current.store(0, Ordering::Relaxed);
+            self.window.second.store(second, Ordering::Relaxed);
+        }
+
+        self.window.current.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Bits per second over the last whole second
+    pub fn bitrate(&self) -> u64 {
+        self.bitrate_at(Instant::now())
+    }
+
+    fn bitrate_at(&self, now: Instant) -> u64 {
+        let second = now.saturating_duration_since(self.started).as_secs();
+        let current = self.window.second.load(Ordering::Relaxed);
+
+        let bytes = if second == current {
+            self.window.previous.load(Ordering::Relaxed)
+        } else if second == current + 1 {
+            self.window.current.load(Ordering::Relaxed)
+        } else {
+            0
+        };
+
+        bytes * 8
+    }
+
+    /// Bits per second since the connection
+    pub fn average_bitrate(&self) -> u64 {
+        self.average_bitrate_at(Instant::now())
+    }
+
+    fn average_bitrate_at(&self, now: Instant) -> u64 {
+        bitrate(self.bytes(), now.saturating_duration_since(self.started))
+    }
+
+    /// The `"dscp"` field of the status, empty for an unmarked peer
+    fn dscp_json(&self) -> String {
+        match self.dscp.load(Ordering::Relaxed) {
+            NO_DSCP => String::new(),
+            dscp => format!(", \"dscp\": {}", dscp),
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.priority.load(Ordering::Relaxed)
+    }
+
+    /// Count `bytes` waiting to be sent to the consumer, keeping the peak
+    pub fn set_queued(&self, bytes: usize) {
+        self.queued.store(bytes, Ordering::Relaxed);
+        self.peak_queued.fetch_max(bytes, Ordering::Relaxed);
+    }
+
+    /// Time since the connection
+    pub fn duration(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Time since the last bytes were counted, or since the connection if none were
+    pub fn idle(&self) -> Duration {
+        self.idle_at(Instant::now())
+    }
+
+    fn idle_at(&self, now: Instant) -> Duration {
+        let last = self.started + Duration::from_millis(self.last_bytes.load(Ordering::Relaxed));
+        now.saturating_duration_since(last)
+    }
+}
+
+/// Counters of a RIST output
+#[derive(Default)]
+pub struct RistStats {
+    /// Packets sent again on request of the receiver
+    pub retransmitted: AtomicU64,
+    /// Packets requested but gone from the retransmit buffer
+    pub unavailable: AtomicU64,
+    /// Packets kept in the retransmit buffer
+    pub buffered: AtomicUsize,
+}
+
+impl RistStats {
+    /// The counters as a JSON object member
+    fn json(&self) -> String {
+        format!(", \"rist\": {{\"retransmitted_packets\": {}, \"unavailable_packets\": {}, \"buffered_packets\": {}}}",
+                self.retransmitted.load(Ordering::Relaxed), self.unavailable.load(Ordering::Relaxed),
+                self.buffered.load(Ordering::Relaxed))
+    }
+}
+
+/// Padding of an output at a constant bitrate
+#[derive(Default)]
+pub struct CbrStats {
+    pub target_bps: u64,
+    /// Null packets added to keep up the rate
+    pub nulls_inserted: AtomicU64,
+    /// Null packets of the stream left out
+    pub nulls_dropped: AtomicU64,
+    /// Bytes waiting for their turn
+    pub backlog: AtomicUsize,
+    /// Times the stream peaked above the rate for too long
+    pub overruns: AtomicU64,
+}
+
+impl CbrStats {
+    pub fn new(target_bps: u64) -> Self {
+        CbrStats { target_bps, ..Default::default() }
+    }
+
+    /// The counters as a JSON object member
+    fn json(&self) -> String {
+        format!(", \"cbr\": {{\"target_bps\": {}, \"null_packets_inserted\": {}, \"null_packets_dropped\": {}, \"backlog_bytes\": {}, \"overruns\": {}}}",
+                self.target_bps, self.nulls_inserted.load(Ordering::Relaxed), self.nulls_dropped.load(Ordering::Relaxed),
+                self.backlog.load(Ordering::Relaxed), self.overruns.load(Ordering::Relaxed))
+    }
+}
+
+/// Counters of a producer received over RTP, see `--rtp-in`
+#[derive(Default)]
+pub struct RtpStats {
+    /// Packets missing according to the sequence numbers
+    pub lost: AtomicU64,
+    /// Datagrams passed through as they are, not looking like RTP
+    pub raw: AtomicU64,
+}
+
+impl RtpStats {
+    /// The counters as a JSON object member
+    fn json(&self) -> String {
+        format!(", \"rtp\": {{\"lost_packets\": {}, \"raw_datagrams\": {}}}",
+                self.lost.load(Ordering::Relaxed), self.raw.load(Ordering::Relaxed))
+    }
+}
+
+/// Counters of one of the paths a merged producer arrives by
+pub struct LegStats {
+    /// Where the path is received
+    pub addr: SocketAddr,
+    /// RTP packets received
+    pub packets: AtomicU64,
+    /// Packets of this path making it to the output, the first copy wins
+    pub used: AtomicU64,
+    /// Packets missing according to the sequence numbers
+    pub lost: AtomicU64,
+}
+
+impl LegStats {
+    pub fn new(addr: SocketAddr) -> Self {
+        LegStats {
+            addr,
+            packets: AtomicU64::new(0),
+            used: AtomicU64::new(0),
+            lost: AtomicU64::new(0),
+        }
+    }
+
+    fn json(&self) -> String {
+        format!("{{\"addr\": \"{}\", \"packets\": {}, \"used_packets\": {}, \"lost_packets\": {}}}",
+                self.addr, self.packets.load(Ordering::Relaxed), self.used.load(Ordering::Relaxed),
+                self.lost.load(Ordering::Relaxed))
+    }
+}
+
+/// Counters of an SRT connection, refreshed by the thread serving it
+#[derive(Default)]
+pub struct LinkStats {
+    /// Round-trip time, in microseconds
+    rtt_us: AtomicU64,
+    retransmitted: AtomicU64,
+    lost: AtomicU64,
+}
+
+impl LinkStats {
+    #[cfg_attr(not(feature = "srt"), allow(dead_code))]
+    pub fn update(&self, rtt: Duration, retransmitted: u64, lost: u64) {
+        self.rtt_us.store(rtt.as_micros() as u64, Ordering::Relaxed);
+        self.retransmitted.store(retransmitted, Ordering::Relaxed);
+        self.lost.store(lost, Ordering::Relaxed);
+    }
+
+    /// The counters as a JSON object member
+    fn json(&self) -> String {
+        format!(", \"srt\": {{\"rtt_ms\": {:.3}, \"retransmitted_packets\": {}, \"lost_packets\": {}}}",
+                self.rtt_us.load(Ordering::Relaxed) as f64 / 1000.0,
+                self.retransmitted.load(Ordering::Relaxed), self.lost.load(Ordering::Relaxed))
+    }
+}
+
+/// A producer session over, as listed in the status
+#[derive(Clone, Debug)]
+struct PastSession {
+    addr: SocketAddr,
+    since: SystemTime,
+    until: SystemTime,
+    bytes: u64,
+    average_bps: u64,
+    reason: Reason,
+}
+
+impl PastSession {
+    fn new(peer: &PeerStats, reason: Reason) -> Self {
+        PastSession {
+            addr: peer.addr,
+            since: peer.since,
+            until: SystemTime::now(),
+            bytes: peer.bytes(),
+            average_bps: peer.average_bitrate(),
+            reason,
+        }
+    }
+
+    fn json(&self) -> String {
+        let mut out = format!("{{\"addr\": \"{}\", \"since\": {}, \"until\": {}, \"bytes_received\": {}, \"average_bps\": {}, \"reason\": \"{}\"",
+                              self.addr, unix_time(self.since), unix_time(self.until), self.bytes, self.average_bps, self.reason);
+        if let Reason::Error(ref e) = self.reason {
+            let _ = write!(out, ", \"error\": {}", json_string(e));
+        }
+        out.push('}');
+        out
+    }
+}
+
+/// Load a tier of consumers absorbed
+#[derive(Clone, Copy, Default)]
+struct Shed {
+    /// Packets dropped by the consumers gone
+    dropped: u64,
+    /// Consumers disconnected to make room for a higher tier
+    displaced: u64,
+}
+
+#[derive(Default)]
+pub struct Stats {
+    /// Channel counted, if several are served
+    pub channel: Option<usize>,
+    /// Stream key counted, when routing on them
+    pub key: Option<String>,
+
+    producer: Mutex<Option<Arc<PeerStats>>>,
+    consumers: Mutex<HashMap<SocketAddr, Arc<PeerStats>>>,
+    /// The last producer sessions, oldest first. Updated while holding
+    /// `producer` so a session is listed either there or here
+    history: Mutex<VecDeque<PastSession>>,
+
+    /// Totals of the connections already closed
+    closed_in: AtomicU64,
+    closed_out: AtomicU64,
+    closed_dropped: AtomicU64,
+    /// Load shed by each tier of consumers, those still connected aside
+    shed: Mutex<BTreeMap<u8, Shed>>,
+
+    producer_connections: AtomicU64,
+    consumer_connections: AtomicU64,
+    /// Connections taken by each accept loop of the consumer port
+    accept_loops: Mutex<Vec<Arc<AtomicU64>>>,
+
+    /// The producer is connected but sends nothing
+    stalled: AtomicBool,
+
+    /// Broadcast delay asked for, with `--delay`
+    delay_target: OnceLock<Duration>,
+    /// How late the last chunk out of the delay was, none while priming
+    delay: Mutex<Option<Duration>>,
+
+    /// Read buffers of the producers
+    pool: OnceLock<Arc<BufferPool>>,
+
+    /// Packets left out by the PID filter
+    filtered: AtomicU64,
+    /// Bytes handed to the consumer queues, once per chunk
+    broadcast: AtomicU64,
+    /// Bytes spliced straight into the recording, broadcast as well
+    spliced: AtomicU64,
+    /// Bytes of null packets queued while the producer was silent, once per consumer
+    keepalive: AtomicU64,
+
+    /// Recent (time, bytes in, bytes broadcast, bytes out) samples for the bitrates
+    samples: Mutex<VecDeque<(Instant, u64, u64, u64)>>,
+    /// Packets of each PID at the same times, for their bitrates
+    pid_samples: Mutex<VecDeque<(Instant, HashMap<u16, u64>)>>,
+
+    /// Per-PID losses of the stream broadcast
+    pub continuity: Arc<Continuity>,
+    /// Programs carried by the stream broadcast
+    programs: Programs,
+    /// Whether the stream broadcast comes scrambled
+    scrambling: Scrambling,
+    /// TR 101 290 priority 1 indicators of the stream broadcast
+    pub tr101290: Monitor,
+    /// PCR jitter and repetition of the stream broadcast
+    pcr: Timing,
+    /// SCTE-35 cues of the stream broadcast
+    scte35: Cues,
+
+    /// Where the connections and stalls are notified, if anywhere
+    webhook: Mutex<Option<Notifier>>,
+    /// Where the embedders subscribe to the connection events, shared by the keyed streams
+    pub events: Arc<EventBus>,
+}
+
+fn unix_time(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn bitrate(bytes: u64, elapsed: Duration) -> u64 {
+    let secs = elapsed.as_secs_f64();
+    if secs > 0.0 {
+        (bytes as f64 * 8.0 / secs) as u64
+    } else {
+        0
+    }
+}
+
+impl Stats {
+    pub fn for_channel(channel: Option<usize>) -> Self {
+        Stats { channel, ..Default::default() }
+    }
+
+    pub fn for_key(key: &str, events: Arc<EventBus>) -> Self {
+        Stats { key: Some(key.to_owned()), events, ..Default::default() }
+    }
+
+    /// The program `number` as listed in the tables of the stream
+    pub fn program(&self, number: u16) -> Option<Program> {
+        self.programs.programs().into_iter().find(|p| p.number == number)
+    }
+
+    /// PIDs carrying the PMT of the programs
+    pub fn pmt_pids(&self) -> Vec<u16> {
+        self.programs.programs().iter().map(|p| p.pmt_pid).collect()
+    }
+
+    /// Video PID of program `number`, or of the first program with video
+    pub fn video_pid(&self, number: Option<u16>) -> Option<u16> {
+        self.programs.programs().iter()
+            .filter(|p| number.is_none_or(|number| p.number == number))
+            .find_map(Program::video_pid)
+    }
+
+    /// The last PAT and PMT, as packets
+    pub fn tables(&self) -> Vec<u8> {
+        self.programs.tables()
+    }
+
+    /// A PAT listing only program `number`
+    pub fn pat_for(&self, number: u16) -> Option<Vec<u8>> {
+        self.programs.pat_for(number)
+    }
+
+    /// Changes whenever the programs of the stream do
+    pub fn programs_generation(&self) -> u64 {
+        self.programs.generation()
+    }
+
+    /// Analyze a chunk about to be broadcast
+    pub fn inspect(&self, chunk: &[u8]) {
+        self.continuity.inspect(chunk);
+        self.programs.inspect(chunk);
+        self.tr101290.inspect(chunk, self.programs_generation(), || self.programs.programs());
+        self.pcr.inspect(chunk, self.programs_generation(), || self.programs.programs());
+        for cue in self.scte35.inspect(chunk, self.programs_generation(), || self.programs.programs()) {
+            if let Some(producer) = self.producer() {
+                self.notify(Event::Scte35 { addr: producer.addr, cue: cue.clone() });
+            }
+            self.events.emit(events::Event::Scte35 { cue });
+        }
+        if let Some(scrambled) = self.scrambling.inspect(chunk, self.programs_generation(), || self.pmt_pids()) {
+            if let Some(producer) = self.producer() {
+                self.notify(Event::StreamScrambled { addr: producer.addr, scrambled });
+            }
+            self.events.emit(events::Event::StreamScrambled { scrambled });
+        }
+    }
+
+    /// The next chunks belong to a new stream
+    pub fn restart_stream(&self) {
+        self.continuity.restart();
+        self.programs.restart();
+        self.tr101290.restart();
+        self.pcr.restart();
+        self.scte35.restart();
+    }
+
+    pub fn set_webhook(&self, notifier: Notifier) {
+        *self.webhook.lock().unwrap() = Some(notifier);
+    }
+
+    fn notify(&self, event: Event) {
+        if let Some(ref mut notifier) = *self.webhook.lock().unwrap() {
+            notifier.notify(event);
+        }
+    }
+
+    fn producer_gone(&self, old: &PeerStats, reason: Reason) {
+        self.closed_in.fetch_add(old.bytes(), Ordering::Relaxed);
+        self.notify(Event::ProducerDisconnected {
+            addr: old.addr,
+            duration: old.duration(),
+            bytes: old.bytes(),
+        });
+        self.events.emit(events::Event::ProducerDisconnected {
+            addr: old.addr,
+            bytes: old.bytes(),
+            duration: old.duration(),
+            reason,
+        });
+    }
+
+    /// Add the session of `old` to the history, with the lock of the producer held
+    fn archive(&self, old: &PeerStats, reason: Reason) {
+        let mut history = self.history.lock().unwrap();
+        if history.len() == HISTORY {
+            history.pop_front();
+        }
+        history.push_back(PastSession::new(old, reason));
+    }
+
+    pub fn set_producer(&self, peer: Arc<PeerStats>) {
+        self.producer_connections.fetch_add(1, Ordering::Relaxed);
+
+        let addr = peer.addr;
+        let old = {
+            let mut producer = self.producer.lock().unwrap();
+            let old = producer.replace(peer);
+            if let Some(ref old) = old {
+                self.archive(old, Reason::Removed);
+            }
+            old
+        };
+        if let Some(old) = old {
+            self.producer_gone(&old, Reason::Removed);
+        }
+        self.notify(Event::ProducerConnected { addr });
+        self.events.emit(events::Event::ProducerConnected { addr });
+    }
+
+    /// Forget the producer, unless another one took over already
+    pub fn remove_producer(&self, addr: &SocketAddr, reason: Reason) {
+        let old = {
+            let mut producer = self.producer.lock().unwrap();
+            if producer.as_ref().is_some_and(|p| p.addr == *addr) {
+                let old = producer.take();
+                if let Some(ref old) = old {
+                    self.archive(old, reason.clone());
+                }
+                old
+            } else {
+                None
+            }
+        };
+
+        if let Some(old) = old {
+            self.producer_gone(&old, reason);
+        }
+    }
+
+    /// Counters of the accept loops of the consumer port, one per listener,
+    /// kept across the producers
+    pub fn accept_counters(&self, loops: usize) -> Vec<Arc<AtomicU64>> {
+        let mut counters = self.accept_loops.lock().unwrap();
+        while counters.len() < loops {
+            counters.push(Arc::default());
+        }
+        counters[..loops].to_vec()
+    }
+
+    fn accepts(&self) -> Vec<u64> {
+        self.accept_loops.lock().unwrap().iter().map(|c| c.load(Ordering::Relaxed)).collect()
+    }
+
+    pub fn set_delay_target(&self, delay: Duration) {
+        let _ = self.delay_target.set(delay);
+    }
+
+    pub fn set_pool(&self, pool: Arc<BufferPool>) {
+        let _ = self.pool.set(pool);
+    }
+
+    fn pool_counts(&self) -> (u64, u64) {
+        self.pool.get().map_or((0, 0), |pool| pool.counts())
+    }
+
+    pub fn set_delay(&self, delay: Option<Duration>) {
+        *self.delay.lock().unwrap() = delay;
+    }
+
+    pub fn set_stalled(&self, stalled: bool) {
+        let was = self.stalled.swap(stalled, Ordering::Relaxed);
+
+        if stalled && !was {
+            if let Some(producer) = self.producer() {
+                self.notify(Event::StreamStalled { addr: producer.addr });
+            }
+            self.events.emit(events::Event::StreamStalled);
+        } else if was && !stalled {
+            self.events.emit(events::Event::StreamResumed);
+        }
+    }
+
+    pub fn is_stalled(&self) -> bool {
+        self.stalled.load(Ordering::Relaxed)
+    }
+
+    /// Most of the packets came scrambled for a while
+    pub fn is_scrambled(&self) -> bool {
+        self.scrambling.is_flagged()
+    }
+
+    pub fn add_filtered(&self, packets: u64) {
+        self.filtered.fetch_add(packets, Ordering::Relaxed);
+    }
+
+    pub fn add_broadcast(&self, bytes: usize) {
+        self.broadcast.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_spliced(&self, bytes: usize) {
+        self.add_broadcast(bytes);
+        self.spliced.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count `bytes` of null packets queued to each of the consumers at `addrs`
+    pub fn add_keepalive(&self, addrs: &[SocketAddr], bytes: usize) {
+        for consumer in self.consumers.lock().unwrap().values().filter(|c| addrs.contains(&c.addr)) {
+            consumer.keepalive.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+        self.keepalive.fetch_add((addrs.len() * bytes) as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_consumer(&self, peer: Arc<PeerStats>) {
+        self.consumer_connections.fetch_add(1, Ordering::Relaxed);
+
+        let addr = peer.addr;
+        let count = {
+            let mut consumers = self.consumers.lock().unwrap();
+            consumers.insert(addr, peer);
+            consumers.len()
+        };
+        self.consumers_changed(addr, count - 1, count);
+        self.events.emit(events::Event::ConsumerConnected { addr });
+    }
+
+    pub fn remove_consumer(&self, addr: &SocketAddr, reason: Reason) {
+        let (old, count) = {
+            let mut consumers = self.consumers.lock().unwrap();
+            (consumers.remove(addr), consumers.len())
+        };
+
+        if let Some(old) = old {
+            self.closed_out.fetch_add(old.bytes(), Ordering::Relaxed);
+            self.closed_dropped.fetch_add(old.dropped(), Ordering::Relaxed);
+            self.shed.lock().unwrap().entry(old.priority()).or_default().dropped += old.dropped();
+            self.consumers_changed(*addr, count + 1, count);
+            self.events.emit(events::Event::ConsumerDisconnected {
+                addr: *addr,
+                bytes: old.bytes(),
+                duration: old.duration(),
+                reason,
+            });
+        }
+    }
+
+    /// Count a consumer of tier `priority` disconnected for a higher one
+    pub fn add_displaced(&self, priority: u8) {
+        self.shed.lock().unwrap().entry(priority).or_default().displaced += 1;
+    }
+
+    /// The `"tiers"` of the status, what each tier of consumers absorbed
+    fn tiers_json(&self, consumers: &[Arc<PeerStats>]) -> String {
+        let mut tiers: BTreeMap<u8, (usize, Shed)> = self.shed.lock().unwrap().iter().map(|(&tier, &shed)| (tier, (0, shed))).collect();
+        for c in consumers {
+            let tier = tiers.entry(c.priority()).or_default();
+            tier.0 += 1;
+            tier.1.dropped += c.dropped();
+        }
+
+        let tiers: Vec<_> = tiers.iter().rev().map(|(tier, &(connected, shed))| {
+            format!("{{\"priority\": {}, \"consumers\": {}, \"dropped_packets\": {}, \"displaced\": {}}}",
+                    tier, connected, shed.dropped, shed.displaced)
+        }).collect();
+        format!("[{}]", tiers.join(", "))
+    }
+
+    fn consumers_changed(&self, addr: SocketAddr, before: usize, after: usize) {
+        if let Some(ref mut notifier) = *self.webhook.lock().unwrap() {
+            notifier.consumers_changed(addr, before, after);
+        }
+    }
+
+    pub fn producer(&self) -> Option<Arc<PeerStats>> {
+        self.producer.lock().unwrap().clone()
+    }
+
+    /// Copy the registries, so nothing stays locked while rendering
+    pub fn peers(&self) -> (Option<Arc<PeerStats>>, Vec<Arc<PeerStats>>) {
+        let producer = self.producer.lock().unwrap().clone();
+        let consumers = self.consumers.lock().unwrap().values().cloned().collect();
+
+        (producer, consumers)
+    }
+
+    /// Bytes received and sent since the start
+    fn totals(&self, producer: &Option<Arc<PeerStats>>, consumers: &[Arc<PeerStats>]) -> (u64, u64) {
+        let bytes_in = self.closed_in.load(Ordering::Relaxed) + producer.as_ref().map_or(0, |p| p.bytes());
+        let bytes_out = self.closed_out.load(Ordering::Relaxed) + consumers.iter().map(|c| c.bytes()).sum::<u64>();
+
+        (bytes_in, bytes_out)
+    }
+
+    /// Record the current totals, meant to be called every second
+    pub fn sample(&self) {
+        self.sample_at(Instant::now());
+    }
+
+    fn sample_at(&self, now: Instant) {
+        let (producer, consumers) = self.peers();
+        let (bytes_in, bytes_out) = self.totals(&producer, &consumers);
+
+        let mut samples = self.samples.lock().unwrap();
+        // A peer leaving between the two loads of `totals` would make them dip
+        let (bytes_in, bytes_out) = match samples.back() {
+            Some(&(_, last_in, _, last_out)) => (bytes_in.max(last_in), bytes_out.max(last_out)),
+            None => (bytes_in, bytes_out),
+        };
+        if samples.len() > RATE_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back((now, bytes_in, self.broadcast.load(Ordering::Relaxed), bytes_out));
+
+        let mut pid_samples = self.pid_samples.lock().unwrap();
+        if pid_samples.len() > RATE_SAMPLES {
+            pid_samples.pop_front();
+        }
+        pid_samples.push_back((now, self.continuity.counters().into_iter().map(|(pid, c)| (pid, c.packets)).collect()));
+    }
+
+    /// Bitrate of the stream received, over the recent samples
+    pub fn input_bitrate(&self) -> u64 {
+        self.bitrates().0
+    }
+
+    /// Input, broadcast and output bitrates over the recent samples
+    fn bitrates(&self) -> (u64, u64, u64) {
+        let samples = self.samples.lock().unwrap();
+
+        match (samples.front(), samples.back()) {
+            (Some(&(start, first_in, first_broadcast, first_out)), Some(&(end, last_in, last_broadcast, last_out))) => {
+                let elapsed = end - start;
+                (bitrate(last_in.saturating_sub(first_in), elapsed),
+                 bitrate(last_broadcast.saturating_sub(first_broadcast), elapsed),
+                 bitrate(last_out.saturating_sub(first_out), elapsed))
+            }
+            _ => (0, 0, 0),
+        }
+    }
+
+    /// Bitrate of every PID over the recent samples, those not seen since aside
+    fn pid_bitrates(&self) -> HashMap<u16, u64> {
+        let samples = self.pid_samples.lock().unwrap();
+
+        match (samples.front(), samples.back()) {
+            (Some(&(start, ref first)), Some(&(end, ref last))) => {
+                last.iter()
+                    .map(|(&pid, &packets)| {
+                        let before = first.get(&pid).cloned().unwrap_or(0);
+                        (pid, bitrate(packets.saturating_sub(before) * ts::PACKET_SIZE as u64, end - start))
+                    })
+                    .collect()
+            }
+            _ => HashMap::new(),
+        }
+    }
+
+    /// The PCR timing, "n/a" without PCRs
+    fn pcr_json(&self) -> String {
+        let range = |r: Range, scale: f64, decimals: usize| {
+            format!("{{\"min\": {:.*}, \"max\": {:.*}, \"avg\": {:.*}}}", decimals, r.min * scale, decimals, r.max * scale, decimals, r.avg * scale)
+        };
+        match self.pcr.summary() {
+            Some(s) => format!("{{\"pid\": {}, \"mux_rate_bps\": {}, \"jitter_ns\": {}, \"interval_ms\": {}}}",
+                               s.pid, s.mux_rate_bps, s.jitter.map_or("\"n/a\"".to_owned(), |j| range(j, 1e9, 0)), range(s.interval, 1e3, 3)),
+            None => "\"n/a\"".to_owned(),
+        }
+    }
+
+    /// Render the status as JSON
+    pub fn json(&self) -> String {
+        // A session ending meanwhile shows up in one or the other, never both
+        let (producer, history) = {
+            let producer = self.producer.lock().unwrap();
+            (producer.clone(), self.history.lock().unwrap().clone())
+        };
+        let mut consumers: Vec<_> = self.consumers.lock().unwrap().values().cloned().collect();
+        let (input_bps, broadcast_bps, output_bps) = self.bitrates();
+
+        consumers.sort_by_key(|c| c.since);
+
+        let mut out = String::from("{\n  ");
+        if let Some(channel) = self.channel {
+            let _ = write!(out, "\"channel\": {},\n  ", channel);
+        }
+        if let Some(ref key) = self.key {
+            let _ = write!(out, "\"key\": \"{}\",\n  ", key);
+        }
+        out.push_str("\"producer\": ");
+        match producer {
+            Some(ref p) => {
+                let _ = write!(out, "{{\"addr\": \"{}\", \"since\": {}, \"bytes_received\": {}, \"bitrate_bps\": {}, \"average_bps\": {}, \"buffered_bytes\": {}",
+                               p.addr, unix_time(p.since), p.bytes(), p.bitrate(), p.average_bitrate(), p.buffered.load(Ordering::Relaxed));
+                if let Some(ref identity) = p.identity {
+                    let _ = write!(out, ", \"identity\": \"{}\"", identity);
+                }
+                match p.packet_size.load(Ordering::Relaxed) {
+                    0 => (),
+                    size => { let _ = write!(out, ", \"packet_size\": {}", size); }
+                }
+                out.push_str(&p.dscp_json());
+                if let Some(ref link) = p.link {
+                    out.push_str(&link.json());
+                }
+                if let Some(ref rtp) = p.rtp {
+                    out.push_str(&rtp.json());
+                }
+                if !p.legs.is_empty() {
+                    let legs: Vec<_> = p.legs.iter().map(|leg| leg.json()).collect();
+                    let _ = write!(out, ", \"legs\": [{}]", legs.join(", "));
+                }
+                out.push('}');
+            }
+            None => out.push_str("null"),
+        }
+
+        out.push_str(",\n  \"sessions\": [");
+        for (i, session) in history.iter().enumerate() {
+            let _ = write!(out, "{}\n    {}", if i > 0 { "," } else { "" }, session.json());
+        }
+        if !history.is_empty() {
+            out.push_str("\n  ");
+        }
+
+        out.push_str("],\n  \"consumers\": [");
+        for (i, c) in consumers.iter().enumerate() {
+            let _ = write!(out, "{}\n    {{\"addr\": \"{}\", \"since\": {}, \"bytes_sent\": {}, \"bitrate_bps\": {}, \"average_bps\": {}, \"queued_bytes\": {}, \"peak_queued_bytes\": {}, \"dropped_packets\": {}, \"priority\": {}",
+                           if i > 0 { "," } else { "" },
+                           c.addr, unix_time(c.since), c.bytes(), c.bitrate(), c.average_bitrate(),
+                           c.queued.load(Ordering::Relaxed), c.peak_queued.load(Ordering::Relaxed), c.dropped(), c.priority());
+            if let Some(ref link) = c.link {
+                out.push_str(&link.json());
+            }
+            if let Some(ref rist) = c.rist {
+                out.push_str(&rist.json());
+            }
+            if let Some(cbr) = c.cbr.get() {
+                out.push_str(&cbr.json());
+            }
+            if c.websocket.load(Ordering::Relaxed) {
+                out.push_str(", \"websocket\": true");
+            }
+            if c.simulated.load(Ordering::Relaxed) {
+                let _ = write!(out, ", \"simulated_drops\": {}", c.simulated_drops.load(Ordering::Relaxed));
+            }
+            match c.keepalive.load(Ordering::Relaxed) {
+                0 => (),
+                bytes => { let _ = write!(out, ", \"keepalive_bytes\": {}", bytes); }
+            }
+            out.push_str(&c.dscp_json());
+            out.push('}');
+        }
+        if !consumers.is_empty() {
+            out.push_str("\n  ");
+        }
+
+        out.push_str("],\n  \"pids\": [");
+        let pids = self.continuity.counters();
+        let pid_bitrates = self.pid_bitrates();
+        for (i, &(pid, ref c)) in pids.iter().enumerate() {
+            let _ = write!(out, "{}\n    {{\"pid\": {}, \"packets\": {}, \"bitrate_bps\": {}, \"discontinuities\": {}, \"duplicates\": {}, \"transport_errors\": {}}}",
+                           if i > 0 { "," } else { "" },
+                           pid, c.packets, pid_bitrates.get(&pid).cloned().unwrap_or(0), c.discontinuities, c.duplicates, c.transport_errors);
+        }
+        if !pids.is_empty() {
+            out.push_str("\n  ");
+        }
+
+        let _ = write!(out, "],\n  \"programs\": {}", self.programs.json());
+        let _ = write!(out, ",\n  \"tiers\": {}", self.tiers_json(&consumers));
+        let indicators: Vec<_> = self.tr101290.indicators().iter()
+            .map(|&(check, ref i)| format!("\"{}\": {{\"active\": {}, \"errors\": {}}}", check, i.active, i.errors))
+            .collect();
+        let _ = write!(out, ",\n  \"tr101290\": {{{}}}", indicators.join(", "));
+        let _ = write!(out, ",\n  \"pcr\": {}", self.pcr_json());
+        let _ = write!(out, ",\n  \"scte35\": {{\"cues\": {}, \"malformed_sections\": {}}}",
+                       self.scte35.received.load(Ordering::Relaxed), self.scte35.malformed.load(Ordering::Relaxed));
+
+        let accepts = self.accepts();
+        if accepts.len() > 1 {
+            let accepts: Vec<_> = accepts.iter().map(u64::to_string).collect();
+            let _ = write!(out, ",\n  \"accepts_per_loop\": [{}]", accepts.join(", "));
+        }
+
+        if let Some(target) = self.delay_target.get() {
+            let _ = write!(out, ",\n  \"delay\": {{\"target_secs\": {:.3}, \"achieved_secs\": ", target.as_secs_f64());
+            match *self.delay.lock().unwrap() {
+                Some(delay) => { let _ = write!(out, "{:.3}}}", delay.as_secs_f64()); }
+                None => out.push_str("null, \"priming\": true}"),
+            }
+        }
+
+        if let Some(pool) = self.pool.get() {
+            let (allocated, reused) = pool.counts();
+            let _ = write!(out, ",\n  \"read_buffers\": {{\"pooled\": {}, \"allocated\": {}, \"reused\": {}}}", pool.len(), allocated, reused);
+        }
+
+        let _ = write!(out, ",\n  \"filtered_packets\": {},\n  \"keepalive_bytes\": {},\n  \"stalled\": {},\n  \"scrambled\": {},\n  \"input_bps\": {},\n  \"broadcast_bps\": {},\n  \"output_bps\": {}\n}}\n",
+                       self.filtered.load(Ordering::Relaxed), self.keepalive.load(Ordering::Relaxed), self.is_stalled(), self.is_scrambled(), input_bps, broadcast_bps, output_bps);
+
+        out
+    }
+
+    /// Whether a producer is connected and sent data within `max_idle`, with
+    /// a JSON body telling why not
+    ///
+    /// Only the producer is looked at, the consumers are left alone however many.
+    pub fn health(&self, max_idle: Duration) -> (bool, String) {
+        let idle = self.producer.lock().unwrap().as_ref().map(|p| p.idle());
+
+        let problem = match idle {
+            None => Some("no producer connected".to_owned()),
+            Some(idle) if idle > max_idle => Some(format!("no data for {:.1}s", idle.as_secs_f64())),
+            Some(_) => None,
+        };
+
+        let mut out = format!("{{\"healthy\": {}, \"producer\": {}", problem.is_none(), idle.is_some());
+        if let Some(idle) = idle {
+            let _ = write!(out, ", \"idle_secs\": {:.3}", idle.as_secs_f64());
+        }
+        let _ = write!(out, ", \"max_idle_secs\": {:.3}", max_idle.as_secs_f64());
+        if let Some(ref problem) = problem {
+            let _ = write!(out, ", \"problem\": \"{}\"", problem);
+        }
+        out.push_str("}\n");
+
+        (problem.is_none(), out)
+    }
+
+    /// Render the Prometheus text exposition
+    pub fn prometheus(&self) -> String {
+        let (producer, consumers) = self.peers();
+        let (bytes_in, bytes_out) = self.totals(&producer, &consumers);
+        let dropped = self.closed_dropped.load(Ordering::Relaxed) + consumers.iter().map(|c| c.dropped()).sum::<u64>();
+
+        let pids = self.continuity.counters();
+        let per_pid = |value: fn(&PidCounters) -> u64| {
+            pids.iter().map(|&(pid, ref c)| (format!("{{pid=\"{}\"}}", pid), value(c))).collect::<Vec<_>>()
+        };
+
+        // Every sample is labelled with the channel or the key
+        let stream = match (self.channel, self.key.as_ref()) {
+            (Some(channel), _) => Some(format!("channel=\"{}\"", channel)),
+            (None, Some(key)) => Some(format!("key=\"{}\"", key)),
+            (None, None) => None,
+        };
+        let labelled = |labels: &str| match stream {
+            Some(ref stream) if labels.is_empty() => format!("{{{}}}", stream),
+            Some(ref stream) => format!("{{{},{}", stream, &labels[1..]),
+            None => labels.to_owned(),
+        };
+
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, u64)]| {
+            let _ = writeln!(out, "# HELP restream_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE restream_{} {}", name, kind);
+            for &(ref labels, value) in samples {
+                let _ = writeln!(out, "restream_{}{} {}", name, labelled(labels), value);
+            }
+        };
+
+        metric("consumers", "gauge", "Connected consumers",
+               &[(String::new(), consumers.len() as u64)]);
+        metric("producer_connected", "gauge", "Whether a producer is streaming",
+               &[(String::new(), producer.is_some() as u64)]);
+        metric("producer_stalled", "gauge", "Whether the producer is connected but sends no data",
+               &[(String::new(), self.is_stalled() as u64)]);
+        let indicators = self.tr101290.indicators();
+        metric("tr101290_active", "gauge", "Whether each TR 101 290 priority 1 indicator is raised",
+               &indicators.iter().map(|&(check, ref i)| (format!("{{check=\"{}\"}}", check), i.active as u64)).collect::<Vec<_>>());
+        metric("tr101290_errors_total", "counter", "TR 101 290 priority 1 errors of each check",
+               &indicators.iter().map(|&(check, ref i)| (format!("{{check=\"{}\"}}", check), i.errors)).collect::<Vec<_>>());
+        metric("scte35_cues_total", "counter", "SCTE-35 splice_insert and time_signal commands broadcast",
+               &[(String::new(), self.scte35.received.load(Ordering::Relaxed))]);
+        metric("scte35_malformed_sections_total", "counter", "SCTE-35 sections skipped for not parsing",
+               &[(String::new(), self.scte35.malformed.load(Ordering::Relaxed))]);
+        metric("stream_scrambled", "gauge", "Whether most of the packets of the stream come scrambled upstream",
+               &[(String::new(), self.is_scrambled() as u64)]);
+        if self.delay_target.get().is_some() {
+            let delay = self.delay.lock().unwrap().map_or(0, |delay| delay.as_millis() as u64);
+            metric("delay_milliseconds", "gauge", "How late the last chunk out of the broadcast delay was, 0 while priming",
+                   &[(String::new(), delay)]);
+        }
+        metric("producer_bytes_total", "counter", "Bytes received from the producers",
+               &[(String::new(), bytes_in)]);
+        metric("producer_buffered_bytes", "gauge", "Bytes read from the producer, not fanned out yet",
+               &[(String::new(), producer.as_ref().map_or(0, |p| p.buffered.load(Ordering::Relaxed) as u64))]);
+        let (allocated, reused) = self.pool_counts();
+        metric("read_buffers_allocated_total", "counter", "Read buffers allocated for the producers",
+               &[(String::new(), allocated)]);
+        metric("read_buffers_reused_total", "counter", "Read buffers of the producers reused from the pool",
+               &[(String::new(), reused)]);
+        metric("broadcast_bytes_total", "counter", "Bytes fanned out once filtered, counted once for all the consumers",
+               &[(String::new(), self.broadcast.load(Ordering::Relaxed))]);
+        metric("sent_bytes_total", "counter", "Bytes sent to all the consumers",
+               &[(String::new(), bytes_out)]);
+        metric("keepalive_bytes_total", "counter", "Bytes of null packets queued to the consumers while the producer was silent, left out of the broadcast",
+               &[(String::new(), self.keepalive.load(Ordering::Relaxed))]);
+        metric("consumer_bytes_total", "counter", "Bytes sent to each connected consumer",
+               &consumers.iter().map(|c| (format!("{{addr=\"{}\"}}", c.addr), c.bytes())).collect::<Vec<_>>());
+        metric("simulated_drops_total", "counter", "Chunks dropped by the simulated link of each connected consumer",
+               &consumers.iter()
+                   .filter(|c| c.simulated.load(Ordering::Relaxed))
+                   .map(|c| (format!("{{addr=\"{}\"}}", c.addr), c.simulated_drops.load(Ordering::Relaxed)))
+                   .collect::<Vec<_>>());
+        let rist = |value: fn(&RistStats) -> u64| {
+            consumers.iter()
+                .filter_map(|c| c.rist.as_ref().map(|r| (format!("{{addr=\"{}\"}}", c.addr), value(r))))
+                .collect::<Vec<_>>()
+        };
+        metric("rist_retransmitted_packets_total", "counter", "Packets sent again to each RIST output",
+               &rist(|r| r.retransmitted.load(Ordering::Relaxed)));
+        metric("rist_buffered_packets", "gauge", "Packets kept for retransmission by each RIST output",
+               &rist(|r| r.buffered.load(Ordering::Relaxed) as u64));
+        let cbr = |value: fn(&CbrStats) -> u64| {
+            consumers.iter()
+                .filter_map(|c| c.cbr.get().map(|cbr| (format!("{{addr=\"{}\"}}", c.addr), value(cbr))))
+                .collect::<Vec<_>>()
+        };
+        metric("cbr_target_bps", "gauge", "Constant bitrate of each output padded to one",
+               &cbr(|cbr| cbr.target_bps));
+        metric("cbr_null_packets_inserted_total", "counter", "Null packets added to each constant bitrate output",
+               &cbr(|cbr| cbr.nulls_inserted.load(Ordering::Relaxed)));
+        metric("cbr_null_packets_dropped_total", "counter", "Null packets of the stream left out of each constant bitrate output",
+               &cbr(|cbr| cbr.nulls_dropped.load(Ordering::Relaxed)));
+        metric("cbr_overruns_total", "counter", "Times the stream peaked above the rate of each constant bitrate output",
+               &cbr(|cbr| cbr.overruns.load(Ordering::Relaxed)));
+        let legs = |value: fn(&LegStats) -> &AtomicU64| {
+            producer.iter()
+                .flat_map(|p| p.legs.iter())
+                .map(|leg| (format!("{{leg=\"{}\"}}", leg.addr), value(leg).load(Ordering::Relaxed)))
+                .collect::<Vec<_>>()
+        };
+        metric("leg_packets_total", "counter", "RTP packets received on each path of the producer",
+               &legs(|leg| &leg.packets));
+        metric("leg_used_packets_total", "counter", "Packets of each path of the producer making it to the output",
+               &legs(|leg| &leg.used));
+        metric("leg_lost_packets_total", "counter", "Packets missing on each path of the producer",
+               &legs(|leg| &leg.lost));
+        let rtp = |value: fn(&RtpStats) -> &AtomicU64| {
+            producer.iter()
+                .filter_map(|p| p.rtp.as_ref())
+                .map(|rtp| (String::new(), value(rtp).load(Ordering::Relaxed)))
+                .collect::<Vec<_>>()
+        };
+        metric("rtp_lost_packets_total", "counter", "Packets of the producer missing according to the RTP sequence numbers",
+               &rtp(|rtp| &rtp.lost));
+        metric("rtp_raw_datagrams_total", "counter", "Datagrams of the RTP producer passed through as they are, not looking like RTP",
+               &rtp(|rtp| &rtp.raw));
+        metric("dropped_packets_total", "counter", "Packets dropped because a consumer queue was full",
+               &[(String::new(), dropped)]);
+        metric("filtered_packets_total", "counter", "Packets left out by the PID filter",
+               &[(String::new(), self.filtered.load(Ordering::Relaxed))]);
+        metric("record_spliced_bytes_total", "counter", "Bytes spliced straight into the recording, left out of the PID and program counters",
+               &[(String::new(), self.spliced.load(Ordering::Relaxed))]);
+        metric("connections_total", "counter", "Connections accepted",
+               &[("{role=\"producer\"}".to_owned(), self.producer_connections.load(Ordering::Relaxed)),
+                 ("{role=\"consumer\"}".to_owned(), self.consumer_connections.load(Ordering::Relaxed))]);
+        metric("accepts_total", "counter", "Consumer connections taken by each accept loop",
+               &self.accepts().iter().enumerate().map(|(i, &n)| (format!("{{loop=\"{}\"}}", i), n)).collect::<Vec<_>>());
+        metric("pid_packets_total", "counter", "Packets broadcast per PID",
+               &per_pid(|c| c.packets));
+        let pid_bitrates = self.pid_bitrates();
+        metric("pid_bitrate_bps", "gauge", "Bitrate of each PID over the last seconds",
+               &pids.iter().map(|&(pid, _)| (format!("{{pid=\"{}\"}}", pid), pid_bitrates.get(&pid).cloned().unwrap_or(0))).collect::<Vec<_>>());
+        metric("cc_discontinuities_total", "counter", "Continuity counter jumps per PID",
+               &per_pid(|c| c.discontinuities));
+        metric("duplicate_packets_total", "counter", "Packets received twice in a row per PID",
+               &per_pid(|c| c.duplicates));
+        metric("transport_errors_total", "counter", "Packets flagged with the transport error indicator per PID",
+               &per_pid(|c| c.transport_errors));
+
+        // Left empty until a PCR is seen
+        let (jitter, interval) = self.pcr.histograms();
+        let mut histogram = |name: &str, help: &str, bounds: &[f64], h: &Histogram| {
+            let _ = writeln!(out, "# HELP restream_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE restream_{} histogram", name);
+            if h.count == 0 {
+                return;
+            }
+            let mut cumulative = 0;
+            for (bound, count) in bounds.iter().zip(&h.counts) {
+                cumulative += count;
+                let _ = writeln!(out, "restream_{}_bucket{} {}", name, labelled(&format!("{{le=\"{}\"}}", bound)), cumulative);
+            }
+            let _ = writeln!(out, "restream_{}_bucket{} {}", name, labelled("{le=\"+Inf\"}"), h.count);
+            let _ = writeln!(out, "restream_{}_sum{} {}", name, labelled(""), h.sum);
+            let _ = writeln!(out, "restream_{}_count{} {}", name, labelled(""), h.count);
+        };
+        histogram("pcr_jitter_seconds", "Jitter of the PCRs against their byte position at the mux rate",
+                  &pcr::JITTER_BUCKETS, &jitter);
+        histogram("pcr_interval_seconds", "Time between two PCRs of the PCR PID",
+                  &pcr::INTERVAL_BUCKETS, &interval);
+
+        out
+    }
+}
+
+fn mbps(bps: u64) -> f64 {
+    bps as f64 / 1e6
+}
+
+/// Log the current input and output bitrates every `REPORT_INTERVAL`, while someone is connected
+pub async fn report(stats: Arc<Stats>) {
+    let mut ticks = crate::interval(REPORT_INTERVAL);
+    loop {
+        ticks.tick().await;
+        let (producer, consumers) = stats.peers();
+        if producer.is_none() && consumers.is_empty() {
+            continue;
+        }
+
+        let input = producer.map_or(0, |p| p.bitrate());
+        let output = consumers.iter().map(|c| c.bitrate()).sum::<u64>();
+        let per_consumer = output.checked_div(consumers.len() as u64).unwrap_or(0);
+
+        let channel = match (stats.channel, stats.key.as_ref()) {
+            (Some(channel), _) => format!("channel {}, ", channel),
+            (None, Some(key)) => format!("{}, ", key),
+            (None, None) => String::new(),
+        };
+        info!("{}in: {:.1} Mbps, out: {}\u{d7}{:.1} Mbps", channel, mbps(input), consumers.len(), mbps(per_consumer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totals_survive_disconnects() {
+        let stats = Stats::default();
+        let addr = "127.0.0.1:1234".parse().unwrap();
+        let peer = Arc::new(PeerStats::new(addr));
+
+        stats.add_consumer(peer.clone());
+        peer.add_bytes(100);
+        assert!(stats.prometheus().contains("restream_consumer_bytes_total{addr=\"127.0.0.1:1234\"} 100"));
+
+        stats.remove_consumer(&addr, Reason::Closed);
+        let out = stats.prometheus();
+        assert!(out.contains("restream_sent_bytes_total 100"));
+        assert!(out.contains("restream_consumers 0"));
+        assert!(out.contains("restream_producer_connected 0"));
+    }
+
+    #[test]
+    fn producer_history() {
+        let stats = Stats::default();
+        for port in 1..=HISTORY as u16 + 2 {
+            let peer = Arc::new(PeerStats::new(([10, 0, 0, 1], port).into()));
+            peer.add_bytes(188);
+            stats.set_producer(peer);
+        }
+        // Taken over by the next one, the last one is still streaming
+        let last: SocketAddr = ([10, 0, 0, 1], HISTORY as u16 + 2).into();
+        stats.remove_producer(&last, Reason::Error("connection reset".to_owned()));
+
+        let out = stats.json();
+        assert!(out.contains("\"producer\": null,\n  \"sessions\": [\n    {\"addr\": \"10.0.0.1:3\""));
+        assert!(!out.contains("\"10.0.0.1:2\""));
+        assert!(out.contains("\"bytes_received\": 188"));
+        assert!(out.contains("\"reason\": \"removed\"}"));
+        assert!(out.contains("\"reason\": \"error\", \"error\": \"connection reset\"}\n  ],"));
+        assert_eq!(out.matches("\"until\"").count(), HISTORY);
+    }
+
+    #[test]
+    fn health() {
+        let stats = Stats::default();
+        let (healthy, body) = stats.health(Duration::from_secs(5));
+        assert!(!healthy);
+        assert_eq!(body, "{\"healthy\": false, \"producer\": false, \"max_idle_secs\": 5.000, \"problem\": \"no producer connected\"}\n");
+
+        let peer = Arc::new(PeerStats::new("127.0.0.1:1234".parse().unwrap()));
+        stats.set_producer(peer.clone());
+        peer.add_bytes_at(188, peer.started + Duration::from_millis(1500));
+        assert_eq!(peer.idle_at(peer.started + Duration::from_secs(2)), Duration::from_millis(500));
+        assert!(stats.health(Duration::from_secs(5)).0);
+
+        // Nothing sent since connecting
+        stats.set_producer(Arc::new(PeerStats::new("127.0.0.1:1235".parse().unwrap())));
+        ::std::thread::sleep(Duration::from_millis(10));
+        let (healthy, body) = stats.health(Duration::from_millis(5));
+        assert!(!healthy);
+        assert!(body.contains("\"producer\": true, \"idle_secs\": "));
+        assert!(body.contains("\"problem\": \"no data for "));
+    }
+
+    #[test]
+    fn json_status() {
+        let stats = Stats::default();
+        assert!(stats.json().contains("\"producer\": null,\n  \"sessions\": [],\n  \"consumers\": [],"));
+
+        let addr = "127.0.0.1:1234".parse().unwrap();
+        let peer = Arc::new(PeerStats::new(addr));
+        peer.set_queued(376);
+        peer.set_queued(188);
+        stats.add_consumer(peer);
+
+        let out = stats.json();
+        assert!(out.contains("\"addr\": \"127.0.0.1:1234\""));
+        assert!(out.contains("\"queued_bytes\": 188, \"peak_queued_bytes\": 376"));
+        assert!(out.contains("\"bitrate_bps\": 0, \"average_bps\": "));
+        assert!(out.contains("\"stalled\": false,\n  \"scrambled\": false"));
+        assert!(out.contains("\"pids\": [],\n  \"programs\": [],"));
+    }
+
+    #[test]
+    fn tiers() {
+        let stats = Stats::default();
+        assert!(stats.json().contains("\"tiers\": [],"));
+
+        let origin = Arc::new(PeerStats::new("10.0.0.1:1234".parse().unwrap()));
+        origin.priority.store(2, Ordering::Relaxed);
+        stats.add_consumer(origin);
+        let viewer = Arc::new(PeerStats::new("192.0.2.1:1234".parse().unwrap()));
+        viewer.dropped.store(5, Ordering::Relaxed);
+        stats.add_consumer(viewer.clone());
+        stats.add_displaced(0);
+        stats.remove_consumer(&viewer.addr, Reason::Removed);
+        let viewer = Arc::new(PeerStats::new("192.0.2.2:1234".parse().unwrap()));
+        viewer.dropped.store(3, Ordering::Relaxed);
+        stats.add_consumer(viewer);
+
+        let out = stats.json();
+        assert!(out.contains("\"dropped_packets\": 0, \"priority\": 2}"));
+        assert!(out.contains("\"tiers\": [{\"priority\": 2, \"consumers\": 1, \"dropped_packets\": 0, \"displaced\": 0}, \
+                              {\"priority\": 0, \"consumers\": 1, \"dropped_packets\": 8, \"displaced\": 1}],"));
+    }
+
+    #[test]
+    fn delay() {
+        let stats = Stats::default();
+        assert!(!stats.json().contains("\"delay\""));
+
+        stats.set_delay_target(Duration::from_secs(7));
+        assert!(stats.json().contains("\"delay\": {\"target_secs\": 7.000, \"achieved_secs\": null, \"priming\": true},"));
+        stats.set_delay(Some(Duration::from_millis(7002)));
+        assert!(stats.json().contains("\"delay\": {\"target_secs\": 7.000, \"achieved_secs\": 7.002},"));
+        assert!(stats.prometheus().contains("restream_delay_milliseconds 7002\n"));
+    }
+
+    #[test]
+    fn read_buffers() {
+        let stats = Stats::default();
+        let pool = Arc::new(BufferPool::new(4));
+        stats.set_pool(pool.clone());
+
+        let buf = pool.get(1000);
+        pool.put(buf);
+        drop(pool.get(1000));
+        assert!(stats.json().contains("\"read_buffers\": {\"pooled\": 0, \"allocated\": 1, \"reused\": 1},"));
+        assert!(stats.prometheus().contains("restream_read_buffers_reused_total 1\n"));
+    }
+
+    #[test]
+    fn srt_link() {
+        let stats = Stats::default();
+        let link = Arc::new(LinkStats::default());
+        let mut peer = PeerStats::new("127.0.0.1:1234".parse().unwrap());
+        peer.link = Some(link.clone());
+        stats.add_consumer(Arc::new(peer));
+
+        link.update(Duration::from_micros(12500), 3, 7);
+        assert!(stats.json().contains("\"dropped_packets\": 0, \"priority\": 0, \"srt\": {\"rtt_ms\": 12.500, \"retransmitted_packets\": 3, \"lost_packets\": 7}}"));
+    }
+
+    #[test]
+    fn merged_legs() {
+        let stats = Stats::default();
+        let leg = Arc::new(LegStats::new("239.1.1.1:5000".parse().unwrap()));
+        let mut peer = PeerStats::new("10.0.0.1:1234".parse().unwrap());
+        peer.legs = vec![leg.clone()];
+        stats.set_producer(Arc::new(peer));
+
+        leg.packets.store(10, Ordering::Relaxed);
+        leg.used.store(8, Ordering::Relaxed);
+        leg.lost.store(1, Ordering::Relaxed);
+        assert!(stats.json().contains(", \"legs\": [{\"addr\": \"239.1.1.1:5000\", \"packets\": 10, \"used_packets\": 8, \"lost_packets\": 1}]}"));
+        assert!(stats.prometheus().contains("restream_leg_used_packets_total{leg=\"239.1.1.1:5000\"} 8"));
+    }
+
+    #[test]
+    fn rtp_producer() {
+        let stats = Stats::default();
+        let rtp = Arc::new(RtpStats::default());
+        let mut peer = PeerStats::new("10.0.0.1:1234".parse().unwrap());
+        peer.rtp = Some(rtp.clone());
+        stats.set_producer(Arc::new(peer));
+
+        rtp.lost.store(3, Ordering::Relaxed);
+        rtp.raw.store(2, Ordering::Relaxed);
+        assert!(stats.json().contains(", \"rtp\": {\"lost_packets\": 3, \"raw_datagrams\": 2}}"));
+        let out = stats.prometheus();
+        assert!(out.contains("restream_rtp_lost_packets_total 3"));
+        assert!(out.contains("restream_rtp_raw_datagrams_total 2"));
+    }
+
+    #[test]
+    fn channel_labels() {
+        let stats = Stats::for_channel(Some(2));
+        let addr = "127.0.0.1:1234".parse().unwrap();
+        stats.add_consumer(Arc::new(PeerStats::new(addr)));
+
+        let out = stats.prometheus();
+        assert!(out.contains("restream_consumers{channel=\"2\"} 1"));
+        assert!(out.contains("restream_consumer_bytes_total{channel=\"2\",addr=\"127.0.0.1:1234\"} 0"));
+        assert!(stats.json().starts_with("{\n  \"channel\": 2,\n  \"producer\": null,"));
+    }
+
+    #[test]
+    fn accept_loops() {
+        let stats = Stats::default();
+        assert!(!stats.json().contains("accepts_per_loop"));
+
+        let counters = stats.accept_counters(2);
+        counters[1].fetch_add(3, Ordering::Relaxed);
+        // The same counters for the next producer
+        stats.accept_counters(2)[0].fetch_add(1, Ordering::Relaxed);
+
+        assert!(stats.json().contains(",\n  \"accepts_per_loop\": [1, 3]"));
+        assert!(stats.prometheus().contains("restream_accepts_total{loop=\"1\"} 3"));
+    }
+
+    #[test]
+    fn per_pid_counters() {
+        let stats = Stats::default();
+        let mut pkt = [0xff; 188];
+        pkt[..4].copy_from_slice(&[0x47, 0x01, 0x00, 0x10]);
+        stats.inspect(&pkt);
+        pkt[3] = 0x15;
+        stats.inspect(&pkt);
+
+        let out = stats.prometheus();
+        assert!(out.contains("restream_pid_packets_total{pid=\"256\"} 2"));
+        assert!(out.contains("restream_cc_discontinuities_total{pid=\"256\"} 1"));
+        assert!(stats.json().contains("{\"pid\": 256, \"packets\": 2, \"bitrate_bps\": 0, \"discontinuities\": 1,"));
+    }
+
+    #[test]
+    fn bitrates_never_go_backwards() {
+        let stats = Stats::default();
+        let start = Instant::now();
+        let addr = "127.0.0.1:1234".parse().unwrap();
+        let peer = Arc::new(PeerStats::new(addr));
+        stats.add_consumer(peer.clone());
+        peer.add_bytes(1000);
+        stats.sample_at(start);
+
+        // Sampled while leaving, gone from the consumers but not yet counted as closed
+        stats.consumers.lock().unwrap().remove(&addr);
+        stats.sample_at(start + Duration::from_secs(1));
+        assert_eq!(stats.bitrates(), (0, 0, 0));
+
+        stats.closed_out.fetch_add(1500, Ordering::Relaxed);
+        stats.sample_at(start + Duration::from_secs(2));
+        assert_eq!(stats.bitrates(), (0, 0, 500 * 8 / 2));
+    }
+
+    #[test]
+    fn pid_bitrates_and_pcr() {
+        let stats = Stats::default();
+        let start = Instant::now();
+        let mut pkt = [0xff; 188];
+        pkt[..4].copy_from_slice(&[0x47, 0x01, 0x00, 0x10]);
+
+        stats.sample_at(start);
+        for cc in 0..10 {
+            pkt[3] = 0x10 | cc;
+            stats.inspect(&pkt);
+        }
+        stats.sample_at(start + Duration::from_secs(2));
+        assert_eq!(stats.pid_bitrates()[&0x100], 10 * 188 * 8 / 2);
+        assert!(stats.prometheus().contains("restream_pid_bitrate_bps{pid=\"256\"} 7520"));
+
+        // No PCR, nothing to tell
+        assert!(stats.json().contains("\"pcr\": \"n/a\""));
+        let out = stats.prometheus();
+        assert!(out.contains("# TYPE restream_pcr_jitter_seconds histogram\n# HELP"));
+        assert!(!out.contains("restream_pcr_interval_seconds_count"));
+    }
+
+    #[test]
+    fn bitrate_windows() {
+        let peer = PeerStats::new("127.0.0.1:1234".parse().unwrap());
+        let start = peer.started;
+        let at = |ms| start + Duration::from_millis(ms);
+
+        // Nothing to divide by right after the start
+        assert_eq!(peer.bitrate_at(start), 0);
+        assert_eq!(peer.average_bitrate_at(start), 0);
+
+        peer.add_bytes_at(1000, at(100));
+        peer.add_bytes_at(1000, at(900));
+        assert_eq!(peer.bitrate_at(at(950)), 0);
+        // The first second is complete
+        assert_eq!(peer.bitrate_at(at(1500)), 16_000);
+
+        peer.add_bytes_at(500, at(1200));
+        assert_eq!(peer.bitrate_at(at(1500)), 16_000);
+        assert_eq!(peer.bitrate_at(at(2100)), 4000);
+        assert_eq!(peer.average_bitrate_at(at(2500)), 8000);
+
+        // A silent second resets the rate
+        assert_eq!(peer.bitrate_at(at(3100)), 0);
+        peer.add_bytes_at(100, at(5000));
+        assert_eq!(peer.bitrate_at(at(5500)), 0);
+        assert_eq!(peer.bytes(), 2600);
+    }
+}
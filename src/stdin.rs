@@ -0,0 +1,105 @@
+//! Producer stream read from the standard input
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use bytes::Bytes;
+use log::error;
+
+use std::cmp;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+
+use crate::net::{self, PeerName, Socket};
+
+/// Chunks read ahead of the producer
+const QUEUE: usize = 16;
+const CHUNK: usize = 64 * 1024;
+
+/// The standard input, read by a thread of its own since it cannot be polled
+pub struct StdinPeer {
+    rx: mpsc::Receiver<Bytes>,
+    /// What is left of the last chunk
+    pending: Bytes,
+    addr: SocketAddr,
+}
+
+impl StdinPeer {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE);
+        thread::spawn(move || forward(tx));
+
+        StdinPeer { rx, pending: Bytes::new(), addr: net::placeholder_addr() }
+    }
+}
+
+/// Send the standard input until its end, or until the producer is gone
+fn forward(tx: mpsc::Sender<Bytes>) {
+    let stdin = io::stdin();
+    let mut stdin = stdin.lock();
+
+    loop {
+        let mut buf = vec![0; CHUNK];
+        let n = match stdin.read(&mut buf) {
+            Ok(0) => return,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                error!("Cannot read the standard input: {}", e);
+                return;
+            }
+        };
+        buf.truncate(n);
+
+        if tx.blocking_send(buf.into()).is_err() {
+            return;
+        }
+    }
+}
+
+impl AsyncRead for StdinPeer {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if self.pending.is_empty() {
+            match self.rx.poll_recv(cx) {
+                Poll::Ready(Some(chunk)) => self.pending = chunk,
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let n = cmp::min(buf.remaining(), self.pending.len());
+        buf.put_slice(&self.pending.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Nothing is ever sent to a producer
+impl AsyncWrite for StdinPeer {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, _: &[u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(Err(io::Error::other("cannot write to the standard input")))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Socket for StdinPeer {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn peer_name(&self) -> io::Result<PeerName> {
+        Ok(PeerName::Stdin)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+//! Socket activation and readiness notification, as systemd does them
+//!
+//! The listeners are passed from fd 3 on, with `LISTEN_PID`, `LISTEN_FDS`
+//! and `LISTEN_FDNAMES` describing them. The ones named `producer` and
+//! `consumer` with `FileDescriptorName=` are matched by name, otherwise the
+//! first one is the producer listener and the second one the consumer one.
+
+use log::debug;
+
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::process;
+
+/// First fd passed by the service manager
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Listeners inherited from the service manager
+#[derive(Debug)]
+pub struct Activation {
+    pub producer: Option<TcpListener>,
+    /// More than one when they share the port with SO_REUSEPORT
+    pub consumers: Vec<TcpListener>,
+}
+
+impl Activation {
+    /// Take the listeners passed to this process, if any
+    ///
+    /// The variables are removed so the children do not take them too.
+    pub fn from_env() -> io::Result<Option<Activation>> {
+        let var = |name| env::var(name).ok();
+        let (pid, fds, names) = (var("LISTEN_PID"), var("LISTEN_FDS"), var("LISTEN_FDNAMES"));
+        for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            env::remove_var(name);
+        }
+
+        let passed = passed(pid.as_deref(), fds.as_deref(), names.as_deref(), process::id())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if passed.is_empty() {
+            return Ok(None);
+        }
+
+        let (producer, consumers) = assign(&passed).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let listener = |fd: RawFd| {
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            match listener.local_addr() {
+                Ok(addr) => {
+                    debug!("Inherited fd {} listening on {}", fd, addr);
+                    Ok(listener)
+                }
+                Err(e) => Err(io::Error::new(e.kind(), format!("fd {} passed by systemd is not a TCP listener: {}", fd, e))),
+            }
+        };
+
+        Ok(Some(Activation {
+            producer: producer.map(listener).transpose()?,
+            consumers: consumers.into_iter().map(listener).collect::<io::Result<_>>()?,
+        }))
+    }
+}
+
+/// The fds passed to the process `own_pid` and their names, none if they are meant for another one
+fn passed(pid: Option<&str>, fds: Option<&str>, names: Option<&str>, own_pid: u32) -> Result<Vec<(RawFd, String)>, String> {
+    let (pid, fds) = match (pid, fds) {
+        (Some(pid), Some(fds)) => (pid, fds),
+        _ => return Ok(Vec::new()),
+    };
+    if pid.parse() != Ok(own_pid) {
+        return Ok(Vec::new());
+    }
+
+    let count: RawFd = fds.parse().map_err(|_| format!("Invalid LISTEN_FDS {}", fds))?;
+    let names: Vec<_> = names.map(|names| names.split(':').collect()).unwrap_or_default();
+
+    Ok((0..count).map(|i| (LISTEN_FDS_START + i, names.get(i as usize).unwrap_or(&"").to_string())).collect())
+}
+
+/// The producer and consumer fds, by name or else by order
+fn assign(passed: &[(RawFd, String)]) -> Result<(Option<RawFd>, Vec<RawFd>), String> {
+    let named = |wanted: &str| passed.iter().filter(|&(_, name)| name == wanted).map(|&(fd, _)| fd).collect::<Vec<_>>();
+    let (producers, consumers) = (named("producer"), named("consumer"));
+
+    if producers.is_empty() && consumers.is_empty() {
+        return match passed.len() {
+            1 | 2 => Ok((Some(passed[0].0), passed.get(1).map(|&(fd, _)| fd).into_iter().collect())),
+            n => Err(format!("systemd passed {} sockets, name them producer and consumer with FileDescriptorName=", n)),
+        };
+    }
+    if producers.len() > 1 {
+        return Err("systemd passed more than one socket named producer".to_owned());
+    }
+    if let Some((_, name)) = passed.iter().find(|&(_, name)| name != "producer" && name != "consumer") {
+        return Err(format!("systemd passed a socket named {:?}, expected producer or consumer", name));
+    }
+
+    Ok((producers.first().cloned(), consumers))
+}
+
+/// Tell the service manager about `state`, e.g. `READY=1` for the `Type=notify` units
+///
+/// Does nothing unless `NOTIFY_SOCKET` is set.
+pub fn sd_notify(state: &str) -> io::Result<()> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let socket = UnixDatagram::unbound()?;
+
+    match path.to_str().and_then(|path| path.strip_prefix('@')) {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::net::SocketAddr;
+
+            socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fds_passed() {
+        assert_eq!(passed(None, None, None, 42), Ok(Vec::new()));
+        // Meant for the parent
+        assert_eq!(passed(Some("41"), Some("2"), None, 42), Ok(Vec::new()));
+        assert!(passed(Some("42"), Some("two"), None, 42).is_err());
+
+        let fds = passed(Some("42"), Some("2"), Some("consumer:producer"), 42).unwrap();
+        assert_eq!(fds, vec![(3, "consumer".to_owned()), (4, "producer".to_owned())]);
+        assert_eq!(assign(&fds), Ok((Some(4), vec![3])));
+    }
+
+    #[test]
+    fn fds_assigned() {
+        let unnamed = passed(Some("42"), Some("2"), Some("restream.socket:restream.socket"), 42).unwrap();
+        assert_eq!(assign(&unnamed), Ok((Some(3), vec![4])));
+        assert_eq!(assign(&unnamed[..1]), Ok((Some(3), Vec::new())));
+
+        let shared = passed(Some("42"), Some("3"), Some("consumer:consumer:producer"), 42).unwrap();
+        assert_eq!(assign(&shared), Ok((Some(5), vec![3, 4])));
+        let consumers = passed(Some("42"), Some("1"), Some("consumer"), 42).unwrap();
+        assert_eq!(assign(&consumers), Ok((None, vec![3])));
+
+        let three = passed(Some("42"), Some("3"), None, 42).unwrap();
+        assert!(assign(&three).is_err());
+        let stray = passed(Some("42"), Some("2"), Some("producer:metrics"), 42).unwrap();
+        assert!(assign(&stray).is_err());
+    }
+
+    #[test]
+    fn notified() {
+        let dir = env::temp_dir().join(format!("restream-notify-{}", process::id()));
+        let _ = std::fs::remove_file(&dir);
+        let manager = UnixDatagram::bind(&dir).unwrap();
+
+        env::set_var("NOTIFY_SOCKET", &dir);
+        sd_notify("READY=1").unwrap();
+        env::remove_var("NOTIFY_SOCKET");
+
+        let mut buf = [0; 64];
+        let n = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        std::fs::remove_file(&dir).unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+//! TLS on the consumer listener, mutual TLS on the producer one
+
+use native_tls::{self, Identity};
+use openssl::nid::Nid;
+use openssl::ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
+use openssl::x509::{X509Name, X509VerifyResult};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_native_tls::{TlsAcceptor, TlsStream};
+use tokio_openssl::SslStream;
+use log::{info, warn};
+
+use std::fmt;
+use std::fs;
+use std::io::{self, IoSlice};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::net::Socket;
+
+/// Time given to the consumers to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl<S: Socket> Socket for TlsStream<S> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().get_ref().get_ref().peer_addr()
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.get_ref().get_ref().get_ref().shutdown_write()
+    }
+
+    fn tcp(&self) -> Option<&TcpStream> {
+        self.get_ref().get_ref().get_ref().tcp()
+    }
+}
+
+/// Certificate and key the consumers are served with
+#[derive(Clone)]
+pub struct Tls {
+    acceptor: TlsAcceptor,
+}
+
+impl Tls {
+    /// Load a PEM certificate chain and its PEM (PKCS#8) private key
+    pub fn from_pem(cert: &Path, key: &Path) -> Result<Self, String> {
+        let read = |path: &Path| fs::read(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e));
+
+        let identity = Identity::from_pkcs8(&read(cert)?, &read(key)?)
+            .map_err(|e| format!("Invalid certificate {} or key {}: {}", cert.display(), key.display(), e))?;
+        let acceptor = native_tls::TlsAcceptor::new(identity)
+            .map_err(|e| format!("Cannot set up TLS: {}", e))?;
+
+        Ok(Tls { acceptor: acceptor.into() })
+    }
+
+    /// Run the handshake in a task of its own, then `start` with the encrypted stream
+    ///
+    /// A failed handshake is only logged.
+    pub fn accept<S, F>(&self, socket: S, addr: SocketAddr, start: F)
+    where
+        S: Socket,
+        F: FnOnce(TlsStream<S>) + Send + 'static,
+    {
+        let acceptor = self.acceptor.clone();
+
+        tokio::spawn(async move {
+            match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+                Ok(Ok(stream)) => start(stream),
+                Ok(Err(e)) => warn!("TLS handshake with {:?} failed: {}", addr, e),
+                Err(_) => warn!("TLS handshake with {:?} failed: no answer within {:?}", addr, HANDSHAKE_TIMEOUT),
+            }
+        });
+    }
+}
+
+impl fmt::Debug for Tls {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Tls")
+    }
+}
+
+/// A producer connection, authenticated by its client certificate
+pub struct ProducerStream<S = TcpStream> {
+    stream: SslStream<S>,
+    /// CN of the client certificate
+    identity: Option<String>,
+}
+
+impl<S: Socket> AsyncRead for ProducerStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: Socket> AsyncWrite for ProducerStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(mut self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+impl<S: Socket> Socket for ProducerStream<S> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.get_ref().peer_addr()
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.stream.get_ref().shutdown_write()
+    }
+
+    fn tcp(&self) -> Option<&TcpStream> {
+        self.stream.get_ref().tcp()
+    }
+
+    fn identity(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+}
+
+/// Server side of the handshake, checking the client certificate
+async fn handshake<S: Socket>(acceptor: &SslAcceptor, socket: S) -> io::Result<ProducerStream<S>> {
+    let ssl = Ssl::new(acceptor.context()).map_err(io::Error::other)?;
+    let mut stream = SslStream::new(ssl, socket).map_err(io::Error::other)?;
+
+    if let Err(e) = Pin::new(&mut stream).accept().await {
+        let verify = stream.ssl().verify_result();
+        let msg = if verify == X509VerifyResult::OK {
+            e.to_string()
+        } else {
+            format!("invalid client certificate, {}", verify.error_string())
+        };
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+
+    let identity = stream.ssl().peer_certificate().and_then(|cert| {
+        cert.subject_name().entries_by_nid(Nid::COMMONNAME).next().and_then(|cn| cn.data().to_string().ok())
+    });
+    Ok(ProducerStream { stream, identity })
+}
+
+/// Certificate, key and client CA of the producer listener
+#[derive(Clone)]
+pub struct ProducerTls {
+    acceptor: Arc<SslAcceptor>,
+}
+
+impl ProducerTls {
+    /// Load the PEM certificate chain, its PEM private key and the PEM CA the producers must be signed by
+    pub fn from_pem(cert: &Path, key: &Path, ca: &Path) -> Result<Self, String> {
+        let invalid = |path: &Path, e| format!("Invalid {}: {}", path.display(), e);
+
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())
+            .map_err(|e| format!("Cannot set up TLS: {}", e))?;
+        builder.set_certificate_chain_file(cert).map_err(|e| invalid(cert, e))?;
+        builder.set_private_key_file(key, SslFiletype::PEM).map_err(|e| invalid(key, e))?;
+
+        builder.set_ca_file(ca).map_err(|e| invalid(ca, e))?;
+        builder.set_client_ca_list(X509Name::load_client_ca_file(ca).map_err(|e| invalid(ca, e))?);
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+
+        Ok(ProducerTls { acceptor: Arc::new(builder.build()) })
+    }
+
+    /// Run the handshake in a task of its own, then `start` with the authenticated stream
+    ///
+    /// A failed handshake is only logged, nothing was read from the producer by then.
+    pub fn accept<S, F>(&self, socket: S, addr: SocketAddr, start: F)
+    where
+        S: Socket,
+        F: FnOnce(ProducerStream<S>) + Send + 'static,
+    {
+        let acceptor = self.acceptor.clone();
+
+        tokio::spawn(async move {
+            match time::timeout(HANDSHAKE_TIMEOUT, handshake(&acceptor, socket)).await {
+                Ok(Ok(stream)) => {
+                    match stream.identity() {
+                        Some(cn) => info!("Producer ({:?}) identified as {}", addr, cn),
+                        None => info!("Producer ({:?}) certificate has no CN", addr),
+                    }
+                    start(stream)
+                }
+                Ok(Err(e)) => warn!("Rejecting Producer ({:?}), TLS handshake failed: {}", addr, e),
+                Err(_) => warn!("Rejecting Producer ({:?}), no TLS handshake within {:?}", addr, HANDSHAKE_TIMEOUT),
+            }
+        });
+    }
+}
+
+impl fmt::Debug for ProducerTls {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ProducerTls")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pem_files() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls");
+        assert!(Tls::from_pem(&dir.join("cert.pem"), &dir.join("key.pem")).is_ok());
+
+        let err = Tls::from_pem(&dir.join("missing.pem"), &dir.join("key.pem")).unwrap_err();
+        assert!(err.contains("missing.pem"), "{}", err);
+
+        // Swapped, the key is no certificate
+        assert!(Tls::from_pem(&dir.join("key.pem"), &dir.join("cert.pem")).is_err());
+    }
+
+    #[test]
+    fn producer_pem_files() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls");
+        assert!(ProducerTls::from_pem(&dir.join("cert.pem"), &dir.join("key.pem"), &dir.join("ca.pem")).is_ok());
+
+        let err = ProducerTls::from_pem(&dir.join("cert.pem"), &dir.join("producer-key.pem"), &dir.join("ca.pem")).unwrap_err();
+        assert!(err.contains("producer-key.pem"), "{}", err);
+
+        let err = ProducerTls::from_pem(&dir.join("cert.pem"), &dir.join("key.pem"), &dir.join("missing.pem")).unwrap_err();
+        assert!(err.contains("missing.pem"), "{}", err);
+    }
+}
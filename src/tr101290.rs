@@ -0,0 +1,385 @@
+//! ETSI TR 101 290 priority 1 monitoring of the stream broadcast
+//!
+//! Besides the continuity counters, checked on their own, the first priority
+//! indicators are followed: the sync losses and sync byte errors of the
+//! producer, the PAT and the PMTs not repeated often enough, scrambled or
+//! carrying the wrong table, and the PIDs the PMTs refer to going missing.
+//! The timers are checked a few times a second while a producer is connected.
+//! An indicator is active while its table or PID is overdue, or for a second
+//! after an error that does not last, every change is logged and emitted.
+
+use log::{info, warn};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use crate::events::Event;
+use crate::psi::{self, Program};
+use crate::stats::PeerStats;
+use crate::ts;
+use crate::Shared;
+
+/// How often the timers are checked
+const TICK: Duration = Duration::from_millis(100);
+/// How long an indicator stays active after a passing error
+const HOLD: Duration = Duration::from_secs(1);
+const PAT_PID: u16 = 0;
+const PAT_TABLE_ID: u8 = 0x00;
+const PMT_TABLE_ID: u8 = 0x02;
+const NULL_PID: u16 = 0x1fff;
+
+/// How long the tables and the PIDs may be missing
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Thresholds {
+    pub pat_interval: Duration,
+    pub pmt_interval: Duration,
+    /// For the PIDs the PMTs refer to
+    pub pid_timeout: Duration,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            pat_interval: Duration::from_millis(500),
+            pmt_interval: Duration::from_millis(500),
+            pid_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Check {
+    /// 1.1, more than a packet lost to resync
+    TsSyncLoss,
+    /// 1.2, a packet without its sync byte
+    SyncByteError,
+    /// 1.3
+    PatError,
+    /// 1.5
+    PmtError,
+    /// 1.6
+    PidError,
+}
+
+const CHECKS: [Check; 5] = [Check::TsSyncLoss, Check::SyncByteError, Check::PatError, Check::PmtError, Check::PidError];
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Check::TsSyncLoss => "ts_sync_loss",
+            Check::SyncByteError => "sync_byte_error",
+            Check::PatError => "pat_error",
+            Check::PmtError => "pmt_error",
+            Check::PidError => "pid_error",
+        })
+    }
+}
+
+/// State and count of a check
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Indicator {
+    pub active: bool,
+    pub errors: u64,
+    /// Last passing error, keeping it active for `HOLD`
+    last: Option<Instant>,
+}
+
+impl Indicator {
+    fn error(&mut self, now: Instant) {
+        self.errors += 1;
+        self.last = Some(now);
+    }
+}
+
+/// When a table or a PID was last seen
+#[derive(Clone, Copy)]
+struct Timer {
+    seen: Instant,
+    /// Last time an error was counted, one for every interval missed
+    counted: Instant,
+}
+
+impl Timer {
+    fn new(now: Instant) -> Self {
+        Timer { seen: now, counted: now }
+    }
+
+    fn seen(&mut self, now: Instant) {
+        *self = Timer::new(now);
+    }
+
+    /// Whether it is overdue, counting an error on `indicator` for every interval missed
+    fn check(&mut self, now: Instant, interval: Duration, indicator: &mut Indicator) -> bool {
+        if now.duration_since(self.counted) > interval {
+            indicator.errors += 1;
+            self.counted = now;
+        }
+        now.duration_since(self.seen) > interval
+    }
+}
+
+#[derive(Default)]
+struct State {
+    indicators: [Indicator; 5],
+    /// Started along with the stream
+    pat: Option<Timer>,
+    pmts: HashMap<u16, Timer>,
+    pids: HashMap<u16, Timer>,
+    /// Tables the PMT and referenced PIDs were taken from
+    generation: Option<u64>,
+    /// Sync byte errors and sync losses of the producer counted so far
+    sync: (u64, u64),
+}
+
+impl State {
+    fn indicator(&mut self, check: Check) -> &mut Indicator {
+        &mut self.indicators[check as usize]
+    }
+
+    /// Follow the PMTs and PIDs of `programs`, the new ones from `now`
+    fn follow(&mut self, programs: &[Program], now: Instant) {
+        let pmts: Vec<u16> = programs.iter().map(|p| p.pmt_pid).collect();
+        let pids: Vec<u16> = programs.iter()
+            .flat_map(|p| p.pcr_pid.into_iter().chain(p.streams.iter().map(|s| s.pid)))
+            .filter(|&pid| pid != NULL_PID)
+            .collect();
+
+        self.pmts.retain(|pid, _| pmts.contains(pid));
+        self.pids.retain(|pid, _| pids.contains(pid));
+        for pid in pmts {
+            self.pmts.entry(pid).or_insert_with(|| Timer::new(now));
+        }
+        for pid in pids {
+            self.pids.entry(pid).or_insert_with(|| Timer::new(now));
+        }
+    }
+
+    /// A packet of the PAT or of a PMT, an error if scrambled or, for the PAT,
+    /// if it starts another table
+    fn table(&mut self, pkt: &[u8], table_id: u8, now: Instant) {
+        let check = if table_id == PAT_TABLE_ID { Check::PatError } else { Check::PmtError };
+
+        if pkt[3] & 0xc0 != 0 {
+            self.indicator(check).error(now);
+            return;
+        }
+        if pkt[1] & 0x40 == 0 {
+            return;
+        }
+
+        let payload = psi::payload(pkt).unwrap_or_default();
+        let found = payload.first().and_then(|&pointer| payload.get(1 + usize::from(pointer)));
+        match found {
+            Some(&id) if id == table_id => {
+                let timer = if check == Check::PatError { self.pat.as_mut() } else { self.pmts.get_mut(&ts::pid(pkt)) };
+                if let Some(timer) = timer {
+                    timer.seen(now);
+                }
+            }
+            // Only the PAT may not share its PID
+            Some(_) if check == Check::PatError => self.indicator(check).error(now),
+            _ => (),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Monitor {
+    state: Mutex<State>,
+}
+
+impl Monitor {
+    /// Look at the packets of an aligned chunk, following the programs of the
+    /// stream whenever the tables change
+    pub fn inspect<F>(&self, chunk: &[u8], generation: u64, programs: F)
+        where F: FnOnce() -> Vec<Program>
+    {
+        self.inspect_at(chunk, generation, programs, Instant::now());
+    }
+
+    fn inspect_at<F>(&self, chunk: &[u8], generation: u64, programs: F, now: Instant)
+        where F: FnOnce() -> Vec<Program>
+    {
+        if !ts::is_aligned(chunk) {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.generation != Some(generation) {
+            state.follow(&programs(), now);
+            state.generation = Some(generation);
+        }
+        state.pat.get_or_insert_with(|| Timer::new(now));
+
+        for pkt in chunk.chunks(ts::PACKET_SIZE) {
+            // Nothing in the header can be trusted
+            if pkt[1] & 0x80 != 0 {
+                continue;
+            }
+
+            let pid = ts::pid(pkt);
+            if let Some(timer) = state.pids.get_mut(&pid) {
+                timer.seen(now);
+            }
+            if pid == PAT_PID {
+                state.table(pkt, PAT_TABLE_ID, now);
+            } else if state.pmts.contains_key(&pid) {
+                state.table(pkt, PMT_TABLE_ID, now);
+            }
+        }
+    }
+
+    /// A new stream starts, the timers with it
+    pub fn restart(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.pat = None;
+        state.pmts.clear();
+        state.pids.clear();
+        state.generation = None;
+        state.sync = (0, 0);
+    }
+
+    /// Check the timers and the sync errors of `producer`, returning the
+    /// indicators changing state
+    fn tick(&self, now: Instant, thresholds: &Thresholds, producer: Option<&PeerStats>) -> Vec<(Check, bool)> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let mut overdue = [false; 5];
+
+        // Nothing is overdue without a producer
+        if let Some(producer) = producer {
+            let sync = producer.sync_errors();
+            // Another producer, counting from 0
+            if sync.0 < state.sync.0 || sync.1 < state.sync.1 {
+                state.sync = (0, 0);
+            }
+            for _ in state.sync.0..sync.0 {
+                state.indicators[Check::SyncByteError as usize].error(now);
+            }
+            for _ in state.sync.1..sync.1 {
+                state.indicators[Check::TsSyncLoss as usize].error(now);
+            }
+            state.sync = sync;
+
+            if let Some(ref mut pat) = state.pat {
+                overdue[Check::PatError as usize] = pat.check(now, thresholds.pat_interval, &mut state.indicators[Check::PatError as usize]);
+            }
+            for timer in state.pmts.values_mut() {
+                overdue[Check::PmtError as usize] |= timer.check(now, thresholds.pmt_interval, &mut state.indicators[Check::PmtError as usize]);
+            }
+            for timer in state.pids.values_mut() {
+                overdue[Check::PidError as usize] |= timer.check(now, thresholds.pid_timeout, &mut state.indicators[Check::PidError as usize]);
+            }
+        }
+
+        CHECKS.iter().filter_map(|&check| {
+            let indicator = &mut state.indicators[check as usize];
+            let active = overdue[check as usize] || indicator.last.is_some_and(|last| now.duration_since(last) < HOLD);
+            if active == indicator.active {
+                return None;
+            }
+            indicator.active = active;
+            Some((check, active))
+        }).collect()
+    }
+
+    /// Every indicator, in the order of the checks
+    pub fn indicators(&self) -> Vec<(Check, Indicator)> {
+        let state = self.state.lock().unwrap();
+        CHECKS.iter().map(|&check| (check, state.indicators[check as usize])).collect()
+    }
+}
+
+/// Check the timers of the stream of `state` until it is gone, with the
+/// thresholds of its current settings
+pub async fn watch(state: Weak<Mutex<Shared>>) {
+    let mut ticks = crate::interval(TICK);
+    loop {
+        let now = ticks.tick().await.into_std();
+        let (stats, thresholds) = match state.upgrade() {
+            Some(state) => {
+                let state = state.lock().unwrap();
+                (state.stats.clone(), state.settings.thresholds)
+            }
+            None => return,
+        };
+
+        let producer = stats.producer();
+        for (check, active) in stats.tr101290.tick(now, &thresholds, producer.as_deref()) {
+            if active {
+                warn!("TR 101 290 {} raised", check);
+            } else {
+                info!("TR 101 290 {} cleared", check);
+            }
+            stats.events.emit(Event::Tr101290 { check, active });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psi::Stream;
+
+    fn packet(pid: u16, start: Option<u8>) -> Vec<u8> {
+        let mut pkt = ts::tests::packet(pid, 0x10);
+        if let Some(table_id) = start {
+            pkt[1] |= 0x40;
+            pkt[4] = 0;
+            pkt[5] = table_id;
+        }
+        pkt
+    }
+
+    fn program() -> Vec<Program> {
+        vec![Program { number: 1, pmt_pid: 0x1000, pcr_pid: Some(0x100), streams: vec![Stream { pid: 0x100, stream_type: 0x1b }] }]
+    }
+
+    #[test]
+    fn overdue_tables_and_pids() {
+        let monitor = Monitor::default();
+        let producer = PeerStats::new("127.0.0.1:1".parse().unwrap());
+        let thresholds = Thresholds::default();
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let tick = |ms: u64| monitor.tick(at(ms), &thresholds, Some(&producer));
+
+        let chunk = [packet(PAT_PID, Some(PAT_TABLE_ID)), packet(0x1000, Some(PMT_TABLE_ID)), packet(0x100, None)].concat();
+        monitor.inspect_at(&chunk, 1, program, start);
+        assert!(tick(100).is_empty());
+
+        // The tables stop, the PID goes on for a while
+        assert_eq!(tick(700), vec![(Check::PatError, true), (Check::PmtError, true)]);
+        assert!(tick(1300).is_empty());
+        let indicators = monitor.indicators();
+        assert_eq!((indicators[2].1.errors, indicators[3].1.errors), (2, 2));
+        assert_eq!(tick(5200), vec![(Check::PidError, true)]);
+
+        // Back, with a stray table on the PAT PID
+        monitor.inspect_at(&[chunk.clone(), packet(PAT_PID, Some(0x42))].concat(), 1, program, at(5200));
+        assert_eq!(tick(5200), vec![(Check::PmtError, false), (Check::PidError, false)]);
+        assert!(monitor.indicators()[2].1.active);
+
+        // Nothing overdue without a producer
+        assert_eq!(monitor.tick(at(60_000), &thresholds, None), vec![(Check::PatError, false)]);
+    }
+
+    #[test]
+    fn sync_errors_held() {
+        let monitor = Monitor::default();
+        let producer = PeerStats::new("127.0.0.1:1".parse().unwrap());
+        let thresholds = Thresholds::default();
+        let now = Instant::now();
+
+        producer.lost_sync(ts::PACKET_SIZE);
+        producer.lost_sync(3 * ts::PACKET_SIZE);
+        assert_eq!(monitor.tick(now, &thresholds, Some(&producer)), vec![(Check::TsSyncLoss, true), (Check::SyncByteError, true)]);
+        assert!(monitor.tick(now + HOLD / 2, &thresholds, Some(&producer)).is_empty());
+        assert_eq!(monitor.tick(now + HOLD, &thresholds, Some(&producer)), vec![(Check::TsSyncLoss, false), (Check::SyncByteError, false)]);
+
+        let indicators = monitor.indicators();
+        assert_eq!((indicators[0].1.errors, indicators[1].1.errors), (1, 2));
+    }
+}
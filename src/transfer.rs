@@ -0,0 +1,217 @@
+//! One-shot copies between a TCP peer and a file, for `restream record` and `restream play`
+//!
+//! The connection is made once, the way the pull and the push make theirs,
+//! and the file is written or read whole packets at a time, so a copy cut
+//! short still ends on a packet boundary.
+
+use tokio::io::AsyncWrite;
+use tokio::net::TcpStream;
+use tokio::time::{self, Sleep};
+use tokio_util::io::poll_read_buf;
+use bytes::{Buf, BytesMut};
+use log::info;
+
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use crate::net::{self, LocalBind};
+use crate::normalize::Normalizer;
+use crate::play::{self, Pacer};
+use crate::ts;
+
+/// Given up on a peer not answering
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Read from the peer or the file at once
+const CHUNK_SIZE: usize = 64 * ts::PACKET_SIZE;
+
+/// How a copy ended
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ending {
+    /// All of it was copied, the whole duration or the whole file
+    Complete,
+    /// Stopped before, e.g. on Ctrl-C
+    Stopped,
+    /// The peer closed the connection first
+    Early,
+}
+
+/// What a copy did
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Copied {
+    pub bytes: u64,
+    pub ending: Ending,
+}
+
+async fn connect(addr: SocketAddr) -> io::Result<TcpStream> {
+    net::connect(&addr, Some(CONNECT_TIMEOUT), &LocalBind::default()).await
+        .map_err(|e| io::Error::new(e.kind(), format!("cannot connect to {:?}: {}", addr, e)))
+}
+
+/// Whether `stop` resolved
+fn stopped<S: Future + Unpin>(stop: &mut S, cx: &mut Context<'_>) -> bool {
+    Pin::new(stop).poll(cx).is_ready()
+}
+
+/// Write what the peer sends to the file, as 188-byte packets
+struct Recording<S> {
+    socket: TcpStream,
+    file: File,
+    normalizer: Normalizer,
+    /// Normalized, the trailing partial packet waits for the rest
+    packets: BytesMut,
+    bytes: u64,
+    deadline: Option<Pin<Box<Sleep>>>,
+    stop: S,
+}
+
+impl<S> Recording<S> {
+    fn end(&mut self, ending: Ending) -> Poll<io::Result<Copied>> {
+        Poll::Ready(self.file.sync_all().map(|()| Copied { bytes: self.bytes, ending }))
+    }
+}
+
+impl<S: Future + Unpin> Future for Recording<S> {
+    type Output = io::Result<Copied>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<Copied>> {
+        let this = &mut *self;
+
+        if stopped(&mut this.stop, cx) {
+            return this.end(Ending::Stopped);
+        }
+        if let Some(ref mut deadline) = this.deadline {
+            if deadline.as_mut().poll(cx).is_ready() {
+                return this.end(Ending::Complete);
+            }
+        }
+
+        loop {
+            let raw = this.normalizer.raw();
+            raw.reserve(CHUNK_SIZE);
+            if ready!(poll_read_buf(Pin::new(&mut this.socket), cx, raw))? == 0 {
+                let ending = if this.deadline.is_some() { Ending::Early } else { Ending::Complete };
+                return this.end(ending);
+            }
+
+            this.normalizer.normalize(&mut this.packets);
+            let len = this.packets.len() - this.packets.len() % ts::PACKET_SIZE;
+            this.file.write_all(&this.packets.split_to(len))?;
+            this.bytes += len as u64;
+        }
+    }
+}
+
+/// Record what `addr` sends to `path` for `duration`, or until it closes the connection
+///
+/// The file is created, or truncated if it exists, right away. The recording
+/// ends early once `stop` resolves.
+pub fn record<S>(addr: SocketAddr, path: &Path, duration: Option<Duration>, stop: S) -> io::Result<impl Future<Output = io::Result<Copied>>>
+where
+    S: Future + Unpin,
+{
+    let file = File::create(path).map_err(|e| io::Error::new(e.kind(), format!("cannot create {}: {}", path.display(), e)))?;
+
+    Ok(async move {
+        let socket = connect(addr).await?;
+        info!("Recording {:?}", addr);
+
+        Recording {
+            socket,
+            file,
+            normalizer: Normalizer::new(None, BytesMut::new()),
+            packets: BytesMut::new(),
+            bytes: 0,
+            deadline: duration.map(|duration| Box::pin(time::sleep(duration))),
+            stop,
+        }.await
+    })
+}
+
+/// Send the file to the peer, once
+struct Playback<S> {
+    file: File,
+    socket: TcpStream,
+    pacer: Option<Pacer>,
+    delay: Pin<Box<Sleep>>,
+    /// What is left to send of the chunk read
+    chunk: BytesMut,
+    bytes: u64,
+    stop: S,
+}
+
+impl<S: Future + Unpin> Future for Playback<S> {
+    type Output = io::Result<Copied>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<Copied>> {
+        let this = &mut *self;
+
+        if stopped(&mut this.stop, cx) {
+            return Poll::Ready(Ok(Copied { bytes: this.bytes, ending: Ending::Stopped }));
+        }
+
+        loop {
+            if this.chunk.is_empty() {
+                let mut buf = vec![0; CHUNK_SIZE];
+                let len = play::read_packets(&mut this.file, &mut buf)?;
+                if len == 0 {
+                    ready!(Pin::new(&mut this.socket).poll_flush(cx))?;
+                    return Poll::Ready(Ok(Copied { bytes: this.bytes, ending: Ending::Complete }));
+                }
+                buf.truncate(len);
+
+                if let Some(ref mut pacer) = this.pacer {
+                    this.delay.as_mut().reset(time::Instant::from_std(pacer.next(&buf)));
+                }
+                this.chunk = BytesMut::from(&buf[..]);
+            }
+
+            ready!(this.delay.as_mut().poll(cx));
+
+            while !this.chunk.is_empty() {
+                let n = match ready!(Pin::new(&mut this.socket).poll_write(cx, &this.chunk)) {
+                    Ok(n) => n,
+                    Err(ref e) if net::is_disconnect(e) => 0,
+                    Err(e) => return Poll::Ready(Err(e)),
+                };
+                if n == 0 {
+                    return Poll::Ready(Ok(Copied { bytes: this.bytes, ending: Ending::Early }));
+                }
+                this.chunk.advance(n);
+                this.bytes += n as u64;
+            }
+        }
+    }
+}
+
+/// Send the whole packets of `path` to `addr`, following its PCR if `realtime`
+///
+/// The file is opened right away. The playback ends early once `stop`
+/// resolves.
+pub fn play<S>(path: &Path, addr: SocketAddr, realtime: bool, stop: S) -> io::Result<impl Future<Output = io::Result<Copied>>>
+where
+    S: Future + Unpin,
+{
+    let file = File::open(path).map_err(|e| io::Error::new(e.kind(), format!("cannot open {}: {}", path.display(), e)))?;
+    let name = path.display().to_string();
+
+    Ok(async move {
+        let socket = connect(addr).await?;
+        info!("Playing {} to {:?}", name, addr);
+
+        Playback {
+            file,
+            socket,
+            pacer: if realtime { Some(Pacer::new(None)) } else { None },
+            delay: Box::pin(time::sleep_until(time::Instant::now())),
+            chunk: BytesMut::new(),
+            bytes: 0,
+            stop,
+        }.await
+    })
+}
@@ -0,0 +1,107 @@
+//! MPEG-TS helpers
+
+/// Size of a transport stream packet
+pub const PACKET_SIZE: usize = 188;
+/// Every transport stream packet starts with this byte
+pub const SYNC_BYTE: u8 = 0x47;
+
+/// Find the first plausible packet start in `buf`.
+///
+/// A sync byte is trusted if the byte one packet later is a sync byte as well,
+/// or if there is not enough data yet to tell.
+pub fn sync_offset(buf: &[u8]) -> Option<usize> {
+    (0..buf.len()).find(|&i| {
+        buf[i] == SYNC_BYTE && buf.get(i + PACKET_SIZE).is_none_or(|&b| b == SYNC_BYTE)
+    })
+}
+
+/// Length of the run of whole, in-sync packets at the start of `buf`,
+/// considering at most `max` bytes.
+pub fn aligned_len(buf: &[u8], max: usize) -> usize {
+    buf.chunks(PACKET_SIZE)
+        .take(max / PACKET_SIZE)
+        .take_while(|pkt| pkt.len() == PACKET_SIZE && pkt[0] == SYNC_BYTE)
+        .count() * PACKET_SIZE
+}
+
+/// Whether `chunk` is made of whole packets only
+pub fn is_aligned(chunk: &[u8]) -> bool {
+    chunk.len().is_multiple_of(PACKET_SIZE) && chunk.chunks(PACKET_SIZE).all(|pkt| pkt[0] == SYNC_BYTE)
+}
+
+/// PID of a packet
+pub fn pid(pkt: &[u8]) -> u16 {
+    (u16::from(pkt[1] & 0x1f) << 8) | u16::from(pkt[2])
+}
+
+/// Whether the adaptation field flags a random access point, e.g. a keyframe
+pub fn random_access(pkt: &[u8]) -> bool {
+    pkt[3] & 0x20 != 0 && pkt[4] > 0 && pkt[5] & 0x40 != 0
+}
+
+/// Set the discontinuity indicator, returns false if the packet has no adaptation field to carry it
+pub fn set_discontinuity(pkt: &mut [u8]) -> bool {
+    if pkt[3] & 0x20 == 0 || pkt[4] == 0 {
+        return false;
+    }
+    pkt[5] |= 0x80;
+    true
+}
+
+/// Round `size` down to a whole number of packets, at least one.
+pub fn chunk_size(size: usize) -> usize {
+    (size / PACKET_SIZE).max(1) * PACKET_SIZE
+}
+
+/// Program Clock Reference frequency
+pub const PCR_HZ: u64 = 27_000_000;
+/// The PCR wraps around past this value
+pub const PCR_WRAP: u64 = (1 << 33) * 300;
+
+/// Time from the PCR `last` to `pcr`, in `PCR_HZ` units, across the wrap around
+pub fn pcr_step(last: u64, pcr: u64) -> u64 {
+    (pcr + PCR_WRAP - last) % PCR_WRAP
+}
+
+/// PID and PCR, in `PCR_HZ` units, of the packet if it carries one
+pub fn pcr(pkt: &[u8]) -> Option<(u16, u64)> {
+    if pkt.len() < 12 || pkt[0] != SYNC_BYTE {
+        return None;
+    }
+
+    let pid = pid(pkt);
+    let adaptation = pkt[3] & 0x20 != 0;
+
+    if !adaptation || pkt[4] < 7 || pkt[5] & 0x10 == 0 {
+        return None;
+    }
+
+    let base = (u64::from(pkt[6]) << 25) | (u64::from(pkt[7]) << 17) | (u64::from(pkt[8]) << 9)
+        | (u64::from(pkt[9]) << 1) | (u64::from(pkt[10]) >> 7);
+    let ext = (u64::from(pkt[10] & 1) << 8) | u64::from(pkt[11]);
+
+    Some((pid, base * 300 + ext))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// A packet of `pid` carrying `pcr`, in `PCR_HZ` units, and stuffing as its payload
+    pub fn pcr_packet(pid: u16, pcr: u64) -> Vec<u8> {
+        let (base, ext) = (pcr / 300, pcr % 300);
+        let mut pkt = vec![0xff; PACKET_SIZE];
+        pkt[..12].copy_from_slice(&[SYNC_BYTE, (pid >> 8) as u8, pid as u8, 0x30, 7, 0x10,
+                                    (base >> 25) as u8, (base >> 17) as u8, (base >> 9) as u8, (base >> 1) as u8,
+                                    ((base & 1) << 7) as u8 | 0x7e | (ext >> 8) as u8, ext as u8]);
+        pkt
+    }
+
+    /// A packet of `pid` with `flags` as its fourth byte, the scrambling and
+    /// adaptation field control and the continuity counter, and stuffing after
+    pub fn packet(pid: u16, flags: u8) -> Vec<u8> {
+        let mut pkt = vec![0xff; PACKET_SIZE];
+        pkt[..4].copy_from_slice(&[SYNC_BYTE, (pid >> 8) as u8, pid as u8, flags]);
+        pkt
+    }
+}
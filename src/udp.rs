@@ -0,0 +1,565 @@
+//! UDP producer input and outputs
+
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::time::{self, Sleep};
+use futures::prelude::*;
+use bytes::{Buf, Bytes, BytesMut};
+use log::{debug, error, info, warn};
+
+use std::fmt;
+use std::io;
+use std::net::{self as std_net, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::{OneShotSharedRx, OneShotTx, Rx, Shared};
+use crate::cbr::{self, Cbr};
+use crate::fanout::Fanout;
+use crate::merge::Merger;
+use crate::net::{self, LocalBind};
+use crate::pace::PcrPacer;
+use crate::rtp::{self, RtpReceiver, RtpState};
+#[cfg(feature = "rist")]
+use crate::rist::Rist;
+use crate::queue::{self, Overflow};
+use crate::events::Reason;
+use crate::stats::{CbrStats, LegStats, PeerStats, RtpStats};
+
+/// Largest datagram we can receive
+const MAX_DATAGRAM: usize = 65536;
+
+/// How often the reception statistics are logged
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Multicast group joined by the input socket
+pub enum Group {
+    V4(Ipv4Addr, Ipv4Addr),
+    V6(Ipv6Addr, u32),
+}
+
+impl Group {
+    fn leave(&self, socket: &UdpSocket) -> io::Result<()> {
+        match *self {
+            Group::V4(group, iface) => socket.leave_multicast_v4(group, iface),
+            Group::V6(ref group, iface) => socket.leave_multicast_v6(group, iface),
+        }
+    }
+}
+
+/// Register a bound socket with the reactor of the current runtime
+pub fn from_std(socket: std_net::UdpSocket) -> io::Result<UdpSocket> {
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+}
+
+/// Bind the input socket, joining the multicast group if `addr` is one.
+///
+/// The interface is an IPv4 address for IPv4 groups and an interface index for IPv6 ones.
+pub fn bind(addr: &SocketAddr, iface: Option<&str>) -> io::Result<(UdpSocket, Option<Group>)> {
+    let invalid = |e| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid interface {}", e));
+
+    match addr.ip() {
+        IpAddr::V4(group) if group.is_multicast() => {
+            let iface = match iface {
+                Some(iface) => iface.parse().map_err(|_| invalid(iface))?,
+                None => Ipv4Addr::UNSPECIFIED,
+            };
+            let socket = std_net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, addr.port()))?;
+            socket.join_multicast_v4(&group, &iface)?;
+            info!("Joined multicast group {} on {}", group, iface);
+            Ok((from_std(socket)?, Some(Group::V4(group, iface))))
+        }
+        IpAddr::V6(group) if group.is_multicast() => {
+            let iface = match iface {
+                Some(iface) => iface.parse().map_err(|_| invalid(iface))?,
+                None => 0,
+            };
+            let socket = std_net::UdpSocket::bind((Ipv6Addr::UNSPECIFIED, addr.port()))?;
+            socket.join_multicast_v6(&group, iface)?;
+            info!("Joined multicast group {} on interface {}", group, iface);
+            Ok((from_std(socket)?, Some(Group::V6(group, iface))))
+        }
+        _ => Ok((from_std(std_net::UdpSocket::bind(addr)?)?, None)),
+    }
+}
+
+/// The producer is alive as long as datagrams keep coming
+struct Session {
+    addr: SocketAddr,
+    _tx: OneShotTx,
+    datagrams: u64,
+    stats: Arc<PeerStats>,
+    reported: Instant,
+    /// The RTP headers stripped and their counters, without a backup path
+    rtp: Option<(RtpReceiver, Arc<RtpStats>)>,
+    merger: Option<Merger>,
+}
+
+impl Session {
+    fn stats(&self) -> String {
+        let mut stats = format!("{} datagrams ({} bytes) received", self.datagrams, self.stats.bytes());
+        if let Some((ref rtp, _)) = self.rtp {
+            stats += &format!(", {} RTP packets lost, {} non-RTP datagrams", rtp.lost, rtp.raw);
+        }
+        if let Some(ref merger) = self.merger {
+            stats += &format!(", {} packets lost on both paths", merger.lost);
+        }
+        stats
+    }
+}
+
+/// The second path of a producer received twice
+struct Backup {
+    socket: UdpSocket,
+    group: Option<Group>,
+    /// Where each path is received
+    legs: [SocketAddr; 2],
+    window: usize,
+}
+
+/// Forward every datagram received as a packet
+pub struct UdpProducer<F> {
+    socket: UdpSocket,
+    group: Option<Group>,
+    state: Arc<Mutex<Shared>>,
+    fanout: Arc<Fanout>,
+    timeout: Duration,
+    idle: Pin<Box<Sleep>>,
+    session: Option<Session>,
+    on_session: F,
+    buf: Vec<u8>,
+    rtp: bool,
+    backup: Option<Backup>,
+    /// The packets put back in order by the merger
+    merged: Vec<Bytes>,
+}
+
+impl<F> UdpProducer<F>
+where
+    F: FnMut(OneShotSharedRx),
+{
+    /// Call `on_session` every time a producer starts sending
+    pub fn new(socket: UdpSocket, group: Option<Group>, state: Arc<Mutex<Shared>>, timeout: Duration, on_session: F) -> Self {
+        let fanout = state.lock().unwrap().fanout.clone();
+
+        UdpProducer {
+            socket,
+            group,
+            state,
+            fanout,
+            timeout,
+            idle: Box::pin(time::sleep(timeout)),
+            session: None,
+            on_session,
+            buf: vec![0; MAX_DATAGRAM],
+            rtp: false,
+            backup: None,
+            merged: Vec::new(),
+        }
+    }
+
+    /// Strip the RTP header from the datagrams
+    pub fn rtp(mut self, rtp: bool) -> Self {
+        self.rtp = rtp;
+        self
+    }
+
+    /// Receive the same RTP stream on a second socket, `legs` are the addresses of both
+    ///
+    /// The packets go out once each, in the order of their sequence numbers,
+    /// waiting for the other path for up to `window` packets.
+    pub fn backup(mut self, socket: UdpSocket, group: Option<Group>, legs: [SocketAddr; 2], window: usize) -> Self {
+        self.backup = Some(Backup { socket, group, legs, window });
+        self
+    }
+
+    fn start_session(&mut self, addr: SocketAddr) {
+        let (tx, rx) = oneshot::channel::<()>();
+
+        info!("Adding UDP Producer ({:?})", addr);
+
+        let mut stats = PeerStats::new(addr);
+        let rtp = if self.rtp && self.backup.is_none() { Some(Arc::new(RtpStats::default())) } else { None };
+        stats.rtp = rtp.clone();
+        let merger = self.backup.as_ref().map(|backup| {
+            stats.legs = backup.legs.iter().map(|&leg| Arc::new(LegStats::new(leg))).collect();
+            Merger::new(backup.window, stats.legs.clone())
+        });
+        let stats = Arc::new(stats);
+        self.state.lock().unwrap().stats.set_producer(stats.clone());
+
+        self.session = Some(Session {
+            addr,
+            _tx: tx,
+            datagrams: 0,
+            stats,
+            reported: Instant::now(),
+            rtp: rtp.map(|stats| (RtpReceiver::default(), stats)),
+            merger,
+        });
+
+        (self.on_session)(rx.shared());
+    }
+
+    /// Forward the datagram of `n` bytes received on the path `leg`
+    fn received(&mut self, leg: usize, n: usize, addr: SocketAddr) {
+        if self.session.is_none() {
+            self.start_session(addr);
+        }
+
+        let mut payload = &self.buf[..n];
+
+        if let Some(ref mut session) = self.session {
+            session.datagrams += 1;
+            session.stats.add_bytes(n as u64);
+
+            if let Some((ref mut rtp, ref stats)) = session.rtp {
+                payload = rtp.strip_header(payload);
+                stats.lost.store(rtp.lost, Ordering::Relaxed);
+                stats.raw.store(rtp.raw, Ordering::Relaxed);
+            }
+
+            if session.reported.elapsed() >= REPORT_INTERVAL {
+                debug!("UDP Producer ({:?}): {}", session.addr, session.stats());
+                session.reported = Instant::now();
+            }
+        }
+
+        self.idle.as_mut().reset(time::Instant::now() + self.timeout);
+
+        match self.session.as_mut().and_then(|session| session.merger.as_mut()) {
+            Some(merger) => {
+                merger.receive(leg, payload, &mut self.merged);
+                for packet in self.merged.drain(..) {
+                    self.fanout.broadcast(&packet);
+                }
+            }
+            None => self.fanout.broadcast(&Bytes::copy_from_slice(payload)),
+        }
+    }
+}
+
+impl<F> Future for UdpProducer<F>
+where
+    F: FnMut(OneShotSharedRx) + Unpin,
+{
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        loop {
+            let mut buf = ReadBuf::new(&mut this.buf);
+            match this.socket.poll_recv_from(cx, &mut buf)? {
+                Poll::Ready(addr) => {
+                    let n = buf.filled().len();
+                    this.received(0, n, addr);
+                }
+                Poll::Pending => break,
+            }
+        }
+
+        while let Some(ref mut backup) = this.backup {
+            let mut buf = ReadBuf::new(&mut this.buf);
+            match backup.socket.poll_recv_from(cx, &mut buf)? {
+                Poll::Ready(addr) => {
+                    let n = buf.filled().len();
+                    this.received(1, n, addr);
+                }
+                Poll::Pending => break,
+            }
+        }
+
+        if this.session.is_some() && this.idle.as_mut().poll(cx).is_ready() {
+            if let Some(session) = this.session.take() {
+                info!("Dropping UDP Producer ({:?}), idle for {:?}, {}",
+                          session.addr, this.timeout, session.stats());
+                let mut state = this.state.lock().unwrap();
+                state.end_session();
+                state.stats.remove_producer(&session.addr, Reason::Idle);
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+impl<F> Drop for UdpProducer<F> {
+    fn drop(&mut self) {
+        if let Some(ref group) = self.group {
+            if let Err(e) = group.leave(&self.socket) {
+                error!("Cannot leave the multicast group: {}", e);
+            }
+        }
+        if let Some(Backup { ref socket, group: Some(ref group), .. }) = self.backup {
+            if let Err(e) = group.leave(socket) {
+                error!("Cannot leave the multicast group of the backup input: {}", e);
+            }
+        }
+    }
+}
+
+/// UDP output destination, `ADDR:PORT`, `udp://ADDR:PORT` or `rtp://ADDR:PORT`
+#[derive(Clone, Debug)]
+pub struct UdpTarget {
+    pub addr: SocketAddr,
+    pub rtp: bool,
+    /// Local end of the output, from the `?local=IP&device=NAME` query
+    pub bind: LocalBind,
+    /// Constant bitrate to pad the output to, from the `?cbr=BITRATE` query
+    pub cbr: Option<u64>,
+}
+
+impl FromStr for UdpTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (s, cbr) = cbr::split_cbr(s)?;
+        let (s, bind) = LocalBind::split(&s)?;
+        let (rtp, addr) = if let Some(addr) = s.strip_prefix("rtp://") {
+            (true, addr)
+        } else {
+            (false, s.strip_prefix("udp://").unwrap_or(s))
+        };
+
+        addr.parse()
+            .map(|addr| UdpTarget { addr, rtp, bind, cbr })
+            .map_err(|e| format!("Invalid address {}: {}", addr, e))
+    }
+}
+
+impl fmt::Display for UdpTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scheme = if self.rtp { "rtp" } else { "udp" };
+        write!(f, "{}://{}", scheme, self.addr)
+    }
+}
+
+/// Push the stream to a fixed destination, one datagram per `datagram_size` bytes
+pub struct UdpOutput {
+    socket: UdpSocket,
+    target: SocketAddr,
+    state: Arc<Mutex<Shared>>,
+    rx: Rx,
+    datagram_size: usize,
+    buf: BytesMut,
+    rtp: Option<RtpState>,
+    datagram: Vec<u8>,
+    errors: u64,
+    stats: Arc<PeerStats>,
+    pacer: Option<PcrPacer>,
+    /// Fires when the datagram prepared is due
+    delay: Pin<Box<Sleep>>,
+    waiting: bool,
+    /// Pads the stream to a constant bitrate, rather than pacing it on the PCR
+    cbr: Option<Cbr>,
+    #[cfg(feature = "rist")]
+    rist: Option<Rist>,
+}
+
+impl UdpOutput {
+    /// Register the output as a consumer, applying the `ttl` if the target is multicast.
+    ///
+    /// The datagrams are wrapped in RTP if `rtp` is set, and paced on the PCR,
+    /// holding them back up to `pace`, if set.
+    pub fn new(target: SocketAddr, bind: &LocalBind, state: Arc<Mutex<Shared>>, datagram_size: usize, ttl: Option<u32>, rtp: Option<RtpState>,
+               pace: Option<Duration>) -> io::Result<Self> {
+        UdpOutput::with_stats(target, bind, state, datagram_size, ttl, rtp, pace, PeerStats::new(target))
+    }
+
+    /// Register the output as `new` does, with its counters prepared already
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_stats(target: SocketAddr, bind: &LocalBind, state: Arc<Mutex<Shared>>, datagram_size: usize, ttl: Option<u32>, rtp: Option<RtpState>,
+                      pace: Option<Duration>, stats: PeerStats) -> io::Result<Self> {
+        let socket = net::udp_socket(&target, bind).map_err(|e| bind.error(e, &format!("the UDP output to {}", target)))?;
+        let socket = from_std(socket)?;
+
+        if let Some(ttl) = ttl {
+            match target.ip() {
+                IpAddr::V4(ip) if ip.is_multicast() => socket.set_multicast_ttl_v4(ttl)?,
+                IpAddr::V6(ip) if ip.is_multicast() => {
+                    warn!("Cannot set the ttl for {}, unsupported for IPv6", target)
+                }
+                _ => (),
+            }
+        }
+
+        let stats = Arc::new(stats);
+
+        let rx = {
+            let state = state.lock().unwrap();
+            // Never disconnect, whatever the policy for the consumers
+            let (tx, rx) = queue::bounded(state.settings.consumer_queue, Overflow::Drop, stats.dropped.clone());
+            state.fanout.insert(target, tx, 0);
+            state.stats.add_consumer(stats.clone());
+            rx
+        };
+
+        Ok(UdpOutput {
+            socket,
+            target,
+            state,
+            rx,
+            datagram_size,
+            buf: BytesMut::new(),
+            rtp,
+            datagram: Vec::with_capacity(rtp::HEADER_SIZE + datagram_size),
+            errors: 0,
+            stats,
+            pacer: pace.map(PcrPacer::new),
+            delay: Box::pin(time::sleep_until(time::Instant::now())),
+            waiting: false,
+            cbr: None,
+            #[cfg(feature = "rist")]
+            rist: None,
+        })
+    }
+
+    /// Mark the datagrams with `dscp`, sending them unmarked if the kernel refuses
+    pub fn dscp(self, dscp: Option<u8>) -> Self {
+        if let Some(dscp) = dscp {
+            match net::set_dscp(&self.socket, self.target.is_ipv6(), dscp) {
+                Ok(dscp) => self.stats.dscp.store(dscp, Ordering::Relaxed),
+                Err(e) => warn!("Cannot set DSCP {} on the output to {}: {}", dscp, self.target, e),
+            }
+        }
+        self
+    }
+
+    /// Pad the stream to `bitrate`, sending a datagram at a time on a timer
+    pub fn cbr(mut self, bitrate: Option<u64>) -> Self {
+        if let Some(bitrate) = bitrate {
+            let stats = Arc::new(CbrStats::new(bitrate));
+            let _ = self.stats.cbr.set(stats.clone());
+            self.cbr = Some(Cbr::new(self.target.to_string(), stats, self.datagram_size));
+            self.pacer = None;
+        }
+        self
+    }
+
+    /// Send the datagrams lost again on request, they must be RTP
+    #[cfg(feature = "rist")]
+    pub fn rist(mut self, rist: Rist) -> Self {
+        self.rist = Some(rist);
+        self
+    }
+
+    /// Prepare the next datagram, prefixing the RTP header if needed
+    fn fill_datagram(&mut self) {
+        let payload = &self.buf[..self.datagram_size];
+
+        self.datagram.clear();
+        if let Some(ref mut rtp) = self.rtp {
+            let mut header = [0u8; rtp::HEADER_SIZE];
+            let timestamp = rtp.timestamp();
+            rtp.write_header(&mut header, timestamp);
+            self.datagram.extend_from_slice(&header);
+        }
+        self.datagram.extend_from_slice(payload);
+
+        #[cfg(feature = "rist")]
+        {
+            if let Some(ref mut rist) = self.rist {
+                rist.sent(&self.datagram);
+            }
+        }
+    }
+
+    /// Send the complete datagrams buffered
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.datagram.is_empty() || self.buf.len() >= self.datagram_size {
+            if self.datagram.is_empty() {
+                let now = Instant::now();
+                let payload = &self.buf[..self.datagram_size];
+
+                if let Some(due) = self.pacer.as_mut().and_then(|pacer| pacer.due(now, payload)) {
+                    if due > now {
+                        self.delay.as_mut().reset(time::Instant::from_std(due));
+                        self.waiting = true;
+                    }
+                }
+
+                self.fill_datagram();
+                self.buf.advance(self.datagram_size);
+            }
+
+            if self.waiting {
+                ready!(self.delay.as_mut().poll(cx));
+                self.waiting = false;
+            }
+
+            match self.socket.poll_send_to(cx, &self.datagram, self.target) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(n)) => {
+                    self.stats.add_bytes(n as u64);
+                    if self.errors > 0 {
+                        info!("UDP Output ({:?}) recovered after {} errors", self.target, self.errors);
+                        self.errors = 0;
+                    }
+                }
+                Poll::Ready(Err(e)) => {
+                    // Transient errors (e.g. ICMP unreachable) must not take down the output
+                    if self.errors == 0 {
+                        error!("UDP Output ({:?}) send failed: {}", self.target, e);
+                    }
+                    self.errors += 1;
+                }
+            }
+
+            self.datagram.clear();
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Future for UdpOutput {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        #[cfg(feature = "rist")]
+        {
+            if let (Some(rist), Some(rtp)) = (this.rist.as_mut(), this.rtp.as_ref()) {
+                rist.poll(cx, &this.socket, &this.target, rtp);
+            }
+        }
+
+        loop {
+            ready!(this.poll_send(cx))?;
+
+            match this.rx.poll_next_unpin(cx) {
+                Poll::Ready(Some(packet)) => match this.cbr {
+                    Some(ref mut cbr) => cbr.push(&packet),
+                    None => this.buf.extend_from_slice(&packet),
+                },
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                // The padded datagrams go out on a timer of their own
+                Poll::Pending => match this.cbr {
+                    Some(ref mut cbr) => {
+                        let chunk = ready!(cbr.poll_due(cx));
+                        this.buf.extend_from_slice(&chunk);
+                    }
+                    None => return Poll::Pending,
+                },
+            }
+        }
+    }
+}
+
+impl Drop for UdpOutput {
+    fn drop(&mut self) {
+        let state = self.state.lock().unwrap();
+        state.fanout.remove(&self.target);
+        state.stats.remove_consumer(&self.target, Reason::Removed);
+
+        info!("Dropping UDP Output ({:?})", self.target);
+    }
+}
@@ -0,0 +1,180 @@
+//! Unix domain socket listeners for the local producers and consumers
+
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use tokio::net::unix::UCred;
+#[cfg(unix)]
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+#[cfg(unix)]
+use tokio::time;
+#[cfg(unix)]
+use socket2::SockRef;
+use log::debug;
+
+#[cfg(unix)]
+use std::fs::{self, Permissions};
+use std::io;
+#[cfg(unix)]
+use std::io::IoSlice;
+#[cfg(unix)]
+use std::net::{Shutdown, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::path::Path;
+#[cfg(unix)]
+use std::pin::Pin;
+#[cfg(unix)]
+use std::task::{Context, Poll};
+
+#[cfg(unix)]
+use crate::net::{self, PeerName, Socket};
+
+/// A peer connected to one of the sockets
+#[cfg(unix)]
+pub struct UnixPeer {
+    stream: UnixStream,
+    /// Placeholder the peer is tracked by, e.g. 0.0.0.1:0
+    addr: SocketAddr,
+    path: PathBuf,
+    cred: Option<UCred>,
+}
+
+#[cfg(unix)]
+impl UnixPeer {
+    fn new(stream: UnixStream, path: PathBuf) -> Self {
+        let cred = stream.peer_cred().ok();
+
+        UnixPeer { stream, addr: net::placeholder_addr(), path, cred }
+    }
+}
+
+#[cfg(unix)]
+impl AsyncRead for UnixPeer {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+#[cfg(unix)]
+impl AsyncWrite for UnixPeer {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    // A vectored write, the default takes a buffer at a time
+    fn poll_write_vectored(mut self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(unix)]
+impl Socket for UnixPeer {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn peer_name(&self) -> io::Result<PeerName> {
+        Ok(PeerName::Unix { path: self.path.clone(), cred: self.cred })
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        SockRef::from(&self.stream).shutdown(Shutdown::Write)
+    }
+
+    fn vectored(&self) -> bool {
+        true
+    }
+}
+
+/// Remove the socket file left behind at `path`, if any
+#[cfg(unix)]
+pub fn remove(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// A socket listened on, along with its path the peers are named after
+#[cfg(unix)]
+pub struct Listener {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+/// Listen on `path`, replacing a stale socket and applying `mode` to the new one
+#[cfg(unix)]
+pub fn listen(path: &Path, mode: Option<u32>) -> io::Result<Listener> {
+    let named = |e: io::Error| io::Error::new(e.kind(), format!("cannot listen on {}: {}", path.display(), e));
+
+    remove(path).map_err(named)?;
+    let listener = UnixListener::bind(path).map_err(named)?;
+
+    if let Some(mode) = mode {
+        fs::set_permissions(path, Permissions::from_mode(mode)).map_err(named)?;
+    }
+
+    Ok(Listener { listener, path: path.to_owned() })
+}
+
+/// Accept the peers of `listener` for as long as the future is polled, as `net::accept_loop` does
+#[cfg(unix)]
+pub async fn accept_loop<F: FnMut(UnixPeer)>(listener: Listener, mut accept: F) {
+    loop {
+        match listener.listener.accept().await {
+            Ok((stream, _)) => accept(UnixPeer::new(stream, listener.path.clone())),
+            Err(e) => {
+                debug!("Cannot accept a connection: {}", e);
+                time::sleep(net::ACCEPT_ERROR_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Nothing to remove where there are no unix sockets
+#[cfg(not(unix))]
+pub fn remove(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// The error reported when asked for unix sockets where there are none
+#[cfg(not(unix))]
+pub fn unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "unix sockets are not supported on this platform")
+}
+
+/// Parse an octal mode, e.g. 660
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("Invalid mode {}, expected octal digits, e.g. 660", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes() {
+        assert_eq!(parse_mode("660"), Ok(0o660));
+        assert_eq!(parse_mode("0600"), Ok(0o600));
+        assert!(parse_mode("8").is_err());
+        assert!(parse_mode("17777").is_err());
+    }
+}
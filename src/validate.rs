@@ -0,0 +1,186 @@
+//! Validation of the producer input
+//!
+//! Nothing is fanned out before the start of the stream shows sync bytes a
+//! packet apart, so a probe or a scanner hitting the producer port never
+//! reaches the consumers.
+
+use log::debug;
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::ts;
+
+/// Packets in a row the stream must start with, and resume with after losing sync
+const PROBE_PACKETS: usize = 8;
+/// Bytes shown of a rejected input
+const DUMP_BYTES: usize = 32;
+/// The sync losses are counted over this period
+const LOSS_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, PartialEq)]
+pub enum Probe {
+    /// Packets start at this offset
+    Valid(usize),
+    NeedMore,
+    Invalid,
+}
+
+/// Look for `PROBE_PACKETS` sync bytes a packet apart, starting in the first packet
+pub fn probe(buf: &[u8]) -> Probe {
+    let mut need_more = buf.len() < ts::PACKET_SIZE;
+
+    for off in 0..ts::PACKET_SIZE.min(buf.len()) {
+        let syncs = (0..PROBE_PACKETS).map(|k| buf.get(off + k * ts::PACKET_SIZE));
+
+        if syncs.clone().all(|b| b.is_none_or(|&b| b == ts::SYNC_BYTE)) {
+            if syncs.clone().all(|b| b.is_some()) {
+                return Probe::Valid(off);
+            }
+            need_more = true;
+        }
+    }
+
+    if need_more {
+        Probe::NeedMore
+    } else {
+        Probe::Invalid
+    }
+}
+
+/// The first bytes of `buf` in hexadecimal
+fn hexdump(buf: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, b) in buf.iter().take(DUMP_BYTES).enumerate() {
+        let _ = write!(out, "{}{:02x}", if i > 0 { " " } else { "" }, b);
+    }
+    out
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Validation state of a producer
+pub struct Validation {
+    /// The start of the stream was found
+    started: bool,
+    /// Looking for the packets again after losing sync
+    resyncing: bool,
+    /// Sync losses tolerated within `LOSS_WINDOW`
+    budget: usize,
+    losses: VecDeque<Instant>,
+}
+
+impl Validation {
+    pub fn new(budget: usize) -> Self {
+        Validation {
+            started: false,
+            resyncing: false,
+            budget,
+            losses: VecDeque::new(),
+        }
+    }
+
+    /// How many bytes of `buf` to skip before the packets, none while more is needed
+    ///
+    /// Fails if the stream does not start with packets, or loses sync more often
+    /// than the budget allows.
+    pub fn check(&mut self, buf: &[u8]) -> io::Result<Option<usize>> {
+        if self.started && !self.resyncing {
+            if buf.first().is_none_or(|&b| b == ts::SYNC_BYTE) {
+                return Ok(Some(0));
+            }
+            self.lost_sync()?;
+        }
+
+        match probe(buf) {
+            Probe::Valid(off) => {
+                self.started = true;
+                self.resyncing = false;
+                Ok(Some(off))
+            }
+            Probe::NeedMore => Ok(None),
+            Probe::Invalid if !self.started => {
+                debug!("Not an MPEG-TS stream, starting with {}", hexdump(buf));
+                Err(invalid("not an MPEG-TS stream".to_owned()))
+            }
+            // No packet starts in the first one
+            Probe::Invalid => Ok(Some(ts::PACKET_SIZE)),
+        }
+    }
+
+    /// Whether the stream started, what is left is dropped otherwise
+    pub fn started(&self) -> bool {
+        self.started
+    }
+
+    fn lost_sync(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        self.resyncing = true;
+
+        while self.losses.front().is_some_and(|&t| now.duration_since(t) > LOSS_WINDOW) {
+            self.losses.pop_front();
+        }
+        self.losses.push_back(now);
+
+        if self.losses.len() > self.budget {
+            return Err(invalid(format!("lost sync {} times within {:?}", self.losses.len(), LOSS_WINDOW)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(offset: usize, packets: usize) -> Vec<u8> {
+        let mut out = vec![0xaa; offset];
+        for _ in 0..packets {
+            let mut pkt = vec![0; ts::PACKET_SIZE];
+            pkt[0] = ts::SYNC_BYTE;
+            out.extend(pkt);
+        }
+        out
+    }
+
+    #[test]
+    fn probes() {
+        assert_eq!(probe(&stream(0, PROBE_PACKETS)), Probe::Valid(0));
+        assert_eq!(probe(&stream(5, PROBE_PACKETS + 1)), Probe::Valid(5));
+        assert_eq!(probe(&stream(5, 2)), Probe::NeedMore);
+        assert_eq!(probe(b"GET / HTTP/1.1\r\n"), Probe::NeedMore);
+        assert_eq!(probe(&[b"GET / HTTP/1.1\r\n".to_vec(), vec![b'x'; 400]].concat()), Probe::Invalid);
+        // A lone sync byte is not enough
+        assert_eq!(probe(&[stream(0, 1), vec![0; 400]].concat()), Probe::Invalid);
+    }
+
+    #[test]
+    fn start_then_sync_losses() {
+        let mut validation = Validation::new(1);
+
+        assert!(validation.check(&[b"SSH-2.0-OpenSSH\r\n".to_vec(), vec![0; 400]].concat()).is_err());
+
+        let mut validation = Validation::new(1);
+        assert_eq!(validation.check(&stream(0, 2)).unwrap(), None);
+        assert_eq!(validation.check(&stream(7, PROBE_PACKETS)).unwrap(), Some(7));
+        assert_eq!(validation.check(&stream(0, 1)).unwrap(), Some(0));
+
+        // Lost once, the garbage is skipped a packet at a time
+        assert_eq!(validation.check(&vec![0; 400]).unwrap(), Some(ts::PACKET_SIZE));
+        assert_eq!(validation.check(&stream(3, 2)).unwrap(), None);
+        assert_eq!(validation.check(&stream(3, PROBE_PACKETS)).unwrap(), Some(3));
+
+        // Lost twice, past the budget
+        assert!(validation.check(&vec![0; 400]).is_err());
+    }
+
+    #[test]
+    fn dump() {
+        assert_eq!(hexdump(b"GET /"), "47 45 54 20 2f");
+        assert_eq!(hexdump(&[0; 40]).len(), DUMP_BYTES * 3 - 1);
+    }
+}
@@ -0,0 +1,305 @@
+//! Webhook notifications of the producer and consumer events
+//!
+//! The events are queued to a task of their own, a slow or failing endpoint
+//! delays the next notifications but never the stream.
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time;
+use futures::prelude::*;
+use log::warn;
+
+use std::fmt::Write;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::scte35::Cue;
+
+/// Events waiting to be delivered, the newer ones are dropped past that
+const QUEUE: usize = 64;
+/// Attempts to deliver an event before giving up
+const ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Time given to the endpoint to answer
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Endpoint receiving the events, only plain `http://` is supported
+#[derive(Clone, Debug)]
+pub struct Webhook {
+    pub addr: SocketAddr,
+    /// Sent in the Host header
+    pub host: String,
+    pub path: String,
+}
+
+impl Webhook {
+    /// Parse and resolve `http://HOST[:PORT][/PATH]`
+    pub fn new(url: &str) -> Result<Self, String> {
+        let rest = url.strip_prefix("http://")
+            .ok_or_else(|| format!("Invalid webhook {}, expected http://HOST[:PORT]/PATH", url))?;
+
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+
+        // An IPv6 address is bracketed, its colons are not the port separator
+        let with_port = if host.rsplit(':').next().is_some_and(|p| !p.contains(']') && p != host) {
+            host.to_owned()
+        } else {
+            format!("{}:80", host)
+        };
+
+        let addr = with_port.to_socket_addrs()
+            .map_err(|e| format!("Cannot resolve {}: {}", host, e))?
+            .next()
+            .ok_or_else(|| format!("Cannot resolve {}: no address found", host))?;
+
+        Ok(Webhook { addr, host: host.to_owned(), path: path.to_owned() })
+    }
+
+    fn request(&self, body: &str) -> Vec<u8> {
+        format!("POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                self.path, self.host, body.len(), body).into_bytes()
+    }
+}
+
+/// What is notified
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    ProducerConnected { addr: SocketAddr },
+    ProducerDisconnected { addr: SocketAddr, duration: Duration, bytes: u64 },
+    /// The number of consumers reached `threshold` or fell below it
+    ConsumerCount { addr: SocketAddr, count: usize, threshold: usize, rising: bool },
+    StreamStalled { addr: SocketAddr },
+    StreamScrambled { addr: SocketAddr, scrambled: bool },
+    Scte35 { addr: SocketAddr, cue: Cue },
+}
+
+fn unix_time(t: SystemTime) -> f64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0)
+}
+
+impl Event {
+    fn json(&self, at: SystemTime, channel: Option<usize>) -> String {
+        let mut out = String::from("{");
+
+        let addr = match *self {
+            Event::ProducerConnected { addr } => {
+                out.push_str("\"event\": \"producer_connected\"");
+                addr
+            }
+            Event::ProducerDisconnected { addr, duration, bytes } => {
+                let _ = write!(out, "\"event\": \"producer_disconnected\", \"duration_secs\": {:.3}, \"bytes\": {}",
+                               duration.as_secs_f64(), bytes);
+                addr
+            }
+            Event::ConsumerCount { addr, count, threshold, rising } => {
+                let _ = write!(out, "\"event\": \"consumer_count\", \"count\": {}, \"threshold\": {}, \"rising\": {}",
+                               count, threshold, rising);
+                addr
+            }
+            Event::StreamStalled { addr } => {
+                out.push_str("\"event\": \"stream_stalled\"");
+                addr
+            }
+            Event::StreamScrambled { addr, scrambled } => {
+                let _ = write!(out, "\"event\": \"stream_scrambled\", \"scrambled\": {}", scrambled);
+                addr
+            }
+            Event::Scte35 { addr, ref cue } => {
+                let or_null = |value: Option<String>| value.unwrap_or_else(|| "null".to_owned());
+                let _ = write!(out, "\"event\": \"scte35\", \"command\": \"{}\", \"pid\": {}, \"event_id\": {}, \"cancel\": {}, \"out_of_network\": {}, \"pts\": {}, \"pre_roll_secs\": {}, \"duration_secs\": {}, \"arrival\": {:.3}",
+                               cue.command, cue.pid, or_null(cue.event_id.map(|id| id.to_string())), cue.cancel, cue.out_of_network,
+                               or_null(cue.pts.map(|pts| pts.to_string())), or_null(cue.pre_roll.map(|d| format!("{:.3}", d.as_secs_f64()))),
+                               or_null(cue.duration.map(|d| format!("{:.3}", d.as_secs_f64()))), unix_time(cue.arrival));
+                addr
+            }
+        };
+
+        if let Some(channel) = channel {
+            let _ = write!(out, ", \"channel\": {}", channel);
+        }
+        let _ = write!(out, ", \"addr\": \"{}\", \"timestamp\": {:.3}}}", addr, unix_time(at));
+        out
+    }
+}
+
+/// Queues the events for the delivery task
+pub struct Notifier {
+    tx: mpsc::Sender<String>,
+    /// Consumer counts worth a notification
+    pub thresholds: Vec<usize>,
+    /// Sent along with every event
+    pub channel: Option<usize>,
+}
+
+impl Notifier {
+    pub fn notify(&mut self, event: Event) {
+        if self.tx.try_send(event.json(SystemTime::now(), self.channel)).is_err() {
+            warn!("Dropping the webhook {:?}, too many pending", event);
+        }
+    }
+
+    /// Notify the thresholds crossed going from `before` to `after` consumers
+    pub fn consumers_changed(&mut self, addr: SocketAddr, before: usize, after: usize) {
+        let crossed: Vec<_> = self.thresholds.iter()
+            .filter(|&&t| (before < t) != (after < t))
+            .cloned()
+            .collect();
+
+        for threshold in crossed {
+            self.notify(Event::ConsumerCount { addr, count: after, threshold, rising: after > before });
+        }
+    }
+}
+
+/// POST the body once, failing unless the answer is a 2xx
+async fn post(webhook: &Webhook, body: &str) -> Result<(), String> {
+    let request = webhook.request(body);
+
+    let exchange = async {
+        let mut socket = TcpStream::connect(&webhook.addr).await?;
+        socket.write_all(&request).await?;
+
+        let mut answer = Vec::new();
+        socket.read_to_end(&mut answer).await?;
+        Ok::<_, io::Error>(answer)
+    };
+
+    let answer = match time::timeout(TIMEOUT, exchange).await {
+        Ok(Ok(answer)) => answer,
+        Ok(Err(e)) => return Err(e.to_string()),
+        Err(_) => return Err(format!("no answer within {:?}", TIMEOUT)),
+    };
+
+    let status = String::from_utf8_lossy(&answer).lines().next().unwrap_or("").to_owned();
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(format!("unexpected answer {:?}", status)),
+    }
+}
+
+/// POST the body, retrying a few times
+async fn deliver(webhook: Webhook, body: String) {
+    for attempt in 1..=ATTEMPTS {
+        match post(&webhook, &body).await {
+            Ok(()) => return,
+            Err(e) => warn!("Webhook to {} failed (attempt {}/{}): {}", webhook.addr, attempt, ATTEMPTS, e),
+        }
+        if attempt < ATTEMPTS {
+            time::sleep(RETRY_DELAY).await;
+        }
+    }
+}
+
+/// The notifier and the task delivering its events one at a time
+pub fn spawn(webhook: Webhook, thresholds: Vec<usize>, channel: Option<usize>) -> (Notifier, impl Future<Output = ()>) {
+    let (tx, mut rx) = mpsc::channel(QUEUE);
+
+    let delivery = async move {
+        while let Some(body) = rx.recv().await {
+            deliver(webhook.clone(), body).await;
+        }
+    };
+
+    (Notifier { tx, thresholds, channel }, delivery)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scte35::SpliceCommand;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn urls() {
+        let webhook = Webhook::new("http://127.0.0.1:8080/hooks/restream").unwrap();
+        assert_eq!(webhook.addr, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(webhook.path, "/hooks/restream");
+
+        let webhook = Webhook::new("http://[::1]").unwrap();
+        assert_eq!(webhook.addr, "[::1]:80".parse().unwrap());
+        assert_eq!(webhook.path, "/");
+
+        assert!(Webhook::new("https://127.0.0.1/").is_err());
+    }
+
+    #[test]
+    fn payloads() {
+        let addr = "10.0.0.1:5000".parse().unwrap();
+        let at = UNIX_EPOCH + Duration::from_millis(1_500);
+
+        assert_eq!(Event::ProducerDisconnected { addr, duration: Duration::from_millis(2500), bytes: 1316 }.json(at, None),
+                   "{\"event\": \"producer_disconnected\", \"duration_secs\": 2.500, \"bytes\": 1316, \"addr\": \"10.0.0.1:5000\", \"timestamp\": 1.500}");
+        assert_eq!(Event::StreamStalled { addr }.json(at, Some(2)),
+                   "{\"event\": \"stream_stalled\", \"channel\": 2, \"addr\": \"10.0.0.1:5000\", \"timestamp\": 1.500}");
+        assert_eq!(Event::StreamScrambled { addr, scrambled: true }.json(at, None),
+                   "{\"event\": \"stream_scrambled\", \"scrambled\": true, \"addr\": \"10.0.0.1:5000\", \"timestamp\": 1.500}");
+
+        let cue = Cue {
+            pid: 0x1f0,
+            command: SpliceCommand::SpliceInsert,
+            event_id: Some(42),
+            cancel: false,
+            out_of_network: true,
+            pts: Some(900_000),
+            pre_roll: Some(Duration::from_millis(4000)),
+            duration: None,
+            arrival: UNIX_EPOCH + Duration::from_millis(1_250),
+        };
+        assert_eq!(Event::Scte35 { addr, cue }.json(at, None),
+                   "{\"event\": \"scte35\", \"command\": \"splice_insert\", \"pid\": 496, \"event_id\": 42, \"cancel\": false, \"out_of_network\": true, \"pts\": 900000, \"pre_roll_secs\": 4.000, \"duration_secs\": null, \"arrival\": 1.250, \"addr\": \"10.0.0.1:5000\", \"timestamp\": 1.500}");
+    }
+
+    #[test]
+    fn thresholds() {
+        let (tx, mut rx) = mpsc::channel(QUEUE);
+        let mut notifier = Notifier { tx, thresholds: vec![2, 5], channel: None };
+        let addr = "10.0.0.1:5000".parse().unwrap();
+
+        notifier.consumers_changed(addr, 0, 1);
+        notifier.consumers_changed(addr, 1, 2);
+        notifier.consumers_changed(addr, 2, 3);
+        notifier.consumers_changed(addr, 2, 1);
+        drop(notifier);
+
+        let mut events = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            events.push(event);
+        }
+        assert_eq!(events.len(), 2);
+        assert!(events[0].contains("\"count\": 2, \"threshold\": 2, \"rising\": true"));
+        assert!(events[1].contains("\"count\": 1, \"threshold\": 2, \"rising\": false"));
+    }
+
+    #[test]
+    fn retries() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let webhook = Webhook::new(&format!("http://{}/hook", listener.local_addr().unwrap())).unwrap();
+
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for answer in &["HTTP/1.1 500 Oops\r\n\r\n", "HTTP/1.1 204 No Content\r\n\r\n"] {
+                let (mut socket, _) = listener.accept().unwrap();
+                let mut buf = [0; 1024];
+                let n = socket.read(&mut buf).unwrap();
+                requests.push(String::from_utf8_lossy(&buf[..n]).into_owned());
+                socket.write_all(answer.as_bytes()).unwrap();
+            }
+            requests
+        });
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(deliver(webhook, "{}".to_owned()));
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(requests[1].ends_with("\r\n\r\n{}"));
+    }
+}
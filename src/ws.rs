@@ -0,0 +1,318 @@
+//! WebSocket consumers, for the players running in a browser
+//!
+//! Only what streaming to them takes: the upgrade, a binary frame per chunk
+//! and the control frames. What the clients send otherwise is discarded.
+
+use tokio::time::Interval;
+use bytes::{Buf, BytesMut};
+
+use std::io;
+use std::task::Context;
+use std::time::Duration;
+
+use crate::http::{self, Request};
+
+/// Appended to the client key for the accept key, from RFC 6455
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Time between the pings keeping the idle connections open
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Largest frame accepted from a client, it has no business sending data
+const MAX_FRAME: u64 = 64 * 1024;
+
+pub const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// Close status of a normal closure
+const CLOSE_NORMAL: [u8; 2] = [0x03, 0xe8];
+
+/// Sec-WebSocket-Accept for the Sec-WebSocket-Key `key`
+pub fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+/// SHA-1 of `data`, as RFC 3174 has it, only ever hashing the short upgrade keys
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    // Padded with a 1 bit, zeros and the length in bits to a multiple of 64 bytes
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in msg.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+
+    let mut out = [0; 20];
+    for (out, h) in out.chunks_mut(4).zip(h) {
+        out.copy_from_slice(&h.to_be_bytes());
+    }
+    out
+}
+
+/// Standard base64 of `data`, padded
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for group in data.chunks(3) {
+        let n = group.iter().enumerate().fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= group.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// The response switching to WebSocket, or the one refusing the request
+pub fn upgrade(req: &Request) -> Result<Vec<u8>, Vec<u8>> {
+    if req.method != "GET" {
+        return Err(http::response("405 Method Not Allowed", "text/plain", "Allow: GET\r\n", b""));
+    }
+
+    let is_upgrade = req.header("upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+        && req.header("connection").is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case("upgrade")));
+    let key = match req.header("sec-websocket-key") {
+        Some(key) if is_upgrade && req.header("sec-websocket-version") == Some("13") => key,
+        _ => return Err(http::response("426 Upgrade Required", "text/plain", "Sec-WebSocket-Version: 13\r\n",
+                                       b"WebSocket upgrade required\n")),
+    };
+
+    Ok(format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+               accept_key(key)).into_bytes())
+}
+
+/// Head of a frame from the server, unmasked
+pub fn header(opcode: u8, len: usize) -> Vec<u8> {
+    let mut head = vec![0x80 | opcode];
+    match len {
+        0..=125 => head.push(len as u8),
+        126..=0xffff => {
+            head.push(126);
+            head.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        _ => {
+            head.push(127);
+            head.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    head
+}
+
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = header(opcode, payload.len());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// What a client asked for
+#[derive(Debug, PartialEq)]
+enum Control {
+    Ping(Vec<u8>),
+    Close,
+}
+
+/// Split the client frames, keeping the control ones
+#[derive(Default)]
+struct Reader {
+    buf: BytesMut,
+}
+
+impl Reader {
+    fn feed(&mut self, data: &[u8]) -> io::Result<Vec<Control>> {
+        self.buf.extend_from_slice(data);
+
+        let mut controls = Vec::new();
+        while let Some((opcode, payload)) = self.next()? {
+            match opcode {
+                OP_PING => controls.push(Control::Ping(payload)),
+                OP_CLOSE => controls.push(Control::Close),
+                _ => (),
+            }
+        }
+        Ok(controls)
+    }
+
+    /// Take the next complete frame, unmasked
+    fn next(&mut self) -> io::Result<Option<(u8, Vec<u8>)>> {
+        if self.buf.len() < 2 {
+            return Ok(None);
+        }
+
+        let (opcode, masked) = (self.buf[0] & 0x0f, self.buf[1] & 0x80 != 0);
+        let (len, mut at) = match self.buf[1] & 0x7f {
+            126 if self.buf.len() >= 4 => (u64::from(u16::from_be_bytes([self.buf[2], self.buf[3]])), 4),
+            127 if self.buf.len() >= 10 => {
+                let mut len = [0; 8];
+                len.copy_from_slice(&self.buf[2..10]);
+                (u64::from_be_bytes(len), 10)
+            }
+            126 | 127 => return Ok(None),
+            len => (u64::from(len), 2),
+        };
+
+        if !masked {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "unmasked WebSocket frame"));
+        }
+        if len > MAX_FRAME {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("WebSocket frame of {} bytes", len)));
+        }
+
+        let len = len as usize;
+        if self.buf.len() < at + 4 + len {
+            return Ok(None);
+        }
+
+        let mut mask = [0; 4];
+        mask.copy_from_slice(&self.buf[at..at + 4]);
+        at += 4;
+
+        let payload = self.buf[at..at + len].iter().enumerate().map(|(i, b)| b ^ mask[i % 4]).collect();
+        self.buf.advance(at + len);
+
+        Ok(Some((opcode, payload)))
+    }
+}
+
+/// State of the WebSocket a consumer is served over
+pub struct WebSocket {
+    reader: Reader,
+    ping: Interval,
+    closing: bool,
+}
+
+impl WebSocket {
+    pub fn new() -> Self {
+        WebSocket {
+            reader: Reader::default(),
+            ping: crate::interval(PING_INTERVAL),
+            closing: false,
+        }
+    }
+
+    /// Take the data read from the client, appending the replies to `out`,
+    /// returns whether the client is closing
+    pub fn receive(&mut self, data: &[u8], out: &mut BytesMut) -> io::Result<bool> {
+        for control in self.reader.feed(data)? {
+            match control {
+                Control::Ping(payload) => out.extend_from_slice(&frame(OP_PONG, &payload)),
+                Control::Close => {
+                    self.close(out);
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Append a ping to `out` when it is time to
+    pub fn poll_ping(&mut self, cx: &mut Context<'_>, out: &mut BytesMut) {
+        while self.ping.poll_tick(cx).is_ready() {
+            out.extend_from_slice(&frame(OP_PING, b""));
+        }
+    }
+
+    /// Append the close frame to `out`, once, returns whether it did
+    pub fn close(&mut self, out: &mut BytesMut) -> bool {
+        if self.closing {
+            return false;
+        }
+        self.closing = true;
+        out.extend_from_slice(&frame(OP_CLOSE, &CLOSE_NORMAL));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn accept() {
+        // The example of RFC 6455
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        // The empty message and the two block one of RFC 3174
+        assert_eq!(base64(&sha1(b"")), "2jmj7l5rSw0yVb/vlWAYkK/YBwk=");
+        assert_eq!(base64(&sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")), "hJg+RBw70m66rkqh+VEp5eVGcPE=");
+        assert_eq!((base64(b"f"), base64(b"fo"), base64(b"foo")), ("Zg==".to_owned(), "Zm8=".to_owned(), "Zm9v".to_owned()));
+    }
+
+    #[test]
+    fn upgrade_request() {
+        let head = "GET /live HTTP/1.1\r\nHost: example\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+                    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        let req = block_on(http::read_request(head.as_bytes())).unwrap().1;
+        let res = String::from_utf8(upgrade(&req).unwrap()).unwrap();
+        assert!(res.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(res.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        let plain = block_on(http::read_request(&b"GET /live HTTP/1.1\r\nHost: example\r\n\r\n"[..])).unwrap().1;
+        assert!(upgrade(&plain).unwrap_err().starts_with(b"HTTP/1.1 426"));
+    }
+
+    #[test]
+    fn headers() {
+        assert_eq!(header(OP_BINARY, 1316), vec![0x82, 126, 0x05, 0x24]);
+        assert_eq!(header(OP_BINARY, 100), vec![0x82, 100]);
+        assert_eq!(header(OP_BINARY, 70000)[..2], [0x82, 127]);
+    }
+
+    #[test]
+    fn control_frames() {
+        let mut reader = Reader::default();
+        let mut data = client_frame(OP_PING, b"hi");
+        data.extend(client_frame(OP_BINARY, b"ignored"));
+        data.extend(client_frame(OP_CLOSE, &CLOSE_NORMAL));
+
+        // Split anywhere
+        assert_eq!(reader.feed(&data[..5]).unwrap(), vec![]);
+        assert_eq!(reader.feed(&data[5..]).unwrap(), vec![Control::Ping(b"hi".to_vec()), Control::Close]);
+
+        assert!(Reader::default().feed(&frame(OP_PING, b"")).is_err());
+    }
+}
@@ -0,0 +1,92 @@
+mod support;
+
+use restream::{Restreamer, Settings};
+use support::{connect, pattern, start};
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Counts the allocations of the whole process, the server and the clients alike
+struct Counting;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(new_size as u64, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Stream `secs` seconds at 20 Mbit/s to `consumers` consumers, yielding the
+/// allocations and the bytes allocated a second meanwhile
+fn stream(read_pool: usize, consumers: usize, secs: u32) -> (u64, u64) {
+    let chunk = 7 * 188;
+    let chunks_per_sec = 20_000_000 / 8 / chunk as u32;
+
+    let harness = start(Restreamer::builder().settings(Settings { read_pool, ..Settings::default() }));
+    let data = pattern(0, 7 * (chunks_per_sec * secs) as usize);
+    let consumers: Vec<_> = (0..consumers).map(|_| {
+        let mut consumer = connect(harness.consumer);
+        thread::spawn(move || {
+            let mut buf = vec![0; 64 * 1024];
+            while let Ok(n) = consumer.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+            }
+        })
+    }).collect();
+
+    let mut producer = connect(harness.producer);
+    thread::sleep(Duration::from_millis(500));
+    let (allocations, allocated) = (ALLOCATIONS.load(Ordering::Relaxed), ALLOCATED.load(Ordering::Relaxed));
+    let started = Instant::now();
+    for (n, chunk) in data.chunks(chunk).enumerate() {
+        let due = started + Duration::from_secs(1) * n as u32 / chunks_per_sec;
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+        producer.write_all(chunk).unwrap();
+    }
+    let elapsed = started.elapsed().as_secs_f64();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    let allocated = ALLOCATED.load(Ordering::Relaxed) - allocated;
+
+    drop(producer);
+    for consumer in consumers {
+        consumer.join().unwrap();
+    }
+    ((allocations as f64 / elapsed) as u64, (allocated as f64 / elapsed) as u64)
+}
+
+#[test]
+#[ignore]
+fn read_buffer_allocations() {
+    const CONSUMERS: usize = 20;
+    const SECS: u32 = 10;
+
+    let unpooled = stream(0, CONSUMERS, SECS);
+    let pooled = stream(Settings::default().read_pool, CONSUMERS, SECS);
+    for &(name, (allocations, allocated)) in &[("--read-pool 0", unpooled), ("--read-pool 256", pooled)] {
+        eprintln!("{}: {} consumers at 20 Mbit/s, {} allocations/s, {} KiB/s allocated",
+                  name, CONSUMERS, allocations, allocated / 1024);
+    }
+    assert!(pooled.1 < unpooled.1);
+}
@@ -0,0 +1,443 @@
+mod support;
+
+use restream::{Backoff, BroadcastDelay, Burst, Event, Overflow, Reason, Restreamer, Settings, Simulator};
+use support::{collect_consumer, connect, packet_numbers, pattern, proxy, spawn_producer, start, SETTLE};
+use futures::executor::block_on_stream;
+use tokio::runtime::Runtime;
+
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn consumers_receive_the_same_packets() {
+    let harness = start(Restreamer::builder());
+    assert_ne!(harness.producer.port(), 0);
+    assert_ne!(harness.consumer.port(), 0);
+
+    let data = pattern(0, 7 * 300);
+    let consumers: Vec<_> = (0..3).map(|_| collect_consumer(harness.consumer, data.len())).collect();
+    spawn_producer(harness.producer, data.clone()).join().unwrap();
+
+    for consumer in consumers {
+        let received = consumer.join().unwrap();
+        assert_eq!(packet_numbers(&received), (0..7 * 300).collect::<Vec<_>>());
+        assert!(received == data);
+    }
+}
+
+#[test]
+fn unaligned_writes_chunked_and_gone_consumers_dropped() {
+    let harness = start(Restreamer::builder());
+    let data = pattern(0, 7 * 100);
+
+    let mut producer = connect(harness.producer);
+    thread::sleep(SETTLE);
+    let consumers: Vec<_> = (0..3).map(|_| collect_consumer(harness.consumer, data.len())).collect();
+    let leaving = connect(harness.consumer);
+    thread::sleep(SETTLE);
+    assert_eq!(harness.restreamer.consumers(), 4);
+
+    // Cut across the packets, the chunks going out are whole ones all the same
+    let (first, second) = data.split_at(data.len() / 2 + 100);
+    for piece in first.chunks(1000) {
+        producer.write_all(piece).unwrap();
+    }
+    drop(leaving);
+    thread::sleep(SETTLE);
+    assert_eq!(harness.restreamer.consumers(), 3);
+    for piece in second.chunks(1000) {
+        producer.write_all(piece).unwrap();
+    }
+
+    for consumer in consumers {
+        let received = consumer.join().unwrap();
+        assert_eq!(packet_numbers(&received), (0..7 * 100).collect::<Vec<_>>());
+    }
+    // Gone while the producer is still there
+    thread::sleep(SETTLE);
+    assert_eq!(harness.restreamer.consumers(), 0);
+}
+
+#[test]
+fn producer_reconnects_on_the_same_ports() {
+    let harness = start(Restreamer::builder());
+
+    let first = pattern(0, 70);
+    let consumer = collect_consumer(harness.consumer, usize::MAX);
+    spawn_producer(harness.producer, first.clone()).join().unwrap();
+    // The stream ended with the producer
+    assert!(consumer.join().unwrap() == first);
+    thread::sleep(SETTLE);
+    assert_eq!(harness.restreamer.consumers(), 0);
+
+    let second = pattern(70, 70);
+    let consumer = collect_consumer(harness.consumer, second.len());
+    spawn_producer(harness.producer, second.clone()).join().unwrap();
+    assert_eq!(packet_numbers(&consumer.join().unwrap()), (70..140).collect::<Vec<_>>());
+}
+
+#[test]
+fn slow_consumer_disconnected_on_overflow() {
+    let settings = Settings { consumer_queue: 8, overflow: Overflow::Disconnect, ..Settings::default() };
+    let harness = start(Restreamer::builder().settings(settings));
+    let mut events = block_on_stream(harness.restreamer.events());
+
+    let mut producer = connect(harness.producer);
+    thread::sleep(SETTLE);
+    // Never reads, its socket fills up and then its queue
+    let mut slow = connect(harness.consumer);
+    thread::sleep(SETTLE);
+    let slow_addr = slow.local_addr().unwrap();
+
+    for chunk in pattern(0, 7 * 2000).chunks(7 * 188) {
+        producer.write_all(chunk).unwrap();
+    }
+
+    loop {
+        match events.next() {
+            Some(Event::ConsumerDisconnected { addr, reason, .. }) => {
+                assert_eq!(addr, slow_addr);
+                assert_eq!(reason, Reason::Removed);
+                break;
+            }
+            Some(_) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    assert_eq!(harness.restreamer.consumers(), 0);
+
+    // What made it to its socket is whole packets, then the end of the stream
+    let mut received = Vec::new();
+    slow.read_to_end(&mut received).unwrap();
+    let numbers = packet_numbers(&received);
+    assert_eq!(numbers, (0..numbers.len() as u32).collect::<Vec<_>>());
+}
+
+#[test]
+fn consumers_past_the_limit_rejected() {
+    let settings = Settings { max_consumers: Some(2), ..Settings::default() };
+    let harness = start(Restreamer::builder().settings(settings));
+
+    let _producer = connect(harness.producer);
+    thread::sleep(SETTLE);
+    let _consumers = [connect(harness.consumer), connect(harness.consumer)];
+    thread::sleep(SETTLE);
+    assert_eq!(harness.restreamer.consumers(), 2);
+
+    let mut third = connect(harness.consumer);
+    let mut buf = [0; 1];
+    assert_eq!(third.read(&mut buf).unwrap(), 0);
+    assert_eq!(harness.restreamer.consumers(), 2);
+}
+
+#[test]
+fn consumers_in_their_handshake_count_against_the_limit() {
+    let settings = Settings { max_consumers: Some(1), consumer_options: true, ..Settings::default() };
+    let harness = start(Restreamer::builder().settings(settings));
+
+    let _producer = connect(harness.producer);
+    thread::sleep(SETTLE);
+    // Still deciding on its options when the second one comes
+    let _first = connect(harness.consumer);
+    let mut second = connect(harness.consumer);
+    assert_eq!(second.read(&mut [0; 1]).unwrap(), 0);
+
+    // Past the moment given for the options
+    thread::sleep(Duration::from_millis(500) + SETTLE);
+    assert_eq!(harness.restreamer.consumers(), 1);
+}
+
+#[test]
+fn simulated_link_drops_and_delays() {
+    let simulate = "loss=50%,delay=300ms".parse().unwrap();
+    let settings = Settings { simulate: Some(Arc::new(Simulator::new(simulate, Some(7)))), ..Settings::default() };
+    let harness = start(Restreamer::builder().settings(settings));
+
+    let mut producer = connect(harness.producer);
+    thread::sleep(SETTLE);
+    let mut consumer = connect(harness.consumer);
+    thread::sleep(SETTLE);
+
+    let sent = Instant::now();
+    producer.write_all(&pattern(0, 7 * 100)).unwrap();
+    let mut first = [0; 188];
+    consumer.read_exact(&mut first).unwrap();
+    assert!(sent.elapsed() >= Duration::from_millis(300));
+    // Dropped as they were taken from the queue
+    thread::sleep(SETTLE);
+    let json = harness.restreamer.stats().json();
+
+    drop(producer);
+    let mut received = first.to_vec();
+    consumer.read_to_end(&mut received).unwrap();
+
+    // Whole chunks are dropped, the others arrive in order
+    let numbers = packet_numbers(&received);
+    assert!(numbers.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(numbers.len() % 7, 0);
+    let dropped = 100 - numbers.len() as u64 / 7;
+    assert!(dropped > 20 && dropped < 80, "{} chunks dropped", dropped);
+    assert!(json.contains(&format!("\"simulated_drops\": {}", dropped)), "{}", json);
+}
+
+#[test]
+fn rewound_consumer_replays_then_follows_live() {
+    let settings = Settings { consumer_options: true, ..Settings::default() };
+    let harness = start(Restreamer::builder().settings(settings).dvr(Some(Duration::from_secs(30))));
+
+    let mut producer = connect(harness.producer);
+    thread::sleep(SETTLE);
+    for chunk in pattern(0, 7 * 100).chunks(7 * 188) {
+        producer.write_all(chunk).unwrap();
+    }
+    thread::sleep(SETTLE);
+
+    let mut live = connect(harness.consumer);
+    live.write_all(b"OPTS burst=0\n").unwrap();
+    let mut rewound = connect(harness.consumer);
+    rewound.write_all(b"OPTS rewind=30\n").unwrap();
+    thread::sleep(SETTLE);
+
+    for chunk in pattern(7 * 100, 7 * 100).chunks(7 * 188) {
+        producer.write_all(chunk).unwrap();
+    }
+    drop(producer);
+
+    let mut received = Vec::new();
+    rewound.read_to_end(&mut received).unwrap();
+    // The past first, then the live stream, without a gap nor a repeat
+    assert_eq!(packet_numbers(&received), (0..7 * 200).collect::<Vec<_>>());
+    received.clear();
+    live.read_to_end(&mut received).unwrap();
+    assert_eq!(packet_numbers(&received), (7 * 100..7 * 200).collect::<Vec<_>>());
+}
+
+#[test]
+fn http_consumer_rewinds_with_a_query() {
+    let harness = start(Restreamer::builder().http(true).dvr(Some(Duration::from_secs(30))));
+
+    let mut producer = connect(harness.producer);
+    thread::sleep(SETTLE);
+    producer.write_all(&pattern(0, 7 * 10)).unwrap();
+    thread::sleep(SETTLE);
+
+    let mut bad = connect(harness.consumer);
+    bad.write_all(b"GET /?rewind=soon HTTP/1.1\r\n\r\n").unwrap();
+    let mut res = String::new();
+    bad.read_to_string(&mut res).unwrap();
+    assert!(res.starts_with("HTTP/1.1 400 "), "{}", res);
+
+    let mut consumer = connect(harness.consumer);
+    consumer.write_all(b"GET /?rewind=30 HTTP/1.1\r\n\r\n").unwrap();
+    thread::sleep(SETTLE);
+    drop(producer);
+
+    let mut received = Vec::new();
+    consumer.read_to_end(&mut received).unwrap();
+    let body = received.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+    assert_eq!(packet_numbers(&received[body..]), (0..7 * 10).collect::<Vec<_>>());
+}
+
+#[test]
+fn snapshot_of_the_burst_buffer() {
+    let burst = Burst::Duration(Duration::from_secs(10));
+    let harness = start(Restreamer::builder().http(true).burst(Some(burst)));
+
+    let mut producer = connect(harness.producer);
+    thread::sleep(SETTLE);
+    producer.write_all(&pattern(0, 7 * 10)).unwrap();
+    thread::sleep(SETTLE);
+
+    let mut snapshot = connect(harness.consumer);
+    snapshot.write_all(b"GET /snapshot?duration=30 HTTP/1.1\r\n\r\n").unwrap();
+    let mut res = Vec::new();
+    snapshot.read_to_end(&mut res).unwrap();
+
+    let body = res.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+    let head = String::from_utf8_lossy(&res[..body]);
+    assert!(head.starts_with("HTTP/1.1 200 "), "{}", head);
+    assert!(head.contains(&format!("Content-Length: {}\r\n", 7 * 10 * 188)), "{}", head);
+    // Less than asked for is buffered
+    assert!(head.contains("X-Available-Duration: "), "{}", head);
+    assert_eq!(packet_numbers(&res[body..]), (0..7 * 10).collect::<Vec<_>>());
+    // Not a consumer
+    assert_eq!(harness.restreamer.consumers(), 0);
+}
+
+#[test]
+fn delayed_broadcast_padded_while_priming() {
+    let delay = BroadcastDelay { delay: Duration::from_millis(500), memory: 7 * 188 * 4, pad: true };
+    let harness = start(Restreamer::builder().delay(Some(delay)));
+
+    let mut producer = connect(harness.producer);
+    let mut consumer = connect(harness.consumer);
+    thread::sleep(SETTLE);
+
+    // Past the memory, the rest is spilled to disk
+    let sent = Instant::now();
+    for chunk in pattern(0, 7 * 10).chunks(7 * 188) {
+        producer.write_all(chunk).unwrap();
+    }
+
+    let (mut received, mut nulls) = (Vec::new(), 0);
+    let mut buf = [0; 188];
+    while received.len() < 7 * 10 {
+        consumer.read_exact(&mut buf).unwrap();
+        match buf[1..3] {
+            // Null packets until the delay is primed
+            [0x1f, 0xff] => {
+                assert!(received.is_empty());
+                nulls += 1;
+            }
+            _ => {
+                if received.is_empty() {
+                    assert!(sent.elapsed() >= Duration::from_millis(500));
+                }
+                received.extend(packet_numbers(&buf));
+            }
+        }
+    }
+    assert_eq!((received, nulls), ((0..7 * 10).collect::<Vec<_>>(), 7 * 10));
+    assert!(harness.restreamer.stats().json().contains("\"delay\": {\"target_secs\": 0.500, \"achieved_secs\": 0."));
+}
+
+#[test]
+fn relay_resumes_past_a_blip() {
+    let relaying = Settings { relay_resume: true, ..Settings::default() };
+    let origin = start(Restreamer::builder().settings(relaying.clone()).burst(Some(Burst::Duration(Duration::from_secs(10)))));
+    let mut producer = connect(origin.producer);
+
+    let (upstream, links) = proxy(origin.consumer);
+    let rt = Runtime::new().unwrap();
+    let edge = Restreamer::builder()
+        .pull(upstream)
+        .pull_backoff(Backoff { initial: Duration::from_millis(50), max: Duration::from_secs(1) })
+        .settings(relaying)
+        .consumer_listener(([127, 0, 0, 1], 0).into())
+        .spawn(&rt)
+        .unwrap();
+    thread::sleep(SETTLE);
+    let mut consumer = connect(edge.consumer_addr().unwrap());
+    // Joining once it had time to send options
+    thread::sleep(Duration::from_millis(500) + SETTLE);
+
+    let mut received = vec![0; 7 * 50 * 188];
+    producer.write_all(&pattern(0, 7 * 50)).unwrap();
+    consumer.read_exact(&mut received).unwrap();
+
+    // Sent by the origin while the relay is cut off
+    let link = links.recv().unwrap();
+    link.shutdown(Shutdown::Both).unwrap();
+    producer.write_all(&pattern(7 * 50, 7 * 50)).unwrap();
+    links.recv().unwrap();
+
+    let mut resumed = vec![0; 7 * 50 * 188];
+    consumer.read_exact(&mut resumed).unwrap();
+    received.extend(resumed);
+    assert_eq!(packet_numbers(&received), (0..7 * 100).collect::<Vec<_>>());
+    assert_eq!(edge.consumers(), 1);
+}
+
+#[test]
+fn relay_told_where_it_joins() {
+    let relaying = Settings { relay_resume: true, ..Settings::default() };
+    let origin = start(Restreamer::builder().settings(relaying).burst(Some(Burst::Duration(Duration::from_secs(10)))));
+    let mut producer = connect(origin.producer);
+    thread::sleep(SETTLE);
+    producer.write_all(&pattern(0, 7 * 10)).unwrap();
+    thread::sleep(SETTLE);
+
+    let resume = |line: &str| {
+        let mut relay = connect(origin.consumer);
+        relay.write_all(line.as_bytes()).unwrap();
+        let mut reply = Vec::new();
+        let mut byte = [0];
+        while byte != *b"\n" {
+            relay.read_exact(&mut byte).unwrap();
+            reply.push(byte[0]);
+        }
+        (String::from_utf8(reply).unwrap(), relay)
+    };
+
+    // Joining, the whole burst follows
+    let (joined, mut relay) = resume("RESUME\n");
+    let start = joined.trim_end().rsplit_once(':').unwrap();
+    assert_eq!((start.0.len(), start.1), ("RESUMED 0000000000000000".len(), "0"));
+    let mut packets = vec![0; 7 * 10 * 188];
+    relay.read_exact(&mut packets).unwrap();
+    assert_eq!(packet_numbers(&packets), (0..7 * 10).collect::<Vec<_>>());
+
+    // Resuming mid-stream, even mid-packet
+    let (resumed, mut relay) = resume(&format!("RESUME {}:{}\n", &start.0[8..], 5 * 188 + 100));
+    assert_eq!(resumed, format!("RESUMED {}:{}\n", &start.0[8..], 5 * 188 + 100));
+    let mut rest = vec![0; 88];
+    relay.read_exact(&mut rest).unwrap();
+    assert_eq!(rest[84..], 5u32.to_be_bytes());
+    let mut packets = vec![0; (7 * 10 - 6) * 188];
+    relay.read_exact(&mut packets).unwrap();
+    assert_eq!(packet_numbers(&packets), (6..7 * 10).collect::<Vec<_>>());
+
+    // Another run of the origin, joining as if new
+    let (joined, _) = resume("RESUME 0000000000000001:1316\n");
+    assert!(joined.ends_with(":0\n"), "{}", joined);
+}
+
+/// 200 consumers of a 20 Mbit/s stream, how late they get each chunk, run with
+/// `cargo test --release --test end_to_end fanout_load -- --ignored --nocapture`
+#[test]
+#[ignore]
+fn fanout_load() {
+    const CONSUMERS: usize = 200;
+    const SECS: u32 = 10;
+    let chunk = 7 * 188;
+    let chunks_per_sec = 20_000_000 / 8 / chunk as u32;
+
+    let harness = start(Restreamer::builder());
+    let data = pattern(0, 7 * (chunks_per_sec * SECS) as usize);
+    let started = Instant::now() + Duration::from_secs(1);
+    let consumers: Vec<_> = (0..CONSUMERS).map(|_| {
+        let mut consumer = connect(harness.consumer);
+        thread::spawn(move || {
+            let mut buf = vec![0; 64 * 1024];
+            let (mut len, mut late) = (0, Vec::new());
+            while let Ok(n) = consumer.read(&mut buf[len..]) {
+                if n == 0 {
+                    break;
+                }
+                len += n;
+                let whole = len / 188 * 188;
+                let now = Instant::now();
+                for pkt in buf[..whole].chunks(188) {
+                    let number = u32::from_be_bytes([pkt[4], pkt[5], pkt[6], pkt[7]]);
+                    // The chunk is due once its last packet is
+                    if number % 7 == 6 {
+                        let due = started + Duration::from_secs(1) * (number / 7) / chunks_per_sec;
+                        late.push(now.saturating_duration_since(due));
+                    }
+                }
+                buf.copy_within(whole..len, 0);
+                len -= whole;
+            }
+            late
+        })
+    }).collect();
+
+    let mut producer = connect(harness.producer);
+    thread::sleep(started.saturating_duration_since(Instant::now()));
+    for (n, chunk) in data.chunks(chunk).enumerate() {
+        let due = started + Duration::from_secs(1) * n as u32 / chunks_per_sec;
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+        producer.write_all(chunk).unwrap();
+    }
+    drop(producer);
+
+    let mut late: Vec<_> = consumers.into_iter().flat_map(|consumer| consumer.join().unwrap()).collect();
+    assert_eq!(late.len(), CONSUMERS * (chunks_per_sec * SECS) as usize);
+    late.sort();
+    let percentile = |p: usize| late[(late.len() - 1) * p / 100];
+    eprintln!("{} consumers at 20 Mbit/s, each chunk late by {:?} (median), {:?} (99th percentile), {:?} at most",
+              CONSUMERS, percentile(50), percentile(99), late[late.len() - 1]);
+}
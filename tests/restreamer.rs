@@ -0,0 +1,1797 @@
+#[cfg(unix)]
+use restream::Account;
+use restream::{AccessFormat, AccessLog, Aes, Backoff, Bandwidth, Burst, Event, Fsync, Hls, Input, LocalBind, PidFilter, PidRemap, ProducerTls, Reason, Record, Restreamer, Settings, Sink, Tls, Webhook};
+use socket2::SockRef;
+use futures::executor::block_on_stream;
+use tokio::runtime::Runtime;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod support;
+
+use support::{free_addr, free_port_pair, free_udp_addr, start, SETTLE};
+
+fn packets(n: usize) -> Vec<u8> {
+    (0..n).flat_map(|i| {
+        let mut pkt = vec![i as u8; 188];
+        pkt[0] = 0x47;
+        pkt
+    }).collect()
+}
+
+/// Connect to `addr`, giving the restreamer a moment to take the connection
+fn connect(addr: SocketAddr) -> TcpStream {
+    let stream = support::connect(addr);
+    thread::sleep(SETTLE);
+    stream
+}
+
+#[test]
+fn fan_out() {
+    let harness = start(Restreamer::builder());
+
+    let mut producer = connect(harness.producer);
+    let mut consumers = vec![connect(harness.consumer), connect(harness.consumer)];
+    assert_eq!(harness.restreamer.consumers(), 2);
+
+    let data = packets(14);
+    producer.write_all(&data).unwrap();
+
+    for consumer in &mut consumers {
+        let mut buf = vec![0; data.len()];
+        consumer.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    let metrics = harness.restreamer.stats().prometheus();
+    assert!(metrics.contains("restream_consumers 2"));
+    assert!(metrics.contains("restream_producer_connected 1"));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn listeners_kept_for_the_account() {
+    use std::os::unix::fs::MetadataExt;
+
+    // Switching to the current account needs no privilege
+    let me = fs::metadata("/proc/self").unwrap();
+    let current = Account { uid: Some(me.uid()), gid: Some(me.gid()) };
+
+    let harness = start(Restreamer::builder().account(Some(current)));
+
+    let data = packets(7);
+    for _ in 0..2 {
+        let mut producer = connect(harness.producer);
+        let mut consumer = connect(harness.consumer);
+
+        producer.write_all(&data).unwrap();
+        let mut buf = vec![0; data.len()];
+        consumer.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        drop(producer);
+        assert_eq!(consumer.read(&mut buf).unwrap(), 0);
+    }
+
+    // Still bound without a producer, nothing could bind it again
+    assert!(TcpListener::bind(harness.consumer).is_err());
+}
+
+#[test]
+fn inherited_listeners() {
+    let producer = TcpListener::bind("127.0.0.1:0").unwrap();
+    let consumer = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (producer_addr, consumer_addr) = (producer.local_addr().unwrap(), consumer.local_addr().unwrap());
+
+    // The ports given are left alone
+    let (given_producer, given_consumer) = (free_addr(), free_addr());
+    let rt = Runtime::new().unwrap();
+    let _restreamer = Restreamer::builder()
+        .producer_listener(given_producer)
+        .consumer_listener(given_consumer)
+        .inherited(Some(producer), vec![consumer])
+        .spawn(&rt)
+        .unwrap();
+
+    let mut producer = connect(producer_addr);
+    let mut consumer = connect(consumer_addr);
+    let data = packets(7);
+    producer.write_all(&data).unwrap();
+
+    let mut buf = vec![0; data.len()];
+    consumer.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data);
+
+    assert!(TcpStream::connect(given_producer).is_err());
+    assert!(TcpListener::bind(given_consumer).is_ok());
+}
+
+#[test]
+fn second_producer_rejected() {
+    let harness = start(Restreamer::builder());
+
+    let _producer = connect(harness.producer);
+    let mut second = connect(harness.producer);
+
+    let mut buf = [0; 1];
+    assert_eq!(second.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn stop_flushes_the_consumers() {
+    let harness = start(Restreamer::builder());
+
+    let mut producer = connect(harness.producer);
+    let mut consumer = connect(harness.consumer);
+
+    let data = packets(7);
+    producer.write_all(&data).unwrap();
+    thread::sleep(SETTLE);
+
+    harness.rt.block_on(harness.restreamer.stop());
+    assert_eq!(harness.restreamer.consumers(), 0);
+
+    let mut buf = Vec::new();
+    consumer.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, data);
+}
+
+#[test]
+fn slow_consumer_does_not_spin() {
+    let harness = start(Restreamer::builder());
+    let mut producer = connect(harness.producer);
+    // Never reads, its socket fills up quickly
+    let _consumer = connect(harness.consumer);
+
+    let chunk = packets(7);
+    let chunks = 4000;
+    for _ in 0..chunks {
+        producer.write_all(&chunk).unwrap();
+    }
+    thread::sleep(SETTLE);
+
+    let (_, consumers) = harness.restreamer.stats().peers();
+    let polls = consumers[0].polls.load(Ordering::Relaxed);
+
+    // About once per chunk queued, a busy loop is orders of magnitude more
+    assert!(polls < 2 * chunks, "{} polls for {} chunks", polls, chunks);
+}
+
+#[test]
+fn reset_consumer_leaves_the_others() {
+    let harness = start(Restreamer::builder());
+    let mut producer = connect(harness.producer);
+    let mut kept = connect(harness.consumer);
+    let reset = connect(harness.consumer);
+
+    let data = packets(14);
+    let mut buf = vec![0; data.len()];
+    producer.write_all(&data).unwrap();
+    kept.read_exact(&mut buf).unwrap();
+
+    // Closing with unread data and no linger sends a reset
+    SockRef::from(&reset).set_linger(Some(Duration::from_secs(0))).unwrap();
+    drop(reset);
+
+    for _ in 0..10 {
+        producer.write_all(&data).unwrap();
+        kept.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    assert_eq!(harness.restreamer.consumers(), 1);
+}
+
+#[test]
+fn capped_read_buffer_keeps_the_stream() {
+    let harness = start(Restreamer::builder()
+        // Raised to 4 chunks
+        .settings(Settings { max_read_buffer: 0, ..Settings::default() }));
+
+    let mut producer = connect(harness.producer);
+    let mut consumer = connect(harness.consumer);
+
+    let data = packets(7 * 200);
+    producer.write_all(&data).unwrap();
+
+    let mut buf = vec![0; data.len()];
+    consumer.read_exact(&mut buf).unwrap();
+    assert!(buf == data);
+
+    let buffered = harness.restreamer.stats().producer().unwrap().buffered.load(Ordering::Relaxed);
+    assert!(buffered <= 4 * 1316, "{} bytes buffered", buffered);
+}
+
+#[test]
+fn closed_consumer_noticed_without_traffic() {
+    let harness = start(Restreamer::builder());
+    let _producer = connect(harness.producer);
+    let consumer = connect(harness.consumer);
+    assert_eq!(harness.restreamer.consumers(), 1);
+
+    // Nothing is written to it, the end of its stream is enough
+    drop(consumer);
+    thread::sleep(SETTLE);
+
+    assert_eq!(harness.restreamer.consumers(), 0);
+}
+
+#[test]
+fn connection_events() {
+    let harness = start(Restreamer::builder());
+    let mut events = block_on_stream(harness.restreamer.events());
+
+    let mut producer = connect(harness.producer);
+    let producer_addr = producer.local_addr().unwrap();
+    assert_eq!(events.next(), Some(Event::ProducerConnected { addr: producer_addr }));
+
+    let mut consumer = connect(harness.consumer);
+    let consumer_addr = consumer.local_addr().unwrap();
+    assert_eq!(events.next(), Some(Event::ConsumerConnected { addr: consumer_addr }));
+
+    let data = packets(14);
+    let mut buf = vec![0; data.len()];
+    producer.write_all(&data).unwrap();
+    consumer.read_exact(&mut buf).unwrap();
+
+    drop(consumer);
+    match events.next() {
+        Some(Event::ConsumerDisconnected { addr, bytes, reason, .. }) => {
+            assert_eq!(addr, consumer_addr);
+            assert_eq!(bytes, data.len() as u64);
+            assert_eq!(reason, Reason::Closed);
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+
+    drop(producer);
+    match events.next() {
+        Some(Event::ProducerDisconnected { addr, bytes, reason, .. }) => {
+            assert_eq!(addr, producer_addr);
+            assert_eq!(bytes, data.len() as u64);
+            assert_eq!(reason, Reason::Closed);
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn access_log_lines() {
+    let path = std::env::temp_dir().join(format!("restream-access-{}.log", std::process::id()));
+    let _ = fs::remove_file(&path);
+
+    let harness = start(Restreamer::builder().access_log(Some(AccessLog { path: path.clone(), format: AccessFormat::Text })));
+
+    let mut producer = connect(harness.producer);
+    let mut consumer = connect(harness.consumer);
+    let consumer_addr = consumer.local_addr().unwrap();
+
+    let data = packets(14);
+    let mut buf = vec![0; data.len()];
+    producer.write_all(&data).unwrap();
+    consumer.read_exact(&mut buf).unwrap();
+
+    drop(consumer);
+    thread::sleep(SETTLE);
+
+    let log = fs::read_to_string(&path).unwrap();
+    let fields: Vec<_> = log.lines().next().unwrap().split(' ').collect();
+    assert_eq!(fields[1..3], ["consumer", &consumer_addr.to_string()[..]]);
+    assert_eq!(fields[4], data.len().to_string());
+    assert_eq!(fields[5], "closed");
+
+    // Rotated away, the next line goes to a new file
+    let rotated = path.with_extension("log.1");
+    fs::rename(&path, &rotated).unwrap();
+    harness.restreamer.reopen_access_log();
+    drop(producer);
+    thread::sleep(SETTLE);
+
+    assert_eq!(fs::read_to_string(&rotated).unwrap(), log);
+    assert!(fs::read_to_string(&path).unwrap().contains(" producer "));
+
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_file(&rotated);
+}
+
+#[test]
+fn consumer_options() {
+    let harness = start(Restreamer::builder()
+        .burst(Some(Burst::Bytes(1024 * 1024)))
+        .settings(Settings { consumer_options: true, ..Settings::default() }));
+
+    let mut producer = connect(harness.producer);
+    let data = packets(84);
+    let (old, new) = data.split_at(70 * 188);
+    producer.write_all(old).unwrap();
+    thread::sleep(SETTLE);
+
+    // Sends nothing, gets the whole burst once the options are given up on
+    let mut legacy = connect(harness.consumer);
+    let mut buf = vec![0; old.len()];
+    legacy.read_exact(&mut buf).unwrap();
+    assert!(buf == old);
+
+    // Unknown keys are ignored
+    let mut live = connect(harness.consumer);
+    live.write_all(b"OPTS burst=0 color=blue\n").unwrap();
+    thread::sleep(SETTLE);
+
+    producer.write_all(new).unwrap();
+    let mut buf = vec![0; new.len()];
+    live.read_exact(&mut buf).unwrap();
+    assert!(buf == new);
+
+    // Malformed, closed without a byte
+    let mut malformed = connect(harness.consumer);
+    malformed.write_all(b"OPTS burst\n").unwrap();
+    assert_eq!(malformed.read(&mut [0; 1]).unwrap(), 0);
+}
+
+#[cfg(unix)]
+#[test]
+fn dscp_marking() {
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    udp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let harness = start(Restreamer::builder()
+        .udp_output(udp.local_addr().unwrap().to_string().parse().unwrap())
+        .udp_output_dscp(Some(34))
+        .settings(Settings { dscp: Some(46), ..Settings::default() }));
+
+    let _producer = connect(harness.producer);
+    let _consumer = connect(harness.consumer);
+
+    let json = harness.restreamer.stats().json();
+    assert_eq!(json.matches("\"dscp\": 46").count(), 2, "{}", json);
+    assert_eq!(json.matches("\"dscp\": 34").count(), 1, "{}", json);
+}
+
+#[test]
+fn non_ts_producer_rejected() {
+    let harness = start(Restreamer::builder().settings(Settings { validate_input: Some(10), ..Settings::default() }));
+
+    let mut probe = connect(harness.producer);
+    let mut consumer = connect(harness.consumer);
+
+    probe.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+    probe.write_all(&[b'x'; 400]).unwrap();
+    assert_eq!(probe.read(&mut [0; 1]).unwrap(), 0);
+
+    // Nothing reached the consumer, the next producer is accepted
+    let mut buf = Vec::new();
+    consumer.read_to_end(&mut buf).unwrap();
+    assert!(buf.is_empty());
+
+    let mut producer = connect(harness.producer);
+    let mut consumer = connect(harness.consumer);
+    let data = packets(14);
+    producer.write_all(&data).unwrap();
+
+    let mut buf = vec![0; data.len()];
+    consumer.read_exact(&mut buf).unwrap();
+    assert!(buf == data);
+}
+
+#[test]
+fn packet_size_detected() {
+    let harness = start(Restreamer::builder());
+
+    let mut producer = connect(harness.producer);
+    let mut consumer = connect(harness.consumer);
+
+    // Reed-Solomon parity after each packet
+    let data = packets(14);
+    let dvb: Vec<u8> = data.chunks(188).flat_map(|pkt| [pkt, &[0xaa; 16]].concat()).collect();
+    producer.write_all(&dvb).unwrap();
+
+    let mut buf = vec![0; data.len()];
+    consumer.read_exact(&mut buf).unwrap();
+    assert!(buf == data);
+    assert!(harness.restreamer.stats().json().contains("\"packet_size\": 204"));
+
+    // The next producer is detected again
+    drop(producer);
+    let mut buf = Vec::new();
+    consumer.read_to_end(&mut buf).unwrap();
+
+    let mut producer = connect(harness.producer);
+    let mut consumer = connect(harness.consumer);
+    producer.write_all(&data).unwrap();
+
+    let mut buf = vec![0; data.len()];
+    consumer.read_exact(&mut buf).unwrap();
+    assert!(buf == data);
+    assert!(harness.restreamer.stats().json().contains("\"packet_size\": 188"));
+}
+
+fn rtp(seq: u16, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = vec![0x80, 33, (seq >> 8) as u8, seq as u8, 0, 0, 0, 0, 0, 0, 0, 1];
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+#[test]
+fn paths_merged() {
+    let (primary, backup) = (free_udp_addr(), free_udp_addr());
+    let rt = Runtime::new().unwrap();
+    let restreamer = Restreamer::builder()
+        .udp_input(primary)
+        .input_backup(Some(Input::Udp(backup)))
+        .consumer_listener(([127, 0, 0, 1], 0).into())
+        .spawn(&rt)
+        .unwrap();
+
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let data = packets(20);
+    let send = |addr: SocketAddr, seq: usize| {
+        sender.send_to(&rtp(seq as u16, &data[seq * 188..(seq + 1) * 188]), addr).unwrap();
+    };
+
+    send(primary, 0);
+    thread::sleep(SETTLE);
+    let mut consumer = connect(restreamer.consumer_addr().unwrap());
+
+    // Each path loses packets the other one has
+    for seq in 1..20 {
+        if !(5..9).contains(&seq) {
+            send(primary, seq);
+        }
+        if seq != 12 {
+            send(backup, seq);
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    let mut buf = vec![0; 19 * 188];
+    consumer.read_exact(&mut buf).unwrap();
+    assert!(buf[..] == data[188..]);
+
+    let json = restreamer.stats().json();
+    assert!(json.contains(&format!("{{\"addr\": \"{}\", \"packets\": 16, \"used_packets\": 16, \"lost_packets\": 4}}", primary)), "{}", json);
+    assert!(json.contains(&format!("{{\"addr\": \"{}\", \"packets\": 18, \"used_packets\": 4, \"lost_packets\": 1}}", backup)), "{}", json);
+}
+
+#[test]
+fn push_reconnects() {
+    let receiver = TcpListener::bind("127.0.0.1:0").unwrap();
+
+    let harness = start(Restreamer::builder()
+        .push(receiver.local_addr().unwrap())
+        .push_backoff(Backoff { initial: Duration::from_millis(50), max: Duration::from_secs(1) }));
+
+    let mut producer = connect(harness.producer);
+    let data = packets(7);
+
+    let (mut consumer, _) = receiver.accept().unwrap();
+    consumer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    thread::sleep(SETTLE);
+
+    producer.write_all(&data).unwrap();
+
+    let mut buf = vec![0; data.len()];
+    consumer.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data);
+    drop(consumer);
+
+    // The consumer being gone is only noticed when writing to it
+    receiver.set_nonblocking(true).unwrap();
+    let mut consumer = loop {
+        producer.write_all(&data).unwrap();
+        thread::sleep(SETTLE);
+
+        if let Ok((consumer, _)) = receiver.accept() {
+            break consumer;
+        }
+    };
+    consumer.set_nonblocking(false).unwrap();
+    consumer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    thread::sleep(SETTLE);
+
+    producer.write_all(&data).unwrap();
+
+    let mut buf = vec![0; data.len()];
+    consumer.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn push_from_local_addr() {
+    let receiver = TcpListener::bind("127.0.0.1:0").unwrap();
+    let target = receiver.local_addr().unwrap();
+    let local = LocalBind { addr: Some("127.0.0.2".parse().unwrap()), device: None };
+
+    let harness = start(Restreamer::builder().push_via(target, local));
+
+    let (_, peer) = receiver.accept().unwrap();
+    assert_eq!(peer.ip().to_string(), "127.0.0.2");
+
+    // Not an address of this host, refused before anything is bound
+    let any: SocketAddr = ([127, 0, 0, 1], 0).into();
+    let err = Restreamer::builder()
+        .producer_listener(any)
+        .consumer_listener(any)
+        .push(target)
+        .local_bind(LocalBind { addr: Some("192.0.2.1".parse().unwrap()), device: None })
+        .spawn(&harness.rt)
+        .err()
+        .unwrap();
+    assert!(err.to_string().starts_with(&format!("cannot bind push target tcp://{} to 192.0.2.1", target)), "{}", err);
+}
+
+fn pull(origin: SocketAddr, timeout: Option<Duration>) -> (Runtime, Restreamer) {
+    let rt = Runtime::new().unwrap();
+    let restreamer = Restreamer::builder()
+        .pull(origin)
+        .pull_backoff(Backoff { initial: Duration::from_millis(50), max: Duration::from_secs(1) })
+        .pull_timeout(timeout)
+        .consumer_listener(([127, 0, 0, 1], 0).into())
+        .spawn(&rt)
+        .unwrap();
+
+    // The consumer listener is bound from the runtime
+    thread::sleep(SETTLE);
+
+    (rt, restreamer)
+}
+
+#[test]
+fn pull_keeps_the_consumers() {
+    let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (_rt, restreamer) = pull(origin.local_addr().unwrap(), None);
+
+    let mut consumer = connect(restreamer.consumer_addr().unwrap());
+    let data = packets(7);
+
+    for _ in 0..2 {
+        let (mut producer, _) = origin.accept().unwrap();
+        thread::sleep(SETTLE);
+
+        producer.write_all(&data).unwrap();
+
+        let mut buf = vec![0; data.len()];
+        consumer.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    assert_eq!(restreamer.consumers(), 1);
+}
+
+#[test]
+fn pull_timeout_reconnects() {
+    let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (_rt, _restreamer) = pull(origin.local_addr().unwrap(), Some(Duration::from_millis(500)));
+
+    let (mut stalled, _) = origin.accept().unwrap();
+    let _producer = origin.accept().unwrap();
+
+    stalled.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0; 1];
+    assert_eq!(stalled.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn stop_closes_the_recording() {
+    let dir = std::env::temp_dir().join(format!("restream-test-{}", std::process::id()));
+
+    let harness = start(Restreamer::builder()
+        .record(Some(Record { dir: dir.clone(), max_size: None, duration: None, fsync: Fsync::Rotate, splice: false })));
+
+    let mut producer = connect(harness.producer);
+    let data = packets(14);
+    producer.write_all(&data).unwrap();
+    thread::sleep(SETTLE);
+
+    harness.rt.block_on(harness.restreamer.stop());
+
+    let files: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+    let recorded = fs::read(&files[0]).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(files.len(), 1);
+    assert_eq!(recorded, data);
+}
+
+#[test]
+fn spliced_recording_matches_the_consumers() {
+    let dir = std::env::temp_dir().join(format!("restream-splice-{}", std::process::id()));
+
+    let harness = start(Restreamer::builder()
+        .record(Some(Record { dir: dir.clone(), max_size: None, duration: None, fsync: Fsync::Rotate, splice: true })));
+    let stats = harness.restreamer.stats();
+
+    // Nobody else takes the stream, it is spliced into the recording once
+    // the size of the packets is known
+    let mut producer = connect(harness.producer);
+    let data = packets(2000);
+    producer.write_all(&data[..100 * 188]).unwrap();
+    thread::sleep(SETTLE);
+    producer.write_all(&data[100 * 188..1000 * 188]).unwrap();
+    thread::sleep(SETTLE);
+
+    // Read again once a consumer joins
+    let mut consumer = connect(harness.consumer);
+    producer.write_all(&data[1000 * 188..]).unwrap();
+    drop(producer);
+    let mut received = Vec::new();
+    consumer.read_to_end(&mut received).unwrap();
+    thread::sleep(SETTLE);
+
+    harness.rt.block_on(harness.restreamer.stop());
+
+    let files: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+    let recorded = fs::read(&files[0]).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(files.len(), 1);
+    assert!(recorded == data);
+    assert_eq!(received.len(), 1000 * 188);
+    assert!(recorded.ends_with(&received));
+
+    let metrics = stats.prometheus();
+    let spliced: usize = metrics.lines()
+        .find_map(|line| line.strip_prefix("restream_record_spliced_bytes_total "))
+        .unwrap().parse().unwrap();
+    if cfg!(target_os = "linux") {
+        assert!(spliced >= 900 * 188, "{} bytes spliced", spliced);
+    }
+}
+
+#[test]
+fn consumer_reconnects_within_the_grace() {
+    let harness = start(Restreamer::builder()
+        .settings(Settings { reconnect_grace: Some(Duration::from_secs(1)), ..Settings::default() }));
+
+    let mut producer = connect(harness.producer);
+    let data = packets(42);
+    let mut consumer = connect(harness.consumer);
+    consumer.write_all(b"OPTS token=tv-1\n").unwrap();
+    thread::sleep(SETTLE);
+
+    producer.write_all(&data[..14 * 188]).unwrap();
+    let mut buf = vec![0; 14 * 188];
+    consumer.read_exact(&mut buf).unwrap();
+    drop(consumer);
+    thread::sleep(SETTLE);
+    assert_eq!(harness.restreamer.consumers(), 0);
+
+    // Queued while away, none of it is missed
+    producer.write_all(&data[14 * 188..28 * 188]).unwrap();
+    thread::sleep(SETTLE);
+    let mut consumer = connect(harness.consumer);
+    consumer.write_all(b"OPTS token=tv-1\n").unwrap();
+    consumer.read_exact(&mut buf).unwrap();
+    assert!(buf[..] == data[14 * 188..28 * 188]);
+    drop(consumer);
+
+    // Too late, joins live as any consumer
+    thread::sleep(Duration::from_millis(1500));
+    producer.write_all(&data[..14 * 188]).unwrap();
+    thread::sleep(SETTLE);
+    let mut consumer = connect(harness.consumer);
+    consumer.write_all(b"OPTS token=tv-1\n").unwrap();
+    thread::sleep(SETTLE);
+    producer.write_all(&data[28 * 188..]).unwrap();
+    consumer.read_exact(&mut buf).unwrap();
+    assert!(buf[..] == data[28 * 188..]);
+}
+
+#[test]
+fn higher_tier_takes_the_place_of_a_lower_one() {
+    let settings = Settings {
+        max_consumers: Some(1),
+        consumer_options: true,
+        priorities: vec!["1=127.0.0.1".parse().unwrap()],
+        ..Settings::default()
+    };
+    let harness = start(Restreamer::builder().settings(settings));
+
+    let mut producer = connect(harness.producer);
+    // Gives up the tier its address gets
+    let mut viewer = connect(harness.consumer);
+    viewer.write_all(b"OPTS priority=0\n").unwrap();
+    thread::sleep(SETTLE);
+
+    let mut origin = connect(harness.consumer);
+    assert_eq!(viewer.read(&mut [0; 1]).unwrap(), 0);
+    // Served once the options are given up on
+    thread::sleep(Duration::from_millis(500));
+    let data = packets(14);
+    producer.write_all(&data).unwrap();
+    let mut buf = vec![0; data.len()];
+    origin.read_exact(&mut buf).unwrap();
+    assert!(buf == data);
+
+    // As high as the one connected, refused
+    let mut refused = connect(harness.consumer);
+    assert_eq!(refused.read(&mut [0; 1]).unwrap(), 0);
+
+    let status = harness.restreamer.stats().json();
+    assert!(status.contains("\"priority\": 1}"));
+    assert!(status.contains("\"tiers\": [{\"priority\": 1, \"consumers\": 1, \"dropped_packets\": 0, \"displaced\": 0}, \
+                             {\"priority\": 0, \"consumers\": 0, \"dropped_packets\": 0, \"displaced\": 1}]"));
+}
+
+#[test]
+fn encrypted_tunnel() {
+    let aes = Aes::new("000102030405060708090a0b0c0d0e0f".parse().unwrap(), Some("0f0e0d0c0b0a09080706050403020100".parse().unwrap()));
+    let origin = start(Restreamer::builder().settings(Settings { encrypt: Some(aes), ..Settings::default() }));
+    let relay = Restreamer::builder()
+        .pull(origin.consumer)
+        .consumer_listener(([127, 0, 0, 1], 0).into())
+        .settings(Settings { decrypt: Some(aes), ..Settings::default() })
+        .spawn(&origin.rt)
+        .unwrap();
+
+    let mut producer = connect(origin.producer);
+    let mut eavesdropper = connect(origin.consumer);
+    let mut consumer = connect(relay.consumer_addr().unwrap());
+
+    // A PAT, then payloads of a few lengths on PID 0x100
+    let data: Vec<u8> = (0..14).flat_map(|i: u8| {
+        let mut pkt = vec![i; 188];
+        pkt[..4].copy_from_slice(&[0x47, if i == 0 { 0x40 } else { 0x01 }, 0, 0x10 | (i & 0x0f)]);
+        if i % 2 == 1 {
+            pkt[3] |= 0x20;
+            pkt[4] = 10 * i;
+        }
+        pkt
+    }).collect();
+    producer.write_all(&data).unwrap();
+
+    let mut buf = vec![0; data.len()];
+    eavesdropper.read_exact(&mut buf).unwrap();
+    assert_eq!(buf[..188], data[..188]);
+    for (pkt, clear) in buf.chunks(188).zip(data.chunks(188)).skip(1) {
+        assert_eq!(pkt[3], clear[3] | 0x80);
+        assert_ne!(pkt[188 - 8..], clear[188 - 8..]);
+    }
+
+    consumer.read_exact(&mut buf).unwrap();
+    assert!(buf == data);
+}
+
+#[test]
+fn sink_outlives_the_producers() {
+    let path = std::env::temp_dir().join(format!("restream-sink-{}.ts", std::process::id()));
+
+    let harness = start(Restreamer::builder().sink(Some(Sink::File(path.clone()))));
+
+    let data = packets(28);
+    for half in data.chunks(14 * 188) {
+        let mut producer = connect(harness.producer);
+        producer.write_all(half).unwrap();
+        // Gone before the next one connects, or it is rejected
+        drop(producer);
+        thread::sleep(SETTLE);
+    }
+
+    harness.rt.block_on(harness.restreamer.stop());
+
+    let written = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(written, data);
+}
+
+/// Status line and body of the response to a GET
+fn get(addr: SocketAddr, path: &str) -> (String, Vec<u8>) {
+    let mut client = connect(addr);
+    write!(client, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+    let mut res = Vec::new();
+    client.read_to_end(&mut res).unwrap();
+
+    let head = res.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let status = String::from_utf8_lossy(&res[..head]).lines().next().unwrap().to_owned();
+    (status, res[head + 4..].to_vec())
+}
+
+#[test]
+fn hls_segments_served() {
+    let dir = std::env::temp_dir().join(format!("restream-hls-{}", std::process::id()));
+
+    let hls = free_addr();
+    let harness = start(Restreamer::builder()
+        .hls(Some(Hls { dir: Some(dir.clone()), segment_duration: Duration::from_millis(200), window: 2 }))
+        .hls_port(Some(hls)));
+
+    // Before the first segment, a playlist listing none
+    let (status, playlist) = get(hls, "/hls/index.m3u8");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert!(playlist.starts_with(b"#EXTM3U\n"));
+    assert!(!String::from_utf8(playlist).unwrap().contains(".ts"));
+
+    // Without video, segments start on a PAT
+    let mut producer = connect(harness.producer);
+    for _ in 0..12 {
+        let mut pat = packets(7);
+        pat[1] = 0x40;
+        pat[2] = 0;
+        producer.write_all(&pat).unwrap();
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    let (_, playlist) = get(hls, "/hls/index.m3u8");
+    let playlist = String::from_utf8(playlist).unwrap();
+    let segments: Vec<_> = playlist.lines().filter(|line| line.ends_with(".ts")).collect();
+    assert_eq!(segments.len(), 2);
+
+    let (status, segment) = get(hls, &format!("/hls/{}", segments[1]));
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert_eq!(&segment[..3], &[0x47, 0x40, 0]);
+    assert_eq!(segment, fs::read(dir.join(segments[1])).unwrap());
+
+    // The segments out of the playlist are deleted
+    let mut files: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+    files.sort();
+    fs::remove_dir_all(&dir).unwrap();
+    let mut expected = vec![segments[0], segments[1], "index.m3u8"];
+    expected.sort();
+    assert_eq!(files, expected);
+}
+
+#[test]
+fn websocket_consumers() {
+    let ws = free_addr();
+    let harness = start(Restreamer::builder().ws_listener(Some(ws)));
+
+    let mut producer = connect(harness.producer);
+    let mut browser = connect(ws);
+    browser.write_all(b"GET /live HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        browser.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap();
+    assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+    assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    thread::sleep(SETTLE);
+
+    // A binary frame per chunk
+    let data = packets(7);
+    producer.write_all(&data).unwrap();
+    let mut frame = vec![0; 4 + data.len()];
+    browser.read_exact(&mut frame).unwrap();
+    assert_eq!(frame[..4], [0x82, 126, 0x05, 0x24]);
+    assert_eq!(frame[4..], data[..]);
+
+    assert!(harness.restreamer.stats().json().contains("\"websocket\": true"));
+
+    // Masked ping, unmasked pong
+    browser.write_all(&[0x89, 0x82, 1, 2, 3, 4, b'h' ^ 1, b'i' ^ 2]).unwrap();
+    let mut pong = [0; 4];
+    browser.read_exact(&mut pong).unwrap();
+    assert_eq!(pong, [0x8a, 2, b'h', b'i']);
+
+    // The stream ends with a close frame
+    drop(producer);
+    let mut close = Vec::new();
+    browser.read_to_end(&mut close).unwrap();
+    assert_eq!(close, [0x88, 2, 0x03, 0xe8]);
+}
+
+#[test]
+fn proxy_protocol_addresses() {
+    let harness = start(Restreamer::builder().settings(Settings { proxy_protocol: true, ..Settings::default() }));
+
+    let mut producer = connect(harness.producer);
+    write!(producer, "PROXY TCP4 192.0.2.1 198.51.100.1 56324 {}\r\n", harness.producer.port()).unwrap();
+    thread::sleep(SETTLE);
+
+    let mut consumer = connect(harness.consumer);
+    let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\0\x0c".to_vec();
+    header.extend_from_slice(&[198, 51, 100, 7, 127, 0, 0, 1, 0x9c, 0x40, 0x5c, 0x63]);
+    consumer.write_all(&header).unwrap();
+    thread::sleep(SETTLE);
+
+    // Whatever follows the header is the stream
+    let data = packets(7);
+    producer.write_all(&data).unwrap();
+    let mut buf = vec![0; data.len()];
+    consumer.read_exact(&mut buf).unwrap();
+    assert!(buf == data);
+
+    let json = harness.restreamer.stats().json();
+    assert!(json.contains("192.0.2.1:56324"), "{}", json);
+    assert!(json.contains("198.51.100.7:40000"), "{}", json);
+
+    // No header, closed without a byte
+    let mut direct = connect(harness.consumer);
+    direct.write_all(b"HELLO!\r\n").unwrap();
+    assert_eq!(direct.read(&mut [0; 1]).unwrap(), 0);
+
+    drop(consumer);
+    producer.write_all(&data).unwrap();
+    thread::sleep(SETTLE);
+    assert!(!harness.restreamer.stats().json().contains("198.51.100.7"));
+}
+
+#[test]
+fn rate_limited_consumers() {
+    let harness = start(Restreamer::builder()
+        .settings(Settings { rate_limit: Some(1316 * 8 * 10), consumer_options: true, ..Settings::default() }));
+
+    let mut producer = connect(harness.producer);
+    let mut limited = connect(harness.consumer);
+    let mut fast = connect(harness.consumer);
+    fast.write_all(b"OPTS pace=100M\n").unwrap();
+    thread::sleep(Duration::from_millis(600));
+
+    // 20 chunks at 10 a second
+    let data = packets(7 * 20);
+    let start = Instant::now();
+    producer.write_all(&data).unwrap();
+
+    let mut buf = vec![0; data.len()];
+    fast.read_exact(&mut buf).unwrap();
+    assert!(buf == data);
+    assert!(start.elapsed() < Duration::from_millis(500));
+
+    limited.read_exact(&mut buf).unwrap();
+    assert!(buf == data);
+    assert!(start.elapsed() >= Duration::from_millis(1500), "{:?}", start.elapsed());
+}
+
+#[test]
+fn total_rate_shared() {
+    let bandwidth = Arc::new(Bandwidth::new(1316 * 8 * 20));
+    let harness = start(Restreamer::builder().settings(Settings { bandwidth: Some(bandwidth), ..Settings::default() }));
+
+    let mut producer = connect(harness.producer);
+    let mut first = connect(harness.consumer);
+    let mut second = connect(harness.consumer);
+
+    // 40 chunks in all at 20 a second, taking turns
+    let data = packets(7 * 20);
+    let start = Instant::now();
+    producer.write_all(&data).unwrap();
+
+    let mut buf = vec![0; data.len()];
+    first.read_exact(&mut buf).unwrap();
+    assert!(buf == data);
+    assert!(start.elapsed() >= Duration::from_millis(1500), "{:?}", start.elapsed());
+
+    second.read_exact(&mut buf).unwrap();
+    assert!(buf == data);
+}
+
+#[test]
+fn accept_rate_limited() {
+    let harness = start(Restreamer::builder().settings(Settings { accept_rate: Some(3.0), ..Settings::default() }));
+
+    let _producer = connect(harness.producer);
+
+    // Same address as the producer, room for two more at once
+    let consumers: Vec<_> = (0..3).map(|_| TcpStream::connect(harness.consumer).unwrap()).collect();
+    thread::sleep(SETTLE);
+    assert_eq!(harness.restreamer.consumers(), 2);
+
+    let mut refused = &consumers[2];
+    refused.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(refused.read(&mut [0; 1]).unwrap(), 0);
+
+    // A second later, there is room again
+    thread::sleep(Duration::from_secs(1));
+    let _later = connect(harness.consumer);
+    assert_eq!(harness.restreamer.consumers(), 3);
+}
+
+#[test]
+fn bind_retry_waits_for_the_port() {
+    let rt = Runtime::new().unwrap();
+    let busy = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = busy.local_addr().unwrap();
+    let builder = Restreamer::builder()
+        .producer_listener(addr)
+        .consumer_listener(([127, 0, 0, 1], 0).into());
+
+    let err = builder.clone().spawn(&rt).err().unwrap();
+    assert!(err.to_string().contains(&format!("port {}", addr.port())), "{}", err);
+
+    let release = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(600));
+        drop(busy);
+    });
+    let start = Instant::now();
+    let _restreamer = builder.bind_retry(Some(Duration::from_secs(5))).spawn(&rt).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(500));
+    release.join().unwrap();
+
+    let _producer = connect(addr);
+}
+
+#[cfg(unix)]
+#[test]
+fn reuseport_accept_loops() {
+    let harness = start(Restreamer::builder().reuseport(4));
+
+    let mut producer = connect(harness.producer);
+    let mut consumers: Vec<_> = (0..8).map(|_| connect(harness.consumer)).collect();
+    assert_eq!(harness.restreamer.consumers(), 8);
+
+    let data = packets(7);
+    producer.write_all(&data).unwrap();
+    for consumer in &mut consumers {
+        let mut buf = vec![0; data.len()];
+        consumer.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    let metrics = harness.restreamer.stats().prometheus();
+    let accepts: u64 = metrics.lines()
+        .filter(|line| line.starts_with("restream_accepts_total{"))
+        .map(|line| line.rsplit(' ').next().unwrap().parse::<u64>().unwrap())
+        .sum();
+    assert_eq!(accepts, 8);
+    assert!(metrics.contains("restream_accepts_total{loop=\"3\"}"));
+}
+
+/// Read chunks of `expected.len()` bytes until one matches
+fn wait_for(consumer: &mut TcpStream, expected: &[u8]) {
+    let mut buf = vec![0; expected.len()];
+
+    for _ in 0..50 {
+        consumer.read_exact(&mut buf).unwrap();
+        if buf == expected {
+            return;
+        }
+    }
+
+    panic!("Chunk never received");
+}
+
+#[test]
+fn slate_fills_the_gaps() {
+    let slate: Vec<u8> = packets(7).iter().map(|&b| if b == 0x47 { b } else { 0xaa }).collect();
+    let path = std::env::temp_dir().join(format!("restream-slate-{}.ts", std::process::id()));
+    fs::write(&path, &slate).unwrap();
+
+    let harness = start(Restreamer::builder()
+        .slate(Some(path.clone()))
+        .play_bitrate(Some(slate.len() as u64 * 8 * 20)));
+    thread::sleep(SETTLE);
+
+    let mut consumer = connect(harness.consumer);
+    wait_for(&mut consumer, &slate);
+
+    let mut producer = connect(harness.producer);
+    let data = packets(7);
+    producer.write_all(&data).unwrap();
+    wait_for(&mut consumer, &data);
+
+    drop(producer);
+    wait_for(&mut consumer, &slate);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn backup_input_takes_over() {
+    let backup: Vec<u8> = packets(7).iter().map(|&b| if b == 0x47 { b } else { 0xaa }).collect();
+    let path = std::env::temp_dir().join(format!("restream-backup-{}.ts", std::process::id()));
+    fs::write(&path, &backup).unwrap();
+
+    let harness = start(Restreamer::builder()
+        .input_backup(Some(Input::File(path.clone())))
+        .failover_timeout(Some(Duration::from_millis(300)))
+        .failback_delay(Duration::from_millis(300))
+        .play_bitrate(Some(backup.len() as u64 * 8 * 20)));
+    let events = block_on_stream(harness.restreamer.events()).filter_map(|event| match event {
+        Event::InputSwitched { backup } => Some(backup),
+        _ => None,
+    });
+    let mut switches = events.take(2);
+    thread::sleep(SETTLE);
+
+    let mut consumer = connect(harness.consumer);
+    wait_for(&mut consumer, &backup);
+    assert_eq!(switches.next(), Some(true));
+
+    // Payload only, nothing to flag on the switch
+    let data: Vec<u8> = packets(7).iter().map(|&b| if b == 0x47 { b } else { 0xcc }).collect();
+    let mut producer = connect(harness.producer);
+    let sender = {
+        let data = data.clone();
+        thread::spawn(move || {
+            for _ in 0..50 {
+                producer.write_all(&data).unwrap();
+                thread::sleep(Duration::from_millis(20));
+            }
+        })
+    };
+    wait_for(&mut consumer, &data);
+    assert_eq!(switches.next(), Some(false));
+
+    // The consumer stays through the switches
+    sender.join().unwrap();
+    wait_for(&mut consumer, &backup);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn idle_consumer_disconnected() {
+    let harness = start(Restreamer::builder()
+        .settings(Settings { idle_timeout: Some(Duration::from_millis(500)), ..Settings::default() }));
+
+    let mut producer = connect(harness.producer);
+    let _consumer = connect(harness.consumer);
+
+    // Way more than the socket buffers can hold, then nothing new
+    let data = packets(7 * 1024);
+    for _ in 0..16 {
+        producer.write_all(&data).unwrap();
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(harness.restreamer.consumers(), 1);
+
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(harness.restreamer.consumers(), 0);
+}
+
+#[test]
+fn stalled_producer_drops_the_consumers() {
+    let harness = start(Restreamer::builder()
+        .producer_stall_timeout(Some(Duration::from_millis(500)))
+        .disconnect_on_stall(true));
+
+    let mut producer = connect(harness.producer);
+    let mut consumer = connect(harness.consumer);
+
+    let data = packets(7);
+    producer.write_all(&data).unwrap();
+
+    let mut buf = Vec::new();
+    consumer.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, data);
+    assert!(harness.restreamer.stats().prometheus().contains("restream_producer_stalled 1"));
+    assert_eq!(harness.restreamer.consumers(), 0);
+
+    producer.write_all(&data).unwrap();
+    thread::sleep(Duration::from_millis(350));
+    assert!(harness.restreamer.stats().prometheus().contains("restream_producer_stalled 0"));
+}
+
+#[test]
+fn dropped_pids_never_reach_the_consumers() {
+    let harness = start(Restreamer::builder().pid_filter(Some(PidFilter::drop(&[0x0101, 0x0303]))));
+
+    let mut producer = connect(harness.producer);
+    let mut consumer = connect(harness.consumer);
+
+    let data = packets(7);
+    producer.write_all(&data).unwrap();
+    producer.write_all(&data).unwrap();
+
+    let kept: Vec<u8> = data.chunks(188)
+        .enumerate()
+        .filter(|&(i, _)| i != 1 && i != 3)
+        .flat_map(|(_, pkt)| pkt.to_vec())
+        .collect();
+    // A full chunk right away, the rest once the producer leaves
+    let mut buf = vec![0; 7 * 188];
+    consumer.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, [&kept[..], &kept[..2 * 188]].concat());
+    drop(producer);
+
+    let mut buf = Vec::new();
+    consumer.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, &kept[2 * 188..]);
+    assert!(harness.restreamer.stats().prometheus().contains("restream_filtered_packets_total 4"));
+}
+
+#[test]
+fn remapped_pids() {
+    let remap = PidRemap::new(&["0x0101:0x0200".parse().unwrap(), "0x0303:0x0101".parse().unwrap()]).unwrap();
+    let harness = start(Restreamer::builder().remap_pids(Some(remap)));
+
+    let mut producer = connect(harness.producer);
+    let mut consumer = connect(harness.consumer);
+
+    let data = packets(7);
+    producer.write_all(&data).unwrap();
+
+    let mut buf = vec![0; data.len()];
+    consumer.read_exact(&mut buf).unwrap();
+    let pids: Vec<u16> = buf.chunks(188).map(|pkt| (u16::from(pkt[1] & 0x1f) << 8) | u16::from(pkt[2])).collect();
+    assert_eq!(pids, vec![0, 0x0200, 0x0202, 0x0101, 0x0404, 0x0505, 0x0606]);
+    // Only the headers changed
+    assert_eq!(buf[188 + 3..2 * 188], data[188 + 3..2 * 188]);
+}
+
+#[test]
+fn null_packets_stripped() {
+    let harness = start(Restreamer::builder().strip_nulls(true));
+
+    let mut producer = connect(harness.producer);
+    let mut consumer = connect(harness.consumer);
+
+    let mut data = packets(14);
+    for pkt in data.chunks_mut(188).skip(7) {
+        pkt[1] = 0x1f;
+        pkt[2] = 0xff;
+    }
+    producer.write_all(&data).unwrap();
+    producer.write_all(&data).unwrap();
+
+    let mut buf = vec![0; 7 * 188];
+    consumer.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, &data[..7 * 188]);
+    consumer.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, &data[..7 * 188]);
+    thread::sleep(SETTLE);
+
+    let metrics = harness.restreamer.stats().prometheus();
+    assert!(metrics.contains("restream_producer_bytes_total 5264"));
+    assert!(metrics.contains("restream_broadcast_bytes_total 2632"));
+}
+
+/// Read the body of the next webhook request, answering it
+fn next_event(hooks: &TcpListener) -> String {
+    let (mut socket, _) = hooks.accept().unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.ends_with(b"}") {
+        let n = socket.read(&mut buf).unwrap();
+        assert!(n > 0);
+        request.extend_from_slice(&buf[..n]);
+    }
+    socket.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+
+    let request = String::from_utf8(request).unwrap();
+    request.split("\r\n\r\n").nth(1).unwrap().to_owned()
+}
+
+#[test]
+fn webhook_notified() {
+    let hooks = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/events", hooks.local_addr().unwrap());
+
+    let harness = start(Restreamer::builder()
+        .webhook(Some(Webhook::new(&url).unwrap()))
+        .webhook_threshold(1));
+
+    let mut producer = connect(harness.producer);
+    let producer_addr = producer.local_addr().unwrap().to_string();
+    assert!(next_event(&hooks).contains("\"event\": \"producer_connected\""));
+
+    let consumer = connect(harness.consumer);
+    let event = next_event(&hooks);
+    assert!(event.contains("\"event\": \"consumer_count\", \"count\": 1, \"threshold\": 1, \"rising\": true"), "{}", event);
+    drop(consumer);
+
+    // The consumer is found gone once a write fails
+    producer.write_all(&packets(7)).unwrap();
+    thread::sleep(SETTLE);
+    producer.write_all(&packets(7)).unwrap();
+    let event = next_event(&hooks);
+    assert!(event.contains("\"rising\": false"), "{}", event);
+
+    drop(producer);
+    let event = next_event(&hooks);
+    assert!(event.contains("\"event\": \"producer_disconnected\""), "{}", event);
+    assert!(event.contains("\"bytes\": 2632"), "{}", event);
+    assert!(event.contains(&producer_addr), "{}", event);
+}
+
+#[test]
+fn channels_are_isolated() {
+    let channels: Vec<_> = (0..2).map(|channel| start(Restreamer::builder().channel(Some(channel)))).collect();
+
+    let mut producer = connect(channels[1].producer);
+    let mut consumer = connect(channels[1].consumer);
+
+    let data = packets(7);
+    producer.write_all(&data).unwrap();
+
+    let mut buf = vec![0; data.len()];
+    consumer.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data);
+
+    assert!(channels[0].restreamer.stats().prometheus().contains("restream_producer_connected{channel=\"0\"} 0"));
+    assert!(channels[1].restreamer.stats().prometheus().contains("restream_producer_connected{channel=\"1\"} 1"));
+    assert!(channels[1].restreamer.stats().json().contains("\"channel\": 1,"));
+}
+
+#[test]
+fn busy_port_named() {
+    let busy = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = busy.local_addr().unwrap().port();
+
+    let rt = Runtime::new().unwrap();
+    let err = Restreamer::builder()
+        .producer_listener(([127, 0, 0, 1], 0).into())
+        .consumer_listener(([127, 0, 0, 1], port).into())
+        .spawn(&rt)
+        .err()
+        .unwrap();
+
+    assert!(err.to_string().contains(&format!("port {}", port)), "{}", err);
+}
+
+fn send_line(addr: SocketAddr, line: &str) -> TcpStream {
+    let mut stream = connect(addr);
+    stream.write_all(line.as_bytes()).unwrap();
+    thread::sleep(SETTLE);
+    stream
+}
+
+#[test]
+fn stream_keys_route() {
+    let harness = start(Restreamer::builder().stream_keys(true));
+
+    let mut cam1 = send_line(harness.producer, "PUBLISH cam1\n");
+    let mut cam2 = send_line(harness.producer, "PUBLISH cam2\n");
+    let mut viewer1 = send_line(harness.consumer, "PLAY cam1\n");
+    let mut viewer2 = send_line(harness.consumer, "PLAY cam2\n");
+    let mut unknown = send_line(harness.consumer, "PLAY cam3\n");
+    assert_eq!(harness.restreamer.consumers(), 2);
+
+    let (data1, data2) = (packets(7), packets(14));
+    cam1.write_all(&data1).unwrap();
+    cam2.write_all(&data2).unwrap();
+
+    let mut buf = vec![0; data1.len()];
+    viewer1.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data1);
+    let mut buf = vec![0; data2.len()];
+    viewer2.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data2);
+
+    let mut buf = [0; 1];
+    assert_eq!(unknown.read(&mut buf).unwrap(), 0);
+
+    let stats = harness.restreamer.stream_stats("cam2").unwrap();
+    assert!(stats.prometheus().contains("restream_producer_bytes_total{key=\"cam2\"} 2632"));
+
+    // The stream is torn down along with its producer
+    drop(cam1);
+    thread::sleep(SETTLE);
+    assert!(harness.restreamer.stream_stats("cam1").is_none());
+    assert_eq!(viewer1.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn stream_keys_wait_for_producer() {
+    let harness = start(Restreamer::builder().stream_keys(true).wait_for_producer(true));
+
+    let mut viewer = send_line(harness.consumer, "PLAY late\n");
+    let mut bad = send_line(harness.consumer, "PLAY ../late\n");
+
+    // The key and the stream may share the first write
+    let data = packets(7);
+    let mut producer = connect(harness.producer);
+    producer.write_all(&[b"PUBLISH late\r\n".as_ref(), &data].concat()).unwrap();
+
+    let mut buf = vec![0; data.len()];
+    viewer.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data);
+
+    let mut buf = [0; 1];
+    assert_eq!(bad.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn consumers_over_tls() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls");
+    let tls = Tls::from_pem(&dir.join("cert.pem"), &dir.join("key.pem")).unwrap();
+
+    let harness = start(Restreamer::builder().tls(Some(tls)));
+
+    let mut producer = connect(harness.producer);
+
+    // A plaintext consumer fails its handshake without closing the listener
+    let mut plain = connect(harness.consumer);
+    plain.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+    let mut buf = Vec::new();
+    let _ = plain.read_to_end(&mut buf);
+    assert_eq!(harness.restreamer.consumers(), 0);
+
+    let connector = native_tls::TlsConnector::builder().danger_accept_invalid_certs(true).build().unwrap();
+    let mut consumer = connector.connect("localhost", connect(harness.consumer)).unwrap();
+    thread::sleep(SETTLE);
+    assert_eq!(harness.restreamer.consumers(), 1);
+
+    let data = packets(14);
+    producer.write_all(&data).unwrap();
+
+    let mut buf = vec![0; data.len()];
+    consumer.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data);
+}
+
+fn tls_producer(addr: SocketAddr, cert: &str) -> openssl::ssl::SslStream<TcpStream> {
+    use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls");
+    let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+    builder.set_verify(SslVerifyMode::NONE);
+    builder.set_certificate_file(dir.join(cert), SslFiletype::PEM).unwrap();
+    builder.set_private_key_file(dir.join("producer-key.pem"), SslFiletype::PEM).unwrap();
+
+    builder.build().connect("localhost", connect(addr)).unwrap()
+}
+
+#[test]
+fn producers_over_mutual_tls() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls");
+    let tls = ProducerTls::from_pem(&dir.join("cert.pem"), &dir.join("key.pem"), &dir.join("ca.pem")).unwrap();
+
+    let harness = start(Restreamer::builder().producer_tls(Some(tls)));
+
+    let data = packets(14);
+
+    // Neither a plaintext producer nor an expired or unchained certificate gets through
+    let mut plain = connect(harness.producer);
+    let _ = plain.write_all(&data);
+    for cert in &["expired.pem", "unchained.pem"] {
+        let mut producer = tls_producer(harness.producer, cert);
+        let _ = producer.write_all(&data);
+    }
+    thread::sleep(SETTLE);
+    assert!(harness.restreamer.stats().json().contains("\"producer\": null"));
+
+    let mut producer = tls_producer(harness.producer, "producer.pem");
+    thread::sleep(SETTLE);
+    let mut consumer = connect(harness.consumer);
+
+    producer.write_all(&data).unwrap();
+
+    let mut buf = vec![0; data.len()];
+    consumer.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data);
+
+    assert!(harness.restreamer.stats().json().contains("\"identity\": \"cam1\""));
+}
+
+#[test]
+#[cfg(unix)]
+fn unix_sockets() {
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    let dir = std::env::temp_dir().join(format!("restream-unix-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let (input, output) = (dir.join("in.sock"), dir.join("out.sock"));
+
+    // Left behind by a previous run
+    drop(UnixListener::bind(&input).unwrap());
+
+    let rt = Runtime::new().unwrap();
+    let restreamer = Restreamer::builder()
+        .producer_socket(input.clone())
+        .consumer_socket(output.clone())
+        .socket_mode(Some(0o600))
+        .spawn(&rt)
+        .unwrap();
+
+    assert_eq!(fs::metadata(&input).unwrap().permissions().mode() & 0o777, 0o600);
+
+    let mut producer = UnixStream::connect(&input).unwrap();
+    thread::sleep(SETTLE);
+    let mut consumer = UnixStream::connect(&output).unwrap();
+    consumer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    thread::sleep(SETTLE);
+    assert_eq!(restreamer.consumers(), 1);
+
+    let data = packets(14);
+    producer.write_all(&data).unwrap();
+
+    let mut buf = vec![0; data.len()];
+    consumer.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data);
+
+    rt.block_on(restreamer.stop());
+    assert!(!input.exists());
+    assert!(!output.exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn stdin_producer() {
+    use std::process::{Command, Stdio};
+    use std::time::Instant;
+
+    let port = free_port_pair();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_restream"))
+        .args(["--stdin", "--exit-on-stdin-eof", "-p", &port.to_string()])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+
+    // The consumers are served as soon as it started
+    let addr: SocketAddr = ([127, 0, 0, 1], port + 1).into();
+    let started = Instant::now();
+    let mut consumer = loop {
+        match TcpStream::connect(addr) {
+            Ok(consumer) => break consumer,
+            Err(_) if started.elapsed() < Duration::from_secs(5) => thread::sleep(Duration::from_millis(50)),
+            Err(e) => panic!("{}", e),
+        }
+    };
+    consumer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    thread::sleep(SETTLE);
+
+    let data = packets(14);
+    stdin.write_all(&data).unwrap();
+    drop(stdin);
+
+    let mut buf = Vec::new();
+    consumer.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, data);
+
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait().unwrap() {
+            Some(status) => break status,
+            None if started.elapsed() < Duration::from_secs(5) => thread::sleep(Duration::from_millis(50)),
+            None => {
+                let _ = child.kill();
+                panic!("still running after the end of the standard input");
+            }
+        }
+    };
+    assert!(status.success());
+}
+
+#[test]
+fn stdout_sink() {
+    use std::process::{Command, Stdio};
+
+    let port = free_port_pair();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_restream"))
+        .args(["--stdin", "--exit-on-stdin-eof", "--stdout", "-p", &port.to_string()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let data = packets(14);
+    let mut stdin = child.stdin.take().unwrap();
+    // Give it time to register the sink before the first packets
+    thread::sleep(SETTLE * 2);
+    stdin.write_all(&data).unwrap();
+    drop(stdin);
+
+    // Only the stream, the logs go to the standard error
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, data);
+}
+
+#[test]
+fn probe_as_a_consumer() {
+    let harness = start(Restreamer::builder());
+    let mut producer = connect(harness.producer);
+
+    let source = Input::Tcp(harness.consumer);
+    let probe = thread::spawn(move || restream::probe(&source, Duration::from_secs(1)));
+    thread::sleep(SETTLE);
+    assert_eq!(harness.restreamer.consumers(), 1);
+    producer.write_all(&packets(14)).unwrap();
+
+    let report = probe.join().unwrap().unwrap();
+    assert_eq!(report.problems(), vec!["no PAT received"]);
+    assert!(report.to_string().starts_with("Packet size: 188 bytes\n"));
+
+    let closed = Input::Tcp(free_addr());
+    assert!(restream::probe(&closed, Duration::from_secs(1)).is_err());
+}
+
+#[test]
+fn record_and_play_once() {
+    let harness = start(Restreamer::builder());
+    let mut producer = connect(harness.producer);
+    let recorded = std::env::temp_dir().join(format!("restream-record-{}.ts", std::process::id()));
+    let never = || futures::future::pending::<()>();
+
+    let (source, path) = (harness.consumer, recorded.clone());
+    let recording = thread::spawn(move || {
+        let record = restream::record(source, &path, Some(Duration::from_secs(1)), never()).unwrap();
+        Runtime::new().unwrap().block_on(record).unwrap()
+    });
+    thread::sleep(SETTLE);
+    // Whole chunks, the producer holds back a partial one
+    let data = packets(21);
+    producer.write_all(&data).unwrap();
+
+    let copied = recording.join().unwrap();
+    assert_eq!(copied, restream::Copied { bytes: data.len() as u64, ending: restream::Ending::Complete });
+    assert_eq!(fs::read(&recorded).unwrap(), data);
+
+    // The producer leaves before the end
+    let (source, path) = (harness.consumer, recorded.clone());
+    let recording = thread::spawn(move || {
+        let record = restream::record(source, &path, Some(Duration::from_secs(5)), never()).unwrap();
+        Runtime::new().unwrap().block_on(record).unwrap()
+    });
+    thread::sleep(SETTLE);
+    drop(producer);
+    assert_eq!(recording.join().unwrap().ending, restream::Ending::Early);
+
+    // The trailing partial packet is left out
+    fs::write(&recorded, [&data[..], &[0x47, 0x01]].concat()).unwrap();
+    let peer = TcpListener::bind("127.0.0.1:0").unwrap();
+    let play = restream::play(&recorded, peer.local_addr().unwrap(), false, never()).unwrap();
+    let receiving = thread::spawn(move || {
+        let mut received = Vec::new();
+        peer.accept().unwrap().0.read_to_end(&mut received).unwrap();
+        received
+    });
+
+    let copied = Runtime::new().unwrap().block_on(play).unwrap();
+    assert_eq!(copied, restream::Copied { bytes: data.len() as u64, ending: restream::Ending::Complete });
+    assert_eq!(receiving.join().unwrap(), data);
+    fs::remove_file(&recorded).unwrap();
+}
+
+#[test]
+fn test_signal_until_a_producer_streams() {
+    let harness = start(Restreamer::builder().generate(Some(1_000_000)));
+    thread::sleep(SETTLE);
+
+    let mut consumer = connect(harness.consumer);
+    let mut buf = vec![0; 20 * 188];
+    consumer.read_exact(&mut buf).unwrap();
+    assert!(buf.chunks(188).all(|pkt| pkt[0] == 0x47));
+
+    let mut producer = connect(harness.producer);
+    let data = packets(14);
+    producer.write_all(&data).unwrap();
+
+    // What was generated before the producer may still be queued
+    let last = &data[13 * 188..];
+    let mut pkt = vec![0; 188];
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while pkt != last {
+        assert!(Instant::now() < deadline, "the producer stream never came through");
+        consumer.read_exact(&mut pkt).unwrap();
+    }
+}
+
+#[test]
+fn health_checks() {
+    let health = free_addr();
+    let harness = start(Restreamer::builder()
+        .status(Some(health))
+        .health_max_idle(Duration::from_millis(500)));
+
+    assert_eq!(get(health, "/livez").0, "HTTP/1.1 200 OK");
+    let (status, body) = get(health, "/healthz");
+    assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+    assert!(String::from_utf8(body).unwrap().contains("\"problem\": \"no producer connected\""));
+
+    let mut producer = connect(harness.producer);
+    producer.write_all(&packets(7)).unwrap();
+    let (status, body) = get(health, "/healthz");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert!(String::from_utf8(body).unwrap().starts_with("{\"healthy\": true, \"producer\": true"));
+
+    // Connected, but silent for too long
+    thread::sleep(Duration::from_millis(600));
+    let (status, body) = get(health, "/healthz");
+    assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+    assert!(String::from_utf8(body).unwrap().contains("\"problem\": \"no data for "));
+    assert_eq!(get(health, "/livez").0, "HTTP/1.1 200 OK");
+}
+
+#[test]
+fn padded_to_a_constant_bitrate() {
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    udp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let receiver = TcpListener::bind("127.0.0.1:0").unwrap();
+
+    // 100 datagrams of 7 packets a second
+    let harness = start(Restreamer::builder()
+        .udp_output(format!("{}?cbr=1052800", udp.local_addr().unwrap()).parse().unwrap())
+        .push_target(receiver.local_addr().unwrap(), LocalBind::default(), Some(1_052_800)));
+
+    let (mut pushed, _) = receiver.accept().unwrap();
+    pushed.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut producer = connect(harness.producer);
+    // The null packets of the stream are dropped
+    let mut data = packets(7);
+    for pkt in data.chunks_mut(188).skip(3) {
+        pkt[1] = 0x1f;
+        pkt[2] = 0xff;
+    }
+    producer.write_all(&data).unwrap();
+
+    // The content first, padded with null packets again
+    let mut buf = vec![0; 1500];
+    let first = loop {
+        let len = udp.recv(&mut buf).unwrap();
+        assert_eq!(len, 7 * 188);
+        if buf[..188] == data[..188] {
+            break buf[..len].to_vec();
+        }
+    };
+    assert_eq!(&first[..3 * 188], &data[..3 * 188]);
+    assert!(first[3 * 188..].chunks(188).all(|pkt| pkt[1] & 0x1f == 0x1f && pkt[2] == 0xff));
+
+    let mut chunk = vec![0; 7 * 188];
+    loop {
+        pushed.read_exact(&mut chunk).unwrap();
+        if chunk[..188] == data[..188] {
+            break;
+        }
+    }
+    assert_eq!(chunk, first);
+
+    // Kept up without any more content
+    let start = Instant::now();
+    let mut datagrams = 0;
+    while start.elapsed() < Duration::from_secs(1) {
+        udp.recv(&mut buf).unwrap();
+        datagrams += 1;
+    }
+    assert!(datagrams > 80 && datagrams < 120, "{}", datagrams);
+
+    let json = harness.restreamer.stats().json();
+    assert_eq!(json.matches("\"cbr\": {\"target_bps\": 1052800").count(), 2, "{}", json);
+}
+
+#[test]
+fn keepalive_nulls_while_silent() {
+    let harness = start(Restreamer::builder().keepalive_nulls(Some(Duration::from_millis(300))));
+
+    let mut producer = connect(harness.producer);
+    let mut consumer = connect(harness.consumer);
+    let data = packets(7);
+    producer.write_all(&data).unwrap();
+
+    let mut buf = vec![0; 7 * 188];
+    consumer.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data);
+
+    // Silent, null packets with their continuity counters in order
+    let mut counters = Vec::new();
+    for _ in 0..2 {
+        consumer.read_exact(&mut buf).unwrap();
+        for pkt in buf.chunks(188) {
+            assert_eq!((pkt[0], pkt[1] & 0x1f, pkt[2]), (0x47, 0x1f, 0xff));
+            counters.push(pkt[3] & 0x0f);
+        }
+    }
+    assert_eq!(counters, (0..14).collect::<Vec<u8>>());
+
+    // Gone as soon as the stream is back
+    for _ in 0..5 {
+        producer.write_all(&data).unwrap();
+        thread::sleep(Duration::from_millis(100));
+    }
+    let mut chunks = 0;
+    while chunks < 5 {
+        consumer.read_exact(&mut buf).unwrap();
+        if buf == data {
+            chunks += 1;
+        } else {
+            assert_eq!(chunks, 0, "null packets after the stream resumed");
+        }
+    }
+
+    let json = harness.restreamer.stats().json();
+    let keepalive: u64 = json.split("\"keepalive_bytes\": ").nth(1).unwrap().split([',', '}']).next().unwrap().parse().unwrap();
+    assert!(keepalive >= 2 * 7 * 188, "{}", json);
+    assert!(harness.restreamer.stats().prometheus().contains("restream_broadcast_bytes_total 7896"));
+}
@@ -0,0 +1,161 @@
+//! Restreamers started on ephemeral ports, with fake producers and consumers
+//!
+//! Shared by the integration tests through `mod support;`, not every test
+//! file uses all of it.
+#![allow(dead_code)]
+
+use restream::{Builder, Restreamer};
+use tokio::runtime::Runtime;
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::mpsc::{self, Receiver};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Long enough for the restreamer to accept a connection and start its session
+pub const SETTLE: Duration = Duration::from_millis(200);
+/// What the producer chunker forwards at once, the data sent should be a multiple of it
+pub const CHUNK_PACKETS: usize = 7;
+
+/// A restreamer running on its own runtime
+pub struct Harness {
+    pub rt: Runtime,
+    pub restreamer: Restreamer,
+    pub producer: SocketAddr,
+    pub consumer: SocketAddr,
+}
+
+/// Spawn `builder` with its TCP listeners on ports picked by the system
+pub fn start(builder: Builder) -> Harness {
+    let rt = Runtime::new().unwrap();
+    let any: SocketAddr = ([127, 0, 0, 1], 0).into();
+    let restreamer = builder
+        .producer_listener(any)
+        .consumer_listener(any)
+        .spawn(&rt)
+        .unwrap();
+
+    Harness {
+        producer: restreamer.producer_addr().unwrap(),
+        consumer: restreamer.consumer_addr().unwrap(),
+        rt,
+        restreamer,
+    }
+}
+
+/// `n` packets telling their place in the stream, starting from packet `first`
+///
+/// Each carries its number after the header, repeated over the payload.
+pub fn pattern(first: u32, n: usize) -> Vec<u8> {
+    (first..first + n as u32).flat_map(|i| {
+        let mut pkt = vec![0x47, 0x01, 0x00, 0x10 | (i & 0x0f) as u8];
+        pkt.extend(i.to_be_bytes().iter().cycle().take(184));
+        pkt
+    }).collect()
+}
+
+/// Numbers of the packets of `data`, panicking unless it is made of whole `pattern` packets
+pub fn packet_numbers(data: &[u8]) -> Vec<u32> {
+    assert_eq!(data.len() % 188, 0, "{} bytes is not whole packets", data.len());
+    data.chunks(188).map(|pkt| {
+        assert_eq!(pkt[0], 0x47, "lost the packet alignment");
+        let n = u32::from_be_bytes([pkt[4], pkt[5], pkt[6], pkt[7]]);
+        assert!(pkt[4..].chunks(4).all(|word| word == &pkt[4..4 + word.len()]), "packet {} corrupted", n);
+        n
+    }).collect()
+}
+
+/// Connect to `addr`, failing reads stalled for 5s
+pub fn connect(addr: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream
+}
+
+/// Connect a producer to `addr` and write `data` in chunks, closing the connection once done
+///
+/// The writing starts once the session had time to start, so that the
+/// consumers already connecting receive all of it.
+pub fn spawn_producer(addr: SocketAddr, data: Vec<u8>) -> JoinHandle<()> {
+    let mut producer = connect(addr);
+
+    thread::spawn(move || {
+        thread::sleep(SETTLE);
+        for chunk in data.chunks(CHUNK_PACKETS * 188) {
+            producer.write_all(chunk).unwrap();
+        }
+    })
+}
+
+/// Connect a consumer to `addr` and read until `len` bytes arrived, the
+/// connection is closed or nothing comes for 5s
+pub fn collect_consumer(addr: SocketAddr, len: usize) -> JoinHandle<Vec<u8>> {
+    let mut consumer = connect(addr);
+
+    thread::spawn(move || {
+        let mut received = Vec::new();
+        let mut buf = [0; 4096];
+        while received.len() < len {
+            match consumer.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+        received
+    })
+}
+
+/// Forward the connections made to the returned address to `upstream`
+///
+/// The accepted sockets are handed over, shutting one down cuts its
+/// connection on both sides.
+pub fn proxy(upstream: SocketAddr) -> (SocketAddr, Receiver<TcpStream>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        for downstream in listener.incoming() {
+            let downstream = downstream.unwrap();
+            let upstream = TcpStream::connect(upstream).unwrap();
+            for (mut from, mut to) in [(downstream.try_clone().unwrap(), upstream.try_clone().unwrap()),
+                                       (upstream, downstream.try_clone().unwrap())] {
+                thread::spawn(move || {
+                    let _ = io::copy(&mut from, &mut to);
+                    let _ = to.shutdown(Shutdown::Both);
+                    let _ = from.shutdown(Shutdown::Both);
+                });
+            }
+            if tx.send(downstream).is_err() {
+                return;
+            }
+        }
+    });
+
+    (addr, rx)
+}
+
+/// An address on a port the system just handed out and took back, for the
+/// TCP listeners the harness does not start
+pub fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+/// An address on a free port for a UDP socket, as `free_addr`
+pub fn free_udp_addr() -> SocketAddr {
+    UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+/// A free port followed by another free one, for the binary whose consumers
+/// listen on the producer port + 1
+pub fn free_port_pair() -> u16 {
+    loop {
+        let port = free_addr().port();
+        if port < u16::MAX && TcpListener::bind(("127.0.0.1", port + 1)).is_ok() {
+            return port;
+        }
+    }
+}